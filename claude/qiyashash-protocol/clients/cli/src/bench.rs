@@ -0,0 +1,168 @@
+//! Throughput probe for `qiyashash bench`
+//!
+//! The criterion benchmarks in `qiyashash-crypto` need a toolchain on the
+//! device. This probe runs from the installed binary instead: each operation
+//! loops for a fixed time and the rate is reported, which is enough to
+//! compare devices when tuning for battery and latency.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use console::style;
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+use qiyashash_crypto::aead::{Aead, AeadAlgorithm, AeadKey};
+use qiyashash_crypto::identity::IdentityKeyPair;
+use qiyashash_crypto::ratchet::RatchetState;
+use qiyashash_crypto::x3dh::{PreKeyManager, X3DHKeyAgreement};
+use qiyashash_crypto::MAX_CHAIN_LENGTH;
+
+/// Rate measured for one operation
+#[derive(Clone, Debug)]
+pub struct ProbeResult {
+    /// Operation name
+    pub name: &'static str,
+    /// Operations completed
+    pub operations: u64,
+    /// Time taken
+    pub elapsed: Duration,
+    /// Message bytes processed per operation; zero for key agreement
+    pub bytes_per_op: usize,
+}
+
+impl ProbeResult {
+    /// Operations (messages or handshakes) per second
+    pub fn ops_per_sec(&self) -> f64 {
+        self.operations as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Megabytes of message data per second, if the operation processes any
+    pub fn mb_per_sec(&self) -> Option<f64> {
+        if self.bytes_per_op == 0 {
+            return None;
+        }
+        Some(self.ops_per_sec() * self.bytes_per_op as f64 / 1_000_000.0)
+    }
+}
+
+/// Probe every operation for `duration` each, with `message_size` byte messages
+pub fn run(duration: Duration, message_size: usize) -> anyhow::Result<Vec<ProbeResult>> {
+    let plaintext = vec![0x42u8; message_size];
+    let key = AeadKey::from_bytes([0x42; 32]);
+    let aad = b"qiyashash bench";
+
+    let xchacha = Aead::with_algorithm(AeadAlgorithm::XChaCha20Poly1305);
+    let aes_gcm = Aead::with_algorithm(AeadAlgorithm::Aes256Gcm);
+
+    let shared_secret = [0x42u8; 32];
+    let bob_secret = X25519StaticSecret::random_from_rng(OsRng);
+    let bob_public = X25519PublicKey::from(&bob_secret);
+    let mut alice = RatchetState::init_alice(&shared_secret, &bob_public)?;
+    let mut bob = RatchetState::init_bob(&shared_secret, bob_secret)?;
+
+    let identity = IdentityKeyPair::generate();
+    let identity_public = identity.public_key();
+
+    Ok(vec![
+        probe("XChaCha20-Poly1305 encrypt", duration, message_size, || {
+            xchacha.encrypt(&key, &plaintext, aad)?;
+            Ok(())
+        })?,
+        probe("AES-256-GCM encrypt", duration, message_size, || {
+            aes_gcm.encrypt(&key, &plaintext, aad)?;
+            Ok(())
+        })?,
+        probe("Ratchet encrypt + decrypt", duration, message_size, || {
+            if alice.sending_chain_length() >= MAX_CHAIN_LENGTH {
+                alice.rekey_sending_chain()?;
+            }
+            let message = alice.encrypt(&plaintext)?;
+            bob.decrypt(&message)?;
+            Ok(())
+        })?,
+        probe("X3DH establish", duration, 0, || {
+            let mut prekeys = PreKeyManager::new(IdentityKeyPair::generate());
+            prekeys.generate_one_time_prekeys(1);
+            let bundle = prekeys.get_bundle();
+
+            let (secret, ephemeral, opk_id) = X3DHKeyAgreement::initiate(&identity, &bundle)?;
+            X3DHKeyAgreement::respond(
+                &mut prekeys,
+                &identity_public,
+                &ephemeral,
+                opk_id,
+                secret.session_nonce(),
+            )?;
+            Ok(())
+        })?,
+    ])
+}
+
+/// Repeat `op` until `duration` has passed, at least once
+fn probe(
+    name: &'static str,
+    duration: Duration,
+    bytes_per_op: usize,
+    mut op: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<ProbeResult> {
+    let start = Instant::now();
+    let mut operations = 0;
+    loop {
+        op()?;
+        operations += 1;
+        if start.elapsed() >= duration {
+            break;
+        }
+    }
+
+    Ok(ProbeResult {
+        name,
+        operations,
+        elapsed: start.elapsed(),
+        bytes_per_op,
+    })
+}
+
+/// Print one line per probe
+pub fn write(out: &mut impl Write, results: &[ProbeResult]) -> std::io::Result<()> {
+    for result in results {
+        let mb = result
+            .mb_per_sec()
+            .map(|mb| format!("{:>9.2} MB/sec", mb))
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "  {:<28} {:>12.0} msg/sec {}",
+            style(result.name).bold(),
+            result.ops_per_sec(),
+            mb
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_reports_positive_throughput() {
+        let results = run(Duration::from_millis(20), 256).unwrap();
+        assert_eq!(results.len(), 4);
+
+        for result in &results {
+            assert!(result.operations > 0, "{}", result.name);
+            assert!(result.ops_per_sec() > 0.0, "{}", result.name);
+            if result.bytes_per_op > 0 {
+                assert!(result.mb_per_sec().unwrap() > 0.0, "{}", result.name);
+            }
+        }
+
+        let mut out = Vec::new();
+        write(&mut out, &results).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert_eq!(report.lines().count(), 4);
+        assert_eq!(report.matches("MB/sec").count(), 3);
+    }
+}
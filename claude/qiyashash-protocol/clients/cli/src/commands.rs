@@ -4,4 +4,273 @@
 //! Currently, the main implementations are in main.rs, but this module
 //! can be expanded for more complex command logic.
 
-// Future command implementations can be added here
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use console::style;
+
+use qiyashash_core::storage::memory::MemoryStorage;
+use qiyashash_core::storage::Storage;
+use qiyashash_core::types::UserId;
+use qiyashash_crypto::identity::Identity;
+use qiyashash_protocol::{ClientConfig, ProtocolClient, SessionInfo};
+
+use crate::output::{write_json, IdentityJson, OutputFormat, SessionJson, SessionsJson};
+use crate::storage::LocalStorage;
+
+/// Protocol client whose state lives in a [`LocalStorage`]
+pub struct LocalClient {
+    /// The client
+    pub client: ProtocolClient<MemoryStorage>,
+    /// Sessions, prekeys and messages of the client
+    state: Arc<MemoryStorage>,
+}
+
+impl LocalClient {
+    /// Write the client's state back to `storage`
+    pub async fn save(&self, storage: &LocalStorage) -> anyhow::Result<()> {
+        storage.save_protocol_state(&self.state.export_snapshot().await?)
+    }
+}
+
+/// Protocol client acting as the identity in `storage`
+///
+/// Sessions, prekeys and messages are loaded from `storage`, and saved back
+/// once the client is initialized.
+pub async fn protocol_client(storage: &LocalStorage) -> anyhow::Result<LocalClient> {
+    let identity = storage
+        .get_identity()?
+        .ok_or_else(|| anyhow::anyhow!("No identity found. Run 'qiyashash init' first."))?;
+
+    let state = MemoryStorage::new();
+    if let Some(snapshot) = storage.get_protocol_state()? {
+        state.import_snapshot(snapshot).await?;
+    }
+
+    let user_id = UserId::from_string(hex::encode(&identity.fingerprint[..16]));
+    let client = ProtocolClient::new(ClientConfig::default(), state.clone())
+        .with_ids(user_id, storage.device_id()?)
+        .with_identity(identity);
+    client.initialize().await?;
+
+    let local = LocalClient { client, state };
+    local.save(storage).await?;
+    Ok(local)
+}
+
+/// Print the sessions of the identity in `storage`
+pub async fn show_sessions(
+    out: &mut impl Write,
+    storage: &LocalStorage,
+    verbose: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let local = protocol_client(storage).await?;
+    write_sessions(out, &local.client, verbose, format)
+}
+
+/// Print `identity`
+///
+/// `show_fingerprint` only affects human output; JSON always includes the
+/// fingerprint.
+pub fn write_identity(
+    out: &mut impl Write,
+    identity: &Identity,
+    show_fingerprint: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let info = IdentityJson::from(identity);
+    if format == OutputFormat::Json {
+        return write_json(out, &info);
+    }
+
+    writeln!(out, "{} Identity Information", crate::KEY)?;
+    writeln!(out)?;
+    writeln!(out, "  User ID:     {}", style(&info.user_id).cyan())?;
+
+    if show_fingerprint {
+        // Format fingerprint in groups
+        let formatted: String = info
+            .fingerprint
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(4)
+            .map(|c| c.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        writeln!(out, "  Fingerprint: {}", style(&formatted).yellow())?;
+    }
+
+    writeln!(
+        out,
+        "  Created:     {}",
+        chrono::DateTime::from_timestamp(identity.created_at, 0)
+            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| "Unknown".to_string())
+    )?;
+    Ok(())
+}
+
+/// Print the active sessions of `client`
+///
+/// With `verbose`, human output also shows the ratchet key fingerprint and
+/// chain sequence of each session.
+pub fn write_sessions<S: Storage + 'static>(
+    out: &mut impl Write,
+    client: &ProtocolClient<S>,
+    verbose: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let sessions = client.sessions()?;
+    if format == OutputFormat::Json {
+        let sessions = sessions.iter().map(SessionJson::from).collect();
+        return write_json(out, &SessionsJson { sessions });
+    }
+
+    writeln!(out, "Active Sessions:")?;
+    if sessions.is_empty() {
+        writeln!(out, "  (No active sessions)")?;
+        return Ok(());
+    }
+
+    for session in &sessions {
+        write_session(out, session, verbose)?;
+    }
+    Ok(())
+}
+
+fn write_session(out: &mut impl Write, session: &SessionInfo, verbose: bool) -> io::Result<()> {
+    let created = chrono::DateTime::from_timestamp_millis(session.created_at.as_millis())
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "Unknown".to_string());
+
+    writeln!(out, "  Peer:          {}", session.their_user_id)?;
+    writeln!(out, "    Device:      {}", session.their_device_id)?;
+    writeln!(out, "    Created:     {}", created)?;
+    writeln!(out, "    Messages:    {}", session.message_count)?;
+    writeln!(
+        out,
+        "    Skipped keys: {}/{}",
+        session.health.skipped_key_count, session.health.max_skipped_keys
+    )?;
+    writeln!(
+        out,
+        "    Re-key:      {}",
+        if session.needs_rekey { "needed" } else { "not needed" }
+    )?;
+
+    if verbose {
+        let fingerprint = session
+            .ratchet_key_fingerprint
+            .as_ref()
+            .map(|f| f.to_hex()[..32].to_string())
+            .unwrap_or_else(|| "(none)".to_string());
+        writeln!(out, "    Ratchet key: {}", fingerprint)?;
+        writeln!(out, "    Chain seq:   {}", session.chain_sequence)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qiyashash_protocol::test_support::establish_paired_clients;
+
+    #[tokio::test]
+    async fn test_sessions_report_established_session() {
+        let pair = establish_paired_clients().await;
+        pair.assert_delivers(&pair.alice, &pair.bob, "hello").await;
+        pair.assert_delivers(&pair.bob, &pair.alice, "hi").await;
+        pair.assert_delivers(&pair.alice, &pair.bob, "bye").await;
+
+        let mut out = Vec::new();
+        write_sessions(&mut out, &pair.alice, true, OutputFormat::Human).unwrap();
+        let report = String::from_utf8(out).unwrap();
+
+        assert_eq!(report.matches("Peer:").count(), 1);
+        assert!(report.contains(&format!("Peer:          {}", pair.bob.user_id())));
+        assert!(report.contains(&format!("Device:      {}", pair.bob.device_id())));
+        assert!(report.contains("Messages:    3"));
+        assert!(report.contains("Ratchet key: "));
+        assert!(!report.contains("(No active sessions)"));
+    }
+
+    #[tokio::test]
+    async fn test_sessions_json() {
+        let pair = establish_paired_clients().await;
+        pair.assert_delivers(&pair.alice, &pair.bob, "hello").await;
+
+        let mut out = Vec::new();
+        write_sessions(&mut out, &pair.alice, false, OutputFormat::Json).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        let sessions = report["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["user_id"], pair.bob.user_id().to_string());
+        assert_eq!(sessions[0]["message_count"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_show_sessions_loads_stored_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::new();
+        let user_id = hex::encode(&identity.fingerprint[..16]);
+        let peer = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        peer.initialize().await.unwrap();
+        {
+            let storage = LocalStorage::open(dir.path()).unwrap();
+            storage.save_identity(&identity, "laptop").unwrap();
+
+            let local = protocol_client(&storage).await.unwrap();
+            assert_eq!(local.client.user_id().to_string(), user_id);
+            assert_eq!(local.client.fingerprint().unwrap().as_bytes(), &identity.fingerprint);
+            let bundle = peer.device_prekey_bundle().unwrap();
+            local
+                .client
+                .establish_session(peer.user_id(), peer.device_id(), &bundle)
+                .await
+                .unwrap();
+            local.save(&storage).await.unwrap();
+        }
+
+        // A later run sees the session, as the same user and device
+        let storage = LocalStorage::open(dir.path()).unwrap();
+        let mut out = Vec::new();
+        show_sessions(&mut out, &storage, false, OutputFormat::Json).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let sessions = report["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["user_id"], peer.user_id().to_string());
+
+        let local = protocol_client(&storage).await.unwrap();
+        assert_eq!(local.client.user_id().to_string(), user_id);
+        assert_eq!(local.client.device_id(), &storage.device_id().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_show_sessions_requires_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::open(dir.path()).unwrap();
+
+        let err = show_sessions(&mut Vec::<u8>::new(), &storage, false, OutputFormat::Human)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No identity found"), "{}", err);
+    }
+
+    #[test]
+    fn test_identity_json() {
+        let identity = Identity::new();
+
+        let mut out = Vec::new();
+        write_identity(&mut out, &identity, false, OutputFormat::Json).unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(info["user_id"], hex::encode(&identity.fingerprint[..16]));
+        assert_eq!(info["fingerprint"], hex::encode(identity.fingerprint));
+        assert!(info["created_at"].is_string());
+        // Nothing but the one object
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1);
+    }
+}
@@ -1,10 +1,14 @@
 //! CLI configuration
 
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn, Level};
 
 /// CLI configuration
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CliConfig {
     /// Storage path
     pub storage_path: PathBuf,
@@ -14,6 +18,16 @@ pub struct CliConfig {
     pub auto_connect: bool,
     /// Show notifications
     pub notifications: bool,
+    /// Relay node URLs
+    #[serde(default)]
+    pub relay_nodes: Vec<String>,
+    /// Log level (trace, debug, info, warn, error)
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
 }
 
 impl Default for CliConfig {
@@ -27,6 +41,8 @@ impl Default for CliConfig {
             server_url: None,
             auto_connect: false,
             notifications: true,
+            relay_nodes: Vec::new(),
+            log_level: default_log_level(),
         }
     }
 }
@@ -37,6 +53,7 @@ impl CliConfig {
         if path.exists() {
             let content = std::fs::read_to_string(path)?;
             let config: CliConfig = toml::from_str(&content)?;
+            config.validate()?;
             Ok(config)
         } else {
             let config = Self::default();
@@ -60,4 +77,194 @@ impl CliConfig {
         std::fs::write(path, content)?;
         Ok(())
     }
+
+    /// Check field values
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.level()?;
+        for url in &self.relay_nodes {
+            if !url.contains("://") || url.chars().any(char::is_whitespace) {
+                anyhow::bail!("Invalid relay URL: {:?}", url);
+            }
+        }
+        Ok(())
+    }
+
+    /// Configured log level
+    pub fn level(&self) -> anyhow::Result<Level> {
+        Level::from_str(&self.log_level)
+            .map_err(|_| anyhow::anyhow!("Invalid log level: {:?}", self.log_level))
+    }
+
+    /// Take the hot-reloadable fields from `new`
+    ///
+    /// Fields that only take effect at startup keep their current values;
+    /// a change to one is reported with a warning.
+    fn apply_reload(&mut self, new: CliConfig) {
+        if new.storage_path != self.storage_path {
+            warn!(
+                "storage_path changed to {} but needs a restart; keeping {}",
+                new.storage_path.display(),
+                self.storage_path.display()
+            );
+        }
+        self.relay_nodes = new.relay_nodes;
+        self.log_level = new.log_level;
+    }
+}
+
+/// Called with the new log level after a reload
+pub type LogLevelHandler = Box<dyn Fn(Level) + Send + Sync>;
+
+/// Reloads the config file when it changes
+///
+/// Only the relay list and log level are applied while running. Edits that
+/// fail to parse or validate are ignored and the previous config is kept.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    config: Arc<RwLock<CliConfig>>,
+    on_log_level: Arc<RwLock<Option<LogLevelHandler>>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    /// Watch `path`, starting from the already loaded `config`
+    pub fn watch(path: &Path, config: CliConfig) -> anyhow::Result<Self> {
+        let path = path.to_path_buf();
+        let config = Arc::new(RwLock::new(config));
+        let on_log_level: Arc<RwLock<Option<LogLevelHandler>>> = Arc::new(RwLock::new(None));
+
+        let (watched_path, watched_config, watched_handler) =
+            (path.clone(), config.clone(), on_log_level.clone());
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    if event.paths.iter().any(|p| p.file_name() == watched_path.file_name()) {
+                        reload(&watched_path, &watched_config, &watched_handler);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Config watch error: {}", e),
+            }
+        })?;
+
+        // Editors often replace the file rather than write to it, so watch
+        // the directory
+        let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            path,
+            config,
+            on_log_level,
+            _watcher: watcher,
+        })
+    }
+
+    /// Call `handler` whenever a reload changes the log level
+    pub fn on_log_level(self, handler: impl Fn(Level) + Send + Sync + 'static) -> Self {
+        *self.on_log_level.write().unwrap() = Some(Box::new(handler));
+        self
+    }
+
+    /// Current config
+    pub fn current(&self) -> CliConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Reload now instead of waiting for a change event
+    pub fn reload(&self) -> bool {
+        reload(&self.path, &self.config, &self.on_log_level)
+    }
+}
+
+/// Read `path` and apply it to `config`; false if the file was rejected
+fn reload(
+    path: &Path,
+    config: &RwLock<CliConfig>,
+    on_log_level: &RwLock<Option<LogLevelHandler>>,
+) -> bool {
+    let new = match std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(toml::from_str::<CliConfig>(&content)?))
+        .and_then(|new| new.validate().map(|()| new))
+    {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("Ignoring config change in {}: {}", path.display(), e);
+            return false;
+        }
+    };
+
+    let mut config = config.write().unwrap();
+    let old_level = config.log_level.clone();
+    config.apply_reload(new);
+    if config.log_level != old_level {
+        if let (Ok(level), Some(handler)) = (config.level(), on_log_level.read().unwrap().as_ref()) {
+            handler(level);
+        }
+    }
+    info!("Reloaded config from {}", path.display());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_for(watcher: &ConfigWatcher, done: impl Fn(&CliConfig) -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if done(&watcher.current()) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn test_config_hot_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let config = CliConfig {
+            storage_path: dir.path().join("data"),
+            ..CliConfig::default()
+        };
+        config.save(&path).unwrap();
+
+        let levels = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = levels.clone();
+        let watcher = ConfigWatcher::watch(&path, config.clone())
+            .unwrap()
+            .on_log_level(move |level| seen.lock().unwrap().push(level));
+
+        let relays = vec!["quic://relay1.example:4433".to_string()];
+        let edited = CliConfig {
+            relay_nodes: relays.clone(),
+            log_level: "debug".to_string(),
+            // Not hot-reloadable
+            storage_path: dir.path().join("elsewhere"),
+            ..config.clone()
+        };
+        edited.save(&path).unwrap();
+        assert!(wait_for(&watcher, |c| c.relay_nodes == relays));
+
+        let current = watcher.current();
+        assert_eq!(current.log_level, "debug");
+        assert_eq!(current.storage_path, config.storage_path);
+        assert_eq!(*levels.lock().unwrap(), vec![Level::DEBUG]);
+
+        // Invalid edits leave the previous config in place
+        std::fs::write(&path, "relay_nodes = [").unwrap();
+        assert!(!watcher.reload());
+        let invalid = CliConfig {
+            relay_nodes: vec!["not a url".to_string()],
+            ..edited
+        };
+        invalid.save(&path).unwrap();
+        assert!(!watcher.reload());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(watcher.current(), current);
+    }
 }
@@ -0,0 +1,407 @@
+//! Self-diagnosis for `qiyashash doctor`
+//!
+//! Each check reports pass, warn or fail with a hint on how to fix it. Only
+//! failures of critical checks (identity and storage) make the command
+//! exit non-zero; the rest degrade messaging without breaking it.
+
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use console::style;
+
+use qiyashash_protocol::{ClientConfig, KeyRotationPolicy, SessionInfo};
+
+use crate::commands::protocol_client;
+use crate::config::CliConfig;
+use crate::storage::LocalStorage;
+
+/// How long a server or relay has to answer
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Outcome of one check
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// Healthy
+    Pass,
+    /// Works, but needs attention
+    Warn,
+    /// Broken
+    Fail,
+}
+
+/// Result of one check
+#[derive(Clone, Debug)]
+pub struct CheckResult {
+    /// Check name
+    pub name: &'static str,
+    /// Outcome
+    pub status: CheckStatus,
+    /// What was found
+    pub detail: String,
+    /// How to fix it
+    pub hint: Option<&'static str>,
+    /// Whether a failure makes the command exit non-zero
+    pub critical: bool,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+            critical: false,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: CheckStatus::Warn,
+            hint: Some(hint),
+            ..Self::pass(name, detail)
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: CheckStatus::Fail,
+            hint: Some(hint),
+            ..Self::pass(name, detail)
+        }
+    }
+
+    fn critical(mut self) -> Self {
+        self.critical = true;
+        self
+    }
+}
+
+/// All check results
+#[derive(Clone, Debug, Default)]
+pub struct DoctorReport {
+    /// Results in the order the checks ran
+    pub checks: Vec<CheckResult>,
+}
+
+impl DoctorReport {
+    /// Whether a critical check failed
+    pub fn has_critical_failure(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|c| c.critical && c.status == CheckStatus::Fail)
+    }
+
+    /// Result of the check called `name`
+    pub fn check(&self, name: &str) -> Option<&CheckResult> {
+        self.checks.iter().find(|c| c.name == name)
+    }
+
+    /// Print the report
+    pub fn write(&self, out: &mut impl Write) -> std::io::Result<()> {
+        for check in &self.checks {
+            let label = match check.status {
+                CheckStatus::Pass => style("PASS").green(),
+                CheckStatus::Warn => style("WARN").yellow(),
+                CheckStatus::Fail => style("FAIL").red(),
+            };
+            writeln!(out, "[{}] {}: {}", label, check.name, check.detail)?;
+            if let Some(hint) = check.hint {
+                writeln!(out, "       {}", style(hint).dim())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run every check against the store at `storage_path`
+pub async fn run(storage_path: &Path, config: &CliConfig) -> DoctorReport {
+    let mut report = DoctorReport::default();
+
+    let storage = match LocalStorage::open(storage_path) {
+        Ok(storage) => storage,
+        Err(e) => {
+            report.checks.push(
+                CheckResult::fail(
+                    "storage",
+                    format!("Cannot open {}: {}", storage_path.display(), e),
+                    "Check permissions, or restore the data directory from a backup",
+                )
+                .critical(),
+            );
+            return report;
+        }
+    };
+
+    report.checks.push(check_storage(&storage));
+    report.checks.push(check_identity(&storage));
+    report.checks.extend(check_prekeys(&storage));
+    report.checks.push(check_chains(&storage).await);
+    report.checks.extend(check_reachability(config).await);
+    report
+}
+
+fn check_storage(storage: &LocalStorage) -> CheckResult {
+    match storage.stats() {
+        Ok(stats) => CheckResult::pass(
+            "storage",
+            format!("{} entries, {} bytes", stats.entries, stats.size_on_disk),
+        ),
+        Err(e) => CheckResult::fail(
+            "storage",
+            format!("Cannot read stored data: {}", e),
+            "Restore the data directory from a backup, or run 'qiyashash import'",
+        ),
+    }
+    .critical()
+}
+
+fn check_identity(storage: &LocalStorage) -> CheckResult {
+    match storage.get_identity() {
+        Ok(Some(identity)) => CheckResult::pass(
+            "identity",
+            format!("Loaded {}", hex::encode(&identity.fingerprint[..16])),
+        ),
+        Ok(None) => CheckResult::fail(
+            "identity",
+            "No identity found",
+            "Run 'qiyashash init' or 'qiyashash import'",
+        ),
+        Err(e) => CheckResult::fail(
+            "identity",
+            format!("Identity does not load: {}", e),
+            "Restore it with 'qiyashash import'",
+        ),
+    }
+    .critical()
+}
+
+fn check_prekeys(storage: &LocalStorage) -> Vec<CheckResult> {
+    let state = match storage.get_prekey_state() {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            return vec![CheckResult::warn(
+                "prekeys",
+                "No prekeys recorded",
+                "Run 'qiyashash init' to generate prekeys",
+            )]
+        }
+        Err(e) => {
+            return vec![CheckResult::warn(
+                "prekeys",
+                format!("Prekey state does not load: {}", e),
+                "Run 'qiyashash init' to generate prekeys",
+            )]
+        }
+    };
+
+    let threshold = ClientConfig::default().prekey_refresh_threshold;
+    let count = state.one_time_prekey_ids.len();
+    let one_time = if count < threshold {
+        CheckResult::warn(
+            "one-time prekeys",
+            format!("{} left, below {}", count, threshold),
+            "New contacts fall back to the signed prekey; replenish one-time prekeys",
+        )
+    } else {
+        CheckResult::pass("one-time prekeys", format!("{} available", count))
+    };
+
+    let max_age = KeyRotationPolicy::default().max_signed_prekey_age_secs;
+    let age = chrono::Utc::now().timestamp() - state.signed_prekey_created_at;
+    let days = age / (24 * 3600);
+    let signed = if age > max_age {
+        CheckResult::warn(
+            "signed prekey",
+            format!("{} days old, older than {}", days, max_age / (24 * 3600)),
+            "Rotate the signed prekey",
+        )
+    } else {
+        CheckResult::pass("signed prekey", format!("{} days old", days))
+    };
+
+    vec![one_time, signed]
+}
+
+async fn check_chains(storage: &LocalStorage) -> CheckResult {
+    // Without an identity there are no sessions; the identity check says why
+    if !storage.has_identity().unwrap_or(false) {
+        return CheckResult::warn(
+            "message chains",
+            "No identity to load sessions for",
+            "Run 'qiyashash init' or 'qiyashash import'",
+        );
+    }
+
+    let sessions = match protocol_client(storage).await.and_then(|local| Ok(local.client.sessions()?)) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            return CheckResult::fail(
+                "message chains",
+                format!("Sessions do not load: {}", e),
+                "Restore the data directory from a backup",
+            )
+        }
+    };
+    check_sessions(&sessions)
+}
+
+fn check_sessions(sessions: &[SessionInfo]) -> CheckResult {
+    let broken: Vec<_> = sessions
+        .iter()
+        .filter(|s| !s.chain_intact)
+        .map(|s| s.their_user_id.to_string())
+        .collect();
+
+    if broken.is_empty() {
+        CheckResult::pass(
+            "message chains",
+            format!("{} conversations verified", sessions.len()),
+        )
+    } else {
+        CheckResult::fail(
+            "message chains",
+            format!("Chain does not verify with {}", broken.join(", ")),
+            "Messages may have been tampered with or lost; reset the affected sessions",
+        )
+    }
+}
+
+async fn check_reachability(config: &CliConfig) -> Vec<CheckResult> {
+    let endpoints: Vec<_> = config
+        .server_url
+        .iter()
+        .map(|url| ("server", url))
+        .chain(config.relay_nodes.iter().map(|url| ("relay", url)))
+        .collect();
+
+    if endpoints.is_empty() {
+        return vec![CheckResult::warn(
+            "network",
+            "No server or relays configured",
+            "Set server_url or relay_nodes in the config file",
+        )];
+    }
+
+    let mut results = Vec::with_capacity(endpoints.len());
+    for (name, url) in endpoints {
+        results.push(match probe(url).await {
+            Ok(()) => CheckResult::pass(name, format!("{} reachable", url)),
+            Err(e) => CheckResult::warn(
+                name,
+                format!("{} unreachable: {}", url, e),
+                "Check your connection and the URL in the config file",
+            ),
+        });
+    }
+    results
+}
+
+/// Resolve `url`, and for TCP schemes connect to it
+///
+/// QUIC relays run over UDP, which has no handshake to probe without
+/// speaking the protocol, so for those a successful lookup has to do.
+async fn probe(url: &str) -> anyhow::Result<()> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("missing scheme"))?;
+    let authority = rest.split('/').next().unwrap_or_default();
+    let default_port = match scheme {
+        "https" | "wss" => 443,
+        "http" | "ws" => 80,
+        _ => 4433,
+    };
+    let address = if authority.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, default_port)
+    };
+
+    let lookup = tokio::time::timeout(REACHABILITY_TIMEOUT, tokio::net::lookup_host(&address))
+        .await
+        .map_err(|_| anyhow::anyhow!("lookup timed out"))??;
+    let addrs: Vec<_> = lookup.collect();
+    if addrs.is_empty() {
+        anyhow::bail!("no addresses");
+    }
+
+    if scheme == "quic" {
+        return Ok(());
+    }
+    tokio::time::timeout(REACHABILITY_TIMEOUT, tokio::net::TcpStream::connect(&addrs[..]))
+        .await
+        .map_err(|_| anyhow::anyhow!("connection timed out"))??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::PreKeyState;
+
+    #[tokio::test]
+    async fn test_doctor_fails_without_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = run(dir.path(), &CliConfig::default()).await;
+
+        assert_eq!(report.check("identity").unwrap().status, CheckStatus::Fail);
+        assert_eq!(report.check("storage").unwrap().status, CheckStatus::Pass);
+        assert!(report.has_critical_failure());
+    }
+
+    #[tokio::test]
+    async fn test_doctor_passes_on_fresh_store() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let storage = LocalStorage::open(dir.path()).unwrap();
+            let identity = qiyashash_crypto::identity::Identity::new();
+            storage.save_identity(&identity, "laptop").unwrap();
+            storage
+                .save_prekey_state(&PreKeyState {
+                    one_time_prekey_ids: (1..=100).collect(),
+                    signed_prekey_created_at: chrono::Utc::now().timestamp(),
+                })
+                .unwrap();
+        }
+
+        let report = run(dir.path(), &CliConfig::default()).await;
+
+        assert!(!report.has_critical_failure());
+        for name in ["storage", "identity", "one-time prekeys", "signed prekey", "message chains"] {
+            assert_eq!(report.check(name).unwrap().status, CheckStatus::Pass, "{}", name);
+        }
+        // Nothing configured to reach
+        assert_eq!(report.check("network").unwrap().status, CheckStatus::Warn);
+    }
+
+    #[tokio::test]
+    async fn test_doctor_verifies_stored_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let peer = qiyashash_protocol::ProtocolClient::new(
+            ClientConfig::default(),
+            qiyashash_core::storage::memory::MemoryStorage::new(),
+        );
+        peer.initialize().await.unwrap();
+        {
+            let storage = LocalStorage::open(dir.path()).unwrap();
+            let identity = qiyashash_crypto::identity::Identity::new();
+            storage.save_identity(&identity, "laptop").unwrap();
+
+            let local = protocol_client(&storage).await.unwrap();
+            let bundle = peer.device_prekey_bundle().unwrap();
+            local
+                .client
+                .establish_session(peer.user_id(), peer.device_id(), &bundle)
+                .await
+                .unwrap();
+            local.save(&storage).await.unwrap();
+        }
+
+        let report = run(dir.path(), &CliConfig::default()).await;
+
+        let chains = report.check("message chains").unwrap();
+        assert_eq!(chains.status, CheckStatus::Pass);
+        assert_eq!(chains.detail, "1 conversations verified");
+    }
+}
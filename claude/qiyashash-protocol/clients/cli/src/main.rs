@@ -8,15 +8,22 @@ use dialoguer::{Confirm, Input, Password};
 use indicatif::{ProgressBar, ProgressStyle};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{info, warn, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload};
 
+mod bench;
 mod commands;
 mod config;
+mod doctor;
+mod output;
 mod storage;
 
-use config::CliConfig;
-use storage::LocalStorage;
+use config::{CliConfig, ConfigWatcher};
+use output::{ConversationsJson, ContactsJson, DoctorJson, ErrorJson, OutputFormat};
+use storage::{LocalStorage, PreKeyState};
 
 static LOCK: Emoji<'_, '_> = Emoji("🔐 ", "");
 static CHECK: Emoji<'_, '_> = Emoji("✅ ", "[OK] ");
@@ -38,6 +45,10 @@ struct Cli {
     #[arg(short, long)]
     verbose: bool,
 
+    /// Print JSON instead of text (identity, sessions, list, contacts list, doctor)
+    #[arg(long, global = true)]
+    json: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -132,6 +143,27 @@ enum Commands {
         #[command(subcommand)]
         action: ServerAction,
     },
+
+    /// Diagnose common problems
+    Doctor,
+
+    /// Upgrade storage to the current layout
+    Migrate {
+        /// Target schema version (defaults to the current one)
+        #[arg(long)]
+        to: Option<u32>,
+    },
+
+    /// Measure crypto throughput on this device
+    #[command(hide = true)]
+    Bench {
+        /// Seconds to run each probe
+        #[arg(long, default_value = "1")]
+        seconds: u64,
+        /// Message size in bytes
+        #[arg(long, default_value = "1024")]
+        size: usize,
+    },
 }
 
 #[derive(Subcommand)]
@@ -179,21 +211,22 @@ enum ServerAction {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-
-    // Initialize logging
-    let log_level = if cli.verbose {
-        Level::DEBUG
+    let format = if cli.json {
+        OutputFormat::Json
     } else {
-        Level::INFO
+        OutputFormat::Human
     };
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(log_level)
-        .with_target(false)
-        .without_time()
-        .finish();
+    match run(cli, format).await {
+        Err(e) if format == OutputFormat::Json => {
+            output::write_json(&mut std::io::stderr().lock(), &ErrorJson::from(&e))?;
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
 
-    tracing::subscriber::set_global_default(subscriber)?;
+async fn run(cli: Cli, format: OutputFormat) -> anyhow::Result<()> {
 
     // Load config
     let config_path = cli.config.unwrap_or_else(|| {
@@ -205,6 +238,54 @@ async fn main() -> anyhow::Result<()> {
 
     let config = CliConfig::load_or_default(&config_path)?;
 
+    // Initialize logging; --verbose wins over the configured level
+    let verbose = cli.verbose;
+    let log_level = if verbose {
+        Level::DEBUG
+    } else {
+        config.level()?
+    };
+
+    // Keep stdout for the JSON document
+    let writer = match format {
+        OutputFormat::Human => BoxMakeWriter::new(std::io::stdout),
+        OutputFormat::Json => BoxMakeWriter::new(std::io::stderr),
+    };
+
+    let (filter, filter_handle) = reload::Layer::new(LevelFilter::from_level(log_level));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_target(false).without_time().with_writer(writer))
+        .try_init()?;
+
+    // Pick up relay and log level changes while running; without a watch
+    // the startup config stays in effect
+    let config_watcher = match ConfigWatcher::watch(&config_path, config.clone()) {
+        Ok(watcher) => Some(watcher.on_log_level(move |level| {
+            if !verbose {
+                let _ = filter_handle.modify(|filter| *filter = LevelFilter::from_level(level));
+            }
+        })),
+        Err(e) => {
+            warn!("Not watching {} for changes: {}", config_path.display(), e);
+            None
+        }
+    };
+    // Relays are read from here, so commands see edits made since startup
+    let config = config_watcher.as_ref().map_or(config, ConfigWatcher::current);
+
+    // The doctor opens storage itself, to report if that fails
+    if let Commands::Doctor = cli.command {
+        return run_doctor(&config, format).await;
+    }
+    // Likewise the migration, which must see the store before it is upgraded
+    if let Commands::Migrate { to } = cli.command {
+        return migrate_storage(&config, to);
+    }
+    if let Commands::Bench { seconds, size } = cli.command {
+        return run_bench(seconds, size);
+    }
+
     // Initialize storage
     let storage_path = config.storage_path.clone();
     let storage = LocalStorage::open(&storage_path)?;
@@ -215,7 +296,7 @@ async fn main() -> anyhow::Result<()> {
             init_identity(&storage, name).await?;
         }
         Commands::Identity { fingerprint } => {
-            show_identity(&storage, fingerprint).await?;
+            show_identity(&storage, fingerprint, format).await?;
         }
         Commands::Rotate { force } => {
             rotate_identity(&storage, force).await?;
@@ -227,16 +308,16 @@ async fn main() -> anyhow::Result<()> {
             receive_messages(&storage, count).await?;
         }
         Commands::List { all } => {
-            list_conversations(&storage, all).await?;
+            list_conversations(&storage, all, format).await?;
         }
         Commands::Contacts { action } => {
-            handle_contacts(&storage, action).await?;
+            handle_contacts(&storage, action, format).await?;
         }
         Commands::Verify { user_id } => {
             verify_contact(&storage, &user_id).await?;
         }
         Commands::Sessions { verbose } => {
-            show_sessions(&storage, verbose).await?;
+            show_sessions(&storage, verbose, format).await?;
         }
         Commands::Export { output } => {
             export_identity(&storage, &output).await?;
@@ -247,6 +328,9 @@ async fn main() -> anyhow::Result<()> {
         Commands::Server { action } => {
             handle_server(&storage, action).await?;
         }
+        Commands::Doctor | Commands::Migrate { .. } | Commands::Bench { .. } => {
+            unreachable!("handled before storage is opened")
+        }
     }
 
     Ok(())
@@ -294,11 +378,17 @@ async fn init_identity(storage: &LocalStorage, name: Option<String>) -> anyhow::
     pb.set_message("Generating prekeys...");
     let mut prekey_manager =
         qiyashash_crypto::x3dh::PreKeyManager::new(identity.key_pair.clone());
-    prekey_manager.generate_one_time_prekeys(100);
+    let one_time_prekeys = prekey_manager.generate_one_time_prekeys(100);
 
     // Save to storage
     pb.set_message("Saving identity...");
     storage.save_identity(&identity, &device_name)?;
+    // Sessions and prekeys belonged to the identity being replaced
+    storage.delete_protocol_state()?;
+    storage.save_prekey_state(&PreKeyState {
+        one_time_prekey_ids: one_time_prekeys.iter().map(|k| k.id).collect(),
+        signed_prekey_created_at: prekey_manager.get_bundle().signed_prekey.timestamp,
+    })?;
 
     pb.finish_and_clear();
 
@@ -320,39 +410,16 @@ async fn init_identity(storage: &LocalStorage, name: Option<String>) -> anyhow::
     Ok(())
 }
 
-async fn show_identity(storage: &LocalStorage, show_fingerprint: bool) -> anyhow::Result<()> {
+async fn show_identity(
+    storage: &LocalStorage,
+    show_fingerprint: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
     let identity = storage
         .get_identity()?
         .ok_or_else(|| anyhow::anyhow!("No identity found. Run 'qiyashash init' first."))?;
 
-    let fingerprint = hex::encode(&identity.fingerprint);
-    let user_id = hex::encode(&identity.fingerprint[..16]);
-
-    println!("{} Identity Information", KEY);
-    println!();
-    println!("  User ID:     {}", style(&user_id).cyan());
-
-    if show_fingerprint {
-        // Format fingerprint in groups
-        let formatted: String = fingerprint
-            .chars()
-            .collect::<Vec<_>>()
-            .chunks(4)
-            .map(|c| c.iter().collect::<String>())
-            .collect::<Vec<_>>()
-            .join(" ");
-
-        println!("  Fingerprint: {}", style(&formatted).yellow());
-    }
-
-    println!(
-        "  Created:     {}",
-        chrono::DateTime::from_timestamp(identity.created_at, 0)
-            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
-            .unwrap_or_else(|| "Unknown".to_string())
-    );
-
-    Ok(())
+    commands::write_identity(&mut std::io::stdout().lock(), &identity, show_fingerprint, format)
 }
 
 async fn rotate_identity(storage: &LocalStorage, force: bool) -> anyhow::Result<()> {
@@ -426,14 +493,26 @@ async fn receive_messages(storage: &LocalStorage, count: usize) -> anyhow::Resul
     Ok(())
 }
 
-async fn list_conversations(storage: &LocalStorage, all: bool) -> anyhow::Result<()> {
+async fn list_conversations(
+    storage: &LocalStorage,
+    all: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    if format == OutputFormat::Json {
+        return output::write_json(&mut std::io::stdout().lock(), &ConversationsJson::default());
+    }
+
     println!("Conversations:");
     println!("  (No conversations yet)");
 
     Ok(())
 }
 
-async fn handle_contacts(storage: &LocalStorage, action: ContactAction) -> anyhow::Result<()> {
+async fn handle_contacts(
+    storage: &LocalStorage,
+    action: ContactAction,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
     match action {
         ContactAction::Add { user_id, alias } => {
             println!("{} Adding contact: {}", CHECK, user_id);
@@ -441,6 +520,9 @@ async fn handle_contacts(storage: &LocalStorage, action: ContactAction) -> anyho
         ContactAction::Remove { user_id } => {
             println!("{} Removing contact: {}", CHECK, user_id);
         }
+        ContactAction::List if format == OutputFormat::Json => {
+            output::write_json(&mut std::io::stdout().lock(), &ContactsJson::default())?;
+        }
         ContactAction::List => {
             println!("Contacts:");
             println!("  (No contacts yet)");
@@ -477,10 +559,64 @@ async fn verify_contact(storage: &LocalStorage, user_id: &str) -> anyhow::Result
     Ok(())
 }
 
-async fn show_sessions(storage: &LocalStorage, verbose: bool) -> anyhow::Result<()> {
-    println!("Active Sessions:");
-    println!("  (No active sessions)");
+async fn show_sessions(
+    storage: &LocalStorage,
+    verbose: bool,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    commands::show_sessions(&mut std::io::stdout().lock(), storage, verbose, format).await
+}
+
+async fn run_doctor(config: &CliConfig, format: OutputFormat) -> anyhow::Result<()> {
+    if format == OutputFormat::Human {
+        println!("{} Running diagnostics...", KEY);
+        println!();
+    }
+
+    let report = doctor::run(&config.storage_path, config).await;
+
+    if format == OutputFormat::Json {
+        output::write_json(&mut std::io::stdout().lock(), &DoctorJson::from(&report))?;
+        if report.has_critical_failure() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    report.write(&mut std::io::stdout().lock())?;
+
+    println!();
+    if report.has_critical_failure() {
+        println!("{} Critical problems found", CROSS);
+        std::process::exit(1);
+    }
+    println!("{} No critical problems found", CHECK);
+    Ok(())
+}
+
+fn migrate_storage(config: &CliConfig, to: Option<u32>) -> anyhow::Result<()> {
+    let storage = LocalStorage::open_unmigrated(&config.storage_path)?;
+    let from = storage.schema_version()?;
+    let to = to.unwrap_or(qiyashash_core::storage::SCHEMA_VERSION);
+
+    match storage.migrate_to(to)? {
+        Some(backup) => {
+            println!("{} Migrated storage from schema v{} to v{}", CHECK, from, to);
+            println!("  Backup: {}", style(backup.display()).dim());
+        }
+        None => println!("{} Storage is already at schema v{}", CHECK, from),
+    }
+    Ok(())
+}
+
+fn run_bench(seconds: u64, size: usize) -> anyhow::Result<()> {
+    println!(
+        "{} Measuring throughput ({}s per probe, {} byte messages)...",
+        KEY, seconds, size
+    );
+    println!();
 
+    let results = bench::run(std::time::Duration::from_secs(seconds), size)?;
+    bench::write(&mut std::io::stdout().lock(), &results)?;
     Ok(())
 }
 
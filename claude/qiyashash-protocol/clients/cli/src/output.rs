@@ -0,0 +1,208 @@
+//! Machine-readable output for `--json`
+//!
+//! Every type here is part of the CLI's JSON interface: fields are only ever
+//! added, never renamed or removed. Each command prints exactly one JSON
+//! object on stdout; failures print an [`ErrorJson`] on stderr instead.
+//! Timestamps are RFC 3339 strings in UTC and keys are lowercase hex.
+
+use std::io::Write;
+
+use serde::Serialize;
+
+use qiyashash_crypto::identity::Identity;
+use qiyashash_protocol::SessionInfo;
+
+use crate::doctor::{CheckStatus, DoctorReport};
+
+/// How commands print their results
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Styled text for people
+    Human,
+    /// One JSON object per command
+    Json,
+}
+
+/// Print `value` as a single line of JSON
+pub fn write_json(out: &mut impl Write, value: &impl Serialize) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn rfc3339_from_millis(millis: i64) -> Option<String> {
+    chrono::DateTime::from_timestamp_millis(millis).map(|dt| dt.to_rfc3339())
+}
+
+/// `qiyashash identity`
+#[derive(Debug, Serialize)]
+pub struct IdentityJson {
+    /// User ID shared with contacts
+    pub user_id: String,
+    /// Full identity fingerprint
+    pub fingerprint: String,
+    /// Identity public key
+    pub public_key: String,
+    /// When the identity was created, if known
+    pub created_at: Option<String>,
+}
+
+impl From<&Identity> for IdentityJson {
+    fn from(identity: &Identity) -> Self {
+        Self {
+            user_id: hex::encode(&identity.fingerprint[..16]),
+            fingerprint: hex::encode(identity.fingerprint),
+            public_key: hex::encode(identity.key_pair.public_key().to_bytes()),
+            created_at: rfc3339_from_millis(identity.created_at.saturating_mul(1000)),
+        }
+    }
+}
+
+/// `qiyashash sessions`
+#[derive(Debug, Serialize)]
+pub struct SessionsJson {
+    /// Active sessions
+    pub sessions: Vec<SessionJson>,
+}
+
+/// One active session
+#[derive(Debug, Serialize)]
+pub struct SessionJson {
+    /// Peer user
+    pub user_id: String,
+    /// Peer device
+    pub device_id: String,
+    /// When the session was established
+    pub created_at: Option<String>,
+    /// Messages sent and received
+    pub message_count: u64,
+    /// Message keys held for messages that have not arrived
+    pub skipped_keys: usize,
+    /// Most skipped keys kept before the oldest are evicted
+    pub max_skipped_keys: usize,
+    /// Whether the session is due for re-keying
+    pub needs_rekey: bool,
+    /// SHA-256 of our current ratchet public key
+    pub ratchet_key_fingerprint: Option<String>,
+    /// Sequence number of the session's message chain
+    pub chain_sequence: u64,
+    /// Whether the message chain's history verifies
+    pub chain_intact: bool,
+}
+
+impl From<&SessionInfo> for SessionJson {
+    fn from(session: &SessionInfo) -> Self {
+        Self {
+            user_id: session.their_user_id.to_string(),
+            device_id: session.their_device_id.to_string(),
+            created_at: rfc3339_from_millis(session.created_at.as_millis()),
+            message_count: session.message_count,
+            skipped_keys: session.health.skipped_key_count,
+            max_skipped_keys: session.health.max_skipped_keys,
+            needs_rekey: session.needs_rekey,
+            ratchet_key_fingerprint: session.ratchet_key_fingerprint.as_ref().map(|f| f.to_hex()),
+            chain_sequence: session.chain_sequence,
+            chain_intact: session.chain_intact,
+        }
+    }
+}
+
+/// `qiyashash list`
+#[derive(Debug, Default, Serialize)]
+pub struct ConversationsJson {
+    /// Conversations, most recent first
+    pub conversations: Vec<ConversationJson>,
+}
+
+/// One conversation
+#[derive(Debug, Serialize)]
+pub struct ConversationJson {
+    /// Peer user
+    pub user_id: String,
+    /// Messages not yet read
+    pub unread: u64,
+    /// When the last message was sent or received
+    pub last_message_at: Option<String>,
+}
+
+/// `qiyashash contacts list`
+#[derive(Debug, Default, Serialize)]
+pub struct ContactsJson {
+    /// Known contacts
+    pub contacts: Vec<ContactJson>,
+}
+
+/// One contact
+#[derive(Debug, Serialize)]
+pub struct ContactJson {
+    /// Contact user ID
+    pub user_id: String,
+    /// Local alias
+    pub alias: Option<String>,
+    /// Whether the safety number was verified
+    pub verified: bool,
+    /// Whether messages from the contact are blocked
+    pub blocked: bool,
+}
+
+/// `qiyashash doctor`
+#[derive(Debug, Serialize)]
+pub struct DoctorJson {
+    /// Whether no critical check failed
+    pub ok: bool,
+    /// Results in the order the checks ran
+    pub checks: Vec<CheckJson>,
+}
+
+/// One doctor check
+#[derive(Debug, Serialize)]
+pub struct CheckJson {
+    /// Check name
+    pub name: &'static str,
+    /// `pass`, `warn` or `fail`
+    pub status: &'static str,
+    /// What was found
+    pub detail: String,
+    /// How to fix it
+    pub hint: Option<&'static str>,
+    /// Whether a failure makes the command exit non-zero
+    pub critical: bool,
+}
+
+impl From<&DoctorReport> for DoctorJson {
+    fn from(report: &DoctorReport) -> Self {
+        Self {
+            ok: !report.has_critical_failure(),
+            checks: report
+                .checks
+                .iter()
+                .map(|check| CheckJson {
+                    name: check.name,
+                    status: match check.status {
+                        CheckStatus::Pass => "pass",
+                        CheckStatus::Warn => "warn",
+                        CheckStatus::Fail => "fail",
+                    },
+                    detail: check.detail.clone(),
+                    hint: check.hint,
+                    critical: check.critical,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Any failed command, printed on stderr
+#[derive(Debug, Serialize)]
+pub struct ErrorJson {
+    /// What went wrong
+    pub error: String,
+}
+
+impl From<&anyhow::Error> for ErrorJson {
+    fn from(error: &anyhow::Error) -> Self {
+        Self {
+            error: format!("{:#}", error),
+        }
+    }
+}
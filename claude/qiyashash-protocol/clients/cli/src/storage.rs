@@ -1,21 +1,117 @@
 //! Local storage for CLI client
 
 use sled::Db;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tracing::info;
 
+use qiyashash_core::storage::{check_migration, StorageSnapshot, SCHEMA_VERSION};
+use qiyashash_core::types::DeviceId;
 use qiyashash_crypto::identity::{Identity, IdentityKeyPair, IdentityRotationProof};
 
+/// Key of the layout version
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Key of the protocol client's sessions, prekeys and messages
+const PROTOCOL_STATE_KEY: &str = "protocol_state";
+
 /// Local storage for CLI
 pub struct LocalStorage {
     db: Db,
+    path: PathBuf,
 }
 
 impl LocalStorage {
     /// Open storage at path
+    ///
+    /// Older layouts are migrated to [`SCHEMA_VERSION`], with a backup taken
+    /// first. Stores written by a newer release are refused.
     pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let storage = Self::open_unmigrated(path)?;
+        storage.migrate_to(SCHEMA_VERSION)?;
+        Ok(storage)
+    }
+
+    /// Open storage at path without checking its layout version
+    pub fn open_unmigrated(path: impl AsRef<Path>) -> anyhow::Result<Self> {
         std::fs::create_dir_all(&path)?;
-        let db = sled::open(path)?;
-        Ok(Self { db })
+        let db = sled::open(&path)?;
+        Ok(Self {
+            db,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Layout version of the stored data
+    ///
+    /// Stores from before versioning have no marker and count as version 0;
+    /// an empty store counts as current.
+    pub fn schema_version(&self) -> anyhow::Result<u32> {
+        match self.db.get(SCHEMA_VERSION_KEY)? {
+            Some(bytes) => {
+                let bytes: [u8; 4] = bytes
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Corrupt schema version"))?;
+                Ok(u32::from_be_bytes(bytes))
+            }
+            None if self.db.is_empty() => Ok(SCHEMA_VERSION),
+            None => Ok(0),
+        }
+    }
+
+    /// Upgrade the stored data to layout `version`
+    ///
+    /// Returns the backup taken before changing anything, if a migration was
+    /// needed. Version 1 only adds the version marker.
+    pub fn migrate_to(&self, version: u32) -> anyhow::Result<Option<PathBuf>> {
+        let current = self.schema_version()?;
+        if current > SCHEMA_VERSION {
+            anyhow::bail!(
+                "Storage at {} has schema version {}, newer than the supported version {}; upgrade qiyashash",
+                self.path.display(),
+                current,
+                SCHEMA_VERSION
+            );
+        }
+        check_migration(current, version)?;
+
+        let backup = if current < version {
+            let backup = self.backup(current)?;
+            info!(
+                "Migrated storage from schema {} to {}; backup at {}",
+                current,
+                version,
+                backup.display()
+            );
+            Some(backup)
+        } else {
+            None
+        };
+
+        if self.db.get(SCHEMA_VERSION_KEY)?.as_deref() != Some(&version.to_be_bytes()[..]) {
+            self.db.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+            self.db.flush()?;
+        }
+        Ok(backup)
+    }
+
+    /// Copy every tree into a new database next to this one
+    fn backup(&self, version: u32) -> anyhow::Result<PathBuf> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".backup-v{}-{}", version, chrono::Utc::now().timestamp()));
+        let backup_path = self.path.with_file_name(name);
+
+        let backup = sled::open(&backup_path)?;
+        for tree_name in self.db.tree_names() {
+            let source = self.db.open_tree(&tree_name)?;
+            let target = backup.open_tree(&tree_name)?;
+            for entry in source.iter() {
+                let (key, value) = entry?;
+                target.insert(key, value)?;
+            }
+        }
+        backup.flush()?;
+        Ok(backup_path)
     }
 
     /// Check if identity exists
@@ -87,6 +183,87 @@ impl LocalStorage {
             .get("device_name")?
             .map(|v| String::from_utf8_lossy(&v).to_string()))
     }
+
+    /// Device ID of this installation, created on first use
+    pub fn device_id(&self) -> anyhow::Result<DeviceId> {
+        if let Some(id) = self.db.get("device_id")? {
+            return Ok(DeviceId::from_string(String::from_utf8_lossy(&id)));
+        }
+        let id = DeviceId::new();
+        self.db.insert("device_id", id.to_string().as_bytes())?;
+        self.db.flush()?;
+        Ok(id)
+    }
+
+    /// Save the protocol client's state
+    pub fn save_protocol_state(&self, snapshot: &StorageSnapshot) -> anyhow::Result<()> {
+        self.db.insert(PROTOCOL_STATE_KEY, serde_json::to_vec(snapshot)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Get the protocol client's state
+    pub fn get_protocol_state(&self) -> anyhow::Result<Option<StorageSnapshot>> {
+        match self.db.get(PROTOCOL_STATE_KEY)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the protocol client's state, such as when the identity changes
+    pub fn delete_protocol_state(&self) -> anyhow::Result<()> {
+        self.db.remove(PROTOCOL_STATE_KEY)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Save which prekeys were published
+    pub fn save_prekey_state(&self, state: &PreKeyState) -> anyhow::Result<()> {
+        self.db.insert("prekeys", serde_json::to_vec(state)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Get prekey state
+    pub fn get_prekey_state(&self) -> anyhow::Result<Option<PreKeyState>> {
+        match self.db.get("prekeys")? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read every entry and report the totals
+    ///
+    /// Fails if any entry cannot be read back.
+    pub fn stats(&self) -> anyhow::Result<StorageStats> {
+        let mut stats = StorageStats {
+            entries: 0,
+            size_on_disk: self.db.size_on_disk()?,
+        };
+        for entry in self.db.iter() {
+            entry?;
+            stats.entries += 1;
+        }
+        Ok(stats)
+    }
+}
+
+/// Published prekeys
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PreKeyState {
+    /// IDs of unused one-time prekeys
+    pub one_time_prekey_ids: Vec<u32>,
+    /// When the signed prekey was created (Unix seconds)
+    pub signed_prekey_created_at: i64,
+}
+
+/// Storage totals
+#[derive(Clone, Copy, Debug)]
+pub struct StorageStats {
+    /// Number of entries
+    pub entries: usize,
+    /// Database size (bytes)
+    pub size_on_disk: u64,
 }
 
 /// Stored identity format
@@ -97,3 +274,49 @@ struct StoredIdentity {
     created_at: i64,
     device_name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unversioned_store_migrates_to_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        {
+            // Layout written before versioning
+            let db = sled::open(&path).unwrap();
+            db.insert("device_name", "laptop").unwrap();
+            db.flush().unwrap();
+        }
+
+        let storage = LocalStorage::open(&path).unwrap();
+        assert_eq!(storage.schema_version().unwrap(), 1);
+        assert_eq!(storage.get_device_name().unwrap().as_deref(), Some("laptop"));
+
+        let backups: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("data.backup-v0-"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        let backup = sled::open(dir.path().join(&backups[0])).unwrap();
+        assert!(backup.get(SCHEMA_VERSION_KEY).unwrap().is_none());
+        assert_eq!(backup.get("device_name").unwrap().as_deref(), Some(&b"laptop"[..]));
+    }
+
+    #[test]
+    fn test_future_schema_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let storage = LocalStorage::open(dir.path()).unwrap();
+            storage
+                .db
+                .insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes())
+                .unwrap();
+        }
+
+        let err = LocalStorage::open(dir.path()).err().expect("newer schema is refused");
+        assert!(err.to_string().contains("newer than the supported version"), "{}", err);
+    }
+}
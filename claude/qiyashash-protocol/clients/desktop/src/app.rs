@@ -4,11 +4,20 @@ use std::sync::Arc;
 use parking_lot::RwLock;
 use tracing::{info, debug};
 
-use qiyashash_core::message::Message;
+use qiyashash_core::message::{Message, MessageId, MessageStatus};
 use qiyashash_core::session::SessionId;
-use qiyashash_core::types::{DeviceId, UserId};
+use qiyashash_core::storage::memory::MemoryStorage;
+use qiyashash_core::storage::{Storage, UserStore};
+use qiyashash_core::types::{DeviceId, Timestamp, UserId};
 use qiyashash_crypto::identity::Identity;
 
+use qiyashash_protocol::config::RetryConfig;
+use qiyashash_protocol::protocol::DevicePreKeyBundle;
+use qiyashash_protocol::{ClientConfig, ProtocolClient, ProtocolError, ProtocolMessage, ProtocolMessageType};
+use tokio::sync::watch;
+
+use crate::connection::{ConnectionManager, ConnectionStatus, SendOutcome, Transport};
+use crate::notifications::{NoopNotifier, NotificationEvent, Notifier};
 use crate::state::AppState;
 use crate::storage::DesktopStorage;
 
@@ -31,6 +40,10 @@ pub enum AppError {
     #[error("Session error: {0}")]
     Session(String),
 
+    /// Network error
+    #[error("Network error: {0}")]
+    Network(String),
+
     /// Internal error
     #[error("Internal error: {0}")]
     Internal(String),
@@ -39,6 +52,16 @@ pub enum AppError {
 /// Result type
 pub type Result<T> = std::result::Result<T, AppError>;
 
+impl From<ProtocolError> for AppError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::SessionNotEstablished(_) => AppError::Session(e.to_string()),
+            ProtocolError::Storage(e) => AppError::Storage(e),
+            e => AppError::Protocol(e.to_string()),
+        }
+    }
+}
+
 /// Desktop application
 pub struct App {
     /// Application state
@@ -49,6 +72,19 @@ pub struct App {
     identity: Option<Identity>,
     /// Our device ID
     device_id: DeviceId,
+    /// Sessions and encryption, once initialized
+    protocol: Option<ProtocolClient<MemoryStorage>>,
+    /// State of `protocol`, saved to `storage` after every change
+    protocol_state: Arc<MemoryStorage>,
+    /// Held while a session changes and its new state is saved, so an older
+    /// snapshot never overwrites a newer one
+    protocol_writes: tokio::sync::Mutex<()>,
+    /// Connection to relays and the DHT, once a transport is set
+    connection: Option<Arc<ConnectionManager>>,
+    /// Contacts and per-conversation settings
+    users: Arc<dyn UserStore>,
+    /// Where notifications go
+    notifier: Arc<dyn Notifier>,
 }
 
 impl App {
@@ -61,9 +97,15 @@ impl App {
 
         Ok(Self {
             state: Arc::new(RwLock::new(AppState::new())),
+            device_id: storage.device_id()?,
             storage: Arc::new(storage),
             identity: None,
-            device_id: DeviceId::new(),
+            protocol: None,
+            protocol_state: MemoryStorage::new(),
+            protocol_writes: tokio::sync::Mutex::new(()),
+            connection: None,
+            users: MemoryStorage::new(),
+            notifier: Arc::new(NoopNotifier),
         })
     }
 
@@ -85,9 +127,13 @@ impl App {
         }
 
         // Update state
-        if let Some(ref identity) = self.identity {
+        let ours = self.identity.as_ref()
+            .map(|identity| (UserId::from_fingerprint(&identity.fingerprint), identity.key_pair.clone()));
+        if let Some((user_id, key_pair)) = ours {
+            self.start_protocol(user_id.clone(), key_pair).await?;
+
             let mut state = self.state.write();
-            state.user_id = Some(UserId::from_fingerprint(&identity.fingerprint));
+            state.user_id = Some(user_id);
             state.device_id = Some(self.device_id.clone());
             state.initialized = true;
         }
@@ -96,6 +142,36 @@ impl App {
         Ok(())
     }
 
+    /// Restore our sessions and keys and start the protocol client
+    async fn start_protocol(
+        &mut self,
+        user_id: UserId,
+        key_pair: qiyashash_crypto::identity::IdentityKeyPair,
+    ) -> Result<()> {
+        if let Some(snapshot) = self.storage.load_protocol_state()? {
+            self.protocol_state.import_snapshot(snapshot).await
+                .map_err(|e| AppError::Storage(e.to_string()))?;
+        }
+
+        let protocol = ProtocolClient::new(ClientConfig::default(), self.protocol_state.clone())
+            .with_ids(user_id, self.device_id.clone())
+            .with_identity(Identity::from_key_pair(key_pair));
+        protocol.initialize().await?;
+        self.protocol = Some(protocol);
+        self.save_protocol_state().await
+    }
+
+    /// Persist sessions and keys for the next run
+    async fn save_protocol_state(&self) -> Result<()> {
+        let snapshot = self.protocol_state.export_snapshot().await
+            .map_err(|e| AppError::Storage(e.to_string()))?;
+        self.storage.save_protocol_state(&snapshot)
+    }
+
+    fn protocol(&self) -> Result<&ProtocolClient<MemoryStorage>> {
+        self.protocol.as_ref().ok_or(AppError::NotInitialized)
+    }
+
     /// Check if initialized
     pub fn is_initialized(&self) -> bool {
         self.state.read().initialized
@@ -111,7 +187,75 @@ impl App {
         self.identity.as_ref().map(|i| i.fingerprint_hex())
     }
 
+    /// Read contacts and conversation settings from `users`
+    pub fn set_user_store(&mut self, users: Arc<dyn UserStore>) {
+        self.users = users;
+    }
+
+    /// Send notifications to `notifier`
+    pub fn set_notifier(&mut self, notifier: Arc<dyn Notifier>) {
+        self.notifier = notifier;
+    }
+
+    /// Use `transport` to reach relays and the DHT
+    ///
+    /// Replaces any previous connection; call [`App::connect`] afterwards.
+    /// Messages still queued from an earlier run go out once connected.
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) -> Result<()> {
+        let outbox = self.storage.outbox()?;
+        self.connection = Some(ConnectionManager::persistent(transport, RetryConfig::default(), outbox)?);
+        self.state.write().set_connection_status(ConnectionStatus::Offline);
+        Ok(())
+    }
+
+    /// Connect, retrying with backoff
+    pub async fn connect(&self) -> Result<ConnectionStatus> {
+        let connection = self.connection.as_ref()
+            .ok_or_else(|| AppError::Network("No transport set".to_string()))?;
+        let status = connection.connect().await;
+        self.state.write().set_connection_status(status);
+        Ok(status)
+    }
+
+    /// Current connection status
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.connection.as_ref()
+            .map_or(ConnectionStatus::Offline, |c| c.status())
+    }
+
+    /// Stream of connection status changes for the UI
+    pub fn subscribe_connection(&self) -> Option<watch::Receiver<ConnectionStatus>> {
+        self.connection.as_ref().map(|c| c.subscribe())
+    }
+
+    /// Connection manager, if a transport is set
+    pub fn connection(&self) -> Option<&Arc<ConnectionManager>> {
+        self.connection.as_ref()
+    }
+
+    /// Start a session with the device that published `bundle`
+    ///
+    /// Returns the device ID. The bundle's signature and age are checked
+    /// first.
+    pub async fn establish_session(
+        &self,
+        user_id: &UserId,
+        bundle: &DevicePreKeyBundle,
+    ) -> Result<DeviceId> {
+        let protocol = self.protocol()?;
+        bundle.verify(Timestamp::now(), protocol.config().max_prekey_bundle_age_secs)?;
+
+        let _writing = self.protocol_writes.lock().await;
+        protocol.establish_session(user_id, &bundle.device_id, bundle).await?;
+        self.save_protocol_state().await?;
+        Ok(bundle.device_id.clone())
+    }
+
     /// Send a message
+    ///
+    /// It is encrypted for the recipient device we last had a session with,
+    /// and queued by the connection while offline. The stored copy is
+    /// pending until it has been handed to the network.
     pub async fn send_message(
         &self,
         recipient_id: &UserId,
@@ -120,28 +264,100 @@ impl App {
         if !self.is_initialized() {
             return Err(AppError::NotInitialized);
         }
-
-        let user_id = self.user_id().ok_or(AppError::NotInitialized)?;
-        
-        let message = Message::text(
-            user_id,
+        let protocol = self.protocol()?;
+        let connection = self.connection.as_ref()
+            .ok_or_else(|| AppError::Network("No transport set".to_string()))?;
+
+        // Sessions come oldest first
+        let device_id = protocol.sessions()?
+            .into_iter()
+            .rev()
+            .find(|session| &session.their_user_id == recipient_id)
+            .map(|session| session.their_device_id)
+            .ok_or_else(|| AppError::Session(format!("No session with {}", recipient_id)))?;
+
+        let mut message = Message::text(
+            protocol.user_id().clone(),
             self.device_id.clone(),
             recipient_id.clone(),
             content,
-        );
-
-        // In full implementation:
-        // 1. Get or establish session
-        // 2. Encrypt message
-        // 3. Send via relay/DHT
-        // 4. Store locally
+        )
+        .with_recipient_device(device_id.clone());
+
+        let envelope = {
+            let _writing = self.protocol_writes.lock().await;
+            let envelope = protocol.encrypt_message(recipient_id, &device_id, &message).await?;
+            self.save_protocol_state().await?;
+            envelope
+        };
+        let payload = serde_json::to_vec(&ProtocolMessage::new(
+            ProtocolMessageType::EncryptedMessage(envelope),
+            protocol.user_id().clone(),
+            self.device_id.clone(),
+        ))
+        .map_err(|e| AppError::Internal(e.to_string()))?;
 
+        if connection.send(recipient_id, payload).await? == SendOutcome::Sent {
+            message.status = MessageStatus::Sent;
+        }
         self.storage.save_message(&message)?;
 
-        debug!("Sent message {} to {}", message.id, recipient_id);
+        debug!("Sent message {} to {} ({:?})", message.id, recipient_id, message.status);
         Ok(message)
     }
 
+    /// Handle a decrypted inbound message
+    ///
+    /// Stores it and notifies the user unless notifications are off or the
+    /// conversation is muted.
+    pub async fn receive_message(&self, message: &Message) -> Result<()> {
+        if !self.is_initialized() {
+            return Err(AppError::NotInitialized);
+        }
+
+        self.storage.save_message(message)?;
+
+        let notifications = self.state.read().settings.notifications.clone();
+        if !notifications.enabled {
+            return Ok(());
+        }
+        let conversation = self.users.get_conversation_settings(&message.sender_id).await
+            .map_err(|e| AppError::Storage(e.to_string()))?
+            .unwrap_or_default();
+        if conversation.is_muted_at(Timestamp::now()) {
+            debug!("Conversation with {} is muted", message.sender_id);
+            return Ok(());
+        }
+
+        let preview = if notifications.show_preview && conversation.show_previews {
+            message.content_as_string()
+        } else {
+            None
+        };
+        self.notifier.notify(NotificationEvent::NewMessage {
+            conversation: message.sender_id.clone(),
+            message_id: message.id.clone(),
+            preview,
+        });
+        Ok(())
+    }
+
+    /// Handle a contact's identity key changing
+    pub fn safety_number_changed(&self, user_id: &UserId) {
+        self.notifier.notify(NotificationEvent::SafetyNumberChanged {
+            user_id: user_id.clone(),
+        });
+    }
+
+    /// Handle a message that could not be delivered
+    pub fn delivery_failed(&self, recipient: &UserId, message_id: &MessageId, reason: &str) {
+        self.notifier.notify(NotificationEvent::DeliveryFailed {
+            recipient: recipient.clone(),
+            message_id: message_id.clone(),
+            reason: reason.to_string(),
+        });
+    }
+
     /// Get conversation messages
     pub fn get_conversation(
         &self,
@@ -180,7 +396,9 @@ impl App {
 
     /// Get current state
     pub fn state(&self) -> AppState {
-        self.state.read().clone()
+        let mut state = self.state.write();
+        state.set_connection_status(self.connection_status());
+        state.clone()
     }
 
     /// Shutdown
@@ -226,4 +444,84 @@ mod tests {
         assert!(app.is_initialized());
         assert!(app.fingerprint().is_some());
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_message_queues_until_connected() {
+        use crate::test_support::{app_with_peer, open};
+        use std::sync::atomic::Ordering;
+
+        let dir = tempdir().unwrap();
+        let (app, peer, transport) = app_with_peer(dir.path().to_str().unwrap()).await;
+
+        let message = app.send_message(peer.user_id(), "while offline").await.unwrap();
+        assert_eq!(message.status, MessageStatus::Pending);
+        assert_eq!(app.connection().unwrap().queued().await, 1);
+
+        transport.online.store(true, Ordering::SeqCst);
+        assert_eq!(app.connect().await.unwrap(), ConnectionStatus::Connected);
+        let sent = app.send_message(peer.user_id(), "online").await.unwrap();
+        assert_eq!(sent.status, MessageStatus::Sent);
+
+        let payloads = transport.sent.lock().clone();
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads.iter().all(|(recipient, _)| recipient == peer.user_id()));
+        assert_eq!(open(&peer, &payloads[0].1).await, "while offline");
+        assert_eq!(open(&peer, &payloads[1].1).await, "online");
+    }
+
+    #[tokio::test]
+    async fn test_send_message_requires_session() {
+        use crate::test_support::MockTransport;
+
+        let dir = tempdir().unwrap();
+        let mut app = App::new(dir.path().to_str().unwrap()).unwrap();
+        app.initialize().await.unwrap();
+        app.set_transport(Arc::new(MockTransport::default())).unwrap();
+
+        let err = app.send_message(&UserId::new(), "hello").await.unwrap_err();
+        assert!(matches!(err, AppError::Session(_)), "{}", err);
+    }
+
+    #[derive(Default)]
+    struct RecordingNotifier {
+        events: parking_lot::Mutex<Vec<NotificationEvent>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, event: NotificationEvent) {
+            self.events.lock().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_message_notifies_unless_muted() {
+        use qiyashash_core::user::ConversationSettings;
+
+        let dir = tempdir().unwrap();
+        let mut app = App::new(dir.path().to_str().unwrap()).unwrap();
+        let users = MemoryStorage::new();
+        let notifier = Arc::new(RecordingNotifier::default());
+        app.set_user_store(users.clone());
+        app.set_notifier(notifier.clone());
+        app.initialize().await.unwrap();
+        let me = app.user_id().unwrap();
+
+        let (alice, bob) = (UserId::new(), UserId::new());
+        let mut muted = ConversationSettings::default();
+        muted.mute();
+        users.set_conversation_settings(&bob, &muted).await.unwrap();
+
+        let from_alice = Message::text(alice.clone(), DeviceId::new(), me.clone(), "hi");
+        app.receive_message(&from_alice).await.unwrap();
+        app.receive_message(&Message::text(bob, DeviceId::new(), me, "psst")).await.unwrap();
+
+        assert_eq!(
+            *notifier.events.lock(),
+            vec![NotificationEvent::NewMessage {
+                conversation: alice,
+                message_id: from_alice.id,
+                preview: Some("hi".to_string()),
+            }]
+        );
+    }
 }
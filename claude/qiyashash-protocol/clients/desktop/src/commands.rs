@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use qiyashash_core::types::UserId;
 
 use crate::app::{App, AppError, ConversationInfo, Result};
+use crate::connection::ConnectionStatus;
 use crate::state::AppState;
 
 /// Initialize the application
@@ -24,6 +25,16 @@ pub fn get_state(app: &App) -> AppState {
     app.state()
 }
 
+/// Connect to relays and the DHT
+pub async fn connect(app: &App) -> Result<ConnectionStatus> {
+    app.connect().await
+}
+
+/// Get connection status
+pub fn get_connection_status(app: &App) -> ConnectionStatus {
+    app.connection_status()
+}
+
 /// Send a message
 pub async fn send_message(
     app: &App,
@@ -151,11 +162,12 @@ mod tests {
     #[tokio::test]
     async fn test_send_message_command() {
         let dir = tempdir().unwrap();
-        let mut app = App::new(dir.path().to_str().unwrap()).unwrap();
-        initialize(&mut app).await.unwrap();
+        let (app, peer, _) = crate::test_support::app_with_peer(dir.path().to_str().unwrap()).await;
         
-        let result = send_message(&app, "recipient-123", "Hello!").await.unwrap();
+        let result = send_message(&app, peer.user_id().as_str(), "Hello!").await.unwrap();
         
         assert!(!result.message_id.is_empty());
+        let stored = get_conversation(&app, peer.user_id().as_str(), 10).unwrap();
+        assert_eq!(stored[0].content.as_deref(), Some("Hello!"));
     }
 }
@@ -0,0 +1,433 @@
+//! Managed connection to relays and the DHT
+//!
+//! [`ConnectionManager`] owns the [`Transport`], publishes its
+//! [`ConnectionStatus`] for the UI and reconnects with exponential backoff
+//! after a drop. Messages sent while the connection is down are queued and
+//! flushed, in order, once it is back. The queue is bounded and, when given
+//! a tree to live in, survives restarts.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, info, warn};
+
+use qiyashash_core::types::UserId;
+use qiyashash_protocol::config::RetryConfig;
+
+use crate::app::{AppError, Result};
+
+/// Most payloads queued while disconnected; sends beyond it fail
+pub const MAX_QUEUED: usize = 1000;
+
+/// Network path to relays and the DHT
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Establish the connection
+    async fn connect(&self) -> Result<()>;
+
+    /// Deliver an encrypted payload to `recipient`
+    async fn send(&self, recipient: &UserId, payload: &[u8]) -> Result<()>;
+}
+
+/// Connection state shown to the user
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionStatus {
+    /// Connected; messages go out immediately
+    Connected,
+    /// Connection lost; retrying with backoff
+    Reconnecting,
+    /// Not connected and not retrying
+    #[default]
+    Offline,
+}
+
+/// What happened to an outbound payload
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// Handed to the transport
+    Sent,
+    /// Queued until the connection is back
+    Queued,
+}
+
+/// Keeps the transport connected and queues traffic while it is not
+pub struct ConnectionManager {
+    transport: Arc<dyn Transport>,
+    retry: RetryConfig,
+    status: watch::Sender<ConnectionStatus>,
+    outbox: Mutex<Outbox>,
+    /// Whether a reconnect loop is running
+    reconnecting: AtomicBool,
+}
+
+impl ConnectionManager {
+    /// Create a manager for `transport`, initially offline
+    ///
+    /// Queued payloads are only kept in memory.
+    pub fn new(transport: Arc<dyn Transport>, retry: RetryConfig) -> Arc<Self> {
+        Self::with_outbox(transport, retry, Outbox::default())
+    }
+
+    /// Create a manager that keeps queued payloads in `tree`
+    ///
+    /// Payloads a previous run left queued are sent first once connected.
+    pub fn persistent(
+        transport: Arc<dyn Transport>,
+        retry: RetryConfig,
+        tree: sled::Tree,
+    ) -> Result<Arc<Self>> {
+        Ok(Self::with_outbox(transport, retry, Outbox::open(tree)?))
+    }
+
+    fn with_outbox(transport: Arc<dyn Transport>, retry: RetryConfig, outbox: Outbox) -> Arc<Self> {
+        let (status, _) = watch::channel(ConnectionStatus::Offline);
+        Arc::new(Self {
+            transport,
+            retry,
+            status,
+            outbox: Mutex::new(outbox),
+            reconnecting: AtomicBool::new(false),
+        })
+    }
+
+    /// Current status
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    /// Stream of status changes, starting with the current status
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    /// Number of payloads waiting for a connection
+    pub async fn queued(&self) -> usize {
+        self.outbox.lock().await.len()
+    }
+
+    /// Connect, retrying with backoff, and flush queued payloads
+    ///
+    /// Returns the resulting status: [`ConnectionStatus::Offline`] once the
+    /// retries are used up. If a reconnect is already running, returns the
+    /// current status without waiting for it.
+    pub async fn connect(&self) -> ConnectionStatus {
+        if self.reconnecting.swap(true, Ordering::SeqCst) {
+            return self.status();
+        }
+        self.run_reconnect().await;
+        self.status()
+    }
+
+    /// Report a dropped connection and reconnect in the background
+    pub fn handle_disconnect(self: &Arc<Self>) {
+        self.set_status(ConnectionStatus::Reconnecting);
+        if self.reconnecting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            manager.run_reconnect().await;
+        });
+    }
+
+    /// Send `payload` to `recipient`, or queue it while disconnected
+    ///
+    /// A failed send counts as a dropped connection: the payload is queued
+    /// and a reconnect is started. Fails if the payload had to be queued
+    /// but [`MAX_QUEUED`] are already waiting, or could not be stored.
+    pub async fn send(self: &Arc<Self>, recipient: &UserId, payload: Vec<u8>) -> Result<SendOutcome> {
+        let mut outbox = self.outbox.lock().await;
+
+        // Anything still queued goes first, to keep the order
+        if self.status() == ConnectionStatus::Connected && outbox.is_empty() {
+            match self.transport.send(recipient, &payload).await {
+                Ok(()) => return Ok(SendOutcome::Sent),
+                Err(e) => {
+                    warn!("Send failed, queueing: {}", e);
+                    let queued = outbox.push_back(recipient, payload);
+                    drop(outbox);
+                    self.handle_disconnect();
+                    return queued.map(|()| SendOutcome::Queued);
+                }
+            }
+        }
+
+        outbox.push_back(recipient, payload)?;
+        debug!("Queued payload for {} ({} waiting)", recipient, outbox.len());
+        Ok(SendOutcome::Queued)
+    }
+
+    /// Reconnect loop; the caller must have set `reconnecting`
+    async fn run_reconnect(&self) {
+        let mut attempt = 0;
+        loop {
+            self.set_status(ConnectionStatus::Reconnecting);
+            match self.transport.connect().await {
+                Ok(()) => {
+                    if self.flush().await {
+                        info!("Connected");
+                        break;
+                    }
+                }
+                Err(e) => warn!("Connection attempt {} failed: {}", attempt + 1, e),
+            }
+
+            if attempt >= self.retry.max_retries {
+                warn!("Giving up after {} attempts", attempt + 1);
+                self.set_status(ConnectionStatus::Offline);
+                break;
+            }
+            tokio::time::sleep(self.retry.delay_for_attempt(attempt)).await;
+            attempt += 1;
+        }
+        self.reconnecting.store(false, Ordering::SeqCst);
+    }
+
+    /// Send queued payloads in order, then report the connection up
+    ///
+    /// Returns false if the connection dropped again. The status changes
+    /// while the outbox is still locked, so no send can slip in between and
+    /// be queued after the flush.
+    async fn flush(&self) -> bool {
+        let mut outbox = self.outbox.lock().await;
+        while let Some((_, recipient, payload)) = outbox.queue.front() {
+            if let Err(e) = self.transport.send(recipient, payload).await {
+                warn!("Flush failed with {} queued: {}", outbox.len(), e);
+                return false;
+            }
+            outbox.pop_front();
+        }
+        self.set_status(ConnectionStatus::Connected);
+        true
+    }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        self.status.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+    }
+}
+
+/// Payloads waiting for a connection, oldest first
+///
+/// With a tree, each payload is also stored under its sequence number until
+/// it has been sent.
+#[derive(Default)]
+struct Outbox {
+    queue: VecDeque<(u64, UserId, Vec<u8>)>,
+    tree: Option<sled::Tree>,
+    next_seq: u64,
+}
+
+impl Outbox {
+    /// Load the payloads stored in `tree`
+    fn open(tree: sled::Tree) -> Result<Self> {
+        let mut queue = VecDeque::new();
+        for entry in tree.iter() {
+            let (key, value) = entry.map_err(|e| AppError::Storage(e.to_string()))?;
+            let seq = key
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| AppError::Storage("Corrupt outbox key".to_string()))?;
+            let (recipient, payload) = bincode::deserialize(&value)
+                .map_err(|e| AppError::Storage(e.to_string()))?;
+            queue.push_back((seq, recipient, payload));
+        }
+        if !queue.is_empty() {
+            info!("{} payloads queued from a previous run", queue.len());
+        }
+
+        let next_seq = queue.back().map_or(0, |(seq, _, _)| seq + 1);
+        Ok(Self {
+            queue,
+            tree: Some(tree),
+            next_seq,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn push_back(&mut self, recipient: &UserId, payload: Vec<u8>) -> Result<()> {
+        if self.queue.len() >= MAX_QUEUED {
+            return Err(AppError::Network(format!(
+                "Outbox full: {} messages waiting for a connection",
+                self.queue.len()
+            )));
+        }
+
+        let seq = self.next_seq;
+        if let Some(tree) = &self.tree {
+            let value = bincode::serialize(&(recipient, &payload))
+                .map_err(|e| AppError::Storage(e.to_string()))?;
+            tree.insert(seq.to_be_bytes(), value)
+                .map_err(|e| AppError::Storage(e.to_string()))?;
+            tree.flush()
+                .map_err(|e| AppError::Storage(e.to_string()))?;
+        }
+        self.next_seq += 1;
+        self.queue.push_back((seq, recipient.clone(), payload));
+        Ok(())
+    }
+
+    /// Drop the oldest payload, once it was sent
+    ///
+    /// If it cannot be removed from disk it is sent again after a restart;
+    /// recipients discard the duplicate.
+    fn pop_front(&mut self) {
+        let Some((seq, _, _)) = self.queue.pop_front() else {
+            return;
+        };
+        if let Some(tree) = &self.tree {
+            if let Err(e) = tree.remove(seq.to_be_bytes()) {
+                warn!("Could not remove sent payload {} from the outbox: {}", seq, e);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionManager")
+            .field("status", &self.status())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockTransport;
+
+    fn manager() -> (Arc<MockTransport>, Arc<ConnectionManager>) {
+        let transport = Arc::new(MockTransport::default());
+        let retry = RetryConfig {
+            max_retries: 3,
+            ..RetryConfig::default()
+        };
+        (transport.clone(), ConnectionManager::new(transport, retry))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disconnect_transitions() {
+        let (transport, manager) = manager();
+        let mut status = manager.subscribe();
+        assert_eq!(*status.borrow_and_update(), ConnectionStatus::Offline);
+
+        transport.online.store(true, Ordering::SeqCst);
+        assert_eq!(manager.connect().await, ConnectionStatus::Connected);
+        assert_eq!(*status.borrow_and_update(), ConnectionStatus::Connected);
+
+        // Dropped and unreachable: retries, then gives up
+        transport.online.store(false, Ordering::SeqCst);
+        manager.handle_disconnect();
+        assert_eq!(manager.status(), ConnectionStatus::Reconnecting);
+        status.wait_for(|s| *s == ConnectionStatus::Offline).await.unwrap();
+
+        // Dropped and back soon: recovers on its own
+        transport.online.store(true, Ordering::SeqCst);
+        assert_eq!(manager.connect().await, ConnectionStatus::Connected);
+        transport.online.store(false, Ordering::SeqCst);
+        manager.handle_disconnect();
+        tokio::time::sleep(RetryConfig::default().delay_for_attempt(0)).await;
+        transport.online.store(true, Ordering::SeqCst);
+        status.wait_for(|s| *s == ConnectionStatus::Connected).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queued_messages_flush_after_reconnect() {
+        let (transport, manager) = manager();
+        let (alice, bob) = (UserId::new(), UserId::new());
+
+        assert_eq!(manager.send(&alice, b"one".to_vec()).await.unwrap(), SendOutcome::Queued);
+        assert_eq!(manager.send(&bob, b"two".to_vec()).await.unwrap(), SendOutcome::Queued);
+        assert_eq!(manager.queued().await, 2);
+        assert!(transport.sent.lock().is_empty());
+
+        transport.online.store(true, Ordering::SeqCst);
+        assert_eq!(manager.connect().await, ConnectionStatus::Connected);
+        assert_eq!(manager.queued().await, 0);
+        assert_eq!(manager.send(&alice, b"three".to_vec()).await.unwrap(), SendOutcome::Sent);
+
+        // A send that hits a dead connection is kept and delivered later
+        transport.online.store(false, Ordering::SeqCst);
+        assert_eq!(manager.send(&bob, b"four".to_vec()).await.unwrap(), SendOutcome::Queued);
+        assert_eq!(manager.status(), ConnectionStatus::Reconnecting);
+        transport.online.store(true, Ordering::SeqCst);
+        manager.subscribe().wait_for(|s| *s == ConnectionStatus::Connected).await.unwrap();
+
+        let sent = transport.sent.lock().clone();
+        assert_eq!(
+            sent,
+            vec![
+                (alice.clone(), b"one".to_vec()),
+                (bob.clone(), b"two".to_vec()),
+                (alice, b"three".to_vec()),
+                (bob, b"four".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outbox_is_bounded() {
+        let (_, manager) = manager();
+        let bob = UserId::new();
+
+        for _ in 0..MAX_QUEUED {
+            manager.send(&bob, b"hi".to_vec()).await.unwrap();
+        }
+        let err = manager.send(&bob, b"one too many".to_vec()).await.unwrap_err();
+        assert!(matches!(err, AppError::Network(_)), "{}", err);
+        assert_eq!(manager.queued().await, MAX_QUEUED);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_outbox_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let (alice, bob) = (UserId::new(), UserId::new());
+        let retry = RetryConfig::default();
+
+        {
+            let transport = Arc::new(MockTransport::default());
+            let manager =
+                ConnectionManager::persistent(transport, retry.clone(), db.open_tree("outbox").unwrap()).unwrap();
+            manager.send(&alice, b"one".to_vec()).await.unwrap();
+            manager.send(&bob, b"two".to_vec()).await.unwrap();
+        }
+
+        let transport = Arc::new(MockTransport::default());
+        let manager =
+            ConnectionManager::persistent(transport.clone(), retry.clone(), db.open_tree("outbox").unwrap()).unwrap();
+        assert_eq!(manager.queued().await, 2);
+        manager.send(&alice, b"three".to_vec()).await.unwrap();
+
+        transport.online.store(true, Ordering::SeqCst);
+        assert_eq!(manager.connect().await, ConnectionStatus::Connected);
+        assert_eq!(
+            *transport.sent.lock(),
+            vec![
+                (alice.clone(), b"one".to_vec()),
+                (bob, b"two".to_vec()),
+                (alice, b"three".to_vec()),
+            ]
+        );
+
+        // Sent payloads are gone from disk too
+        let manager = ConnectionManager::persistent(transport, retry, db.open_tree("outbox").unwrap()).unwrap();
+        assert_eq!(manager.queued().await, 0);
+    }
+}
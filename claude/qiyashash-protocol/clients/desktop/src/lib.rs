@@ -8,6 +8,7 @@
 //! - Complete messaging functionality
 //! - Local storage with encryption
 //! - Multi-device support
+//! - Reconnecting relay/DHT connection
 //! - Notification handling
 
 #![forbid(unsafe_code)]
@@ -15,10 +16,17 @@
 
 pub mod app;
 pub mod commands;
+pub mod connection;
+pub mod notifications;
 pub mod state;
 pub mod storage;
 
+#[cfg(test)]
+mod test_support;
+
 pub use app::App;
+pub use connection::{ConnectionManager, ConnectionStatus};
+pub use notifications::{NotificationEvent, Notifier};
 pub use state::AppState;
 
 /// Application version
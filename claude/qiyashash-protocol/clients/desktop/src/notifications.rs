@@ -0,0 +1,53 @@
+//! Notification dispatch
+//!
+//! The app reports events worth interrupting the user for through a
+//! [`Notifier`]; the Tauri shell implements it with OS notifications. Muted
+//! conversations only silence new messages: safety-number changes and
+//! failed deliveries need the user's attention regardless.
+
+use serde::Serialize;
+
+use qiyashash_core::message::MessageId;
+use qiyashash_core::types::UserId;
+
+/// Something the user should be told about
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum NotificationEvent {
+    /// A message arrived
+    NewMessage {
+        /// Conversation (the sender)
+        conversation: UserId,
+        /// Message ID
+        message_id: MessageId,
+        /// Message text, if previews are enabled
+        preview: Option<String>,
+    },
+    /// A contact's identity key changed
+    SafetyNumberChanged {
+        /// Contact whose key changed
+        user_id: UserId,
+    },
+    /// A message could not be delivered
+    DeliveryFailed {
+        /// Intended recipient
+        recipient: UserId,
+        /// Message ID
+        message_id: MessageId,
+        /// Why delivery failed
+        reason: String,
+    },
+}
+
+/// Shows notifications to the user
+pub trait Notifier: Send + Sync {
+    /// Show a notification for `event`
+    fn notify(&self, event: NotificationEvent);
+}
+
+/// Notifier that drops every event
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {
+    fn notify(&self, _event: NotificationEvent) {}
+}
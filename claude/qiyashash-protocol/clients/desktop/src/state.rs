@@ -3,6 +3,8 @@
 use qiyashash_core::types::{DeviceId, UserId};
 use serde::{Deserialize, Serialize};
 
+use crate::connection::ConnectionStatus;
+
 /// Application state
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct AppState {
@@ -14,6 +16,8 @@ pub struct AppState {
     pub device_id: Option<DeviceId>,
     /// Online status
     pub is_online: bool,
+    /// Connection to relays and the DHT
+    pub connection_status: ConnectionStatus,
     /// Syncing status
     pub is_syncing: bool,
     /// Unread count total
@@ -40,6 +44,12 @@ impl AppState {
         self.is_online = online;
     }
 
+    /// Update connection status, and online status with it
+    pub fn set_connection_status(&mut self, status: ConnectionStatus) {
+        self.connection_status = status;
+        self.is_online = status == ConnectionStatus::Connected;
+    }
+
     /// Update syncing status
     pub fn set_syncing(&mut self, syncing: bool) {
         self.is_syncing = syncing;
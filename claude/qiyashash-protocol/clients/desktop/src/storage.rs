@@ -4,7 +4,8 @@ use std::path::Path;
 use tracing::{debug, info};
 
 use qiyashash_core::message::Message;
-use qiyashash_core::types::UserId;
+use qiyashash_core::storage::StorageSnapshot;
+use qiyashash_core::types::{DeviceId, UserId};
 
 use crate::app::{AppError, ConversationInfo, Result};
 
@@ -51,6 +52,52 @@ impl DesktopStorage {
         }
     }
 
+    /// Device ID of this installation, created on first use
+    pub fn device_id(&self) -> Result<DeviceId> {
+        if let Some(id) = self.db.get("device_id")
+            .map_err(|e| AppError::Storage(e.to_string()))? {
+            return Ok(DeviceId::from_string(String::from_utf8_lossy(&id)));
+        }
+
+        let id = DeviceId::new();
+        self.db.insert("device_id", id.to_string().as_bytes())
+            .map_err(|e| AppError::Storage(e.to_string()))?;
+        self.db.flush()
+            .map_err(|e| AppError::Storage(e.to_string()))?;
+        Ok(id)
+    }
+
+    /// Save the protocol client's sessions and keys
+    pub fn save_protocol_state(&self, snapshot: &StorageSnapshot) -> Result<()> {
+        let data = serde_json::to_vec(snapshot)
+            .map_err(|e| AppError::Storage(e.to_string()))?;
+
+        self.db.insert("protocol_state", data)
+            .map_err(|e| AppError::Storage(e.to_string()))?;
+        self.db.flush()
+            .map_err(|e| AppError::Storage(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Load the protocol client's sessions and keys
+    pub fn load_protocol_state(&self) -> Result<Option<StorageSnapshot>> {
+        match self.db.get("protocol_state")
+            .map_err(|e| AppError::Storage(e.to_string()))? {
+            Some(data) => {
+                let snapshot = serde_json::from_slice(&data)
+                    .map_err(|e| AppError::Storage(e.to_string()))?;
+                Ok(Some(snapshot))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Tree holding payloads queued while offline
+    pub fn outbox(&self) -> Result<sled::Tree> {
+        self.db.open_tree("outbox")
+            .map_err(|e| AppError::Storage(e.to_string()))
+    }
+
     /// Save message
     pub fn save_message(&self, message: &Message) -> Result<()> {
         let key = format!("msg:{}", message.id);
@@ -0,0 +1,70 @@
+//! Helpers shared by the unit tests
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use qiyashash_core::storage::memory::MemoryStorage;
+use qiyashash_core::types::UserId;
+use qiyashash_protocol::{ClientConfig, ProtocolClient, ProtocolMessage, ProtocolMessageType};
+
+use crate::app::{App, AppError, Result};
+use crate::connection::Transport;
+
+/// Transport that records what is sent while `online`
+#[derive(Default)]
+pub struct MockTransport {
+    pub online: AtomicBool,
+    pub sent: Mutex<Vec<(UserId, Vec<u8>)>>,
+}
+
+#[async_trait]
+impl Transport for MockTransport {
+    async fn connect(&self) -> Result<()> {
+        if self.online.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(AppError::Network("unreachable".to_string()))
+        }
+    }
+
+    async fn send(&self, recipient: &UserId, payload: &[u8]) -> Result<()> {
+        if !self.online.load(Ordering::SeqCst) {
+            return Err(AppError::Network("connection reset".to_string()));
+        }
+        self.sent.lock().push((recipient.clone(), payload.to_vec()));
+        Ok(())
+    }
+}
+
+/// Initialized app in `data_dir`, with a session to a new peer and an
+/// offline mock transport
+pub async fn app_with_peer(
+    data_dir: &str,
+) -> (App, ProtocolClient<MemoryStorage>, Arc<MockTransport>) {
+    let mut app = App::new(data_dir).unwrap();
+    app.initialize().await.unwrap();
+    let transport = Arc::new(MockTransport::default());
+    app.set_transport(transport.clone()).unwrap();
+
+    let peer = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+    peer.initialize().await.unwrap();
+    let bundle = peer.device_prekey_bundle().unwrap();
+    app.establish_session(peer.user_id(), &bundle).await.unwrap();
+    (app, peer, transport)
+}
+
+/// Decrypt a payload the app sent to `peer`, returning the text
+pub async fn open(peer: &ProtocolClient<MemoryStorage>, payload: &[u8]) -> String {
+    let message: ProtocolMessage = serde_json::from_slice(payload).unwrap();
+    let ProtocolMessageType::EncryptedMessage(envelope) = message.message_type else {
+        panic!("expected an encrypted message");
+    };
+    peer.decrypt_message(&message.sender_id, &message.sender_device_id, &envelope)
+        .await
+        .unwrap()
+        .content_as_string()
+        .unwrap()
+}
@@ -0,0 +1,147 @@
+//! Attachment decryption for mobile
+//!
+//! Attachments are compressed, then sealed with the streaming AEAD in
+//! [`qiyashash_crypto::stream`], so they can be decrypted, decompressed and
+//! handed to the host one chunk at a time.
+
+use std::sync::{Mutex, PoisonError};
+
+use qiyashash_crypto::aead::AeadKey;
+use qiyashash_crypto::stream::{StreamDecryptor, NONCE_PREFIX_SIZE};
+use qiyashash_protocol::compression::Decompressor;
+use zeroize::Zeroize;
+
+pub use qiyashash_protocol::Compression;
+
+use crate::{MobileError, MobileErrorCode, MobileResult};
+
+/// Key material for decrypting one attachment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachmentManifest {
+    /// Attachment key (base64, 32 bytes)
+    pub key: String,
+    /// Stream nonce prefix (base64)
+    pub nonce_prefix: String,
+    /// How the sender compressed the attachment
+    pub compression: Compression,
+    /// Attachment size after decompression
+    pub size: u64,
+}
+
+/// Receives decrypted attachment chunks, implemented by the host
+pub trait ChunkSink: Send + Sync {
+    /// Handle the next plaintext chunk, in order
+    fn on_chunk(&self, chunk: Vec<u8>) -> MobileResult<()>;
+}
+
+/// Decrypts one attachment as its chunks arrive, passing plaintext to a sink
+///
+/// Only the latest ciphertext chunk is held back, until it is known whether
+/// it is the last. Output is decompressed with the manifest's algorithm and
+/// must come to exactly its `size`. Any failure ends the stream.
+pub struct AttachmentDecryptor {
+    state: Mutex<Option<DecryptState>>,
+    sink: Box<dyn ChunkSink>,
+}
+
+struct DecryptState {
+    decryptor: StreamDecryptor,
+    decompressor: Decompressor,
+    /// Latest chunk, decrypted once the next one arrives or the stream ends
+    held: Option<Vec<u8>>,
+    delivered: u64,
+}
+
+impl AttachmentDecryptor {
+    /// Start decrypting the attachment `manifest` describes into `sink`
+    pub fn new(manifest: AttachmentManifest, sink: Box<dyn ChunkSink>) -> MobileResult<Self> {
+        let mut key_bytes = decode_fixed::<32>(&manifest.key, "attachment key")?;
+        let key = AeadKey::from_bytes(key_bytes);
+        key_bytes.zeroize();
+        let prefix = decode_fixed::<NONCE_PREFIX_SIZE>(&manifest.nonce_prefix, "nonce prefix")?;
+
+        let state = DecryptState {
+            decryptor: StreamDecryptor::new(&key, prefix),
+            decompressor: Decompressor::new(manifest.compression, manifest.size)?,
+            held: None,
+            delivered: 0,
+        };
+        Ok(Self {
+            state: Mutex::new(Some(state)),
+            sink,
+        })
+    }
+
+    /// Pass the next ciphertext chunk, in order
+    ///
+    /// The previous chunk is decrypted and its plaintext delivered.
+    pub fn feed(&self, chunk: Vec<u8>) -> MobileResult<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let running = state.as_mut().ok_or_else(finished)?;
+
+        let result = match running.held.replace(chunk) {
+            Some(previous) => running.decrypt_next(&previous, self.sink.as_ref()),
+            None => Ok(()),
+        };
+        if result.is_err() {
+            *state = None;
+        }
+        result
+    }
+
+    /// End the stream, returning the number of bytes delivered
+    ///
+    /// The last chunk fed must be the stream's final chunk; otherwise the
+    /// attachment was truncated and an error is returned, after any earlier
+    /// chunks were delivered.
+    pub fn finish(&self) -> MobileResult<u64> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner).take();
+        state.ok_or_else(finished)?.finish(self.sink.as_ref())
+    }
+}
+
+impl DecryptState {
+    fn decrypt_next(&mut self, chunk: &[u8], sink: &dyn ChunkSink) -> MobileResult<()> {
+        let compressed = self.decryptor.decrypt_next(chunk).map_err(truncated_or)?;
+        deliver(&mut self.delivered, self.decompressor.update(&compressed)?, sink)
+    }
+
+    fn finish(self, sink: &dyn ChunkSink) -> MobileResult<u64> {
+        let Self { decryptor, mut decompressor, held, mut delivered } = self;
+        let chunk = held.ok_or_else(|| {
+            MobileError::new(MobileErrorCode::DecryptionFailed, "Attachment has no chunks")
+        })?;
+
+        let compressed = decryptor.decrypt_last(&chunk).map_err(truncated_or)?;
+        deliver(&mut delivered, decompressor.update(&compressed)?, sink)?;
+        deliver(&mut delivered, decompressor.finish()?, sink)?;
+        Ok(delivered)
+    }
+}
+
+fn deliver(delivered: &mut u64, plaintext: Vec<u8>, sink: &dyn ChunkSink) -> MobileResult<()> {
+    if plaintext.is_empty() {
+        return Ok(());
+    }
+    *delivered += plaintext.len() as u64;
+    sink.on_chunk(plaintext)
+}
+
+fn finished() -> MobileError {
+    MobileError::new(MobileErrorCode::InvalidInput, "Attachment stream already ended")
+}
+
+fn truncated_or(e: qiyashash_crypto::CryptoError) -> MobileError {
+    MobileError::new(
+        MobileErrorCode::DecryptionFailed,
+        format!("Attachment chunk rejected, possibly truncated: {}", e),
+    )
+}
+
+fn decode_fixed<const N: usize>(encoded: &str, what: &str) -> MobileResult<[u8; N]> {
+    let bytes = base64::decode(encoded)
+        .map_err(|e| MobileError::new(MobileErrorCode::InvalidInput, format!("Invalid {}: {}", what, e)))?;
+    bytes.try_into().map_err(|_| {
+        MobileError::new(MobileErrorCode::InvalidKey, format!("Invalid {} length", what))
+    })
+}
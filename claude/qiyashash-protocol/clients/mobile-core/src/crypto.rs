@@ -1,6 +1,7 @@
 //! Mobile crypto utilities
 
 use crate::MobileResult;
+use qiyashash_crypto::rng::secure_rng;
 use rand::RngCore;
 
 /// Mobile crypto utilities
@@ -10,14 +11,14 @@ impl MobileCrypto {
     /// Generate a random session key (32 bytes)
     pub fn generate_session_key() -> MobileResult<Vec<u8>> {
         let mut key = vec![0u8; 32];
-        rand::thread_rng().fill_bytes(&mut key);
+        secure_rng().fill_bytes(&mut key);
         Ok(key)
     }
 
     /// Generate random bytes
     pub fn random_bytes(len: usize) -> Vec<u8> {
         let mut bytes = vec![0u8; len];
-        rand::thread_rng().fill_bytes(&mut bytes);
+        secure_rng().fill_bytes(&mut bytes);
         bytes
     }
 
@@ -1,9 +1,11 @@
 //! User identity management for mobile
 
 use chrono::{DateTime, Utc};
+use qiyashash_crypto::rng::secure_rng;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
+use zeroize::Zeroize;
 
 #[derive(Error, Debug)]
 pub enum IdentityError {
@@ -43,11 +45,11 @@ impl UserIdentity {
         
         // Generate signing key pair (Ed25519)
         let mut signing_seed = [0u8; 32];
-        rand::thread_rng().fill_bytes(&mut signing_seed);
+        secure_rng().fill_bytes(&mut signing_seed);
         
         // Generate encryption key pair (X25519)
         let mut encryption_seed = [0u8; 32];
-        rand::thread_rng().fill_bytes(&mut encryption_seed);
+        secure_rng().fill_bytes(&mut encryption_seed);
         
         // In a real implementation, use proper Ed25519 and X25519 key derivation
         // For now, we use the seeds as keys (simplified)
@@ -63,6 +65,11 @@ impl UserIdentity {
         })
     }
 
+    /// Whether the secret keys have been zeroized
+    pub fn is_wiped(&self) -> bool {
+        self.signing_secret_key.is_empty() && self.encryption_secret_key.is_empty()
+    }
+
     /// Get public key in base64 format (for sharing)
     pub fn public_key_base64(&self) -> String {
         base64::encode(format!(
@@ -84,7 +91,7 @@ impl UserIdentity {
         
         // Generate nonce
         let mut nonce_bytes = [0u8; 12];
-        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut nonce_bytes);
+        rand::RngCore::fill_bytes(&mut secure_rng(), &mut nonce_bytes);
         let nonce = Nonce::from_slice(&nonce_bytes);
         
         // Encrypt
@@ -131,6 +138,10 @@ impl UserIdentity {
     fn derive_shared_secret(&self, peer_public_key: &str) -> Result<[u8; 32], IdentityError> {
         use sha2::{Sha256, Digest};
         
+        if self.is_wiped() {
+            return Err(IdentityError::InvalidKey("identity was wiped".into()));
+        }
+
         // Simplified shared secret derivation
         // In real implementation, use X25519 ECDH
         let mut hasher = Sha256::new();
@@ -145,6 +156,13 @@ impl UserIdentity {
     }
 }
 
+impl Zeroize for UserIdentity {
+    fn zeroize(&mut self) {
+        self.signing_secret_key.zeroize();
+        self.encryption_secret_key.zeroize();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -6,37 +6,167 @@
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::RwLock;
+use tracing::info;
+use zeroize::Zeroize;
 
+use qiyashash_core::message::{self as core_message, Message, MessageEnvelope, MessageId, ReceiptType};
+use qiyashash_core::storage::{MessageStore, PreKeyStore, Storage};
+use qiyashash_core::types::{DeviceId, Timestamp, UserId};
+use qiyashash_crypto::CryptoError;
+use qiyashash_protocol::config::PrivacyConfig;
+use qiyashash_protocol::protocol::DevicePreKeyBundle;
+use qiyashash_protocol::{
+    ClientConfig, ProtocolClient, ProtocolError, ProtocolMessage, ProtocolMessageType,
+};
+
+mod attachments;
 mod crypto;
 mod identity;
 mod messaging;
+mod prekeys;
+mod protocol_store;
 mod storage;
 
+pub use attachments::*;
 pub use crypto::*;
 pub use identity::*;
 pub use messaging::*;
+pub use prekeys::*;
 pub use storage::*;
 
+use protocol_store::SledStorage;
+
+/// Stable error codes hosts can switch on
+///
+/// Codes keep their meaning across releases; new ones are only added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MobileErrorCode {
+    /// Client or identity not set up yet
+    NotInitialized,
+    /// Malformed argument
+    InvalidInput,
+    /// Unusable public or private key
+    InvalidKey,
+    /// Encryption failed
+    EncryptionFailed,
+    /// Ciphertext did not authenticate or could not be decrypted
+    DecryptionFailed,
+    /// Peer's identity key is not trusted
+    UntrustedIdentity,
+    /// No session with the peer
+    SessionNotFound,
+    /// Local storage failed
+    StorageFailed,
+    /// Network failure
+    NetworkFailed,
+    /// Any other cryptographic failure
+    CryptoFailed,
+    /// Unexpected internal failure
+    Internal,
+}
+
 /// Mobile-specific error types
+///
+/// Hosts branch on [`MobileError::code`]; the message is for logging.
 #[derive(Error, Debug)]
 pub enum MobileError {
-    #[error("Initialization error: {0}")]
-    InitError(String),
+    #[error("{message}")]
+    Failed {
+        code: MobileErrorCode,
+        message: String,
+    },
+}
 
-    #[error("Crypto error: {0}")]
-    CryptoError(String),
+impl MobileError {
+    /// Create an error with `code`
+    pub fn new(code: MobileErrorCode, message: impl Into<String>) -> Self {
+        Self::Failed { code, message: message.into() }
+    }
 
-    #[error("Storage error: {0}")]
-    StorageError(String),
+    /// Error for calls made before the client or identity is set up
+    pub fn not_initialized() -> Self {
+        Self::new(MobileErrorCode::NotInitialized, "Not initialized")
+    }
 
-    #[error("Network error: {0}")]
-    NetworkError(String),
+    /// Stable code of this error
+    pub fn code(&self) -> MobileErrorCode {
+        match self {
+            Self::Failed { code, .. } => *code,
+        }
+    }
 
-    #[error("Not initialized")]
-    NotInitialized,
+    /// Human-readable description
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Failed { message, .. } => message,
+        }
+    }
+}
+
+impl From<IdentityError> for MobileError {
+    fn from(e: IdentityError) -> Self {
+        let code = match e {
+            IdentityError::KeyGeneration(_) => MobileErrorCode::CryptoFailed,
+            IdentityError::Encryption(_) => MobileErrorCode::EncryptionFailed,
+            IdentityError::Decryption(_) => MobileErrorCode::DecryptionFailed,
+            IdentityError::InvalidKey(_) => MobileErrorCode::InvalidKey,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl From<StorageError> for MobileError {
+    fn from(e: StorageError) -> Self {
+        Self::new(MobileErrorCode::StorageFailed, e.to_string())
+    }
+}
+
+impl From<uniffi::UnexpectedUniFFICallbackError> for MobileError {
+    fn from(e: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        Self::new(MobileErrorCode::Internal, e.reason)
+    }
+}
 
-    #[error("Invalid input: {0}")]
-    InvalidInput(String),
+impl From<CryptoError> for MobileError {
+    fn from(e: CryptoError) -> Self {
+        let code = match e {
+            CryptoError::EncryptionFailed(_) => MobileErrorCode::EncryptionFailed,
+            CryptoError::DecryptionFailed(_) | CryptoError::AuthenticationFailed => {
+                MobileErrorCode::DecryptionFailed
+            }
+            CryptoError::InvalidKeyLength { .. } | CryptoError::InvalidPublicKey(_) => {
+                MobileErrorCode::InvalidKey
+            }
+            _ => MobileErrorCode::CryptoFailed,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl From<ProtocolError> for MobileError {
+    fn from(e: ProtocolError) -> Self {
+        let code = match e {
+            ProtocolError::Crypto(e) => return e.into(),
+            ProtocolError::NotInitialized => MobileErrorCode::NotInitialized,
+            ProtocolError::DecryptionFailed(_) | ProtocolError::MalformedPlaintext(_) => {
+                MobileErrorCode::DecryptionFailed
+            }
+            ProtocolError::UntrustedIdentity(_) | ProtocolError::IdentityMismatch { .. } => {
+                MobileErrorCode::UntrustedIdentity
+            }
+            ProtocolError::SessionNotFound(_) | ProtocolError::SessionNotEstablished(_) => {
+                MobileErrorCode::SessionNotFound
+            }
+            ProtocolError::InvalidMessage(_)
+            | ProtocolError::InvalidPreKeyBundle(_)
+            | ProtocolError::MessageTooLarge { .. }
+            | ProtocolError::Decompression(_) => MobileErrorCode::InvalidInput,
+            ProtocolError::Storage(_) => MobileErrorCode::StorageFailed,
+            ProtocolError::Delivery(_) => MobileErrorCode::NetworkFailed,
+            _ => MobileErrorCode::Internal,
+        };
+        Self::new(code, e.to_string())
+    }
 }
 
 /// Result type for mobile operations
@@ -52,17 +182,101 @@ pub struct QiyasHashClient {
 
 struct ClientInner {
     identity: Option<UserIdentity>,
+    device_id: Option<DeviceId>,
     storage: Option<SecureStorage>,
+    messages: Option<Arc<SledStorage>>,
+    protocol: Option<ProtocolClient<SledStorage>>,
+    uploader: Option<Box<dyn PreKeyUploader>>,
+    prekey_policy: PreKeyPolicy,
+    privacy: PrivacyConfig,
     initialized: bool,
 }
 
+impl ClientInner {
+    /// Session layer of the loaded identity
+    fn protocol(&self) -> MobileResult<&ProtocolClient<SledStorage>> {
+        self.protocol.as_ref().ok_or_else(MobileError::not_initialized)
+    }
+
+    /// Sessions, keys and history of the opened storage
+    fn messages(&self) -> MobileResult<&Arc<SledStorage>> {
+        self.messages.as_ref().ok_or_else(MobileError::not_initialized)
+    }
+
+    /// Start the session layer for the loaded identity on this device
+    ///
+    /// Sessions and keys saved by an earlier run are picked up from storage.
+    async fn start_protocol(&mut self) -> MobileResult<()> {
+        let user_id = self.identity.as_ref()
+            .map(|identity| UserId::from_string(identity.id.clone()))
+            .ok_or_else(MobileError::not_initialized)?;
+        let device_id = self.device_id.clone()
+            .ok_or_else(MobileError::not_initialized)?;
+
+        let protocol = ProtocolClient::new(ClientConfig::default(), self.messages()?.clone())
+            .with_ids(user_id, device_id);
+        protocol.initialize().await?;
+        self.protocol = Some(protocol);
+        self.flush().await
+    }
+
+    /// Make the records changed so far survive a crash
+    ///
+    /// Each change is written as its own record when it happens; this only
+    /// waits for sled to sync them.
+    async fn flush(&self) -> MobileResult<()> {
+        self.messages()?.flush().await
+            .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))
+    }
+
+    /// Protocol message (JSON) carrying `envelope`, for the host to send
+    fn outgoing(&self, envelope: MessageEnvelope) -> MobileResult<String> {
+        let protocol = self.protocol()?;
+        let message = ProtocolMessage::new(
+            ProtocolMessageType::EncryptedMessage(envelope),
+            protocol.user_id().clone(),
+            protocol.device_id().clone(),
+        );
+        serde_json::to_string(&message)
+            .map_err(|e| MobileError::new(MobileErrorCode::Internal, e.to_string()))
+    }
+
+    /// Stored message `message_id`, which must be one we received
+    async fn received_message(&self, message_id: &MessageId) -> MobileResult<Message> {
+        let protocol = self.protocol()?;
+        let stored = self.messages()?.get_message(message_id).await
+            .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
+
+        stored
+            .filter(|message| &message.recipient_id == protocol.user_id())
+            .ok_or_else(|| {
+                MobileError::new(MobileErrorCode::InvalidInput, format!("No received message {}", message_id))
+            })
+    }
+
+    /// Encrypt a receipt for `message` through the session with its sender
+    async fn seal_receipt(&self, message: &Message, receipt_type: ReceiptType) -> MobileResult<String> {
+        let envelope = self.protocol()?
+            .send_receipt(&message.sender_id, &message.sender_device_id, &message.id, receipt_type)
+            .await?;
+        self.flush().await?;
+        self.outgoing(envelope)
+    }
+}
+
 impl QiyasHashClient {
     /// Create a new QiyasHash client
     pub fn new() -> Self {
         Self {
             inner: Arc::new(RwLock::new(ClientInner {
                 identity: None,
+                device_id: None,
                 storage: None,
+                messages: None,
+                protocol: None,
+                uploader: None,
+                prekey_policy: PreKeyPolicy::default(),
+                privacy: PrivacyConfig::default(),
                 initialized: false,
             })),
         }
@@ -71,41 +285,82 @@ impl QiyasHashClient {
     /// Initialize the client with a storage path
     pub async fn initialize(&self, storage_path: String) -> MobileResult<()> {
         let mut inner = self.inner.write().await;
-        
-        let storage = SecureStorage::new(&storage_path)
-            .map_err(|e| MobileError::StorageError(e.to_string()))?;
-        
+
+        // Release the previous database before opening one, which may be
+        // the same path
+        inner.protocol = None;
+        inner.messages = None;
+        inner.storage = None;
+        let storage = SecureStorage::new(&storage_path)?;
+        inner.device_id = Some(storage.load_or_create_device_id()?);
+        inner.messages = Some(SledStorage::open(&storage).await?);
+
         inner.storage = Some(storage);
         inner.initialized = true;
         
         Ok(())
     }
 
+    /// This install's device ID
+    ///
+    /// Created on the first `initialize` and kept in storage, so it only
+    /// changes when the app's data is wiped.
+    pub async fn get_device_id(&self) -> MobileResult<String> {
+        let inner = self.inner.read().await;
+        inner.device_id.as_ref()
+            .map(|id| id.to_string())
+            .ok_or_else(MobileError::not_initialized)
+    }
+
     /// Check if client is initialized
     pub async fn is_initialized(&self) -> bool {
         self.inner.read().await.initialized
     }
 
-    /// Create or load identity
-    pub async fn create_identity(&self, display_name: String) -> MobileResult<String> {
+    /// Generate and save a new identity
+    ///
+    /// Fails with `InvalidInput` if an identity is already loaded or stored,
+    /// unless `overwrite` is set; overwriting discards existing sessions and
+    /// message history.
+    pub async fn create_identity(
+        &self,
+        display_name: String,
+        overwrite: bool,
+    ) -> MobileResult<String> {
+        // Held until the new identity is saved, so concurrent calls cannot
+        // both pass the existence check
         let mut inner = self.inner.write().await;
         
         if !inner.initialized {
-            return Err(MobileError::NotInitialized);
+            return Err(MobileError::not_initialized());
+        }
+
+        if !overwrite {
+            let stored = match inner.storage {
+                Some(ref storage) => storage.load_identity()?.is_some(),
+                None => false,
+            };
+            if stored || inner.identity.is_some() {
+                return Err(MobileError::new(MobileErrorCode::InvalidInput, "identity exists"));
+            }
         }
 
-        let identity = UserIdentity::generate(display_name)
-            .map_err(|e| MobileError::CryptoError(e.to_string()))?;
+        let identity = UserIdentity::generate(display_name)?;
         
         let identity_id = identity.id.clone();
         
         // Save to storage
         if let Some(ref storage) = inner.storage {
-            storage.save_identity(&identity)
-                .map_err(|e| MobileError::StorageError(e.to_string()))?;
+            storage.save_identity(&identity)?;
+        }
+        if overwrite {
+            inner.protocol = None;
+            inner.messages()?.clear().await
+                .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
         }
         
         inner.identity = Some(identity);
+        inner.start_protocol().await?;
         
         Ok(identity_id)
     }
@@ -115,15 +370,14 @@ impl QiyasHashClient {
         let mut inner = self.inner.write().await;
         
         if !inner.initialized {
-            return Err(MobileError::NotInitialized);
+            return Err(MobileError::not_initialized());
         }
 
         if let Some(ref storage) = inner.storage {
-            if let Some(identity) = storage.load_identity()
-                .map_err(|e| MobileError::StorageError(e.to_string()))? 
-            {
+            if let Some(identity) = storage.load_identity()? {
                 let id = identity.id.clone();
                 inner.identity = Some(identity);
+                inner.start_protocol().await?;
                 return Ok(Some(id));
             }
         }
@@ -142,68 +396,281 @@ impl QiyasHashClient {
         let inner = self.inner.read().await;
         
         let identity = inner.identity.as_ref()
-            .ok_or(MobileError::NotInitialized)?;
+            .ok_or_else(MobileError::not_initialized)?;
         
         Ok(identity.public_key_base64())
     }
 
-    /// Encrypt a message for a recipient
+    /// Encrypt a text message for one of a recipient's devices
+    ///
+    /// Needs a session with that device, see [`Self::establish_session`].
+    /// Returns the protocol message (JSON) for the host to send; the
+    /// message is kept in the history.
     pub async fn encrypt_message(
         &self,
-        recipient_public_key: String,
+        recipient_id: String,
+        recipient_device_id: String,
         plaintext: String,
     ) -> MobileResult<String> {
+        let inner = self.inner.write().await;
+
+        let envelope = inner.protocol()?
+            .send_message(
+                &UserId::from_string(recipient_id),
+                &DeviceId::from_string(recipient_device_id),
+                &plaintext,
+            )
+            .await?;
+        inner.flush().await?;
+        inner.outgoing(envelope)
+    }
+
+    /// Decrypt a received protocol message (JSON)
+    ///
+    /// A text message is stored, so it shows up in
+    /// [`Self::list_conversations`] as unread until [`Self::mark_read`], and
+    /// returned. A receipt updates the status of the message it refers to
+    /// and returns `None`.
+    pub async fn decrypt_message(&self, message: String) -> MobileResult<Option<ReceivedMessage>> {
+        let inner = self.inner.write().await;
+
+        let protocol = inner.protocol()?;
+        let message: ProtocolMessage = serde_json::from_str(&message)
+            .map_err(|e| MobileError::new(MobileErrorCode::InvalidInput, e.to_string()))?;
+        let ProtocolMessageType::EncryptedMessage(envelope) = &message.message_type else {
+            return Err(MobileError::new(MobileErrorCode::InvalidInput, "Not an encrypted message"));
+        };
+
+        let decrypted = protocol
+            .decrypt_message(&message.sender_id, &message.sender_device_id, envelope)
+            .await;
+        let applied = match &decrypted {
+            Ok(decrypted) if decrypted.is_control() => {
+                protocol.apply_control(&message.sender_id, decrypted).await
+            }
+            _ => Ok(()),
+        };
+        // The ratchet moved even if the message turns out to be unusable
+        inner.flush().await?;
+        applied?;
+
+        let decrypted = decrypted?;
+        if decrypted.is_control() {
+            return Ok(None);
+        }
+        let text = decrypted.content_as_string()
+            .ok_or_else(|| MobileError::new(MobileErrorCode::InvalidInput, "Not a text message"))?;
+        Ok(Some(ReceivedMessage {
+            id: decrypted.id.to_string(),
+            sender_id: decrypted.sender_id.to_string(),
+            text,
+        }))
+    }
+
+    /// This device's prekey bundle (JSON), for peers to start sessions with
+    ///
+    /// The bundle names this install's device ID, so sessions built from it
+    /// are with this device.
+    pub async fn get_prekey_bundle(&self) -> MobileResult<String> {
         let inner = self.inner.read().await;
-        
-        let identity = inner.identity.as_ref()
-            .ok_or(MobileError::NotInitialized)?;
-        
-        let ciphertext = identity.encrypt_for(&recipient_public_key, plaintext.as_bytes())
-            .map_err(|e| MobileError::CryptoError(e.to_string()))?;
-        
-        Ok(base64::encode(&ciphertext))
+
+        let bundle = inner.protocol()?.device_prekey_bundle()?;
+        serde_json::to_string(&bundle)
+            .map_err(|e| MobileError::new(MobileErrorCode::Internal, e.to_string()))
     }
 
-    /// Decrypt a received message
-    pub async fn decrypt_message(
-        &self,
-        sender_public_key: String,
-        ciphertext: String,
-    ) -> MobileResult<String> {
+    /// Start a session with `peer_id` from a prekey bundle (JSON) one of
+    /// their devices published
+    ///
+    /// The session is with the device the bundle names, once its signature
+    /// and age check out. Returns that device's ID.
+    pub async fn establish_session(&self, peer_id: String, bundle: String) -> MobileResult<String> {
+        // Held until the new state is saved, like the other state changes
+        let inner = self.inner.write().await;
+
+        let protocol = inner.protocol()?;
+        let bundle: DevicePreKeyBundle = serde_json::from_str(&bundle)
+            .map_err(|e| MobileError::new(MobileErrorCode::InvalidInput, e.to_string()))?;
+        bundle.verify(Timestamp::now(), protocol.config().max_prekey_bundle_age_secs)?;
+
+        protocol.establish_session(&UserId::from_string(peer_id), &bundle.device_id, &bundle).await?;
+        inner.flush().await?;
+        Ok(bundle.device_id.to_string())
+    }
+
+    /// List conversations with their last message, newest first
+    pub async fn list_conversations(&self) -> MobileResult<Vec<ConversationSummary>> {
         let inner = self.inner.read().await;
-        
+
         let identity = inner.identity.as_ref()
-            .ok_or(MobileError::NotInitialized)?;
-        
-        let ciphertext_bytes = base64::decode(&ciphertext)
-            .map_err(|e| MobileError::InvalidInput(e.to_string()))?;
-        
-        let plaintext = identity.decrypt_from(&sender_public_key, &ciphertext_bytes)
-            .map_err(|e| MobileError::CryptoError(e.to_string()))?;
-        
-        String::from_utf8(plaintext)
-            .map_err(|e| MobileError::InvalidInput(e.to_string()))
+            .ok_or_else(MobileError::not_initialized)?;
+
+        let summaries = inner.messages()?
+            .get_conversation_summaries(&UserId::from_string(identity.id.clone()))
+            .await
+            .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
+
+        Ok(summaries.into_iter().map(ConversationSummary::from).collect())
+    }
+
+    /// Enable or disable sending read receipts
+    pub async fn set_read_receipts_enabled(&self, enabled: bool) {
+        self.inner.write().await.privacy.send_read_receipts = enabled;
+    }
+
+    /// Record that a received message arrived
+    ///
+    /// Returns the delivery receipt, encrypted through the session with the
+    /// message's sender, as a protocol message (JSON) for the host to send.
+    pub async fn mark_delivered(&self, message_id: String) -> MobileResult<String> {
+        let inner = self.inner.write().await;
+
+        let mut message = inner.received_message(&MessageId::from_string(message_id)).await?;
+        if message.status != core_message::MessageStatus::Read {
+            message.status = core_message::MessageStatus::Delivered;
+            inner.messages()?.save_message(&message).await
+                .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
+        }
+
+        inner.seal_receipt(&message, ReceiptType::Delivered).await
+    }
+
+    /// Record that a conversation was read up to `up_to_message_id`
+    ///
+    /// Returns the read receipt, encrypted through the session with the
+    /// other participant, as a protocol message (JSON) for the host to send,
+    /// or `None` when read receipts are disabled. Local unread state is
+    /// updated either way.
+    pub async fn mark_read(
+        &self,
+        conversation_id: String,
+        up_to_message_id: String,
+    ) -> MobileResult<Option<String>> {
+        let inner = self.inner.write().await;
+
+        let message = inner.received_message(&MessageId::from_string(up_to_message_id)).await?;
+        let conversation_id = UserId::from_string(conversation_id);
+        if message.sender_id != conversation_id {
+            return Err(MobileError::new(
+                MobileErrorCode::InvalidInput,
+                format!("Message {} is not from {}", message.id, conversation_id),
+            ));
+        }
+        inner.messages()?.mark_as_read(&conversation_id, &message.id).await
+            .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
+
+        if !inner.privacy.send_read_receipts {
+            inner.flush().await?;
+            return Ok(None);
+        }
+        inner.seal_receipt(&message, ReceiptType::Read).await.map(Some)
+    }
+
+    /// Set where new one-time prekeys are published
+    pub async fn set_prekey_uploader(&self, uploader: Box<dyn PreKeyUploader>) {
+        self.inner.write().await.uploader = Some(uploader);
+    }
+
+    /// Replenish below `threshold` prekeys with batches of `batch_size`
+    pub async fn set_prekey_policy(&self, threshold: u32, batch_size: u32) -> MobileResult<()> {
+        if batch_size == 0 {
+            return Err(MobileError::new(MobileErrorCode::InvalidInput, "batch_size must be positive"));
+        }
+        self.inner.write().await.prekey_policy = PreKeyPolicy { threshold, batch_size };
+        Ok(())
+    }
+
+    /// Generate and upload a batch of one-time prekeys if few are left
+    ///
+    /// Counts the unused prekeys the session layer holds, which answer
+    /// initial messages. Returns the number of prekeys added. If the upload
+    /// fails the new keys are discarded, so the next call tries again.
+    pub async fn replenish_prekeys_if_needed(&self) -> MobileResult<u32> {
+        // Held until the new state is saved, like the other state changes
+        let inner = self.inner.write().await;
+
+        if inner.identity.is_none() {
+            return Err(MobileError::not_initialized());
+        }
+        let protocol = inner.protocol()?;
+        let uploader = inner.uploader.as_ref()
+            .ok_or_else(|| MobileError::new(MobileErrorCode::NotInitialized, "No prekey uploader set"))?;
+
+        let policy = inner.prekey_policy;
+        let available = inner.messages()?.get_one_time_prekey_count().await
+            .map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
+        if available >= policy.threshold as usize {
+            return Ok(0);
+        }
+
+        let prekeys = protocol.generate_prekeys(policy.batch_size as usize).await?;
+        let ids: Vec<u32> = prekeys.iter().map(|p| p.id).collect();
+        let upload = prekeys.into_iter().map(PublicPreKey::from).collect();
+        if let Err(e) = uploader.upload_prekeys(protocol.device_id().to_string(), upload) {
+            protocol.discard_prekeys(&ids).await?;
+            return Err(e);
+        }
+
+        inner.flush().await?;
+        Ok(policy.batch_size)
+    }
+
+    /// Periodic maintenance for the host's background scheduler
+    ///
+    /// Hosts run this from BGTaskScheduler or WorkManager about every
+    /// [`BACKGROUND_REFRESH_INTERVAL_SECS`]. Returns the number of prekeys
+    /// added.
+    pub async fn background_refresh(&self) -> MobileResult<u32> {
+        self.replenish_prekeys_if_needed().await
     }
 
     /// Generate a random session key
     pub fn generate_session_key(&self) -> MobileResult<String> {
-        let key = MobileCrypto::generate_session_key()
-            .map_err(|e| MobileError::CryptoError(e.to_string()))?;
+        let key = MobileCrypto::generate_session_key()?;
         Ok(base64::encode(&key))
     }
 
     /// Delete all local data
-    pub async fn wipe_data(&self) -> MobileResult<()> {
+    ///
+    /// Stored values are overwritten before deletion and the loaded
+    /// identity's secrets are zeroized; see [`SecureStorage::secure_wipe`].
+    /// Sessions and message history held in memory are dropped too, even
+    /// if wiping the storage fails. Returns what was destroyed.
+    pub async fn wipe_data(&self) -> MobileResult<WipeReport> {
         let mut inner = self.inner.write().await;
-        
+        let inner = &mut *inner;
+
+        inner.protocol = None;
+        let mut identity = inner.identity.take();
+        let cleared = match inner.messages.take() {
+            Some(messages) => messages.clear().await,
+            None => Ok(()),
+        };
+
+        let wiped = match inner.storage {
+            Some(ref storage) => storage.secure_wipe(identity.as_mut()),
+            None => Ok(WipeReport::default()),
+        };
+        if let Some(identity) = identity.as_mut() {
+            identity.zeroize();
+        }
+        cleared.map_err(|e| MobileError::new(MobileErrorCode::StorageFailed, e.to_string()))?;
+        let mut report = wiped?;
+        report.secrets_zeroized = identity.is_some();
+
         if let Some(ref storage) = inner.storage {
-            storage.wipe_all()
-                .map_err(|e| MobileError::StorageError(e.to_string()))?;
+            // Wiped data means a new install as far as peers can tell
+            inner.device_id = Some(storage.load_or_create_device_id()?);
+            inner.messages = Some(SledStorage::open(storage).await?);
         }
-        
-        inner.identity = None;
-        
-        Ok(())
+
+        info!(
+            entries = report.entries_overwritten,
+            bytes = report.bytes_overwritten,
+            secrets_zeroized = report.secrets_zeroized,
+            "Wiped local data"
+        );
+        Ok(report)
     }
 }
 
@@ -230,4 +697,477 @@ mod tests {
         
         assert!(client.is_initialized().await);
     }
+
+    #[derive(Default)]
+    struct MockUploader {
+        uploaded: std::sync::Mutex<Vec<PublicPreKey>>,
+        device_ids: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl PreKeyUploader for Arc<MockUploader> {
+        fn upload_prekeys(&self, device_id: String, prekeys: Vec<PublicPreKey>) -> MobileResult<()> {
+            self.device_ids.lock().unwrap().push(device_id);
+            self.uploaded.lock().unwrap().extend(prekeys);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replenish_prekeys() {
+        let (me_dir, alice_dir) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+        let (client, my_id, my_device) = user(&me_dir, "Me").await;
+        let (alice, _, _) = user(&alice_dir, "Alice").await;
+
+        let uploader = Arc::new(MockUploader::default());
+        client.set_prekey_uploader(Box::new(uploader.clone())).await;
+        client.set_prekey_policy(10, 10).await.unwrap();
+
+        // Nothing stored yet, so a full batch is generated and uploaded
+        assert_eq!(client.replenish_prekeys_if_needed().await.unwrap(), 10);
+        let uploaded = uploader.uploaded.lock().unwrap().clone();
+        assert_eq!(uploaded.len(), 10);
+
+        // Healthy supply: nothing to do
+        assert_eq!(client.background_refresh().await.unwrap(), 0);
+        assert_eq!(uploader.uploaded.lock().unwrap().len(), 10);
+
+        // A peer starts a session with an uploaded prekey, and the first
+        // message opens with its secret
+        let bundle = client.get_prekey_bundle().await.unwrap();
+        let parsed: DevicePreKeyBundle = serde_json::from_str(&bundle).unwrap();
+        let opk_id = parsed.one_time_prekey_id.expect("bundle offers a one-time prekey");
+        let published = uploaded.iter().find(|p| p.id == opk_id).expect("prekey was uploaded");
+        assert_eq!(base64::encode(parsed.one_time_prekey.unwrap()), published.public_key);
+
+        alice.establish_session(my_id.clone(), bundle).await.unwrap();
+        let sent = alice.encrypt_message(my_id, my_device, "hi".to_string()).await.unwrap();
+        let received = client.decrypt_message(sent).await.unwrap().unwrap();
+        assert_eq!(received.text, "hi");
+
+        // The used prekey is gone, which drops the supply below the
+        // threshold; new IDs continue after the old ones
+        assert_eq!(client.replenish_prekeys_if_needed().await.unwrap(), 10);
+        let uploaded = uploader.uploaded.lock().unwrap().clone();
+        assert_eq!(uploaded.len(), 20);
+        assert!(uploaded[10].id > uploaded[9].id);
+        assert!(uploaded[10..].iter().all(|p| p.id != opk_id));
+
+        // Every batch is published for this install's device
+        let device_id = client.get_device_id().await.unwrap();
+        assert_eq!(*uploader.device_ids.lock().unwrap(), vec![device_id.clone(), device_id]);
+    }
+
+    #[tokio::test]
+    async fn test_device_id_persists_per_install() {
+        let first_install = tempfile::TempDir::new().unwrap();
+        let second_install = tempfile::TempDir::new().unwrap();
+        let path = |dir: &tempfile::TempDir| dir.path().to_string_lossy().to_string();
+
+        let client = QiyasHashClient::new();
+        assert_eq!(client.get_device_id().await.unwrap_err().code(), MobileErrorCode::NotInitialized);
+
+        client.initialize(path(&first_install)).await.unwrap();
+        let device_id = client.get_device_id().await.unwrap();
+        client.initialize(path(&first_install)).await.unwrap();
+        assert_eq!(client.get_device_id().await.unwrap(), device_id);
+
+        // A restarted app reads the same ID back
+        drop(client);
+        let restarted = QiyasHashClient::new();
+        restarted.initialize(path(&first_install)).await.unwrap();
+        assert_eq!(restarted.get_device_id().await.unwrap(), device_id);
+
+        let other = QiyasHashClient::new();
+        other.initialize(path(&second_install)).await.unwrap();
+        assert_ne!(other.get_device_id().await.unwrap(), device_id);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_use_bundle_device() {
+        let alice_dir = tempfile::TempDir::new().unwrap();
+        let bob_dir = tempfile::TempDir::new().unwrap();
+        let path = |dir: &tempfile::TempDir| dir.path().to_string_lossy().to_string();
+
+        let alice = QiyasHashClient::new();
+        alice.initialize(path(&alice_dir)).await.unwrap();
+        alice.create_identity("Alice".to_string(), false).await.unwrap();
+        let bob = QiyasHashClient::new();
+        bob.initialize(path(&bob_dir)).await.unwrap();
+        let bob_id = bob.create_identity("Bob".to_string(), false).await.unwrap();
+        let bob_device = bob.get_device_id().await.unwrap();
+
+        let bundle = bob.get_prekey_bundle().await.unwrap();
+        let mut forged: DevicePreKeyBundle = serde_json::from_str(&bundle).unwrap();
+        assert_eq!(forged.device_id.to_string(), bob_device);
+
+        forged.signed_prekey_signature[0] ^= 1;
+        let err = alice
+            .establish_session(bob_id.clone(), serde_json::to_string(&forged).unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+
+        assert_eq!(alice.establish_session(bob_id.clone(), bundle).await.unwrap(), bob_device);
+
+        // The session survives a restart, still bound to Bob's device
+        drop(alice);
+        let alice = QiyasHashClient::new();
+        alice.initialize(path(&alice_dir)).await.unwrap();
+        alice.load_identity().await.unwrap();
+        let inner = alice.inner.read().await;
+        let sessions = inner.protocol().unwrap().sessions().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].their_user_id.to_string(), bob_id);
+        assert_eq!(sessions[0].their_device_id.to_string(), bob_device);
+    }
+
+    #[tokio::test]
+    async fn test_receipts() {
+        use qiyashash_core::message::MessageStatus;
+
+        let (me_dir, alice_dir) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+        let (me, my_id, my_device) = user(&me_dir, "Me").await;
+        let (alice, alice_id, _) = user(&alice_dir, "Alice").await;
+        alice.establish_session(my_id.clone(), me.get_prekey_bundle().await.unwrap()).await.unwrap();
+
+        let mut received = Vec::new();
+        for text in ["one", "two", "three"] {
+            let sent = alice
+                .encrypt_message(my_id.clone(), my_device.clone(), text.to_string())
+                .await
+                .unwrap();
+            received.push(me.decrypt_message(sent).await.unwrap().unwrap());
+        }
+        let status = |message: &ReceivedMessage| {
+            let id = MessageId::from_string(message.id.clone());
+            let alice = &alice;
+            async move {
+                let inner = alice.inner.read().await;
+                inner.messages().unwrap().get_message(&id).await.unwrap().unwrap().status
+            }
+        };
+
+        // The receipt only opens in Alice's session and updates her copy
+        let receipt = me.mark_delivered(received[2].id.clone()).await.unwrap();
+        assert!(alice.decrypt_message(receipt).await.unwrap().is_none());
+        assert_eq!(status(&received[2]).await, MessageStatus::Delivered);
+        {
+            let inner = me.inner.read().await;
+            let id = MessageId::from_string(received[2].id.clone());
+            let stored = inner.messages().unwrap().get_message(&id).await.unwrap().unwrap();
+            assert_eq!(stored.status, MessageStatus::Delivered);
+        }
+
+        // Reading up to the second message leaves the third unread
+        let receipt = me
+            .mark_read(alice_id.clone(), received[1].id.clone())
+            .await
+            .unwrap()
+            .expect("read receipts are on by default");
+        assert!(alice.decrypt_message(receipt).await.unwrap().is_none());
+        assert_eq!(status(&received[1]).await, MessageStatus::Read);
+        assert_eq!(me.list_conversations().await.unwrap()[0].unread_count, 1);
+
+        // Only messages from the conversation's participant can be marked
+        let err = me.mark_read(my_id.clone(), received[2].id.clone()).await.unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+
+        // Disabled receipts are not produced, but the local state still changes
+        me.set_read_receipts_enabled(false).await;
+        let receipt = me.mark_read(alice_id, received[2].id.clone()).await.unwrap();
+        assert!(receipt.is_none());
+        assert_eq!(me.list_conversations().await.unwrap()[0].unread_count, 0);
+        assert_eq!(status(&received[2]).await, MessageStatus::Delivered);
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        chunks: std::sync::Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl ChunkSink for Arc<RecordingSink> {
+        fn on_chunk(&self, chunk: Vec<u8>) -> MobileResult<()> {
+            self.chunks.lock().unwrap().push(chunk);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_decrypt_attachment_stream() {
+        use qiyashash_crypto::aead::AeadKey;
+        use qiyashash_crypto::stream::StreamEncryptor;
+
+        let key = [0x5a; 32];
+        let mut encryptor = StreamEncryptor::new(&AeadKey::from_bytes(key));
+        let manifest = AttachmentManifest {
+            key: base64::encode(key),
+            nonce_prefix: base64::encode(encryptor.nonce_prefix()),
+            compression: Compression::None,
+            size: 18,
+        };
+        let mut sealed = vec![
+            encryptor.encrypt_next(b"first ").unwrap(),
+            encryptor.encrypt_next(b"second ").unwrap(),
+        ];
+        sealed.push(encryptor.encrypt_last(b"third").unwrap());
+
+        // Each chunk is delivered as soon as the next one shows it was not the last
+        let sink = Arc::new(RecordingSink::default());
+        let decryptor = AttachmentDecryptor::new(manifest.clone(), Box::new(sink.clone())).unwrap();
+        decryptor.feed(sealed[0].clone()).unwrap();
+        assert!(sink.chunks.lock().unwrap().is_empty());
+        decryptor.feed(sealed[1].clone()).unwrap();
+        assert_eq!(*sink.chunks.lock().unwrap(), vec![b"first ".to_vec()]);
+        decryptor.feed(sealed[2].clone()).unwrap();
+        assert_eq!(decryptor.finish().unwrap(), 18);
+        assert_eq!(
+            *sink.chunks.lock().unwrap(),
+            vec![b"first ".to_vec(), b"second ".to_vec(), b"third".to_vec()]
+        );
+        let err = decryptor.feed(sealed[0].clone()).unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+
+        // Dropping the final chunk is detected once the stream ends early
+        let sink = Arc::new(RecordingSink::default());
+        let err = decrypt_all(&manifest, &sealed[..2], &sink).unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::DecryptionFailed);
+        assert_eq!(sink.chunks.lock().unwrap().len(), 1);
+
+        let err = decrypt_all(&manifest, &[], &sink).unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::DecryptionFailed);
+    }
+
+    /// Feed all of `chunks` into a new decryptor and finish it
+    fn decrypt_all(
+        manifest: &AttachmentManifest,
+        chunks: &[Vec<u8>],
+        sink: &Arc<RecordingSink>,
+    ) -> MobileResult<u64> {
+        let decryptor = AttachmentDecryptor::new(manifest.clone(), Box::new(sink.clone()))?;
+        for chunk in chunks {
+            decryptor.feed(chunk.clone())?;
+        }
+        decryptor.finish()
+    }
+
+    #[test]
+    fn test_decrypt_compressed_attachment() {
+        use qiyashash_crypto::aead::AeadKey;
+        use qiyashash_crypto::stream::StreamEncryptor;
+
+        let original = b"attachment body ".repeat(512);
+        let compressed = Compression::Zstd.compress(&original).unwrap();
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+
+        let key = [0x3c; 32];
+        let mut encryptor = StreamEncryptor::new(&AeadKey::from_bytes(key));
+        let mut manifest = AttachmentManifest {
+            key: base64::encode(key),
+            nonce_prefix: base64::encode(encryptor.nonce_prefix()),
+            compression: Compression::Zstd,
+            size: original.len() as u64,
+        };
+        let sealed = vec![
+            encryptor.encrypt_next(head).unwrap(),
+            encryptor.encrypt_last(tail).unwrap(),
+        ];
+
+        let sink = Arc::new(RecordingSink::default());
+        let delivered = decrypt_all(&manifest, &sealed, &sink).unwrap();
+        assert_eq!(delivered, original.len() as u64);
+        assert_eq!(sink.chunks.lock().unwrap().concat(), original);
+
+        // A manifest claiming an implausible size is refused before decrypting
+        manifest.size = qiyashash_protocol::compression::MAX_DECOMPRESSED_SIZE + 1;
+        let sink = Arc::new(RecordingSink::default());
+        let err = decrypt_all(&manifest, &sealed, &sink).unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+        assert!(sink.chunks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_identity_refuses_to_replace() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().to_string_lossy().to_string();
+        let client = QiyasHashClient::new();
+        client.initialize(path.clone()).await.unwrap();
+        let original = client.create_identity("Me".to_string(), false).await.unwrap();
+
+        let err = client.create_identity("Me".to_string(), false).await.unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+        assert_eq!(client.get_identity_id().await.unwrap(), Some(original.clone()));
+
+        let replaced = client.create_identity("Me".to_string(), true).await.unwrap();
+        assert_ne!(replaced, original);
+        assert_eq!(client.get_identity_id().await.unwrap(), Some(replaced.clone()));
+
+        // A stored identity counts even before it is loaded
+        drop(client);
+        let client = QiyasHashClient::new();
+        client.initialize(path).await.unwrap();
+        let err = client.create_identity("Me".to_string(), false).await.unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+        assert_eq!(client.load_identity().await.unwrap(), Some(replaced));
+    }
+
+    /// A client with a fresh identity in `dir`, and its user and device IDs
+    async fn user(dir: &tempfile::TempDir, name: &str) -> (QiyasHashClient, String, String) {
+        let client = QiyasHashClient::new();
+        client.initialize(dir.path().to_string_lossy().to_string()).await.unwrap();
+        let user_id = client.create_identity(name.to_string(), false).await.unwrap();
+        let device_id = client.get_device_id().await.unwrap();
+        (client, user_id, device_id)
+    }
+
+    #[tokio::test]
+    async fn test_error_codes() {
+        let client = QiyasHashClient::new();
+        let err = client.create_identity("Me".to_string(), false).await.unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::NotInitialized);
+
+        let (alice_dir, bob_dir) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+        let (alice, _, _) = user(&alice_dir, "Alice").await;
+        let (bob, bob_id, bob_device) = user(&bob_dir, "Bob").await;
+
+        let err = alice
+            .encrypt_message(bob_id.clone(), bob_device.clone(), "hi".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::SessionNotFound);
+
+        alice.establish_session(bob_id.clone(), bob.get_prekey_bundle().await.unwrap()).await.unwrap();
+        let sent = alice.encrypt_message(bob_id, bob_device, "hi".to_string()).await.unwrap();
+        let mut tampered: ProtocolMessage = serde_json::from_str(&sent).unwrap();
+        if let ProtocolMessageType::EncryptedMessage(envelope) = &mut tampered.message_type {
+            let last = envelope.ciphertext.len() - 1;
+            envelope.ciphertext[last] ^= 1;
+        }
+        let err = bob
+            .decrypt_message(serde_json::to_string(&tampered).unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::DecryptionFailed);
+        assert!(!err.message().is_empty());
+
+        let err = bob.decrypt_message("not json".to_string()).await.unwrap_err();
+        assert_eq!(err.code(), MobileErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_decrypted_messages_are_listed() {
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::TempDir::new().unwrap()).collect();
+        let (me, my_id, my_device) = user(&dirs[0], "Me").await;
+        let (alice, alice_id, _) = user(&dirs[1], "Alice").await;
+        let (bob, bob_id, _) = user(&dirs[2], "Bob").await;
+
+        for (sender, text) in [(&alice, "hi"), (&alice, "are you there?"), (&bob, "lunch?")] {
+            // A bundle's one-time prekey is only handed out until it is used
+            if sender.inner.read().await.protocol().unwrap().sessions().unwrap().is_empty() {
+                let bundle = me.get_prekey_bundle().await.unwrap();
+                sender.establish_session(my_id.clone(), bundle).await.unwrap();
+            }
+            let sent = sender
+                .encrypt_message(my_id.clone(), my_device.clone(), text.to_string())
+                .await
+                .unwrap();
+            assert_eq!(me.decrypt_message(sent).await.unwrap().unwrap().text, text);
+            // Keep the send times apart so "last" is well defined
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+
+        let summaries = me.list_conversations().await.unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].contact_id, bob_id);
+        assert_eq!(summaries[0].last_message_preview.as_deref(), Some("lunch?"));
+        assert_eq!(summaries[0].unread_count, 1);
+        assert_eq!(summaries[1].contact_id, alice_id);
+        assert_eq!(summaries[1].last_message_preview.as_deref(), Some("are you there?"));
+        assert_eq!(summaries[1].unread_count, 2);
+
+        // History is kept across restarts
+        drop(me);
+        let me = QiyasHashClient::new();
+        me.initialize(dirs[0].path().to_string_lossy().to_string()).await.unwrap();
+        me.load_identity().await.unwrap();
+        assert_eq!(me.list_conversations().await.unwrap(), summaries);
+    }
+
+    #[tokio::test]
+    async fn test_wipe_data_clears_memory() {
+        let (me_dir, alice_dir) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+        let (me, my_id, my_device) = user(&me_dir, "Me").await;
+        let (alice, _, _) = user(&alice_dir, "Alice").await;
+        alice.establish_session(my_id.clone(), me.get_prekey_bundle().await.unwrap()).await.unwrap();
+        let sent = alice.encrypt_message(my_id, my_device.clone(), "hi".to_string()).await.unwrap();
+        me.decrypt_message(sent).await.unwrap();
+
+        let report = me.wipe_data().await.unwrap();
+        assert!(report.entries_overwritten > 0);
+        assert!(report.bytes_overwritten > 0);
+        assert!(report.secrets_zeroized);
+
+        {
+            let inner = me.inner.read().await;
+            assert!(inner.protocol.is_none());
+            assert!(inner.identity.is_none());
+            assert!(inner.messages().unwrap().export_snapshot().await.unwrap().is_empty());
+        }
+        assert_eq!(me.list_conversations().await.unwrap_err().code(), MobileErrorCode::NotInitialized);
+        assert_eq!(me.load_identity().await.unwrap(), None);
+        assert_ne!(me.get_device_id().await.unwrap(), my_device);
+    }
+
+    #[tokio::test]
+    async fn test_list_conversations() {
+        use qiyashash_core::message::{Message, MessageStatus};
+        use qiyashash_core::types::{DeviceId, Timestamp};
+
+        let client = QiyasHashClient::new();
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        client.initialize(temp_dir.path().to_string_lossy().to_string()).await.unwrap();
+        let store = client.inner.read().await.messages().unwrap().clone();
+        let me = UserId::from_string(client.create_identity("Me".to_string(), false).await.unwrap());
+        let (alice, bob) = (UserId::new(), UserId::new());
+
+        let base = 1_700_000_000;
+        let message_at = |from: &UserId, to: &UserId, text: &str, at: i64| {
+            let mut message = Message::text(from.clone(), DeviceId::new(), to.clone(), text);
+            message.created_at = Timestamp::from_secs(base + at);
+            message
+        };
+
+        let mut read = message_at(&alice, &me, "hi", 1);
+        read.status = MessageStatus::Read;
+        let messages = [
+            read,
+            message_at(&alice, &me, "are you there?", 2),
+            message_at(&me, &alice, "yes", 3),
+            message_at(&bob, &me, "lunch?", 4),
+            message_at(&bob, &me, "at noon", 5),
+            // Newest, but already gone
+            message_at(&bob, &me, "secret", 6).with_expiration(1),
+        ];
+        for message in &messages {
+            store.save_message(message).await.unwrap();
+        }
+
+        let summaries = client.list_conversations().await.unwrap();
+        assert_eq!(
+            summaries,
+            vec![
+                ConversationSummary {
+                    contact_id: bob.to_string(),
+                    last_message_preview: Some("at noon".to_string()),
+                    last_message_at: Some(Timestamp::from_secs(base + 5).as_millis()),
+                    unread_count: 2,
+                },
+                ConversationSummary {
+                    contact_id: alice.to_string(),
+                    last_message_preview: Some("yes".to_string()),
+                    last_message_at: Some(Timestamp::from_secs(base + 3).as_millis()),
+                    unread_count: 1,
+                },
+            ]
+        );
+    }
 }
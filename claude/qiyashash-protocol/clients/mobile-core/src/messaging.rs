@@ -122,3 +122,44 @@ impl Conversation {
         }
     }
 }
+
+/// A decrypted text message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    /// Message ID, for marking it delivered or read
+    pub id: String,
+    /// Sender's user ID, which is also the conversation ID
+    pub sender_id: String,
+    /// Message text
+    pub text: String,
+}
+
+/// Maximum number of characters in a conversation preview
+pub const PREVIEW_MAX_CHARS: usize = 80;
+
+/// Row of the conversation list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    /// The other participant
+    pub contact_id: String,
+    /// Start of the last message, if it is text and has not expired
+    pub last_message_preview: Option<String>,
+    /// When the last message was sent (milliseconds since the Unix epoch)
+    pub last_message_at: Option<i64>,
+    /// Received messages not yet read
+    pub unread_count: u32,
+}
+
+impl From<qiyashash_core::storage::ConversationSummary> for ConversationSummary {
+    fn from(summary: qiyashash_core::storage::ConversationSummary) -> Self {
+        let last = summary.last_message.as_ref();
+        Self {
+            contact_id: summary.other_user_id.to_string(),
+            last_message_preview: last
+                .and_then(|m| m.content_as_string())
+                .map(|text| text.chars().take(PREVIEW_MAX_CHARS).collect()),
+            last_message_at: last.map(|m| m.created_at.as_millis()),
+            unread_count: u32::try_from(summary.unread_count).unwrap_or(u32::MAX),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! One-time prekey replenishment for mobile
+
+use qiyashash_protocol::protocol::OneTimePreKeyInfo;
+
+use crate::MobileResult;
+
+/// How often hosts should run [`QiyasHashClient::background_refresh`]
+///
+/// [`QiyasHashClient::background_refresh`]: crate::QiyasHashClient::background_refresh
+pub const BACKGROUND_REFRESH_INTERVAL_SECS: u64 = 6 * 3600;
+
+/// Public half of a one-time prekey, as uploaded to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicPreKey {
+    /// Prekey ID
+    pub id: u32,
+    /// X25519 public key (base64)
+    pub public_key: String,
+}
+
+impl From<OneTimePreKeyInfo> for PublicPreKey {
+    fn from(prekey: OneTimePreKeyInfo) -> Self {
+        Self {
+            id: prekey.id,
+            public_key: base64::encode(prekey.public_key),
+        }
+    }
+}
+
+/// Publishes new one-time prekeys, implemented by the host
+pub trait PreKeyUploader: Send + Sync {
+    /// Upload `prekeys` of device `device_id` to the identity service
+    fn upload_prekeys(&self, device_id: String, prekeys: Vec<PublicPreKey>) -> MobileResult<()>;
+}
+
+/// When and how many one-time prekeys to generate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreKeyPolicy {
+    /// Replenish when fewer prekeys than this are stored
+    pub threshold: u32,
+    /// Number of prekeys generated per replenishment
+    pub batch_size: u32,
+}
+
+impl Default for PreKeyPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 20,
+            batch_size: 100,
+        }
+    }
+}
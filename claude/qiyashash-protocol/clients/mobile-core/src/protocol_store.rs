@@ -0,0 +1,812 @@
+//! Session layer storage for mobile
+//!
+//! Every session, prekey and message is its own record in a sled tree, so
+//! a change rewrites only the records it touches. Reads are served from an
+//! in-memory copy loaded when the store opens. Our identity key, the one
+//! secret the session layer stores unsealed, is sealed under a key kept in
+//! [`SecureStorage`].
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::{Batch, IVec, Tree};
+
+use qiyashash_core::error::{Error, Result};
+use qiyashash_core::message::{Message, MessageId, MessageStatus, QuarantinedPayload};
+use qiyashash_core::session::{SessionId, SessionRecord};
+use qiyashash_core::storage::memory::MemoryStorage;
+use qiyashash_core::storage::{
+    check_migration, ConversationSummary, IdentityStore, MessageStore, PreKeyStore, SessionStore,
+    Storage, StorageSnapshot, StorageStats, UserStore, SCHEMA_VERSION,
+};
+use qiyashash_core::types::{DeviceId, UserId};
+use qiyashash_core::user::{Contact, ConversationSettings, User};
+use qiyashash_crypto::aead::{Aead, AeadKey, EncryptedPayload};
+
+use crate::storage::{SecureStorage, StorageError};
+
+/// Tree holding the records
+const RECORDS_TREE: &str = "protocol_records";
+
+/// Key of the layout version, outside every record prefix
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Key of our sealed identity key
+const IDENTITY_KEY_KEY: &str = "identity_key";
+
+/// Associated data binding a sealed identity key to its purpose
+const IDENTITY_KEY_AAD: &[u8] = b"qiyashash-mobile identity key";
+
+const USER: &str = "user";
+const CONTACT: &str = "contact";
+const SETTINGS: &str = "settings";
+const SESSION: &str = "session";
+const NONCE: &str = "nonce";
+const MESSAGE: &str = "message";
+const DELIVERY: &str = "delivery";
+const QUARANTINE: &str = "quarantine";
+const REMOTE_IDENTITY: &str = "remote_identity";
+const SIGNED_PREKEY: &str = "signed_prekey";
+const ONE_TIME_PREKEY: &str = "one_time_prekey";
+
+/// Prefixes of every record kind
+const RECORD_KINDS: [&str; 11] = [
+    USER,
+    CONTACT,
+    SETTINGS,
+    SESSION,
+    NONCE,
+    MESSAGE,
+    DELIVERY,
+    QUARANTINE,
+    REMOTE_IDENTITY,
+    SIGNED_PREKEY,
+    ONE_TIME_PREKEY,
+];
+
+/// Record changes: the new value, or `None` to remove the key
+type Changes = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// Sled-backed [`Storage`] for the session layer
+///
+/// Writes go to the tree before the in-memory copy, one sled batch per
+/// call, so a call is stored entirely or not at all. Inside a transaction
+/// the changes are held back and written as a single batch on commit.
+pub struct SledStorage {
+    cache: Arc<MemoryStorage>,
+    records: Tree,
+    record_key: AeadKey,
+    pending: Mutex<Option<Changes>>,
+}
+
+impl SledStorage {
+    /// Open the records kept in `storage`
+    ///
+    /// A state blob left by an older release is split into records and
+    /// then deleted. Records written by a newer release are refused.
+    pub async fn open(storage: &SecureStorage) -> std::result::Result<Arc<Self>, StorageError> {
+        let store = Self {
+            cache: MemoryStorage::new(),
+            records: storage.open_tree(RECORDS_TREE)?,
+            record_key: storage.load_or_create_record_key()?,
+            pending: Mutex::new(None),
+        };
+        let loaded = async {
+            store.migrate_to(SCHEMA_VERSION).await?;
+            store.load().await
+        };
+        loaded.await.map_err(|e| StorageError::Database(e.to_string()))?;
+
+        if let Some(snapshot) = storage.load_protocol_state()? {
+            let moved = async {
+                store.import_snapshot(snapshot).await?;
+                store.flush().await
+            };
+            moved.await.map_err(|e| StorageError::Database(e.to_string()))?;
+            storage.delete_protocol_state()?;
+        }
+        Ok(Arc::new(store))
+    }
+
+    /// Fill the in-memory copy from the tree
+    async fn load(&self) -> Result<()> {
+        let cache = &self.cache;
+        for (_, value) in self.scan(USER)? {
+            cache.save_user(&decode(&value)?).await?;
+        }
+        for (_, value) in self.scan(CONTACT)? {
+            cache.save_contact(&decode(&value)?).await?;
+        }
+        for (user_id, value) in self.scan(SETTINGS)? {
+            cache.set_conversation_settings(&user_id_from(&user_id)?, &decode(&value)?).await?;
+        }
+        for (_, value) in self.scan(SESSION)? {
+            cache.save_session(&decode(&value)?).await?;
+        }
+        for (nonce, _) in self.scan(NONCE)? {
+            cache.record_session_nonce(&fixed(&nonce)?).await?;
+        }
+        let messages = self.scan(MESSAGE)?
+            .into_iter()
+            .map(|(_, value)| decode(&value))
+            .collect::<Result<Vec<Message>>>()?;
+        cache.save_messages(&messages).await?;
+        for (key, message_id) in self.scan(DELIVERY)? {
+            let message_id = String::from_utf8(message_id.to_vec()).map_err(storage_error)?;
+            cache.record_delivery(&fixed(&key)?, &MessageId::from_string(message_id)).await?;
+        }
+        for (_, value) in self.scan(QUARANTINE)? {
+            cache.quarantine_payload(&decode(&value)?).await?;
+        }
+        if let Some(sealed) = self.records.get(IDENTITY_KEY_KEY).map_err(storage_error)? {
+            cache.save_identity_key(self.unseal(&sealed)?).await?;
+        }
+        for (user_id, identity_key) in self.scan(REMOTE_IDENTITY)? {
+            cache.save_remote_identity(&user_id_from(&user_id)?, fixed(&identity_key)?).await?;
+        }
+        for (id, prekey) in self.scan(SIGNED_PREKEY)? {
+            cache.save_signed_prekey(u32::from_be_bytes(fixed(&id)?), prekey.to_vec()).await?;
+        }
+        for (id, prekey) in self.scan(ONE_TIME_PREKEY)? {
+            cache.save_one_time_prekey(u32::from_be_bytes(fixed(&id)?), prekey.to_vec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Records of one kind, keyed without the kind prefix
+    fn scan(&self, kind: &str) -> Result<Vec<(Vec<u8>, IVec)>> {
+        let prefix = record_key(kind, b"");
+        self.records
+            .scan_prefix(&prefix)
+            .map(|entry| {
+                let (key, value) = entry.map_err(storage_error)?;
+                Ok((key[prefix.len()..].to_vec(), value))
+            })
+            .collect()
+    }
+
+    /// Write `changes` now, or at commit if a transaction is open
+    fn persist(&self, changes: Changes) -> Result<()> {
+        let mut pending = self.pending();
+        match pending.as_mut() {
+            Some(held) => held.extend(changes),
+            None => self.apply(changes)?,
+        }
+        Ok(())
+    }
+
+    fn apply(&self, changes: Changes) -> Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in changes {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.records.apply_batch(batch).map_err(storage_error)
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Option<Changes>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn seal(&self, identity_key: &[u8]) -> Result<Vec<u8>> {
+        let sealed = Aead::new()
+            .encrypt(&self.record_key, identity_key, IDENTITY_KEY_AAD)
+            .map_err(storage_error)?;
+        Ok(bincode::serialize(&sealed)?)
+    }
+
+    fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let sealed: EncryptedPayload = bincode::deserialize(sealed)?;
+        Aead::new()
+            .decrypt(&self.record_key, &sealed, IDENTITY_KEY_AAD)
+            .map_err(storage_error)
+    }
+
+    /// Messages of the conversation with `other_user_id`
+    async fn conversation(&self, other_user_id: &UserId) -> Result<Vec<Message>> {
+        self.cache.stream_conversation(other_user_id).try_collect().await
+    }
+}
+
+/// Key of the record `id` of `kind`
+fn record_key(kind: &str, id: impl AsRef<[u8]>) -> Vec<u8> {
+    let id = id.as_ref();
+    let mut key = Vec::with_capacity(kind.len() + 1 + id.len());
+    key.extend_from_slice(kind.as_bytes());
+    key.push(b'/');
+    key.extend_from_slice(id);
+    key
+}
+
+fn put<T: Serialize>(kind: &str, id: impl AsRef<[u8]>, value: &T) -> Result<Changes> {
+    Ok(vec![(record_key(kind, id), Some(bincode::serialize(value)?))])
+}
+
+fn put_raw(kind: &str, id: impl AsRef<[u8]>, value: &[u8]) -> Changes {
+    vec![(record_key(kind, id), Some(value.to_vec()))]
+}
+
+fn remove(kind: &str, id: impl AsRef<[u8]>) -> Changes {
+    vec![(record_key(kind, id), None)]
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::Storage(format!("expected {} bytes, found {}", N, bytes.len())))
+}
+
+fn user_id_from(bytes: &[u8]) -> Result<UserId> {
+    String::from_utf8(bytes.to_vec())
+        .map(UserId::from_string)
+        .map_err(storage_error)
+}
+
+fn storage_error(e: impl std::fmt::Display) -> Error {
+    Error::Storage(e.to_string())
+}
+
+#[async_trait]
+impl UserStore for SledStorage {
+    async fn get_user(&self, user_id: &UserId) -> Result<Option<User>> {
+        self.cache.get_user(user_id).await
+    }
+
+    async fn save_user(&self, user: &User) -> Result<()> {
+        self.persist(put(USER, user.id.as_str(), user)?)?;
+        self.cache.save_user(user).await
+    }
+
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        self.persist(remove(USER, user_id.as_str()))?;
+        self.cache.delete_user(user_id).await
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>> {
+        self.cache.get_all_users().await
+    }
+
+    async fn search_users(&self, query: &str) -> Result<Vec<User>> {
+        self.cache.search_users(query).await
+    }
+
+    async fn get_contact(&self, user_id: &UserId) -> Result<Option<Contact>> {
+        self.cache.get_contact(user_id).await
+    }
+
+    async fn save_contact(&self, contact: &Contact) -> Result<()> {
+        self.persist(put(CONTACT, contact.user_id.as_str(), contact)?)?;
+        self.cache.save_contact(contact).await
+    }
+
+    async fn delete_contact(&self, user_id: &UserId) -> Result<()> {
+        self.persist(remove(CONTACT, user_id.as_str()))?;
+        self.cache.delete_contact(user_id).await
+    }
+
+    async fn get_all_contacts(&self) -> Result<Vec<Contact>> {
+        self.cache.get_all_contacts().await
+    }
+
+    async fn get_blocked_contacts(&self) -> Result<Vec<Contact>> {
+        self.cache.get_blocked_contacts().await
+    }
+
+    async fn get_conversation_settings(&self, user_id: &UserId) -> Result<Option<ConversationSettings>> {
+        self.cache.get_conversation_settings(user_id).await
+    }
+
+    async fn set_conversation_settings(
+        &self,
+        user_id: &UserId,
+        settings: &ConversationSettings,
+    ) -> Result<()> {
+        self.persist(put(SETTINGS, user_id.as_str(), settings)?)?;
+        self.cache.set_conversation_settings(user_id, settings).await
+    }
+}
+
+#[async_trait]
+impl SessionStore for SledStorage {
+    async fn get_session(&self, session_id: &SessionId) -> Result<Option<SessionRecord>> {
+        self.cache.get_session(session_id).await
+    }
+
+    async fn get_session_by_user_device(
+        &self,
+        their_user_id: &UserId,
+        their_device_id: &DeviceId,
+    ) -> Result<Option<SessionRecord>> {
+        self.cache.get_session_by_user_device(their_user_id, their_device_id).await
+    }
+
+    async fn save_session(&self, session: &SessionRecord) -> Result<()> {
+        self.persist(put(SESSION, session.session.id.as_str(), session)?)?;
+        self.cache.save_session(session).await
+    }
+
+    async fn delete_session(&self, session_id: &SessionId) -> Result<()> {
+        self.persist(remove(SESSION, session_id.as_str()))?;
+        self.cache.delete_session(session_id).await
+    }
+
+    async fn get_sessions_for_user(&self, their_user_id: &UserId) -> Result<Vec<SessionRecord>> {
+        self.cache.get_sessions_for_user(their_user_id).await
+    }
+
+    async fn get_active_sessions(&self) -> Result<Vec<SessionRecord>> {
+        self.cache.get_active_sessions().await
+    }
+
+    async fn get_open_sessions(&self) -> Result<Vec<SessionRecord>> {
+        self.cache.get_open_sessions().await
+    }
+
+    async fn get_sessions_needing_rekey(&self) -> Result<Vec<SessionRecord>> {
+        self.cache.get_sessions_needing_rekey().await
+    }
+
+    async fn record_session_nonce(&self, nonce: &[u8; 16]) -> Result<bool> {
+        // Like the in-memory store, applied at once even inside a transaction
+        let fresh = self.cache.record_session_nonce(nonce).await?;
+        if fresh {
+            self.apply(put_raw(NONCE, nonce, &[]))?;
+        }
+        Ok(fresh)
+    }
+
+    async fn update_ratchet_state(
+        &self,
+        session_id: &SessionId,
+        ratchet_state: Vec<u8>,
+        chain_state: Vec<u8>,
+    ) -> Result<()> {
+        if let Some(mut record) = self.cache.get_session(session_id).await? {
+            record.ratchet_state = ratchet_state.clone();
+            record.chain_state = chain_state.clone();
+            self.persist(put(SESSION, session_id.as_str(), &record)?)?;
+        }
+        self.cache.update_ratchet_state(session_id, ratchet_state, chain_state).await
+    }
+}
+
+#[async_trait]
+impl MessageStore for SledStorage {
+    async fn get_message(&self, message_id: &MessageId) -> Result<Option<Message>> {
+        self.cache.get_message(message_id).await
+    }
+
+    async fn save_message(&self, message: &Message) -> Result<()> {
+        self.persist(put(MESSAGE, message.id.as_str(), message)?)?;
+        self.cache.save_message(message).await
+    }
+
+    async fn save_messages(&self, messages: &[Message]) -> Result<()> {
+        let mut changes = Changes::with_capacity(messages.len());
+        for message in messages {
+            changes.extend(put(MESSAGE, message.id.as_str(), message)?);
+        }
+        self.persist(changes)?;
+        self.cache.save_messages(messages).await
+    }
+
+    async fn delete_message(&self, message_id: &MessageId) -> Result<()> {
+        self.persist(remove(MESSAGE, message_id.as_str()))?;
+        self.cache.delete_message(message_id).await
+    }
+
+    async fn get_delivery(&self, key: &[u8; 32]) -> Result<Option<MessageId>> {
+        self.cache.get_delivery(key).await
+    }
+
+    async fn record_delivery(&self, key: &[u8; 32], message_id: &MessageId) -> Result<()> {
+        // The first message recorded for a key is kept
+        if self.cache.get_delivery(key).await?.is_none() {
+            self.persist(put_raw(DELIVERY, key, message_id.as_str().as_bytes()))?;
+        }
+        self.cache.record_delivery(key, message_id).await
+    }
+
+    async fn get_messages_for_conversation(
+        &self,
+        other_user_id: &UserId,
+        limit: usize,
+        before: Option<&MessageId>,
+    ) -> Result<Vec<Message>> {
+        self.cache.get_messages_for_conversation(other_user_id, limit, before).await
+    }
+
+    fn stream_conversation<'a>(
+        &'a self,
+        other_user_id: &'a UserId,
+    ) -> BoxStream<'a, Result<Message>> {
+        self.cache.stream_conversation(other_user_id)
+    }
+
+    async fn get_conversation_summaries(&self, our_user_id: &UserId) -> Result<Vec<ConversationSummary>> {
+        self.cache.get_conversation_summaries(our_user_id).await
+    }
+
+    async fn get_unread_count(&self, other_user_id: &UserId) -> Result<usize> {
+        self.cache.get_unread_count(other_user_id).await
+    }
+
+    async fn mark_as_read(&self, other_user_id: &UserId, until: &MessageId) -> Result<()> {
+        let cutoff = match self.cache.get_message(until).await? {
+            Some(message) => message.created_at,
+            None => return Ok(()),
+        };
+
+        // Only the messages whose status changes are rewritten
+        let mut changes = Changes::new();
+        for mut message in self.conversation(other_user_id).await? {
+            if message.sender_id == *other_user_id
+                && message.created_at <= cutoff
+                && message.status != MessageStatus::Read
+            {
+                message.status = MessageStatus::Read;
+                changes.extend(put(MESSAGE, message.id.as_str(), &message)?);
+            }
+        }
+        self.persist(changes)?;
+        self.cache.mark_as_read(other_user_id, until).await
+    }
+
+    async fn search_messages(&self, query: &str, limit: usize) -> Result<Vec<Message>> {
+        self.cache.search_messages(query, limit).await
+    }
+
+    async fn get_pending_messages(&self) -> Result<Vec<Message>> {
+        self.cache.get_pending_messages().await
+    }
+
+    async fn get_expired_messages(&self) -> Result<Vec<MessageId>> {
+        self.cache.get_expired_messages().await
+    }
+
+    async fn delete_conversation(&self, other_user_id: &UserId) -> Result<()> {
+        let changes = self.conversation(other_user_id).await?
+            .iter()
+            .flat_map(|message| remove(MESSAGE, message.id.as_str()))
+            .collect();
+        self.persist(changes)?;
+        self.cache.delete_conversation(other_user_id).await
+    }
+
+    async fn quarantine_payload(&self, payload: &QuarantinedPayload) -> Result<()> {
+        self.persist(put(QUARANTINE, payload.id.as_str(), payload)?)?;
+        self.cache.quarantine_payload(payload).await
+    }
+
+    async fn get_quarantined_payloads(&self) -> Result<Vec<QuarantinedPayload>> {
+        self.cache.get_quarantined_payloads().await
+    }
+
+    async fn delete_quarantined_payload(&self, id: &MessageId) -> Result<()> {
+        self.persist(remove(QUARANTINE, id.as_str()))?;
+        self.cache.delete_quarantined_payload(id).await
+    }
+}
+
+#[async_trait]
+impl IdentityStore for SledStorage {
+    async fn get_identity_key(&self) -> Result<Option<Vec<u8>>> {
+        self.cache.get_identity_key().await
+    }
+
+    async fn save_identity_key(&self, encrypted_key: Vec<u8>) -> Result<()> {
+        let sealed = self.seal(&encrypted_key)?;
+        self.persist(vec![(IDENTITY_KEY_KEY.as_bytes().to_vec(), Some(sealed))])?;
+        self.cache.save_identity_key(encrypted_key).await
+    }
+
+    async fn get_remote_identity(&self, user_id: &UserId) -> Result<Option<[u8; 32]>> {
+        self.cache.get_remote_identity(user_id).await
+    }
+
+    async fn save_remote_identity(&self, user_id: &UserId, identity_key: [u8; 32]) -> Result<()> {
+        self.persist(put_raw(REMOTE_IDENTITY, user_id.as_str(), &identity_key))?;
+        self.cache.save_remote_identity(user_id, identity_key).await
+    }
+
+    async fn is_trusted_identity(&self, user_id: &UserId, identity_key: &[u8; 32]) -> Result<bool> {
+        self.cache.is_trusted_identity(user_id, identity_key).await
+    }
+}
+
+#[async_trait]
+impl PreKeyStore for SledStorage {
+    async fn get_signed_prekey(&self, id: u32) -> Result<Option<Vec<u8>>> {
+        self.cache.get_signed_prekey(id).await
+    }
+
+    async fn save_signed_prekey(&self, id: u32, prekey: Vec<u8>) -> Result<()> {
+        self.persist(put_raw(SIGNED_PREKEY, id.to_be_bytes(), &prekey))?;
+        self.cache.save_signed_prekey(id, prekey).await
+    }
+
+    async fn delete_signed_prekey(&self, id: u32) -> Result<()> {
+        self.persist(remove(SIGNED_PREKEY, id.to_be_bytes()))?;
+        self.cache.delete_signed_prekey(id).await
+    }
+
+    async fn get_one_time_prekey(&self, id: u32) -> Result<Option<Vec<u8>>> {
+        self.cache.get_one_time_prekey(id).await
+    }
+
+    async fn save_one_time_prekey(&self, id: u32, prekey: Vec<u8>) -> Result<()> {
+        self.persist(put_raw(ONE_TIME_PREKEY, id.to_be_bytes(), &prekey))?;
+        self.cache.save_one_time_prekey(id, prekey).await
+    }
+
+    async fn delete_one_time_prekey(&self, id: u32) -> Result<()> {
+        self.persist(remove(ONE_TIME_PREKEY, id.to_be_bytes()))?;
+        self.cache.delete_one_time_prekey(id).await
+    }
+
+    async fn get_one_time_prekey_count(&self) -> Result<usize> {
+        self.cache.get_one_time_prekey_count().await
+    }
+
+    async fn get_one_time_prekey_ids(&self) -> Result<Vec<u32>> {
+        self.cache.get_one_time_prekey_ids().await
+    }
+}
+
+#[async_trait]
+impl Storage for SledStorage {
+    async fn begin_transaction(&self) -> Result<()> {
+        self.cache.begin_transaction().await?;
+        *self.pending() = Some(Changes::new());
+        Ok(())
+    }
+
+    async fn commit(&self) -> Result<()> {
+        let changes = self.pending()
+            .take()
+            .ok_or_else(|| Error::InvalidState("commit without begin_transaction".to_string()))?;
+        if let Err(e) = self.apply(changes) {
+            self.cache.rollback().await?;
+            return Err(e);
+        }
+        self.cache.commit().await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        self.pending().take();
+        self.cache.rollback().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.records.flush_async().await.map_err(storage_error)?;
+        Ok(())
+    }
+
+    async fn get_stats(&self) -> Result<StorageStats> {
+        let mut stats = self.cache.get_stats().await?;
+        stats.storage_size_bytes = self.records.iter()
+            .map(|entry| entry.map(|(key, value)| (key.len() + value.len()) as u64))
+            .sum::<std::result::Result<u64, _>>()
+            .map_err(storage_error)?;
+        Ok(stats)
+    }
+
+    async fn vacuum(&self) -> Result<()> {
+        let expired = self.cache.get_expired_messages().await?;
+        self.persist(expired.iter().flat_map(|id| remove(MESSAGE, id.as_str())).collect())?;
+        self.cache.vacuum().await
+    }
+
+    async fn export_snapshot(&self) -> Result<StorageSnapshot> {
+        self.cache.export_snapshot().await
+    }
+
+    async fn import_snapshot(&self, snapshot: StorageSnapshot) -> Result<()> {
+        // Through the regular writers so an open transaction holds it all
+        for user in &snapshot.users {
+            self.save_user(user).await?;
+        }
+        for contact in &snapshot.contacts {
+            self.save_contact(contact).await?;
+        }
+        for (user_id, settings) in &snapshot.conversation_settings {
+            self.set_conversation_settings(user_id, settings).await?;
+        }
+        for session in &snapshot.sessions {
+            self.save_session(session).await?;
+        }
+        for nonce in &snapshot.session_nonces {
+            self.record_session_nonce(nonce).await?;
+        }
+        self.save_messages(&snapshot.messages).await?;
+        for (key, message_id) in &snapshot.deliveries {
+            self.record_delivery(key, message_id).await?;
+        }
+        for payload in &snapshot.quarantine {
+            self.quarantine_payload(payload).await?;
+        }
+        if let Some(identity_key) = snapshot.identity_key {
+            self.save_identity_key(identity_key).await?;
+        }
+        for (user_id, identity_key) in snapshot.remote_identities {
+            self.save_remote_identity(&user_id, identity_key).await?;
+        }
+        for (id, prekey) in snapshot.signed_prekeys {
+            self.save_signed_prekey(id, prekey).await?;
+        }
+        for (id, prekey) in snapshot.one_time_prekeys {
+            self.save_one_time_prekey(id, prekey).await?;
+        }
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut changes = vec![(IDENTITY_KEY_KEY.as_bytes().to_vec(), None)];
+        for kind in RECORD_KINDS {
+            for entry in self.records.scan_prefix(record_key(kind, b"")) {
+                let (key, _) = entry.map_err(storage_error)?;
+                changes.push((key.to_vec(), None));
+            }
+        }
+        self.persist(changes)?;
+        self.cache.clear().await
+    }
+
+    async fn schema_version(&self) -> Result<u32> {
+        match self.records.get(SCHEMA_VERSION_KEY).map_err(storage_error)? {
+            Some(bytes) => Ok(u32::from_be_bytes(fixed(&bytes)?)),
+            // Records have had one layout so far
+            None => Ok(SCHEMA_VERSION),
+        }
+    }
+
+    async fn migrate_to(&self, version: u32) -> Result<()> {
+        // Only one layout exists, so there is nothing to back up or rewrite
+        check_migration(self.schema_version().await?, version)?;
+        self.records
+            .insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())
+            .map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn message(from: &str, to: &str, text: &str) -> Message {
+        Message::text(UserId::from_string(from), DeviceId::new(), UserId::from_string(to), text)
+    }
+
+    #[tokio::test]
+    async fn test_records_survive_reopen() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+        let store = SledStorage::open(&storage).await.unwrap();
+
+        let hello = message("alice", "me", "hello");
+        store.save_message(&hello).await.unwrap();
+        store.save_identity_key(b"identity secret".to_vec()).await.unwrap();
+        store.save_remote_identity(&UserId::from_string("alice"), [7; 32]).await.unwrap();
+        store.save_one_time_prekey(3, vec![3; 32]).await.unwrap();
+        store.record_session_nonce(&[9; 16]).await.unwrap();
+        store.mark_as_read(&UserId::from_string("alice"), &hello.id).await.unwrap();
+        store.flush().await.unwrap();
+        let before = store.export_snapshot().await.unwrap();
+        drop(store);
+
+        let store = SledStorage::open(&storage).await.unwrap();
+        let after = store.export_snapshot().await.unwrap();
+        assert_eq!(after.identity_key.as_deref(), Some(&b"identity secret"[..]));
+        assert_eq!(after.remote_identities.len(), 1);
+        assert_eq!(after.one_time_prekeys, before.one_time_prekeys);
+        assert!(!store.record_session_nonce(&[9; 16]).await.unwrap());
+        let stored = store.get_message(&hello.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, MessageStatus::Read);
+    }
+
+    #[tokio::test]
+    async fn test_identity_key_is_sealed() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+        let store = SledStorage::open(&storage).await.unwrap();
+
+        let secret = vec![0x5a; 64];
+        store.save_identity_key(secret.clone()).await.unwrap();
+
+        let records = storage.open_tree(RECORDS_TREE).unwrap();
+        for entry in records.iter() {
+            let (_, value) = entry.unwrap();
+            assert!(!value.windows(secret.len()).any(|window| window == secret.as_slice()));
+        }
+        assert!(storage.get("protocol_state").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_writes_touch_only_changed_records() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+        let store = SledStorage::open(&storage).await.unwrap();
+
+        let first = message("alice", "me", "first");
+        store.save_message(&first).await.unwrap();
+        let records = storage.open_tree(RECORDS_TREE).unwrap();
+
+        // Tamper with the stored copy; a later unrelated write must not
+        // rewrite it from memory
+        records.insert(record_key(MESSAGE, first.id.as_str()), b"untouched".to_vec()).unwrap();
+        store.save_message(&message("bob", "me", "second")).await.unwrap();
+        store.save_one_time_prekey(1, vec![1; 32]).await.unwrap();
+
+        let current = records.get(record_key(MESSAGE, first.id.as_str())).unwrap().unwrap();
+        assert_eq!(&current[..], b"untouched");
+    }
+
+    #[tokio::test]
+    async fn test_rolled_back_writes_are_not_stored() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+        let store = SledStorage::open(&storage).await.unwrap();
+
+        store.begin_transaction().await.unwrap();
+        store.save_one_time_prekey(1, vec![1; 32]).await.unwrap();
+        store.rollback().await.unwrap();
+
+        store.begin_transaction().await.unwrap();
+        store.save_one_time_prekey(2, vec![2; 32]).await.unwrap();
+        store.commit().await.unwrap();
+        drop(store);
+
+        let store = SledStorage::open(&storage).await.unwrap();
+        assert_eq!(store.get_one_time_prekey_ids().await.unwrap(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_state_blob_is_split_into_records() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+
+        let hello = message("alice", "me", "hello");
+        let blob = StorageSnapshot {
+            messages: vec![hello.clone()],
+            identity_key: Some(b"identity secret".to_vec()),
+            one_time_prekeys: vec![(4, vec![4; 32])],
+            ..Default::default()
+        };
+        storage.set("protocol_state", &serde_json::to_vec(&blob).unwrap()).unwrap();
+
+        let store = SledStorage::open(&storage).await.unwrap();
+        assert!(storage.load_protocol_state().unwrap().is_none());
+        drop(store);
+
+        let store = SledStorage::open(&storage).await.unwrap();
+        assert!(store.get_message(&hello.id).await.unwrap().is_some());
+        assert_eq!(store.get_identity_key().await.unwrap().as_deref(), Some(&b"identity secret"[..]));
+        assert_eq!(store.get_one_time_prekey_ids().await.unwrap(), vec![4]);
+    }
+
+    #[tokio::test]
+    async fn test_future_schema_is_rejected() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+        storage
+            .open_tree(RECORDS_TREE)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes())
+            .unwrap();
+
+        let err = SledStorage::open(&storage).await.err().expect("newer schema is refused");
+        assert!(err.to_string().contains("newer than the supported version"), "{}", err);
+    }
+}
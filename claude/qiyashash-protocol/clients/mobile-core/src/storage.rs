@@ -1,9 +1,14 @@
 //! Secure storage for mobile
 
+use crate::crypto::MobileCrypto;
 use crate::identity::UserIdentity;
-use sled::Db;
+use qiyashash_core::storage::StorageSnapshot;
+use qiyashash_core::types::DeviceId;
+use qiyashash_crypto::aead::AeadKey;
+use sled::{Db, Tree};
 use std::path::Path;
 use thiserror::Error;
+use zeroize::Zeroize;
 
 #[derive(Error, Debug)]
 pub enum StorageError {
@@ -15,7 +20,29 @@ pub enum StorageError {
     NotFound(String),
 }
 
+/// What [`SecureStorage::secure_wipe`] destroyed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WipeReport {
+    /// Entries overwritten with random data before removal
+    pub entries_overwritten: u32,
+    /// Bytes of values overwritten
+    pub bytes_overwritten: u64,
+    /// Whether in-memory identity secrets were zeroized
+    pub secrets_zeroized: bool,
+}
+
+/// Key of this install's device ID
+const DEVICE_ID_KEY: &str = "device_id";
+
+/// Key of the session layer's state as one blob, as written by releases
+/// that did not store its records one by one
+const PROTOCOL_STATE_KEY: &str = "protocol_state";
+
+/// Key of the key sealing secrets in protocol records
+const RECORD_KEY_KEY: &str = "record_key";
+
 /// Secure local storage
+#[derive(Clone)]
 pub struct SecureStorage {
     db: Db,
 }
@@ -55,6 +82,71 @@ impl SecureStorage {
         }
     }
 
+    /// Load the session layer's state blob left by an older release
+    pub fn load_protocol_state(&self) -> Result<Option<StorageSnapshot>, StorageError> {
+        match self.db.get(PROTOCOL_STATE_KEY) {
+            Ok(Some(data)) => {
+                let snapshot = serde_json::from_slice(&data)
+                    .map_err(|e| StorageError::Serialization(e.to_string()))?;
+                Ok(Some(snapshot))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(StorageError::Database(e.to_string())),
+        }
+    }
+
+    /// Forget the session layer's state blob once its records are moved
+    pub fn delete_protocol_state(&self) -> Result<(), StorageError> {
+        self.delete(PROTOCOL_STATE_KEY).map(drop)
+    }
+
+    /// Named tree in the same database, wiped along with everything else
+    pub(crate) fn open_tree(&self, name: &str) -> Result<Tree, StorageError> {
+        self.db.open_tree(name)
+            .map_err(|e| StorageError::Database(e.to_string()))
+    }
+
+    /// Key sealing secrets in protocol records, generated the first time it
+    /// is asked for
+    pub(crate) fn load_or_create_record_key(&self) -> Result<AeadKey, StorageError> {
+        let mut fresh = MobileCrypto::random_bytes(32);
+        // Only the first writer's key is kept
+        let stored = self.db
+            .compare_and_swap(RECORD_KEY_KEY, None as Option<&[u8]>, Some(fresh.as_slice()))
+            .map_err(|e| StorageError::Database(e.to_string()))?;
+        let mut key = match stored {
+            Ok(()) => fresh.clone(),
+            Err(conflict) => conflict.current
+                .ok_or_else(|| StorageError::NotFound(RECORD_KEY_KEY.to_string()))?
+                .to_vec(),
+        };
+        fresh.zeroize();
+
+        let bytes: Result<[u8; 32], _> = key.as_slice().try_into();
+        key.zeroize();
+        bytes
+            .map(AeadKey::from_bytes)
+            .map_err(|_| StorageError::Serialization("Corrupt record key".to_string()))
+    }
+
+    /// This install's device ID, generated the first time it is asked for
+    pub fn load_or_create_device_id(&self) -> Result<DeviceId, StorageError> {
+        let fresh = DeviceId::new();
+        // Only the first writer's ID is kept
+        let stored = match self.db
+            .compare_and_swap(DEVICE_ID_KEY, None as Option<&[u8]>, Some(fresh.as_str().as_bytes()))
+            .map_err(|e| StorageError::Database(e.to_string()))?
+        {
+            Ok(()) => return Ok(fresh),
+            Err(conflict) => conflict.current,
+        };
+
+        let stored = stored.ok_or_else(|| StorageError::NotFound(DEVICE_ID_KEY.to_string()))?;
+        String::from_utf8(stored.to_vec())
+            .map(DeviceId::from_string)
+            .map_err(|e| StorageError::Serialization(e.to_string()))
+    }
+
     /// Save a key-value pair
     pub fn set(&self, key: &str, value: &[u8]) -> Result<(), StorageError> {
         self.db.insert(key, value)
@@ -76,15 +168,62 @@ impl SecureStorage {
             .map_err(|e| StorageError::Database(e.to_string()))
     }
 
+    /// Every tree in the database, the default one included
+    fn trees(&self) -> Result<Vec<Tree>, StorageError> {
+        self.db.tree_names()
+            .into_iter()
+            .map(|name| self.db.open_tree(name))
+            .collect::<Result<_, _>>()
+            .map_err(|e| StorageError::Database(e.to_string()))
+    }
+
     /// Wipe all data
     pub fn wipe_all(&self) -> Result<(), StorageError> {
-        self.db.clear()
-            .map_err(|e| StorageError::Database(e.to_string()))?;
+        for tree in self.trees()? {
+            tree.clear()
+                .map_err(|e| StorageError::Database(e.to_string()))?;
+        }
         self.db.flush()
             .map_err(|e| StorageError::Database(e.to_string()))?;
         Ok(())
     }
 
+    /// Overwrite every entry with random data, then delete everything
+    ///
+    /// `identity`, the in-memory copy of the stored identity, has its
+    /// secret keys zeroized as well.
+    ///
+    /// Best effort only: sled appends rewritten values instead of updating
+    /// them in place, and flash controllers remap writes for wear
+    /// leveling, so old blocks can survive until the filesystem or device
+    /// reclaims them. Combine with platform file encryption (iOS Data
+    /// Protection, Android file-based encryption) and discard the keys.
+    pub fn secure_wipe(&self, identity: Option<&mut UserIdentity>) -> Result<WipeReport, StorageError> {
+        let mut report = WipeReport::default();
+        for tree in self.trees()? {
+            let entries = tree.iter()
+                .map(|entry| entry.map(|(key, value)| (key, value.len())))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| StorageError::Database(e.to_string()))?;
+
+            for (key, len) in entries {
+                tree.insert(key, MobileCrypto::random_bytes(len))
+                    .map_err(|e| StorageError::Database(e.to_string()))?;
+                report.entries_overwritten += 1;
+                report.bytes_overwritten += len as u64;
+            }
+        }
+        self.db.flush()
+            .map_err(|e| StorageError::Database(e.to_string()))?;
+        self.wipe_all()?;
+
+        if let Some(identity) = identity {
+            identity.zeroize();
+            report.secrets_zeroized = true;
+        }
+        Ok(report)
+    }
+
     /// Get storage size in bytes
     pub fn size_bytes(&self) -> u64 {
         self.db.size_on_disk().unwrap_or(0)
@@ -120,4 +259,30 @@ mod tests {
         let loaded = storage.load_identity().unwrap().unwrap();
         assert_eq!(loaded.id, identity.id);
     }
+
+    #[test]
+    fn test_secure_wipe() {
+        let temp = TempDir::new().unwrap();
+        let storage = SecureStorage::new(temp.path()).unwrap();
+
+        let mut identity = UserIdentity::generate("Test".to_string()).unwrap();
+        storage.save_identity(&identity).unwrap();
+        storage.set("secret", &[0x11; 32]).unwrap();
+        storage.set("session:peer", b"ratchet state").unwrap();
+        let records = storage.open_tree("records").unwrap();
+        records.insert("message/1", &b"hello"[..]).unwrap();
+
+        let report = storage.secure_wipe(Some(&mut identity)).unwrap();
+        assert_eq!(report.entries_overwritten, 4);
+        assert!(report.bytes_overwritten >= 32 + 13 + 5);
+        assert!(report.secrets_zeroized);
+
+        assert!(storage.load_identity().unwrap().is_none());
+        assert!(storage.get("session:peer").unwrap().is_none());
+        assert!(storage.get("secret").unwrap().is_none());
+        assert!(records.is_empty());
+
+        assert!(identity.is_wiped());
+        assert!(identity.encrypt_for("peer", b"hello").is_err());
+    }
 }
@@ -3,6 +3,9 @@
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+use crate::error::{AnonymityError, Result};
+use crate::obfuscation::{Obfuscator, ObfuscatorChain};
+
 /// Anonymity layer configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnonymityConfig {
@@ -12,6 +15,11 @@ pub struct AnonymityConfig {
     pub obfuscation: ObfuscationConfig,
     /// Cover traffic settings
     pub cover_traffic: CoverTrafficConfig,
+    /// Custom payload obfuscators, applied in order on send
+    ///
+    /// When empty, the padding described by `obfuscation` is used.
+    #[serde(skip)]
+    pub obfuscators: ObfuscatorChain,
 }
 
 impl Default for AnonymityConfig {
@@ -20,6 +28,7 @@ impl Default for AnonymityConfig {
             transport: TransportConfig::default(),
             obfuscation: ObfuscationConfig::default(),
             cover_traffic: CoverTrafficConfig::default(),
+            obfuscators: ObfuscatorChain::default(),
         }
     }
 }
@@ -151,6 +160,11 @@ pub struct CoverTrafficConfig {
     pub poisson_timing: bool,
     /// Cover message size range (min, max bytes)
     pub size_range: (usize, usize),
+    /// Keep a constant aggregate rate instead of a fixed decoy rate
+    ///
+    /// When set, `rate_per_hour` and `poisson_timing` are ignored.
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveRateConfig>,
 }
 
 impl Default for CoverTrafficConfig {
@@ -160,10 +174,66 @@ impl Default for CoverTrafficConfig {
             rate_per_hour: 10.0,
             poisson_timing: true,
             size_range: (256, 2048),
+            adaptive: None,
         }
     }
 }
 
+/// Adaptive cover-traffic rates, in messages per hour
+///
+/// Decoys fill in for missing real messages so the aggregate rate stays at
+/// `target_rate`. At least `min_rate` decoys are always sent, and real
+/// messages beyond `max_rate` are held back for the next tick.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdaptiveRateConfig {
+    /// Aggregate (real + decoy) rate to maintain
+    pub target_rate: f64,
+    /// Decoy rate kept up even under heavy real traffic
+    pub min_rate: f64,
+    /// Aggregate rate never exceeded
+    pub max_rate: f64,
+    /// Scheduling tick (milliseconds)
+    pub tick_ms: u64,
+}
+
+impl Default for AdaptiveRateConfig {
+    fn default() -> Self {
+        Self {
+            target_rate: 60.0,
+            min_rate: 6.0,
+            max_rate: 600.0,
+            tick_ms: 1000,
+        }
+    }
+}
+
+impl AdaptiveRateConfig {
+    /// Check that `min_rate <= target_rate <= max_rate` and the tick is set
+    pub fn validate(&self) -> Result<()> {
+        let rates = [self.min_rate, self.target_rate, self.max_rate];
+        if rates.iter().any(|rate| !rate.is_finite() || *rate < 0.0) {
+            return Err(AnonymityError::Configuration(
+                "cover rates must be finite and non-negative".to_string(),
+            ));
+        }
+        if self.min_rate > self.target_rate || self.target_rate > self.max_rate {
+            return Err(AnonymityError::Configuration(format!(
+                "cover rates must satisfy min <= target <= max, got {} / {} / {}",
+                self.min_rate, self.target_rate, self.max_rate
+            )));
+        }
+        if self.tick_ms == 0 {
+            return Err(AnonymityError::Configuration("tick_ms must be > 0".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Scheduling tick as Duration
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+}
+
 impl AnonymityConfig {
     /// Create configuration for maximum privacy
     pub fn maximum_privacy() -> Self {
@@ -185,10 +255,68 @@ impl AnonymityConfig {
                 rate_per_hour: 30.0,
                 poisson_timing: true,
                 size_range: (512, 4096),
+                adaptive: None,
             },
+            obfuscators: ObfuscatorChain::default(),
         }
     }
 
+    /// Add a payload obfuscator to the end of the chain
+    pub fn with_obfuscator(mut self, obfuscator: impl Obfuscator + 'static) -> Self {
+        self.obfuscators = self.obfuscators.with(obfuscator);
+        self
+    }
+
+    /// Validate configuration
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(AnonymityError::Configuration(msg.to_string()));
+
+        match &self.transport.transport_type {
+            TransportTypeConfig::Direct => {}
+            TransportTypeConfig::Tor(_) if !cfg!(feature = "tor") => {
+                return invalid("Tor transport requires the `tor` feature");
+            }
+            TransportTypeConfig::I2P(_) if !cfg!(feature = "i2p") => {
+                return invalid("I2P transport requires the `i2p` feature");
+            }
+            TransportTypeConfig::Tor(tor) => {
+                if tor.use_bridges && tor.bridges.is_empty() {
+                    return invalid("use_bridges requires at least one bridge");
+                }
+            }
+            TransportTypeConfig::I2P(i2p) => {
+                if i2p.tunnel_quantity == 0 {
+                    return invalid("tunnel_quantity must be > 0");
+                }
+            }
+        }
+        if self.transport.connection_timeout_secs == 0 {
+            return invalid("connection_timeout_secs must be > 0");
+        }
+
+        let obfuscation = &self.obfuscation;
+        if obfuscation.min_delay_ms > obfuscation.max_delay_ms {
+            return invalid("min_delay_ms must be <= max_delay_ms");
+        }
+        if obfuscation.padding_range.0 > obfuscation.padding_range.1 {
+            return invalid("padding_range min must be <= max");
+        }
+
+        let cover = &self.cover_traffic;
+        if cover.enabled {
+            if !(cover.rate_per_hour.is_finite() && cover.rate_per_hour > 0.0) {
+                return invalid("cover_traffic.rate_per_hour must be > 0");
+            }
+            if cover.size_range.0 > cover.size_range.1 {
+                return invalid("cover_traffic.size_range min must be <= max");
+            }
+            if let Some(adaptive) = &cover.adaptive {
+                adaptive.validate()?;
+            }
+        }
+        Ok(())
+    }
+
     /// Get connection timeout as Duration
     pub fn connection_timeout(&self) -> Duration {
         Duration::from_secs(self.transport.connection_timeout_secs)
@@ -216,4 +344,55 @@ mod tests {
         assert!(config.obfuscation.enabled);
         assert!(config.cover_traffic.enabled);
     }
+
+    fn assert_rejected(config: &AnonymityConfig, expected: &str) {
+        match config.validate() {
+            Err(AnonymityError::Configuration(msg)) => assert_eq!(msg, expected),
+            other => panic!("expected configuration error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        AnonymityConfig::default().validate().unwrap();
+
+        let mut config = AnonymityConfig::default();
+        config.obfuscation.min_delay_ms = 3000;
+        config.obfuscation.max_delay_ms = 2000;
+        assert_rejected(&config, "min_delay_ms must be <= max_delay_ms");
+
+        let mut config = AnonymityConfig::default();
+        config.cover_traffic.enabled = true;
+        config.cover_traffic.rate_per_hour = 0.0;
+        assert_rejected(&config, "cover_traffic.rate_per_hour must be > 0");
+
+        // A zero rate is harmless while cover traffic is off
+        config.cover_traffic.enabled = false;
+        config.validate().unwrap();
+    }
+
+    #[cfg(not(feature = "tor"))]
+    #[test]
+    fn test_validate_rejects_tor_without_feature() {
+        let config = AnonymityConfig::maximum_privacy();
+        assert_rejected(&config, "Tor transport requires the `tor` feature");
+    }
+
+    #[test]
+    fn test_adaptive_rate_validation() {
+        AdaptiveRateConfig::default().validate().unwrap();
+
+        let inverted = AdaptiveRateConfig {
+            min_rate: 100.0,
+            target_rate: 50.0,
+            ..Default::default()
+        };
+        assert!(inverted.validate().is_err());
+
+        let no_tick = AdaptiveRateConfig { tick_ms: 0, ..Default::default() };
+        assert!(no_tick.validate().is_err());
+
+        let nan = AdaptiveRateConfig { max_rate: f64::NAN, ..Default::default() };
+        assert!(nan.validate().is_err());
+    }
 }
@@ -0,0 +1,145 @@
+//! Decoy content for cover traffic
+//!
+//! Cover messages travel next to real ciphertext, so their bytes should be
+//! statistically indistinguishable from it. The default [`AeadDecoy`]
+//! produces genuine AEAD output by encrypting random plaintext under a
+//! throwaway key.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::RngCore;
+
+/// Poly1305 tag length appended by the AEAD
+const TAG_LEN: usize = 16;
+
+/// Source of cover-traffic payloads
+pub trait DecoyGenerator: Send + Sync {
+    /// Short name for logging
+    fn name(&self) -> &str;
+
+    /// Produce a decoy of exactly `size` bytes
+    fn generate(&self, size: usize) -> Vec<u8>;
+}
+
+/// Uniformly random bytes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomDecoy;
+
+impl DecoyGenerator for RandomDecoy {
+    fn name(&self) -> &str {
+        "random"
+    }
+
+    fn generate(&self, size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+}
+
+/// Random plaintext sealed with a one-off ChaCha20-Poly1305 key
+///
+/// The key is discarded immediately, so nobody (including us) can open
+/// the decoy; it is simply real ciphertext of the requested length.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AeadDecoy;
+
+impl DecoyGenerator for AeadDecoy {
+    fn name(&self) -> &str {
+        "aead"
+    }
+
+    fn generate(&self, size: usize) -> Vec<u8> {
+        // Too short to hold a tag; real ciphertext never looks like this
+        if size < TAG_LEN {
+            return RandomDecoy.generate(size);
+        }
+
+        let mut plaintext = vec![0u8; size - TAG_LEN];
+        OsRng.fill_bytes(&mut plaintext);
+
+        let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .expect("encrypting into a Vec cannot fail")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    /// Fraction of set bits
+    fn ones_ratio(data: &[u8]) -> f64 {
+        let ones: u32 = data.iter().map(|b| b.count_ones()).sum();
+        ones as f64 / (data.len() * 8) as f64
+    }
+
+    /// Chi-square statistic of the byte histogram against uniform
+    fn byte_chi_square(data: &[u8]) -> f64 {
+        let mut counts = [0u64; 256];
+        for &b in data {
+            counts[b as usize] += 1;
+        }
+        let expected = data.len() as f64 / 256.0;
+        counts
+            .iter()
+            .map(|&c| (c as f64 - expected).powi(2) / expected)
+            .sum()
+    }
+
+    /// Basic randomness checks for 64 KiB samples
+    fn looks_random(data: &[u8]) -> bool {
+        // 255 degrees of freedom: the 99.99th percentile is ~352
+        (ones_ratio(data) - 0.5).abs() < 0.01 && byte_chi_square(data) < 352.0
+    }
+
+    fn real_ciphertext(size: usize) -> Vec<u8> {
+        let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // Real messages are structured, not random, before encryption
+        let plaintext = b"hello ".repeat((size - TAG_LEN) / 6 + 1);
+        cipher.encrypt(&nonce, &plaintext[..size - TAG_LEN]).unwrap()
+    }
+
+    #[test]
+    fn test_decoy_exact_sizes() {
+        for size in [0, 1, TAG_LEN - 1, TAG_LEN, TAG_LEN + 1, 4096] {
+            assert_eq!(AeadDecoy.generate(size).len(), size);
+            assert_eq!(RandomDecoy.generate(size).len(), size);
+        }
+    }
+
+    #[test]
+    fn test_decoys_pass_same_randomness_checks_as_ciphertext() {
+        let real: Vec<u8> = (0..64).flat_map(|_| real_ciphertext(1024)).collect();
+        let decoy: Vec<u8> = (0..64).flat_map(|_| AeadDecoy.generate(1024)).collect();
+
+        assert!(looks_random(&real));
+        assert!(looks_random(&decoy));
+        // The checks do discriminate: zero-filled cover would fail them
+        assert!(!looks_random(&vec![0u8; decoy.len()]));
+    }
+
+    #[test]
+    fn test_decoy_length_distribution_matches() {
+        let (min, max) = (256usize, 2048usize);
+        let buckets = 4;
+        let bucket_of = |len: usize| ((len - min) * buckets / (max - min + 1)).min(buckets - 1);
+
+        let mut rng = rand::thread_rng();
+        let mut real = vec![0usize; buckets];
+        let mut decoy = vec![0usize; buckets];
+        for _ in 0..2000 {
+            real[bucket_of(real_ciphertext(rng.gen_range(min..=max)).len())] += 1;
+            decoy[bucket_of(AeadDecoy.generate(rng.gen_range(min..=max)).len())] += 1;
+        }
+
+        // 2000 samples per side: each bucket holds ~500, tolerate sampling noise
+        for (r, d) in real.iter().zip(&decoy) {
+            assert!(r.abs_diff(*d) < 120, "real {:?} vs decoy {:?}", real, decoy);
+        }
+    }
+}
@@ -32,6 +32,14 @@ pub enum AnonymityError {
     #[error("Operation timed out")]
     Timeout,
 
+    /// Obfuscated payload could not be reversed
+    #[error("Deobfuscation failed: {0}")]
+    Deobfuscation(String),
+
+    /// Frame failed authentication or arrived out of order
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+
     /// Configuration error
     #[error("Configuration error: {0}")]
     Configuration(String),
@@ -1,16 +1,29 @@
 //! I2P transport implementation
 //!
-//! Provides transport over the I2P network using the SAM bridge.
+//! Provides transport over the I2P network using the SAM v3 bridge. A
+//! session is held open on a control connection; each outgoing stream is a
+//! fresh bridge connection that becomes a raw pipe after `STREAM CONNECT`.
 
 use async_trait::async_trait;
-use std::sync::Arc;
 use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
 use tracing::{debug, info, warn};
 
 use crate::config::I2PConfig;
 use crate::error::{AnonymityError, Result};
 use crate::transport::{AnonymousTransport, CircuitInfo, Connection, TransportType};
 
+/// SAM protocol version we speak
+const SAM_VERSION: &str = "3.1";
+
+/// Longest SAM reply line we accept (destinations are ~500 bytes)
+const MAX_SAM_LINE: usize = 16 * 1024;
+
 /// I2P transport
 pub struct I2PTransport {
     config: I2PConfig,
@@ -23,82 +36,218 @@ struct I2PSession {
     destination: String,
     /// Session ID
     session_id: String,
-    /// Connected to SAM bridge
-    connected: bool,
+    /// Cleared when the bridge closes the control connection
+    alive: Arc<AtomicBool>,
+    /// Keeps the control connection open and answers keepalives
+    control_task: JoinHandle<()>,
+    /// When the session was created
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl I2PSession {
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for I2PSession {
+    fn drop(&mut self) {
+        // Closing the control connection tears the session down on the bridge
+        self.control_task.abort();
+    }
 }
 
 impl I2PTransport {
     /// Create new I2P transport
+    ///
+    /// The SAM session is created lazily on first use, or eagerly with
+    /// [`I2PTransport::initialize`].
     pub fn new(config: I2PConfig) -> Result<Self> {
         info!("Initializing I2P transport");
-        
+
         Ok(Self {
             config,
             session: RwLock::new(None),
         })
     }
 
-    /// Initialize I2P session
+    /// Initialize I2P session, replacing any existing one
     pub async fn initialize(&self) -> Result<()> {
         info!("Connecting to I2P SAM bridge at {}", self.config.sam_addr);
-        
-        // Connect to SAM bridge
-        let stream = tokio::net::TcpStream::connect(&self.config.sam_addr)
-            .await
-            .map_err(|e| AnonymityError::I2PUnavailable(e.to_string()))?;
-        
-        // SAM handshake
-        // In production, implement full SAM protocol
-        
-        // Create session
-        let session_id = format!("qiyashash-{}", uuid::Uuid::new_v4());
-        
-        // Generate destination
-        let destination = self.create_destination().await?;
-        
+
+        let mut control = self.open_bridge().await?;
+        let session_id = format!("qiyashash-{:016x}", rand::random::<u64>());
+
+        sam_command(
+            &mut control,
+            &SamMessage::SessionCreate {
+                style: "STREAM".to_string(),
+                id: session_id.clone(),
+                destination: "TRANSIENT".to_string(),
+                options: session_options(&self.config),
+            },
+        )
+        .await?;
+
+        // SESSION STATUS carries our private key; look up the public half
+        let reply = sam_command(
+            &mut control,
+            &SamMessage::NamingLookup {
+                name: "ME".to_string(),
+            },
+        )
+        .await?;
+        let destination = reply.get("VALUE").cloned().ok_or_else(|| {
+            AnonymityError::I2PUnavailable("No destination for session".to_string())
+        })?;
+
+        let alive = Arc::new(AtomicBool::new(true));
+        let control_task = tokio::spawn(watch_control(control, alive.clone()));
+
         *self.session.write() = Some(I2PSession {
             destination,
             session_id,
-            connected: true,
+            alive,
+            control_task,
+            created_at: chrono::Utc::now(),
         });
-        
+
         info!("I2P session established");
         Ok(())
     }
 
-    /// Create a new I2P destination
-    async fn create_destination(&self) -> Result<String> {
-        // In production, use SAM to create destination
-        // For now, return placeholder
-        Ok("placeholder-destination".to_string())
-    }
-
     /// Get our I2P destination address
     pub fn our_destination(&self) -> Option<String> {
         self.session.read().as_ref().map(|s| s.destination.clone())
     }
+
+    /// Resolve a `.i2p` name (including `.b32.i2p`) to a full destination
+    ///
+    /// Anything else is assumed to already be a base64 destination.
+    pub async fn resolve(&self, name: &str) -> Result<String> {
+        if !name.ends_with(".i2p") {
+            return Ok(name.to_string());
+        }
+
+        let mut bridge = self.open_bridge().await?;
+        let reply = sam_command(
+            &mut bridge,
+            &SamMessage::NamingLookup {
+                name: name.to_string(),
+            },
+        )
+        .await
+        .map_err(|e| AnonymityError::ConnectionFailed(format!("Cannot resolve {}: {}", name, e)))?;
+
+        reply
+            .get("VALUE")
+            .cloned()
+            .ok_or_else(|| AnonymityError::ConnectionFailed(format!("No destination for {}", name)))
+    }
+
+    /// Session ID of a live session, creating one if needed
+    async fn ensure_session(&self) -> Result<String> {
+        if let Some(session) = self.session.read().as_ref() {
+            if session.is_alive() {
+                return Ok(session.session_id.clone());
+            }
+        }
+
+        warn!("I2P session missing or closed; creating a new one");
+        self.initialize().await?;
+        self.session
+            .read()
+            .as_ref()
+            .map(|s| s.session_id.clone())
+            .ok_or(AnonymityError::NotInitialized)
+    }
+
+    /// Open a bridge connection and complete the HELLO handshake
+    async fn open_bridge(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.config.sam_addr)
+            .await
+            .map_err(|e| AnonymityError::I2PUnavailable(e.to_string()))?;
+
+        sam_command(
+            &mut stream,
+            &SamMessage::Hello {
+                version: SAM_VERSION.to_string(),
+            },
+        )
+        .await
+        .map_err(|e| AnonymityError::I2PUnavailable(format!("SAM handshake failed: {}", e)))?;
+
+        Ok(stream)
+    }
+
+    /// Open a stream to `destination` through session `session_id`
+    ///
+    /// Failures that mean the session itself is gone come back as
+    /// [`AnonymityError::I2PUnavailable`]; failures to reach the peer come
+    /// back as [`AnonymityError::ConnectionFailed`].
+    async fn stream_connect(&self, session_id: &str, destination: &str) -> Result<TcpStream> {
+        let mut stream = self.open_bridge().await?;
+        let message = SamMessage::StreamConnect {
+            id: session_id.to_string(),
+            destination: destination.to_string(),
+        };
+        stream
+            .write_all(message.format().as_bytes())
+            .await
+            .map_err(|e| AnonymityError::Transport(e.to_string()))?;
+
+        let (_, params) = parse_sam_response(&read_line(&mut stream).await?)?;
+        let detail = params.get("MESSAGE").map(String::as_str).unwrap_or("no message");
+        match params.get("RESULT").map(String::as_str) {
+            Some("OK") => Ok(stream),
+            Some(result @ ("INVALID_ID" | "I2P_ERROR")) => Err(AnonymityError::I2PUnavailable(
+                format!("SAM session error {}: {}", result, detail),
+            )),
+            result => Err(AnonymityError::ConnectionFailed(format!(
+                "SAM error {}: {}",
+                result.unwrap_or("MISSING"),
+                detail
+            ))),
+        }
+    }
+}
+
+/// Whether `error` means the SAM session is unusable and must be recreated
+///
+/// Peer-level failures (unreachable or invalid destination, timeouts) leave
+/// the session intact.
+fn is_session_error(error: &AnonymityError) -> bool {
+    matches!(
+        error,
+        AnonymityError::I2PUnavailable(_) | AnonymityError::Transport(_)
+    )
 }
 
 #[async_trait]
 impl AnonymousTransport for I2PTransport {
     async fn connect(&self, destination: &str) -> Result<Box<dyn Connection>> {
-        let session = self.session.read();
-        let session = session.as_ref()
-            .ok_or(AnonymityError::NotInitialized)?;
-        
-        if !session.connected {
-            return Err(AnonymityError::NotInitialized);
-        }
-        
         debug!("Connecting via I2P to {}", destination);
-        
-        // In production, use SAM STREAM CONNECT
-        // For now, simulate connection
-        
-        Ok(Box::new(I2PConnection {
-            destination: destination.to_string(),
-            connected: true,
-        }))
+        let destination = self.resolve(destination).await?;
+
+        // One retry with a fresh session covers a bridge restart; a peer we
+        // can't reach doesn't cost the session
+        let mut retried = false;
+        loop {
+            let session_id = self.ensure_session().await?;
+            match self.stream_connect(&session_id, &destination).await {
+                Ok(stream) => {
+                    return Ok(Box::new(I2PConnection {
+                        stream: Some(stream),
+                    }))
+                }
+                Err(e) if !retried && is_session_error(&e) => {
+                    warn!("I2P stream connect failed ({}); recreating session", e);
+                    self.session.write().take();
+                    retried = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
     fn transport_type(&self) -> TransportType {
@@ -106,8 +255,10 @@ impl AnonymousTransport for I2PTransport {
     }
 
     async fn is_available(&self) -> bool {
-        let session = self.session.read();
-        session.as_ref().map(|s| s.connected).unwrap_or(false)
+        if self.session.read().as_ref().is_some_and(I2PSession::is_alive) {
+            return true;
+        }
+        self.open_bridge().await.is_ok()
     }
 
     fn circuit_info(&self) -> Option<CircuitInfo> {
@@ -116,45 +267,59 @@ impl AnonymousTransport for I2PTransport {
             id: s.session_id.clone(),
             hops: self.config.tunnel_length as usize,
             exit_node: None,
-            created_at: chrono::Utc::now(),
+            created_at: s.created_at,
         })
     }
 }
 
-/// I2P connection
+/// I2P stream connection
 struct I2PConnection {
-    destination: String,
-    connected: bool,
+    stream: Option<TcpStream>,
 }
 
 #[async_trait]
 impl Connection for I2PConnection {
     async fn send(&mut self, data: &[u8]) -> Result<()> {
-        if !self.connected {
-            return Err(AnonymityError::Transport("Not connected".to_string()));
-        }
-        
-        // In production, send via SAM
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| AnonymityError::Transport("Not connected".to_string()))?;
+
         debug!("Sending {} bytes via I2P", data.len());
-        Ok(())
+        stream
+            .write_all(data)
+            .await
+            .map_err(|e| AnonymityError::Transport(e.to_string()))
     }
 
     async fn receive(&mut self) -> Result<Vec<u8>> {
-        if !self.connected {
-            return Err(AnonymityError::Transport("Not connected".to_string()));
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| AnonymityError::Transport("Not connected".to_string()))?;
+
+        let mut buf = vec![0u8; 65536];
+        let n = stream
+            .read(&mut buf)
+            .await
+            .map_err(|e| AnonymityError::Transport(e.to_string()))?;
+        if n == 0 {
+            self.stream = None;
+            return Err(AnonymityError::Transport("Stream closed by peer".to_string()));
         }
-        
-        // In production, receive via SAM
-        Ok(Vec::new())
+        buf.truncate(n);
+        Ok(buf)
     }
 
     async fn close(&mut self) -> Result<()> {
-        self.connected = false;
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.shutdown().await;
+        }
         Ok(())
     }
 
     fn is_connected(&self) -> bool {
-        self.connected
+        self.stream.is_some()
     }
 }
 
@@ -174,9 +339,15 @@ pub struct EepsiteConfig {
 #[derive(Debug)]
 enum SamMessage {
     Hello { version: String },
-    SessionCreate { style: String, id: String, destination: String },
+    SessionCreate {
+        style: String,
+        id: String,
+        destination: String,
+        options: Vec<(String, String)>,
+    },
     StreamConnect { id: String, destination: String },
     StreamAccept { id: String },
+    NamingLookup { name: String },
 }
 
 impl SamMessage {
@@ -186,55 +357,350 @@ impl SamMessage {
             SamMessage::Hello { version } => {
                 format!("HELLO VERSION MIN={} MAX={}\n", version, version)
             }
-            SamMessage::SessionCreate { style, id, destination } => {
-                format!("SESSION CREATE STYLE={} ID={} DESTINATION={}\n", style, id, destination)
+            SamMessage::SessionCreate { style, id, destination, options } => {
+                let mut line = format!(
+                    "SESSION CREATE STYLE={} ID={} DESTINATION={}",
+                    style, id, destination
+                );
+                for (key, value) in options {
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+                line.push('\n');
+                line
             }
             SamMessage::StreamConnect { id, destination } => {
-                format!("STREAM CONNECT ID={} DESTINATION={}\n", id, destination)
+                format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false\n", id, destination)
             }
             SamMessage::StreamAccept { id } => {
                 format!("STREAM ACCEPT ID={}\n", id)
             }
+            SamMessage::NamingLookup { name } => {
+                format!("NAMING LOOKUP NAME={}\n", name)
+            }
         }
     }
 }
 
+/// Tunnel options for `SESSION CREATE`
+fn session_options(config: &I2PConfig) -> Vec<(String, String)> {
+    let mut options = vec![("SIGNATURE_TYPE".to_string(), "EdDSA_SHA512_Ed25519".to_string())];
+    for direction in ["inbound", "outbound"] {
+        options.push((format!("{}.length", direction), config.tunnel_length.to_string()));
+        options.push((format!("{}.quantity", direction), config.tunnel_quantity.to_string()));
+        options.push((
+            format!("{}.backupQuantity", direction),
+            config.backup_quantity.to_string(),
+        ));
+    }
+    options
+}
+
 /// Parse SAM response
-fn parse_sam_response(response: &str) -> Result<(String, std::collections::HashMap<String, String>)> {
-    let parts: Vec<&str> = response.split_whitespace().collect();
-    
-    if parts.is_empty() {
+///
+/// Values may be double-quoted (e.g. `MESSAGE="no such session"`).
+fn parse_sam_response(response: &str) -> Result<(String, HashMap<String, String>)> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in response.trim_end_matches(['\r', '\n']).chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    if tokens.is_empty() {
         return Err(AnonymityError::Transport("Empty SAM response".to_string()));
     }
-    
-    let command = parts[0].to_string();
-    let mut params = std::collections::HashMap::new();
-    
-    for part in &parts[1..] {
-        if let Some((key, value)) = part.split_once('=') {
+
+    let command = tokens[0].clone();
+    let mut params = HashMap::new();
+
+    for token in &tokens[1..] {
+        if let Some((key, value)) = token.split_once('=') {
             params.insert(key.to_string(), value.to_string());
         }
     }
-    
+
     Ok((command, params))
 }
 
+/// Fail unless a reply reports `RESULT=OK`
+fn check_result(params: &HashMap<String, String>) -> Result<()> {
+    match params.get("RESULT").map(String::as_str) {
+        Some("OK") => Ok(()),
+        result => Err(AnonymityError::Transport(format!(
+            "SAM error {}: {}",
+            result.unwrap_or("MISSING"),
+            params.get("MESSAGE").map(String::as_str).unwrap_or("no message")
+        ))),
+    }
+}
+
+/// Send one command and read its reply
+async fn sam_command(
+    stream: &mut TcpStream,
+    message: &SamMessage,
+) -> Result<HashMap<String, String>> {
+    stream
+        .write_all(message.format().as_bytes())
+        .await
+        .map_err(|e| AnonymityError::Transport(e.to_string()))?;
+
+    let line = read_line(stream).await?;
+    let (_, params) = parse_sam_response(&line)?;
+    check_result(&params)?;
+    Ok(params)
+}
+
+/// Read one newline-terminated reply
+///
+/// Reads byte by byte so nothing past the newline is consumed: after
+/// `STREAM CONNECT` the rest of the socket is application data.
+async fn read_line(stream: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|e| AnonymityError::Transport(format!("SAM bridge closed: {}", e)))?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_SAM_LINE {
+            return Err(AnonymityError::Transport("SAM reply too long".to_string()));
+        }
+        line.push(byte);
+    }
+    String::from_utf8(line)
+        .map_err(|_| AnonymityError::Transport("SAM reply not UTF-8".to_string()))
+}
+
+/// Hold the control connection open, answering PINGs until the bridge hangs up
+async fn watch_control(mut control: TcpStream, alive: Arc<AtomicBool>) {
+    while let Ok(line) = read_line(&mut control).await {
+        if let Some(payload) = line.strip_prefix("PING") {
+            let pong = format!("PONG{}\n", payload);
+            if control.write_all(pong.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    }
+    warn!("I2P SAM control connection closed");
+    alive.store(false, Ordering::Release);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
 
     #[test]
     fn test_sam_message_format() {
         let msg = SamMessage::Hello { version: "3.1".to_string() };
         assert!(msg.format().contains("HELLO VERSION"));
+        assert_eq!(msg.format(), "HELLO VERSION MIN=3.1 MAX=3.1\n");
+    }
+
+    #[test]
+    fn test_session_create_format() {
+        let msg = SamMessage::SessionCreate {
+            style: "STREAM".to_string(),
+            id: "qh".to_string(),
+            destination: "TRANSIENT".to_string(),
+            options: session_options(&I2PConfig::default()),
+        };
+        let line = msg.format();
+
+        assert!(line.starts_with("SESSION CREATE STYLE=STREAM ID=qh DESTINATION=TRANSIENT "));
+        assert!(line.contains(" inbound.length=3 "));
+        assert!(line.contains(" outbound.quantity=2 "));
+        assert!(line.contains(" outbound.backupQuantity=1"));
+        assert!(line.ends_with('\n'));
+        assert_eq!(line.matches('\n').count(), 1);
+    }
+
+    #[test]
+    fn test_stream_and_naming_format() {
+        let connect = SamMessage::StreamConnect {
+            id: "qh".to_string(),
+            destination: "AAAA".to_string(),
+        };
+        assert_eq!(connect.format(), "STREAM CONNECT ID=qh DESTINATION=AAAA SILENT=false\n");
+
+        let accept = SamMessage::StreamAccept { id: "qh".to_string() };
+        assert_eq!(accept.format(), "STREAM ACCEPT ID=qh\n");
+
+        let lookup = SamMessage::NamingLookup { name: "abc.b32.i2p".to_string() };
+        assert_eq!(lookup.format(), "NAMING LOOKUP NAME=abc.b32.i2p\n");
     }
 
     #[test]
     fn test_parse_sam_response() {
         let response = "HELLO REPLY RESULT=OK VERSION=3.1";
         let (cmd, params) = parse_sam_response(response).unwrap();
-        
+
         assert_eq!(cmd, "HELLO");
         assert_eq!(params.get("RESULT"), Some(&"OK".to_string()));
     }
+
+    #[test]
+    fn test_parse_quoted_error() {
+        let response = "STREAM STATUS RESULT=CANT_REACH_PEER MESSAGE=\"peer is offline\"\n";
+        let (cmd, params) = parse_sam_response(response).unwrap();
+
+        assert_eq!(cmd, "STREAM");
+        assert_eq!(params.get("MESSAGE").map(String::as_str), Some("peer is offline"));
+        let err = check_result(&params).unwrap_err().to_string();
+        assert!(err.contains("CANT_REACH_PEER"));
+        assert!(err.contains("peer is offline"));
+
+        assert!(parse_sam_response("   ").is_err());
+    }
+
+    /// Minimal SAM bridge that counts sessions and echoes stream data
+    async fn fake_bridge() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let sessions = Arc::new(AtomicUsize::new(0));
+        let counter = sessions.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut socket = BufReader::new(socket);
+                    let mut line = String::new();
+                    while socket.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let reply = if line.starts_with("HELLO") {
+                            "HELLO REPLY RESULT=OK VERSION=3.1\n"
+                        } else if line.starts_with("SESSION CREATE") {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            "SESSION STATUS RESULT=OK DESTINATION=PRIVATE\n"
+                        } else if line.starts_with("NAMING LOOKUP NAME=ME") {
+                            "NAMING REPLY RESULT=OK NAME=ME VALUE=OURDEST\n"
+                        } else if line.starts_with("NAMING LOOKUP") {
+                            "NAMING REPLY RESULT=OK NAME=peer.b32.i2p VALUE=PEERDEST\n"
+                        } else if line.contains("DESTINATION=UNREACHABLE") {
+                            "STREAM STATUS RESULT=CANT_REACH_PEER MESSAGE=unreachable\n"
+                        } else if line.contains("DESTINATION=STALE") {
+                            "STREAM STATUS RESULT=INVALID_ID MESSAGE=unknown\n"
+                        } else if line.starts_with("STREAM CONNECT ID=") {
+                            assert!(line.contains("DESTINATION=PEERDEST"));
+                            let mut socket = socket.into_inner();
+                            socket.write_all(b"STREAM STATUS RESULT=OK\n").await.unwrap();
+                            let (mut rd, mut wr) = socket.split();
+                            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+                            return;
+                        } else {
+                            "STATUS RESULT=I2P_ERROR\n"
+                        };
+                        socket.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        (addr, sessions)
+    }
+
+    fn transport_for(sam_addr: String) -> I2PTransport {
+        I2PTransport::new(I2PConfig {
+            sam_addr,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_bridge() {
+        let (sam_addr, sessions) = fake_bridge().await;
+        let transport = transport_for(sam_addr);
+
+        assert!(transport.is_available().await);
+        let mut conn = transport.connect("peer.b32.i2p").await.unwrap();
+        assert_eq!(transport.our_destination().as_deref(), Some("OURDEST"));
+
+        conn.send(b"ping").await.unwrap();
+        assert_eq!(conn.receive().await.unwrap(), b"ping");
+
+        // Later connections reuse the session
+        transport.connect("peer.b32.i2p").await.unwrap();
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recreates_closed_session() {
+        let (sam_addr, sessions) = fake_bridge().await;
+        let transport = transport_for(sam_addr);
+        transport.initialize().await.unwrap();
+
+        // Simulate the bridge dropping the control connection
+        transport
+            .session
+            .read()
+            .as_ref()
+            .unwrap()
+            .alive
+            .store(false, Ordering::Release);
+
+        transport.connect("peer.b32.i2p").await.unwrap();
+        assert_eq!(sessions.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_peer_errors_keep_session() {
+        let (sam_addr, sessions) = fake_bridge().await;
+        let transport = transport_for(sam_addr);
+        transport.initialize().await.unwrap();
+
+        assert!(matches!(
+            transport.connect("UNREACHABLE").await,
+            Err(AnonymityError::ConnectionFailed(_))
+        ));
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+        transport.connect("peer.b32.i2p").await.unwrap();
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+
+        // The bridge forgot the session: recreate it once, then give up
+        assert!(matches!(
+            transport.connect("STALE").await,
+            Err(AnonymityError::I2PUnavailable(_))
+        ));
+        assert_eq!(sessions.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_bridge() {
+        let transport = transport_for("127.0.0.1:1".to_string());
+
+        assert!(!transport.is_available().await);
+        assert!(matches!(
+            transport.connect("peer.b32.i2p").await,
+            Err(AnonymityError::I2PUnavailable(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a local I2P router with SAM enabled
+    async fn test_live_sam_session() {
+        let transport = I2PTransport::new(I2PConfig::default()).unwrap();
+        transport.initialize().await.unwrap();
+
+        assert!(transport.our_destination().is_some());
+        assert!(transport.is_available().await);
+    }
 }
@@ -14,6 +14,7 @@
 #![warn(missing_docs, rust_2018_idioms)]
 
 pub mod config;
+pub mod decoy;
 pub mod error;
 pub mod obfuscation;
 pub mod transport;
@@ -25,6 +26,12 @@ pub mod tor;
 pub mod i2p;
 
 pub use config::AnonymityConfig;
+pub use decoy::{AeadDecoy, DecoyGenerator, RandomDecoy};
 pub use error::{AnonymityError, Result};
-pub use obfuscation::TrafficObfuscator;
-pub use transport::{AnonymousTransport, TransportType};
+pub use obfuscation::{
+    AdaptiveCoverScheduler, Obfuscator, ObfuscatorChain, PaddingObfuscator, TickPlan,
+    TrafficObfuscator,
+};
+pub use transport::{
+    AnonymousTransport, AuthenticatedConnection, ConnectionRole, FrameAuthenticator, TransportType,
+};
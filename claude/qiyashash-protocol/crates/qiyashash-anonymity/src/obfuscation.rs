@@ -5,23 +5,248 @@
 //! - Message padding to uniform size
 //! - Message batching
 //! - Cover traffic generation
+//!
+//! Payload transforms are pluggable: each [`Obfuscator`] stage in an
+//! [`ObfuscatorChain`] runs in order on send and in reverse on receive.
+//!
+//! With an [`AdaptiveRateConfig`], cover traffic is scheduled by an
+//! [`AdaptiveCoverScheduler`] so that real and decoy messages together keep
+//! a constant rate, rather than adding decoys at a fixed rate on top.
 
 use std::collections::VecDeque;
+use std::fmt;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use parking_lot::Mutex;
+use rand::seq::SliceRandom;
 use rand::Rng;
 use tokio::sync::mpsc;
 use tokio::time::{interval, sleep};
 use tracing::{debug, trace};
 
-use crate::config::{CoverTrafficConfig, ObfuscationConfig};
-use crate::error::Result;
+use crate::config::{AdaptiveRateConfig, AnonymityConfig, CoverTrafficConfig, ObfuscationConfig};
+use crate::decoy::{AeadDecoy, DecoyGenerator};
+use crate::error::{AnonymityError, Result};
+
+/// A reversible payload transform (pluggable-transport style)
+///
+/// Implementations must satisfy `deobfuscate(obfuscate(x)) == x`.
+pub trait Obfuscator: Send + Sync {
+    /// Short name for logging
+    fn name(&self) -> &str;
+
+    /// Transform an outgoing payload
+    fn obfuscate(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Undo [`Obfuscator::obfuscate`] on an incoming payload
+    fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Ordered list of obfuscators
+///
+/// Stages run first-to-last on send and last-to-first on receive.
+#[derive(Clone, Default)]
+pub struct ObfuscatorChain {
+    stages: Vec<Arc<dyn Obfuscator>>,
+}
+
+impl ObfuscatorChain {
+    /// Create an empty chain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a stage
+    pub fn with(mut self, stage: impl Obfuscator + 'static) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    /// Append a shared stage
+    pub fn push(&mut self, stage: Arc<dyn Obfuscator>) {
+        self.stages.push(stage);
+    }
+
+    /// Number of stages
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Whether the chain has no stages
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Stage names in send order
+    pub fn names(&self) -> Vec<&str> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    /// Apply every stage in order
+    pub fn obfuscate(&self, data: &[u8]) -> Vec<u8> {
+        self.stages
+            .iter()
+            .fold(data.to_vec(), |acc, stage| stage.obfuscate(&acc))
+    }
+
+    /// Undo every stage in reverse order
+    pub fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.stages
+            .iter()
+            .rev()
+            .try_fold(data.to_vec(), |acc, stage| stage.deobfuscate(&acc))
+    }
+}
+
+impl fmt::Debug for ObfuscatorChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Length-prefixed random padding; the default obfuscator
+#[derive(Clone, Debug)]
+pub struct PaddingObfuscator {
+    padding_range: (usize, usize),
+}
+
+impl PaddingObfuscator {
+    /// Pad with between `min` and `max` random bytes
+    pub fn new(padding_range: (usize, usize)) -> Self {
+        Self { padding_range }
+    }
+
+    /// Use the padding range from an obfuscation config
+    pub fn from_config(config: &ObfuscationConfig) -> Self {
+        Self::new(config.padding_range)
+    }
+}
+
+impl Obfuscator for PaddingObfuscator {
+    fn name(&self) -> &str {
+        "padding"
+    }
+
+    fn obfuscate(&self, data: &[u8]) -> Vec<u8> {
+        pad(data, self.padding_range)
+    }
+
+    fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 4 {
+            return Err(AnonymityError::Deobfuscation("padded message too short".to_string()));
+        }
+        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if 4 + length > data.len() {
+            return Err(AnonymityError::Deobfuscation(
+                "padding length exceeds message".to_string(),
+            ));
+        }
+        Ok(data[4..4 + length].to_vec())
+    }
+}
+
+/// Create padded message: [length: 4 bytes][data][random padding]
+fn pad(data: &[u8], (min_pad, max_pad): (usize, usize)) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let pad_size = rng.gen_range(min_pad..=max_pad);
+
+    let mut result = Vec::with_capacity(4 + data.len() + pad_size);
+    result.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    result.extend_from_slice(data);
+
+    // Add random padding
+    let mut padding = vec![0u8; pad_size];
+    rng.fill(&mut padding[..]);
+    result.extend_from_slice(&padding);
+
+    result
+}
+
+/// Sends allowed in one scheduler tick
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TickPlan {
+    /// Queued real messages to release
+    pub real: usize,
+    /// Decoys to generate
+    pub decoys: usize,
+}
+
+impl TickPlan {
+    /// Total messages sent this tick
+    pub fn total(&self) -> usize {
+        self.real + self.decoys
+    }
+}
+
+/// Keeps the aggregate (real + decoy) send rate constant
+///
+/// Each tick accrues credit at the configured rates. Real messages are sent
+/// first, up to `max_rate` less the mandatory `min_rate` decoys; decoys then
+/// make up whatever is still missing to reach `target_rate`. Credit never
+/// carries over past one tick, so a burst cannot be followed by a quiet gap
+/// or preceded by a saved-up flood.
+#[derive(Debug)]
+pub struct AdaptiveCoverScheduler {
+    config: AdaptiveRateConfig,
+    target_credit: f64,
+    cover_credit: f64,
+    cap_credit: f64,
+}
+
+impl AdaptiveCoverScheduler {
+    /// Create a scheduler, rejecting inconsistent rates
+    pub fn new(config: AdaptiveRateConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            target_credit: 0.0,
+            cover_credit: 0.0,
+            cap_credit: 0.0,
+        })
+    }
+
+    /// Configured rates
+    pub fn config(&self) -> &AdaptiveRateConfig {
+        &self.config
+    }
+
+    /// Plan the sends for a tick of length `elapsed` with `pending_real`
+    /// real messages waiting
+    pub fn tick(&mut self, elapsed: Duration, pending_real: usize) -> TickPlan {
+        let hours = elapsed.as_secs_f64() / 3600.0;
+        self.target_credit += self.config.target_rate * hours;
+        self.cover_credit += self.config.min_rate * hours;
+        self.cap_credit += self.config.max_rate * hours;
+        let cap = self.cap_credit.floor() as usize;
+
+        let mandatory = (self.cover_credit.floor() as usize).min(cap);
+        let real = pending_real.min(cap - mandatory);
+        let fill = (self.target_credit.floor() as usize)
+            .saturating_sub(real + mandatory)
+            .min(cap - real - mandatory);
+        let plan = TickPlan {
+            real,
+            decoys: mandatory + fill,
+        };
+
+        // Only the fractional remainder carries over; a surplus from a
+        // burst is forgiven instead of starving the following ticks
+        self.target_credit = (self.target_credit - plan.total() as f64).max(0.0).fract();
+        self.cover_credit = (self.cover_credit - mandatory as f64).max(0.0).fract();
+        self.cap_credit = (self.cap_credit - plan.total() as f64).max(0.0).fract();
+
+        trace!("Adaptive tick: {} real, {} decoys", plan.real, plan.decoys);
+        plan
+    }
+}
 
 /// Traffic obfuscator
 pub struct TrafficObfuscator {
     config: ObfuscationConfig,
     cover_config: CoverTrafficConfig,
+    chain: ObfuscatorChain,
+    decoys: Arc<dyn DecoyGenerator>,
+    scheduler: Option<Arc<Mutex<AdaptiveCoverScheduler>>>,
     message_queue: Arc<Mutex<VecDeque<QueuedMessage>>>,
     last_send: Arc<Mutex<Instant>>,
 }
@@ -35,15 +260,63 @@ struct QueuedMessage {
 
 impl TrafficObfuscator {
     /// Create a new traffic obfuscator
-    pub fn new(config: ObfuscationConfig, cover_config: CoverTrafficConfig) -> Self {
-        Self {
+    ///
+    /// Payloads go through the default [`PaddingObfuscator`] and cover
+    /// messages are filled by [`AeadDecoy`]. Fails with
+    /// [`AnonymityError::Configuration`] if the adaptive rates are invalid.
+    pub fn new(config: ObfuscationConfig, cover_config: CoverTrafficConfig) -> Result<Self> {
+        let chain = ObfuscatorChain::new().with(PaddingObfuscator::from_config(&config));
+        let scheduler = cover_config
+            .adaptive
+            .clone()
+            .map(AdaptiveCoverScheduler::new)
+            .transpose()?;
+        Ok(Self {
             config,
             cover_config,
+            chain,
+            decoys: Arc::new(AeadDecoy),
+            scheduler: scheduler.map(|s| Arc::new(Mutex::new(s))),
             message_queue: Arc::new(Mutex::new(VecDeque::new())),
             last_send: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+
+    /// Create from an anonymity config, using its obfuscator chain if set
+    pub fn from_config(config: &AnonymityConfig) -> Result<Self> {
+        let obfuscator = Self::new(config.obfuscation.clone(), config.cover_traffic.clone())?;
+        if config.obfuscators.is_empty() {
+            Ok(obfuscator)
+        } else {
+            Ok(obfuscator.with_chain(config.obfuscators.clone()))
         }
     }
 
+    /// Replace the payload obfuscator chain
+    pub fn with_chain(mut self, chain: ObfuscatorChain) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// Replace the cover-traffic decoy generator
+    pub fn with_decoy_generator(mut self, generator: impl DecoyGenerator + 'static) -> Self {
+        self.decoys = Arc::new(generator);
+        self
+    }
+
+    /// Schedule cover traffic adaptively with `rates`
+    pub fn with_adaptive_rate(mut self, rates: AdaptiveRateConfig) -> Result<Self> {
+        let scheduler = AdaptiveCoverScheduler::new(rates.clone())?;
+        self.cover_config.adaptive = Some(rates);
+        self.scheduler = Some(Arc::new(Mutex::new(scheduler)));
+        Ok(self)
+    }
+
+    /// Adaptive rates in use, if any
+    pub fn adaptive_rates(&self) -> Option<AdaptiveRateConfig> {
+        self.scheduler.as_ref().map(|s| s.lock().config().clone())
+    }
+
     /// Process outgoing message with obfuscation
     pub async fn obfuscate(&self, data: &[u8]) -> Vec<u8> {
         if !self.config.enabled {
@@ -54,10 +327,16 @@ impl TrafficObfuscator {
         let delay = self.random_delay();
         sleep(delay).await;
 
-        // Add padding
-        let padded = self.add_padding(data);
+        trace!("Obfuscating {} bytes through {:?}", data.len(), self.chain);
+        self.chain.obfuscate(data)
+    }
 
-        padded
+    /// Reverse [`TrafficObfuscator::obfuscate`] on a received message
+    pub fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if !self.config.enabled {
+            return Ok(data.to_vec());
+        }
+        self.chain.deobfuscate(data)
     }
 
     /// Queue message for batched sending
@@ -91,23 +370,69 @@ impl TrafficObfuscator {
         batch
     }
 
+    /// Messages to send for a tick of length `elapsed`
+    ///
+    /// With adaptive cover traffic, returns queued real messages and decoys
+    /// in random order, as planned by the [`AdaptiveCoverScheduler`].
+    /// Otherwise this is [`TrafficObfuscator::get_batch`].
+    pub fn adaptive_tick(&self, elapsed: Duration) -> Vec<Vec<u8>> {
+        match &self.scheduler {
+            Some(scheduler) => adaptive_batch(
+                scheduler,
+                &self.message_queue,
+                self.decoys.as_ref(),
+                self.cover_config.size_range,
+                elapsed,
+            ),
+            None => self.get_batch(),
+        }
+    }
+
+    /// Start the adaptive sender
+    ///
+    /// Every tick, the output of [`TrafficObfuscator::adaptive_tick`] is
+    /// sent on the returned channel; real messages must be handed over with
+    /// [`TrafficObfuscator::queue_message`]. Returns a closed channel when
+    /// adaptive cover traffic is not configured.
+    pub fn start_adaptive_traffic(&self) -> mpsc::Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel(100);
+        let Some(scheduler) = self.scheduler.clone() else {
+            return rx;
+        };
+        let queue = self.message_queue.clone();
+        let decoys = self.decoys.clone();
+        let size_range = self.cover_config.size_range;
+        let tick = scheduler.lock().config().tick();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(tick);
+            let mut last = Instant::now();
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                let batch = adaptive_batch(
+                    &scheduler,
+                    &queue,
+                    decoys.as_ref(),
+                    size_range,
+                    now.duration_since(last),
+                );
+                last = now;
+
+                for data in batch {
+                    if tx.send(data).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        rx
+    }
+
     /// Add padding to message
     pub fn add_padding(&self, data: &[u8]) -> Vec<u8> {
-        let mut rng = rand::thread_rng();
-        let (min_pad, max_pad) = self.config.padding_range;
-        let pad_size = rng.gen_range(min_pad..=max_pad);
-        
-        // Create padded message: [length: 4 bytes][data][random padding]
-        let mut result = Vec::with_capacity(4 + data.len() + pad_size);
-        result.extend_from_slice(&(data.len() as u32).to_be_bytes());
-        result.extend_from_slice(data);
-        
-        // Add random padding
-        let mut padding = vec![0u8; pad_size];
-        rng.fill(&mut padding[..]);
-        result.extend_from_slice(&padding);
-        
-        result
+        pad(data, self.config.padding_range)
     }
 
     /// Remove padding from message
@@ -134,17 +459,7 @@ impl TrafficObfuscator {
 
     /// Generate cover message
     pub fn generate_cover_message(&self) -> Vec<u8> {
-        let mut rng = rand::thread_rng();
-        let (min_size, max_size) = self.cover_config.size_range;
-        let size = rng.gen_range(min_size..=max_size);
-        
-        let mut data = vec![0u8; size];
-        rng.fill(&mut data[..]);
-        
-        // Mark as cover traffic (first byte = 0xFF)
-        data[0] = 0xFF;
-        
-        data
+        cover_message(self.decoys.as_ref(), self.cover_config.size_range)
     }
 
     /// Check if message is cover traffic
@@ -156,6 +471,7 @@ impl TrafficObfuscator {
     pub fn start_cover_traffic(&self) -> mpsc::Receiver<Vec<u8>> {
         let (tx, rx) = mpsc::channel(100);
         let config = self.cover_config.clone();
+        let decoys = self.decoys.clone();
         
         if !config.enabled {
             return rx;
@@ -178,14 +494,7 @@ impl TrafficObfuscator {
                 sleep(Duration::from_secs_f64(interval_secs)).await;
                 
                 // Generate and send cover message
-                let mut rng = rand::thread_rng();
-                let (min_size, max_size) = config.size_range;
-                let size = rng.gen_range(min_size..=max_size);
-                
-                let mut data = vec![0u8; size];
-                rng.fill(&mut data[..]);
-                data[0] = 0xFF; // Mark as cover
-                
+                let data = cover_message(decoys.as_ref(), config.size_range);
                 if tx.send(data).await.is_err() {
                     break;
                 }
@@ -196,6 +505,34 @@ impl TrafficObfuscator {
     }
 }
 
+/// Real messages and decoys for one adaptive tick, shuffled
+fn adaptive_batch(
+    scheduler: &Mutex<AdaptiveCoverScheduler>,
+    queue: &Mutex<VecDeque<QueuedMessage>>,
+    decoys: &dyn DecoyGenerator,
+    size_range: (usize, usize),
+    elapsed: Duration,
+) -> Vec<Vec<u8>> {
+    let mut queue = queue.lock();
+    let plan = scheduler.lock().tick(elapsed, queue.len());
+
+    let mut batch: Vec<Vec<u8>> = queue.drain(..plan.real).map(|msg| msg.data).collect();
+    drop(queue);
+    batch.extend((0..plan.decoys).map(|_| cover_message(decoys, size_range)));
+    batch.shuffle(&mut rand::thread_rng());
+    batch
+}
+
+/// Decoy of a random size in `size_range`, marked as cover (first byte 0xFF)
+fn cover_message(decoys: &dyn DecoyGenerator, (min_size, max_size): (usize, usize)) -> Vec<u8> {
+    let size = rand::thread_rng().gen_range(min_size..=max_size).max(1);
+    trace!("Generating {}-byte {} decoy", size, decoys.name());
+
+    let mut data = decoys.generate(size);
+    data[0] = 0xFF;
+    data
+}
+
 /// Message timing analyzer (for detection of traffic analysis)
 pub struct TimingAnalyzer {
     message_times: Vec<Instant>,
@@ -266,7 +603,7 @@ mod tests {
     fn test_padding() {
         let config = ObfuscationConfig::default();
         let cover_config = CoverTrafficConfig::default();
-        let obfuscator = TrafficObfuscator::new(config, cover_config);
+        let obfuscator = TrafficObfuscator::new(config, cover_config).unwrap();
         
         let original = b"Hello, World!";
         let padded = obfuscator.add_padding(original);
@@ -285,7 +622,7 @@ mod tests {
             size_range: (100, 200),
             ..Default::default()
         };
-        let obfuscator = TrafficObfuscator::new(config, cover_config);
+        let obfuscator = TrafficObfuscator::new(config, cover_config).unwrap();
         
         let cover = obfuscator.generate_cover_message();
         
@@ -294,6 +631,108 @@ mod tests {
         assert!(obfuscator.is_cover_traffic(&cover));
     }
 
+    /// Decoy generator producing recognizable bytes
+    struct FixedDecoy;
+
+    impl DecoyGenerator for FixedDecoy {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn generate(&self, size: usize) -> Vec<u8> {
+            vec![0xAB; size]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cover_traffic_uses_decoy_generator() {
+        let cover_config = CoverTrafficConfig {
+            enabled: true,
+            rate_per_hour: 3_600_000.0,
+            poisson_timing: false,
+            size_range: (32, 64),
+            adaptive: None,
+        };
+        let obfuscator = TrafficObfuscator::new(ObfuscationConfig::default(), cover_config)
+            .unwrap()
+            .with_decoy_generator(FixedDecoy);
+
+        let mut rx = obfuscator.start_cover_traffic();
+        let cover = rx.recv().await.unwrap();
+        assert!(obfuscator.is_cover_traffic(&cover));
+        assert!((32..=64).contains(&cover.len()));
+        assert!(cover[1..].iter().all(|&b| b == 0xAB));
+    }
+
+    #[test]
+    fn test_adaptive_rate_stays_in_band() {
+        let rates = AdaptiveRateConfig {
+            target_rate: 3600.0, // 1 per second
+            min_rate: 720.0,
+            max_rate: 18_000.0,
+            tick_ms: 1000,
+        };
+        let cover_config = CoverTrafficConfig {
+            size_range: (32, 64),
+            ..Default::default()
+        };
+        let obfuscator = TrafficObfuscator::new(ObfuscationConfig::default(), cover_config)
+            .unwrap()
+            .with_adaptive_rate(rates.clone())
+            .unwrap();
+        assert_eq!(obfuscator.adaptive_rates().unwrap().target_rate, 3600.0);
+
+        // Idle, one large burst, a sustained stretch above target, a trickle
+        let arrivals = |tick: usize| match tick {
+            10 => 40,
+            30..=39 => 3,
+            50 => 2,
+            _ => 0,
+        };
+
+        let mut per_tick = Vec::new();
+        let mut real_sent = 0;
+        for tick in 0..80 {
+            for _ in 0..arrivals(tick) {
+                obfuscator.queue_message(vec![0x01; 16]);
+            }
+            let batch = obfuscator.adaptive_tick(rates.tick());
+            let decoys = batch.iter().filter(|m| obfuscator.is_cover_traffic(m)).count();
+            real_sent += batch.len() - decoys;
+            per_tick.push((batch.len(), decoys));
+        }
+
+        assert_eq!(real_sent, 40 + 30 + 2);
+        for window in per_tick.windows(10) {
+            let total: usize = window.iter().map(|(total, _)| total).sum();
+            let decoys: usize = window.iter().map(|(_, decoys)| decoys).sum();
+            assert!((10..=50).contains(&total), "aggregate {} outside band", total);
+            assert!(decoys > 0, "decoys stopped during a burst");
+        }
+
+        // Idle ticks are fully made up of decoys at the target rate
+        assert!(per_tick[..10].iter().all(|&(total, decoys)| total == 1 && decoys == 1));
+    }
+
+    #[test]
+    fn test_invalid_adaptive_rate_is_rejected() {
+        let cover_config = CoverTrafficConfig {
+            adaptive: Some(AdaptiveRateConfig { tick_ms: 0, ..Default::default() }),
+            ..Default::default()
+        };
+        let result = TrafficObfuscator::new(ObfuscationConfig::default(), cover_config.clone());
+        assert!(matches!(result, Err(AnonymityError::Configuration(_))));
+
+        let config = AnonymityConfig {
+            cover_traffic: cover_config,
+            ..Default::default()
+        };
+        assert!(matches!(
+            TrafficObfuscator::from_config(&config),
+            Err(AnonymityError::Configuration(_))
+        ));
+    }
+
     #[test]
     fn test_timing_analyzer() {
         let mut analyzer = TimingAnalyzer::new(100);
@@ -307,4 +746,96 @@ mod tests {
         let avg = analyzer.average_delay();
         assert!(avg.is_some());
     }
+
+    /// XORs every byte with a key
+    struct XorStage(u8);
+
+    impl Obfuscator for XorStage {
+        fn name(&self) -> &str {
+            "xor"
+        }
+
+        fn obfuscate(&self, data: &[u8]) -> Vec<u8> {
+            data.iter().map(|b| b ^ self.0).collect()
+        }
+
+        fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(self.obfuscate(data))
+        }
+    }
+
+    /// Prepends a fixed header, e.g. a fronting marker
+    struct HeaderStage(&'static [u8]);
+
+    impl Obfuscator for HeaderStage {
+        fn name(&self) -> &str {
+            "header"
+        }
+
+        fn obfuscate(&self, data: &[u8]) -> Vec<u8> {
+            [self.0, data].concat()
+        }
+
+        fn deobfuscate(&self, data: &[u8]) -> Result<Vec<u8>> {
+            data.strip_prefix(self.0)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| AnonymityError::Deobfuscation("missing header".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_two_stage_chain_round_trip() {
+        let chain = ObfuscatorChain::new()
+            .with(HeaderStage(b"GET /"))
+            .with(XorStage(0x5A));
+        let original = b"Hello, chain!";
+
+        let obfuscated = chain.obfuscate(original);
+        assert_eq!(chain.deobfuscate(&obfuscated).unwrap(), original);
+        assert_eq!(chain.names(), vec!["header", "xor"]);
+    }
+
+    #[test]
+    fn test_chain_reverses_order_on_deobfuscation() {
+        let header = HeaderStage(b"GET /");
+        let xor = XorStage(0x5A);
+        let chain = ObfuscatorChain::new()
+            .with(HeaderStage(b"GET /"))
+            .with(XorStage(0x5A));
+        let original = b"payload";
+
+        // Send order: header first, then xor
+        let obfuscated = chain.obfuscate(original);
+        assert_eq!(obfuscated, xor.obfuscate(&header.obfuscate(original)));
+
+        // Undoing in send order would look for the header under the xor layer
+        assert!(header.deobfuscate(&obfuscated).is_err());
+        assert_eq!(chain.deobfuscate(&obfuscated).unwrap(), original);
+    }
+
+    #[tokio::test]
+    async fn test_traffic_obfuscator_uses_chain() {
+        let config = ObfuscationConfig {
+            min_delay_ms: 0,
+            max_delay_ms: 0,
+            ..Default::default()
+        };
+        let chain = ObfuscatorChain::new()
+            .with(PaddingObfuscator::new((8, 16)))
+            .with(XorStage(0x01));
+        let obfuscator = TrafficObfuscator::new(config, CoverTrafficConfig::default())
+            .unwrap()
+            .with_chain(chain);
+
+        let obfuscated = obfuscator.obfuscate(b"secret").await;
+        assert!(obfuscated.len() >= 4 + 6 + 8);
+        assert_eq!(obfuscator.deobfuscate(&obfuscated).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_padding_obfuscator_rejects_truncated() {
+        let padding = PaddingObfuscator::new((0, 0));
+        let padded = padding.obfuscate(b"data");
+        assert!(padding.deobfuscate(&padded[..5]).is_err());
+    }
 }
@@ -1,8 +1,17 @@
 //! Anonymous transport layer
+//!
+//! [`AuthenticatedConnection`] wraps any [`Connection`] so that every frame
+//! carries a MAC over the transport type, a sequence number and the payload.
+//! A frame delivered over the wrong transport, replayed, reordered or
+//! reflected back to its sender fails to open. Connections opened with
+//! [`AnonymousTransport::connect_authenticated`] are wrapped this way.
 
 use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::sync::Arc;
 use tracing::{debug, info, warn};
+use zeroize::Zeroize;
 
 use crate::config::{AnonymityConfig, TransportTypeConfig};
 use crate::error::{AnonymityError, Result};
@@ -18,12 +27,37 @@ pub enum TransportType {
     I2P,
 }
 
+impl TransportType {
+    /// Label bound into frame MACs
+    fn frame_label(self) -> &'static [u8] {
+        match self {
+            TransportType::Direct => b"direct",
+            TransportType::Tor => b"tor",
+            TransportType::I2P => b"i2p",
+        }
+    }
+}
+
 /// Anonymous transport trait
 #[async_trait]
 pub trait AnonymousTransport: Send + Sync {
     /// Connect to a destination
     async fn connect(&self, destination: &str) -> Result<Box<dyn Connection>>;
 
+    /// Connect to a destination, authenticating every frame with `key`
+    ///
+    /// `key` is shared with the destination, which opens the frames with a
+    /// [`ConnectionRole::Responder`] authenticator for this transport.
+    async fn connect_authenticated(
+        &self,
+        destination: &str,
+        key: [u8; 32],
+    ) -> Result<Box<dyn Connection>> {
+        let auth = FrameAuthenticator::new(key, self.transport_type(), ConnectionRole::Initiator);
+        let inner = self.connect(destination).await?;
+        Ok(Box::new(AuthenticatedConnection::new(inner, auth)))
+    }
+
     /// Get transport type
     fn transport_type(&self) -> TransportType;
 
@@ -63,8 +97,179 @@ pub struct CircuitInfo {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Domain separator for frame MACs
+const FRAME_MAC_DOMAIN: &[u8] = b"QiyasHash_TransportFrame_v1";
+
+/// Labels of the two directions' frame keys
+const INITIATOR_KEY_LABEL: &[u8] = b"initiator";
+const RESPONDER_KEY_LABEL: &[u8] = b"responder";
+
+/// Sequence number bytes at the start of a frame
+const FRAME_SEQ_LEN: usize = 8;
+
+/// MAC bytes at the end of a frame
+const FRAME_TAG_LEN: usize = 32;
+
+/// Which end of a connection an authenticator serves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionRole {
+    /// The end that connected
+    Initiator,
+    /// The end that accepted
+    Responder,
+}
+
+/// Seals and opens frames for one transport
+///
+/// A frame is `seq (u64 BE) || payload || HMAC-SHA256(key, label || seq ||
+/// payload)`, where the label names the transport. The transport is not
+/// sent, so a frame sealed for one transport never opens on another. Each
+/// direction has its own key derived from the shared one, so a frame cannot
+/// be reflected back to the end that sealed it. Frames must be opened in
+/// the order they were sealed.
+pub struct FrameAuthenticator {
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    transport: TransportType,
+    send_seq: u64,
+    recv_seq: u64,
+}
+
+impl FrameAuthenticator {
+    /// Create an authenticator for `role` with a key shared by both ends
+    pub fn new(mut key: [u8; 32], transport: TransportType, role: ConnectionRole) -> Self {
+        let initiator_key = direction_key(&key, INITIATOR_KEY_LABEL);
+        let responder_key = direction_key(&key, RESPONDER_KEY_LABEL);
+        key.zeroize();
+
+        let (send_key, recv_key) = match role {
+            ConnectionRole::Initiator => (initiator_key, responder_key),
+            ConnectionRole::Responder => (responder_key, initiator_key),
+        };
+        Self {
+            send_key,
+            recv_key,
+            transport,
+            send_seq: 0,
+            recv_seq: 0,
+        }
+    }
+
+    /// Transport the frames are bound to
+    pub fn transport_type(&self) -> TransportType {
+        self.transport
+    }
+
+    /// Frame `payload` with the next sequence number
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let seq = self.send_seq;
+        self.send_seq += 1;
+
+        let mut frame = Vec::with_capacity(FRAME_SEQ_LEN + payload.len() + FRAME_TAG_LEN);
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(payload);
+        let tag = self.mac(&self.send_key, seq, payload).finalize().into_bytes();
+        frame.extend_from_slice(&tag);
+        frame
+    }
+
+    /// Verify `frame` and return its payload
+    ///
+    /// Fails on a bad MAC, which includes frames sealed for another
+    /// transport, and on any sequence number other than the next expected.
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        if frame.len() < FRAME_SEQ_LEN + FRAME_TAG_LEN {
+            return Err(AnonymityError::InvalidFrame("frame too short".to_string()));
+        }
+        let (seq, rest) = frame.split_at(FRAME_SEQ_LEN);
+        let (payload, tag) = rest.split_at(rest.len() - FRAME_TAG_LEN);
+        let mut seq_bytes = [0u8; FRAME_SEQ_LEN];
+        seq_bytes.copy_from_slice(seq);
+        let seq = u64::from_be_bytes(seq_bytes);
+
+        self.mac(&self.recv_key, seq, payload)
+            .verify_slice(tag)
+            .map_err(|_| AnonymityError::InvalidFrame("bad frame MAC".to_string()))?;
+
+        if seq != self.recv_seq {
+            return Err(AnonymityError::InvalidFrame(format!(
+                "expected frame {}, got {}",
+                self.recv_seq, seq
+            )));
+        }
+        self.recv_seq += 1;
+        Ok(payload.to_vec())
+    }
+
+    fn mac(&self, key: &[u8; 32], seq: u64, payload: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(key)
+            .expect("HMAC accepts any key length");
+        let label = self.transport.frame_label();
+        mac.update(FRAME_MAC_DOMAIN);
+        mac.update(&[label.len() as u8]);
+        mac.update(label);
+        mac.update(&seq.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+}
+
+impl Drop for FrameAuthenticator {
+    fn drop(&mut self) {
+        self.send_key.zeroize();
+        self.recv_key.zeroize();
+    }
+}
+
+/// Key for frames sent in the direction named by `label`
+fn direction_key(key: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key)
+        .expect("HMAC accepts any key length");
+    mac.update(FRAME_MAC_DOMAIN);
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// Connection whose frames are authenticated with a [`FrameAuthenticator`]
+pub struct AuthenticatedConnection {
+    inner: Box<dyn Connection>,
+    auth: FrameAuthenticator,
+}
+
+impl AuthenticatedConnection {
+    /// Wrap `inner`, binding frames to `auth`'s transport
+    pub fn new(inner: Box<dyn Connection>, auth: FrameAuthenticator) -> Self {
+        Self { inner, auth }
+    }
+}
+
+#[async_trait]
+impl Connection for AuthenticatedConnection {
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let frame = self.auth.seal(data);
+        self.inner.send(&frame).await
+    }
+
+    async fn receive(&mut self) -> Result<Vec<u8>> {
+        let frame = self.inner.receive().await?;
+        self.auth.open(&frame)
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+}
+
 /// Create transport from configuration
 pub fn create_transport(config: &AnonymityConfig) -> Result<Arc<dyn AnonymousTransport>> {
+    config.validate()?;
+
     match &config.transport.transport_type {
         TransportTypeConfig::Direct => Ok(Arc::new(DirectTransport::new())),
         
@@ -192,4 +397,101 @@ mod tests {
         let transport = DirectTransport::new();
         assert!(transport.is_available().await);
     }
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    fn assert_invalid(result: Result<Vec<u8>>) {
+        assert!(matches!(result, Err(AnonymityError::InvalidFrame(_))));
+    }
+
+    fn initiator(transport: TransportType) -> FrameAuthenticator {
+        FrameAuthenticator::new(KEY, transport, ConnectionRole::Initiator)
+    }
+
+    fn responder(transport: TransportType) -> FrameAuthenticator {
+        FrameAuthenticator::new(KEY, transport, ConnectionRole::Responder)
+    }
+
+    #[test]
+    fn test_frames_accepted_in_order() {
+        let mut sender = initiator(TransportType::Tor);
+        let mut receiver = responder(TransportType::Tor);
+
+        for payload in [&b"first"[..], b"", b"third"] {
+            let frame = sender.seal(payload);
+            assert_eq!(receiver.open(&frame).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn test_reordered_frames_rejected() {
+        let mut sender = initiator(TransportType::Tor);
+        let mut receiver = responder(TransportType::Tor);
+
+        let first = sender.seal(b"first");
+        let second = sender.seal(b"second");
+        assert_invalid(receiver.open(&second));
+
+        assert_eq!(receiver.open(&first).unwrap(), b"first");
+        assert_invalid(receiver.open(&first));
+        assert_eq!(receiver.open(&second).unwrap(), b"second");
+
+        // Rewriting the sequence number breaks the MAC
+        let mut third = sender.seal(b"third");
+        third[7] ^= 1;
+        assert_invalid(receiver.open(&third));
+    }
+
+    #[test]
+    fn test_cross_transport_frames_rejected() {
+        let mut tor = initiator(TransportType::Tor);
+        let mut direct = responder(TransportType::Direct);
+
+        let frame = tor.seal(b"meant for tor");
+        assert_invalid(direct.open(&frame));
+
+        let mut tampered = initiator(TransportType::Tor).seal(b"payload");
+        tampered[FRAME_SEQ_LEN] ^= 1;
+        assert_invalid(responder(TransportType::Tor).open(&tampered));
+        assert_invalid(direct.open(&[0u8; FRAME_SEQ_LEN + FRAME_TAG_LEN - 1]));
+    }
+
+    #[test]
+    fn test_reflected_frames_rejected() {
+        let mut client = initiator(TransportType::Tor);
+        let mut server = responder(TransportType::Tor);
+
+        // A frame sent back to its sealer does not open
+        let frame = client.seal(b"request");
+        assert_invalid(initiator(TransportType::Tor).open(&frame));
+        assert_eq!(server.open(&frame).unwrap(), b"request");
+
+        let reply = server.seal(b"reply");
+        assert_invalid(responder(TransportType::Tor).open(&reply));
+        assert_eq!(client.open(&reply).unwrap(), b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_connect_authenticated() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut auth = responder(TransportType::Direct);
+            let mut frame = vec![0u8; FRAME_SEQ_LEN + 5 + FRAME_TAG_LEN];
+            stream.read_exact(&mut frame).await.unwrap();
+            assert_eq!(auth.open(&frame).unwrap(), b"hello");
+            stream.write_all(&auth.seal(b"hi")).await.unwrap();
+        });
+
+        let mut connection = DirectTransport::new()
+            .connect_authenticated(&address, KEY)
+            .await
+            .unwrap();
+        connection.send(b"hello").await.unwrap();
+        assert_eq!(connection.receive().await.unwrap(), b"hi");
+        server.await.unwrap();
+    }
 }
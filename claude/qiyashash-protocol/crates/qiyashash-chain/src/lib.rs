@@ -22,5 +22,5 @@ pub use qiyashash_crypto::chain::{
 pub mod manager;
 pub mod storage;
 
-pub use manager::ChainManager;
+pub use manager::{ChainManager, OrderedMessage};
 pub use storage::ChainStorage;
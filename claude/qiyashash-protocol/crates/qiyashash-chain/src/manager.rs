@@ -1,12 +1,13 @@
 //! Chain manager for session chain states
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 use parking_lot::RwLock;
 use tracing::{debug, info, warn};
 
+use qiyashash_core::message::Message;
 use qiyashash_core::session::SessionId;
-use qiyashash_crypto::chain::{ChainState, ChainLink, ChainProof, ChainVerifier};
+use qiyashash_crypto::chain::{ChainState, ChainLink, ChainLinkType, ChainProof, ChainVerifier};
 
 use crate::storage::ChainStorage;
 
@@ -33,6 +34,42 @@ pub enum ChainError {
 /// Result type alias
 pub type Result<T> = std::result::Result<T, ChainError>;
 
+/// A position in a conversation's authoritative order
+#[derive(Debug, Clone)]
+pub enum OrderedMessage {
+    /// A message that was supplied
+    Delivered {
+        /// Chain sequence number of the message
+        sequence: u64,
+        /// The decrypted message
+        message: Message,
+    },
+    /// A message recorded in the chain that was not supplied
+    Gap {
+        /// Chain sequence number of the missing message
+        sequence: u64,
+    },
+}
+
+impl OrderedMessage {
+    /// Chain sequence number of this position
+    pub fn sequence(&self) -> u64 {
+        match self {
+            OrderedMessage::Delivered { sequence, .. } | OrderedMessage::Gap { sequence } => {
+                *sequence
+            }
+        }
+    }
+
+    /// The message, unless this is a gap
+    pub fn message(&self) -> Option<&Message> {
+        match self {
+            OrderedMessage::Delivered { message, .. } => Some(message),
+            OrderedMessage::Gap { .. } => None,
+        }
+    }
+}
+
 /// Manager for session chain states
 pub struct ChainManager {
     /// Active chains in memory
@@ -193,6 +230,71 @@ impl ChainManager {
         Ok(chain.history().to_vec())
     }
 
+    /// Put decrypted messages into send order
+    ///
+    /// Each message comes with the chain link it was recorded under. Links
+    /// are checked against the session's chain, then every message link in
+    /// the retained history is returned in sequence order: as
+    /// [`OrderedMessage::Delivered`] when its message was supplied and as
+    /// [`OrderedMessage::Gap`] when it was not. Messages later deleted on
+    /// the chain are left out rather than reported as gaps.
+    pub fn order_messages(
+        &self,
+        session_id: &SessionId,
+        messages: Vec<(ChainLink, Message)>,
+    ) -> Result<Vec<OrderedMessage>> {
+        let chains = self.chains.read();
+
+        let chain = chains.get(session_id)
+            .ok_or_else(|| ChainError::NotFound(session_id.to_string()))?;
+
+        let mut supplied = BTreeMap::new();
+        for (link, message) in messages {
+            let recorded = chain.get_link(link.sequence).ok_or_else(|| {
+                ChainError::VerificationFailed(format!(
+                    "link {} is not in the chain",
+                    link.sequence
+                ))
+            })?;
+            if recorded.link_type != ChainLinkType::Message || recorded.state != link.state {
+                return Err(ChainError::VerificationFailed(format!(
+                    "link {} does not match the chain",
+                    link.sequence
+                )));
+            }
+            if supplied.insert(link.sequence, message).is_some() {
+                return Err(ChainError::VerificationFailed(format!(
+                    "duplicate message for link {}",
+                    link.sequence
+                )));
+            }
+        }
+
+        let deleted: HashSet<&[u8; 32]> = chain.history()
+            .iter()
+            .filter(|link| link.link_type == ChainLinkType::Deletion)
+            .map(|link| &link.message_hash)
+            .collect();
+
+        let ordered: Vec<OrderedMessage> = chain.history()
+            .iter()
+            .filter(|link| {
+                link.link_type == ChainLinkType::Message && !deleted.contains(&link.message_hash)
+            })
+            .map(|link| match supplied.remove(&link.sequence) {
+                Some(message) => OrderedMessage::Delivered { sequence: link.sequence, message },
+                None => OrderedMessage::Gap { sequence: link.sequence },
+            })
+            .collect();
+
+        let gaps = ordered.iter().filter(|m| m.message().is_none()).count();
+        if gaps > 0 {
+            debug!("Ordered messages for {} with {} gaps", session_id, gaps);
+        }
+
+        Ok(ordered)
+    }
+
     /// Remove chain
     pub fn remove_chain(&self, session_id: &SessionId) -> bool {
         let removed = self.chains.write().remove(session_id).is_some();
@@ -255,6 +357,7 @@ impl Default for ChainManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use qiyashash_core::types::{DeviceId, UserId};
 
     #[test]
     fn test_create_chain() {
@@ -327,6 +430,79 @@ mod tests {
         assert_eq!(proof.sequence, 1);
     }
 
+    fn ordering_fixture(count: u8) -> (ChainManager, SessionId, Vec<(ChainLink, Message)>) {
+        let manager = ChainManager::new();
+        let session_id = SessionId::new();
+        manager.create_chain(&session_id, &[0x42u8; 32]);
+
+        let sender = UserId::new();
+        let recipient = UserId::new();
+        let messages = (0..count)
+            .map(|i| {
+                let link = manager.add_message(&session_id, &[i; 32]).unwrap();
+                let message =
+                    Message::text(sender.clone(), DeviceId::new(), recipient.clone(), i.to_string());
+                (link, message)
+            })
+            .collect();
+        (manager, session_id, messages)
+    }
+
+    fn contents(ordered: &[OrderedMessage]) -> Vec<Option<String>> {
+        ordered
+            .iter()
+            .map(|m| m.message().and_then(Message::content_as_string))
+            .collect()
+    }
+
+    #[test]
+    fn test_order_messages_restores_send_order() {
+        let (manager, session_id, messages) = ordering_fixture(5);
+        let mut shuffled = messages.clone();
+        shuffled.swap(0, 3);
+        shuffled.swap(1, 4);
+        shuffled.reverse();
+
+        let ordered = manager.order_messages(&session_id, shuffled).unwrap();
+        let expected: Vec<_> = (0..5).map(|i: u8| Some(i.to_string())).collect();
+        assert_eq!(contents(&ordered), expected);
+
+        let sequences: Vec<u64> = ordered.iter().map(OrderedMessage::sequence).collect();
+        let mut sorted = sequences.clone();
+        sorted.sort();
+        assert_eq!(sequences, sorted);
+    }
+
+    #[test]
+    fn test_order_messages_reports_gaps() {
+        let (manager, session_id, mut messages) = ordering_fixture(5);
+        let (missing, _) = messages.remove(2);
+        let (deleted, _) = messages.remove(3);
+        manager.add_deletion(&session_id, &deleted.message_hash).unwrap();
+        messages.reverse();
+
+        let ordered = manager.order_messages(&session_id, messages).unwrap();
+        assert_eq!(
+            contents(&ordered),
+            vec![Some("0".to_string()), Some("1".to_string()), None, Some("3".to_string())]
+        );
+        assert!(matches!(
+            ordered[2],
+            OrderedMessage::Gap { sequence } if sequence == missing.sequence
+        ));
+    }
+
+    #[test]
+    fn test_order_messages_rejects_foreign_links() {
+        let (manager, session_id, mut messages) = ordering_fixture(2);
+        messages[1].0.state = [0xff; 32];
+
+        assert!(matches!(
+            manager.order_messages(&session_id, messages),
+            Err(ChainError::VerificationFailed(_))
+        ));
+    }
+
     #[test]
     fn test_remove_chain() {
         let manager = ChainManager::new();
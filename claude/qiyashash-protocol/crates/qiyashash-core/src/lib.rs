@@ -19,10 +19,12 @@ pub mod types;
 pub mod user;
 
 pub use error::{Error, Result};
-pub use message::{Message, MessageEnvelope, MessageId, MessageStatus};
+pub use message::{
+    DeliveryFailure, Message, MessageEnvelope, MessageId, MessageReaction, MessageStatus, Reaction,
+};
 pub use session::{Session, SessionId, SessionState};
-pub use types::{DeviceId, Timestamp, UserId};
-pub use user::{User, UserProfile};
+pub use types::{DeviceId, MonotonicClock, Timestamp, UserId};
+pub use user::{ConversationSettings, User, UserProfile};
 
 /// Protocol version
 pub const PROTOCOL_VERSION: u32 = 1;
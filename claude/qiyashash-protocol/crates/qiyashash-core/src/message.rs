@@ -1,9 +1,15 @@
 //! Message types for QiyasHash protocol
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
+use qiyashash_crypto::keys::PublicKeyBytes;
+use qiyashash_crypto::wire::WireFrame;
+use qiyashash_crypto::x3dh::{X3DHHeader, SESSION_NONCE_SIZE};
+
 use crate::types::{ContentType, DeviceId, Timestamp, UserId};
 
 /// Unique message identifier
@@ -51,11 +57,77 @@ pub enum MessageStatus {
     /// Message read by recipient
     Read,
     /// Message failed to send
-    Failed,
+    Failed {
+        /// Why the last attempt failed
+        reason: DeliveryFailure,
+        /// Whether sending again could succeed
+        retryable: bool,
+        /// Number of failed attempts so far
+        attempts: u32,
+    },
     /// Message deleted
     Deleted,
 }
 
+impl MessageStatus {
+    /// Whether the message still needs to be sent
+    ///
+    /// True for new messages and for failures that may succeed on retry.
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Self::Pending | Self::Failed { retryable: true, .. })
+    }
+}
+
+/// Why a message could not be delivered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryFailure {
+    /// No network connection
+    NoNetwork,
+    /// Not enough relays reachable
+    RelayUnavailable,
+    /// The operation timed out
+    Timeout,
+    /// A relay asked us to slow down
+    RateLimited,
+    /// No session with the recipient yet
+    NoSession,
+    /// The recipient has blocked us
+    RecipientBlocked,
+    /// The recipient does not exist
+    RecipientNotFound,
+    /// The message exceeds the size limit
+    MessageTooLarge,
+    /// Refused for any other reason
+    Rejected,
+}
+
+impl DeliveryFailure {
+    /// Whether a later attempt could succeed without user action
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::NoNetwork | Self::RelayUnavailable | Self::Timeout | Self::RateLimited | Self::NoSession
+        )
+    }
+}
+
+impl fmt::Display for DeliveryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::NoNetwork => "no network connection",
+            Self::RelayUnavailable => "no relay available",
+            Self::Timeout => "timed out",
+            Self::RateLimited => "rate limited",
+            Self::NoSession => "no session with recipient",
+            Self::RecipientBlocked => "blocked by recipient",
+            Self::RecipientNotFound => "recipient not found",
+            Self::MessageTooLarge => "message too large",
+            Self::Rejected => "rejected",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
 impl Default for MessageStatus {
     fn default() -> Self {
         Self::Pending
@@ -87,6 +159,16 @@ pub struct Message {
     pub expires_at: Option<Timestamp>,
     /// Message status
     pub status: MessageStatus,
+    /// Reactions attached to this message
+    #[serde(default)]
+    pub reactions: Vec<MessageReaction>,
+    /// Set by the recipient when the sender's identity key changed and has
+    /// not been verified again
+    #[serde(default)]
+    pub unverified_sender: bool,
+    /// Recipient's device, for outgoing messages awaiting a retry
+    #[serde(default)]
+    pub recipient_device_id: Option<DeviceId>,
 }
 
 impl Message {
@@ -109,6 +191,86 @@ impl Message {
             created_at: Timestamp::now(),
             expires_at: None,
             status: MessageStatus::Pending,
+            reactions: Vec::new(),
+            unverified_sender: false,
+            recipient_device_id: None,
+        }
+    }
+
+    /// Create a reaction to another message
+    ///
+    /// Reactions travel through the ratchet like any message but are never
+    /// displayed on their own; the receiver attaches them to `reaction.target`.
+    pub fn reaction(
+        sender_id: UserId,
+        sender_device_id: DeviceId,
+        recipient_id: UserId,
+        reaction: &Reaction,
+    ) -> crate::Result<Self> {
+        reaction.validate()?;
+
+        let mut message = Self::text(sender_id, sender_device_id, recipient_id, "");
+        message.content_type = ContentType::Reaction;
+        message.content = bincode::serialize(reaction)?;
+        Ok(message)
+    }
+
+    /// The reaction this message carries, if it is one
+    pub fn as_reaction(&self) -> Option<crate::Result<Reaction>> {
+        if !matches!(self.content_type, ContentType::Reaction) {
+            return None;
+        }
+
+        let reaction = bincode::deserialize::<Reaction>(&self.content)
+            .map_err(crate::Error::from)
+            .and_then(|reaction| reaction.validate().map(|()| reaction));
+        Some(reaction)
+    }
+
+    /// Create a delivery or read receipt for a message from `recipient_id`
+    ///
+    /// Like reactions, receipts travel through the ratchet but are never
+    /// displayed; the receiver updates the status of `receipt.message_id`.
+    pub fn receipt(
+        sender_id: UserId,
+        sender_device_id: DeviceId,
+        recipient_id: UserId,
+        receipt: &MessageReceipt,
+    ) -> crate::Result<Self> {
+        let mut message = Self::text(sender_id, sender_device_id, recipient_id, "");
+        message.content_type = ContentType::Receipt;
+        message.content = bincode::serialize(receipt)?;
+        Ok(message)
+    }
+
+    /// The receipt this message carries, if it is one
+    pub fn as_receipt(&self) -> Option<crate::Result<MessageReceipt>> {
+        if !matches!(self.content_type, ContentType::Receipt) {
+            return None;
+        }
+
+        Some(bincode::deserialize(&self.content).map_err(crate::Error::from))
+    }
+
+    /// Whether this is a reaction or receipt, which only updates other
+    /// messages and is never stored on its own
+    pub fn is_control(&self) -> bool {
+        matches!(self.content_type, ContentType::Reaction | ContentType::Receipt)
+    }
+
+    /// Attach or clear `sender_id`'s reaction
+    ///
+    /// Each sender has at most one reaction per emoji.
+    pub fn apply_reaction(&mut self, sender_id: &UserId, reaction: &Reaction, at: Timestamp) {
+        self.reactions
+            .retain(|r| !(&r.sender_id == sender_id && r.emoji == reaction.emoji));
+
+        if !reaction.remove {
+            self.reactions.push(MessageReaction {
+                sender_id: sender_id.clone(),
+                emoji: reaction.emoji.clone(),
+                reacted_at: at,
+            });
         }
     }
 
@@ -123,9 +285,13 @@ impl Message {
 
     /// Set expiration for disappearing message
     pub fn with_expiration(mut self, duration_secs: i64) -> Self {
-        self.expires_at = Some(Timestamp::from_millis(
-            self.created_at.as_millis() + duration_secs * 1000,
-        ));
+        self.expires_at = Some(self.created_at.saturating_add_secs(duration_secs));
+        self
+    }
+
+    /// Address the message to one of the recipient's devices
+    pub fn with_recipient_device(mut self, device_id: DeviceId) -> Self {
+        self.recipient_device_id = Some(device_id);
         self
     }
 
@@ -141,6 +307,25 @@ impl Message {
         self
     }
 
+    /// Record a failed delivery attempt
+    ///
+    /// Counts attempts across failures; retryability follows `reason`.
+    pub fn mark_failed(&mut self, reason: DeliveryFailure) {
+        self.status = MessageStatus::Failed {
+            reason,
+            retryable: reason.is_retryable(),
+            attempts: self.delivery_attempts() + 1,
+        };
+    }
+
+    /// Number of failed delivery attempts
+    pub fn delivery_attempts(&self) -> u32 {
+        match self.status {
+            MessageStatus::Failed { attempts, .. } => attempts,
+            _ => 0,
+        }
+    }
+
     /// Check if message is expired
     pub fn is_expired(&self) -> bool {
         if let Some(expires_at) = self.expires_at {
@@ -161,6 +346,81 @@ impl Message {
     }
 }
 
+/// Content of a [`ContentType::Reaction`] message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    /// Message being reacted to
+    pub target: MessageId,
+    /// A single emoji
+    pub emoji: String,
+    /// Whether this withdraws an earlier reaction
+    pub remove: bool,
+}
+
+impl Reaction {
+    /// React to `target` with `emoji`
+    pub fn add(target: MessageId, emoji: impl Into<String>) -> Self {
+        Self {
+            target,
+            emoji: emoji.into(),
+            remove: false,
+        }
+    }
+
+    /// Withdraw a reaction to `target`
+    pub fn remove(target: MessageId, emoji: impl Into<String>) -> Self {
+        Self {
+            target,
+            emoji: emoji.into(),
+            remove: true,
+        }
+    }
+
+    /// Check that the emoji is exactly one grapheme
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.emoji.graphemes(true).count() != 1 {
+            return Err(crate::Error::InvalidMessage(format!(
+                "reaction must be a single grapheme, got {:?}",
+                self.emoji
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A reaction attached to a stored message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageReaction {
+    /// Who reacted
+    pub sender_id: UserId,
+    /// The emoji
+    pub emoji: String,
+    /// When the reaction arrived
+    pub reacted_at: Timestamp,
+}
+
+/// Authenticated plaintext that did not parse as a [`Message`]
+///
+/// Kept so it can be inspected, or parsed again after a protocol upgrade,
+/// since the ratchet key that decrypted it is already gone.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuarantinedPayload {
+    /// Quarantine entry ID
+    pub id: MessageId,
+    /// Sender's user ID
+    pub sender_id: UserId,
+    /// Sender's device ID
+    pub sender_device_id: DeviceId,
+    /// Protocol version of the envelope it arrived in
+    pub envelope_version: u32,
+    /// Decrypted bytes
+    pub plaintext: Vec<u8>,
+    /// Why parsing failed
+    pub error: String,
+    /// When it was quarantined
+    pub quarantined_at: Timestamp,
+}
+
 /// Attachment metadata
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Attachment {
@@ -194,12 +454,19 @@ pub struct Thumbnail {
     pub data: Vec<u8>,
 }
 
+/// `sender_identity_key` of a sealed-sender envelope
+pub const SEALED_SENDER_IDENTITY: [u8; 32] = [0u8; 32];
+
 /// Encrypted message envelope (wire format)
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MessageEnvelope {
     /// Protocol version
     pub version: u32,
     /// Sender's identity key (for X3DH)
+    ///
+    /// [`SEALED_SENDER_IDENTITY`] when the identity travels inside the
+    /// ciphertext instead. Initial messages always carry it, since the
+    /// recipient needs it to derive the session.
     #[serde(with = "hex::serde")]
     pub sender_identity_key: [u8; 32],
     /// Ephemeral key (for X3DH initial message)
@@ -218,6 +485,12 @@ pub struct MessageEnvelope {
     /// Timestamp hash (for metadata protection)
     #[serde(with = "hex::serde")]
     pub timestamp_hash: [u8; 32],
+    /// Initiator's X3DH session nonce (for X3DH initial message)
+    #[serde(default)]
+    pub session_nonce: Option<[u8; SESSION_NONCE_SIZE]>,
+    /// Signed prekey used (for X3DH initial message)
+    #[serde(default)]
+    pub signed_prekey_id: Option<u32>,
 }
 
 /// Wire format for ratchet header
@@ -233,14 +506,51 @@ pub struct RatchetHeaderWire {
 }
 
 impl MessageEnvelope {
-    /// Serialize to bytes
+    /// Whether the sender's identity is sealed inside the ciphertext
+    pub fn is_sealed_sender(&self) -> bool {
+        self.sender_identity_key == SEALED_SENDER_IDENTITY
+    }
+
+    /// Fingerprint every copy of this envelope from `sender` shares
+    ///
+    /// The same envelope arriving through several relays has the same key,
+    /// so a receiver can recognise copies it has already decrypted.
+    pub fn delivery_key(&self, sender: &UserId) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"QiyasHash_v1_DeliveryKey");
+        hasher.update((sender.as_str().len() as u64).to_be_bytes());
+        hasher.update(sender.as_str().as_bytes());
+        hasher.update(self.timestamp_hash);
+        hasher.update(Sha256::digest(&self.ciphertext));
+        hasher.finalize().into()
+    }
+
+    /// X3DH header of an initial message, sent until the recipient answers
+    pub fn x3dh_header(&self) -> Option<X3DHHeader> {
+        match (self.ephemeral_key, self.signed_prekey_id, self.session_nonce) {
+            (Some(ephemeral_key), Some(signed_prekey_id), Some(session_nonce))
+                if !self.is_sealed_sender() =>
+            {
+                Some(X3DHHeader {
+                    identity_key: PublicKeyBytes::from(self.sender_identity_key),
+                    ephemeral_key: PublicKeyBytes::from(ephemeral_key),
+                    one_time_prekey_id: self.one_time_prekey_id,
+                    signed_prekey_id,
+                    session_nonce,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Serialize to a versioned frame
     pub fn to_bytes(&self) -> crate::Result<Vec<u8>> {
-        bincode::serialize(self).map_err(Into::into)
+        WireFrame::encode(self).map_err(Into::into)
     }
 
-    /// Deserialize from bytes
+    /// Deserialize from a versioned frame
     pub fn from_bytes(bytes: &[u8]) -> crate::Result<Self> {
-        bincode::deserialize(bytes).map_err(Into::into)
+        WireFrame::decode(bytes).map_err(Into::into)
     }
 
     /// Serialize to JSON
@@ -336,6 +646,35 @@ mod tests {
         assert!(!msg.is_expired());
     }
 
+    #[test]
+    fn test_delivery_key() {
+        let sender = UserId::new();
+        let envelope = MessageEnvelope {
+            version: 1,
+            sender_identity_key: [0x42; 32],
+            ephemeral_key: None,
+            one_time_prekey_id: None,
+            ratchet_header: RatchetHeaderWire {
+                dh_public: [0x44; 32],
+                message_number: 1,
+                previous_chain_length: 0,
+            },
+            ciphertext: vec![0x01, 0x02, 0x03],
+            chain_proof: [0x45; 32],
+            timestamp_hash: [0x46; 32],
+            session_nonce: None,
+            signed_prekey_id: None,
+        };
+        let key = envelope.delivery_key(&sender);
+
+        assert_eq!(key, envelope.clone().delivery_key(&sender));
+        assert_ne!(key, envelope.delivery_key(&UserId::new()));
+        let other = MessageEnvelope { ciphertext: vec![0x04], ..envelope.clone() };
+        assert_ne!(key, other.delivery_key(&sender));
+        let other = MessageEnvelope { timestamp_hash: [0x47; 32], ..envelope };
+        assert_ne!(key, other.delivery_key(&sender));
+    }
+
     #[test]
     fn test_message_expiration() {
         let msg = Message::text(
@@ -367,6 +706,51 @@ mod tests {
         assert_eq!(msg.content, restored.content);
     }
 
+    #[test]
+    fn test_reaction_round_trip() {
+        let target = MessageId::new();
+        let message = Message::reaction(
+            UserId::new(),
+            DeviceId::new(),
+            UserId::new(),
+            &Reaction::add(target.clone(), "👍🏽"),
+        )
+        .unwrap();
+
+        let restored = Message::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        let reaction = restored.as_reaction().unwrap().unwrap();
+        assert_eq!(reaction, Reaction::add(target.clone(), "👍🏽"));
+
+        // Family emoji is one grapheme; two emoji or none are not
+        assert!(Reaction::add(target.clone(), "👨‍👩‍👧").validate().is_ok());
+        assert!(Reaction::add(target.clone(), "👍❤️").validate().is_err());
+        assert!(Reaction::add(target.clone(), "").validate().is_err());
+
+        let text = Message::text(UserId::new(), DeviceId::new(), UserId::new(), "hi");
+        assert!(text.as_reaction().is_none());
+    }
+
+    #[test]
+    fn test_receipt_round_trip() {
+        let receipt = MessageReceipt {
+            message_id: MessageId::new(),
+            receipt_type: ReceiptType::Read,
+            timestamp: Timestamp::from_secs(1_700_000_000),
+        };
+        let message = Message::receipt(UserId::new(), DeviceId::new(), UserId::new(), &receipt).unwrap();
+        assert!(message.is_control());
+        assert!(message.as_reaction().is_none());
+
+        let restored = Message::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        let opened = restored.as_receipt().unwrap().unwrap();
+        assert_eq!(opened.message_id, receipt.message_id);
+        assert_eq!(opened.receipt_type, ReceiptType::Read);
+
+        let text = Message::text(UserId::new(), DeviceId::new(), UserId::new(), "hi");
+        assert!(text.as_receipt().is_none());
+        assert!(!text.is_control());
+    }
+
     #[test]
     fn test_envelope_serialization() {
         let envelope = MessageEnvelope {
@@ -382,6 +766,8 @@ mod tests {
             ciphertext: vec![0x01, 0x02, 0x03],
             chain_proof: [0x45; 32],
             timestamp_hash: [0x46; 32],
+            session_nonce: Some([0x47; SESSION_NONCE_SIZE]),
+            signed_prekey_id: Some(2),
         };
 
         let json = envelope.to_json().unwrap();
@@ -389,5 +775,48 @@ mod tests {
 
         assert_eq!(envelope.version, restored.version);
         assert_eq!(envelope.ciphertext, restored.ciphertext);
+
+        // The X3DH header survives the binary wire format too
+        let restored = MessageEnvelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+        let header = restored.x3dh_header().unwrap();
+        assert_eq!(header.identity_key.0, [0x42; 32]);
+        assert_eq!(header.ephemeral_key.0, [0x43; 32]);
+        assert_eq!(header.one_time_prekey_id, Some(1));
+        assert_eq!(header.signed_prekey_id, 2);
+        assert_eq!(header.session_nonce, [0x47; SESSION_NONCE_SIZE]);
+    }
+
+    #[test]
+    fn test_envelope_framing_rejects_malformed_input() {
+        let envelope = MessageEnvelope {
+            version: 1,
+            sender_identity_key: [0x42; 32],
+            ephemeral_key: None,
+            one_time_prekey_id: None,
+            ratchet_header: RatchetHeaderWire {
+                dh_public: [0x44; 32],
+                message_number: 3,
+                previous_chain_length: 0,
+            },
+            ciphertext: vec![0x01, 0x02, 0x03],
+            chain_proof: [0x45; 32],
+            timestamp_hash: [0x46; 32],
+            session_nonce: None,
+            signed_prekey_id: None,
+        };
+
+        let bytes = envelope.to_bytes().unwrap();
+        let restored = MessageEnvelope::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.ratchet_header.message_number, 3);
+
+        for len in [0, 1, 4, bytes.len() / 2, bytes.len() - 1] {
+            assert!(MessageEnvelope::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut bumped = bytes;
+        bumped[0] += 1;
+        assert!(matches!(
+            MessageEnvelope::from_bytes(&bumped),
+            Err(crate::Error::Crypto(qiyashash_crypto::CryptoError::Serialization(_)))
+        ));
     }
 }
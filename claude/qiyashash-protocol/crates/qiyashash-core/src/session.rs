@@ -5,6 +5,7 @@
 
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::types::{DeviceId, Fingerprint, SafetyNumber, Timestamp, UserId};
@@ -107,7 +108,7 @@ pub struct Session {
     pub is_verified: bool,
     /// Session creation time
     pub created_at: Timestamp,
-    /// Last activity time
+    /// Last time a message was encrypted or decrypted
     pub last_activity_at: Timestamp,
     /// Message count
     pub message_count: u64,
@@ -116,6 +117,13 @@ pub struct Session {
     /// Ratchet state hash (for sync verification)
     #[serde(with = "hex::serde")]
     pub ratchet_state_hash: [u8; 32],
+    /// Protocol version negotiated at establishment
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u32,
+}
+
+fn default_protocol_version() -> u32 {
+    1
 }
 
 impl Session {
@@ -153,6 +161,7 @@ impl Session {
             message_count: 0,
             root_key_fingerprint,
             ratchet_state_hash: [0; 32],
+            protocol_version: default_protocol_version(),
         }
     }
 
@@ -169,8 +178,18 @@ impl Session {
 
     /// Increment message count
     pub fn increment_message_count(&mut self) {
+        self.record_message(Timestamp::now());
+    }
+
+    /// Count a message encrypted or decrypted at `now`
+    pub fn record_message(&mut self, now: Timestamp) {
         self.message_count += 1;
-        self.update_activity();
+        self.last_activity_at = now;
+    }
+
+    /// Time since the last message, as of `now`
+    pub fn idle_for(&self, now: Timestamp) -> Duration {
+        Duration::from_millis(now.millis_since(self.last_activity_at) as u64)
     }
 
     /// Mark as verified
@@ -330,6 +349,14 @@ mod tests {
 
         session.increment_message_count();
         assert_eq!(session.message_count, 1);
+
+        let at = session.last_activity_at.saturating_add_secs(60);
+        session.record_message(at);
+        assert_eq!(session.message_count, 2);
+        assert_eq!(session.idle_for(at), Duration::ZERO);
+        assert_eq!(session.idle_for(at.saturating_add_secs(90)), Duration::from_secs(90));
+        // A clock behind the last message reads as not idle
+        assert_eq!(session.idle_for(Timestamp::from_millis(0)), Duration::ZERO);
     }
 
     #[test]
@@ -4,12 +4,14 @@
 //! for different backends (RocksDB, SQLite, memory, etc.)
 
 use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
 
 use crate::error::Result;
-use crate::message::{Message, MessageId};
+use crate::message::{Message, MessageId, MessageStatus, QuarantinedPayload};
 use crate::session::{SessionId, SessionRecord};
 use crate::types::{DeviceId, UserId};
-use crate::user::{Contact, User};
+use crate::user::{Contact, ConversationSettings, User};
 
 /// Storage for user data
 #[async_trait]
@@ -43,6 +45,16 @@ pub trait UserStore: Send + Sync {
 
     /// Get blocked contacts
     async fn get_blocked_contacts(&self) -> Result<Vec<Contact>>;
+
+    /// Get notification settings for the conversation with a user
+    async fn get_conversation_settings(&self, user_id: &UserId) -> Result<Option<ConversationSettings>>;
+
+    /// Save notification settings for the conversation with a user
+    async fn set_conversation_settings(
+        &self,
+        user_id: &UserId,
+        settings: &ConversationSettings,
+    ) -> Result<()>;
 }
 
 /// Storage for session data
@@ -70,9 +82,18 @@ pub trait SessionStore: Send + Sync {
     /// Get all active sessions
     async fn get_active_sessions(&self) -> Result<Vec<SessionRecord>>;
 
+    /// Get every session that has not been closed, whatever its state
+    async fn get_open_sessions(&self) -> Result<Vec<SessionRecord>>;
+
     /// Get sessions needing re-key
     async fn get_sessions_needing_rekey(&self) -> Result<Vec<SessionRecord>>;
 
+    /// Remember the session nonce of an accepted X3DH initiation
+    ///
+    /// Returns `false` if the nonce was recorded before. Applied at once,
+    /// even inside a transaction, so a replay is caught by the next call.
+    async fn record_session_nonce(&self, nonce: &[u8; 16]) -> Result<bool>;
+
     /// Update ratchet state
     async fn update_ratchet_state(
         &self,
@@ -89,11 +110,29 @@ pub trait MessageStore: Send + Sync {
     async fn get_message(&self, message_id: &MessageId) -> Result<Option<Message>>;
 
     /// Save message
+    ///
+    /// Saving a message whose ID is already stored replaces it, so saving
+    /// the same message twice leaves one copy.
     async fn save_message(&self, message: &Message) -> Result<()>;
 
+    /// Save `messages` in a single write
+    ///
+    /// Either all of them are stored or, on error, none is. Transactional
+    /// backends use one transaction, so a bulk sync costs one round-trip
+    /// instead of one per message.
+    async fn save_messages(&self, messages: &[Message]) -> Result<()>;
+
     /// Delete message
     async fn delete_message(&self, message_id: &MessageId) -> Result<()>;
 
+    /// Message first received with delivery `key`, if any
+    ///
+    /// See [`MessageEnvelope::delivery_key`](crate::message::MessageEnvelope::delivery_key).
+    async fn get_delivery(&self, key: &[u8; 32]) -> Result<Option<MessageId>>;
+
+    /// Remember that `message_id` was received with delivery `key`
+    async fn record_delivery(&self, key: &[u8; 32], message_id: &MessageId) -> Result<()>;
+
     /// Get messages for conversation
     async fn get_messages_for_conversation(
         &self,
@@ -102,6 +141,22 @@ pub trait MessageStore: Send + Sync {
         before: Option<&MessageId>,
     ) -> Result<Vec<Message>>;
 
+    /// Stream a conversation newest first
+    ///
+    /// Messages are loaded as the stream is polled, so a caller that stops
+    /// early never reads the rest of the history.
+    fn stream_conversation<'a>(
+        &'a self,
+        other_user_id: &'a UserId,
+    ) -> BoxStream<'a, Result<Message>>;
+
+    /// Latest message and unread count of every conversation
+    ///
+    /// Built in a single pass over the history. `our_user_id` decides which
+    /// side of each message is the other party; expired messages are
+    /// neither returned as the latest nor counted as unread.
+    async fn get_conversation_summaries(&self, our_user_id: &UserId) -> Result<Vec<ConversationSummary>>;
+
     /// Get unread message count
     async fn get_unread_count(&self, other_user_id: &UserId) -> Result<usize>;
 
@@ -119,6 +174,15 @@ pub trait MessageStore: Send + Sync {
 
     /// Delete all messages for conversation
     async fn delete_conversation(&self, other_user_id: &UserId) -> Result<()>;
+
+    /// Keep a decrypted payload that could not be parsed
+    async fn quarantine_payload(&self, payload: &QuarantinedPayload) -> Result<()>;
+
+    /// Get all quarantined payloads, oldest first
+    async fn get_quarantined_payloads(&self) -> Result<Vec<QuarantinedPayload>>;
+
+    /// Delete a quarantined payload
+    async fn delete_quarantined_payload(&self, id: &MessageId) -> Result<()>;
 }
 
 /// Storage for identity keys
@@ -168,6 +232,12 @@ pub trait PreKeyStore: Send + Sync {
     async fn get_one_time_prekey_ids(&self) -> Result<Vec<u32>>;
 }
 
+/// Storage layout version written by this release
+///
+/// Backends record the version of the layout they hold and refuse to open
+/// anything newer, so data written by a future release is never misread.
+pub const SCHEMA_VERSION: u32 = 1;
+
 /// Combined storage interface
 #[async_trait]
 pub trait Storage:
@@ -190,6 +260,104 @@ pub trait Storage:
 
     /// Vacuum/compact storage
     async fn vacuum(&self) -> Result<()>;
+
+    /// Dump every record, e.g. for an account backup
+    async fn export_snapshot(&self) -> Result<StorageSnapshot>;
+
+    /// Write every record in `snapshot`, replacing records with the same key
+    async fn import_snapshot(&self, snapshot: StorageSnapshot) -> Result<()>;
+
+    /// Delete every record, keeping the schema version
+    async fn clear(&self) -> Result<()>;
+
+    /// Layout version of the stored data
+    async fn schema_version(&self) -> Result<u32>;
+
+    /// Upgrade the stored data to layout `version`
+    ///
+    /// Persistent backends back up the data before changing it. Fails for
+    /// versions newer than [`SCHEMA_VERSION`] or older than the current one.
+    async fn migrate_to(&self, version: u32) -> Result<()>;
+}
+
+/// Full contents of a store
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StorageSnapshot {
+    /// Known users
+    pub users: Vec<User>,
+    /// Contacts
+    pub contacts: Vec<Contact>,
+    /// Notification settings by conversation
+    #[serde(default)]
+    pub conversation_settings: Vec<(UserId, ConversationSettings)>,
+    /// Sessions with ratchet and chain state
+    pub sessions: Vec<SessionRecord>,
+    /// Session nonces of accepted X3DH initiations
+    #[serde(default)]
+    pub session_nonces: Vec<[u8; 16]>,
+    /// Message history
+    pub messages: Vec<Message>,
+    /// Delivery keys and the message first received with each
+    #[serde(default)]
+    pub deliveries: Vec<([u8; 32], MessageId)>,
+    /// Decrypted payloads that could not be parsed
+    #[serde(default)]
+    pub quarantine: Vec<QuarantinedPayload>,
+    /// Our identity key pair (encrypted)
+    pub identity_key: Option<Vec<u8>>,
+    /// Identity keys of other users
+    pub remote_identities: Vec<(UserId, [u8; 32])>,
+    /// Signed prekeys by ID
+    pub signed_prekeys: Vec<(u32, Vec<u8>)>,
+    /// One-time prekeys by ID
+    pub one_time_prekeys: Vec<(u32, Vec<u8>)>,
+}
+
+impl StorageSnapshot {
+    /// Whether the snapshot holds no records at all
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+            && self.contacts.is_empty()
+            && self.conversation_settings.is_empty()
+            && self.sessions.is_empty()
+            && self.session_nonces.is_empty()
+            && self.messages.is_empty()
+            && self.deliveries.is_empty()
+            && self.quarantine.is_empty()
+            && self.identity_key.is_none()
+            && self.remote_identities.is_empty()
+            && self.signed_prekeys.is_empty()
+            && self.one_time_prekeys.is_empty()
+    }
+}
+
+/// Latest state of one conversation
+#[derive(Clone, Debug)]
+pub struct ConversationSummary {
+    /// The other participant
+    pub other_user_id: UserId,
+    /// Newest message that has not expired
+    pub last_message: Option<Message>,
+    /// Received messages not yet read
+    pub unread_count: usize,
+}
+
+impl ConversationSummary {
+    /// Fold `message` into the summary
+    fn record(&mut self, message: &Message, our_user_id: &UserId) {
+        if message.is_expired() {
+            return;
+        }
+        if message.sender_id != *our_user_id && message.status != MessageStatus::Read {
+            self.unread_count += 1;
+        }
+        let newer = self.last_message.as_ref().map_or(true, |last| {
+            (message.created_at, message.id.as_str()) > (last.created_at, last.id.as_str())
+        });
+        if newer {
+            self.last_message = Some(message.clone());
+        }
+    }
 }
 
 /// Storage statistics
@@ -203,27 +371,60 @@ pub struct StorageStats {
     pub message_count: usize,
     /// Storage size in bytes
     pub storage_size_bytes: u64,
+    /// Bytes used by messages
+    pub message_bytes: u64,
+    /// Bytes used by sessions
+    pub session_bytes: u64,
+    /// Bytes used by signed and one-time prekeys
+    pub prekey_bytes: u64,
     /// One-time prekeys remaining
     pub prekey_count: usize,
+    /// Bytes freed by the most recent vacuum
+    pub reclaimed_bytes: u64,
 }
 
 /// In-memory storage for testing
 pub mod memory {
     use super::*;
-    use parking_lot::RwLock;
-    use std::collections::HashMap;
+    use crate::error::Error;
+    use futures::{future, stream, StreamExt};
+    use parking_lot::{Mutex, RwLock};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicU64, Ordering};
     use std::sync::Arc;
 
+    /// Write deferred until the surrounding transaction commits
+    type PendingWrite = Box<dyn FnOnce(&MemoryStorage) + Send>;
+
     /// In-memory storage implementation
+    ///
+    /// Transactions buffer writes: between `begin_transaction` and `commit`
+    /// reads see the last committed state, and `rollback` drops the buffer.
     pub struct MemoryStorage {
         users: RwLock<HashMap<String, User>>,
         contacts: RwLock<HashMap<String, Contact>>,
+        conversation_settings: RwLock<HashMap<String, ConversationSettings>>,
         sessions: RwLock<HashMap<String, SessionRecord>>,
+        /// Secondary index: their user ID -> session IDs
+        sessions_by_user: RwLock<HashMap<String, HashSet<String>>>,
         messages: RwLock<HashMap<String, Message>>,
+        /// Delivery key -> ID of the message first received with it
+        deliveries: RwLock<HashMap<[u8; 32], MessageId>>,
+        quarantine: RwLock<HashMap<String, QuarantinedPayload>>,
         identity_key: RwLock<Option<Vec<u8>>>,
         remote_identities: RwLock<HashMap<String, [u8; 32]>>,
         signed_prekeys: RwLock<HashMap<u32, Vec<u8>>>,
         one_time_prekeys: RwLock<HashMap<u32, Vec<u8>>>,
+        /// Session nonces of accepted X3DH initiations
+        session_nonces: RwLock<HashSet<[u8; 16]>>,
+        schema_version: RwLock<u32>,
+        /// Buffered writes of the open transaction, if any
+        pending: Mutex<Option<Vec<PendingWrite>>>,
+        /// Writes applied outside a transaction plus transactions committed
+        commits: AtomicU64,
+        /// Messages that may still be written before writes fail
+        #[cfg(any(test, feature = "testing"))]
+        message_write_budget: Mutex<Option<usize>>,
     }
 
     impl MemoryStorage {
@@ -232,27 +433,156 @@ pub mod memory {
             Arc::new(Self {
                 users: RwLock::new(HashMap::new()),
                 contacts: RwLock::new(HashMap::new()),
+                conversation_settings: RwLock::new(HashMap::new()),
                 sessions: RwLock::new(HashMap::new()),
+                sessions_by_user: RwLock::new(HashMap::new()),
                 messages: RwLock::new(HashMap::new()),
+                deliveries: RwLock::new(HashMap::new()),
+                quarantine: RwLock::new(HashMap::new()),
                 identity_key: RwLock::new(None),
                 remote_identities: RwLock::new(HashMap::new()),
                 signed_prekeys: RwLock::new(HashMap::new()),
                 one_time_prekeys: RwLock::new(HashMap::new()),
+                session_nonces: RwLock::new(HashSet::new()),
+                schema_version: RwLock::new(SCHEMA_VERSION),
+                pending: Mutex::new(None),
+                commits: AtomicU64::new(0),
+                #[cfg(any(test, feature = "testing"))]
+                message_write_budget: Mutex::new(None),
             })
         }
     }
 
+    impl MemoryStorage {
+        /// Number of writes applied outside a transaction plus commits
+        ///
+        /// Stands in for the commit count of a transactional backend.
+        pub fn commit_count(&self) -> u64 {
+            self.commits.load(Ordering::SeqCst)
+        }
+
+        /// Fail message writes once `count` more messages have been saved
+        ///
+        /// For testing callers against a backend that fails part-way.
+        #[cfg(any(test, feature = "testing"))]
+        pub fn fail_message_writes_after(&self, count: usize) {
+            *self.message_write_budget.lock() = Some(count);
+        }
+
+        /// Take up to `count` messages from the write budget; returns how
+        /// many may be written before the write fails
+        #[cfg(any(test, feature = "testing"))]
+        fn reserve_message_writes(&self, count: usize) -> usize {
+            match self.message_write_budget.lock().as_mut() {
+                Some(remaining) => {
+                    let granted = count.min(*remaining);
+                    *remaining -= granted;
+                    granted
+                }
+                None => count,
+            }
+        }
+
+        /// Without the `testing` feature every write is granted
+        #[cfg(not(any(test, feature = "testing")))]
+        fn reserve_message_writes(&self, count: usize) -> usize {
+            count
+        }
+
+        /// Run a write now, or buffer it while a transaction is open
+        fn write(&self, op: impl FnOnce(&MemoryStorage) + Send + 'static) {
+            // Held while applying so a concurrent commit cannot interleave
+            let mut pending = self.pending.lock();
+            match pending.as_mut() {
+                Some(ops) => ops.push(Box::new(op)),
+                None => {
+                    op(self);
+                    self.commits.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        fn insert_session(&self, session: SessionRecord) {
+            let session_id = session.session.id.as_str().to_string();
+            let user_id = session.session.their_user_id.as_str().to_string();
+
+            let mut sessions = self.sessions.write();
+            let mut index = self.sessions_by_user.write();
+
+            if let Some(existing) = sessions.get(&session_id) {
+                let previous_user = existing.session.their_user_id.as_str();
+                debug_assert_eq!(
+                    previous_user, user_id,
+                    "session {} changed its remote user",
+                    session_id
+                );
+                if previous_user != user_id {
+                    // Keep the index consistent even if the invariant is broken
+                    if let Some(ids) = index.get_mut(previous_user) {
+                        ids.remove(&session_id);
+                        if ids.is_empty() {
+                            index.remove(previous_user);
+                        }
+                    }
+                }
+            }
+
+            index.entry(user_id).or_default().insert(session_id.clone());
+            sessions.insert(session_id, session);
+        }
+
+        fn remove_session(&self, session_id: &str) {
+            let mut sessions = self.sessions.write();
+            let mut index = self.sessions_by_user.write();
+
+            if let Some(removed) = sessions.remove(session_id) {
+                let user_id = removed.session.their_user_id.as_str();
+                if let Some(ids) = index.get_mut(user_id) {
+                    ids.remove(session_id);
+                    if ids.is_empty() {
+                        index.remove(user_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Error for a write refused by [`MemoryStorage::fail_message_writes_after`]
+    fn injected_write_failure(written: usize, requested: usize) -> Error {
+        Error::Storage(format!(
+            "injected failure after {} of {} message writes",
+            written, requested
+        ))
+    }
+
+    /// Total bincode-encoded length of `items`
+    fn serialized_len<'a, T: serde::Serialize + 'a>(items: impl Iterator<Item = &'a T>) -> u64 {
+        items
+            .map(|item| bincode::serialized_size(item).unwrap_or(0))
+            .sum()
+    }
+
     impl Default for MemoryStorage {
         fn default() -> Self {
             Self {
                 users: RwLock::new(HashMap::new()),
                 contacts: RwLock::new(HashMap::new()),
+                conversation_settings: RwLock::new(HashMap::new()),
                 sessions: RwLock::new(HashMap::new()),
+                sessions_by_user: RwLock::new(HashMap::new()),
                 messages: RwLock::new(HashMap::new()),
+                deliveries: RwLock::new(HashMap::new()),
+                quarantine: RwLock::new(HashMap::new()),
                 identity_key: RwLock::new(None),
                 remote_identities: RwLock::new(HashMap::new()),
                 signed_prekeys: RwLock::new(HashMap::new()),
                 one_time_prekeys: RwLock::new(HashMap::new()),
+                session_nonces: RwLock::new(HashSet::new()),
+                schema_version: RwLock::new(SCHEMA_VERSION),
+                pending: Mutex::new(None),
+                commits: AtomicU64::new(0),
+                #[cfg(any(test, feature = "testing"))]
+                message_write_budget: Mutex::new(None),
             }
         }
     }
@@ -264,14 +594,18 @@ pub mod memory {
         }
 
         async fn save_user(&self, user: &User) -> Result<()> {
-            self.users
-                .write()
-                .insert(user.id.as_str().to_string(), user.clone());
+            let user = user.clone();
+            self.write(move |s| {
+                s.users.write().insert(user.id.as_str().to_string(), user);
+            });
             Ok(())
         }
 
         async fn delete_user(&self, user_id: &UserId) -> Result<()> {
-            self.users.write().remove(user_id.as_str());
+            let user_id = user_id.as_str().to_string();
+            self.write(move |s| {
+                s.users.write().remove(&user_id);
+            });
             Ok(())
         }
 
@@ -301,14 +635,20 @@ pub mod memory {
         }
 
         async fn save_contact(&self, contact: &Contact) -> Result<()> {
-            self.contacts
-                .write()
-                .insert(contact.user_id.as_str().to_string(), contact.clone());
+            let contact = contact.clone();
+            self.write(move |s| {
+                s.contacts
+                    .write()
+                    .insert(contact.user_id.as_str().to_string(), contact);
+            });
             Ok(())
         }
 
         async fn delete_contact(&self, user_id: &UserId) -> Result<()> {
-            self.contacts.write().remove(user_id.as_str());
+            let user_id = user_id.as_str().to_string();
+            self.write(move |s| {
+                s.contacts.write().remove(&user_id);
+            });
             Ok(())
         }
 
@@ -325,6 +665,26 @@ pub mod memory {
                 .cloned()
                 .collect())
         }
+
+        async fn get_conversation_settings(
+            &self,
+            user_id: &UserId,
+        ) -> Result<Option<ConversationSettings>> {
+            Ok(self.conversation_settings.read().get(user_id.as_str()).cloned())
+        }
+
+        async fn set_conversation_settings(
+            &self,
+            user_id: &UserId,
+            settings: &ConversationSettings,
+        ) -> Result<()> {
+            let user_id = user_id.as_str().to_string();
+            let settings = settings.clone();
+            self.write(move |s| {
+                s.conversation_settings.write().insert(user_id, settings);
+            });
+            Ok(())
+        }
     }
 
     #[async_trait]
@@ -338,46 +698,62 @@ pub mod memory {
             their_user_id: &UserId,
             their_device_id: &DeviceId,
         ) -> Result<Option<SessionRecord>> {
-            Ok(self
-                .sessions
-                .read()
-                .values()
-                .find(|s| {
-                    s.session.their_user_id == *their_user_id
-                        && s.session.their_device_id == *their_device_id
-                })
+            // Lock order: sessions before index, matching save/delete
+            let sessions = self.sessions.read();
+            let index = self.sessions_by_user.read();
+            let Some(ids) = index.get(their_user_id.as_str()) else {
+                return Ok(None);
+            };
+            Ok(ids
+                .iter()
+                .filter_map(|id| sessions.get(id))
+                .find(|s| s.session.their_device_id == *their_device_id)
                 .cloned())
         }
 
         async fn save_session(&self, session: &SessionRecord) -> Result<()> {
-            self.sessions
-                .write()
-                .insert(session.session.id.as_str().to_string(), session.clone());
+            let session = session.clone();
+            self.write(move |s| s.insert_session(session));
             Ok(())
         }
 
         async fn delete_session(&self, session_id: &SessionId) -> Result<()> {
-            self.sessions.write().remove(session_id.as_str());
+            let session_id = session_id.as_str().to_string();
+            self.write(move |s| s.remove_session(&session_id));
             Ok(())
         }
 
         async fn get_sessions_for_user(&self, their_user_id: &UserId) -> Result<Vec<SessionRecord>> {
+            let sessions = self.sessions.read();
+            let index = self.sessions_by_user.read();
+            let Some(ids) = index.get(their_user_id.as_str()) else {
+                return Ok(Vec::new());
+            };
+            Ok(ids
+                .iter()
+                .filter_map(|id| sessions.get(id))
+                .cloned()
+                .collect())
+        }
+
+        async fn get_active_sessions(&self) -> Result<Vec<SessionRecord>> {
+            use crate::session::SessionState;
             Ok(self
                 .sessions
                 .read()
                 .values()
-                .filter(|s| s.session.their_user_id == *their_user_id)
+                .filter(|s| s.session.state == SessionState::Active)
                 .cloned()
                 .collect())
         }
 
-        async fn get_active_sessions(&self) -> Result<Vec<SessionRecord>> {
+        async fn get_open_sessions(&self) -> Result<Vec<SessionRecord>> {
             use crate::session::SessionState;
             Ok(self
                 .sessions
                 .read()
                 .values()
-                .filter(|s| s.session.state == SessionState::Active)
+                .filter(|s| s.session.state != SessionState::Closed)
                 .cloned()
                 .collect())
         }
@@ -392,16 +768,23 @@ pub mod memory {
                 .collect())
         }
 
+        async fn record_session_nonce(&self, nonce: &[u8; 16]) -> Result<bool> {
+            Ok(self.session_nonces.write().insert(*nonce))
+        }
+
         async fn update_ratchet_state(
             &self,
             session_id: &SessionId,
             ratchet_state: Vec<u8>,
             chain_state: Vec<u8>,
         ) -> Result<()> {
-            if let Some(session) = self.sessions.write().get_mut(session_id.as_str()) {
-                session.ratchet_state = ratchet_state;
-                session.chain_state = chain_state;
-            }
+            let session_id = session_id.as_str().to_string();
+            self.write(move |s| {
+                if let Some(session) = s.sessions.write().get_mut(&session_id) {
+                    session.ratchet_state = ratchet_state;
+                    session.chain_state = chain_state;
+                }
+            });
             Ok(())
         }
     }
@@ -413,14 +796,65 @@ pub mod memory {
         }
 
         async fn save_message(&self, message: &Message) -> Result<()> {
-            self.messages
-                .write()
-                .insert(message.id.as_str().to_string(), message.clone());
+            if self.reserve_message_writes(1) == 0 {
+                return Err(injected_write_failure(0, 1));
+            }
+            let message = message.clone();
+            self.write(move |s| {
+                s.messages
+                    .write()
+                    .insert(message.id.as_str().to_string(), message);
+            });
+            Ok(())
+        }
+
+        async fn save_messages(&self, messages: &[Message]) -> Result<()> {
+            let granted = self.reserve_message_writes(messages.len());
+            let failed = granted < messages.len();
+            let messages_len = messages.len();
+            let messages = messages.to_vec();
+            // One write under one lock, so readers see all or none. A failure
+            // part-way undoes what the batch already wrote
+            self.write(move |s| {
+                let mut stored = s.messages.write();
+                let mut undo = Vec::with_capacity(granted);
+                for message in messages.into_iter().take(granted) {
+                    let id = message.id.as_str().to_string();
+                    let previous = stored.insert(id.clone(), message);
+                    undo.push((id, previous));
+                }
+                if failed {
+                    for (id, previous) in undo.into_iter().rev() {
+                        match previous {
+                            Some(previous) => stored.insert(id, previous),
+                            None => stored.remove(&id),
+                        };
+                    }
+                }
+            });
+            if failed {
+                return Err(injected_write_failure(granted, messages_len));
+            }
             Ok(())
         }
 
         async fn delete_message(&self, message_id: &MessageId) -> Result<()> {
-            self.messages.write().remove(message_id.as_str());
+            let message_id = message_id.as_str().to_string();
+            self.write(move |s| {
+                s.messages.write().remove(&message_id);
+            });
+            Ok(())
+        }
+
+        async fn get_delivery(&self, key: &[u8; 32]) -> Result<Option<MessageId>> {
+            Ok(self.deliveries.read().get(key).cloned())
+        }
+
+        async fn record_delivery(&self, key: &[u8; 32], message_id: &MessageId) -> Result<()> {
+            let (key, message_id) = (*key, message_id.clone());
+            self.write(move |s| {
+                s.deliveries.write().entry(key).or_insert(message_id);
+            });
             Ok(())
         }
 
@@ -439,17 +873,93 @@ pub mod memory {
                 })
                 .cloned()
                 .collect();
-            msgs.sort_by(|a, b| b.created_at.as_millis().cmp(&a.created_at.as_millis()));
+            msgs.sort_by(|a, b| {
+                b.created_at
+                    .as_millis()
+                    .cmp(&a.created_at.as_millis())
+                    .then_with(|| b.id.as_str().cmp(a.id.as_str()))
+            });
             msgs.truncate(limit);
             Ok(msgs)
         }
 
-        async fn get_unread_count(&self, _other_user_id: &UserId) -> Result<usize> {
-            Ok(0) // Simplified
+        fn stream_conversation<'a>(
+            &'a self,
+            other_user_id: &'a UserId,
+        ) -> BoxStream<'a, Result<Message>> {
+            // Only the ordering keys are collected up front; each message is
+            // cloned when the stream reaches it
+            let mut keys: Vec<(i64, String)> = self
+                .messages
+                .read()
+                .values()
+                .filter(|m| m.sender_id == *other_user_id || m.recipient_id == *other_user_id)
+                .map(|m| (m.created_at.as_millis(), m.id.as_str().to_string()))
+                .collect();
+            keys.sort_by(|a, b| b.cmp(a));
+
+            stream::iter(keys)
+                .filter_map(move |(_, id)| {
+                    future::ready(self.messages.read().get(&id).cloned().map(Ok))
+                })
+                .boxed()
+        }
+
+        async fn get_conversation_summaries(&self, our_user_id: &UserId) -> Result<Vec<ConversationSummary>> {
+            let mut summaries: HashMap<UserId, ConversationSummary> = HashMap::new();
+            for message in self.messages.read().values() {
+                let other = if message.sender_id == *our_user_id {
+                    &message.recipient_id
+                } else {
+                    &message.sender_id
+                };
+                summaries
+                    .entry(other.clone())
+                    .or_insert_with(|| ConversationSummary {
+                        other_user_id: other.clone(),
+                        last_message: None,
+                        unread_count: 0,
+                    })
+                    .record(message, our_user_id);
+            }
+
+            // Most recently active first
+            let mut summaries: Vec<_> = summaries.into_values().collect();
+            summaries.sort_by(|a, b| {
+                let at = |s: &ConversationSummary| s.last_message.as_ref().map(|m| m.created_at);
+                at(b).cmp(&at(a))
+            });
+            Ok(summaries)
+        }
+
+        async fn get_unread_count(&self, other_user_id: &UserId) -> Result<usize> {
+            Ok(self
+                .messages
+                .read()
+                .values()
+                .filter(|m| {
+                    m.sender_id == *other_user_id
+                        && m.status != MessageStatus::Read
+                        && !m.is_expired()
+                })
+                .count())
         }
 
-        async fn mark_as_read(&self, _other_user_id: &UserId, _until: &MessageId) -> Result<()> {
-            Ok(()) // Simplified
+        async fn mark_as_read(&self, other_user_id: &UserId, until: &MessageId) -> Result<()> {
+            let cutoff = match self.messages.read().get(until.as_str()) {
+                Some(message) => message.created_at,
+                None => return Ok(()),
+            };
+
+            let other_user_id = other_user_id.clone();
+            self.write(move |s| {
+                for message in s.messages.write().values_mut() {
+                    if message.sender_id == other_user_id && message.created_at <= cutoff {
+                        message.status = MessageStatus::Read;
+                    }
+                }
+            });
+            Ok(())
         }
 
         async fn search_messages(&self, query: &str, limit: usize) -> Result<Vec<Message>> {
@@ -470,12 +980,11 @@ pub mod memory {
         }
 
         async fn get_pending_messages(&self) -> Result<Vec<Message>> {
-            use crate::message::MessageStatus;
             Ok(self
                 .messages
                 .read()
                 .values()
-                .filter(|m| m.status == MessageStatus::Pending)
+                .filter(|m| m.status.is_pending())
                 .cloned()
                 .collect())
         }
@@ -491,8 +1000,35 @@ pub mod memory {
         }
 
         async fn delete_conversation(&self, other_user_id: &UserId) -> Result<()> {
-            self.messages.write().retain(|_, m| {
-                m.sender_id != *other_user_id && m.recipient_id != *other_user_id
+            let other_user_id = other_user_id.clone();
+            self.write(move |s| {
+                s.messages.write().retain(|_, m| {
+                    m.sender_id != other_user_id && m.recipient_id != other_user_id
+                });
+            });
+            Ok(())
+        }
+
+        async fn quarantine_payload(&self, payload: &QuarantinedPayload) -> Result<()> {
+            let payload = payload.clone();
+            self.write(move |s| {
+                s.quarantine
+                    .write()
+                    .insert(payload.id.as_str().to_string(), payload);
+            });
+            Ok(())
+        }
+
+        async fn get_quarantined_payloads(&self) -> Result<Vec<QuarantinedPayload>> {
+            let mut payloads: Vec<_> = self.quarantine.read().values().cloned().collect();
+            payloads.sort_by_key(|p| p.quarantined_at.as_millis());
+            Ok(payloads)
+        }
+
+        async fn delete_quarantined_payload(&self, id: &MessageId) -> Result<()> {
+            let id = id.as_str().to_string();
+            self.write(move |s| {
+                s.quarantine.write().remove(&id);
             });
             Ok(())
         }
@@ -505,7 +1041,7 @@ pub mod memory {
         }
 
         async fn save_identity_key(&self, encrypted_key: Vec<u8>) -> Result<()> {
-            *self.identity_key.write() = Some(encrypted_key);
+            self.write(move |s| *s.identity_key.write() = Some(encrypted_key));
             Ok(())
         }
 
@@ -518,9 +1054,10 @@ pub mod memory {
             user_id: &UserId,
             identity_key: [u8; 32],
         ) -> Result<()> {
-            self.remote_identities
-                .write()
-                .insert(user_id.as_str().to_string(), identity_key);
+            let user_id = user_id.as_str().to_string();
+            self.write(move |s| {
+                s.remote_identities.write().insert(user_id, identity_key);
+            });
             Ok(())
         }
 
@@ -545,12 +1082,16 @@ pub mod memory {
         }
 
         async fn save_signed_prekey(&self, id: u32, prekey: Vec<u8>) -> Result<()> {
-            self.signed_prekeys.write().insert(id, prekey);
+            self.write(move |s| {
+                s.signed_prekeys.write().insert(id, prekey);
+            });
             Ok(())
         }
 
         async fn delete_signed_prekey(&self, id: u32) -> Result<()> {
-            self.signed_prekeys.write().remove(&id);
+            self.write(move |s| {
+                s.signed_prekeys.write().remove(&id);
+            });
             Ok(())
         }
 
@@ -559,12 +1100,16 @@ pub mod memory {
         }
 
         async fn save_one_time_prekey(&self, id: u32, prekey: Vec<u8>) -> Result<()> {
-            self.one_time_prekeys.write().insert(id, prekey);
+            self.write(move |s| {
+                s.one_time_prekeys.write().insert(id, prekey);
+            });
             Ok(())
         }
 
         async fn delete_one_time_prekey(&self, id: u32) -> Result<()> {
-            self.one_time_prekeys.write().remove(&id);
+            self.write(move |s| {
+                s.one_time_prekeys.write().remove(&id);
+            });
             Ok(())
         }
 
@@ -580,15 +1125,35 @@ pub mod memory {
     #[async_trait]
     impl Storage for MemoryStorage {
         async fn begin_transaction(&self) -> Result<()> {
+            let mut pending = self.pending.lock();
+            if pending.is_some() {
+                return Err(Error::InvalidState(
+                    "transaction already in progress; nested transactions are not supported"
+                        .to_string(),
+                ));
+            }
+            *pending = Some(Vec::new());
             Ok(())
         }
 
         async fn commit(&self) -> Result<()> {
+            let mut pending = self.pending.lock();
+            let ops = pending
+                .take()
+                .ok_or_else(|| Error::InvalidState("commit without begin_transaction".to_string()))?;
+            for op in ops {
+                op(self);
+            }
+            self.commits.fetch_add(1, Ordering::SeqCst);
             Ok(())
         }
 
         async fn rollback(&self) -> Result<()> {
-            Ok(())
+            self.pending
+                .lock()
+                .take()
+                .map(drop)
+                .ok_or_else(|| Error::InvalidState("rollback without begin_transaction".to_string()))
         }
 
         async fn flush(&self) -> Result<()> {
@@ -596,17 +1161,631 @@ pub mod memory {
         }
 
         async fn get_stats(&self) -> Result<StorageStats> {
+            // Nothing is on disk, so estimate from the serialized lengths
+            let message_bytes = serialized_len(self.messages.read().values());
+            let session_bytes = serialized_len(self.sessions.read().values());
+            let prekey_bytes = self
+                .signed_prekeys
+                .read()
+                .values()
+                .chain(self.one_time_prekeys.read().values())
+                .map(|prekey| (std::mem::size_of::<u32>() + prekey.len()) as u64)
+                .sum::<u64>();
+            let other_bytes = serialized_len(self.users.read().values())
+                + serialized_len(self.contacts.read().values())
+                + self.identity_key.read().as_ref().map_or(0, |key| key.len() as u64)
+                + (self.remote_identities.read().len() * 32) as u64;
+
             Ok(StorageStats {
                 user_count: self.users.read().len(),
                 session_count: self.sessions.read().len(),
                 message_count: self.messages.read().len(),
-                storage_size_bytes: 0,
+                storage_size_bytes: message_bytes + session_bytes + prekey_bytes + other_bytes,
+                message_bytes,
+                session_bytes,
+                prekey_bytes,
                 prekey_count: self.one_time_prekeys.read().len(),
+                reclaimed_bytes: 0,
             })
         }
 
         async fn vacuum(&self) -> Result<()> {
+            // Nothing to compact; just drop messages past their expiry
+            self.write(|s| s.messages.write().retain(|_, m| !m.is_expired()));
             Ok(())
         }
+
+        async fn export_snapshot(&self) -> Result<StorageSnapshot> {
+            Ok(StorageSnapshot {
+                users: self.users.read().values().cloned().collect(),
+                contacts: self.contacts.read().values().cloned().collect(),
+                conversation_settings: self
+                    .conversation_settings
+                    .read()
+                    .iter()
+                    .map(|(id, settings)| (UserId::from_string(id.clone()), settings.clone()))
+                    .collect(),
+                sessions: self.sessions.read().values().cloned().collect(),
+                session_nonces: self.session_nonces.read().iter().copied().collect(),
+                messages: self.messages.read().values().cloned().collect(),
+                deliveries: self
+                    .deliveries
+                    .read()
+                    .iter()
+                    .map(|(key, id)| (*key, id.clone()))
+                    .collect(),
+                quarantine: self.quarantine.read().values().cloned().collect(),
+                identity_key: self.identity_key.read().clone(),
+                remote_identities: self
+                    .remote_identities
+                    .read()
+                    .iter()
+                    .map(|(id, key)| (UserId::from_string(id.clone()), *key))
+                    .collect(),
+                signed_prekeys: self
+                    .signed_prekeys
+                    .read()
+                    .iter()
+                    .map(|(id, prekey)| (*id, prekey.clone()))
+                    .collect(),
+                one_time_prekeys: self
+                    .one_time_prekeys
+                    .read()
+                    .iter()
+                    .map(|(id, prekey)| (*id, prekey.clone()))
+                    .collect(),
+            })
+        }
+
+        async fn import_snapshot(&self, snapshot: StorageSnapshot) -> Result<()> {
+            // Through the regular writers so an open transaction buffers it all
+            for user in &snapshot.users {
+                self.save_user(user).await?;
+            }
+            for contact in &snapshot.contacts {
+                self.save_contact(contact).await?;
+            }
+            for (user_id, settings) in &snapshot.conversation_settings {
+                self.set_conversation_settings(user_id, settings).await?;
+            }
+            for session in &snapshot.sessions {
+                self.save_session(session).await?;
+            }
+            for nonce in &snapshot.session_nonces {
+                self.record_session_nonce(nonce).await?;
+            }
+            self.save_messages(&snapshot.messages).await?;
+            for (key, message_id) in &snapshot.deliveries {
+                self.record_delivery(key, message_id).await?;
+            }
+            for payload in &snapshot.quarantine {
+                self.quarantine_payload(payload).await?;
+            }
+            if let Some(identity_key) = snapshot.identity_key {
+                self.save_identity_key(identity_key).await?;
+            }
+            for (user_id, identity_key) in snapshot.remote_identities {
+                self.save_remote_identity(&user_id, identity_key).await?;
+            }
+            for (id, prekey) in snapshot.signed_prekeys {
+                self.save_signed_prekey(id, prekey).await?;
+            }
+            for (id, prekey) in snapshot.one_time_prekeys {
+                self.save_one_time_prekey(id, prekey).await?;
+            }
+            Ok(())
+        }
+
+        async fn clear(&self) -> Result<()> {
+            self.write(|s| {
+                s.users.write().clear();
+                s.contacts.write().clear();
+                s.conversation_settings.write().clear();
+                s.sessions.write().clear();
+                s.sessions_by_user.write().clear();
+                s.messages.write().clear();
+                s.deliveries.write().clear();
+                s.quarantine.write().clear();
+                *s.identity_key.write() = None;
+                s.remote_identities.write().clear();
+                s.signed_prekeys.write().clear();
+                s.one_time_prekeys.write().clear();
+                s.session_nonces.write().clear();
+            });
+            Ok(())
+        }
+
+        async fn schema_version(&self) -> Result<u32> {
+            Ok(*self.schema_version.read())
+        }
+
+        async fn migrate_to(&self, version: u32) -> Result<()> {
+            // Nothing is persisted, so there is nothing to back up or rewrite
+            let mut current = self.schema_version.write();
+            check_migration(*current, version)?;
+            *current = version;
+            Ok(())
+        }
+    }
+}
+
+/// Check that a store at layout `from` can be migrated to `to`
+pub fn check_migration(from: u32, to: u32) -> Result<()> {
+    if to > SCHEMA_VERSION {
+        return Err(crate::error::Error::Storage(format!(
+            "schema version {} is newer than the supported version {}",
+            to, SCHEMA_VERSION
+        )));
+    }
+    if to < from {
+        return Err(crate::error::Error::Storage(format!(
+            "cannot migrate schema version {} down to {}",
+            from, to
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryStorage;
+    use super::*;
+    use crate::session::Session;
+    use crate::types::Fingerprint;
+    use futures::StreamExt;
+
+    fn record(our: &UserId, their: &UserId, their_device: &DeviceId) -> SessionRecord {
+        SessionRecord {
+            session: Session::new(
+                our.clone(),
+                DeviceId::from_string("our-device"),
+                their.clone(),
+                their_device.clone(),
+                Fingerprint::from_bytes([0x01; 32]),
+                Fingerprint::from_bytes([0x02; 32]),
+                Fingerprint::from_bytes([0x03; 32]),
+            ),
+            ratchet_state: Vec::new(),
+            chain_state: Vec::new(),
+        }
+    }
+
+    fn sorted_ids(records: &[SessionRecord]) -> Vec<String> {
+        let mut ids: Vec<_> = records
+            .iter()
+            .map(|r| r.session.id.as_str().to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_session_index_matches_scan() {
+        let storage = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let users: Vec<_> = (0..5)
+            .map(|i| UserId::from_string(format!("user-{}", i)))
+            .collect();
+
+        let mut all = Vec::new();
+        for user in &users {
+            for d in 0..3 {
+                let rec = record(&me, user, &DeviceId::from_string(format!("device-{}", d)));
+                storage.save_session(&rec).await.unwrap();
+                all.push(rec);
+            }
+        }
+
+        for user in &users {
+            let indexed = storage.get_sessions_for_user(user).await.unwrap();
+            let scanned: Vec<_> = all
+                .iter()
+                .filter(|r| r.session.their_user_id == *user)
+                .cloned()
+                .collect();
+            assert_eq!(sorted_ids(&indexed), sorted_ids(&scanned));
+
+            for d in 0..3 {
+                let device = DeviceId::from_string(format!("device-{}", d));
+                let found = storage
+                    .get_session_by_user_device(user, &device)
+                    .await
+                    .unwrap()
+                    .expect("session should be indexed");
+                let expected = all
+                    .iter()
+                    .find(|r| {
+                        r.session.their_user_id == *user && r.session.their_device_id == device
+                    })
+                    .unwrap();
+                assert_eq!(found.session.id, expected.session.id);
+            }
+        }
+
+        let unknown = UserId::from_string("nobody");
+        assert!(storage.get_sessions_for_user(&unknown).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_index_updated_on_delete() {
+        let storage = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let bob = UserId::from_string("bob");
+        let d1 = DeviceId::from_string("d1");
+        let d2 = DeviceId::from_string("d2");
+
+        let r1 = record(&me, &bob, &d1);
+        let r2 = record(&me, &bob, &d2);
+        storage.save_session(&r1).await.unwrap();
+        storage.save_session(&r2).await.unwrap();
+        // Re-saving must not duplicate index entries
+        storage.save_session(&r1).await.unwrap();
+        assert_eq!(storage.get_sessions_for_user(&bob).await.unwrap().len(), 2);
+
+        storage.delete_session(&r1.session.id).await.unwrap();
+        let remaining = storage.get_sessions_for_user(&bob).await.unwrap();
+        assert_eq!(sorted_ids(&remaining), sorted_ids(&[r2.clone()]));
+        assert!(storage
+            .get_session_by_user_device(&bob, &d1)
+            .await
+            .unwrap()
+            .is_none());
+
+        storage.delete_session(&r2.session.id).await.unwrap();
+        assert!(storage.get_sessions_for_user(&bob).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_writes_invisible_until_commit() {
+        let storage = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let bob = UserId::from_string("bob");
+        let rec = record(&me, &bob, &DeviceId::from_string("d1"));
+
+        storage.begin_transaction().await.unwrap();
+        storage.save_session(&rec).await.unwrap();
+        storage.save_one_time_prekey(1, vec![0xAA]).await.unwrap();
+        assert!(storage.get_session(&rec.session.id).await.unwrap().is_none());
+        assert_eq!(storage.get_one_time_prekey_count().await.unwrap(), 0);
+
+        storage.commit().await.unwrap();
+        assert!(storage.get_session(&rec.session.id).await.unwrap().is_some());
+        assert_eq!(storage.get_sessions_for_user(&bob).await.unwrap().len(), 1);
+        assert_eq!(storage.get_one_time_prekey(1).await.unwrap(), Some(vec![0xAA]));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rollback_reverts_writes() {
+        let storage = MemoryStorage::new();
+        storage.save_one_time_prekey(1, vec![0x01]).await.unwrap();
+
+        storage.begin_transaction().await.unwrap();
+        storage.delete_one_time_prekey(1).await.unwrap();
+        storage.save_one_time_prekey(2, vec![0x02]).await.unwrap();
+        storage.save_identity_key(vec![0x03]).await.unwrap();
+        storage.rollback().await.unwrap();
+
+        assert_eq!(storage.get_one_time_prekey_ids().await.unwrap(), vec![1]);
+        assert!(storage.get_identity_key().await.unwrap().is_none());
+
+        // Writes outside a transaction apply immediately again
+        storage.save_one_time_prekey(3, vec![0x03]).await.unwrap();
+        assert_eq!(storage.get_one_time_prekey_count().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_misuse_is_an_error() {
+        let storage = MemoryStorage::new();
+        assert!(storage.commit().await.is_err());
+        assert!(storage.rollback().await.is_err());
+
+        storage.begin_transaction().await.unwrap();
+        assert!(storage.begin_transaction().await.is_err());
+        // The failed nested begin leaves the outer transaction usable
+        storage.save_one_time_prekey(1, vec![0x01]).await.unwrap();
+        storage.commit().await.unwrap();
+        assert_eq!(storage.get_one_time_prekey_count().await.unwrap(), 1);
+        assert!(storage.commit().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stats_estimate_message_size() {
+        let storage = MemoryStorage::new();
+        let alice = UserId::from_string("alice");
+        let bob = UserId::from_string("bob");
+
+        let empty = storage.get_stats().await.unwrap();
+        assert_eq!(empty.storage_size_bytes, 0);
+
+        let body = "x".repeat(1024);
+        for _ in 0..10 {
+            let msg = Message::text(
+                alice.clone(),
+                DeviceId::from_string("d1"),
+                bob.clone(),
+                body.as_str(),
+            );
+            storage.save_message(&msg).await.unwrap();
+        }
+        storage.save_one_time_prekey(1, vec![0; 32]).await.unwrap();
+
+        let stats = storage.get_stats().await.unwrap();
+        assert_eq!(stats.message_count, 10);
+        // Content dominates; per-message envelope overhead stays modest
+        assert!(stats.message_bytes >= 10 * 1024);
+        assert!(stats.message_bytes < 10 * 2048);
+        assert_eq!(stats.prekey_bytes, 36);
+        assert_eq!(stats.session_bytes, 0);
+        assert_eq!(stats.storage_size_bytes, stats.message_bytes + stats.prekey_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_stream_conversation_matches_bulk_order() {
+        let storage = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let bob = UserId::from_string("bob");
+        let carol = UserId::from_string("carol");
+        let device = DeviceId::from_string("d1");
+
+        for i in 0..20 {
+            let msg = Message::text(me.clone(), device.clone(), bob.clone(), format!("hi {}", i));
+            storage.save_message(&msg).await.unwrap();
+            let other = Message::text(me.clone(), device.clone(), carol.clone(), "other");
+            storage.save_message(&other).await.unwrap();
+        }
+
+        let bulk = storage.get_messages_for_conversation(&bob, usize::MAX, None).await.unwrap();
+        let streamed: Vec<_> = storage
+            .stream_conversation(&bob)
+            .map(|m| m.unwrap().id)
+            .collect()
+            .await;
+        let bulk_ids: Vec<_> = bulk.into_iter().map(|m| m.id).collect();
+        assert_eq!(streamed, bulk_ids);
+        assert_eq!(streamed.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_stream_conversation_loads_lazily() {
+        let storage = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let bob = UserId::from_string("bob");
+        let device = DeviceId::from_string("d1");
+
+        for i in 0..5 {
+            let msg = Message::text(me.clone(), device.clone(), bob.clone(), format!("hi {}", i));
+            storage.save_message(&msg).await.unwrap();
+        }
+        let newest_first = storage.get_messages_for_conversation(&bob, 5, None).await.unwrap();
+
+        let mut stream = storage.stream_conversation(&bob);
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.id, newest_first[0].id);
+
+        // Messages the stream has not reached yet are read only when polled
+        storage.delete_message(&newest_first[1].id).await.unwrap();
+        let rest: Vec<_> = stream.map(|m| m.unwrap().id).collect().await;
+        let expected: Vec<_> = newest_first[2..].iter().map(|m| m.id.clone()).collect();
+        assert_eq!(rest, expected);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let source = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let bob = UserId::from_string("bob");
+        let device = DeviceId::from_string("d1");
+
+        let rec = record(&me, &bob, &device);
+        let msg = Message::text(me.clone(), device.clone(), bob.clone(), "hello");
+        source.save_session(&rec).await.unwrap();
+        source.save_message(&msg).await.unwrap();
+        source.save_identity_key(vec![0x01; 32]).await.unwrap();
+        source.save_remote_identity(&bob, [0x02; 32]).await.unwrap();
+        source.save_signed_prekey(1, vec![0x03]).await.unwrap();
+        source.save_one_time_prekey(2, vec![0x04]).await.unwrap();
+
+        let target = MemoryStorage::new();
+        assert!(target.export_snapshot().await.unwrap().is_empty());
+        target
+            .import_snapshot(source.export_snapshot().await.unwrap())
+            .await
+            .unwrap();
+
+        assert!(target.get_message(&msg.id).await.unwrap().is_some());
+        assert_eq!(target.get_sessions_for_user(&bob).await.unwrap().len(), 1);
+        assert_eq!(target.get_identity_key().await.unwrap(), Some(vec![0x01; 32]));
+        assert_eq!(target.get_remote_identity(&bob).await.unwrap(), Some([0x02; 32]));
+        assert_eq!(target.get_signed_prekey(1).await.unwrap(), Some(vec![0x03]));
+        assert_eq!(target.get_one_time_prekey(2).await.unwrap(), Some(vec![0x04]));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restores_every_record_type() {
+        use crate::types::Timestamp;
+        use crate::user::UserProfile;
+
+        let storage = MemoryStorage::new();
+        let me = UserId::from_string("me");
+        let bob = UserId::from_string("bob");
+        let device = DeviceId::from_string("d1");
+
+        let user = User::new(Fingerprint::from_bytes([0x05; 32]), UserProfile::with_name("Bob"));
+        let mut settings = ConversationSettings::default();
+        settings.mute();
+        let msg = Message::text(me.clone(), device.clone(), bob.clone(), "hello");
+        let payload = QuarantinedPayload {
+            id: MessageId::new(),
+            sender_id: bob.clone(),
+            sender_device_id: device.clone(),
+            envelope_version: 1,
+            plaintext: vec![0xff],
+            error: "bad payload".to_string(),
+            quarantined_at: Timestamp::now(),
+        };
+        storage.save_user(&user).await.unwrap();
+        storage.save_contact(&Contact::new(bob.clone())).await.unwrap();
+        storage.set_conversation_settings(&bob, &settings).await.unwrap();
+        storage.save_session(&record(&me, &bob, &device)).await.unwrap();
+        assert!(storage.record_session_nonce(&[0x06; 16]).await.unwrap());
+        storage.save_message(&msg).await.unwrap();
+        storage.record_delivery(&[0x07; 32], &msg.id).await.unwrap();
+        storage.quarantine_payload(&payload).await.unwrap();
+        storage.save_identity_key(vec![0x01; 32]).await.unwrap();
+        storage.save_remote_identity(&bob, [0x02; 32]).await.unwrap();
+        storage.save_signed_prekey(1, vec![0x03]).await.unwrap();
+        storage.save_one_time_prekey(2, vec![0x04]).await.unwrap();
+
+        let snapshot = storage.export_snapshot().await.unwrap();
+        storage.clear().await.unwrap();
+        assert!(storage.export_snapshot().await.unwrap().is_empty());
+        storage.import_snapshot(snapshot).await.unwrap();
+
+        assert!(storage.get_user(&user.id).await.unwrap().is_some());
+        assert!(storage.get_contact(&bob).await.unwrap().is_some());
+        assert_eq!(
+            storage.get_conversation_settings(&bob).await.unwrap(),
+            Some(settings)
+        );
+        assert_eq!(storage.get_sessions_for_user(&bob).await.unwrap().len(), 1);
+        // A restored nonce still catches a replayed initiation
+        assert!(!storage.record_session_nonce(&[0x06; 16]).await.unwrap());
+        assert!(storage.get_message(&msg.id).await.unwrap().is_some());
+        assert_eq!(storage.get_delivery(&[0x07; 32]).await.unwrap(), Some(msg.id.clone()));
+        let quarantined = storage.get_quarantined_payloads().await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].id, payload.id);
+        assert_eq!(storage.get_identity_key().await.unwrap(), Some(vec![0x01; 32]));
+        assert_eq!(storage.get_remote_identity(&bob).await.unwrap(), Some([0x02; 32]));
+        assert_eq!(storage.get_signed_prekey(1).await.unwrap(), Some(vec![0x03]));
+        assert_eq!(storage.get_one_time_prekey(2).await.unwrap(), Some(vec![0x04]));
+    }
+
+    #[tokio::test]
+    async fn test_pending_excludes_permanent_failures() {
+        use crate::message::{DeliveryFailure, MessageStatus};
+
+        let storage = MemoryStorage::new();
+        let new_message = || {
+            Message::text(
+                UserId::from_string("me"),
+                DeviceId::from_string("my-device"),
+                UserId::from_string("them"),
+                "hello",
+            )
+        };
+
+        let mut transient = new_message();
+        transient.mark_failed(DeliveryFailure::NoNetwork);
+        transient.mark_failed(DeliveryFailure::Timeout);
+        assert_eq!(
+            transient.status,
+            MessageStatus::Failed {
+                reason: DeliveryFailure::Timeout,
+                retryable: true,
+                attempts: 2,
+            }
+        );
+
+        let mut permanent = new_message();
+        permanent.mark_failed(DeliveryFailure::RecipientBlocked);
+        assert!(!permanent.status.is_pending());
+
+        let fresh = new_message();
+        for message in [&transient, &permanent, &fresh] {
+            storage.save_message(message).await.unwrap();
+        }
+
+        let mut pending: Vec<_> = storage
+            .get_pending_messages()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        pending.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        let mut expected = vec![transient.id.clone(), fresh.id.clone()];
+        expected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(pending, expected);
+    }
+
+    fn conversation(count: usize) -> Vec<Message> {
+        let alice = UserId::from_string("alice");
+        let bob = UserId::from_string("bob");
+        (0..count)
+            .map(|i| {
+                Message::text(
+                    alice.clone(),
+                    DeviceId::from_string("d1"),
+                    bob.clone(),
+                    format!("message {}", i).as_str(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_save_messages_commits_once() {
+        let storage = MemoryStorage::new();
+        let messages = conversation(500);
+
+        storage.save_messages(&messages).await.unwrap();
+        assert_eq!(storage.commit_count(), 1);
+        assert_eq!(storage.get_stats().await.unwrap().message_count, 500);
+
+        // One at a time is one commit each
+        let more = conversation(3);
+        for message in &more {
+            storage.save_message(message).await.unwrap();
+        }
+        assert_eq!(storage.commit_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_resaving_message_keeps_one_copy() {
+        let storage = MemoryStorage::new();
+        let bob = UserId::new();
+        let message = Message::text(UserId::new(), DeviceId::new(), bob.clone(), "once");
+
+        storage.save_message(&message).await.unwrap();
+        storage.save_message(&message).await.unwrap();
+        storage.save_messages(&[message.clone()]).await.unwrap();
+
+        assert_eq!(storage.get_stats().await.unwrap().message_count, 1);
+        let stored = storage.get_messages_for_conversation(&bob, 10, None).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, message.id);
+    }
+
+    #[tokio::test]
+    async fn test_save_messages_failure_stores_none() {
+        let storage = MemoryStorage::new();
+        let mut earlier = conversation(1).remove(0);
+        storage.save_message(&earlier).await.unwrap();
+        storage.fail_message_writes_after(250);
+
+        // The batch overwrites a stored message, then fails part-way; both
+        // the new messages and the overwrite are undone
+        let mut batch = vec![earlier.clone()];
+        batch[0].status = MessageStatus::Read;
+        batch.extend(conversation(499));
+        assert!(storage.save_messages(&batch).await.is_err());
+        assert_eq!(storage.get_stats().await.unwrap().message_count, 1);
+        let stored = storage.get_message(&earlier.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, earlier.status);
+        earlier.status = MessageStatus::Read;
+        storage.fail_message_writes_after(250);
+
+        // Inside a caller's transaction, earlier writes roll back with it
+        let commits = storage.commit_count();
+        storage.begin_transaction().await.unwrap();
+        storage.save_message(&earlier).await.unwrap();
+        storage.save_messages(&conversation(200)).await.unwrap();
+        assert!(storage.save_messages(&conversation(100)).await.is_err());
+        storage.rollback().await.unwrap();
+        assert_eq!(storage.get_stats().await.unwrap().message_count, 1);
+        let stored = storage.get_message(&earlier.id).await.unwrap().unwrap();
+        assert_ne!(stored.status, MessageStatus::Read);
+        assert_eq!(storage.commit_count(), commits);
     }
 }
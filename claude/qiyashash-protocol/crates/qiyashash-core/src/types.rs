@@ -2,6 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
 use uuid::Uuid;
 
 /// User identifier
@@ -87,14 +88,67 @@ impl fmt::Display for DeviceId {
     }
 }
 
+/// Process-wide clock backing [`Timestamp::now`]
+static PROCESS_CLOCK: MonotonicClock = MonotonicClock::new();
+
+/// Clock that never issues a value lower than one it already issued
+///
+/// Wall-clock time can jump backwards (NTP adjustments, manual changes).
+/// Chain proofs and message ordering assume timestamps never decrease, so
+/// readings are clamped to the last issued value until the wall clock
+/// catches up again.
+#[derive(Debug)]
+pub struct MonotonicClock {
+    last_issued: AtomicI64,
+}
+
+impl MonotonicClock {
+    /// Create a clock that has not issued any timestamp yet
+    pub const fn new() -> Self {
+        Self {
+            last_issued: AtomicI64::new(i64::MIN),
+        }
+    }
+
+    /// Issue a timestamp from the current wall-clock time
+    pub fn now(&self) -> Timestamp {
+        self.issue(chrono::Utc::now().timestamp_millis())
+    }
+
+    /// Issue a timestamp for a wall-clock reading in milliseconds
+    ///
+    /// Returns `wall_millis` unless it is lower than a previously issued
+    /// value, in which case the previous value is returned again.
+    pub fn issue(&self, wall_millis: i64) -> Timestamp {
+        let previous = self.last_issued.fetch_max(wall_millis, Ordering::AcqRel);
+        Timestamp(previous.max(wall_millis))
+    }
+
+    /// Last issued timestamp, if any
+    pub fn last_issued(&self) -> Option<Timestamp> {
+        match self.last_issued.load(Ordering::Acquire) {
+            i64::MIN => None,
+            millis => Some(Timestamp(millis)),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Timestamp in milliseconds since Unix epoch
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Timestamp(i64);
 
 impl Timestamp {
     /// Create timestamp for current time
+    ///
+    /// Non-decreasing within a process, see [`MonotonicClock`].
     pub fn now() -> Self {
-        Self(chrono::Utc::now().timestamp_millis())
+        PROCESS_CLOCK.now()
     }
 
     /// Create from milliseconds
@@ -102,9 +156,9 @@ impl Timestamp {
         Self(millis)
     }
 
-    /// Create from seconds
+    /// Create from seconds (saturates on overflow)
     pub fn from_secs(secs: i64) -> Self {
-        Self(secs * 1000)
+        Self(secs.saturating_mul(1000))
     }
 
     /// Get as milliseconds
@@ -117,6 +171,16 @@ impl Timestamp {
         self.0 / 1000
     }
 
+    /// Add seconds (saturates on overflow)
+    pub fn saturating_add_secs(&self, secs: i64) -> Self {
+        Self(self.0.saturating_add(secs.saturating_mul(1000)))
+    }
+
+    /// Milliseconds elapsed since `earlier` (zero if `earlier` is later)
+    pub fn millis_since(&self, earlier: Timestamp) -> i64 {
+        self.0.saturating_sub(earlier.0).max(0)
+    }
+
     /// Get as chrono DateTime
     pub fn as_datetime(&self) -> chrono::DateTime<chrono::Utc> {
         chrono::DateTime::from_timestamp_millis(self.0)
@@ -125,8 +189,7 @@ impl Timestamp {
 
     /// Check if expired (older than duration)
     pub fn is_expired(&self, duration_secs: i64) -> bool {
-        let now = Self::now();
-        now.0 - self.0 > duration_secs * 1000
+        Self::now().millis_since(*self) > duration_secs.saturating_mul(1000)
     }
 }
 
@@ -264,6 +327,8 @@ pub enum ContentType {
     Reaction,
     /// System message
     System,
+    /// Delivery or read receipt
+    Receipt,
 }
 
 impl Default for ContentType {
@@ -295,6 +360,41 @@ mod tests {
         assert_eq!(ts2.as_millis(), 1_000_000);
     }
 
+    #[test]
+    fn test_timestamp_overflow() {
+        assert_eq!(Timestamp::from_secs(i64::MAX).as_millis(), i64::MAX);
+        assert_eq!(Timestamp::from_secs(i64::MIN).as_millis(), i64::MIN);
+
+        let ts = Timestamp::from_millis(i64::MAX - 10);
+        assert_eq!(ts.saturating_add_secs(1).as_millis(), i64::MAX);
+        assert!(!Timestamp::from_millis(i64::MAX).is_expired(0));
+        assert!(Timestamp::from_millis(i64::MIN).is_expired(1_000));
+    }
+
+    #[test]
+    fn test_monotonic_clock_backwards_jump() {
+        let clock = MonotonicClock::new();
+        assert!(clock.last_issued().is_none());
+
+        assert_eq!(clock.issue(10_000).as_millis(), 10_000);
+        // Wall clock jumps back five seconds
+        assert_eq!(clock.issue(5_000).as_millis(), 10_000);
+        assert_eq!(clock.issue(9_999).as_millis(), 10_000);
+        // Wall clock catches up again
+        assert_eq!(clock.issue(10_500).as_millis(), 10_500);
+        assert_eq!(clock.last_issued(), Some(Timestamp::from_millis(10_500)));
+    }
+
+    #[test]
+    fn test_timestamp_now_monotonic() {
+        let mut previous = Timestamp::now();
+        for _ in 0..1000 {
+            let next = Timestamp::now();
+            assert!(next >= previous);
+            previous = next;
+        }
+    }
+
     #[test]
     fn test_fingerprint() {
         let bytes = [0x42u8; 32];
@@ -264,10 +264,81 @@ impl Contact {
     }
 }
 
+/// Per-conversation notification preferences
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationSettings {
+    /// Muted
+    pub muted: bool,
+    /// When a temporary mute ends (`None` mutes indefinitely)
+    pub mute_until: Option<Timestamp>,
+    /// Custom notification sound
+    pub notification_sound: Option<String>,
+    /// Show message previews in notifications
+    pub show_previews: bool,
+}
+
+impl Default for ConversationSettings {
+    fn default() -> Self {
+        Self {
+            muted: false,
+            mute_until: None,
+            notification_sound: None,
+            show_previews: true,
+        }
+    }
+}
+
+impl ConversationSettings {
+    /// Mute indefinitely
+    pub fn mute(&mut self) {
+        self.muted = true;
+        self.mute_until = None;
+    }
+
+    /// Mute until `until`
+    pub fn mute_until(&mut self, until: Timestamp) {
+        self.muted = true;
+        self.mute_until = Some(until);
+    }
+
+    /// Unmute
+    pub fn unmute(&mut self) {
+        self.muted = false;
+        self.mute_until = None;
+    }
+
+    /// Whether the conversation is muted at `now`
+    ///
+    /// A temporary mute lapses on its own once `mute_until` has passed.
+    pub fn is_muted_at(&self, now: Timestamp) -> bool {
+        match self.mute_until {
+            Some(until) => self.muted && now < until,
+            None => self.muted,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_conversation_mute_expires() {
+        let now = Timestamp::from_secs(1_700_000_000);
+        let mut settings = ConversationSettings::default();
+        assert!(!settings.is_muted_at(now));
+
+        settings.mute();
+        assert!(settings.is_muted_at(now.saturating_add_secs(365 * 24 * 3600)));
+
+        settings.mute_until(now.saturating_add_secs(3600));
+        assert!(settings.is_muted_at(now));
+        assert!(!settings.is_muted_at(now.saturating_add_secs(3600)));
+
+        settings.unmute();
+        assert!(!settings.is_muted_at(now));
+    }
+
     #[test]
     fn test_user_creation() {
         let fingerprint = Fingerprint::from_bytes([0x42; 32]);
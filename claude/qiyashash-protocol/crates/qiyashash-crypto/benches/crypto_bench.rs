@@ -1,13 +1,16 @@
 //! Benchmarks for QiyasHash cryptographic operations
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
 use qiyashash_crypto::{
     aead::{Aead, AeadAlgorithm, AeadKey},
     identity::{Identity, IdentityKeyPair},
     keys::EphemeralKeyPair,
     kdf::{ChainRatchet, KeyDerivationContext, derive_message_keys},
-    ratchet::DoubleRatchet,
+    ratchet::{DoubleRatchet, RatchetMessage, RatchetState},
     x3dh::{PreKeyManager, X3DHKeyAgreement},
+    MAX_CHAIN_LENGTH,
 };
 use rand::rngs::OsRng;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
@@ -76,6 +79,7 @@ fn bench_aead(c: &mut Criterion) {
 
     for size in [64, 256, 1024, 4096, 16384].iter() {
         let plaintext = vec![0x42u8; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
 
         group.bench_with_input(
             BenchmarkId::new("xchacha_encrypt", size),
@@ -97,6 +101,7 @@ fn bench_aead(c: &mut Criterion) {
     }
 
     // Decryption benchmarks
+    group.throughput(Throughput::Bytes(1024));
     let plaintext = vec![0x42u8; 1024];
     let cipher_xchacha = Aead::new();
     let cipher_aes = Aead::with_algorithm(AeadAlgorithm::Aes256Gcm);
@@ -118,6 +123,35 @@ fn bench_aead(c: &mut Criterion) {
 fn bench_x3dh(c: &mut Criterion) {
     let mut group = c.benchmark_group("X3DH");
 
+    // Both sides of a session with a one-time prekey, as on first contact
+    group.bench_function("establish", |b| {
+        let alice = IdentityKeyPair::generate();
+        let alice_public = alice.public_key();
+
+        b.iter_batched(
+            || {
+                let mut bob_prekeys = PreKeyManager::new(IdentityKeyPair::generate());
+                bob_prekeys.generate_one_time_prekeys(1);
+                bob_prekeys
+            },
+            |mut bob_prekeys| {
+                let bundle = bob_prekeys.get_bundle();
+                let (alice_secret, ephemeral, opk_id) =
+                    X3DHKeyAgreement::initiate(&alice, &bundle).unwrap();
+                let bob_secret = X3DHKeyAgreement::respond(
+                    &mut bob_prekeys,
+                    &alice_public,
+                    &ephemeral,
+                    opk_id,
+                    alice_secret.session_nonce(),
+                )
+                .unwrap();
+                black_box((alice_secret, bob_secret))
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
     group.bench_function("initiate", |b| {
         let alice = IdentityKeyPair::generate();
         let bob = IdentityKeyPair::generate();
@@ -135,7 +169,8 @@ fn bench_x3dh(c: &mut Criterion) {
         bob_prekeys.generate_one_time_prekeys(100);
         let bundle = bob_prekeys.get_bundle();
 
-        let (_, ephemeral, opk_id) = X3DHKeyAgreement::initiate(&alice, &bundle).unwrap();
+        let (alice_secret, ephemeral, opk_id) = X3DHKeyAgreement::initiate(&alice, &bundle).unwrap();
+        let session_nonce = *alice_secret.session_nonce();
         let alice_public = alice.public_key();
 
         b.iter(|| {
@@ -150,6 +185,7 @@ fn bench_x3dh(c: &mut Criterion) {
                     &alice_public,
                     &ephemeral,
                     None, // Skip OPK to avoid consumption issues
+                    &session_nonce,
                 )
                 .unwrap(),
             )
@@ -159,6 +195,51 @@ fn bench_x3dh(c: &mut Criterion) {
     group.finish();
 }
 
+/// Encrypt on `sender`, re-keying before the chain reaches its length limit
+///
+/// Criterion runs far more iterations than one chain allows.
+fn ratchet_encrypt(sender: &mut RatchetState, plaintext: &[u8]) -> RatchetMessage {
+    if sender.sending_chain_length() >= MAX_CHAIN_LENGTH {
+        sender.rekey_sending_chain().unwrap();
+    }
+    sender.encrypt(plaintext).unwrap()
+}
+
+fn bench_ratchet_state(c: &mut Criterion) {
+    let mut group = c.benchmark_group("RatchetState");
+
+    let shared_secret = [0x42u8; 32];
+    let ratchet_pair = || {
+        let bob_secret = X25519StaticSecret::random_from_rng(OsRng);
+        let bob_public = X25519PublicKey::from(&bob_secret);
+        (
+            RatchetState::init_alice(&shared_secret, &bob_public).unwrap(),
+            RatchetState::init_bob(&shared_secret, bob_secret).unwrap(),
+        )
+    };
+
+    for size in [64, 1024, 16384].iter() {
+        let plaintext = vec![0x42u8; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encrypt", size), size, |b, _| {
+            let (mut alice, _bob) = ratchet_pair();
+            b.iter(|| black_box(ratchet_encrypt(&mut alice, &plaintext)))
+        });
+
+        group.bench_with_input(BenchmarkId::new("decrypt", size), size, |b, _| {
+            let (mut alice, mut bob) = ratchet_pair();
+            b.iter_batched(
+                || ratchet_encrypt(&mut alice, &plaintext),
+                |message| black_box(bob.decrypt(&message).unwrap()),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
 fn bench_double_ratchet(c: &mut Criterion) {
     let mut group = c.benchmark_group("Double Ratchet");
 
@@ -172,12 +253,17 @@ fn bench_double_ratchet(c: &mut Criterion) {
         let mut alice = DoubleRatchet::new_initiator(&shared_secret, &bob_public, session_id).unwrap();
         let plaintext = b"Hello, QiyasHash!";
 
-        b.iter(|| black_box(alice.encrypt(plaintext).unwrap()))
+        b.iter(|| {
+            if alice.sending_chain_length() >= MAX_CHAIN_LENGTH {
+                alice.rekey_sending_chain().unwrap();
+            }
+            black_box(alice.encrypt(plaintext).unwrap())
+        })
     });
 
     group.bench_function("decrypt", |b| {
         let mut alice = DoubleRatchet::new_initiator(&shared_secret, &bob_public, session_id).unwrap();
-        let mut bob = DoubleRatchet::new_responder(&shared_secret, bob_secret.clone(), session_id);
+        let mut bob = DoubleRatchet::new_responder(&shared_secret, bob_secret.clone(), session_id).unwrap();
         
         let plaintext = b"Hello, QiyasHash!";
         let encrypted = alice.encrypt(plaintext).unwrap();
@@ -188,7 +274,7 @@ fn bench_double_ratchet(c: &mut Criterion) {
                 &shared_secret,
                 X25519StaticSecret::from(bob_secret.to_bytes()),
                 session_id,
-            );
+            ).unwrap();
             black_box(fresh_bob.decrypt(&encrypted).unwrap())
         })
     });
@@ -199,7 +285,7 @@ fn bench_double_ratchet(c: &mut Criterion) {
             let bob_public = X25519PublicKey::from(&bob_secret);
             
             let mut alice = DoubleRatchet::new_initiator(&shared_secret, &bob_public, session_id).unwrap();
-            let mut bob = DoubleRatchet::new_responder(&shared_secret, bob_secret, session_id);
+            let mut bob = DoubleRatchet::new_responder(&shared_secret, bob_secret, session_id).unwrap();
             
             let encrypted = alice.encrypt(b"Hello").unwrap();
             let decrypted = bob.decrypt(&encrypted).unwrap();
@@ -248,6 +334,7 @@ criterion_group!(
     bench_kdf,
     bench_aead,
     bench_x3dh,
+    bench_ratchet_state,
     bench_double_ratchet,
     bench_signing,
     bench_identity_rotation,
@@ -2,7 +2,13 @@
 //!
 //! Provides both ChaCha20-Poly1305 and AES-256-GCM for message encryption.
 //! ChaCha20-Poly1305 is preferred for software implementations while
-//! AES-256-GCM may be faster on hardware with AES-NI support.
+//! AES-256-GCM may be faster on hardware with AES-NI support;
+//! [`AeadAlgorithm::detect_fastest`] measures which one wins on this machine.
+//!
+//! Random 96-bit AES-GCM nonces hit birthday collisions after roughly 2^32
+//! messages under one key. [`AeadAlgorithm::Aes256GcmCounter`] instead
+//! derives the nonce from a message counter that must never repeat under a
+//! key; [`CounterNonces`] enforces that.
 
 use aes_gcm::{
     aead::{Aead as AeadTrait, KeyInit},
@@ -11,9 +17,14 @@ use aes_gcm::{
 use chacha20poly1305::XChaCha20Poly1305;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use crate::error::{CryptoError, Result};
+use crate::kdf::{compute_auth_tag, constant_time_eq};
+use crate::rng::secure_rng;
 use crate::MAX_MESSAGE_SIZE;
 
 /// Nonce size for XChaCha20-Poly1305 (192 bits)
@@ -63,14 +74,21 @@ impl Nonce {
     /// Generate a random XChaCha20 nonce
     pub fn random_xchacha() -> Self {
         let mut nonce = [0u8; XCHACHA_NONCE_SIZE];
-        rand::thread_rng().fill_bytes(&mut nonce);
+        secure_rng().fill_bytes(&mut nonce);
         Self::XChaCha(nonce)
     }
 
     /// Generate a random AES-GCM nonce
     pub fn random_aes_gcm() -> Self {
         let mut nonce = [0u8; AES_GCM_NONCE_SIZE];
-        rand::thread_rng().fill_bytes(&mut nonce);
+        secure_rng().fill_bytes(&mut nonce);
+        Self::AesGcm(nonce)
+    }
+
+    /// Deterministic AES-GCM nonce: 4 zero bytes then the big-endian counter
+    pub fn counter_aes_gcm(counter: u64) -> Self {
+        let mut nonce = [0u8; AES_GCM_NONCE_SIZE];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
         Self::AesGcm(nonce)
     }
 
@@ -90,6 +108,11 @@ pub enum AeadAlgorithm {
     XChaCha20Poly1305,
     /// AES-256-GCM (faster with hardware support)
     Aes256Gcm,
+    /// AES-256-GCM with nonces derived from a per-key message counter
+    ///
+    /// Only safe while the counter never repeats under the same key; use
+    /// [`Aead::encrypt_with_counter`] so [`CounterNonces`] can enforce it.
+    Aes256GcmCounter,
 }
 
 impl Default for AeadAlgorithm {
@@ -98,6 +121,94 @@ impl Default for AeadAlgorithm {
     }
 }
 
+/// Plaintext size encrypted per benchmark round
+const BENCHMARK_CHUNK_SIZE: usize = 4096;
+
+/// Timed rounds per algorithm
+const BENCHMARK_ROUNDS: usize = 16;
+
+/// Winner of the self-benchmark, measured on first use
+static FASTEST: OnceLock<AeadAlgorithm> = OnceLock::new();
+
+/// Number of times the self-benchmark has run
+static BENCHMARK_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+impl AeadAlgorithm {
+    /// The faster of XChaCha20-Poly1305 and AES-256-GCM on this machine
+    ///
+    /// The first call encrypts a few KB with each and keeps the one with the
+    /// higher throughput; later calls, from any thread, return that result.
+    /// Ties go to XChaCha20-Poly1305. Counter-nonce AES-GCM is never picked
+    /// because it needs a caller-managed counter.
+    pub fn detect_fastest() -> Self {
+        *FASTEST.get_or_init(|| {
+            BENCHMARK_RUNS.fetch_add(1, Ordering::Relaxed);
+
+            let xchacha = Self::benchmark(Self::XChaCha20Poly1305);
+            let aes = Self::benchmark(Self::Aes256Gcm);
+            if aes < xchacha {
+                Self::Aes256Gcm
+            } else {
+                Self::XChaCha20Poly1305
+            }
+        })
+    }
+
+    /// Time spent encrypting `BENCHMARK_ROUNDS` chunks with `algorithm`
+    fn benchmark(algorithm: Self) -> Duration {
+        let cipher = Aead::with_algorithm(algorithm);
+        let key = AeadKey::from_bytes([0x42; KEY_SIZE]);
+        let chunk = [0u8; BENCHMARK_CHUNK_SIZE];
+
+        // Warm up so one-time setup is not counted against either side
+        let _ = cipher.encrypt(&key, &chunk, &[]);
+
+        let start = Instant::now();
+        for _ in 0..BENCHMARK_ROUNDS {
+            let _ = std::hint::black_box(cipher.encrypt(&key, &chunk, &[]));
+        }
+        start.elapsed()
+    }
+}
+
+/// Counters already used for encryption under one key
+///
+/// Counters must strictly increase, which rules out reuse without
+/// remembering every value; `u64::MAX` is never issued. Create a fresh
+/// instance whenever the key changes.
+#[derive(Clone, Debug, Default)]
+pub struct CounterNonces {
+    next: u64,
+}
+
+impl CounterNonces {
+    /// Start at counter 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve `counter`, failing if it (or a later one) was already used
+    pub fn claim(&mut self, counter: u64) -> Result<Nonce> {
+        if counter < self.next {
+            return Err(CryptoError::NonceReuse { counter });
+        }
+        self.next = counter
+            .checked_add(1)
+            .ok_or(CryptoError::NonceReuse { counter })?;
+        Ok(Nonce::counter_aes_gcm(counter))
+    }
+
+    /// Resume with `next` as the lowest counter still available
+    pub(crate) fn starting_at(next: u64) -> Self {
+        Self { next }
+    }
+
+    /// Lowest counter still available
+    pub(crate) fn next(&self) -> u64 {
+        self.next
+    }
+}
+
 /// Encrypted payload with metadata
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EncryptedPayload {
@@ -153,9 +264,50 @@ impl Aead {
         }
 
         match self.algorithm {
-            AeadAlgorithm::XChaCha20Poly1305 => self.encrypt_xchacha(key, plaintext, aad),
-            AeadAlgorithm::Aes256Gcm => self.encrypt_aes_gcm(key, plaintext, aad),
+            AeadAlgorithm::XChaCha20Poly1305 => {
+                self.encrypt_xchacha(key, Nonce::random_xchacha(), plaintext, aad)
+            }
+            AeadAlgorithm::Aes256Gcm => self.encrypt_aes_gcm(
+                key,
+                Nonce::random_aes_gcm(),
+                AeadAlgorithm::Aes256Gcm,
+                plaintext,
+                aad,
+            ),
+            AeadAlgorithm::Aes256GcmCounter => Err(CryptoError::EncryptionFailed(
+                "AES-256-GCM counter mode needs encrypt_with_counter".to_string(),
+            )),
+        }
+    }
+
+    /// Encrypt in counter mode using `counter` as the nonce
+    ///
+    /// `nonces` must belong to `key`; a counter that is not greater than
+    /// every previously claimed one is rejected with
+    /// [`CryptoError::NonceReuse`].
+    pub fn encrypt_with_counter(
+        &self,
+        key: &AeadKey,
+        nonces: &mut CounterNonces,
+        counter: u64,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<EncryptedPayload> {
+        if self.algorithm != AeadAlgorithm::Aes256GcmCounter {
+            return Err(CryptoError::EncryptionFailed(format!(
+                "{:?} does not use counter nonces",
+                self.algorithm
+            )));
         }
+        if plaintext.len() > MAX_MESSAGE_SIZE {
+            return Err(CryptoError::MessageTooLarge {
+                size: plaintext.len(),
+                max: MAX_MESSAGE_SIZE,
+            });
+        }
+
+        let nonce = nonces.claim(counter)?;
+        self.encrypt_aes_gcm(key, nonce, AeadAlgorithm::Aes256GcmCounter, plaintext, aad)
     }
 
     /// Decrypt ciphertext with associated data
@@ -175,20 +327,51 @@ impl Aead {
     ) -> Result<Vec<u8>> {
         match payload.algorithm {
             AeadAlgorithm::XChaCha20Poly1305 => self.decrypt_xchacha(key, payload, aad),
-            AeadAlgorithm::Aes256Gcm => self.decrypt_aes_gcm(key, payload, aad),
+            AeadAlgorithm::Aes256Gcm | AeadAlgorithm::Aes256GcmCounter => {
+                self.decrypt_aes_gcm(key, payload, aad)
+            }
+        }
+    }
+
+    /// Encrypt with a caller-chosen nonce, which must match the algorithm
+    fn encrypt_with_nonce(
+        &self,
+        key: &AeadKey,
+        nonce: Nonce,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<EncryptedPayload> {
+        if plaintext.len() > MAX_MESSAGE_SIZE {
+            return Err(CryptoError::MessageTooLarge {
+                size: plaintext.len(),
+                max: MAX_MESSAGE_SIZE,
+            });
+        }
+
+        match (self.algorithm, &nonce) {
+            (AeadAlgorithm::XChaCha20Poly1305, Nonce::XChaCha(_)) => {
+                self.encrypt_xchacha(key, nonce, plaintext, aad)
+            }
+            (AeadAlgorithm::Aes256Gcm, Nonce::AesGcm(_)) => {
+                self.encrypt_aes_gcm(key, nonce, AeadAlgorithm::Aes256Gcm, plaintext, aad)
+            }
+            (algorithm, _) => Err(CryptoError::EncryptionFailed(format!(
+                "{:?} cannot use this nonce",
+                algorithm
+            ))),
         }
     }
 
     fn encrypt_xchacha(
         &self,
         key: &AeadKey,
+        nonce: Nonce,
         plaintext: &[u8],
         aad: &[u8],
     ) -> Result<EncryptedPayload> {
         use chacha20poly1305::aead::Payload;
 
         let cipher = XChaCha20Poly1305::new(key.as_bytes().into());
-        let nonce = Nonce::random_xchacha();
 
         let nonce_bytes = match &nonce {
             Nonce::XChaCha(n) => n,
@@ -241,13 +424,14 @@ impl Aead {
     fn encrypt_aes_gcm(
         &self,
         key: &AeadKey,
+        nonce: Nonce,
+        algorithm: AeadAlgorithm,
         plaintext: &[u8],
         aad: &[u8],
     ) -> Result<EncryptedPayload> {
         use aes_gcm::aead::Payload;
 
         let cipher = Aes256Gcm::new(key.as_bytes().into());
-        let nonce = Nonce::random_aes_gcm();
 
         let nonce_bytes = match &nonce {
             Nonce::AesGcm(n) => n,
@@ -265,7 +449,7 @@ impl Aead {
             .map_err(|_| CryptoError::EncryptionFailed("AES-256-GCM failed".to_string()))?;
 
         Ok(EncryptedPayload {
-            algorithm: AeadAlgorithm::Aes256Gcm,
+            algorithm,
             nonce,
             ciphertext,
         })
@@ -304,30 +488,96 @@ impl Default for Aead {
     }
 }
 
-/// Encrypt-then-MAC construction for header encryption
+/// Label deriving the synthetic-nonce key from a header key
+const HEADER_NONCE_LABEL: &[u8] = b"QiyasHash_HeaderNonce";
+
+/// Header encryption
 ///
-/// Used when we need deterministic encryption for headers
+/// By default each header gets a random nonce. In deterministic mode the
+/// nonce is instead an HMAC of the header under a key derived from the
+/// header key (a synthetic nonce, as in SIV), so the same header and key
+/// always give the same ciphertext, as header-key agreement needs. That also
+/// shows when a header repeats, so deterministic mode is only for headers
+/// that are unique under their key.
 pub struct HeaderCipher {
     cipher: Aead,
+    deterministic: bool,
 }
 
 impl HeaderCipher {
     /// Create a new header cipher
     pub fn new() -> Self {
+        Self::with_algorithm(AeadAlgorithm::default())
+    }
+
+    /// Header cipher using `algorithm`, e.g. to match the message cipher
+    ///
+    /// Counter-nonce AES-GCM becomes plain AES-GCM, since headers carry no
+    /// counter of their own.
+    pub fn with_algorithm(algorithm: AeadAlgorithm) -> Self {
+        let algorithm = match algorithm {
+            AeadAlgorithm::Aes256GcmCounter => AeadAlgorithm::Aes256Gcm,
+            other => other,
+        };
         Self {
-            cipher: Aead::new(),
+            cipher: Aead::with_algorithm(algorithm),
+            deterministic: false,
         }
     }
 
+    /// Derive nonces from the key and header instead of drawing them
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
+
+    /// Whether equal headers encrypt to equal ciphertexts
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
     /// Encrypt a header
     pub fn encrypt(&self, key: &AeadKey, header: &[u8]) -> Result<EncryptedPayload> {
         // Use empty AAD for headers since the header itself is the data
-        self.cipher.encrypt(key, header, &[])
+        if !self.deterministic {
+            return self.cipher.encrypt(key, header, &[]);
+        }
+        let nonce = self.synthetic_nonce(key, header);
+        self.cipher.encrypt_with_nonce(key, nonce, header, &[])
     }
 
     /// Decrypt a header
+    ///
+    /// In deterministic mode the nonce must also be the one derived from
+    /// the decrypted header.
     pub fn decrypt(&self, key: &AeadKey, payload: &EncryptedPayload) -> Result<Vec<u8>> {
-        self.cipher.decrypt(key, payload, &[])
+        let header = self.cipher.decrypt(key, payload, &[])?;
+        if self.deterministic {
+            let expected = self.synthetic_nonce(key, &header);
+            if !constant_time_eq(expected.as_bytes(), payload.nonce.as_bytes()) {
+                return Err(CryptoError::AuthenticationFailed);
+            }
+        }
+        Ok(header)
+    }
+
+    fn synthetic_nonce(&self, key: &AeadKey, header: &[u8]) -> Nonce {
+        let mut nonce_key = compute_auth_tag(key.as_bytes(), HEADER_NONCE_LABEL);
+        let tag = compute_auth_tag(&nonce_key, header);
+        nonce_key.zeroize();
+
+        match self.cipher.algorithm {
+            AeadAlgorithm::XChaCha20Poly1305 => {
+                let mut nonce = [0u8; XCHACHA_NONCE_SIZE];
+                nonce.copy_from_slice(&tag[..XCHACHA_NONCE_SIZE]);
+                Nonce::XChaCha(nonce)
+            }
+            AeadAlgorithm::Aes256Gcm | AeadAlgorithm::Aes256GcmCounter => {
+                let mut nonce = [0u8; AES_GCM_NONCE_SIZE];
+                nonce.copy_from_slice(&tag[..AES_GCM_NONCE_SIZE]);
+                Nonce::AesGcm(nonce)
+            }
+        }
     }
 }
 
@@ -361,6 +611,27 @@ mod serde_bytes {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_detect_fastest_is_cached() {
+        let first = AeadAlgorithm::detect_fastest();
+        assert!(matches!(
+            first,
+            AeadAlgorithm::XChaCha20Poly1305 | AeadAlgorithm::Aes256Gcm
+        ));
+
+        for _ in 0..10 {
+            assert_eq!(AeadAlgorithm::detect_fastest(), first);
+        }
+        assert_eq!(BENCHMARK_RUNS.load(Ordering::Relaxed), 1);
+
+        // The winner must actually work
+        let cipher = Aead::with_algorithm(first);
+        let key = AeadKey::from_bytes([0x42; KEY_SIZE]);
+        let encrypted = cipher.encrypt(&key, b"auto", b"").unwrap();
+        assert_eq!(encrypted.algorithm, first);
+        assert_eq!(cipher.decrypt(&key, &encrypted, b"").unwrap(), b"auto");
+    }
+
     #[test]
     fn test_xchacha20_roundtrip() {
         let cipher = Aead::new();
@@ -387,6 +658,47 @@ mod tests {
         assert_eq!(plaintext.as_slice(), decrypted.as_slice());
     }
 
+    #[test]
+    fn test_counter_nonces_unique_across_chain() {
+        let cipher = Aead::with_algorithm(AeadAlgorithm::Aes256GcmCounter);
+        let key = AeadKey::from_bytes([0x42; KEY_SIZE]);
+        let mut nonces = CounterNonces::new();
+
+        let mut seen = std::collections::HashSet::new();
+        for counter in 0..1000 {
+            let encrypted = cipher
+                .encrypt_with_counter(&key, &mut nonces, counter, b"msg", b"aad")
+                .unwrap();
+            assert_eq!(encrypted.algorithm, AeadAlgorithm::Aes256GcmCounter);
+            assert!(seen.insert(encrypted.nonce.as_bytes().to_vec()));
+            assert_eq!(cipher.decrypt(&key, &encrypted, b"aad").unwrap(), b"msg");
+        }
+
+        // Counters are big-endian in the last 8 bytes
+        assert_eq!(
+            Nonce::counter_aes_gcm(0x0102).as_bytes(),
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_counter_reuse_rejected() {
+        let cipher = Aead::with_algorithm(AeadAlgorithm::Aes256GcmCounter);
+        let key = AeadKey::from_bytes([0x42; KEY_SIZE]);
+        let mut nonces = CounterNonces::new();
+
+        cipher.encrypt_with_counter(&key, &mut nonces, 5, b"first", b"").unwrap();
+        let reused = cipher.encrypt_with_counter(&key, &mut nonces, 5, b"second", b"");
+        assert!(matches!(reused, Err(CryptoError::NonceReuse { counter: 5 })));
+        let older = cipher.encrypt_with_counter(&key, &mut nonces, 3, b"third", b"");
+        assert!(matches!(older, Err(CryptoError::NonceReuse { counter: 3 })));
+
+        // Counter mode cannot be used through the random-nonce entry point
+        assert!(cipher.encrypt(&key, b"msg", b"").is_err());
+        assert!(nonces.claim(u64::MAX - 1).is_ok());
+        assert!(nonces.claim(u64::MAX - 1).is_err());
+    }
+
     #[test]
     fn test_wrong_key_fails() {
         let cipher = Aead::new();
@@ -462,4 +774,60 @@ mod tests {
         let result = cipher.encrypt(&key, &plaintext, b"");
         assert!(matches!(result, Err(CryptoError::MessageTooLarge { .. })));
     }
+
+    #[test]
+    fn test_header_cipher_deterministic_mode() {
+        let key = AeadKey::from_bytes([0x42; 32]);
+        let header = b"ratchet header";
+
+        for algorithm in [AeadAlgorithm::XChaCha20Poly1305, AeadAlgorithm::Aes256Gcm] {
+            let cipher = HeaderCipher::with_algorithm(algorithm).deterministic();
+            let first = cipher.encrypt(&key, header).unwrap();
+            let second = cipher.encrypt(&key, header).unwrap();
+
+            assert_eq!(first.algorithm, algorithm);
+            assert_eq!(first.nonce.as_bytes(), second.nonce.as_bytes());
+            assert_eq!(first.ciphertext, second.ciphertext);
+            assert_eq!(cipher.decrypt(&key, &first).unwrap(), header);
+
+            // Another header or key gets another nonce
+            let other = cipher.encrypt(&key, b"other header").unwrap();
+            assert_ne!(other.nonce.as_bytes(), first.nonce.as_bytes());
+            let other_key = AeadKey::from_bytes([0x43; 32]);
+            let other = cipher.encrypt(&other_key, header).unwrap();
+            assert_ne!(other.ciphertext, first.ciphertext);
+
+            // A random-mode ciphertext decrypts, but not as a deterministic one
+            let random = HeaderCipher::with_algorithm(algorithm).encrypt(&key, header).unwrap();
+            assert!(matches!(
+                cipher.decrypt(&key, &random),
+                Err(CryptoError::AuthenticationFailed)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_header_cipher_random_mode() {
+        let key = AeadKey::from_bytes([0x42; 32]);
+        let header = b"ratchet header";
+
+        for algorithm in [
+            AeadAlgorithm::XChaCha20Poly1305,
+            AeadAlgorithm::Aes256Gcm,
+            AeadAlgorithm::Aes256GcmCounter,
+        ] {
+            let cipher = HeaderCipher::with_algorithm(algorithm);
+            assert!(!cipher.is_deterministic());
+
+            let first = cipher.encrypt(&key, header).unwrap();
+            let second = cipher.encrypt(&key, header).unwrap();
+            assert_ne!(first.ciphertext, second.ciphertext);
+            assert_eq!(cipher.decrypt(&key, &first).unwrap(), header);
+            assert_eq!(cipher.decrypt(&key, &second).unwrap(), header);
+        }
+        assert_eq!(
+            HeaderCipher::new().encrypt(&key, header).unwrap().algorithm,
+            AeadAlgorithm::XChaCha20Poly1305
+        );
+    }
 }
@@ -76,6 +76,7 @@ pub enum ChainLinkType {
 }
 
 /// Chain state manager
+#[derive(Serialize, Deserialize)]
 pub struct ChainState {
     /// Current state hash
     state: [u8; 32],
@@ -163,81 +164,49 @@ impl ChainState {
 
     /// Add a message to the chain
     pub fn add_message(&mut self, message_hash: &[u8; 32]) -> ChainLink {
-        self.sequence += 1;
-        let timestamp = Self::current_timestamp();
-        
-        // Compute new state
-        let new_state = self.compute_new_state(message_hash, timestamp);
-        self.state = new_state;
-        
-        let link = ChainLink {
-            link_type: ChainLinkType::Message,
-            state: new_state,
-            message_hash: *message_hash,
-            timestamp,
-            sequence: self.sequence,
-        };
-        
-        self.add_to_history(link.clone());
-        link
+        self.append_link(ChainLinkType::Message, message_hash, Self::current_timestamp())
     }
 
     /// Record a message deletion
     pub fn add_deletion(&mut self, message_hash: &[u8; 32]) -> ChainLink {
-        self.sequence += 1;
-        let timestamp = Self::current_timestamp();
-        
-        let new_state = self.compute_new_state(message_hash, timestamp);
-        self.state = new_state;
-        
-        let link = ChainLink {
-            link_type: ChainLinkType::Deletion,
-            state: new_state,
-            message_hash: *message_hash,
-            timestamp,
-            sequence: self.sequence,
-        };
-        
-        self.add_to_history(link.clone());
-        link
+        self.append_link(ChainLinkType::Deletion, message_hash, Self::current_timestamp())
     }
 
     /// Record an identity rotation
     pub fn add_identity_rotation(&mut self, proof_hash: &[u8; 32]) -> ChainLink {
-        self.sequence += 1;
-        let timestamp = Self::current_timestamp();
-        
-        let new_state = self.compute_new_state(proof_hash, timestamp);
-        self.state = new_state;
-        
-        let link = ChainLink {
-            link_type: ChainLinkType::IdentityRotation,
-            state: new_state,
-            message_hash: *proof_hash,
-            timestamp,
-            sequence: self.sequence,
-        };
-        
-        self.add_to_history(link.clone());
-        link
+        self.append_link(ChainLinkType::IdentityRotation, proof_hash, Self::current_timestamp())
     }
 
     /// Record a re-key event
     pub fn add_rekey(&mut self, rekey_proof: &[u8; 32]) -> ChainLink {
+        self.append_link(ChainLinkType::ReKey, rekey_proof, Self::current_timestamp())
+    }
+
+    /// Append a link using the given wall-clock reading (seconds)
+    ///
+    /// The reading is clamped to the previous link's timestamp so a clock
+    /// that jumps backwards can never produce a decreasing chain.
+    fn append_link(
+        &mut self,
+        link_type: ChainLinkType,
+        input: &[u8; 32],
+        wall_clock: u64,
+    ) -> ChainLink {
         self.sequence += 1;
-        let timestamp = Self::current_timestamp();
-        
-        let new_state = self.compute_new_state(rekey_proof, timestamp);
+        let last_timestamp = self.history.last().map(|l| l.timestamp).unwrap_or(0);
+        let timestamp = wall_clock.max(last_timestamp);
+
+        let new_state = self.compute_new_state(input, timestamp);
         self.state = new_state;
-        
+
         let link = ChainLink {
-            link_type: ChainLinkType::ReKey,
+            link_type,
             state: new_state,
-            message_hash: *rekey_proof,
+            message_hash: *input,
             timestamp,
             sequence: self.sequence,
         };
-        
+
         self.add_to_history(link.clone());
         link
     }
@@ -522,6 +491,26 @@ mod tests {
         assert!(ChainVerifier::verify_chain(&links).is_err());
     }
 
+    #[test]
+    fn test_backwards_clock_jump() {
+        let mut chain = ChainState::new();
+        let start = chain.history()[0].timestamp;
+
+        let link1 = chain.append_link(ChainLinkType::Message, &[0x01; 32], start + 100);
+        // Clock jumps back an hour
+        let link2 = chain.append_link(ChainLinkType::Message, &[0x02; 32], start + 100 - 3600);
+        let link3 = chain.append_link(ChainLinkType::Deletion, &[0x01; 32], start + 50);
+        let link4 = chain.append_link(ChainLinkType::Message, &[0x03; 32], start + 200);
+
+        assert_eq!(link1.timestamp, start + 100);
+        assert_eq!(link2.timestamp, start + 100);
+        assert_eq!(link3.timestamp, start + 100);
+        assert_eq!(link4.timestamp, start + 200);
+
+        assert!(chain.verify_integrity().is_ok());
+        assert!(ChainVerifier::verify_chain(chain.history()).is_ok());
+    }
+
     #[test]
     fn test_deletion_link() {
         let mut chain = ChainState::new();
@@ -52,6 +52,10 @@ pub enum CryptoError {
     #[error("Replay attack detected: message {message_id} already processed")]
     ReplayDetected { message_id: u64 },
 
+    /// AEAD nonce counter already used under the current key
+    #[error("Nonce counter {counter} already used under this key")]
+    NonceReuse { counter: u64 },
+
     /// Out of order message with too large gap
     #[error("Message gap too large: {gap} messages skipped")]
     MessageGapTooLarge { gap: u32 },
@@ -87,6 +91,10 @@ pub enum CryptoError {
     /// Session not established
     #[error("Session not established")]
     SessionNotEstablished,
+
+    /// Key agreement produced an all-zero or otherwise degenerate secret
+    #[error("Weak shared secret")]
+    WeakSharedSecret,
 }
 
 impl From<bincode::Error> for CryptoError {
@@ -129,6 +129,9 @@ impl IdentityPublicKey {
     }
 
     /// Convert Ed25519 public key to X25519
+    ///
+    /// Small-order Ed25519 points, including the identity, map to low-order
+    /// X25519 points and are rejected.
     fn ed25519_pk_to_x25519_pk(ed_pk: &VerifyingKey) -> Result<X25519PublicKey> {
         use curve25519_dalek::edwards::CompressedEdwardsY;
         use curve25519_dalek::montgomery::MontgomeryPoint;
@@ -140,7 +143,7 @@ impl IdentityPublicKey {
             .ok_or_else(|| CryptoError::InvalidPublicKey("Could not decompress point".to_string()))?;
         
         let montgomery: MontgomeryPoint = edwards.to_montgomery();
-        Ok(X25519PublicKey::from(montgomery.to_bytes()))
+        PublicKeyBytes(montgomery.to_bytes()).to_x25519()
     }
 
     /// Verify a signature
@@ -161,6 +164,11 @@ impl IdentityPublicKey {
         *self.dh_key.as_bytes()
     }
 
+    /// Fingerprint of this key, as in [`Identity::fingerprint`]
+    pub fn fingerprint(&self) -> [u8; 32] {
+        Identity::compute_fingerprint(self)
+    }
+
     /// Serialize to bytes (Ed25519 + X25519 = 64 bytes)
     pub fn to_bytes(&self) -> [u8; 64] {
         let mut bytes = [0u8; 64];
@@ -174,7 +182,7 @@ impl IdentityPublicKey {
         let signing_key = VerifyingKey::from_bytes(&bytes[..32].try_into().unwrap())
             .map_err(|_| CryptoError::InvalidPublicKey("Invalid Ed25519 public key".to_string()))?;
         
-        let dh_key = X25519PublicKey::from(<[u8; 32]>::try_from(&bytes[32..]).unwrap());
+        let dh_key = PublicKeyBytes(<[u8; 32]>::try_from(&bytes[32..]).unwrap()).to_x25519()?;
         
         Ok(Self { signing_key, dh_key })
     }
@@ -203,11 +211,16 @@ impl From<&IdentityPublicKey> for SerializableIdentityKey {
 impl TryFrom<SerializableIdentityKey> for IdentityPublicKey {
     type Error = CryptoError;
 
+    /// The X25519 key must be valid and the one the Ed25519 key maps to
     fn try_from(value: SerializableIdentityKey) -> Result<Self> {
-        let signing_key = VerifyingKey::from_bytes(&value.signing_key)
-            .map_err(|_| CryptoError::InvalidPublicKey("Invalid Ed25519 public key".to_string()))?;
-        let dh_key = X25519PublicKey::from(value.dh_key);
-        Ok(Self { signing_key, dh_key })
+        let dh_key = PublicKeyBytes(value.dh_key).to_x25519()?;
+        let key = Self::from_bytes(&value.signing_key)?;
+        if key.dh_key != dh_key {
+            return Err(CryptoError::InvalidPublicKey(
+                "X25519 key does not match the Ed25519 key".to_string(),
+            ));
+        }
+        Ok(key)
     }
 }
 
@@ -348,12 +361,7 @@ impl IdentityRotationProof {
     pub fn verify(&self) -> Result<()> {
         let old_public: IdentityPublicKey = self.old_public_key.clone().try_into()?;
         let new_public: IdentityPublicKey = self.new_public_key.clone().try_into()?;
-        
-        // Reconstruct message
-        let mut message = Vec::with_capacity(128 + 8);
-        message.extend_from_slice(&old_public.to_bytes());
-        message.extend_from_slice(&new_public.to_bytes());
-        message.extend_from_slice(&self.timestamp.to_be_bytes());
+        let message = self.signed_message(&old_public, &new_public);
         
         // Verify old signature
         old_public.verify(&message, &self.old_signature)?;
@@ -361,12 +369,66 @@ impl IdentityRotationProof {
         // Verify new signature
         new_public.verify(&message, &self.new_signature)?;
         
-        // Verify commitment
+        self.verify_commitment(&message)
+    }
+
+    /// Verify an ordered rotation history with a single batched signature check
+    ///
+    /// Accepts exactly what [`verify_rotation_chain`] accepts, but all
+    /// `2 * proofs.len()` Ed25519 signatures are checked together. A failure
+    /// does not say which proof was bad; fall back to [`verify`](Self::verify)
+    /// per proof to find it.
+    pub fn batch_verify(proofs: &[Self]) -> Result<()> {
+        if proofs.is_empty() {
+            return Ok(());
+        }
+
+        check_rotation_links(proofs)?;
+
+        let mut messages = Vec::with_capacity(proofs.len());
+        let mut signatures = Vec::with_capacity(proofs.len() * 2);
+        let mut verifying_keys = Vec::with_capacity(proofs.len() * 2);
+
+        for proof in proofs {
+            let old_public: IdentityPublicKey = proof.old_public_key.clone().try_into()?;
+            let new_public: IdentityPublicKey = proof.new_public_key.clone().try_into()?;
+            let message = proof.signed_message(&old_public, &new_public);
+
+            proof.verify_commitment(&message)?;
+
+            signatures.push(Signature::from_bytes(&proof.old_signature));
+            signatures.push(Signature::from_bytes(&proof.new_signature));
+            verifying_keys.push(old_public.signing_key);
+            verifying_keys.push(new_public.signing_key);
+            messages.push(message);
+        }
+
+        // Both signatures of a proof cover the same message
+        let message_refs: Vec<&[u8]> = messages
+            .iter()
+            .flat_map(|m| [m.as_slice(), m.as_slice()])
+            .collect();
+
+        ed25519_dalek::verify_batch(&message_refs, &signatures, &verifying_keys)
+            .map_err(|_| CryptoError::InvalidSignature)
+    }
+
+    /// Message signed by both keys: old_pub || new_pub || timestamp
+    fn signed_message(&self, old_public: &IdentityPublicKey, new_public: &IdentityPublicKey) -> Vec<u8> {
+        let mut message = Vec::with_capacity(128 + 8);
+        message.extend_from_slice(&old_public.to_bytes());
+        message.extend_from_slice(&new_public.to_bytes());
+        message.extend_from_slice(&self.timestamp.to_be_bytes());
+        message
+    }
+
+    /// Check the commitment over the message and both signatures
+    fn verify_commitment(&self, message: &[u8]) -> Result<()> {
         use sha2::{Sha256, Digest};
         let mut hasher = Sha256::new();
-        hasher.update(&message);
-        hasher.update(&self.old_signature);
-        hasher.update(&self.new_signature);
+        hasher.update(message);
+        hasher.update(self.old_signature);
+        hasher.update(self.new_signature);
         let computed_commitment: [u8; 32] = hasher.finalize().into();
         
         if computed_commitment != self.commitment {
@@ -379,6 +441,46 @@ impl IdentityRotationProof {
     }
 }
 
+/// Verify an ordered rotation history
+///
+/// Every proof must verify on its own, each proof's new key must be the next
+/// proof's old key, and timestamps must not go backwards. An empty history
+/// is trivially valid.
+pub fn verify_rotation_chain(proofs: &[IdentityRotationProof]) -> Result<()> {
+    for proof in proofs {
+        proof.verify()?;
+    }
+
+    check_rotation_links(proofs)
+}
+
+/// Check that consecutive proofs link up and are in time order
+fn check_rotation_links(proofs: &[IdentityRotationProof]) -> Result<()> {
+    for (i, pair) in proofs.windows(2).enumerate() {
+        let (prev, next) = (&pair[0], &pair[1]);
+
+        if prev.new_public_key.signing_key != next.old_public_key.signing_key
+            || prev.new_public_key.dh_key != next.old_public_key.dh_key
+        {
+            return Err(CryptoError::IdentityVerificationFailed(format!(
+                "Rotation {} does not continue from rotation {}",
+                i + 1,
+                i
+            )));
+        }
+
+        if next.timestamp < prev.timestamp {
+            return Err(CryptoError::IdentityVerificationFailed(format!(
+                "Rotation {} predates rotation {}",
+                i + 1,
+                i
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -400,6 +502,36 @@ mod tests {
         assert!(public_key.verify(message, &signature).is_ok());
     }
 
+    #[test]
+    fn test_small_order_ed25519_keys_rejected() {
+        // Ed25519 encodings of small-order points; each maps to an RFC 7748
+        // low-order X25519 point
+        let small_order = [
+            "0100000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+            "c7176a703d4dd84fba3c0b760d10670f2a2053fa2c39ccc64ec7fd7792ac037a",
+            "c7176a703d4dd84fba3c0b760d10670f2a2053fa2c39ccc64ec7fd7792ac03fa",
+            "26e8958fc2b227b045c3f489f2ef98f0d5dfac05d3c63339b13802886d53fc05",
+            "26e8958fc2b227b045c3f489f2ef98f0d5dfac05d3c63339b13802886d53fc85",
+        ];
+        for encoded in small_order {
+            let bytes: [u8; 32] = hex::decode(encoded).unwrap().try_into().unwrap();
+            assert!(matches!(
+                IdentityPublicKey::from_bytes(&bytes),
+                Err(CryptoError::InvalidPublicKey(_))
+            ), "{}", encoded);
+        }
+
+        let identity = IdentityKeyPair::generate().public_key();
+        for point in crate::keys::LOW_ORDER_POINTS {
+            let mut full = identity.to_bytes();
+            full[32..].copy_from_slice(&point);
+            assert!(IdentityPublicKey::from_full_bytes(&full).is_err());
+        }
+        assert!(IdentityPublicKey::from_full_bytes(&identity.to_bytes()).is_ok());
+    }
+
     #[test]
     fn test_invalid_signature() {
         let identity = Identity::new();
@@ -424,6 +556,80 @@ mod tests {
         assert_ne!(identity.fingerprint, new_identity.fingerprint);
     }
 
+    #[test]
+    fn test_rotation_chain_links() {
+        let first = Identity::new();
+        let (second, proof1) = first.rotate();
+        let (third, proof2) = second.rotate();
+        let (_, proof3) = third.rotate();
+
+        assert!(verify_rotation_chain(&[]).is_ok());
+        assert!(verify_rotation_chain(&[proof1.clone(), proof2.clone()]).is_ok());
+
+        // Dropping the middle rotation leaves a gap
+        assert!(verify_rotation_chain(&[proof1.clone(), proof3.clone()]).is_err());
+
+        // So does a rotation from an unrelated identity
+        let (_, foreign) = Identity::new().rotate();
+        assert!(verify_rotation_chain(&[proof1.clone(), foreign]).is_err());
+
+        // Out of order
+        assert!(verify_rotation_chain(&[proof2, proof1]).is_err());
+    }
+
+    fn rotation_history(len: usize) -> Vec<IdentityRotationProof> {
+        let mut current = Identity::new();
+        let mut proofs = Vec::with_capacity(len);
+        for _ in 0..len {
+            let (next, proof) = current.rotate();
+            proofs.push(proof);
+            current = next;
+        }
+        proofs
+    }
+
+    #[test]
+    fn test_batch_verify_rotation_history() {
+        let proofs = rotation_history(100);
+
+        assert!(IdentityRotationProof::batch_verify(&[]).is_ok());
+        assert!(IdentityRotationProof::batch_verify(&proofs).is_ok());
+        assert!(verify_rotation_chain(&proofs).is_ok());
+    }
+
+    #[test]
+    fn test_batch_verify_rejects_bad_signature() {
+        let mut proofs = rotation_history(100);
+        proofs[57].new_signature[0] ^= 0x01;
+
+        // The commitment no longer matches either, so recompute it to make
+        // sure the batched signature check itself catches the flip
+        let mut resealed = proofs.clone();
+        {
+            use sha2::{Sha256, Digest};
+            let proof = &mut resealed[57];
+            let old_public: IdentityPublicKey = proof.old_public_key.clone().try_into().unwrap();
+            let new_public: IdentityPublicKey = proof.new_public_key.clone().try_into().unwrap();
+            let message = proof.signed_message(&old_public, &new_public);
+            let mut hasher = Sha256::new();
+            hasher.update(&message);
+            hasher.update(proof.old_signature);
+            hasher.update(proof.new_signature);
+            proof.commitment = hasher.finalize().into();
+        }
+
+        assert!(IdentityRotationProof::batch_verify(&proofs).is_err());
+        assert!(matches!(
+            IdentityRotationProof::batch_verify(&resealed),
+            Err(CryptoError::InvalidSignature)
+        ));
+
+        // Linkage is still enforced
+        let mut gapped = rotation_history(3);
+        gapped.remove(1);
+        assert!(IdentityRotationProof::batch_verify(&gapped).is_err());
+    }
+
     #[test]
     fn test_diffie_hellman() {
         let alice = Identity::new();
@@ -447,5 +653,19 @@ mod tests {
         let restored: IdentityPublicKey = deserialized.try_into().unwrap();
         
         assert_eq!(public_key.signing_key_bytes(), restored.signing_key_bytes());
+
+        // The DH key is never taken on trust
+        let mut mismatched = SerializableIdentityKey::from(&public_key);
+        mismatched.dh_key = Identity::new().key_pair.public_key().dh_key_bytes();
+        assert!(matches!(
+            IdentityPublicKey::try_from(mismatched),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
+        let mut low_order = SerializableIdentityKey::from(&public_key);
+        low_order.dh_key = [0u8; 32];
+        assert!(matches!(
+            IdentityPublicKey::try_from(low_order),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
     }
 }
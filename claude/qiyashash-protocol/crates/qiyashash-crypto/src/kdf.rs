@@ -40,6 +40,57 @@ pub mod domain {
     pub const CHAIN_PROOF: &[u8] = b"QiyasHash_v1_ChainProof";
     /// Identity proof derivation
     pub const IDENTITY_PROOF: &[u8] = b"QiyasHash_v1_IdentityProof";
+    /// Sending chain re-key
+    pub const REKEY: &[u8] = b"QiyasHash_v1_ReKey";
+    /// Per-conversation search index key
+    pub const SEARCH_KEY: &[u8] = b"QiyasHash_v1_SearchKey";
+    /// Per-session key for application-level deniable MACs
+    pub const DENIABLE_MAC: &[u8] = b"QiyasHash_v1_DeniableMac";
+    /// Key confirmation after X3DH
+    pub const KEY_CONFIRMATION: &[u8] = b"QiyasHash_v1_KeyConfirmation";
+    /// Per-session key authenticating session resets
+    pub const SESSION_RESET: &[u8] = b"QiyasHash_v1_SessionReset";
+    /// Account secret for identities stored before it was kept alongside them
+    pub const ACCOUNT_SECRET: &[u8] = b"QiyasHash_v1_AccountSecret";
+    /// Key sealing session secrets at rest
+    pub const SESSION_STORAGE: &[u8] = b"QiyasHash_v1_SessionStorage";
+}
+
+/// Purposes for [`KeyDerivationContext::derive_labeled`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KdfDomain {
+    /// Per-conversation search index key
+    SearchKey,
+    /// Per-session key for deniable MACs
+    DeniableMac,
+    /// Key confirmation after X3DH
+    KeyConfirmation,
+    /// Message header encryption
+    HeaderKey,
+    /// Per-session key authenticating session resets
+    SessionReset,
+}
+
+impl KdfDomain {
+    /// Every domain
+    pub const ALL: [KdfDomain; 5] = [
+        KdfDomain::SearchKey,
+        KdfDomain::DeniableMac,
+        KdfDomain::KeyConfirmation,
+        KdfDomain::HeaderKey,
+        KdfDomain::SessionReset,
+    ];
+
+    /// Domain separation string
+    pub fn label(self) -> &'static [u8] {
+        match self {
+            KdfDomain::SearchKey => domain::SEARCH_KEY,
+            KdfDomain::DeniableMac => domain::DENIABLE_MAC,
+            KdfDomain::KeyConfirmation => domain::KEY_CONFIRMATION,
+            KdfDomain::HeaderKey => domain::HEADER_KEY,
+            KdfDomain::SessionReset => domain::SESSION_RESET,
+        }
+    }
 }
 
 /// A derived key with automatic zeroization
@@ -101,6 +152,26 @@ impl KeyDerivationContext {
         Ok(DerivedKey(output))
     }
 
+    /// Derive the `index`-th per-purpose key for `label`
+    ///
+    /// The HKDF info is the label, prefixed with its 16-bit length, then
+    /// `index` as 32 bits, so distinct (label, index) pairs never share an
+    /// info string. The leading zero byte also keeps it apart from the bare
+    /// [`domain`] strings passed to [`KeyDerivationContext::derive`].
+    pub fn derive_labeled(&self, label: KdfDomain, index: u32) -> [u8; 32] {
+        let label = label.label();
+        let mut info = Vec::with_capacity(2 + label.len() + 4);
+        info.extend_from_slice(&(label.len() as u16).to_be_bytes());
+        info.extend_from_slice(label);
+        info.extend_from_slice(&index.to_be_bytes());
+
+        let mut output = [0u8; 32];
+        self.hkdf
+            .expand(&info, &mut output)
+            .expect("32 bytes is a valid HKDF-SHA512 output length");
+        output
+    }
+
     /// Derive multiple keys at once for efficiency
     pub fn derive_keys(&self, infos: &[&[u8]], output_sizes: &[usize]) -> Result<Vec<Vec<u8>>> {
         let mut results = Vec::with_capacity(infos.len());
@@ -191,15 +262,8 @@ pub fn derive_message_keys(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32], [u8; 32
     let (new_chain_key, message_key) = ratchet.ratchet();
 
     // Derive header key from message key
-    let header_key = {
-        let mut mac = <HmacSha256 as Mac>::new_from_slice(&message_key)
-            .expect("HMAC can take key of any size");
-        mac.update(domain::HEADER_KEY);
-        let result = mac.finalize();
-        let mut output = [0u8; 32];
-        output.copy_from_slice(&result.into_bytes());
-        output
-    };
+    let header_key =
+        KeyDerivationContext::new(None, &message_key).derive_labeled(KdfDomain::HeaderKey, 0);
 
     (new_chain_key, message_key, header_key)
 }
@@ -224,7 +288,7 @@ pub fn verify_auth_tag(auth_key: &[u8; 32], data: &[u8], tag: &[u8; 32]) -> bool
 }
 
 /// Constant-time comparison to prevent timing attacks
-fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
     }
@@ -258,6 +322,30 @@ pub fn derive_chain_proof(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_derive_labeled_keys_are_distinct_and_stable() {
+        let kdf = KeyDerivationContext::new(None, &[0x42u8; 32]);
+        let same = KeyDerivationContext::new(None, &[0x42u8; 32]);
+
+        let mut seen = std::collections::HashSet::new();
+        for label in KdfDomain::ALL {
+            for index in [0, 1, 2, u32::MAX] {
+                let key = kdf.derive_labeled(label, index);
+                assert_eq!(key, same.derive_labeled(label, index));
+                assert!(seen.insert(key), "{:?}/{} collides", label, index);
+            }
+            // Never the key the bare label would give
+            let bare: DerivedKey<32> = kdf.derive(label.label()).unwrap();
+            assert!(seen.insert(*bare.as_bytes()));
+        }
+
+        let other = KeyDerivationContext::new(None, &[0x43u8; 32]);
+        assert_ne!(
+            kdf.derive_labeled(KdfDomain::SearchKey, 0),
+            other.derive_labeled(KdfDomain::SearchKey, 0)
+        );
+    }
+
     #[test]
     fn test_key_derivation_context() {
         let ikm = [0x42u8; 32];
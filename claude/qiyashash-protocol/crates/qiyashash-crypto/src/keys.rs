@@ -18,6 +18,61 @@ pub const X25519_PUBLIC_KEY_SIZE: usize = 32;
 /// Size of X25519 private keys in bytes  
 pub const X25519_PRIVATE_KEY_SIZE: usize = 32;
 
+/// X25519 points of order 1, 2, 4 or 8, ignoring the unused top bit
+///
+/// The small-order list from RFC 7748 section 7, including the identity
+/// element (0) and the non-canonical encodings of 0 and 1. DH with any of
+/// them gives the same output whatever our secret is, so a peer offering one
+/// controls the shared secret.
+pub(crate) const LOW_ORDER_POINTS: [[u8; 32]; 7] = [
+    // 0 (order 4)
+    [0x00; 32],
+    // 1 (order 1)
+    [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    // Order 8
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    // Order 8
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    // p - 1 (order 2)
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    // p, non-canonical 0 (order 4)
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    // p + 1, non-canonical 1 (order 1)
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
+/// Whether a DH output or derived secret is all zeros
+///
+/// That is what X25519 gives for a low-order peer key, and what a broken
+/// key agreement tends to produce.
+pub fn is_weak_shared_secret(secret: &[u8; 32]) -> bool {
+    secret.iter().fold(0u8, |acc, byte| acc | byte) == 0
+}
+
 /// A X25519 key pair for Diffie-Hellman key exchange
 #[derive(ZeroizeOnDrop)]
 pub struct EphemeralKeyPair {
@@ -62,9 +117,9 @@ impl EphemeralKeyPair {
         SharedSecret(*shared.as_bytes())
     }
 
-    /// Perform X25519 DH with public key bytes
+    /// Perform X25519 DH with public key bytes, rejecting low-order keys
     pub fn diffie_hellman_bytes(&self, their_public: &[u8; 32]) -> Result<SharedSecret> {
-        let their_key = X25519PublicKey::from(*their_public);
+        let their_key = PublicKeyBytes(*their_public).to_x25519()?;
         Ok(self.diffie_hellman(&their_key))
     }
 
@@ -121,9 +176,21 @@ impl PublicKeyBytes {
         Self(*key.as_bytes())
     }
 
-    /// Convert to X25519 public key
-    pub fn to_x25519(&self) -> X25519PublicKey {
-        X25519PublicKey::from(self.0)
+    /// Convert to an X25519 public key usable for DH
+    ///
+    /// Fails for low-order points, including the identity element.
+    pub fn to_x25519(&self) -> Result<X25519PublicKey> {
+        if self.is_low_order() {
+            return Err(CryptoError::InvalidPublicKey("low-order X25519 key".to_string()));
+        }
+        Ok(X25519PublicKey::from(self.0))
+    }
+
+    /// Whether this is a low-order X25519 point, unusable for key agreement
+    pub fn is_low_order(&self) -> bool {
+        let mut masked = self.0;
+        masked[31] &= 0x7f;
+        LOW_ORDER_POINTS.contains(&masked)
     }
 
     /// Get raw bytes
@@ -151,7 +218,7 @@ pub struct SignedPreKey {
     pub id: u32,
     /// The public key
     pub public_key: PublicKeyBytes,
-    /// Signature over the public key by the identity key
+    /// Signature over the public key and timestamp by the identity key
     #[serde(with = "hex::serde")]
     pub signature: [u8; 64],
     /// Timestamp when this key was generated
@@ -159,16 +226,59 @@ pub struct SignedPreKey {
 }
 
 impl SignedPreKey {
+    /// Bytes the identity key signs: the public key, then the timestamp
+    /// (i64 BE), so the key's age cannot be changed without the signature
+    pub fn signed_data(public_key: &[u8; 32], timestamp: i64) -> [u8; 40] {
+        let mut data = [0u8; 40];
+        data[..32].copy_from_slice(public_key);
+        data[32..].copy_from_slice(&timestamp.to_be_bytes());
+        data
+    }
+
     /// Verify the signature using the identity public key
     pub fn verify(&self, identity_key: &ed25519_dalek::VerifyingKey) -> Result<()> {
         use ed25519_dalek::Signature;
         let signature = Signature::from_bytes(&self.signature);
         identity_key
-            .verify_strict(self.public_key.as_bytes(), &signature)
+            .verify_strict(&Self::signed_data(self.public_key.as_bytes(), self.timestamp), &signature)
             .map_err(|_| CryptoError::InvalidSignature)
     }
 }
 
+/// Version written by [`PreKeyBundle::to_wire`]
+pub const PREKEY_BUNDLE_WIRE_VERSION: u8 = 1;
+
+/// Wire flag: the identity's X25519 key follows its Ed25519 key
+const WIRE_HAS_IDENTITY_DH: u8 = 0x01;
+/// Wire flag: a one-time prekey ends the bundle
+const WIRE_HAS_ONE_TIME_PREKEY: u8 = 0x02;
+
+/// Type tag in front of every public key in [`PreKeyBundle::to_wire`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    /// Ed25519 verifying key
+    Ed25519,
+    /// X25519 Diffie-Hellman key
+    X25519,
+}
+
+impl KeyType {
+    /// Tag byte on the wire
+    pub fn tag(self) -> u8 {
+        match self {
+            KeyType::Ed25519 => 0x01,
+            KeyType::X25519 => 0x02,
+        }
+    }
+
+    /// Length of a key of this type
+    pub fn key_len(self) -> usize {
+        match self {
+            KeyType::Ed25519 | KeyType::X25519 => 32,
+        }
+    }
+}
+
 /// A bundle of pre-keys for asynchronous session establishment
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PreKeyBundle {
@@ -181,6 +291,160 @@ pub struct PreKeyBundle {
     pub one_time_prekey: Option<OneTimePreKey>,
 }
 
+impl PreKeyBundle {
+    /// Check the signed prekey signature and reject low-order keys
+    ///
+    /// Returns the bundle's identity key.
+    pub fn verify(&self) -> Result<crate::identity::IdentityPublicKey> {
+        // Rejects low-order identity keys
+        let identity = crate::identity::IdentityPublicKey::from_bytes(&self.identity_key)?;
+        self.signed_prekey.verify(&identity.signing_key)?;
+
+        if self.signed_prekey.public_key.is_low_order() {
+            return Err(CryptoError::InvalidPublicKey("low-order signed prekey".to_string()));
+        }
+        if let Some(opk) = &self.one_time_prekey {
+            if opk.public_key.is_low_order() {
+                return Err(CryptoError::InvalidPublicKey(format!(
+                    "low-order one-time prekey {}",
+                    opk.id
+                )));
+            }
+        }
+        Ok(identity)
+    }
+
+    /// Encode with every key tagged by its type and length
+    ///
+    /// ```text
+    /// version || flags || key(Ed25519 identity) || key(X25519 identity)
+    ///     || spk id (u32 BE) || key(X25519 spk) || signature (64) || timestamp (i64 BE)
+    ///     || [opk id (u32 BE) || key(X25519 opk)]
+    /// key(T) = tag(T) || length (1 byte) || bytes
+    /// ```
+    ///
+    /// The identity's X25519 key is the one derived from its Ed25519 key.
+    pub fn to_wire(&self) -> Result<Vec<u8>> {
+        let identity = crate::identity::IdentityPublicKey::from_bytes(&self.identity_key)?;
+
+        let mut flags = WIRE_HAS_IDENTITY_DH;
+        if self.one_time_prekey.is_some() {
+            flags |= WIRE_HAS_ONE_TIME_PREKEY;
+        }
+
+        let mut wire = Vec::with_capacity(2 + 4 * 34 + 4 + 64 + 8 + 4);
+        wire.push(PREKEY_BUNDLE_WIRE_VERSION);
+        wire.push(flags);
+        write_key(&mut wire, KeyType::Ed25519, &self.identity_key);
+        write_key(&mut wire, KeyType::X25519, &identity.dh_key_bytes());
+        wire.extend_from_slice(&self.signed_prekey.id.to_be_bytes());
+        write_key(&mut wire, KeyType::X25519, self.signed_prekey.public_key.as_bytes());
+        wire.extend_from_slice(&self.signed_prekey.signature);
+        wire.extend_from_slice(&self.signed_prekey.timestamp.to_be_bytes());
+        if let Some(opk) = &self.one_time_prekey {
+            wire.extend_from_slice(&opk.id.to_be_bytes());
+            write_key(&mut wire, KeyType::X25519, opk.public_key.as_bytes());
+        }
+        Ok(wire)
+    }
+
+    /// Decode [`to_wire`](Self::to_wire) output
+    ///
+    /// Every key must carry the expected type and length, and a supplied
+    /// identity X25519 key must equal the one derived from the Ed25519 key.
+    /// The signature is not checked here; call [`verify`](Self::verify).
+    pub fn from_wire(wire: &[u8]) -> Result<Self> {
+        let mut reader = WireReader(wire);
+        let version = reader.take::<1>()?[0];
+        if version != PREKEY_BUNDLE_WIRE_VERSION {
+            return Err(CryptoError::InvalidVersion(version as u32));
+        }
+        let flags = reader.take::<1>()?[0];
+        if flags & !(WIRE_HAS_IDENTITY_DH | WIRE_HAS_ONE_TIME_PREKEY) != 0 {
+            return Err(CryptoError::Serialization(format!(
+                "unknown bundle flags {:#04x}",
+                flags
+            )));
+        }
+
+        let identity_key = reader.key(KeyType::Ed25519)?;
+        let identity = crate::identity::IdentityPublicKey::from_bytes(&identity_key)?;
+        if flags & WIRE_HAS_IDENTITY_DH != 0 {
+            let dh_key = reader.key(KeyType::X25519)?;
+            if !crate::kdf::constant_time_eq(&dh_key, &identity.dh_key_bytes()) {
+                return Err(CryptoError::InvalidPublicKey(
+                    "identity X25519 key does not match its Ed25519 key".to_string(),
+                ));
+            }
+        }
+
+        let signed_prekey = SignedPreKey {
+            id: u32::from_be_bytes(reader.take()?),
+            public_key: PublicKeyBytes(reader.key(KeyType::X25519)?),
+            signature: reader.take()?,
+            timestamp: i64::from_be_bytes(reader.take()?),
+        };
+        let one_time_prekey = if flags & WIRE_HAS_ONE_TIME_PREKEY != 0 {
+            Some(OneTimePreKey {
+                id: u32::from_be_bytes(reader.take()?),
+                public_key: PublicKeyBytes(reader.key(KeyType::X25519)?),
+            })
+        } else {
+            None
+        };
+
+        if !reader.0.is_empty() {
+            return Err(CryptoError::Serialization(format!(
+                "{} trailing bytes after bundle",
+                reader.0.len()
+            )));
+        }
+
+        Ok(Self {
+            identity_key,
+            signed_prekey,
+            one_time_prekey,
+        })
+    }
+}
+
+fn write_key(wire: &mut Vec<u8>, key_type: KeyType, key: &[u8; 32]) {
+    wire.push(key_type.tag());
+    wire.push(key.len() as u8);
+    wire.extend_from_slice(key);
+}
+
+/// Cursor over [`PreKeyBundle::to_wire`] output
+struct WireReader<'a>(&'a [u8]);
+
+impl WireReader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.0.len() < N {
+            return Err(CryptoError::Serialization("prekey bundle truncated".to_string()));
+        }
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(field.try_into().expect("split at N"))
+    }
+
+    fn key(&mut self, expected: KeyType) -> Result<[u8; 32]> {
+        let [tag, len] = self.take::<2>()?;
+        if tag != expected.tag() {
+            return Err(CryptoError::InvalidPublicKey(format!(
+                "expected {:?} key, got type {:#04x}",
+                expected, tag
+            )));
+        }
+        if len as usize != expected.key_len() {
+            return Err(CryptoError::InvalidKeyLength {
+                expected: expected.key_len(),
+                actual: len as usize,
+            });
+        }
+        self.take()
+    }
+}
+
 /// A one-time pre-key (used once and discarded)
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OneTimePreKey {
@@ -291,6 +555,92 @@ mod tests {
         assert!(storage.signing_public_key().verify_strict(message, &sig).is_ok());
     }
 
+    fn wire_bundle() -> PreKeyBundle {
+        let identity = crate::identity::IdentityKeyPair::generate();
+        let mut prekeys = crate::x3dh::PreKeyManager::new(identity);
+        prekeys.generate_one_time_prekeys(1);
+        prekeys.get_bundle()
+    }
+
+    #[test]
+    fn test_prekey_bundle_wire_round_trip() {
+        let bundle = wire_bundle();
+        let wire = bundle.to_wire().unwrap();
+        let decoded = PreKeyBundle::from_wire(&wire).unwrap();
+
+        assert_eq!(decoded.identity_key, bundle.identity_key);
+        assert_eq!(decoded.signed_prekey.id, bundle.signed_prekey.id);
+        assert_eq!(decoded.signed_prekey.public_key, bundle.signed_prekey.public_key);
+        assert_eq!(decoded.signed_prekey.signature, bundle.signed_prekey.signature);
+        assert_eq!(decoded.signed_prekey.timestamp, bundle.signed_prekey.timestamp);
+        let opk = bundle.one_time_prekey.unwrap();
+        let decoded_opk = decoded.one_time_prekey.clone().unwrap();
+        assert_eq!((decoded_opk.id, decoded_opk.public_key), (opk.id, opk.public_key));
+        decoded.verify().unwrap();
+
+        let mut without_opk = decoded;
+        without_opk.one_time_prekey = None;
+        let wire = without_opk.to_wire().unwrap();
+        assert!(PreKeyBundle::from_wire(&wire).unwrap().one_time_prekey.is_none());
+        assert!(matches!(
+            PreKeyBundle::from_wire(&wire[..wire.len() - 1]),
+            Err(CryptoError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_prekey_bundle_wire_rejects_inconsistent_keys() {
+        let wire = wire_bundle().to_wire().unwrap();
+        // version, flags, tagged Ed25519 identity, then its tagged X25519 key
+        let identity_dh = 2 + 34 + 2;
+
+        let mut mismatched = wire.clone();
+        mismatched[identity_dh] ^= 0x01;
+        assert!(matches!(
+            PreKeyBundle::from_wire(&mismatched),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
+
+        // An X25519 key where the Ed25519 identity belongs
+        let mut wrong_type = wire.clone();
+        wrong_type[2] = KeyType::X25519.tag();
+        assert!(matches!(
+            PreKeyBundle::from_wire(&wrong_type),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
+
+        let mut wrong_len = wire;
+        wrong_len[3] = 33;
+        assert!(matches!(
+            PreKeyBundle::from_wire(&wrong_len),
+            Err(CryptoError::InvalidKeyLength { .. })
+        ));
+    }
+
+    #[test]
+    fn test_low_order_points_rejected() {
+        for point in LOW_ORDER_POINTS {
+            for top_bit in [0x00, 0x80] {
+                let mut bytes = point;
+                bytes[31] |= top_bit;
+                assert!(matches!(
+                    PublicKeyBytes(bytes).to_x25519(),
+                    Err(CryptoError::InvalidPublicKey(_))
+                ));
+            }
+        }
+
+        let valid = EphemeralKeyPair::generate().public_key_bytes();
+        assert_eq!(PublicKeyBytes(valid).to_x25519().unwrap().to_bytes(), valid);
+
+        let ours = EphemeralKeyPair::generate();
+        assert!(ours.diffie_hellman_bytes(&valid).is_ok());
+        assert!(matches!(
+            ours.diffie_hellman_bytes(&LOW_ORDER_POINTS[0]),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
+    }
+
     #[test]
     fn test_shared_secret_zeroize() {
         let bytes = [0xAB; 32];
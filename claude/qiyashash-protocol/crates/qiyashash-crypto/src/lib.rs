@@ -19,20 +19,47 @@
 //! - [`ratchet`]: Double Ratchet algorithm for message encryption
 //! - [`keys`]: Key types and derivation functions
 //! - [`aead`]: Authenticated encryption (ChaCha20-Poly1305, AES-256-GCM)
+//! - [`stream`]: Chunked AEAD for large payloads such as attachments
 //! - [`chain`]: Chain state management for message ordering
+//! - [`portable`]: AEAD and chain-key math with injected RNG and clock
+//!
+//! ## `no_std`
+//!
+//! The `std` feature is on by default. Without it the crate is `no_std` +
+//! `alloc` and only [`portable`] is available, for WASM and embedded
+//! targets that cannot use `OsRng` or the system clock.
 
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
 #![forbid(unsafe_code)]
 #![warn(missing_docs, rust_2018_idioms)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod aead;
+#[cfg(feature = "std")]
 pub mod chain;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "std")]
 pub mod identity;
+#[cfg(feature = "std")]
 pub mod keys;
+#[cfg(feature = "std")]
 pub mod kdf;
+pub mod portable;
+#[cfg(feature = "std")]
 pub mod ratchet;
+#[cfg(feature = "std")]
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "std")]
+pub mod wire;
+#[cfg(feature = "std")]
 pub mod x3dh;
 
+#[cfg(feature = "std")]
 pub use error::{CryptoError, Result};
 
 /// Protocol version for compatibility checking
@@ -45,12 +72,13 @@ pub const MAX_MESSAGE_SIZE: usize = 65536;
 pub const MAX_CHAIN_LENGTH: u32 = 1000;
 
 /// Prelude module for convenient imports
+#[cfg(feature = "std")]
 pub mod prelude {
     pub use crate::aead::{Aead, AeadKey, Nonce};
     pub use crate::chain::{ChainKey, ChainState, MessageKey};
     pub use crate::error::{CryptoError, Result};
     pub use crate::identity::{Identity, IdentityKeyPair, IdentityPublicKey};
     pub use crate::keys::{EphemeralKeyPair, PreKeyBundle, SignedPreKey};
-    pub use crate::ratchet::{DoubleRatchet, RatchetHeader, RatchetState};
+    pub use crate::ratchet::{DoubleRatchet, RatchetHeader, RatchetHealth, RatchetState};
     pub use crate::x3dh::{X3DHKeyAgreement, X3DHSharedSecret};
 }
@@ -0,0 +1,323 @@
+//! Allocation-only crypto subset for `no_std` targets
+//!
+//! Everything in this module builds without the `std` feature: there is no
+//! `OsRng`, no system clock and no `chrono`. Randomness is injected through
+//! `rand_core` and time through [`Clock`], so WASM and embedded builds can
+//! share the message math with desktop and mobile.
+//!
+//! [`chain_step`] derives the same keys as [`ChainRatchet`] and sealing uses
+//! XChaCha20-Poly1305, the default [`Aead`] algorithm.
+//!
+//! [`ChainRatchet`]: crate::kdf::ChainRatchet
+//! [`Aead`]: crate::aead::Aead
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use chacha20poly1305::aead::{Aead as _, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// XChaCha20-Poly1305 nonce size
+pub const NONCE_SIZE: usize = 24;
+
+/// Ratchet message header: counter (u32 BE) then timestamp (u64 BE)
+pub const HEADER_SIZE: usize = 4 + 8;
+
+/// Source of the current time
+pub trait Clock {
+    /// Seconds since the Unix epoch
+    fn now_secs(&self) -> u64;
+}
+
+/// [`Clock`] backed by the system time
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+/// Errors from the portable subset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortableError {
+    /// Input shorter than its fixed-size fields
+    Truncated,
+    /// Encryption failed
+    Encryption,
+    /// Authentication failed or wrong key
+    Decryption,
+    /// Message counter is not the next one expected
+    OutOfOrder {
+        /// Counter expected next
+        expected: u32,
+        /// Counter received
+        got: u32,
+    },
+}
+
+impl fmt::Display for PortableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortableError::Truncated => write!(f, "Input truncated"),
+            PortableError::Encryption => write!(f, "Encryption failed"),
+            PortableError::Decryption => write!(f, "Decryption failed"),
+            PortableError::OutOfOrder { expected, got } => {
+                write!(f, "Expected message {}, got {}", expected, got)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PortableError {}
+
+/// Encrypt `plaintext` as `nonce || ciphertext` with a nonce drawn from `rng`
+pub fn seal<R: RngCore + CryptoRng>(
+    key: &[u8; 32],
+    rng: &mut R,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, PortableError> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| PortableError::Encryption)?;
+
+    let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypt the output of [`seal`]
+pub fn open(key: &[u8; 32], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, PortableError> {
+    if sealed.len() < NONCE_SIZE {
+        return Err(PortableError::Truncated);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| PortableError::Decryption)
+}
+
+/// Advance a chain key
+///
+/// Returns (new_chain_key, message_key), like [`ChainRatchet::ratchet`].
+///
+/// [`ChainRatchet::ratchet`]: crate::kdf::ChainRatchet::ratchet
+pub fn chain_step(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |input: u8| {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(chain_key)
+            .expect("HMAC can take key of any size");
+        mac.update(&[input]);
+        let mut output = [0u8; 32];
+        output.copy_from_slice(&mac.finalize().into_bytes());
+        output
+    };
+
+    let message_key = derive(0x01);
+    let new_chain_key = derive(0x02);
+    (new_chain_key, message_key)
+}
+
+/// One-directional symmetric ratchet with injected RNG and clock
+///
+/// Each message is `header || seal(message_key, header)`; the header carries
+/// the message counter and the sender's timestamp and is authenticated.
+/// Messages must be decrypted in order.
+pub struct SymmetricRatchet {
+    chain_key: [u8; 32],
+    counter: u32,
+}
+
+impl SymmetricRatchet {
+    /// Start a ratchet from a shared chain key
+    pub fn new(chain_key: [u8; 32]) -> Self {
+        Self {
+            chain_key,
+            counter: 0,
+        }
+    }
+
+    /// Counter of the next message
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Encrypt the next message
+    pub fn encrypt<R: RngCore + CryptoRng>(
+        &mut self,
+        rng: &mut R,
+        clock: &dyn Clock,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, PortableError> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&self.counter.to_be_bytes());
+        header[4..].copy_from_slice(&clock.now_secs().to_be_bytes());
+
+        let mut message_key = self.advance();
+        let sealed = seal(&message_key, rng, plaintext, &header);
+        message_key.zeroize();
+
+        let mut message = Vec::with_capacity(HEADER_SIZE + NONCE_SIZE + plaintext.len() + 16);
+        message.extend_from_slice(&header);
+        message.extend_from_slice(&sealed?);
+        Ok(message)
+    }
+
+    /// Decrypt the next message
+    ///
+    /// Returns the sender's timestamp and the plaintext. The chain only
+    /// advances when the message authenticates.
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<(u64, Vec<u8>), PortableError> {
+        if message.len() < HEADER_SIZE {
+            return Err(PortableError::Truncated);
+        }
+        let (header, sealed) = message.split_at(HEADER_SIZE);
+        let mut counter = [0u8; 4];
+        counter.copy_from_slice(&header[..4]);
+        let counter = u32::from_be_bytes(counter);
+        if counter != self.counter {
+            return Err(PortableError::OutOfOrder {
+                expected: self.counter,
+                got: counter,
+            });
+        }
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&header[4..]);
+
+        let (mut next_chain_key, mut message_key) = chain_step(&self.chain_key);
+        let result = open(&message_key, sealed, header);
+        message_key.zeroize();
+
+        match result {
+            Ok(plaintext) => {
+                self.chain_key.zeroize();
+                self.chain_key = next_chain_key;
+                self.counter += 1;
+                Ok((u64::from_be_bytes(timestamp), plaintext))
+            }
+            Err(e) => {
+                next_chain_key.zeroize();
+                Err(e)
+            }
+        }
+    }
+
+    fn advance(&mut self) -> [u8; 32] {
+        let (next_chain_key, message_key) = chain_step(&self.chain_key);
+        self.chain_key.zeroize();
+        self.chain_key = next_chain_key;
+        self.counter += 1;
+        message_key
+    }
+}
+
+impl Drop for SymmetricRatchet {
+    fn drop(&mut self) {
+        self.chain_key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic RNG standing in for a platform entropy source
+    struct CounterRng(u8);
+
+    impl RngCore for CounterRng {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                self.0 = self.0.wrapping_add(1);
+                *byte = self.0;
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for CounterRng {}
+
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now_secs(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_ratchet_with_injected_rng_and_clock() {
+        let mut rng = CounterRng(0);
+        let clock = FixedClock(1_700_000_000);
+        let mut alice = SymmetricRatchet::new([0x07; 32]);
+        let mut bob = SymmetricRatchet::new([0x07; 32]);
+
+        let first = alice.encrypt(&mut rng, &clock, b"first").unwrap();
+        let second = alice.encrypt(&mut rng, &clock, b"second").unwrap();
+        assert_ne!(first[HEADER_SIZE..], second[HEADER_SIZE..]);
+
+        // Out of order is refused without advancing the chain
+        assert_eq!(
+            bob.decrypt(&second),
+            Err(PortableError::OutOfOrder { expected: 0, got: 1 })
+        );
+
+        let mut tampered = first.clone();
+        tampered[4] ^= 1;
+        assert_eq!(bob.decrypt(&tampered), Err(PortableError::Decryption));
+
+        assert_eq!(bob.decrypt(&first).unwrap(), (1_700_000_000, b"first".to_vec()));
+        assert_eq!(bob.decrypt(&second).unwrap(), (1_700_000_000, b"second".to_vec()));
+        assert_eq!(bob.counter(), 2);
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let mut rng = CounterRng(0x80);
+        let sealed = seal(&[0x01; 32], &mut rng, b"payload", b"aad").unwrap();
+
+        assert_eq!(open(&[0x01; 32], &sealed, b"aad").unwrap(), b"payload");
+        assert_eq!(open(&[0x02; 32], &sealed, b"aad"), Err(PortableError::Decryption));
+        assert_eq!(open(&[0x01; 32], &sealed, b"other"), Err(PortableError::Decryption));
+        assert_eq!(open(&[0x01; 32], &sealed[..8], b"aad"), Err(PortableError::Truncated));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_chain_step_matches_std_ratchet() {
+        let mut ratchet = crate::kdf::ChainRatchet::new([0x33; 32]);
+        assert_eq!(chain_step(&[0x33; 32]), ratchet.ratchet());
+    }
+}
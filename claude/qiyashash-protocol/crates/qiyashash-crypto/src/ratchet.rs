@@ -23,15 +23,43 @@ use zeroize::{Zeroize, ZeroizeOnDrop};
 use std::collections::HashMap;
 use rand::rngs::OsRng;
 
-use crate::aead::{Aead, AeadKey, EncryptedPayload};
+use crate::aead::{Aead, AeadAlgorithm, AeadKey, CounterNonces, EncryptedPayload, Nonce};
 use crate::error::{CryptoError, Result};
-use crate::kdf::{derive_message_keys, derive_root_and_chain_keys, ChainRatchet};
-use crate::keys::{PublicKeyBytes, SharedSecret};
+use crate::kdf::{
+    derive_message_keys, derive_root_and_chain_keys, domain, ChainRatchet, DerivedKey,
+    KeyDerivationContext,
+};
+use crate::keys::{is_weak_shared_secret, PublicKeyBytes, SharedSecret};
+use crate::wire::WireFrame;
 use crate::{MAX_CHAIN_LENGTH, MAX_MESSAGE_SIZE};
 
 /// Maximum number of skipped message keys to store
 const MAX_SKIP: usize = 1000;
 
+/// Fraction of a limit, in percent, past which [`RatchetHealth`] warns
+const NEAR_LIMIT_PERCENT: usize = 80;
+
+/// Diagnostic snapshot of a ratchet
+///
+/// A peer that keeps losing or reordering messages makes skipped keys pile
+/// up; once they pass `max_skipped_keys` the oldest are dropped and those
+/// messages can no longer be decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RatchetHealth {
+    /// Message keys held for messages that have not arrived
+    pub skipped_key_count: usize,
+    /// Most skipped keys kept before the oldest are evicted
+    pub max_skipped_keys: usize,
+    /// Messages sent or received since the last DH ratchet step
+    pub messages_since_dh_ratchet: u64,
+    /// Messages sent in the current sending chain
+    pub ns: u32,
+    /// Messages received in the current receiving chain
+    pub nr: u32,
+    /// Skipped keys or the sending chain are close to their limits
+    pub near_limit: bool,
+}
+
 /// Message header containing ratchet state information
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RatchetHeader {
@@ -39,19 +67,21 @@ pub struct RatchetHeader {
     pub dh_public: PublicKeyBytes,
     /// Message number in the sending chain
     pub message_number: u32,
-    /// Number of messages in previous sending chain
+    /// Number of messages in previous sending chain (or re-key epoch)
     pub previous_chain_length: u32,
+    /// Re-key epoch within the current sending chain
+    pub epoch: u32,
 }
 
 impl RatchetHeader {
-    /// Serialize to bytes
-    pub fn to_bytes(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("Header serialization should not fail")
+    /// Serialize to a versioned frame
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        WireFrame::encode(self)
     }
 
-    /// Deserialize from bytes
+    /// Deserialize from a versioned frame
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        bincode::deserialize(bytes).map_err(|e| CryptoError::Serialization(e.to_string()))
+        WireFrame::decode(bytes)
     }
 }
 
@@ -64,13 +94,74 @@ pub struct RatchetMessage {
     pub payload: EncryptedPayload,
 }
 
+impl RatchetMessage {
+    /// Serialize to a versioned frame
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        WireFrame::encode(self)
+    }
+
+    /// Deserialize from a versioned frame
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        WireFrame::decode(bytes)
+    }
+}
+
+/// Message key kept for a message that has not arrived yet
+///
+/// Wiped when dropped, whether it is used, evicted or discarded with the
+/// ratchet.
+struct SkippedKey([u8; 32]);
+
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        #[cfg(test)]
+        tests::record_dropped_skipped_key(&self.0);
+    }
+}
+
+/// Serialized form of a [`DoubleRatchet`], secret keys included
+#[derive(Serialize, Deserialize)]
+struct RatchetSnapshot {
+    dh_self: Option<[u8; 32]>,
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    chain_key_send: Option<[u8; 32]>,
+    chain_key_recv: Option<[u8; 32]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    send_epoch: u32,
+    recv_epoch: u32,
+    messages_since_dh_ratchet: u64,
+    skipped_keys: Vec<(PublicKeyBytes, u32, u32, [u8; 32])>,
+    algorithm: AeadAlgorithm,
+    send_nonce: u64,
+    associated_data: Vec<u8>,
+    session_id: [u8; 32],
+    created_at: i64,
+    message_count: u64,
+}
+
+impl Drop for RatchetSnapshot {
+    fn drop(&mut self) {
+        self.dh_self.zeroize();
+        self.root_key.zeroize();
+        self.chain_key_send.zeroize();
+        self.chain_key_recv.zeroize();
+        for (_, _, _, key) in &mut self.skipped_keys {
+            key.zeroize();
+        }
+    }
+}
+
 /// State of the Double Ratchet
 #[derive(ZeroizeOnDrop)]
 pub struct RatchetState {
-    /// Our current DH ratchet key pair
+    /// Our current DH ratchet key pair (`StaticSecret` wipes itself on drop)
     #[zeroize(skip)]
     dh_self: Option<X25519StaticSecret>,
-    /// Their current DH ratchet public key
+    /// Their current DH ratchet public key (not secret)
     #[zeroize(skip)]
     dh_remote: Option<X25519PublicKey>,
     /// Root key
@@ -85,9 +176,26 @@ pub struct RatchetState {
     nr: u32,
     /// Previous chain length (for header)
     pn: u32,
-    /// Skipped message keys: (ratchet_public, message_number) -> message_key
+    /// Re-key epoch of the sending chain
+    send_epoch: u32,
+    /// Re-key epoch of the receiving chain
+    recv_epoch: u32,
+    /// Messages sent or received since the last DH ratchet step
+    messages_since_dh_ratchet: u64,
+    /// Skipped message keys: (ratchet_public, epoch, message_number) -> message_key
+    ///
+    /// Skipped by the derive because each [`SkippedKey`] wipes itself.
+    #[zeroize(skip)]
+    skipped_keys: HashMap<(PublicKeyBytes, u32, u32), SkippedKey>,
+    /// AEAD used for outgoing messages
     #[zeroize(skip)]
-    skipped_keys: HashMap<(PublicKeyBytes, u32), [u8; 32]>,
+    algorithm: AeadAlgorithm,
+    /// Counter nonces used in the current sending chain
+    #[zeroize(skip)]
+    send_nonces: CounterNonces,
+    /// Session-level data authenticated with every message
+    #[zeroize(skip)]
+    associated_data: Vec<u8>,
 }
 
 impl RatchetState {
@@ -100,12 +208,19 @@ impl RatchetState {
         shared_secret: &[u8; 32],
         their_ratchet_public: &X25519PublicKey,
     ) -> Result<Self> {
+        if is_weak_shared_secret(shared_secret) {
+            return Err(CryptoError::WeakSharedSecret);
+        }
+
         // Generate our first ratchet key pair
         let dh_self = X25519StaticSecret::random_from_rng(OsRng);
         let dh_public = X25519PublicKey::from(&dh_self);
         
         // Perform DH ratchet step
         let dh_output = dh_self.diffie_hellman(their_ratchet_public);
+        if is_weak_shared_secret(dh_output.as_bytes()) {
+            return Err(CryptoError::WeakSharedSecret);
+        }
         let (root_key, chain_key_send) = 
             derive_root_and_chain_keys(shared_secret, dh_output.as_bytes())?;
         
@@ -118,7 +233,13 @@ impl RatchetState {
             ns: 0,
             nr: 0,
             pn: 0,
+            send_epoch: 0,
+            recv_epoch: 0,
+            messages_since_dh_ratchet: 0,
             skipped_keys: HashMap::new(),
+            algorithm: AeadAlgorithm::default(),
+            send_nonces: CounterNonces::new(),
+            associated_data: Vec::new(),
         })
     }
 
@@ -130,8 +251,12 @@ impl RatchetState {
     pub fn init_bob(
         shared_secret: &[u8; 32],
         our_ratchet_secret: X25519StaticSecret,
-    ) -> Self {
-        Self {
+    ) -> Result<Self> {
+        if is_weak_shared_secret(shared_secret) {
+            return Err(CryptoError::WeakSharedSecret);
+        }
+
+        Ok(Self {
             dh_self: Some(our_ratchet_secret),
             dh_remote: None,
             root_key: *shared_secret,
@@ -140,8 +265,37 @@ impl RatchetState {
             ns: 0,
             nr: 0,
             pn: 0,
+            send_epoch: 0,
+            recv_epoch: 0,
+            messages_since_dh_ratchet: 0,
             skipped_keys: HashMap::new(),
-        }
+            algorithm: AeadAlgorithm::default(),
+            send_nonces: CounterNonces::new(),
+            associated_data: Vec::new(),
+        })
+    }
+
+    /// Select the AEAD used for outgoing messages
+    ///
+    /// With [`AeadAlgorithm::Aes256GcmCounter`] the nonce is the message
+    /// number, which never repeats within a sending chain; every DH ratchet
+    /// step starts a new chain key and resets the counter.
+    pub fn set_algorithm(&mut self, algorithm: AeadAlgorithm) {
+        self.algorithm = algorithm;
+    }
+
+    /// Authenticate `associated_data` with every message
+    ///
+    /// Both sides must set the same value or every message fails to decrypt.
+    pub fn set_associated_data(&mut self, associated_data: Vec<u8>) {
+        self.associated_data = associated_data;
+    }
+
+    /// Header bytes followed by the session associated data
+    fn message_associated_data(&self, header: &RatchetHeader) -> Result<Vec<u8>> {
+        let mut associated_data = header.to_bytes()?;
+        associated_data.extend_from_slice(&self.associated_data);
+        Ok(associated_data)
     }
 
     /// Get our current DH ratchet public key
@@ -179,35 +333,78 @@ impl RatchetState {
                 .ok_or_else(|| CryptoError::RatchetCorrupted("No DH key".to_string()))?,
             message_number: self.ns,
             previous_chain_length: self.pn,
+            epoch: self.send_epoch,
         };
 
         // Encrypt with AEAD
-        let aead = Aead::new();
+        let aead = Aead::with_algorithm(self.algorithm);
         let aead_key = AeadKey::from_bytes(message_key);
-        let associated_data = header.to_bytes();
-        let payload = aead.encrypt(&aead_key, plaintext, &associated_data)?;
+        let associated_data = self.message_associated_data(&header)?;
+        let payload = match self.algorithm {
+            AeadAlgorithm::Aes256GcmCounter => aead.encrypt_with_counter(
+                &aead_key,
+                &mut self.send_nonces,
+                u64::from(self.ns),
+                plaintext,
+                &associated_data,
+            )?,
+            _ => aead.encrypt(&aead_key, plaintext, &associated_data)?,
+        };
 
         self.ns += 1;
 
         Ok(RatchetMessage { header, payload })
     }
 
+    /// Number of messages sent in the current sending chain
+    pub fn sending_chain_length(&self) -> u32 {
+        self.ns
+    }
+
+    /// Replace the sending chain before it reaches [`MAX_CHAIN_LENGTH`]
+    ///
+    /// A DH step cannot be taken unilaterally: the peer may already have
+    /// rotated away from the ratchet key we last saw. Instead the next chain
+    /// key is derived one-way from the current one and the epoch in the
+    /// header is bumped, so the receiver can follow without a round trip.
+    /// Returns the new epoch.
+    pub fn rekey_sending_chain(&mut self) -> Result<u32> {
+        let chain_key = self.chain_key_send
+            .ok_or_else(|| CryptoError::RatchetCorrupted("No sending chain key".to_string()))?;
+
+        self.chain_key_send = Some(Self::next_epoch_key(&chain_key)?);
+        self.send_epoch += 1;
+        self.pn = self.ns;
+        self.ns = 0;
+        self.send_nonces = CounterNonces::new();
+
+        Ok(self.send_epoch)
+    }
+
     /// Decrypt a message
     pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
         // Try skipped keys first
-        let header_key = (message.header.dh_public.clone(), message.header.message_number);
+        let header_key = (
+            message.header.dh_public.clone(),
+            message.header.epoch,
+            message.header.message_number,
+        );
         if let Some(message_key) = self.skipped_keys.remove(&header_key) {
-            return self.decrypt_with_key(&message_key, message);
+            return self.decrypt_with_key(&message_key.0, message);
         }
 
         // Check if we need to perform DH ratchet
-        let their_public = message.header.dh_public.to_x25519();
+        let their_public = message.header.dh_public.to_x25519()?;
         
         let need_ratchet = match &self.dh_remote {
             None => true,
             Some(current) => current.as_bytes() != their_public.as_bytes(),
         };
 
+        // Header counters are attacker-controlled: bound every skip before
+        // the state changes, so a rejected header can't leave it half-updated
+        self.check_skip_bounds(&message.header, need_ratchet)?;
+
         if need_ratchet {
             // Skip any remaining messages from previous chain
             self.skip_message_keys(message.header.previous_chain_length)?;
@@ -216,6 +413,23 @@ impl RatchetState {
             self.dh_ratchet(&their_public)?;
         }
 
+        if message.header.epoch != self.recv_epoch {
+            // Only the next epoch can be followed; anything else was either
+            // already skipped or lost with its predecessor
+            if message.header.epoch != self.recv_epoch + 1 {
+                return Err(CryptoError::RatchetCorrupted(format!(
+                    "Unexpected re-key epoch {} (current {})",
+                    message.header.epoch, self.recv_epoch
+                )));
+            }
+            self.skip_message_keys(message.header.previous_chain_length)?;
+            let chain_key = self.chain_key_recv
+                .ok_or_else(|| CryptoError::RatchetCorrupted("No receiving chain key".to_string()))?;
+            self.chain_key_recv = Some(Self::next_epoch_key(&chain_key)?);
+            self.recv_epoch += 1;
+            self.nr = 0;
+        }
+
         // Skip any messages in current chain
         self.skip_message_keys(message.header.message_number)?;
 
@@ -232,17 +446,29 @@ impl RatchetState {
 
     /// Decrypt with a specific message key
     fn decrypt_with_key(&self, message_key: &[u8; 32], message: &RatchetMessage) -> Result<Vec<u8>> {
+        // A counter-mode nonce must be the message number it claims to be
+        if message.payload.algorithm == AeadAlgorithm::Aes256GcmCounter {
+            let expected = Nonce::counter_aes_gcm(u64::from(message.header.message_number));
+            if message.payload.nonce.as_bytes() != expected.as_bytes() {
+                return Err(CryptoError::DecryptionFailed(
+                    "Counter nonce does not match message number".to_string(),
+                ));
+            }
+        }
         let aead = Aead::new();
         let aead_key = AeadKey::from_bytes(*message_key);
-        let associated_data = message.header.to_bytes();
+        let associated_data = self.message_associated_data(&message.header)?;
         aead.decrypt(&aead_key, &message.payload, &associated_data)
     }
 
     /// Perform DH ratchet step
     fn dh_ratchet(&mut self, their_public: &X25519PublicKey) -> Result<()> {
+        self.messages_since_dh_ratchet = 0;
         self.pn = self.ns;
         self.ns = 0;
         self.nr = 0;
+        self.send_epoch = 0;
+        self.recv_epoch = 0;
         self.dh_remote = Some(*their_public);
 
         // Derive new receiving chain
@@ -264,17 +490,48 @@ impl RatchetState {
         
         self.root_key = new_root_key;
         self.chain_key_send = Some(chain_key_send);
+        self.send_nonces = CounterNonces::new();
         self.dh_self = Some(new_dh_self);
 
         Ok(())
     }
 
+    /// Chain key for the epoch following `chain_key`
+    fn next_epoch_key(chain_key: &[u8; 32]) -> Result<[u8; 32]> {
+        let next: DerivedKey<32> =
+            KeyDerivationContext::new(None, chain_key).derive(domain::REKEY)?;
+        Ok(next.into_bytes())
+    }
+
+    /// Check the skips `header` would cause against [`MAX_SKIP`]
+    fn check_skip_bounds(&self, header: &RatchetHeader, need_ratchet: bool) -> Result<()> {
+        let (mut nr, epoch) = if need_ratchet {
+            if self.chain_key_recv.is_some() {
+                Self::check_gap(self.nr, header.previous_chain_length)?;
+            }
+            (0, 0)
+        } else {
+            (self.nr, self.recv_epoch)
+        };
+
+        if header.epoch != epoch {
+            Self::check_gap(nr, header.previous_chain_length)?;
+            nr = 0;
+        }
+        Self::check_gap(nr, header.message_number)
+    }
+
+    fn check_gap(from: u32, until: u32) -> Result<()> {
+        if until as usize > from as usize + MAX_SKIP {
+            return Err(CryptoError::MessageGapTooLarge { gap: until - from });
+        }
+        Ok(())
+    }
+
     /// Skip message keys (for out-of-order messages)
     fn skip_message_keys(&mut self, until: u32) -> Result<()> {
         if let Some(mut chain_key) = self.chain_key_recv {
-            if (until as usize) > self.nr as usize + MAX_SKIP {
-                return Err(CryptoError::MessageGapTooLarge { gap: until - self.nr });
-            }
+            Self::check_gap(self.nr, until)?;
 
             let their_public = self.dh_remote
                 .map(|pk| PublicKeyBytes::from_x25519(&pk))
@@ -285,8 +542,8 @@ impl RatchetState {
                 chain_key = new_chain_key;
                 
                 // Store skipped key
-                let key = (their_public.clone(), self.nr);
-                self.skipped_keys.insert(key, message_key);
+                let key = (their_public.clone(), self.recv_epoch, self.nr);
+                self.skipped_keys.insert(key, SkippedKey(message_key));
                 
                 // Limit stored keys
                 if self.skipped_keys.len() > MAX_SKIP {
@@ -305,6 +562,22 @@ impl RatchetState {
         Ok(())
     }
 
+    /// Diagnostic snapshot of the ratchet
+    pub fn health(&self) -> RatchetHealth {
+        let near = |value: usize, limit: usize| value * 100 >= limit * NEAR_LIMIT_PERCENT;
+        let skipped_key_count = self.skipped_keys.len();
+
+        RatchetHealth {
+            skipped_key_count,
+            max_skipped_keys: MAX_SKIP,
+            messages_since_dh_ratchet: self.messages_since_dh_ratchet,
+            ns: self.ns,
+            nr: self.nr,
+            near_limit: near(skipped_key_count, MAX_SKIP)
+                || near(self.ns as usize, MAX_CHAIN_LENGTH as usize),
+        }
+    }
+
     /// Get the current state for serialization (without sensitive keys)
     pub fn state_fingerprint(&self) -> [u8; 32] {
         use sha2::{Sha256, Digest};
@@ -360,21 +633,34 @@ impl DoubleRatchet {
         shared_secret: &[u8; 32],
         our_ratchet_secret: X25519StaticSecret,
         session_id: [u8; 32],
-    ) -> Self {
-        let state = RatchetState::init_bob(shared_secret, our_ratchet_secret);
+    ) -> Result<Self> {
+        let state = RatchetState::init_bob(shared_secret, our_ratchet_secret)?;
         
-        Self {
+        Ok(Self {
             state,
             session_id,
             created_at: chrono::Utc::now().timestamp(),
             message_count: 0,
-        }
+        })
+    }
+
+    /// Use `algorithm` for outgoing messages
+    pub fn with_algorithm(mut self, algorithm: AeadAlgorithm) -> Self {
+        self.state.set_algorithm(algorithm);
+        self
+    }
+
+    /// Authenticate `associated_data` with every message
+    pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+        self.state.set_associated_data(associated_data);
+        self
     }
 
     /// Encrypt a message
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<RatchetMessage> {
         let message = self.state.encrypt(plaintext)?;
         self.message_count += 1;
+        self.state.messages_since_dh_ratchet += 1;
         Ok(message)
     }
 
@@ -382,6 +668,7 @@ impl DoubleRatchet {
     pub fn decrypt(&mut self, message: &RatchetMessage) -> Result<Vec<u8>> {
         let plaintext = self.state.decrypt(message)?;
         self.message_count += 1;
+        self.state.messages_since_dh_ratchet += 1;
         Ok(plaintext)
     }
 
@@ -399,11 +686,109 @@ impl DoubleRatchet {
     pub fn current_ratchet_public(&self) -> Option<PublicKeyBytes> {
         self.state.dh_public()
     }
+
+    /// Number of messages sent in the current sending chain
+    pub fn sending_chain_length(&self) -> u32 {
+        self.state.sending_chain_length()
+    }
+
+    /// Start a new sending chain epoch, returning its number
+    pub fn rekey_sending_chain(&mut self) -> Result<u32> {
+        self.state.rekey_sending_chain()
+    }
+
+    /// Diagnostic snapshot of the ratchet
+    pub fn health(&self) -> RatchetHealth {
+        self.state.health()
+    }
+
+    /// Serialize the whole ratchet, secret keys included
+    ///
+    /// Store the output encrypted. The ratchet restored from it with
+    /// [`DoubleRatchet::import_state`] must replace this one: carrying on
+    /// with both would reuse message keys.
+    pub fn export_state(&self) -> Result<Vec<u8>> {
+        let state = &self.state;
+        let snapshot = RatchetSnapshot {
+            dh_self: state.dh_self.as_ref().map(X25519StaticSecret::to_bytes),
+            dh_remote: state.dh_remote.map(|pk| pk.to_bytes()),
+            root_key: state.root_key,
+            chain_key_send: state.chain_key_send,
+            chain_key_recv: state.chain_key_recv,
+            ns: state.ns,
+            nr: state.nr,
+            pn: state.pn,
+            send_epoch: state.send_epoch,
+            recv_epoch: state.recv_epoch,
+            messages_since_dh_ratchet: state.messages_since_dh_ratchet,
+            skipped_keys: state
+                .skipped_keys
+                .iter()
+                .map(|((public, epoch, number), key)| (public.clone(), *epoch, *number, key.0))
+                .collect(),
+            algorithm: state.algorithm,
+            send_nonce: state.send_nonces.next(),
+            associated_data: state.associated_data.clone(),
+            session_id: self.session_id,
+            created_at: self.created_at,
+            message_count: self.message_count,
+        };
+        WireFrame::encode(&snapshot)
+    }
+
+    /// Restore a ratchet from [`DoubleRatchet::export_state`]
+    pub fn import_state(bytes: &[u8]) -> Result<Self> {
+        let snapshot: RatchetSnapshot = WireFrame::decode(bytes)?;
+        let state = RatchetState {
+            dh_self: snapshot.dh_self.map(X25519StaticSecret::from),
+            dh_remote: snapshot.dh_remote.map(X25519PublicKey::from),
+            root_key: snapshot.root_key,
+            chain_key_send: snapshot.chain_key_send,
+            chain_key_recv: snapshot.chain_key_recv,
+            ns: snapshot.ns,
+            nr: snapshot.nr,
+            pn: snapshot.pn,
+            send_epoch: snapshot.send_epoch,
+            recv_epoch: snapshot.recv_epoch,
+            messages_since_dh_ratchet: snapshot.messages_since_dh_ratchet,
+            skipped_keys: snapshot
+                .skipped_keys
+                .iter()
+                .map(|(public, epoch, number, key)| {
+                    ((public.clone(), *epoch, *number), SkippedKey(*key))
+                })
+                .collect(),
+            algorithm: snapshot.algorithm,
+            send_nonces: CounterNonces::starting_at(snapshot.send_nonce),
+            associated_data: snapshot.associated_data.clone(),
+        };
+
+        Ok(Self {
+            state,
+            session_id: snapshot.session_id,
+            created_at: snapshot.created_at,
+            message_count: snapshot.message_count,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// Contents of every skipped key buffer at the moment it was dropped
+        static DROPPED_SKIPPED_KEYS: RefCell<Vec<[u8; 32]>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(super) fn record_dropped_skipped_key(key: &[u8; 32]) {
+        DROPPED_SKIPPED_KEYS.with(|keys| keys.borrow_mut().push(*key));
+    }
+
+    fn take_dropped_skipped_keys() -> Vec<[u8; 32]> {
+        DROPPED_SKIPPED_KEYS.with(|keys| std::mem::take(&mut *keys.borrow_mut()))
+    }
 
     fn create_test_session() -> (DoubleRatchet, DoubleRatchet) {
         let shared_secret = [0x42u8; 32];
@@ -423,7 +808,7 @@ mod tests {
             &shared_secret,
             bob_ratchet_secret,
             session_id,
-        );
+        ).unwrap();
         
         (alice, bob)
     }
@@ -562,6 +947,28 @@ mod tests {
         assert_eq!(large_plaintext, decrypted);
     }
 
+    #[test]
+    fn test_weak_shared_secret_rejected() {
+        let bob_secret = X25519StaticSecret::random_from_rng(OsRng);
+        let bob_public = X25519PublicKey::from(&bob_secret);
+
+        assert!(matches!(
+            RatchetState::init_alice(&[0u8; 32], &bob_public),
+            Err(CryptoError::WeakSharedSecret)
+        ));
+        assert!(matches!(
+            RatchetState::init_bob(&[0u8; 32], bob_secret),
+            Err(CryptoError::WeakSharedSecret)
+        ));
+
+        // A low-order ratchet key zeroes the first DH output
+        let low_order = X25519PublicKey::from([0u8; 32]);
+        assert!(matches!(
+            RatchetState::init_alice(&[0x42; 32], &low_order),
+            Err(CryptoError::WeakSharedSecret)
+        ));
+    }
+
     #[test]
     fn test_empty_message() {
         let (mut alice, mut bob) = create_test_session();
@@ -571,4 +978,235 @@ mod tests {
         
         assert!(decrypted.is_empty());
     }
+
+    #[test]
+    fn test_counter_mode_nonces_follow_message_number() {
+        let (alice, mut bob) = create_test_session();
+        let mut alice = alice.with_algorithm(AeadAlgorithm::Aes256GcmCounter);
+
+        let mut nonces = std::collections::HashSet::new();
+        for i in 0..50u32 {
+            let encrypted = alice.encrypt(format!("msg {}", i).as_bytes()).unwrap();
+            assert_eq!(encrypted.payload.algorithm, AeadAlgorithm::Aes256GcmCounter);
+            assert_eq!(
+                encrypted.payload.nonce.as_bytes(),
+                Nonce::counter_aes_gcm(u64::from(encrypted.header.message_number)).as_bytes()
+            );
+            assert!(nonces.insert(encrypted.payload.nonce.as_bytes().to_vec()));
+            bob.decrypt(&encrypted).unwrap();
+        }
+
+        // New sending chain after a round trip starts counting again
+        let reply = bob.encrypt(b"reply").unwrap();
+        alice.decrypt(&reply).unwrap();
+        let next = alice.encrypt(b"next chain").unwrap();
+        assert_eq!(next.header.message_number, 0);
+        assert_eq!(bob.decrypt(&next).unwrap(), b"next chain");
+    }
+
+    #[test]
+    fn test_counter_mode_rejects_mismatched_nonce() {
+        let (alice, mut bob) = create_test_session();
+        let mut alice = alice.with_algorithm(AeadAlgorithm::Aes256GcmCounter);
+
+        let mut encrypted = alice.encrypt(b"hello").unwrap();
+        encrypted.payload.nonce = Nonce::counter_aes_gcm(7);
+        assert!(matches!(
+            bob.decrypt(&encrypted),
+            Err(CryptoError::DecryptionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_rekey_sending_chain() {
+        let (mut alice, mut bob) = create_test_session();
+
+        let before = alice.encrypt(b"before").unwrap();
+        let skipped = alice.encrypt(b"skipped").unwrap();
+        bob.decrypt(&before).unwrap();
+
+        assert_eq!(alice.rekey_sending_chain().unwrap(), 1);
+        assert_eq!(alice.sending_chain_length(), 0);
+        let after = alice.encrypt(b"after").unwrap();
+        assert_eq!(after.header.epoch, 1);
+        assert_eq!(after.header.message_number, 0);
+
+        assert_eq!(bob.decrypt(&after).unwrap(), b"after");
+        // The tail of the previous epoch is still readable
+        assert_eq!(bob.decrypt(&skipped).unwrap(), b"skipped");
+
+        // A DH step afterwards starts both sides at epoch 0 again
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(reply.header.epoch, 0);
+        alice.decrypt(&reply).unwrap();
+        let next = alice.encrypt(b"next").unwrap();
+        assert_eq!(next.header.epoch, 0);
+        assert_eq!(bob.decrypt(&next).unwrap(), b"next");
+    }
+
+    #[test]
+    fn test_rejects_epoch_gap() {
+        let (mut alice, mut bob) = create_test_session();
+
+        bob.decrypt(&alice.encrypt(b"first").unwrap()).unwrap();
+        alice.rekey_sending_chain().unwrap();
+        alice.rekey_sending_chain().unwrap();
+        let msg = alice.encrypt(b"too far").unwrap();
+        assert!(matches!(
+            bob.decrypt(&msg),
+            Err(CryptoError::RatchetCorrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_huge_skips_before_ratcheting() {
+        let (mut alice, mut bob) = create_test_session();
+        bob.decrypt(&alice.encrypt(b"first").unwrap()).unwrap();
+        alice.decrypt(&bob.encrypt(b"reply").unwrap()).unwrap();
+
+        // Alice's next message carries a new DH key, so Bob must ratchet
+        let next = alice.encrypt(b"next").unwrap();
+        let before = bob.health();
+
+        let mut forged = next.clone();
+        forged.header.previous_chain_length = u32::MAX;
+        assert!(matches!(
+            bob.decrypt(&forged),
+            Err(CryptoError::MessageGapTooLarge { .. })
+        ));
+
+        let mut forged = next.clone();
+        forged.header.message_number = u32::MAX;
+        assert!(matches!(
+            bob.decrypt(&forged),
+            Err(CryptoError::MessageGapTooLarge { .. })
+        ));
+
+        let mut forged = next.clone();
+        forged.header.epoch = 1;
+        forged.header.previous_chain_length = u32::MAX;
+        assert!(matches!(
+            bob.decrypt(&forged),
+            Err(CryptoError::MessageGapTooLarge { .. })
+        ));
+
+        // Nothing was skipped or ratcheted by the rejected headers
+        assert_eq!(bob.health(), before);
+        assert_eq!(bob.decrypt(&next).unwrap(), b"next");
+    }
+
+    #[test]
+    fn test_export_import_state() {
+        let (alice, mut bob) = create_test_session();
+        let mut alice = alice
+            .with_algorithm(AeadAlgorithm::Aes256GcmCounter)
+            .with_associated_data(b"ad".to_vec());
+        bob = bob.with_associated_data(b"ad".to_vec());
+
+        let skipped = alice.encrypt(b"skipped").unwrap();
+        bob.decrypt(&alice.encrypt(b"second").unwrap()).unwrap();
+
+        let mut alice = DoubleRatchet::import_state(&alice.export_state().unwrap()).unwrap();
+        let mut bob = DoubleRatchet::import_state(&bob.export_state().unwrap()).unwrap();
+        assert_eq!(bob.health().skipped_key_count, 1);
+        assert_eq!(bob.decrypt(&skipped).unwrap(), b"skipped");
+
+        // The restored sender keeps counting where it left off
+        let third = alice.encrypt(b"third").unwrap();
+        assert_eq!(third.header.message_number, 2);
+        assert_eq!(bob.decrypt(&third).unwrap(), b"third");
+        alice.decrypt(&bob.encrypt(b"reply").unwrap()).unwrap();
+        assert_eq!(alice.message_count, 4);
+    }
+
+    #[test]
+    fn test_associated_data_must_match() {
+        let (alice, bob) = create_test_session();
+        let mut alice = alice.with_associated_data(b"v2".to_vec());
+        let mut bob = bob.with_associated_data(b"v1".to_vec());
+
+        let encrypted = alice.encrypt(b"hello").unwrap();
+        assert!(bob.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_skipped_keys_wiped_on_drop() {
+        let (mut alice, mut bob) = create_test_session();
+        take_dropped_skipped_keys();
+
+        let _lost = alice.encrypt(b"0").unwrap();
+        let used = alice.encrypt(b"1").unwrap();
+        let _also_lost = alice.encrypt(b"2").unwrap();
+        let last = alice.encrypt(b"3").unwrap();
+        bob.decrypt(&last).unwrap();
+        assert_eq!(bob.state.skipped_keys.len(), 3);
+
+        // A consumed key is wiped as soon as it is used
+        bob.decrypt(&used).unwrap();
+        let dropped = take_dropped_skipped_keys();
+        assert_eq!(dropped, vec![[0u8; 32]]);
+
+        // The rest go with the ratchet
+        drop(bob);
+        let dropped = take_dropped_skipped_keys();
+        assert_eq!(dropped.len(), 2);
+        assert!(dropped.iter().all(|key| key == &[0u8; 32]));
+    }
+
+    #[test]
+    fn test_health_tracks_skipped_keys() {
+        let (mut alice, mut bob) = create_test_session();
+        let fresh = bob.health();
+        assert_eq!(fresh.skipped_key_count, 0);
+        assert!(!fresh.near_limit);
+
+        let messages: Vec<_> = (0..900)
+            .map(|i| alice.encrypt(format!("Message {}", i).as_bytes()).unwrap())
+            .collect();
+        let sender = alice.health();
+        assert_eq!((sender.ns, sender.messages_since_dh_ratchet), (900, 900));
+        assert!(sender.near_limit);
+
+        // Only the last one arrives; every earlier key is held back
+        bob.decrypt(&messages[899]).unwrap();
+        let health = bob.health();
+        assert_eq!(health.skipped_key_count, 899);
+        assert_eq!(health.skipped_key_count, bob.state.skipped_keys.len());
+        assert_eq!(health.nr, 900);
+        assert_eq!(health.messages_since_dh_ratchet, 1);
+        assert!(health.near_limit);
+
+        // Late arrivals use up their skipped keys
+        for message in &messages[..200] {
+            bob.decrypt(message).unwrap();
+        }
+        let health = bob.health();
+        assert_eq!(health.skipped_key_count, 699);
+        assert_eq!(health.messages_since_dh_ratchet, 201);
+        assert!(!health.near_limit);
+
+        // Replying starts a new DH ratchet on the other side
+        let reply = bob.encrypt(b"caught up").unwrap();
+        alice.decrypt(&reply).unwrap();
+        let sender = alice.health();
+        assert_eq!((sender.ns, sender.messages_since_dh_ratchet), (0, 1));
+        assert!(!sender.near_limit);
+    }
+
+    #[test]
+    fn test_ratchet_message_framing() {
+        let (mut alice, mut bob) = create_test_session();
+
+        let bytes = alice.encrypt(b"framed").unwrap().to_bytes().unwrap();
+        assert!(RatchetMessage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut bumped = bytes.clone();
+        bumped[0] += 1;
+        assert!(matches!(
+            RatchetMessage::from_bytes(&bumped),
+            Err(CryptoError::Serialization(_))
+        ));
+
+        let decoded = RatchetMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(bob.decrypt(&decoded).unwrap(), b"framed");
+    }
 }
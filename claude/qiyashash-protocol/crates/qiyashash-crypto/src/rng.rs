@@ -0,0 +1,38 @@
+//! Randomness for keys, nonces and salts
+//!
+//! Secret or must-be-unique values come from [`secure_rng`], so there is one
+//! place to audit the source and, if a platform ever needs it, to replace it.
+//! Randomness that only has to look random, such as decoy shuffling, may use
+//! whatever is convenient.
+
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+
+/// Generator for keys, nonces and salts
+///
+/// Reads the operating system's CSPRNG directly, so there is no userspace
+/// state to be copied by a fork or left in memory.
+pub fn secure_rng() -> impl RngCore + CryptoRng {
+    OsRng
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_crypto_rng<R: RngCore + CryptoRng>(_rng: &R) {}
+
+    #[test]
+    fn test_secure_rng_is_crypto_rng() {
+        let mut rng = secure_rng();
+        assert_crypto_rng(&rng);
+
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        rng.fill_bytes(&mut first);
+        secure_rng().fill_bytes(&mut second);
+        assert_ne!(first, second);
+        assert_ne!(first, [0u8; 32]);
+        assert_ne!(secure_rng().next_u64(), secure_rng().next_u64());
+    }
+}
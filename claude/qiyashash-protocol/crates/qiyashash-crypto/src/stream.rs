@@ -0,0 +1,170 @@
+//! Chunked AEAD for payloads too large to hold in memory
+//!
+//! Follows the STREAM construction: every chunk is sealed with
+//! XChaCha20-Poly1305 under a nonce made of a random per-stream prefix, the
+//! big-endian chunk counter and a flag byte that is 1 only for the final
+//! chunk. Reordered, dropped or duplicated chunks fail authentication, and
+//! so does a stream cut off before its final chunk.
+
+use chacha20poly1305::aead::{Aead as _, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+
+use crate::aead::{AeadKey, XCHACHA_NONCE_SIZE};
+use crate::error::{CryptoError, Result};
+use crate::rng::secure_rng;
+
+/// Size of the random part of each chunk nonce
+pub const NONCE_PREFIX_SIZE: usize = XCHACHA_NONCE_SIZE - 4 - 1;
+
+/// Plaintext bytes per chunk unless the caller chooses otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Nonce of chunk `counter`
+fn chunk_nonce(
+    prefix: &[u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    last: bool,
+) -> [u8; XCHACHA_NONCE_SIZE] {
+    let mut nonce = [0u8; XCHACHA_NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..XCHACHA_NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[XCHACHA_NONCE_SIZE - 1] = u8::from(last);
+    nonce
+}
+
+/// Seals a stream one chunk at a time
+pub struct StreamEncryptor {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+}
+
+impl StreamEncryptor {
+    /// Start a stream under `key` with a random nonce prefix
+    pub fn new(key: &AeadKey) -> Self {
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        secure_rng().fill_bytes(&mut prefix);
+        Self {
+            cipher: XChaCha20Poly1305::new(key.as_bytes().into()),
+            prefix,
+            counter: 0,
+        }
+    }
+
+    /// Nonce prefix the decryptor needs
+    pub fn nonce_prefix(&self) -> [u8; NONCE_PREFIX_SIZE] {
+        self.prefix
+    }
+
+    /// Seal a chunk that is followed by more
+    pub fn encrypt_next(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let sealed = self.seal(chunk, false)?;
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            CryptoError::EncryptionFailed("Stream has too many chunks".to_string())
+        })?;
+        Ok(sealed)
+    }
+
+    /// Seal the final chunk, ending the stream
+    pub fn encrypt_last(self, chunk: &[u8]) -> Result<Vec<u8>> {
+        self.seal(chunk, true)
+    }
+
+    fn seal(&self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.cipher
+            .encrypt(XNonce::from_slice(&nonce), chunk)
+            .map_err(|_| CryptoError::EncryptionFailed("XChaCha20-Poly1305 failed".to_string()))
+    }
+}
+
+/// Opens a stream sealed by [`StreamEncryptor`], in order
+pub struct StreamDecryptor {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+}
+
+impl StreamDecryptor {
+    /// Open a stream under `key` with the encryptor's nonce prefix
+    pub fn new(key: &AeadKey, prefix: [u8; NONCE_PREFIX_SIZE]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.as_bytes().into()),
+            prefix,
+            counter: 0,
+        }
+    }
+
+    /// Open a chunk that is followed by more
+    ///
+    /// Fails on the final chunk, so a stream cannot be extended past it.
+    pub fn decrypt_next(&mut self, chunk: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = self.open(chunk, false)?;
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            CryptoError::DecryptionFailed("Stream has too many chunks".to_string())
+        })?;
+        Ok(plaintext)
+    }
+
+    /// Open the final chunk
+    ///
+    /// Fails unless `chunk` was sealed as the final one, which is how a
+    /// truncated stream is detected.
+    pub fn decrypt_last(self, chunk: &[u8]) -> Result<Vec<u8>> {
+        self.open(chunk, true)
+    }
+
+    fn open(&self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.cipher
+            .decrypt(XNonce::from_slice(&nonce), chunk)
+            .map_err(|_| CryptoError::AuthenticationFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal_all(key: &AeadKey, chunks: &[&[u8]]) -> ([u8; NONCE_PREFIX_SIZE], Vec<Vec<u8>>) {
+        let mut encryptor = StreamEncryptor::new(key);
+        let prefix = encryptor.nonce_prefix();
+        let (last, rest) = chunks.split_last().unwrap();
+        let mut sealed: Vec<_> = rest
+            .iter()
+            .map(|c| encryptor.encrypt_next(c).unwrap())
+            .collect();
+        sealed.push(encryptor.encrypt_last(last).unwrap());
+        (prefix, sealed)
+    }
+
+    #[test]
+    fn test_stream_detects_truncation_and_reordering() {
+        let key = AeadKey::from_bytes([0x24; 32]);
+        let (prefix, sealed) = seal_all(&key, &[b"one", b"two", b"three"]);
+
+        let mut decryptor = StreamDecryptor::new(&key, prefix);
+        assert_eq!(decryptor.decrypt_next(&sealed[0]).unwrap(), b"one");
+        assert_eq!(decryptor.decrypt_next(&sealed[1]).unwrap(), b"two");
+        assert_eq!(decryptor.decrypt_last(&sealed[2]).unwrap(), b"three");
+
+        // Cut short: the last chunk present was not sealed as final
+        let mut decryptor = StreamDecryptor::new(&key, prefix);
+        decryptor.decrypt_next(&sealed[0]).unwrap();
+        assert!(matches!(
+            decryptor.decrypt_last(&sealed[1]),
+            Err(CryptoError::AuthenticationFailed)
+        ));
+
+        // Out of order
+        let mut decryptor = StreamDecryptor::new(&key, prefix);
+        assert!(decryptor.decrypt_next(&sealed[1]).is_err());
+
+        // Nothing may follow the final chunk
+        let mut decryptor = StreamDecryptor::new(&key, prefix);
+        decryptor.decrypt_next(&sealed[0]).unwrap();
+        decryptor.decrypt_next(&sealed[1]).unwrap();
+        assert!(decryptor.decrypt_next(&sealed[2]).is_err());
+    }
+}
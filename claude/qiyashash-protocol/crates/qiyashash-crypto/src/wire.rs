@@ -0,0 +1,151 @@
+//! Versioned framing for wire types
+//!
+//! Every type that crosses the wire is encoded as
+//!
+//! ```text
+//! version (1 byte) || length (4 bytes, big endian) || bincode(value)
+//! ```
+//!
+//! The version lets a later format be told apart from this one instead of
+//! being misparsed, and the length is checked against the input before any
+//! decoding happens, so truncated or padded frames are rejected up front.
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{CryptoError, Result};
+
+/// Current wire format version
+pub const WIRE_FORMAT_VERSION: u8 = 1;
+
+/// Largest payload a frame may carry (1 MB)
+pub const MAX_FRAME_PAYLOAD: usize = 1024 * 1024;
+
+/// Version byte plus length
+const FRAME_HEADER_LEN: usize = 1 + 4;
+
+/// Encoder and decoder for versioned frames
+pub struct WireFrame;
+
+impl WireFrame {
+    /// Encode `value` as a frame
+    pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(value)
+            .map_err(|e| CryptoError::Serialization(e.to_string()))?;
+        if payload.len() > MAX_FRAME_PAYLOAD {
+            return Err(CryptoError::Serialization(format!(
+                "frame payload of {} bytes exceeds {}",
+                payload.len(),
+                MAX_FRAME_PAYLOAD
+            )));
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.push(WIRE_FORMAT_VERSION);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Decode a frame produced by [`encode`](Self::encode)
+    pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        if bytes.len() < FRAME_HEADER_LEN {
+            return Err(CryptoError::Serialization(format!(
+                "frame of {} bytes is shorter than its header",
+                bytes.len()
+            )));
+        }
+
+        let version = bytes[0];
+        if version != WIRE_FORMAT_VERSION {
+            return Err(CryptoError::Serialization(format!(
+                "unsupported wire format version {}",
+                version
+            )));
+        }
+
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&bytes[1..FRAME_HEADER_LEN]);
+        let len = u32::from_be_bytes(len) as usize;
+        let payload = &bytes[FRAME_HEADER_LEN..];
+        if len > MAX_FRAME_PAYLOAD || len != payload.len() {
+            return Err(CryptoError::Serialization(format!(
+                "frame declares {} payload bytes but carries {}",
+                len,
+                payload.len()
+            )));
+        }
+
+        // Same encoding as `bincode::serialize`, but no length prefix inside
+        // the payload may claim more bytes than the payload holds
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(len as u64)
+            .deserialize(payload)
+            .map_err(|e| CryptoError::Serialization(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id: u32,
+        data: Vec<u8>,
+    }
+
+    fn sample() -> Sample {
+        Sample { id: 7, data: vec![1, 2, 3, 4] }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frame = WireFrame::encode(&sample()).unwrap();
+        assert_eq!(frame[0], WIRE_FORMAT_VERSION);
+        assert_eq!(WireFrame::decode::<Sample>(&frame).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_truncated_frames_rejected() {
+        let frame = WireFrame::encode(&sample()).unwrap();
+        for len in 0..frame.len() {
+            assert!(matches!(
+                WireFrame::decode::<Sample>(&frame[..len]),
+                Err(CryptoError::Serialization(_))
+            ));
+        }
+
+        let mut padded = frame;
+        padded.push(0);
+        assert!(WireFrame::decode::<Sample>(&padded).is_err());
+    }
+
+    #[test]
+    fn test_other_versions_rejected() {
+        let mut frame = WireFrame::encode(&sample()).unwrap();
+        frame[0] = WIRE_FORMAT_VERSION + 1;
+        assert!(matches!(
+            WireFrame::decode::<Sample>(&frame),
+            Err(CryptoError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_oversized_inner_length_rejected() {
+        // A consistent frame whose vector claims far more bytes than it has
+        let mut payload = 7u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&u64::MAX.to_le_bytes());
+        let mut frame = vec![WIRE_FORMAT_VERSION];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+
+        assert!(matches!(
+            WireFrame::decode::<Sample>(&frame),
+            Err(CryptoError::Serialization(_))
+        ));
+    }
+}
@@ -15,9 +15,15 @@
 //!    - DH2 = DH(EK_A, IK_B)  
 //!    - DH3 = DH(EK_A, SPK_B)
 //!    - DH4 = DH(EK_A, OPK_B) (if OPK present)
-//! 4. Shared secret = KDF(DH1 || DH2 || DH3 || DH4)
+//! 4. Shared secret = KDF(DH1 || DH2 || DH3 || DH4), salted with a random
+//!    session nonce chosen by Alice and sent alongside her ephemeral key
+//!
+//! The nonce is also part of the associated data, so no two initiations
+//! between the same identities share it and a replayed initial message
+//! cannot be mistaken for a fresh session.
 
 use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
 use zeroize::{Zeroize, ZeroizeOnDrop};
@@ -25,7 +31,13 @@ use zeroize::{Zeroize, ZeroizeOnDrop};
 use crate::error::{CryptoError, Result};
 use crate::identity::{IdentityKeyPair, IdentityPublicKey};
 use crate::kdf::{domain, KeyDerivationContext};
-use crate::keys::{EphemeralKeyPair, PublicKeyBytes, SharedSecret, SignedPreKey, OneTimePreKey, PreKeyBundle};
+use crate::keys::{
+    is_weak_shared_secret, EphemeralKeyPair, PublicKeyBytes, SharedSecret, SignedPreKey,
+    OneTimePreKey, PreKeyBundle,
+};
+
+/// Size of the initiator's session nonce in bytes
+pub const SESSION_NONCE_SIZE: usize = 16;
 
 /// X3DH shared secret
 #[derive(Clone, Zeroize, ZeroizeOnDrop)]
@@ -34,6 +46,8 @@ pub struct X3DHSharedSecret {
     secret: [u8; 32],
     /// Associated data for the first message
     ad: Vec<u8>,
+    /// Nonce the initiator chose for this session
+    session_nonce: [u8; SESSION_NONCE_SIZE],
 }
 
 impl X3DHSharedSecret {
@@ -46,6 +60,11 @@ impl X3DHSharedSecret {
     pub fn associated_data(&self) -> &[u8] {
         &self.ad
     }
+
+    /// Get the session nonce, to be sent to the responder in the [`X3DHHeader`]
+    pub fn session_nonce(&self) -> &[u8; SESSION_NONCE_SIZE] {
+        &self.session_nonce
+    }
 }
 
 /// Pre-key manager for generating and storing pre-keys
@@ -54,6 +73,9 @@ pub struct PreKeyManager {
     identity: IdentityKeyPair,
     /// Signed pre-key (rotated periodically)
     signed_prekey: SignedPreKeyPair,
+    /// Signed pre-key replaced by the last rotation, with the identity it
+    /// belongs to, for initial messages still in flight
+    previous: Option<(IdentityKeyPair, SignedPreKeyPair)>,
     /// One-time pre-keys (each used once)
     one_time_prekeys: Vec<OneTimePreKeyPair>,
     /// Counter for one-time pre-key IDs
@@ -88,6 +110,7 @@ impl PreKeyManager {
         Self {
             identity,
             signed_prekey,
+            previous: None,
             one_time_prekeys: Vec::new(),
             opk_counter: 0,
         }
@@ -98,8 +121,8 @@ impl PreKeyManager {
         let public = X25519PublicKey::from(&secret);
         let timestamp = chrono::Utc::now().timestamp();
         
-        // Sign the public key
-        let signature = identity.sign(public.as_bytes());
+        // Sign the public key together with its creation time
+        let signature = identity.sign(&SignedPreKey::signed_data(public.as_bytes(), timestamp));
         
         SignedPreKeyPair {
             id,
@@ -110,19 +133,25 @@ impl PreKeyManager {
         }
     }
 
-    /// Generate one-time pre-keys
-    pub fn generate_one_time_prekeys(&mut self, count: usize) {
+    /// Generate one-time pre-keys, returning the new public keys
+    pub fn generate_one_time_prekeys(&mut self, count: usize) -> Vec<OneTimePreKey> {
+        let mut generated = Vec::with_capacity(count);
         for _ in 0..count {
             self.opk_counter += 1;
             let secret = X25519StaticSecret::random_from_rng(OsRng);
             let public = X25519PublicKey::from(&secret);
             
+            generated.push(OneTimePreKey {
+                id: self.opk_counter,
+                public_key: PublicKeyBytes::from_x25519(&public),
+            });
             self.one_time_prekeys.push(OneTimePreKeyPair {
                 id: self.opk_counter,
                 secret,
                 public,
             });
         }
+        generated
     }
 
     /// Get the pre-key bundle for publishing
@@ -160,9 +189,59 @@ impl PreKeyManager {
     }
 
     /// Rotate signed pre-key
+    ///
+    /// The replaced key keeps answering initial messages until
+    /// [`PreKeyManager::retire_previous_signed_prekey`].
     pub fn rotate_signed_prekey(&mut self) {
         let new_id = self.signed_prekey.id + 1;
-        self.signed_prekey = Self::generate_signed_prekey(&self.identity, new_id);
+        let replaced = std::mem::replace(
+            &mut self.signed_prekey,
+            Self::generate_signed_prekey(&self.identity, new_id),
+        );
+        self.previous = Some((self.identity.clone(), replaced));
+    }
+
+    /// Move to a new identity with a new signed pre-key
+    ///
+    /// One-time pre-keys are dropped, but IDs keep counting up so no peer
+    /// holding an old bundle names a new key. The old identity and signed
+    /// pre-key stay as the previous generation.
+    pub fn rotate_identity(&mut self, identity: IdentityKeyPair) {
+        let new_id = self.signed_prekey.id + 1;
+        let replaced = std::mem::replace(
+            &mut self.signed_prekey,
+            Self::generate_signed_prekey(&identity, new_id),
+        );
+        let old_identity = std::mem::replace(&mut self.identity, identity);
+        self.previous = Some((old_identity, replaced));
+        self.one_time_prekeys.clear();
+    }
+
+    /// ID of the signed pre-key replaced by the last rotation, if kept
+    pub fn previous_signed_prekey_id(&self) -> Option<u32> {
+        self.previous.as_ref().map(|(_, prekey)| prekey.id)
+    }
+
+    /// Forget the previous signed pre-key once its grace period is over,
+    /// returning its ID
+    pub fn retire_previous_signed_prekey(&mut self) -> Option<u32> {
+        self.previous.take().map(|(_, prekey)| prekey.id)
+    }
+
+    /// Secret of signed pre-key `id`, the current one or the previous one
+    pub fn signed_prekey_secret_for(&self, id: u32) -> Option<&X25519StaticSecret> {
+        self.generation(id).map(|(_, secret)| secret)
+    }
+
+    /// Identity and signed pre-key secret for signed pre-key `id`
+    fn generation(&self, id: u32) -> Option<(&IdentityKeyPair, &X25519StaticSecret)> {
+        if self.signed_prekey.id == id {
+            return Some((&self.identity, &self.signed_prekey.secret));
+        }
+        self.previous
+            .as_ref()
+            .filter(|(_, prekey)| prekey.id == id)
+            .map(|(identity, prekey)| (identity, &prekey.secret))
     }
 
     /// Get identity key pair reference
@@ -187,15 +266,16 @@ impl X3DHKeyAgreement {
         our_identity: &IdentityKeyPair,
         their_bundle: &PreKeyBundle,
     ) -> Result<(X3DHSharedSecret, PublicKeyBytes, Option<u32>)> {
-        // Verify signed pre-key signature
-        let their_identity = IdentityPublicKey::from_bytes(&their_bundle.identity_key)?;
-        their_bundle.signed_prekey.verify(&their_identity.signing_key)?;
+        // Verify signed pre-key signature and reject low-order keys
+        let their_identity = their_bundle.verify()?;
         
-        // Generate ephemeral key
+        // Generate ephemeral key and session nonce
         let ephemeral = EphemeralKeyPair::generate();
+        let mut session_nonce = [0u8; SESSION_NONCE_SIZE];
+        OsRng.fill_bytes(&mut session_nonce);
         
         // Perform DH computations
-        let spk_public = their_bundle.signed_prekey.public_key.to_x25519();
+        let spk_public = their_bundle.signed_prekey.public_key.to_x25519()?;
         
         // DH1 = DH(IK_A, SPK_B)
         let dh1 = our_identity.diffie_hellman(&spk_public);
@@ -208,7 +288,7 @@ impl X3DHKeyAgreement {
         
         // DH4 = DH(EK_A, OPK_B) if OPK present
         let (dh4, opk_id) = if let Some(ref opk) = their_bundle.one_time_prekey {
-            let opk_public = opk.public_key.to_x25519();
+            let opk_public = opk.public_key.to_x25519()?;
             (Some(ephemeral.diffie_hellman(&opk_public)), Some(opk.id))
         } else {
             (None, None)
@@ -219,6 +299,7 @@ impl X3DHKeyAgreement {
             &dh1, &dh2, &dh3, dh4.as_ref(),
             &our_identity.public_key(),
             &their_identity,
+            &session_nonce,
         )?;
         
         Ok((
@@ -235,26 +316,64 @@ impl X3DHKeyAgreement {
     /// * `their_identity` - Alice's identity public key
     /// * `their_ephemeral` - Alice's ephemeral public key
     /// * `used_opk_id` - ID of the one-time pre-key Alice used (if any)
+    /// * `session_nonce` - Nonce from Alice's [`X3DHHeader`]
     pub fn respond(
         our_prekeys: &mut PreKeyManager,
         their_identity: &IdentityPublicKey,
         their_ephemeral: &PublicKeyBytes,
         used_opk_id: Option<u32>,
+        session_nonce: &[u8; SESSION_NONCE_SIZE],
     ) -> Result<X3DHSharedSecret> {
-        let ephemeral_public = their_ephemeral.to_x25519();
+        let signed_prekey_id = our_prekeys.signed_prekey.id;
+        Self::respond_with(
+            our_prekeys,
+            signed_prekey_id,
+            their_identity,
+            their_ephemeral,
+            used_opk_id,
+            session_nonce,
+        )
+    }
+
+    /// Responder side for an [`X3DHHeader`], using the signed pre-key it
+    /// names: the current one or, during its grace period, the previous one
+    pub fn respond_to(our_prekeys: &mut PreKeyManager, header: &X3DHHeader) -> Result<X3DHSharedSecret> {
+        let their_identity = IdentityPublicKey::from_bytes(&header.identity_key.0)?;
+        Self::respond_with(
+            our_prekeys,
+            header.signed_prekey_id,
+            &their_identity,
+            &header.ephemeral_key,
+            header.one_time_prekey_id,
+            &header.session_nonce,
+        )
+    }
+
+    fn respond_with(
+        our_prekeys: &mut PreKeyManager,
+        signed_prekey_id: u32,
+        their_identity: &IdentityPublicKey,
+        their_ephemeral: &PublicKeyBytes,
+        used_opk_id: Option<u32>,
+        session_nonce: &[u8; SESSION_NONCE_SIZE],
+    ) -> Result<X3DHSharedSecret> {
+        let ephemeral_public = their_ephemeral.to_x25519()?;
+        let (our_identity, spk_secret) = our_prekeys.generation(signed_prekey_id)
+            .ok_or_else(|| CryptoError::PrekeyNotFound(format!("SPK {}", signed_prekey_id)))?;
+        let our_public = our_identity.public_key();
         
         // DH1 = DH(SPK_B, IK_A)
         let dh1 = {
-            let shared = our_prekeys.signed_prekey_secret().diffie_hellman(&their_identity.dh_key);
+            let shared = spk_secret.diffie_hellman(&their_identity.dh_key);
             SharedSecret(*shared.as_bytes())
         };
         
         // DH2 = DH(IK_B, EK_A)
-        let dh2 = our_prekeys.identity().diffie_hellman(&ephemeral_public);
+        let dh2 = our_identity.diffie_hellman(&ephemeral_public);
         
         // DH3 = DH(SPK_B, EK_A)
         let dh3 = {
-            let shared = our_prekeys.signed_prekey_secret().diffie_hellman(&ephemeral_public);
+            let shared = spk_secret.diffie_hellman(&ephemeral_public);
             SharedSecret(*shared.as_bytes())
         };
         
@@ -272,7 +391,8 @@ impl X3DHKeyAgreement {
         Self::derive_shared_secret(
             &dh1, &dh2, &dh3, dh4.as_ref(),
             their_identity,
-            &our_prekeys.identity().public_key(),
+            &our_public,
+            session_nonce,
         )
     }
 
@@ -284,7 +404,14 @@ impl X3DHKeyAgreement {
         dh4: Option<&SharedSecret>,
         initiator_identity: &IdentityPublicKey,
         responder_identity: &IdentityPublicKey,
+        session_nonce: &[u8; SESSION_NONCE_SIZE],
     ) -> Result<X3DHSharedSecret> {
+        // A zero DH output means a low-order key slipped through
+        let outputs = [Some(dh1), Some(dh2), Some(dh3), dh4];
+        if outputs.iter().flatten().any(|dh| is_weak_shared_secret(dh.as_bytes())) {
+            return Err(CryptoError::WeakSharedSecret);
+        }
+
         // Concatenate DH outputs
         let mut dh_concat = Vec::with_capacity(128);
         
@@ -299,23 +426,28 @@ impl X3DHKeyAgreement {
         }
         
         // Derive shared secret
-        let kdf = KeyDerivationContext::new(None, &dh_concat);
+        let kdf = KeyDerivationContext::new(Some(session_nonce), &dh_concat);
         let secret = kdf.derive::<32>(domain::ROOT_KEY)?;
+        if is_weak_shared_secret(secret.as_bytes()) {
+            return Err(CryptoError::WeakSharedSecret);
+        }
         
-        // Create associated data: initiator_identity || responder_identity
-        let mut ad = Vec::with_capacity(128);
+        // Create associated data: initiator_identity || responder_identity || session_nonce
+        let mut ad = Vec::with_capacity(128 + SESSION_NONCE_SIZE);
         ad.extend_from_slice(&initiator_identity.to_bytes());
         ad.extend_from_slice(&responder_identity.to_bytes());
+        ad.extend_from_slice(session_nonce);
         
         Ok(X3DHSharedSecret {
             secret: secret.into_bytes(),
             ad,
+            session_nonce: *session_nonce,
         })
     }
 }
 
 /// Initial message header for X3DH
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct X3DHHeader {
     /// Sender's identity public key
     pub identity_key: PublicKeyBytes,
@@ -323,6 +455,10 @@ pub struct X3DHHeader {
     pub ephemeral_key: PublicKeyBytes,
     /// ID of one-time pre-key used (if any)
     pub one_time_prekey_id: Option<u32>,
+    /// ID of the signed pre-key used
+    pub signed_prekey_id: u32,
+    /// Initiator's session nonce
+    pub session_nonce: [u8; SESSION_NONCE_SIZE],
 }
 
 #[cfg(test)]
@@ -350,6 +486,7 @@ mod tests {
             &alice_public,
             &ephemeral,
             opk_id,
+            alice_secret.session_nonce(),
         ).unwrap();
         
         // Both should derive the same secret
@@ -381,11 +518,49 @@ mod tests {
             &alice_public,
             &ephemeral,
             opk_id,
+            alice_secret.session_nonce(),
         ).unwrap();
         
         assert_eq!(alice_secret.secret(), bob_secret.secret());
     }
 
+    #[test]
+    fn test_session_nonce_binds_initiation() {
+        let alice_identity = IdentityKeyPair::generate();
+        let bob_identity = IdentityKeyPair::generate();
+        let mut bob_prekeys = PreKeyManager::new(bob_identity);
+        let bob_bundle = bob_prekeys.get_bundle();
+        
+        // Same identities and bundle, yet every initiation is distinct
+        let (first, _, _) = X3DHKeyAgreement::initiate(&alice_identity, &bob_bundle).unwrap();
+        let (second, ephemeral, _) =
+            X3DHKeyAgreement::initiate(&alice_identity, &bob_bundle).unwrap();
+        assert_ne!(first.session_nonce(), second.session_nonce());
+        assert_ne!(first.associated_data(), second.associated_data());
+        
+        let alice_public = alice_identity.public_key();
+        let bob_secret = X3DHKeyAgreement::respond(
+            &mut bob_prekeys,
+            &alice_public,
+            &ephemeral,
+            None,
+            second.session_nonce(),
+        ).unwrap();
+        assert_eq!(second.secret(), bob_secret.secret());
+        assert_eq!(second.associated_data(), bob_secret.associated_data());
+        
+        // A swapped nonce breaks agreement
+        let swapped = X3DHKeyAgreement::respond(
+            &mut bob_prekeys,
+            &alice_public,
+            &ephemeral,
+            None,
+            first.session_nonce(),
+        ).unwrap();
+        assert_ne!(second.secret(), swapped.secret());
+        assert_ne!(second.associated_data(), swapped.associated_data());
+    }
+
     #[test]
     fn test_prekey_rotation() {
         let identity = IdentityKeyPair::generate();
@@ -403,6 +578,44 @@ mod tests {
         assert_ne!(bundle1.signed_prekey.public_key, bundle2.signed_prekey.public_key);
     }
 
+    #[test]
+    fn test_previous_generation_answers_during_grace() {
+        let alice_identity = IdentityKeyPair::generate();
+        let mut bob_prekeys = PreKeyManager::new(IdentityKeyPair::generate());
+        let first_opks = bob_prekeys.generate_one_time_prekeys(2);
+
+        // Alice starts from the bundle Bob published before rotating twice
+        let old_bundle = bob_prekeys.get_bundle();
+        let (alice_secret, ephemeral, _) =
+            X3DHKeyAgreement::initiate(&alice_identity, &old_bundle).unwrap();
+        let header = X3DHHeader {
+            identity_key: PublicKeyBytes::from(alice_identity.public_key().signing_key_bytes()),
+            ephemeral_key: ephemeral,
+            one_time_prekey_id: None,
+            signed_prekey_id: old_bundle.signed_prekey.id,
+            session_nonce: *alice_secret.session_nonce(),
+        };
+
+        bob_prekeys.rotate_identity(IdentityKeyPair::generate());
+        assert_eq!(bob_prekeys.previous_signed_prekey_id(), Some(old_bundle.signed_prekey.id));
+        let bob_secret = X3DHKeyAgreement::respond_to(&mut bob_prekeys, &header).unwrap();
+        assert_eq!(alice_secret.secret(), bob_secret.secret());
+
+        // IDs keep counting across the new identity
+        let new_opks = bob_prekeys.generate_one_time_prekeys(1);
+        assert!(new_opks[0].id > first_opks[1].id);
+        assert!(bob_prekeys.get_bundle().signed_prekey.id > old_bundle.signed_prekey.id);
+
+        // Once retired, or replaced by a later rotation, the old key is gone
+        bob_prekeys.rotate_signed_prekey();
+        assert!(matches!(
+            X3DHKeyAgreement::respond_to(&mut bob_prekeys, &header),
+            Err(CryptoError::PrekeyNotFound(_))
+        ));
+        assert!(bob_prekeys.retire_previous_signed_prekey().is_some());
+        assert_eq!(bob_prekeys.previous_signed_prekey_id(), None);
+    }
+
     #[test]
     fn test_one_time_prekey_consumption() {
         let identity = IdentityKeyPair::generate();
@@ -434,10 +647,109 @@ mod tests {
         let bob_prekeys = PreKeyManager::new(bob_identity);
         let mut bob_bundle = bob_prekeys.get_bundle();
         
+        // A backdated or postdated key no longer matches its signature
+        let mut redated = bob_bundle.clone();
+        redated.signed_prekey.timestamp += 1;
+        assert!(matches!(
+            X3DHKeyAgreement::initiate(&alice_identity, &redated),
+            Err(CryptoError::InvalidSignature)
+        ));
+
         // Tamper with signature
         bob_bundle.signed_prekey.signature[0] ^= 0xFF;
         
         let result = X3DHKeyAgreement::initiate(&alice_identity, &bob_bundle);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_low_order_keys_rejected() {
+        let alice_identity = IdentityKeyPair::generate();
+        let mut bob_prekeys = PreKeyManager::new(IdentityKeyPair::generate());
+        bob_prekeys.generate_one_time_prekeys(1);
+
+        // Order-8 point with the ignored top bit set; one-time prekeys are unsigned
+        let mut low_order = [
+            0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f,
+            0xc4, 0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16,
+            0x5f, 0x49, 0xb8, 0x00,
+        ];
+        low_order[31] |= 0x80;
+        let mut bundle = bob_prekeys.get_bundle();
+        bundle.one_time_prekey.as_mut().unwrap().public_key = PublicKeyBytes(low_order);
+
+        assert!(matches!(bundle.verify(), Err(CryptoError::InvalidPublicKey(_))));
+        assert!(matches!(
+            X3DHKeyAgreement::initiate(&alice_identity, &bundle),
+            Err(CryptoError::InvalidPublicKey(_))
+        ));
+        assert!(bob_prekeys.get_bundle().verify().is_ok());
+
+        // Nor may the initiator's ephemeral key be low order
+        let result = X3DHKeyAgreement::respond(
+            &mut bob_prekeys,
+            &alice_identity.public_key(),
+            &PublicKeyBytes([0u8; 32]),
+            None,
+            &[0u8; SESSION_NONCE_SIZE],
+        );
+        assert!(matches!(result, Err(CryptoError::InvalidPublicKey(_))));
+    }
+
+    #[test]
+    fn test_rfc7748_small_order_points_rejected() {
+        let alice_identity = IdentityKeyPair::generate();
+        let bob_identity = IdentityKeyPair::generate();
+        let mut bob_prekeys = PreKeyManager::new(bob_identity.clone());
+        bob_prekeys.generate_one_time_prekeys(1);
+
+        for point in crate::keys::LOW_ORDER_POINTS {
+            let public_key = PublicKeyBytes(point);
+            assert!(matches!(public_key.to_x25519(), Err(CryptoError::InvalidPublicKey(_))));
+
+            // Correctly signed, so only the key itself is at fault
+            let mut bundle = bob_prekeys.get_bundle();
+            bundle.signed_prekey.public_key = public_key.clone();
+            bundle.signed_prekey.signature = bob_identity
+                .sign(&SignedPreKey::signed_data(&point, bundle.signed_prekey.timestamp));
+            assert!(matches!(
+                X3DHKeyAgreement::initiate(&alice_identity, &bundle),
+                Err(CryptoError::InvalidPublicKey(_))
+            ));
+
+            let mut bundle = bob_prekeys.get_bundle();
+            bundle.one_time_prekey.as_mut().unwrap().public_key = public_key.clone();
+            assert!(matches!(
+                X3DHKeyAgreement::initiate(&alice_identity, &bundle),
+                Err(CryptoError::InvalidPublicKey(_))
+            ));
+
+            let result = X3DHKeyAgreement::respond(
+                &mut bob_prekeys,
+                &alice_identity.public_key(),
+                &public_key,
+                None,
+                &[0u8; SESSION_NONCE_SIZE],
+            );
+            assert!(matches!(result, Err(CryptoError::InvalidPublicKey(_))));
+        }
+    }
+
+    #[test]
+    fn test_zero_dh_output_is_weak() {
+        let zero = SharedSecret([0u8; 32]);
+        let fine = SharedSecret([0x42; 32]);
+        let identity = IdentityKeyPair::generate().public_key();
+
+        let result = X3DHKeyAgreement::derive_shared_secret(
+            &fine,
+            &zero,
+            &fine,
+            None,
+            &identity,
+            &identity,
+            &[0u8; SESSION_NONCE_SIZE],
+        );
+        assert!(matches!(result, Err(CryptoError::WeakSharedSecret)));
+    }
 }
@@ -0,0 +1,270 @@
+//! DNS seeds for bootstrap peers
+//!
+//! A seed is a domain publishing `dnsaddr` TXT records under
+//! `_dnsaddr.<domain>`, e.g. `dnsaddr=/ip4/203.0.113.7/tcp/4001/p2p/12D3Koo...`.
+//! Records may point at further `/dnsaddr/<domain>` entries, which are
+//! followed a few levels deep. Resolution runs once at node startup in a
+//! task of its own, so the event loop keeps serving commands meanwhile, and
+//! the results are merged with the static bootstrap list as they arrive.
+
+use async_trait::async_trait;
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use std::collections::HashSet;
+use tracing::{debug, warn};
+
+use crate::config::DhtConfig;
+use crate::error::{DhtError, Result};
+
+/// Maximum nesting of `/dnsaddr/` records followed from a seed
+const MAX_DNSADDR_DEPTH: usize = 4;
+
+/// Source of TXT records, mockable for tests
+#[async_trait]
+pub trait TxtResolver: Send + Sync {
+    /// Look up the TXT record strings published for `name`
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>>;
+}
+
+/// Resolver using the system DNS configuration
+pub struct SystemResolver {
+    inner: TokioAsyncResolver,
+}
+
+impl SystemResolver {
+    /// Create a resolver from the system configuration, falling back to
+    /// public defaults when it cannot be read
+    pub fn new() -> Self {
+        let inner = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+            warn!("Cannot read system DNS config ({}); using defaults", e);
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+        });
+        Self { inner }
+    }
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl TxtResolver for SystemResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        let lookup = self
+            .inner
+            .txt_lookup(name)
+            .await
+            .map_err(|e| DhtError::Network(format!("TXT lookup for {} failed: {}", name, e)))?;
+
+        // A TXT record may be split into several character-strings
+        Ok(lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|chunk| String::from_utf8_lossy(chunk))
+                    .collect::<String>()
+            })
+            .collect())
+    }
+}
+
+/// Parse one `dnsaddr=<multiaddr>` TXT record
+///
+/// Returns `None` for unrelated TXT records and malformed addresses.
+pub fn parse_dnsaddr_record(record: &str) -> Option<Multiaddr> {
+    record
+        .trim()
+        .strip_prefix("dnsaddr=")
+        .and_then(|addr| addr.parse().ok())
+}
+
+/// Domain queried for a seed, accepting `example.com` or `/dnsaddr/example.com`
+fn seed_domain(seed: &str) -> &str {
+    let seed = seed.trim();
+    seed.strip_prefix("/dnsaddr/")
+        .unwrap_or(seed)
+        .trim_end_matches('.')
+}
+
+/// Resolve DNS seeds to multiaddrs
+///
+/// Failed lookups are logged and skipped so one bad seed does not prevent
+/// the others (or the static bootstrap nodes) from being used.
+pub async fn resolve_dns_seeds(resolver: &dyn TxtResolver, seeds: &[String]) -> Vec<Multiaddr> {
+    let mut resolved = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending: Vec<(String, usize)> = seeds
+        .iter()
+        .map(|seed| (seed_domain(seed).to_string(), 0))
+        .collect();
+    pending.reverse();
+
+    while let Some((domain, depth)) = pending.pop() {
+        if !visited.insert(domain.clone()) {
+            continue;
+        }
+
+        let records = match resolver.lookup_txt(&format!("_dnsaddr.{}", domain)).await {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("DNS seed {} did not resolve: {}", domain, e);
+                continue;
+            }
+        };
+
+        for record in records {
+            let Some(addr) = parse_dnsaddr_record(&record) else {
+                debug!("Ignoring TXT record on {}: {}", domain, record);
+                continue;
+            };
+            match addr.iter().next() {
+                Some(Protocol::Dnsaddr(nested)) if depth + 1 < MAX_DNSADDR_DEPTH => {
+                    pending.push((nested.to_string(), depth + 1));
+                }
+                Some(Protocol::Dnsaddr(nested)) => {
+                    warn!("Not following {}: dnsaddr nesting too deep", nested);
+                }
+                _ => resolved.push(addr),
+            }
+        }
+    }
+
+    resolved
+}
+
+/// Parsed static bootstrap nodes, skipping invalid entries
+pub fn static_addresses(config: &DhtConfig) -> Vec<Multiaddr> {
+    let mut addresses = Vec::new();
+    for addr in &config.bootstrap_nodes {
+        match addr.parse::<Multiaddr>() {
+            Ok(multiaddr) => addresses.push(multiaddr),
+            Err(e) => warn!("Invalid bootstrap address {}: {}", addr, e),
+        }
+    }
+    addresses
+}
+
+/// Append resolved seed addresses not already in `addresses`
+///
+/// Returns the newly added addresses, which still need dialing.
+pub fn merge_seeded(addresses: &mut Vec<Multiaddr>, seeded: Vec<Multiaddr>) -> Vec<Multiaddr> {
+    if seeded.is_empty() {
+        warn!("No DNS seed resolved; using static bootstrap nodes only");
+    }
+    let mut added = Vec::new();
+    for addr in seeded {
+        if !addresses.contains(&addr) {
+            addresses.push(addr.clone());
+            added.push(addr);
+        }
+    }
+    added
+}
+
+/// Full bootstrap dial set: static nodes followed by resolved DNS seeds
+pub async fn bootstrap_addresses(config: &DhtConfig, resolver: &dyn TxtResolver) -> Vec<Multiaddr> {
+    let mut addresses = static_addresses(config);
+    if !config.dns_seeds.is_empty() {
+        let seeded = resolve_dns_seeds(resolver, &config.dns_seeds).await;
+        merge_seeded(&mut addresses, seeded);
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Resolver answering from a fixed table; unknown names fail
+    struct StaticResolver(HashMap<String, Vec<String>>);
+
+    impl StaticResolver {
+        fn new(entries: &[(&str, &[&str])]) -> Self {
+            Self(
+                entries
+                    .iter()
+                    .map(|(name, records)| {
+                        (name.to_string(), records.iter().map(|r| r.to_string()).collect())
+                    })
+                    .collect(),
+            )
+        }
+    }
+
+    #[async_trait]
+    impl TxtResolver for StaticResolver {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+            self.0
+                .get(name)
+                .cloned()
+                .ok_or_else(|| DhtError::Network(format!("NXDOMAIN {}", name)))
+        }
+    }
+
+    #[test]
+    fn test_parse_dnsaddr_record() {
+        let peer = libp2p::PeerId::random();
+        let record = format!("dnsaddr=/ip4/203.0.113.7/tcp/4001/p2p/{}", peer);
+        let addr = parse_dnsaddr_record(&record).unwrap();
+        assert_eq!(addr.to_string(), record["dnsaddr=".len()..]);
+
+        assert!(parse_dnsaddr_record("dnsaddr=/dnsaddr/seed.example.org").is_some());
+        assert!(parse_dnsaddr_record("v=spf1 -all").is_none());
+        assert!(parse_dnsaddr_record("dnsaddr=not-a-multiaddr").is_none());
+    }
+
+    #[test]
+    fn test_seed_domain() {
+        assert_eq!(seed_domain("seed.example.org"), "seed.example.org");
+        assert_eq!(seed_domain("/dnsaddr/seed.example.org."), "seed.example.org");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_nested_seeds() {
+        let resolver = StaticResolver::new(&[
+            (
+                "_dnsaddr.seed.example.org",
+                &[
+                    "dnsaddr=/ip4/203.0.113.7/tcp/4001",
+                    "dnsaddr=/dnsaddr/eu.seed.example.org",
+                    "unrelated",
+                ],
+            ),
+            ("_dnsaddr.eu.seed.example.org", &["dnsaddr=/ip6/2001:db8::1/tcp/4001"]),
+            // Self-reference must not loop
+            ("_dnsaddr.loop.example.org", &["dnsaddr=/dnsaddr/loop.example.org"]),
+        ]);
+
+        let seeds = vec!["seed.example.org".to_string(), "/dnsaddr/loop.example.org".to_string()];
+        let addrs = resolve_dns_seeds(&resolver, &seeds).await;
+        let addrs: Vec<_> = addrs.iter().map(ToString::to_string).collect();
+        assert_eq!(addrs, vec!["/ip4/203.0.113.7/tcp/4001", "/ip6/2001:db8::1/tcp/4001"]);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_falls_back_to_static_nodes() {
+        let resolver = StaticResolver::new(&[(
+            "_dnsaddr.seed.example.org",
+            &["dnsaddr=/ip4/203.0.113.7/tcp/4001", "dnsaddr=/ip4/198.51.100.1/tcp/4001"],
+        )]);
+        let mut config = DhtConfig::default();
+        config.add_bootstrap_node("/ip4/198.51.100.1/tcp/4001");
+
+        // Unresolvable seed: static nodes still dialed
+        config.dns_seeds = vec!["missing.example.org".to_string()];
+        let addrs = bootstrap_addresses(&config, &resolver).await;
+        assert_eq!(addrs.len(), 1);
+
+        // Seeded addresses are merged without duplicates
+        config.add_dns_seed("seed.example.org");
+        let addrs = bootstrap_addresses(&config, &resolver).await;
+        let addrs: Vec<_> = addrs.iter().map(ToString::to_string).collect();
+        assert_eq!(addrs, vec!["/ip4/198.51.100.1/tcp/4001", "/ip4/203.0.113.7/tcp/4001"]);
+    }
+}
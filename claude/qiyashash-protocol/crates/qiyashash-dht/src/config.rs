@@ -1,6 +1,8 @@
 //! DHT configuration
 
+use libp2p::{Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::time::Duration;
 
 /// DHT node configuration
@@ -10,9 +12,24 @@ pub struct DhtConfig {
     pub listen_addresses: Vec<String>,
     /// Bootstrap nodes
     pub bootstrap_nodes: Vec<String>,
+    /// Domains publishing `dnsaddr` TXT records, resolved at startup and
+    /// merged with `bootstrap_nodes`
+    #[serde(default)]
+    pub dns_seeds: Vec<String>,
+    /// Initial delay between bootstrap re-dial attempts
+    #[serde(default = "default_bootstrap_retry_interval_secs")]
+    pub bootstrap_retry_interval_secs: u64,
+    /// Upper bound for the exponential bootstrap backoff
+    #[serde(default = "default_bootstrap_retry_max_interval_secs")]
+    pub bootstrap_retry_max_interval_secs: u64,
+    /// Connected peers required before the node reports itself ready
+    #[serde(default = "default_min_connected_peers")]
+    pub min_connected_peers: usize,
     /// Storage path
     pub storage_path: String,
-    /// Maximum storage size in bytes
+    /// Maximum bytes of fragment data stored locally
+    ///
+    /// Past this, fragments closest to expiry are evicted to make room.
     pub max_storage_bytes: u64,
     /// Fragment count for Reed-Solomon
     pub fragment_count: usize,
@@ -20,6 +37,10 @@ pub struct DhtConfig {
     pub fragment_threshold: usize,
     /// Message expiry duration
     pub message_expiry_secs: u64,
+    /// Maximum random extension added to each fragment's expiry, so the
+    /// fragments of one message don't all expire at the same instant
+    #[serde(default)]
+    pub expiry_jitter_secs: u64,
     /// Replication factor
     pub replication_factor: usize,
     /// Query timeout
@@ -27,11 +48,22 @@ pub struct DhtConfig {
     /// Connection timeout
     pub connection_timeout_secs: u64,
     /// Enable mDNS for local discovery
+    ///
+    /// When disabled the node never announces itself on the local network.
     pub enable_mdns: bool,
+    /// Protocol string advertised through identify
+    #[serde(default = "default_identify_protocol")]
+    pub identify_protocol: String,
     /// Maximum concurrent connections
     pub max_connections: usize,
     /// Gossipsub configuration
     pub gossipsub: GossipsubConfig,
+    /// If non-empty, only these peers may connect
+    #[serde(default)]
+    pub peer_allowlist: Vec<PeerId>,
+    /// Peers that are always disconnected (takes precedence over the allowlist)
+    #[serde(default)]
+    pub peer_denylist: Vec<PeerId>,
 }
 
 impl Default for DhtConfig {
@@ -42,21 +74,45 @@ impl Default for DhtConfig {
                 "/ip4/0.0.0.0/udp/0/quic-v1".to_string(),
             ],
             bootstrap_nodes: Vec::new(),
+            dns_seeds: Vec::new(),
+            bootstrap_retry_interval_secs: default_bootstrap_retry_interval_secs(),
+            bootstrap_retry_max_interval_secs: default_bootstrap_retry_max_interval_secs(),
+            min_connected_peers: default_min_connected_peers(),
             storage_path: "./dht_storage".to_string(),
             max_storage_bytes: 1024 * 1024 * 1024, // 1 GB
             fragment_count: 5,
             fragment_threshold: 3,
             message_expiry_secs: 30 * 24 * 3600, // 30 days
+            expiry_jitter_secs: 6 * 3600,        // 6 hours
             replication_factor: 3,
             query_timeout_secs: 30,
             connection_timeout_secs: 10,
             enable_mdns: true,
+            identify_protocol: default_identify_protocol(),
             max_connections: 100,
             gossipsub: GossipsubConfig::default(),
+            peer_allowlist: Vec::new(),
+            peer_denylist: Vec::new(),
         }
     }
 }
 
+fn default_bootstrap_retry_interval_secs() -> u64 {
+    5
+}
+
+fn default_bootstrap_retry_max_interval_secs() -> u64 {
+    300
+}
+
+fn default_min_connected_peers() -> usize {
+    1
+}
+
+fn default_identify_protocol() -> String {
+    "/qiyashash/1.0.0".to_string()
+}
+
 impl DhtConfig {
     /// Create with custom storage path
     pub fn with_storage_path(path: impl Into<String>) -> Self {
@@ -71,6 +127,11 @@ impl DhtConfig {
         self.bootstrap_nodes.push(addr.into());
     }
 
+    /// Add DNS seed domain
+    pub fn add_dns_seed(&mut self, domain: impl Into<String>) {
+        self.dns_seeds.push(domain.into());
+    }
+
     /// Query timeout as Duration
     pub fn query_timeout(&self) -> Duration {
         Duration::from_secs(self.query_timeout_secs)
@@ -81,11 +142,52 @@ impl DhtConfig {
         Duration::from_secs(self.connection_timeout_secs)
     }
 
+    /// Bootstrap retry interval as Duration
+    pub fn bootstrap_retry_interval(&self) -> Duration {
+        Duration::from_secs(self.bootstrap_retry_interval_secs)
+    }
+
+    /// Maximum bootstrap retry interval as Duration
+    pub fn bootstrap_retry_max_interval(&self) -> Duration {
+        Duration::from_secs(self.bootstrap_retry_max_interval_secs)
+    }
+
     /// Message expiry as Duration
     pub fn message_expiry(&self) -> Duration {
         Duration::from_secs(self.message_expiry_secs)
     }
 
+    /// Parse listen addresses, returning the valid ones and the rejected strings
+    pub fn parse_listen_addresses(&self) -> (Vec<Multiaddr>, Vec<String>) {
+        let mut valid = Vec::new();
+        let mut invalid = Vec::new();
+        for addr in &self.listen_addresses {
+            match addr.parse::<Multiaddr>() {
+                Ok(multiaddr) => valid.push(multiaddr),
+                Err(_) => invalid.push(addr.clone()),
+            }
+        }
+        (valid, invalid)
+    }
+
+    /// Build the peer filter from the allow/deny lists
+    pub fn peer_filter(&self) -> PeerFilter {
+        PeerFilter::new(
+            self.peer_allowlist.iter().copied(),
+            self.peer_denylist.iter().copied(),
+        )
+    }
+
+    /// Check if any listen address asks the OS to pick a port (`/tcp/0`, `/udp/0`)
+    pub fn uses_auto_port(&self) -> bool {
+        use libp2p::multiaddr::Protocol;
+
+        self.parse_listen_addresses().0.iter().any(|addr| {
+            addr.iter()
+                .any(|p| matches!(p, Protocol::Tcp(0) | Protocol::Udp(0)))
+        })
+    }
+
     /// Validate configuration
     pub fn validate(&self) -> Result<(), String> {
         if self.fragment_threshold > self.fragment_count {
@@ -97,10 +199,96 @@ impl DhtConfig {
         if self.replication_factor == 0 {
             return Err("replication_factor must be > 0".to_string());
         }
+        if self.bootstrap_retry_interval_secs == 0 {
+            return Err("bootstrap_retry_interval_secs must be > 0".to_string());
+        }
+        if self.bootstrap_retry_max_interval_secs < self.bootstrap_retry_interval_secs {
+            return Err(
+                "bootstrap_retry_max_interval_secs must be >= bootstrap_retry_interval_secs"
+                    .to_string(),
+            );
+        }
+        if self.dns_seeds.iter().any(|seed| seed.trim().is_empty()) {
+            return Err("dns_seeds must not contain empty entries".to_string());
+        }
+        if self.min_connected_peers == 0 {
+            return Err("min_connected_peers must be > 0".to_string());
+        }
+        if !self.identify_protocol.starts_with('/') {
+            return Err("identify_protocol must start with '/'".to_string());
+        }
+        if self.expiry_jitter_secs > self.message_expiry_secs {
+            return Err("expiry_jitter_secs must be <= message_expiry_secs".to_string());
+        }
+        let (valid, invalid) = self.parse_listen_addresses();
+        if valid.is_empty() {
+            return Err(format!(
+                "no valid listen address (rejected: {})",
+                invalid.join(", ")
+            ));
+        }
         Ok(())
     }
 }
 
+/// Decision for whether a peer may join
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerDecision {
+    /// Peer is explicitly allowed
+    Allowed,
+    /// Peer is allowed because no allowlist is configured
+    AllowedByDefault,
+    /// Peer is on the denylist
+    Denied,
+    /// An allowlist is configured and the peer is not on it
+    NotAllowlisted,
+}
+
+impl PeerDecision {
+    /// Whether the peer may stay connected
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Self::Allowed | Self::AllowedByDefault)
+    }
+}
+
+/// Peer allow/deny filter applied to connections and discovered peers
+#[derive(Clone, Debug, Default)]
+pub struct PeerFilter {
+    allow: HashSet<PeerId>,
+    deny: HashSet<PeerId>,
+}
+
+impl PeerFilter {
+    /// Create a filter from allow and deny lists
+    pub fn new(
+        allow: impl IntoIterator<Item = PeerId>,
+        deny: impl IntoIterator<Item = PeerId>,
+    ) -> Self {
+        Self {
+            allow: allow.into_iter().collect(),
+            deny: deny.into_iter().collect(),
+        }
+    }
+
+    /// Decide whether a peer may join
+    pub fn decide(&self, peer_id: &PeerId) -> PeerDecision {
+        if self.deny.contains(peer_id) {
+            PeerDecision::Denied
+        } else if self.allow.is_empty() {
+            PeerDecision::AllowedByDefault
+        } else if self.allow.contains(peer_id) {
+            PeerDecision::Allowed
+        } else {
+            PeerDecision::NotAllowlisted
+        }
+    }
+
+    /// Shorthand for `decide(peer_id).is_allowed()`
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.decide(peer_id).is_allowed()
+    }
+}
+
 /// Gossipsub configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GossipsubConfig {
@@ -146,4 +334,79 @@ mod tests {
         config.fragment_count = 5;
         assert!(config.validate().is_err());
     }
+
+    #[test]
+    fn test_identify_protocol() {
+        let mut config = DhtConfig::default();
+        assert_eq!(config.identify_protocol, "/qiyashash/1.0.0");
+
+        config.identify_protocol = "/qiyashash-staging/1.0.0".to_string();
+        assert!(config.validate().is_ok());
+
+        config.identify_protocol = "qiyashash".to_string();
+        assert!(config.validate().is_err());
+        config.identify_protocol.clear();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_listen_addresses() {
+        let mut config = DhtConfig::default();
+        config.listen_addresses = vec!["not-an-addr".to_string(), "/ip4/0.0.0.0/tpc/0".to_string()];
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("not-an-addr"));
+
+        config.listen_addresses.clear();
+        assert!(config.validate().is_err());
+
+        // One valid address is enough
+        config.listen_addresses = vec!["typo".to_string(), "/ip4/127.0.0.1/tcp/4001".to_string()];
+        assert!(config.validate().is_ok());
+        let (valid, invalid) = config.parse_listen_addresses();
+        assert_eq!(valid.len(), 1);
+        assert_eq!(invalid, vec!["typo".to_string()]);
+        assert!(!config.uses_auto_port());
+    }
+
+    #[test]
+    fn test_peer_filter() {
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let c = PeerId::random();
+
+        // Default policy: everyone allowed
+        let filter = DhtConfig::default().peer_filter();
+        assert_eq!(filter.decide(&a), PeerDecision::AllowedByDefault);
+
+        // Denylist only
+        let mut config = DhtConfig::default();
+        config.peer_denylist = vec![a];
+        let filter = config.peer_filter();
+        assert_eq!(filter.decide(&a), PeerDecision::Denied);
+        assert!(filter.is_allowed(&b));
+
+        // Allowlist restricts everyone else
+        config.peer_allowlist = vec![a, b];
+        let filter = config.peer_filter();
+        assert_eq!(filter.decide(&a), PeerDecision::Denied); // deny wins
+        assert_eq!(filter.decide(&b), PeerDecision::Allowed);
+        assert_eq!(filter.decide(&c), PeerDecision::NotAllowlisted);
+        assert!(!filter.is_allowed(&c));
+    }
+
+    #[test]
+    fn test_dns_seed_validation() {
+        let mut config = DhtConfig::default();
+        config.add_dns_seed("seed.example.org");
+        assert!(config.validate().is_ok());
+
+        config.add_dns_seed(" ");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_auto_port_detection() {
+        let config = DhtConfig::default();
+        assert!(config.uses_auto_port());
+    }
 }
@@ -36,6 +36,10 @@ pub enum DhtError {
     #[error("Decoding error: {0}")]
     DecodingError(String),
 
+    /// Reed-Solomon decode failed on malformed or inconsistent shards
+    #[error("Decode failed: {0}")]
+    DecodeFailed(String),
+
     /// Node not connected
     #[error("Node not connected to DHT network")]
     NotConnected,
@@ -3,11 +3,14 @@
 //! Messages are split into fragments using Reed-Solomon encoding,
 //! allowing reconstruction from any subset of fragments.
 
+use rand::Rng;
 use reed_solomon_erasure::galois_8::ReedSolomon;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use tracing::warn;
+
 use crate::error::{DhtError, Result};
 
 /// Fragment identifier
@@ -16,10 +19,14 @@ pub struct FragmentId(String);
 
 impl FragmentId {
     /// Create new fragment ID from message ID and index
+    ///
+    /// The ID is `hex(SHA-256(message_id || u64_be(index))[..16])`, so anyone
+    /// who knows the message ID can locate every shard without extra metadata.
+    /// The index is always encoded as 8 bytes regardless of platform width.
     pub fn new(message_id: &str, index: usize) -> Self {
         let mut hasher = Sha256::new();
         hasher.update(message_id.as_bytes());
-        hasher.update(&index.to_be_bytes());
+        hasher.update((index as u64).to_be_bytes());
         let hash = hasher.finalize();
         Self(hex::encode(&hash[..16]))
     }
@@ -98,6 +105,14 @@ pub struct MessageFragments {
 }
 
 impl MessageFragments {
+    /// DHT key of shard `index` for `message_id`
+    ///
+    /// Used by both the store and the retrieval paths so a recipient that only
+    /// knows the message ID derives the same keys as the sender.
+    pub fn fragment_key(message_id: &str, index: usize) -> FragmentId {
+        FragmentId::new(message_id, index)
+    }
+
     /// Create from encoded message
     pub fn encode(
         message_id: impl Into<String>,
@@ -105,6 +120,23 @@ impl MessageFragments {
         data_shards: usize,
         parity_shards: usize,
         expiry_secs: u64,
+    ) -> Result<Self> {
+        Self::encode_with_jitter(message_id, data, data_shards, parity_shards, expiry_secs, 0)
+    }
+
+    /// Create from encoded message with per-fragment expiry jitter
+    ///
+    /// Each fragment expires at `now + expiry_secs + r` with `r` drawn
+    /// independently from `[0, jitter_secs]`. Jitter only ever extends the
+    /// lifetime, so every fragment is still retrievable at the nominal expiry
+    /// while expirations across the DHT are no longer synchronized.
+    pub fn encode_with_jitter(
+        message_id: impl Into<String>,
+        data: &[u8],
+        data_shards: usize,
+        parity_shards: usize,
+        expiry_secs: u64,
+        jitter_secs: u64,
     ) -> Result<Self> {
         let message_id = message_id.into();
         let total_shards = data_shards + parity_shards;
@@ -114,7 +146,7 @@ impl MessageFragments {
             .map_err(|e| DhtError::EncodingError(e.to_string()))?;
 
         // Calculate shard size (must be equal for all shards)
-        let shard_size = (data.len() + data_shards - 1) / data_shards;
+        let shard_size = Self::shard_size_for(data.len(), data_shards);
 
         // Create shards with padding
         let mut shards: Vec<Vec<u8>> = (0..data_shards)
@@ -144,15 +176,22 @@ impl MessageFragments {
             .unwrap_or_default()
             .as_secs();
 
-        let expiry = now + expiry_secs;
+        let expiry = now.saturating_add(expiry_secs);
+        let mut rng = rand::thread_rng();
 
         // Create fragments
         let fragments: Vec<Option<Fragment>> = shards
             .into_iter()
             .enumerate()
             .map(|(i, shard_data)| {
+                let jitter = if jitter_secs > 0 {
+                    rng.gen_range(0..=jitter_secs)
+                } else {
+                    0
+                };
+                let expiry = expiry.saturating_add(jitter);
                 Some(Fragment {
-                    id: FragmentId::new(&message_id, i),
+                    id: Self::fragment_key(&message_id, i),
                     message_id: message_id.clone(),
                     index: i,
                     total: total_shards,
@@ -192,38 +231,113 @@ impl MessageFragments {
         let rs = ReedSolomon::new(self.data_shards, self.parity_shards)
             .map_err(|e| DhtError::DecodingError(e.to_string()))?;
 
-        // Get shard size from first available fragment
-        let shard_size = self
+        // The shard size follows from the message size, so one bad shard
+        // can't set it for the others
+        if self.fragments.len() != total_shards {
+            return Err(DhtError::DecodeFailed(format!(
+                "Expected {} shard slots, got {}",
+                total_shards,
+                self.fragments.len()
+            )));
+        }
+        let shard_size = Self::shard_size_for(self.message_size, self.data_shards);
+
+        // Erase malformed shards; reconstruction treats them as missing
+        let mut problems = Vec::new();
+        let mut shards: Vec<Option<Vec<u8>>> = self
             .fragments
             .iter()
-            .filter_map(|f| f.as_ref())
-            .next()
-            .map(|f| f.shard_size)
-            .ok_or_else(|| DhtError::DecodingError("No fragments available".to_string()))?;
-
-        // Prepare shards for reconstruction
-        let mut shards: Vec<Option<Vec<u8>>> = (0..total_shards)
-            .map(|i| self.fragments.get(i).and_then(|f| f.as_ref()).map(|f| f.data.clone()))
+            .enumerate()
+            .map(|(i, fragment)| {
+                let fragment = fragment.as_ref()?;
+                match self.shard_problem(i, fragment, shard_size) {
+                    Some(problem) => {
+                        warn!("Skipping shard {} of {}: {}", i, self.message_id, problem);
+                        problems.push(format!("shard {} {}", i, problem));
+                        None
+                    }
+                    None => Some(fragment.data.clone()),
+                }
+            })
             .collect();
 
-        // Reconstruct missing shards
-        rs.reconstruct(&mut shards)
-            .map_err(|e| DhtError::DecodingError(e.to_string()))?;
+        let valid = shards.iter().filter(|s| s.is_some()).count();
+        if valid < self.data_shards {
+            return Err(DhtError::DecodeFailed(format!(
+                "Only {} of {} needed shards are valid: {}",
+                valid,
+                self.data_shards,
+                problems.join("; ")
+            )));
+        }
 
-        // Combine data shards
-        let mut result = Vec::with_capacity(self.message_size);
-        for shard in shards.iter().take(self.data_shards) {
-            if let Some(data) = shard {
-                result.extend_from_slice(data);
+        // Reconstruct missing shards. Shards are validated above; the unwind
+        // guard only catches bugs in the codec (it is a no-op with panic=abort).
+        let reconstructed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            rs.reconstruct(&mut shards)
+        }));
+        match reconstructed {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(DhtError::DecodeFailed(e.to_string())),
+            Err(_) => {
+                return Err(DhtError::DecodeFailed(
+                    "Reed-Solomon reconstruction panicked".to_string(),
+                ))
             }
         }
 
-        // Truncate to original size
+        // Combine data shards
+        let mut result = Vec::with_capacity(shard_size * self.data_shards);
+        for (i, shard) in shards.iter().take(self.data_shards).enumerate() {
+            let data = shard.as_ref().ok_or_else(|| {
+                DhtError::DecodeFailed(format!("Data shard {} missing after reconstruction", i))
+            })?;
+            result.extend_from_slice(data);
+        }
+
+        // Everything past the original size is encoder padding, which is
+        // always zero; anything else means the recorded size is wrong
+        if result[self.message_size..].iter().any(|&b| b != 0) {
+            return Err(DhtError::DecodeFailed(format!(
+                "Message size {} does not match the reconstructed data",
+                self.message_size
+            )));
+        }
         result.truncate(self.message_size);
 
         Ok(result)
     }
 
+    /// Shard size the encoder uses for a message of `message_size` bytes
+    ///
+    /// The payload is zero-padded up to `shard_size * data_shards`. Shards
+    /// are never empty, so an empty message still gets one byte per shard.
+    fn shard_size_for(message_size: usize, data_shards: usize) -> usize {
+        message_size.div_ceil(data_shards).max(1)
+    }
+
+    /// Why the shard in slot `index` can't be used, if it can't
+    fn shard_problem(&self, index: usize, fragment: &Fragment, shard_size: usize) -> Option<String> {
+        if fragment.index != index {
+            return Some(format!("claims index {}", fragment.index));
+        }
+        if fragment.message_size != self.message_size {
+            return Some(format!(
+                "records message size {}, expected {}",
+                fragment.message_size, self.message_size
+            ));
+        }
+        if fragment.shard_size != shard_size || fragment.data.len() != shard_size {
+            return Some(format!(
+                "has size {} (declared {}), expected {}",
+                fragment.data.len(),
+                fragment.shard_size,
+                shard_size
+            ));
+        }
+        None
+    }
+
     /// Add a fragment
     pub fn add_fragment(&mut self, fragment: Fragment) -> Result<()> {
         if fragment.message_id != self.message_id {
@@ -232,14 +346,35 @@ impl MessageFragments {
         if fragment.index >= self.fragments.len() {
             return Err(DhtError::InvalidFragment("Invalid index".to_string()));
         }
+        if fragment.id != Self::fragment_key(&self.message_id, fragment.index) {
+            return Err(DhtError::InvalidFragment(
+                "Fragment ID does not match its message ID and index".to_string(),
+            ));
+        }
 
         self.fragments[fragment.index] = Some(fragment);
         Ok(())
     }
 
-    /// Check if we have enough fragments to reconstruct
+    /// Check if we have enough valid fragments to reconstruct
     pub fn can_reconstruct(&self) -> bool {
-        self.fragments.iter().filter(|f| f.is_some()).count() >= self.data_shards
+        self.valid_shards() >= self.data_shards
+    }
+
+    /// Whether slot `index` holds a shard that decoding would use
+    pub fn has_valid_shard(&self, index: usize) -> bool {
+        let shard_size = Self::shard_size_for(self.message_size, self.data_shards);
+        self.fragments
+            .get(index)
+            .and_then(Option::as_ref)
+            .is_some_and(|fragment| self.shard_problem(index, fragment, shard_size).is_none())
+    }
+
+    /// Number of shards that pass validation; malformed ones aren't counted
+    pub fn valid_shards(&self) -> usize {
+        (0..self.fragments.len())
+            .filter(|&index| self.has_valid_shard(index))
+            .count()
     }
 
     /// Get missing fragment indices
@@ -255,7 +390,7 @@ impl MessageFragments {
     /// Get fragment IDs for all fragments
     pub fn fragment_ids(&self) -> Vec<FragmentId> {
         (0..self.fragments.len())
-            .map(|i| FragmentId::new(&self.message_id, i))
+            .map(|i| Self::fragment_key(&self.message_id, i))
             .collect()
     }
 
@@ -348,6 +483,145 @@ mod tests {
         assert!(fragments.decode().is_err());
     }
 
+    #[test]
+    fn test_malformed_shards_not_counted() {
+        let message = b"Hello, QiyasHash!";
+        let mut fragments = MessageFragments::encode("msg-123", message, 3, 2, 3600).unwrap();
+        fragments.fragments[0] = None;
+        fragments.fragments[1] = None;
+        fragments.fragments[2].as_mut().unwrap().data.push(0);
+
+        assert_eq!(fragments.valid_shards(), 2);
+        assert!(!fragments.has_valid_shard(2));
+        assert!(fragments.has_valid_shard(3));
+        assert!(!fragments.can_reconstruct());
+    }
+
+    #[test]
+    fn test_mismatched_shard_lengths() {
+        let message = b"Hello, QiyasHash! This is a test message.";
+        let mut fragments = MessageFragments::encode("msg-123", message, 3, 2, 3600).unwrap();
+
+        // Truncate one shard's data without updating its declared size;
+        // the remaining shards are enough
+        fragments.fragments[1].as_mut().unwrap().data.truncate(3);
+        assert_eq!(fragments.decode().unwrap(), message);
+
+        // Inconsistent declared shard sizes, including on the first shard
+        let mut fragments = MessageFragments::encode("msg-123", message, 3, 2, 3600).unwrap();
+        for i in [0, 4] {
+            let frag = fragments.fragments[i].as_mut().unwrap();
+            frag.data.push(0);
+            frag.shard_size += 1;
+        }
+        assert_eq!(fragments.decode().unwrap(), message);
+
+        // More bad shards than parity can cover
+        fragments.fragments[2].as_mut().unwrap().data.truncate(1);
+        assert!(matches!(fragments.decode(), Err(DhtError::DecodeFailed(_))));
+
+        // Zero-length shards
+        let mut fragments = MessageFragments::encode("msg-123", message, 3, 2, 3600).unwrap();
+        for frag in fragments.fragments.iter_mut().flatten() {
+            frag.data.clear();
+            frag.shard_size = 0;
+        }
+        assert!(matches!(fragments.decode(), Err(DhtError::DecodeFailed(_))));
+    }
+
+    #[test]
+    fn test_exact_recovery_across_payload_sizes() {
+        let data_shards = 4;
+        let sizes = [
+            0,
+            1,
+            data_shards - 1,
+            data_shards,
+            data_shards + 1,
+            2 * data_shards - 1,
+            2 * data_shards,
+            1000,
+            1001,
+        ];
+
+        for size in sizes {
+            // Non-zero tail bytes so a sloppy trim would be visible
+            let message: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+            let fragments =
+                MessageFragments::encode("msg-size", &message, data_shards, 2, 3600).unwrap();
+            assert_eq!(fragments.decode().unwrap(), message, "size {}", size);
+
+            // Also through parity, with data shards missing
+            let mut degraded =
+                MessageFragments::new_empty("msg-size", data_shards, 2, message.len());
+            for fragment in fragments.fragments.iter().flatten().skip(2) {
+                degraded.add_fragment(fragment.clone()).unwrap();
+            }
+            assert_eq!(degraded.decode().unwrap(), message, "size {} degraded", size);
+        }
+    }
+
+    #[test]
+    fn test_inconsistent_message_size_rejected() {
+        let message: Vec<u8> = (1..=10).collect();
+        let fragments = MessageFragments::encode("msg-1", &message, 4, 2, 3600).unwrap();
+
+        let receive = |message_size: usize| {
+            let mut container = MessageFragments::new_empty("msg-1", 4, 2, message_size);
+            for fragment in fragments.fragments.iter().flatten() {
+                let mut fragment = fragment.clone();
+                fragment.message_size = message_size;
+                container.add_fragment(fragment).unwrap();
+            }
+            container.decode()
+        };
+
+        assert_eq!(receive(10).unwrap(), message);
+        // Same shard size, but the recorded size would cut real data
+        assert!(matches!(receive(9), Err(DhtError::DecodeFailed(_))));
+        // Different shard size altogether
+        assert!(matches!(receive(20), Err(DhtError::DecodeFailed(_))));
+        assert!(matches!(receive(4), Err(DhtError::DecodeFailed(_))));
+
+        // Container and fragments disagree
+        let mut container = MessageFragments::new_empty("msg-1", 4, 2, 11);
+        for fragment in fragments.fragments.iter().flatten() {
+            container.add_fragment(fragment.clone()).unwrap();
+        }
+        assert!(matches!(container.decode(), Err(DhtError::DecodeFailed(_))));
+    }
+
+    #[test]
+    fn test_expiry_jitter() {
+        let message = vec![0x5au8; 4096];
+        let base = 3600;
+        let jitter = 6 * 3600;
+        let fragments =
+            MessageFragments::encode_with_jitter("msg-jitter", &message, 10, 4, base, jitter)
+                .unwrap();
+
+        let frags: Vec<_> = fragments.fragments.iter().flatten().collect();
+        for frag in &frags {
+            // Still retrievable at the nominal expiry
+            assert!(frag.expiry >= frag.created_at + base);
+            assert!(frag.expiry <= frag.created_at + base + jitter);
+            assert!(!frag.is_expired());
+        }
+
+        let distinct: std::collections::HashSet<_> = frags.iter().map(|f| f.expiry).collect();
+        assert!(distinct.len() > 1, "fragment expiries should be decorrelated");
+
+        assert_eq!(fragments.decode().unwrap(), message);
+    }
+
+    #[test]
+    fn test_no_jitter_shares_expiry() {
+        let fragments = MessageFragments::encode("msg-123", b"same expiry", 3, 2, 3600).unwrap();
+        let expiries: std::collections::HashSet<_> =
+            fragments.fragments.iter().flatten().map(|f| f.expiry).collect();
+        assert_eq!(expiries.len(), 1);
+    }
+
     #[test]
     fn test_fragment_id() {
         let id1 = FragmentId::new("msg-123", 0);
@@ -358,6 +632,41 @@ mod tests {
         assert_eq!(id1, id3);
     }
 
+    #[test]
+    fn test_fragment_key_reproducible_by_recipient() {
+        let message_id = "msg-7f3a";
+        let sender = MessageFragments::encode(message_id, b"locate me", 3, 2, 3600).unwrap();
+
+        // The recipient only knows the message ID and the shard count
+        let recipient_keys: Vec<_> = (0..5)
+            .map(|i| MessageFragments::fragment_key(message_id, i))
+            .collect();
+        let sender_keys: Vec<_> = sender
+            .fragments
+            .iter()
+            .map(|f| f.as_ref().unwrap().id.clone())
+            .collect();
+        assert_eq!(sender_keys, recipient_keys);
+        assert_eq!(sender.fragment_ids(), recipient_keys);
+
+        // Key is H(message_id || u64_be(index)) truncated to 16 bytes
+        let mut hasher = Sha256::new();
+        hasher.update(message_id.as_bytes());
+        hasher.update(2u64.to_be_bytes());
+        let expected = hex::encode(&hasher.finalize()[..16]);
+        assert_eq!(recipient_keys[2].as_str(), expected);
+    }
+
+    #[test]
+    fn test_add_fragment_rejects_foreign_id() {
+        let encoded = MessageFragments::encode("msg-1", b"payload", 2, 1, 3600).unwrap();
+        let mut frag = encoded.fragments[0].clone().unwrap();
+        frag.id = FragmentId::new("msg-2", 0);
+
+        let mut container = MessageFragments::new_empty("msg-1", 2, 1, 7);
+        assert!(container.add_fragment(frag).is_err());
+    }
+
     #[test]
     fn test_fragment_serialization() {
         let message = b"Test message";
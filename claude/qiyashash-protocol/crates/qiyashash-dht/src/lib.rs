@@ -21,16 +21,18 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs, rust_2018_idioms)]
 
+pub mod bootstrap;
 pub mod config;
 pub mod error;
 pub mod fragment;
 pub mod node;
 pub mod storage;
 
+pub use bootstrap::{SystemResolver, TxtResolver};
 pub use config::DhtConfig;
 pub use error::{DhtError, Result};
 pub use fragment::{Fragment, FragmentId, MessageFragments};
-pub use node::{DhtNode, DhtEvent};
+pub use node::{DhtNode, DhtEvent, ShutdownSignal};
 pub use storage::DhtStorage;
 
 /// Default fragment count for Reed-Solomon encoding
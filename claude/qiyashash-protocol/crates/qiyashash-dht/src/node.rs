@@ -5,29 +5,35 @@
 use futures::StreamExt;
 use libp2p::{
     gossipsub, identify, kad, mdns, noise, ping,
-    swarm::{NetworkBehaviour, SwarmEvent},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
     tcp, yamux, Multiaddr, PeerId, Swarm,
 };
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::{mpsc, oneshot, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use tokio::task::JoinHandle;
 use tracing::{debug, error, info, warn};
 
+use crate::bootstrap::{self, SystemResolver, TxtResolver};
 use crate::config::DhtConfig;
 use crate::error::{DhtError, Result};
 use crate::fragment::{Fragment, FragmentId, MessageFragments};
-use crate::storage::DhtStorage;
+use crate::storage::{DhtStorage, RetrievalGuard};
 
 /// Events emitted by the DHT node
 #[derive(Debug)]
 pub enum DhtEvent {
+    /// Node is listening on a concrete address (OS-assigned port resolved)
+    Listening { address: Multiaddr },
     /// Node connected to network
     Connected { peer_count: usize },
     /// New peer discovered
     PeerDiscovered { peer_id: PeerId },
     /// Peer disconnected
     PeerDisconnected { peer_id: PeerId },
+    /// Peer rejected by the allow/deny lists
+    PeerRejected { peer_id: PeerId },
     /// Fragment stored successfully
     FragmentStored { fragment_id: FragmentId },
     /// Fragment retrieved
@@ -64,12 +70,221 @@ enum DhtCommand {
         message_size: usize,
         response: oneshot::Sender<Result<Vec<u8>>>,
     },
+    /// Delete all fragments of a message
+    DeleteMessage {
+        message_id: String,
+        response: oneshot::Sender<Result<usize>>,
+    },
     /// Get connected peer count
     GetPeerCount {
         response: oneshot::Sender<usize>,
     },
-    /// Shutdown the node
-    Shutdown,
+}
+
+/// Shutdown notification shared by the event loop and external pollers
+///
+/// Obtained from [`DhtNode::shutdown_signal`]; anything driving work
+/// alongside the node should stop once [`ShutdownSignal::wait`] returns.
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal {
+    rx: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    /// Whether shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until shutdown is requested or the node is dropped
+    pub async fn wait(&mut self) {
+        // An error means the node is gone, which is a shutdown too
+        let _ = self.rx.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+/// Exponential backoff for re-dialing bootstrap nodes
+///
+/// Retries are only attempted while the node has fewer connected peers
+/// than the readiness threshold.
+#[derive(Debug)]
+pub(crate) struct BootstrapBackoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    threshold: usize,
+}
+
+impl BootstrapBackoff {
+    pub(crate) fn new(base: Duration, max: Duration, threshold: usize) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+            threshold,
+        }
+    }
+
+    pub(crate) fn from_config(config: &DhtConfig) -> Self {
+        Self::new(
+            config.bootstrap_retry_interval(),
+            config.bootstrap_retry_max_interval(),
+            config.min_connected_peers,
+        )
+    }
+
+    /// Whether a retry is warranted for the current peer count
+    pub(crate) fn should_retry(&self, peer_count: usize) -> bool {
+        peer_count < self.threshold
+    }
+
+    /// Whether the peer count satisfies the readiness threshold
+    pub(crate) fn is_ready(&self, peer_count: usize) -> bool {
+        peer_count >= self.threshold
+    }
+
+    /// Delay until the next attempt; doubles each call up to `max`
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    /// Reset to the base delay after the node became connected
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// A message retrieval, possibly waiting on shards from the network
+///
+/// Holds the pin on the message's local fragments until it finishes, so
+/// they can't be evicted while the remaining shards are fetched.
+struct PendingRetrieval {
+    fragments: MessageFragments,
+    /// DHT queries for missing shards that haven't answered yet
+    queries: HashSet<kad::QueryId>,
+    response: oneshot::Sender<Result<Vec<u8>>>,
+    _pinned: RetrievalGuard,
+}
+
+impl PendingRetrieval {
+    /// Whether the retrieval has nothing left to wait for
+    ///
+    /// Only shards that pass validation count towards decoding, so a
+    /// malformed answer keeps the retrieval waiting on the others.
+    fn is_done(&self) -> bool {
+        self.fragments.can_reconstruct() || self.queries.is_empty()
+    }
+
+    /// Decode the message and answer the caller, releasing the pins
+    fn finish(self) {
+        let result = if self.fragments.can_reconstruct() {
+            self.fragments.decode()
+        } else {
+            Err(DhtError::MessageNotFound(self.fragments.message_id.clone()))
+        };
+        let _ = self.response.send(result);
+    }
+}
+
+/// Message retrievals in progress, by id and by outstanding DHT query
+#[derive(Default)]
+struct Retrievals {
+    pending: HashMap<u64, PendingRetrieval>,
+    by_query: HashMap<kad::QueryId, u64>,
+    next_id: u64,
+}
+
+impl Retrievals {
+    /// Read the local shards of `fragments.message_id` and query the DHT
+    /// for the missing ones, unless the local shards already suffice
+    fn start(
+        &mut self,
+        kademlia: &mut kad::Behaviour<kad::store::MemoryStore>,
+        storage: &DhtStorage,
+        mut fragments: MessageFragments,
+        response: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        let keys: Vec<FragmentId> = (0..fragments.fragments.len())
+            .map(|index| MessageFragments::fragment_key(&fragments.message_id, index))
+            .collect();
+        let pinned = storage.pin_for_retrieval(keys.iter().cloned());
+
+        // A malformed local shard is fetched again like a missing one
+        let mut missing = Vec::new();
+        for (index, key) in keys.into_iter().enumerate() {
+            if let Ok(Some(fragment)) = storage.get(&key) {
+                let _ = fragments.add_fragment(fragment);
+            }
+            if fragments.has_valid_shard(index) || storage.is_deleted(&key).unwrap_or(false) {
+                continue;
+            }
+            missing.push(key);
+        }
+
+        let mut retrieval = PendingRetrieval {
+            fragments,
+            queries: HashSet::new(),
+            response,
+            _pinned: pinned,
+        };
+        if !retrieval.fragments.can_reconstruct() {
+            retrieval.queries = missing
+                .iter()
+                .map(|key| kademlia.get_record(kad::RecordKey::new(&key.as_str())))
+                .collect();
+        }
+        if retrieval.is_done() {
+            retrieval.finish();
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        for query in &retrieval.queries {
+            self.by_query.insert(*query, id);
+        }
+        self.pending.insert(id, retrieval);
+    }
+
+    /// Apply the answer to `query`, `None` if the shard wasn't found
+    ///
+    /// A retrieval that can now decode, or has no queries left, finishes and
+    /// its remaining queries are cancelled. Queries not started by a
+    /// retrieval are ignored.
+    fn answer(
+        &mut self,
+        kademlia: &mut kad::Behaviour<kad::store::MemoryStore>,
+        query: kad::QueryId,
+        fragment: Option<Fragment>,
+    ) {
+        let Some(id) = self.by_query.remove(&query) else {
+            return;
+        };
+        if let Some(mut running) = kademlia.query_mut(&query) {
+            running.finish();
+        }
+        let Some(retrieval) = self.pending.get_mut(&id) else {
+            return;
+        };
+        retrieval.queries.remove(&query);
+        if let Some(fragment) = fragment {
+            let _ = retrieval.fragments.add_fragment(fragment);
+        }
+        if !retrieval.is_done() {
+            return;
+        }
+
+        let retrieval = self.pending.remove(&id).expect("retrieval is pending");
+        for query in &retrieval.queries {
+            self.by_query.remove(query);
+            if let Some(mut running) = kademlia.query_mut(query) {
+                running.finish();
+            }
+        }
+        retrieval.finish();
+    }
 }
 
 /// Network behaviour combining Kademlia, Gossipsub, and other protocols
@@ -79,8 +294,8 @@ struct QiyasHashBehaviour {
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
     /// Gossipsub for pub/sub messaging
     gossipsub: gossipsub::Behaviour,
-    /// mDNS for local peer discovery
-    mdns: mdns::tokio::Behaviour,
+    /// mDNS for local peer discovery, absent when disabled in the config
+    mdns: Toggle<mdns::tokio::Behaviour>,
     /// Identify protocol
     identify: identify::Behaviour,
     /// Ping for connection health
@@ -98,11 +313,24 @@ pub struct DhtNode {
     storage: Arc<DhtStorage>,
     /// Configuration
     config: DhtConfig,
+    /// Set to `true` to stop the event loop
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    /// Event loop task, taken by the first `shutdown`
+    event_loop: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl DhtNode {
     /// Create and start a new DHT node
     pub async fn start(config: DhtConfig, storage: DhtStorage) -> Result<(Self, mpsc::Receiver<DhtEvent>)> {
+        Self::start_with_resolver(config, storage, Arc::new(SystemResolver::new())).await
+    }
+
+    /// Create and start a new DHT node, resolving DNS seeds with `resolver`
+    pub async fn start_with_resolver(
+        config: DhtConfig,
+        storage: DhtStorage,
+        resolver: Arc<dyn TxtResolver>,
+    ) -> Result<(Self, mpsc::Receiver<DhtEvent>)> {
         config.validate().map_err(DhtError::Configuration)?;
 
         let storage = Arc::new(storage);
@@ -119,10 +347,21 @@ impl DhtNode {
         let swarm = Self::create_swarm(&config, local_key.clone())?;
 
         // Start event loop
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let shutdown = ShutdownSignal { rx: shutdown_rx };
         let storage_clone = storage.clone();
         let config_clone = config.clone();
-        tokio::spawn(async move {
-            Self::run_event_loop(swarm, command_rx, event_tx, storage_clone, config_clone).await;
+        let event_loop = tokio::spawn(async move {
+            Self::run_event_loop(
+                swarm,
+                command_rx,
+                event_tx,
+                storage_clone,
+                config_clone,
+                resolver,
+                shutdown,
+            )
+            .await;
         });
 
         let node = Self {
@@ -130,6 +369,8 @@ impl DhtNode {
             peer_id,
             storage,
             config,
+            shutdown_tx: Arc::new(shutdown_tx),
+            event_loop: Arc::new(Mutex::new(Some(event_loop))),
         };
 
         Ok((node, event_rx))
@@ -140,8 +381,6 @@ impl DhtNode {
         config: &DhtConfig,
         local_key: libp2p::identity::Keypair,
     ) -> Result<Swarm<QiyasHashBehaviour>> {
-        let peer_id = PeerId::from(local_key.public());
-
         // Build swarm
         let swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
             .with_tokio()
@@ -153,47 +392,8 @@ impl DhtNode {
             .map_err(|e| DhtError::Network(e.to_string()))?
             .with_quic()
             .with_behaviour(|key| {
-                // Kademlia
-                let store = kad::store::MemoryStore::new(peer_id);
-                let kademlia_config = kad::Config::default();
-                let kademlia = kad::Behaviour::with_config(peer_id, store, kademlia_config);
-
-                // Gossipsub
-                let gossipsub_config = gossipsub::ConfigBuilder::default()
-                    .heartbeat_interval(Duration::from_millis(config.gossipsub.heartbeat_interval_ms))
-                    .validation_mode(gossipsub::ValidationMode::Strict)
-                    .build()
-                    .expect("Valid gossipsub config");
-
-                let gossipsub = gossipsub::Behaviour::new(
-                    gossipsub::MessageAuthenticity::Signed(key.clone()),
-                    gossipsub_config,
-                )
-                .expect("Valid gossipsub behaviour");
-
-                // mDNS
-                let mdns = mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    peer_id,
-                )
-                .expect("Valid mDNS behaviour");
-
-                // Identify
-                let identify = identify::Behaviour::new(identify::Config::new(
-                    "/qiyashash/1.0.0".to_string(),
-                    key.public(),
-                ));
-
-                // Ping
-                let ping = ping::Behaviour::new(ping::Config::new());
-
-                QiyasHashBehaviour {
-                    kademlia,
-                    gossipsub,
-                    mdns,
-                    identify,
-                    ping,
-                }
+                Self::build_behaviour(config, key)
+                    .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
             })
             .map_err(|e| DhtError::Network(e.to_string()))?
             .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
@@ -202,6 +402,59 @@ impl DhtNode {
         Ok(swarm)
     }
 
+    /// Build the network behaviour for `key` according to `config`
+    fn build_behaviour(
+        config: &DhtConfig,
+        key: &libp2p::identity::Keypair,
+    ) -> Result<QiyasHashBehaviour> {
+        let peer_id = PeerId::from(key.public());
+
+        // Kademlia
+        let store = kad::store::MemoryStore::new(peer_id);
+        let kademlia_config = kad::Config::default();
+        let kademlia = kad::Behaviour::with_config(peer_id, store, kademlia_config);
+
+        // Gossipsub
+        let gossipsub_config = gossipsub::ConfigBuilder::default()
+            .heartbeat_interval(Duration::from_millis(config.gossipsub.heartbeat_interval_ms))
+            .validation_mode(gossipsub::ValidationMode::Strict)
+            .build()
+            .expect("Valid gossipsub config");
+
+        let gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(key.clone()),
+            gossipsub_config,
+        )
+        .expect("Valid gossipsub behaviour");
+
+        // mDNS
+        let mdns = if config.enable_mdns {
+            Some(
+                mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)
+                    .map_err(|e| DhtError::Network(e.to_string()))?,
+            )
+        } else {
+            None
+        };
+
+        // Identify
+        let identify = identify::Behaviour::new(identify::Config::new(
+            config.identify_protocol.clone(),
+            key.public(),
+        ));
+
+        // Ping
+        let ping = ping::Behaviour::new(ping::Config::new());
+
+        Ok(QiyasHashBehaviour {
+            kademlia,
+            gossipsub,
+            mdns: Toggle::from(mdns),
+            identify,
+            ping,
+        })
+    }
+
     /// Run the event loop
     async fn run_event_loop(
         mut swarm: Swarm<QiyasHashBehaviour>,
@@ -209,39 +462,72 @@ impl DhtNode {
         event_tx: mpsc::Sender<DhtEvent>,
         storage: Arc<DhtStorage>,
         config: DhtConfig,
+        resolver: Arc<dyn TxtResolver>,
+        mut shutdown: ShutdownSignal,
     ) {
         // Start listening
-        for addr in &config.listen_addresses {
-            if let Ok(multiaddr) = addr.parse::<Multiaddr>() {
-                if let Err(e) = swarm.listen_on(multiaddr.clone()) {
-                    error!("Failed to listen on {}: {}", addr, e);
-                }
-            }
+        let (listen_addrs, invalid_addrs) = config.parse_listen_addresses();
+        for addr in &invalid_addrs {
+            warn!("Ignoring invalid listen address: {}", addr);
         }
-
-        // Bootstrap
-        for addr in &config.bootstrap_nodes {
-            if let Ok(multiaddr) = addr.parse::<Multiaddr>() {
-                if let Err(e) = swarm.dial(multiaddr.clone()) {
-                    warn!("Failed to dial bootstrap node {}: {}", addr, e);
+        let mut listeners = Vec::new();
+        for multiaddr in listen_addrs {
+            match swarm.listen_on(multiaddr.clone()) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", multiaddr, e);
+                    let _ = event_tx
+                        .send(DhtEvent::Error {
+                            message: format!("Failed to listen on {}: {}", multiaddr, e),
+                        })
+                        .await;
                 }
             }
         }
 
+        // Bootstrap from static nodes now and from the DNS seeds once they
+        // resolve; lookups can take seconds, so they run off the event loop
+        let mut bootstrap_addrs = bootstrap::static_addresses(&config);
+        Self::dial_bootstrap_nodes(&mut swarm, &bootstrap_addrs);
+        let (seeded_tx, mut seeded_rx) = mpsc::channel(1);
+        let seed_task = (!config.dns_seeds.is_empty()).then(|| {
+            let seeds = config.dns_seeds.clone();
+            tokio::spawn(async move {
+                let seeded = bootstrap::resolve_dns_seeds(resolver.as_ref(), &seeds).await;
+                let _ = seeded_tx.send(seeded).await;
+            })
+        });
+        let mut backoff = BootstrapBackoff::from_config(&config);
+        let mut ready = false;
+        let bootstrap_retry = tokio::time::sleep(backoff.next_delay());
+        tokio::pin!(bootstrap_retry);
+
+        let peer_filter = config.peer_filter();
+
         // Pending queries
         let mut pending_gets: HashMap<kad::QueryId, oneshot::Sender<Result<Option<Fragment>>>> =
             HashMap::new();
+        let mut retrievals = Retrievals::default();
 
         loop {
             tokio::select! {
+                _ = shutdown.wait() => {
+                    info!("DHT node shutting down");
+                    break;
+                }
                 // Handle swarm events
                 event = swarm.select_next_some() => {
                     match event {
                         SwarmEvent::NewListenAddr { address, .. } => {
                             info!("Listening on {}", address);
+                            let _ = event_tx.send(DhtEvent::Listening { address }).await;
                         }
                         SwarmEvent::Behaviour(QiyasHashBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
                             for (peer_id, addr) in peers {
+                                if !peer_filter.is_allowed(&peer_id) {
+                                    debug!("Ignoring mDNS peer {} (filtered)", peer_id);
+                                    continue;
+                                }
                                 debug!("mDNS discovered: {} at {}", peer_id, addr);
                                 swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
                                 let _ = event_tx.send(DhtEvent::PeerDiscovered { peer_id }).await;
@@ -259,32 +545,78 @@ impl DhtNode {
                                                 let _ = response.send(Err(e));
                                             }
                                         }
+                                    } else {
+                                        let fragment = Fragment::from_bytes(&record.record.value).ok();
+                                        retrievals.answer(&mut swarm.behaviour_mut().kademlia, id, fragment);
                                     }
                                 }
                                 kad::QueryResult::GetRecord(Err(_)) => {
                                     if let Some(response) = pending_gets.remove(&id) {
                                         let _ = response.send(Ok(None));
+                                    } else {
+                                        retrievals.answer(&mut swarm.behaviour_mut().kademlia, id, None);
                                     }
                                 }
                                 _ => {}
                             }
                         }
                         SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            let decision = peer_filter.decide(&peer_id);
+                            if !decision.is_allowed() {
+                                warn!("Disconnecting peer {}: {:?}", peer_id, decision);
+                                swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                                let _ = swarm.disconnect_peer_id(peer_id);
+                                let _ = event_tx.send(DhtEvent::PeerRejected { peer_id }).await;
+                                continue;
+                            }
                             debug!("Connected to peer: {}", peer_id);
+                            let peer_count = swarm.connected_peers().count();
+                            if !ready && backoff.is_ready(peer_count) {
+                                ready = true;
+                                backoff.reset();
+                                info!("DHT ready with {} connected peers", peer_count);
+                                let _ = event_tx.send(DhtEvent::Connected { peer_count }).await;
+                            }
                         }
                         SwarmEvent::ConnectionClosed { peer_id, .. } => {
                             debug!("Disconnected from peer: {}", peer_id);
                             let _ = event_tx.send(DhtEvent::PeerDisconnected { peer_id }).await;
+                            if !backoff.is_ready(swarm.connected_peers().count()) {
+                                ready = false;
+                            }
                         }
                         _ => {}
                     }
                 }
 
+                // DNS seeds resolved
+                Some(seeded) = seeded_rx.recv() => {
+                    let added = bootstrap::merge_seeded(&mut bootstrap_addrs, seeded);
+                    debug!("DNS seeds added {} bootstrap addresses", added.len());
+                    Self::dial_bootstrap_nodes(&mut swarm, &added);
+                }
+
+                // Re-dial bootstrap nodes while under the peer threshold
+                () = &mut bootstrap_retry => {
+                    let peer_count = swarm.connected_peers().count();
+                    let delay = if backoff.should_retry(peer_count) && !bootstrap_addrs.is_empty() {
+                        debug!("Retrying bootstrap ({} connected peers)", peer_count);
+                        Self::dial_bootstrap_nodes(&mut swarm, &bootstrap_addrs);
+                        backoff.next_delay()
+                    } else {
+                        backoff.reset();
+                        config.bootstrap_retry_interval()
+                    };
+                    bootstrap_retry
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + delay);
+                }
+
                 // Handle commands
                 Some(command) = command_rx.recv() => {
                     match command {
                         DhtCommand::StoreFragment { fragment, response } => {
-                            // Store locally
+                            // Store locally, reviving it if it was deleted
                             let local_result = storage.store(&fragment);
 
                             // Store in DHT
@@ -300,6 +632,13 @@ impl DhtNode {
                             let _ = response.send(local_result);
                         }
                         DhtCommand::GetFragment { id, response } => {
+                            // Deleted messages are not served, even if peers
+                            // still hold copies
+                            if storage.is_deleted(&id).unwrap_or(false) {
+                                let _ = response.send(Ok(None));
+                                continue;
+                            }
+
                             // Try local first
                             if let Ok(Some(fragment)) = storage.get(&id) {
                                 let _ = response.send(Ok(Some(fragment)));
@@ -335,37 +674,115 @@ impl DhtNode {
                             }
                         }
                         DhtCommand::GetMessage { message_id, data_shards, parity_shards, message_size, response } => {
-                            // Try to get from local storage first
-                            match storage.get_message_fragments(&message_id) {
-                                Ok(fragments) if fragments.len() >= data_shards => {
-                                    let mut msg_fragments = MessageFragments::new_empty(
-                                        &message_id,
-                                        data_shards,
-                                        parity_shards,
-                                        message_size,
-                                    );
-                                    for frag in fragments {
-                                        let _ = msg_fragments.add_fragment(frag);
-                                    }
-                                    let _ = response.send(msg_fragments.decode());
-                                }
-                                _ => {
-                                    let _ = response.send(Err(DhtError::MessageNotFound(message_id)));
-                                }
-                            }
+                            // Look up each shard by its derived key, locally
+                            // first and then in the DHT
+                            let msg_fragments = MessageFragments::new_empty(
+                                &message_id,
+                                data_shards,
+                                parity_shards,
+                                message_size,
+                            );
+                            retrievals.start(
+                                &mut swarm.behaviour_mut().kademlia,
+                                &storage,
+                                msg_fragments,
+                                response,
+                            );
+                        }
+                        DhtCommand::DeleteMessage { message_id, response } => {
+                            let result = Self::delete_message_fragments(
+                                &mut swarm.behaviour_mut().kademlia,
+                                &storage,
+                                &message_id,
+                                &config,
+                            );
+                            let result = result.map(|ids| {
+                                let removed = ids.len();
+                                info!("Deleted {} fragments of message {}", removed, message_id);
+                                removed
+                            });
+                            let _ = response.send(result);
                         }
                         DhtCommand::GetPeerCount { response } => {
                             let count = swarm.connected_peers().count();
                             let _ = response.send(count);
                         }
-                        DhtCommand::Shutdown => {
-                            info!("DHT node shutting down");
-                            break;
-                        }
                     }
                 }
             }
         }
+
+        if let Some(seed_task) = seed_task {
+            seed_task.abort();
+        }
+        for listener in listeners {
+            swarm.remove_listener(listener);
+        }
+        if let Err(e) = storage.flush() {
+            error!("Failed to flush DHT storage on shutdown: {}", e);
+        }
+    }
+
+    /// Remove the fragments of `message_id` from local storage and from the
+    /// Kademlia record store
+    ///
+    /// Records no longer in our store are not republished; copies already on
+    /// other peers expire with their TTL, and until then the IDs stay marked
+    /// deleted in storage. Returns every fragment ID of the message that
+    /// must no longer be served, with the locally stored ones first.
+    fn delete_message_fragments(
+        kademlia: &mut kad::Behaviour<kad::store::MemoryStore>,
+        storage: &DhtStorage,
+        message_id: &str,
+        config: &DhtConfig,
+    ) -> Result<Vec<FragmentId>> {
+        use kad::store::RecordStore;
+
+        let mut ids = storage.remove_message(message_id)?;
+
+        // Replicas we hold for other publishers are only in the record store
+        let replicas: Vec<kad::RecordKey> = kademlia
+            .store_mut()
+            .records()
+            .filter(|record| {
+                Fragment::from_bytes(&record.value)
+                    .is_ok_and(|fragment| fragment.message_id == message_id)
+            })
+            .map(|record| record.key.clone())
+            .collect();
+        for key in replicas {
+            kademlia.remove_record(&key);
+        }
+
+        // Also catches local entries too corrupt to say which message they
+        // belong to
+        for index in 0..config.fragment_count {
+            let id = MessageFragments::fragment_key(message_id, index);
+            storage.remove(&id)?;
+            kademlia.remove_record(&kad::RecordKey::new(&id.as_str()));
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        // Longest any copy of a fragment can live
+        let until = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .saturating_add(config.message_expiry_secs)
+            .saturating_add(config.expiry_jitter_secs);
+        storage.mark_deleted(&ids, until)?;
+        Ok(ids)
+    }
+
+    /// Dial every bootstrap address
+    fn dial_bootstrap_nodes(swarm: &mut Swarm<QiyasHashBehaviour>, addrs: &[Multiaddr]) {
+        for addr in addrs {
+            if let Err(e) = swarm.dial(addr.clone()) {
+                warn!("Failed to dial bootstrap node {}: {}", addr, e);
+            }
+        }
     }
 
     /// Get our peer ID
@@ -398,12 +815,13 @@ impl DhtNode {
 
     /// Store a complete message (all fragments)
     pub async fn store_message(&self, data: &[u8], message_id: &str) -> Result<()> {
-        let fragments = MessageFragments::encode(
+        let fragments = MessageFragments::encode_with_jitter(
             message_id,
             data,
             self.config.fragment_count - 2, // data shards
             2, // parity shards
             self.config.message_expiry_secs,
+            self.config.expiry_jitter_secs,
         )?;
 
         let frags: Vec<Fragment> = fragments
@@ -439,6 +857,24 @@ impl DhtNode {
         rx.await.map_err(|_| DhtError::Internal("Response channel closed".to_string()))?
     }
 
+    /// Delete every fragment of a message, e.g. when it disappears
+    ///
+    /// Fragments are removed from local storage and the Kademlia record
+    /// store, so they are no longer served or republished by this node.
+    /// DHT deletes are not authoritative: peers holding copies keep them
+    /// until they expire. Returns the number of fragment IDs now refused.
+    pub async fn delete_message(&self, message_id: &str) -> Result<usize> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(DhtCommand::DeleteMessage {
+                message_id: message_id.to_string(),
+                response: tx,
+            })
+            .await
+            .map_err(|_| DhtError::Internal("Channel closed".to_string()))?;
+        rx.await.map_err(|_| DhtError::Internal("Response channel closed".to_string()))?
+    }
+
     /// Get connected peer count
     pub async fn peer_count(&self) -> usize {
         let (tx, rx) = oneshot::channel();
@@ -455,14 +891,37 @@ impl DhtNode {
     }
 
     /// Shutdown the node
+    ///
+    /// Stops the event loop and waits for it to close its listeners and
+    /// flush storage. Commands sent afterwards fail with "Channel closed".
     pub async fn shutdown(&self) -> Result<()> {
-        self.command_tx
-            .send(DhtCommand::Shutdown)
-            .await
-            .map_err(|_| DhtError::Internal("Channel closed".to_string()))?;
+        self.shutdown_tx.send_replace(true);
+
+        let event_loop = self.event_loop.lock().expect("event loop lock").take();
+        if let Some(event_loop) = event_loop {
+            event_loop
+                .await
+                .map_err(|e| DhtError::Internal(format!("Event loop failed: {}", e)))?;
+        }
         Ok(())
     }
 
+    /// Signal for tasks that poll alongside the node and must stop with it
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            rx: self.shutdown_tx.subscribe(),
+        }
+    }
+
+    /// Whether the event loop is still running
+    pub fn is_running(&self) -> bool {
+        self.event_loop
+            .lock()
+            .expect("event loop lock")
+            .as_ref()
+            .is_some_and(|event_loop| !event_loop.is_finished())
+    }
+
     /// Get local storage reference
     pub fn storage(&self) -> &DhtStorage {
         &self.storage
@@ -474,6 +933,80 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_bootstrap_backoff_schedule() {
+        let mut backoff =
+            BootstrapBackoff::new(Duration::from_secs(5), Duration::from_secs(60), 1);
+
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+
+        // Never overflows, just stays capped
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(60));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mdns_disabled_behaviour() {
+        let mut config = DhtConfig::default();
+        config.enable_mdns = false;
+        let key = libp2p::identity::Keypair::generate_ed25519();
+
+        // A disabled toggle has no inner behaviour, so no Mdns events can
+        // ever reach the event loop
+        let behaviour = DhtNode::build_behaviour(&config, &key).unwrap();
+        assert!(!behaviour.mdns.is_enabled());
+    }
+
+    #[test]
+    fn test_bootstrap_retry_gating() {
+        let backoff = BootstrapBackoff::new(Duration::from_secs(1), Duration::from_secs(8), 3);
+
+        assert!(backoff.should_retry(0));
+        assert!(backoff.should_retry(2));
+        assert!(!backoff.should_retry(3));
+        assert!(!backoff.should_retry(10));
+
+        assert!(!backoff.is_ready(2));
+        assert!(backoff.is_ready(3));
+    }
+
+    /// Resolver whose lookups never complete
+    struct HangingResolver;
+
+    #[async_trait::async_trait]
+    impl TxtResolver for HangingResolver {
+        async fn lookup_txt(&self, _name: &str) -> Result<Vec<String>> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_seeds_do_not_block_event_loop() {
+        let dir = tempdir().unwrap();
+        let mut config = DhtConfig::with_storage_path(dir.path().join("storage"));
+        config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        config.enable_mdns = false;
+        config.add_dns_seed("seed.example.org");
+        let storage = DhtStorage::open(dir.path().join("db"), 1024 * 1024).unwrap();
+        let (node, _events) =
+            DhtNode::start_with_resolver(config, storage, Arc::new(HangingResolver))
+                .await
+                .unwrap();
+
+        // Commands are answered while the seed lookup is still pending
+        let count = tokio::time::timeout(Duration::from_secs(5), node.peer_count()).await;
+        assert_eq!(count.unwrap(), 0);
+        tokio::time::timeout(Duration::from_secs(5), node.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
     // Integration tests would go here
     // They require actual network connectivity so are marked as ignored
 
@@ -488,4 +1021,324 @@ mod tests {
         assert!(!node.peer_id().to_string().is_empty());
         node.shutdown().await.unwrap();
     }
+
+    async fn next_listening(events: &mut mpsc::Receiver<DhtEvent>) -> Multiaddr {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await {
+                    Some(DhtEvent::Listening { address }) => break address,
+                    Some(_) => continue,
+                    None => panic!("event channel closed"),
+                }
+            }
+        })
+        .await
+        .expect("no Listening event")
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_denied_peer_disconnected() {
+        let dir = tempdir().unwrap();
+
+        // Node B only accepts an unrelated peer, so A is not allowlisted
+        let mut config_b = DhtConfig::with_storage_path(dir.path().join("b"));
+        config_b.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        config_b.enable_mdns = false;
+        config_b.peer_allowlist = vec![PeerId::random()];
+        let storage_b = DhtStorage::open(dir.path().join("b-db"), 1024 * 1024).unwrap();
+        let (node_b, mut events_b) = DhtNode::start(config_b, storage_b).await.unwrap();
+        let addr_b = next_listening(&mut events_b).await;
+
+        let mut config_a = DhtConfig::with_storage_path(dir.path().join("a"));
+        config_a.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        config_a.enable_mdns = false;
+        config_a.bootstrap_nodes = vec![addr_b.to_string()];
+        let storage_a = DhtStorage::open(dir.path().join("a-db"), 1024 * 1024).unwrap();
+        let (node_a, _events_a) = DhtNode::start(config_a, storage_a).await.unwrap();
+
+        let rejected = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events_b.recv().await {
+                    Some(DhtEvent::PeerRejected { peer_id }) => break peer_id,
+                    Some(_) => continue,
+                    None => panic!("event channel closed"),
+                }
+            }
+        })
+        .await
+        .expect("peer was not rejected");
+
+        assert_eq!(rejected, *node_a.peer_id());
+        node_a.shutdown().await.unwrap();
+        node_b.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_event_loop_survives_malformed_shards() {
+        let dir = tempdir().unwrap();
+        let mut config = DhtConfig::with_storage_path(dir.path().join("storage"));
+        config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        let storage = DhtStorage::open(dir.path().join("db"), 1024 * 1024).unwrap();
+        let (node, _events) = DhtNode::start(config.clone(), storage).await.unwrap();
+
+        let data = b"malformed shard test payload";
+        let encoded = MessageFragments::encode(
+            "msg-bad",
+            data,
+            config.fragment_count - 2,
+            2,
+            3600,
+        )
+        .unwrap();
+        // One more bad shard than parity can make up for
+        for mut frag in encoded.fragments.into_iter().flatten() {
+            if frag.index <= 2 {
+                frag.data.truncate(1);
+            }
+            node.storage().store(&frag).unwrap();
+        }
+
+        let result = node.get_message("msg-bad", data.len()).await;
+        assert!(matches!(result, Err(DhtError::DecodeFailed(_))));
+
+        // The event loop must still answer commands
+        assert_eq!(node.peer_count().await, 0);
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_event_loop() {
+        use futures::FutureExt;
+
+        // Loopback only, no bootstrap nodes, seeds or mDNS: nothing here
+        // depends on the network or on timing
+        let dir = tempdir().unwrap();
+        let mut config = DhtConfig::with_storage_path(dir.path().join("storage"));
+        config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        config.bootstrap_nodes.clear();
+        config.dns_seeds.clear();
+        config.enable_mdns = false;
+        let storage = DhtStorage::open(dir.path().join("db"), 1024 * 1024).unwrap();
+        let (node, _events) =
+            DhtNode::start_with_resolver(config, storage, Arc::new(HangingResolver))
+                .await
+                .unwrap();
+        let mut signal = node.shutdown_signal();
+        assert!(!signal.is_shutdown());
+        assert!(signal.wait().now_or_never().is_none());
+
+        // The loop is serving commands before shutdown
+        assert_eq!(node.peer_count().await, 0);
+        assert!(node.is_running());
+
+        // `shutdown` joins the event loop, so everything below holds as soon
+        // as it returns
+        node.shutdown().await.unwrap();
+        assert!(!node.is_running());
+        assert!(signal.is_shutdown());
+        assert!(signal.wait().now_or_never().is_some());
+
+        let fragment = MessageFragments::encode("msg-after", b"payload", 3, 2, 3600)
+            .unwrap()
+            .fragments
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap();
+        let result = node.store_fragment(fragment).await;
+        assert!(matches!(result, Err(DhtError::Internal(ref m)) if m == "Channel closed"));
+
+        // A second shutdown is a no-op
+        node.shutdown().await.unwrap();
+    }
+
+    fn encode_fragments(message_id: &str) -> Vec<Fragment> {
+        MessageFragments::encode(message_id, b"delete me", 3, 2, 3600)
+            .unwrap()
+            .fragments
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn test_delete_message_fragments_stops_republish() {
+        use kad::store::RecordStore;
+
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+        let peer_id = PeerId::random();
+        let mut kademlia = kad::Behaviour::with_config(
+            peer_id,
+            kad::store::MemoryStore::new(peer_id),
+            kad::Config::default(),
+        );
+
+        for fragment in encode_fragments("msg-gone").into_iter().chain(encode_fragments("msg-kept")) {
+            storage.store(&fragment).unwrap();
+            let key = kad::RecordKey::new(&fragment.id.as_str());
+            let record = kad::Record::new(key, fragment.to_bytes().unwrap());
+            kademlia.store_mut().put(record).unwrap();
+        }
+
+        let ids =
+            DhtNode::delete_message_fragments(&mut kademlia, &storage, "msg-gone", &DhtConfig::default())
+                .unwrap();
+        assert_eq!(ids.len(), 5);
+
+        // Nothing left to serve locally or to republish from the record store
+        for id in &ids {
+            assert!(storage.is_deleted(id).unwrap());
+            assert!(storage.get(id).unwrap().is_none());
+            assert!(kademlia.store_mut().get(&kad::RecordKey::new(&id.as_str())).is_none());
+        }
+        assert_eq!(kademlia.store_mut().records().count(), 5);
+        assert_eq!(storage.get_message_fragments("msg-kept").unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_retrieval_pins_until_finished() {
+        let fragments = encode_fragments("msg-pin");
+        let local: u64 = fragments[..2]
+            .iter()
+            .map(|f| f.to_bytes().unwrap().len() as u64)
+            .sum();
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), local).unwrap();
+        for fragment in &fragments[..2] {
+            storage.store(fragment).unwrap();
+        }
+        let peer_id = PeerId::random();
+        let mut kademlia = kad::Behaviour::with_config(
+            peer_id,
+            kad::store::MemoryStore::new(peer_id),
+            kad::Config::default(),
+        );
+        let mut retrievals = Retrievals::default();
+
+        // Two local shards aren't enough, so the other three are fetched
+        let (tx, mut rx) = oneshot::channel();
+        let empty = MessageFragments::new_empty("msg-pin", 3, 2, b"delete me".len());
+        retrievals.start(&mut kademlia, &storage, empty, tx);
+        let queries: Vec<_> = retrievals.by_query.keys().copied().collect();
+        assert_eq!(queries.len(), 3);
+        assert!(rx.try_recv().is_err());
+
+        // The local shards stay pinned while the fetches are outstanding
+        let other = encode_fragments("msg-other").remove(0);
+        assert!(matches!(
+            storage.store(&other),
+            Err(DhtError::Storage(reason)) if reason == "full"
+        ));
+
+        // One fetched shard completes it and cancels the other queries
+        retrievals.answer(&mut kademlia, queries[0], Some(fragments[2].clone()));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), b"delete me");
+        assert!(retrievals.pending.is_empty());
+        assert!(retrievals.by_query.is_empty());
+        storage.store(&other).unwrap();
+
+        // Nothing local and nothing found
+        let (tx, mut rx) = oneshot::channel();
+        let empty = MessageFragments::new_empty("msg-missing", 3, 2, 9);
+        retrievals.start(&mut kademlia, &storage, empty, tx);
+        let queries: Vec<_> = retrievals.by_query.keys().copied().collect();
+        assert_eq!(queries.len(), 5);
+        for query in queries {
+            retrievals.answer(&mut kademlia, query, None);
+        }
+        assert!(matches!(rx.try_recv().unwrap(), Err(DhtError::MessageNotFound(_))));
+        assert!(retrievals.pending.is_empty());
+    }
+
+    #[test]
+    fn test_retrieval_waits_past_malformed_shards() {
+        let fragments = encode_fragments("msg-bad");
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+        for fragment in &fragments[..2] {
+            storage.store(fragment).unwrap();
+        }
+        let peer_id = PeerId::random();
+        let mut kademlia = kad::Behaviour::with_config(
+            peer_id,
+            kad::store::MemoryStore::new(peer_id),
+            kad::Config::default(),
+        );
+        let mut retrievals = Retrievals::default();
+
+        let (tx, mut rx) = oneshot::channel();
+        let empty = MessageFragments::new_empty("msg-bad", 3, 2, b"delete me".len());
+        retrievals.start(&mut kademlia, &storage, empty, tx);
+        let queries: Vec<_> = retrievals.by_query.keys().copied().collect();
+        assert_eq!(queries.len(), 3);
+
+        // A malformed shard is the third present but not the third valid
+        let mut malformed = fragments[2].clone();
+        malformed.data.push(0);
+        retrievals.answer(&mut kademlia, queries[0], Some(malformed));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(retrievals.by_query.len(), 2);
+
+        // A valid shard from another query completes it
+        retrievals.answer(&mut kademlia, queries[1], Some(fragments[3].clone()));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), b"delete me");
+        assert!(retrievals.pending.is_empty());
+        assert!(retrievals.by_query.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_message() {
+        // Loopback only with no peers, so everything is answered locally
+        let dir = tempdir().unwrap();
+        let mut config = DhtConfig::with_storage_path(dir.path().join("storage"));
+        config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        config.enable_mdns = false;
+        config.fragment_count = 5;
+        let storage = DhtStorage::open(dir.path().join("db"), 1024 * 1024).unwrap();
+        let (node, _events) = DhtNode::start(config, storage).await.unwrap();
+
+        node.store_message(b"delete me", "msg-delete").await.unwrap();
+        let ids: Vec<_> = (0..5)
+            .map(|index| MessageFragments::fragment_key("msg-delete", index))
+            .collect();
+        assert!(node.get_fragment(&ids[0]).await.unwrap().is_some());
+
+        assert_eq!(node.delete_message("msg-delete").await.unwrap(), 5);
+        for id in &ids {
+            assert!(node.get_fragment(id).await.unwrap().is_none());
+        }
+        assert_eq!(node.storage().count().unwrap(), 0);
+
+        node.shutdown().await.unwrap();
+        drop(node);
+
+        // Still refused after a restart
+        let storage = DhtStorage::open(dir.path().join("db"), 1024 * 1024).unwrap();
+        for id in &ids {
+            assert!(storage.is_deleted(id).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_port_reports_bound_address() {
+        use libp2p::multiaddr::Protocol;
+
+        let dir = tempdir().unwrap();
+        let mut config = DhtConfig::with_storage_path(dir.path().join("storage"));
+        config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".to_string()];
+        let storage = DhtStorage::open(dir.path().join("db"), 1024 * 1024).unwrap();
+
+        let (node, mut events) = DhtNode::start(config, storage).await.unwrap();
+        let address = next_listening(&mut events).await;
+
+        let port = address.iter().find_map(|p| match p {
+            Protocol::Tcp(port) => Some(port),
+            _ => None,
+        });
+        assert!(matches!(port, Some(p) if p != 0));
+        node.shutdown().await.unwrap();
+    }
 }
@@ -1,13 +1,24 @@
 //! Local storage for DHT fragments
 //!
 //! Uses sled for persistent storage of fragments with automatic expiry.
+//!
+//! Stored fragment data is capped at a fixed number of bytes. When a store
+//! would exceed the cap, the fragments closest to expiry are evicted first,
+//! skipping any pinned by an in-progress local retrieval.
+//!
+//! Fragments of deleted messages are remembered until every copy peers may
+//! still hold has expired, so they are not served again in the meantime.
 
-use sled::{Db, Tree};
+use parking_lot::Mutex;
+use sled::{Db, IVec, Tree};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{debug, info, warn};
 
+use crate::config::DhtConfig;
 use crate::error::{DhtError, Result};
 use crate::fragment::{Fragment, FragmentId};
 
@@ -19,8 +30,17 @@ pub struct DhtStorage {
     fragments: Tree,
     /// Expiry index tree (expiry_timestamp -> fragment_id)
     expiry_index: Tree,
-    /// Maximum storage size
+    /// Deleted fragments (fragment_id -> Unix seconds until refused)
+    deleted: Tree,
+    /// Maximum bytes of fragment data
     max_size: u64,
+    /// Bytes of fragment data stored, updated on every insert and removal
+    used: AtomicU64,
+    /// Fragment keys pinned by local retrievals, with their pin counts
+    pinned: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+    /// Serializes stores and removals so quota checks, inserts and deletes
+    /// don't interleave
+    write_lock: Mutex<()>,
 }
 
 impl DhtStorage {
@@ -29,12 +49,23 @@ impl DhtStorage {
         let db = sled::open(path)?;
         let fragments = db.open_tree("fragments")?;
         let expiry_index = db.open_tree("expiry_index")?;
+        let deleted = db.open_tree("deleted")?;
+
+        let mut used = 0u64;
+        for result in fragments.iter() {
+            let (_, value) = result?;
+            used += value.len() as u64;
+        }
 
         let storage = Self {
             db,
             fragments,
             expiry_index,
+            deleted,
             max_size,
+            used: AtomicU64::new(used),
+            pinned: Arc::new(Mutex::new(HashMap::new())),
+            write_lock: Mutex::new(()),
         };
 
         // Run initial cleanup
@@ -43,28 +74,79 @@ impl DhtStorage {
         Ok(storage)
     }
 
+    /// Open storage at `config.storage_path`, capped at `config.max_storage_bytes`
+    pub fn from_config(config: &DhtConfig) -> Result<Self> {
+        Self::open(&config.storage_path, config.max_storage_bytes)
+    }
+
     /// Store a fragment
+    ///
+    /// If the fragment doesn't fit, fragments closest to expiry are evicted
+    /// to make room. Fails with `DhtError::Storage("full")` when even
+    /// evicting every unpinned fragment would not be enough. Storing a
+    /// deleted fragment revives it.
     pub fn store(&self, fragment: &Fragment) -> Result<()> {
-        // Check storage capacity
-        if self.size()? > self.max_size {
-            warn!("Storage capacity exceeded, running cleanup");
-            self.cleanup_expired()?;
-            self.cleanup_oldest(self.max_size / 10)?; // Remove 10%
-        }
-
         let key = fragment.id.as_str().as_bytes();
         let value = fragment.to_bytes()?;
 
-        self.fragments.insert(key, value)?;
+        let _write = self.write_lock.lock();
+
+        let replaced = self.fragments.get(key)?.map_or(0, |old| old.len() as u64);
+        let needed = self
+            .usage()
+            .saturating_sub(replaced)
+            .saturating_add(value.len() as u64);
+        if needed > self.max_size {
+            warn!("Storage capacity exceeded, evicting fragments");
+            let (victims, freed) = self.eviction_candidates(needed - self.max_size, key)?;
+            if freed < needed - self.max_size {
+                return Err(DhtError::Storage("full".to_string()));
+            }
+            for (expiry_key, fragment_key) in &victims {
+                self.remove_indexed(expiry_key, fragment_key)?;
+            }
+            info!("Evicted {} fragments to free {} bytes", victims.len(), freed);
+        }
+
+        let stored = value.len() as u64;
+        if let Some(old) = self.fragments.insert(key, value)? {
+            self.release(old.len() as u64);
+            if let Ok(old) = Fragment::from_bytes(&old) {
+                let _ = self.expiry_index.remove(Self::expiry_key(&old).as_bytes());
+            }
+        }
+        self.used.fetch_add(stored, Ordering::SeqCst);
 
         // Add to expiry index
-        let expiry_key = format!("{:016x}:{}", fragment.expiry, fragment.id);
-        self.expiry_index.insert(expiry_key.as_bytes(), key)?;
+        self.expiry_index.insert(Self::expiry_key(fragment).as_bytes(), key)?;
+        self.deleted.remove(key)?;
 
         debug!("Stored fragment {}", fragment.id);
         Ok(())
     }
 
+    /// Keep `ids` from being evicted until the guard is dropped
+    ///
+    /// Take this before reading the fragments of a message locally and hold
+    /// it until the message is decoded, network fetches included, so a
+    /// concurrent store can't evict them halfway through.
+    pub fn pin_for_retrieval(&self, ids: impl IntoIterator<Item = FragmentId>) -> RetrievalGuard {
+        let keys: Vec<Vec<u8>> = ids
+            .into_iter()
+            .map(|id| id.as_str().as_bytes().to_vec())
+            .collect();
+
+        let mut pinned = self.pinned.lock();
+        for key in &keys {
+            *pinned.entry(key.clone()).or_insert(0) += 1;
+        }
+
+        RetrievalGuard {
+            pinned: self.pinned.clone(),
+            keys,
+        }
+    }
+
     /// Retrieve a fragment
     pub fn get(&self, id: &FragmentId) -> Result<Option<Fragment>> {
         let key = id.as_str().as_bytes();
@@ -88,13 +170,20 @@ impl DhtStorage {
 
     /// Remove a fragment
     pub fn remove(&self, id: &FragmentId) -> Result<bool> {
+        let _write = self.write_lock.lock();
+        self.remove_unlocked(id)
+    }
+
+    /// Remove a fragment; the caller holds `write_lock`
+    fn remove_unlocked(&self, id: &FragmentId) -> Result<bool> {
         let key = id.as_str().as_bytes();
 
         if let Some(value) = self.fragments.remove(key)? {
+            self.release(value.len() as u64);
+
             // Try to remove from expiry index
             if let Ok(fragment) = Fragment::from_bytes(&value) {
-                let expiry_key = format!("{:016x}:{}", fragment.expiry, fragment.id);
-                let _ = self.expiry_index.remove(expiry_key.as_bytes());
+                let _ = self.expiry_index.remove(Self::expiry_key(&fragment).as_bytes());
             }
 
             debug!("Removed fragment {}", id);
@@ -110,11 +199,16 @@ impl DhtStorage {
         Ok(self.fragments.contains_key(key)?)
     }
 
-    /// Get storage size in bytes
+    /// Get storage size on disk in bytes, including database overhead
     pub fn size(&self) -> Result<u64> {
         Ok(self.db.size_on_disk()?)
     }
 
+    /// Bytes of fragment data stored, as counted against the cap
+    pub fn usage(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
     /// Get fragment count
     pub fn count(&self) -> Result<usize> {
         Ok(self.fragments.len())
@@ -135,60 +229,179 @@ impl DhtStorage {
 
     /// Cleanup expired fragments
     pub fn cleanup_expired(&self) -> Result<usize> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
+        let now = Self::now();
 
         let cutoff = format!("{:016x}", now);
         let mut removed = 0;
 
+        let _write = self.write_lock.lock();
+
         // Iterate through expiry index up to current time
         for result in self.expiry_index.range(..cutoff.as_bytes()) {
             let (expiry_key, fragment_key) = result?;
 
-            // Remove fragment
-            if self.fragments.remove(&fragment_key)?.is_some() {
+            if self.remove_indexed(&expiry_key, &fragment_key)? > 0 {
                 removed += 1;
             }
-
-            // Remove from index
-            self.expiry_index.remove(&expiry_key)?;
         }
 
         if removed > 0 {
             info!("Cleaned up {} expired fragments", removed);
         }
+        self.prune_deleted(now)?;
 
         Ok(removed)
     }
 
+    /// Refuse `ids` until `until` (Unix seconds), by when every copy left
+    /// on peers has expired
+    pub fn mark_deleted(&self, ids: &[FragmentId], until: u64) -> Result<()> {
+        for id in ids {
+            let key = id.as_str().as_bytes();
+            let until = Self::deleted_until(self.deleted.get(key)?).max(until);
+            self.deleted.insert(key, &until.to_be_bytes())?;
+        }
+        self.prune_deleted(Self::now())?;
+        Ok(())
+    }
+
+    /// Whether `id` belongs to a deleted message that peers may still hold
+    pub fn is_deleted(&self, id: &FragmentId) -> Result<bool> {
+        let until = Self::deleted_until(self.deleted.get(id.as_str().as_bytes())?);
+        Ok(until > Self::now())
+    }
+
+    /// Forget deleted fragments whose copies have all expired by `now`
+    fn prune_deleted(&self, now: u64) -> Result<usize> {
+        let mut pruned = 0;
+        for result in self.deleted.iter() {
+            let (key, value) = result?;
+            if Self::deleted_until(Some(value)) <= now {
+                self.deleted.remove(key)?;
+                pruned += 1;
+            }
+        }
+        if pruned > 0 {
+            debug!("Forgot {} deleted fragments past expiry", pruned);
+        }
+        Ok(pruned)
+    }
+
+    /// Refusal deadline stored for a deleted fragment, 0 if none
+    fn deleted_until(value: Option<IVec>) -> u64 {
+        value
+            .and_then(|v| <[u8; 8]>::try_from(v.as_ref()).ok())
+            .map_or(0, u64::from_be_bytes)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
     /// Cleanup oldest fragments to free space
+    ///
+    /// Fragments pinned by a retrieval are skipped.
     pub fn cleanup_oldest(&self, bytes_to_free: u64) -> Result<usize> {
+        let _write = self.write_lock.lock();
+        let (victims, _) = self.eviction_candidates(bytes_to_free, &[])?;
+
         let mut freed: u64 = 0;
         let mut removed = 0;
+        for (expiry_key, fragment_key) in &victims {
+            let size = self.remove_indexed(expiry_key, fragment_key)?;
+            if size > 0 {
+                freed += size;
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            info!("Removed {} old fragments to free {} bytes", removed, freed);
+        }
+
+        Ok(removed)
+    }
 
-        // Iterate through expiry index (oldest first)
+    /// Unpinned fragments closest to expiry that free at least `bytes_to_free`
+    ///
+    /// Returns (expiry key, fragment key) pairs and the bytes they hold,
+    /// which is less than asked when not enough can be evicted. `keep` is
+    /// never chosen.
+    fn eviction_candidates(&self, bytes_to_free: u64, keep: &[u8]) -> Result<(Vec<(IVec, IVec)>, u64)> {
+        let pinned = self.pinned.lock();
+        let mut victims = Vec::new();
+        let mut freed: u64 = 0;
+
+        // Expiry index is ordered soonest-to-expire first
         for result in self.expiry_index.iter() {
             if freed >= bytes_to_free {
                 break;
             }
 
             let (expiry_key, fragment_key) = result?;
-
-            if let Some(value) = self.fragments.remove(&fragment_key)? {
-                freed += value.len() as u64;
-                removed += 1;
+            if fragment_key.as_ref() == keep || pinned.contains_key(fragment_key.as_ref()) {
+                continue;
             }
 
-            self.expiry_index.remove(&expiry_key)?;
+            freed += self.fragments.get(&fragment_key)?.map_or(0, |v| v.len() as u64);
+            victims.push((expiry_key, fragment_key));
         }
 
-        if removed > 0 {
-            info!("Removed {} old fragments to free {} bytes", removed, freed);
+        Ok((victims, freed))
+    }
+
+    /// Remove a fragment and its expiry index entry, returning the bytes freed
+    fn remove_indexed(&self, expiry_key: &[u8], fragment_key: &[u8]) -> Result<u64> {
+        let freed = match self.fragments.remove(fragment_key)? {
+            Some(value) => value.len() as u64,
+            None => 0,
+        };
+        self.release(freed);
+        self.expiry_index.remove(expiry_key)?;
+        Ok(freed)
+    }
+
+    fn release(&self, bytes: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+
+    fn expiry_key(fragment: &Fragment) -> String {
+        format!("{:016x}:{}", fragment.expiry, fragment.id)
+    }
+
+    /// Remove every fragment of a message, expired or not
+    ///
+    /// Returns the IDs of the removed fragments. Entries that don't decode
+    /// are skipped, since their message can't be told; callers that know a
+    /// message's fragment IDs should remove those too.
+    pub fn remove_message(&self, message_id: &str) -> Result<Vec<FragmentId>> {
+        let _write = self.write_lock.lock();
+
+        let mut ids = Vec::new();
+        for result in self.fragments.iter() {
+            let (key, value) = result?;
+            match Fragment::from_bytes(&value) {
+                Ok(fragment) if fragment.message_id == message_id => ids.push(fragment.id),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Skipping undecodable fragment {}: {}",
+                    String::from_utf8_lossy(&key),
+                    e
+                ),
+            }
         }
 
-        Ok(removed)
+        for id in &ids {
+            self.remove_unlocked(id)?;
+        }
+        Ok(ids)
     }
 
     /// Flush to disk
@@ -218,18 +431,42 @@ impl DhtStorage {
     pub fn stats(&self) -> Result<StorageStats> {
         Ok(StorageStats {
             fragment_count: self.count()?,
-            size_bytes: self.size()?,
+            size_bytes: self.usage(),
             max_size_bytes: self.max_size,
         })
     }
 }
 
+/// Pins fragments against eviction while a retrieval reads them
+///
+/// Returned by [`DhtStorage::pin_for_retrieval`]; unpins on drop. The guard
+/// doesn't borrow the storage, so it can outlive a single call and be kept
+/// with a retrieval that is waiting on the network.
+pub struct RetrievalGuard {
+    pinned: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl Drop for RetrievalGuard {
+    fn drop(&mut self) {
+        let mut pinned = self.pinned.lock();
+        for key in &self.keys {
+            if let Some(count) = pinned.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    pinned.remove(key);
+                }
+            }
+        }
+    }
+}
+
 /// Storage statistics
 #[derive(Clone, Debug)]
 pub struct StorageStats {
     /// Number of fragments stored
     pub fragment_count: usize,
-    /// Current size in bytes
+    /// Bytes of fragment data stored
     pub size_bytes: u64,
     /// Maximum size in bytes
     pub max_size_bytes: u64,
@@ -321,4 +558,160 @@ mod tests {
         let removed = storage.cleanup_expired().unwrap();
         assert_eq!(removed, 1);
     }
+
+    fn fragment_for(message_id: &str, expiry_offset: i64) -> Fragment {
+        let mut fragment = create_test_fragment(message_id, expiry_offset);
+        fragment.id = FragmentId::new(message_id, 0);
+        fragment.message_id = message_id.to_string();
+        fragment.data = vec![0xAB; 64];
+        fragment
+    }
+
+    fn stored_len(fragment: &Fragment) -> u64 {
+        fragment.to_bytes().unwrap().len() as u64
+    }
+
+    #[test]
+    fn test_store_past_cap_evicts_nearest_expiry() {
+        let a = fragment_for("msg-a", 3000);
+        let b = fragment_for("msg-b", 1000);
+        let c = fragment_for("msg-c", 2000);
+        let d = fragment_for("msg-d", 4000);
+        let len = stored_len(&a);
+
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), 3 * len).unwrap();
+        for fragment in [&a, &b, &c] {
+            storage.store(fragment).unwrap();
+        }
+        assert_eq!(storage.usage(), 3 * len);
+
+        // b expires soonest
+        storage.store(&d).unwrap();
+        assert!(!storage.contains(&b.id).unwrap());
+        for fragment in [&a, &c, &d] {
+            assert!(storage.contains(&fragment.id).unwrap());
+        }
+        assert_eq!(storage.usage(), 3 * len);
+
+        // c is next, but a retrieval is reading it
+        let e = fragment_for("msg-e", 5000);
+        {
+            let _guard = storage.pin_for_retrieval([c.id.clone()]);
+            storage.store(&e).unwrap();
+            assert!(storage.contains(&c.id).unwrap());
+            assert!(!storage.contains(&a.id).unwrap());
+
+            // Nothing left to evict
+            let _more = storage.pin_for_retrieval([d.id.clone(), e.id.clone()]);
+            assert!(matches!(
+                storage.store(&b),
+                Err(DhtError::Storage(reason)) if reason == "full"
+            ));
+            assert!(!storage.contains(&b.id).unwrap());
+        }
+
+        // Unpinned, c can go
+        storage.store(&b).unwrap();
+        assert!(!storage.contains(&c.id).unwrap());
+        assert_eq!(storage.usage(), 3 * len);
+    }
+
+    #[test]
+    fn test_usage_accounting_after_deletes() {
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+
+        let kept = fragment_for("msg-kept", 3600);
+        let removed = fragment_for("msg-gone", 3600);
+        let dropped = fragment_for("msg-drop", 3600);
+        let expired = fragment_for("msg-past", -10);
+        for fragment in [&kept, &removed, &dropped, &expired] {
+            storage.store(fragment).unwrap();
+        }
+        let len = stored_len(&kept);
+        assert_eq!(storage.usage(), 4 * len);
+
+        // Re-storing replaces rather than double counts
+        storage.store(&kept).unwrap();
+        assert_eq!(storage.usage(), 4 * len);
+
+        storage.remove(&removed.id).unwrap();
+        assert_eq!(storage.usage(), 3 * len);
+
+        storage.remove_message("msg-drop").unwrap();
+        assert_eq!(storage.usage(), 2 * len);
+
+        assert_eq!(storage.cleanup_expired().unwrap(), 1);
+        assert_eq!(storage.usage(), len);
+        assert_eq!(storage.stats().unwrap().size_bytes, len);
+
+        storage.remove(&kept.id).unwrap();
+        assert_eq!(storage.usage(), 0);
+
+        // Reopening recounts from disk
+        storage.store(&kept).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(storage.usage(), len);
+    }
+
+    #[test]
+    fn test_remove_message_skips_corrupt_entries() {
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+
+        let gone = fragment_for("msg-gone", 3600);
+        let mut second = fragment_for("msg-gone", 3600);
+        second.id = FragmentId::new("msg-gone", 1);
+        second.index = 1;
+        let kept = fragment_for("msg-kept", 3600);
+        for fragment in [&gone, &second, &kept] {
+            storage.store(fragment).unwrap();
+        }
+        let junk = FragmentId::new("msg-junk", 0);
+        storage.fragments.insert(junk.as_str().as_bytes(), &[0xFF; 3][..]).unwrap();
+
+        let mut ids = storage.remove_message("msg-gone").unwrap();
+        ids.sort_by_key(|id| id.as_str().to_string());
+        assert_eq!(ids, vec![gone.id.clone(), second.id.clone()]);
+        assert!(storage.contains(&kept.id).unwrap());
+        assert!(storage.contains(&junk).unwrap());
+    }
+
+    #[test]
+    fn test_deleted_fragments_persist_until_expiry() {
+        let dir = tempdir().unwrap();
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+        let now = DhtStorage::now();
+
+        let live = fragment_for("msg-live", 3600);
+        let past = FragmentId::new("msg-past", 0);
+        storage.mark_deleted(&[live.id.clone()], now + 3600).unwrap();
+        storage.mark_deleted(&[past.clone()], now + 3600).unwrap();
+        assert!(storage.is_deleted(&live.id).unwrap());
+
+        // A later, shorter mark never shortens the refusal
+        storage.mark_deleted(&[past.clone()], now - 10).unwrap();
+        assert!(storage.is_deleted(&past).unwrap());
+
+        // Survives a restart
+        storage.flush().unwrap();
+        drop(storage);
+        let storage = DhtStorage::open(dir.path(), 1024 * 1024).unwrap();
+        assert!(storage.is_deleted(&live.id).unwrap());
+        assert_eq!(storage.deleted.len(), 2);
+
+        // Entries whose copies have all expired are forgotten
+        storage.deleted.insert(past.as_str().as_bytes(), &(now - 10).to_be_bytes()).unwrap();
+        assert!(!storage.is_deleted(&past).unwrap());
+        storage.cleanup_expired().unwrap();
+        assert_eq!(storage.deleted.len(), 1);
+
+        // Storing again revives it
+        storage.store(&live).unwrap();
+        assert!(!storage.is_deleted(&live.id).unwrap());
+        assert!(storage.deleted.is_empty());
+    }
 }
@@ -0,0 +1,370 @@
+//! Encrypted account backups
+//!
+//! A backup is a [`StorageSnapshot`] serialized with bincode, compressed
+//! with the writer's [`Compression`] and encrypted under a key derived from a
+//! passphrase with Argon2id. Layout:
+//!
+//! ```text
+//! magic "QHBK" || version (1 byte) || compression (1 byte)
+//!     || decompressed size (u64 BE) || salt (16 bytes) || bincode(chunks)
+//! ```
+//!
+//! The compressed payload is split into chunks no larger than the AEAD
+//! message limit. Each chunk's associated data is the header followed by its
+//! index and the chunk count, so chunks cannot be reordered, dropped or
+//! spliced between backups.
+//!
+//! Version 1 backups have no compression or size fields and are always
+//! DEFLATE; they can still be opened. Versions 1 and 2 hold a snapshot
+//! without conversation settings, quarantine, deliveries or session nonces,
+//! which are restored empty.
+//!
+//! Readers decompress and deserialize at most [`MAX_SNAPSHOT_SIZE`] bytes,
+//! and wipe the decrypted buffers once the snapshot is parsed.
+
+use std::io::Read;
+
+use argon2::Argon2;
+use bincode::Options;
+use flate2::read::DeflateDecoder;
+use rand::RngCore;
+use zeroize::{Zeroize, Zeroizing};
+
+use qiyashash_core::message::Message;
+use qiyashash_core::session::SessionRecord;
+use qiyashash_core::storage::StorageSnapshot;
+use qiyashash_core::types::UserId;
+use qiyashash_core::user::{Contact, User};
+use serde::Deserialize;
+use qiyashash_crypto::aead::{Aead, AeadKey, EncryptedPayload, KEY_SIZE};
+use qiyashash_crypto::rng::secure_rng;
+use qiyashash_crypto::MAX_MESSAGE_SIZE;
+
+use crate::compression::Compression;
+use crate::error::{ProtocolError, Result};
+
+/// Identifies a backup file
+const BACKUP_MAGIC: &[u8; 4] = b"QHBK";
+
+/// Current backup format version
+pub const BACKUP_VERSION: u8 = 3;
+
+/// Argon2 salt size
+const SALT_SIZE: usize = 16;
+
+/// Header length: magic, version, compression, decompressed size, salt
+const HEADER_SIZE: usize = BACKUP_MAGIC.len() + 1 + 1 + 8 + SALT_SIZE;
+
+/// Version 1 header length: magic, version, salt
+const V1_HEADER_SIZE: usize = BACKUP_MAGIC.len() + 1 + SALT_SIZE;
+
+/// Largest serialized snapshot a reader accepts (256 MiB)
+pub const MAX_SNAPSHOT_SIZE: u64 = 256 << 20;
+
+/// bincode with the defaults of `bincode::deserialize`, reading at most
+/// `limit` bytes
+fn bincode_limited(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+/// Encrypt `snapshot` under `passphrase`, compressed with `compression`
+pub fn seal(
+    snapshot: &StorageSnapshot,
+    passphrase: &str,
+    compression: Compression,
+) -> Result<Vec<u8>> {
+    let serialized = Zeroizing::new(
+        bincode::serialize(snapshot).map_err(|e| ProtocolError::Backup(e.to_string()))?,
+    );
+    let compressed = Zeroizing::new(compression.compress(&serialized)?);
+    seal_compressed(
+        BACKUP_VERSION,
+        &compressed,
+        compression,
+        serialized.len() as u64,
+        passphrase,
+    )
+}
+
+/// Encrypt an already compressed payload, recording how to decompress it
+fn seal_compressed(
+    version: u8,
+    compressed: &[u8],
+    compression: Compression,
+    decompressed_size: u64,
+    passphrase: &str,
+) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_SIZE];
+    secure_rng().fill_bytes(&mut salt);
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(BACKUP_MAGIC);
+    header.push(version);
+    header.push(compression.to_byte());
+    header.extend_from_slice(&decompressed_size.to_be_bytes());
+    header.extend_from_slice(&salt);
+
+    let key = derive_key(passphrase, &salt)?;
+    let aead = Aead::new();
+    // A serialized snapshot is never empty, and neither is any compressed
+    // form of it, so there is always at least one chunk
+    let count = compressed.chunks(MAX_MESSAGE_SIZE).len();
+    let mut chunks = Vec::with_capacity(count);
+    for (index, piece) in compressed.chunks(MAX_MESSAGE_SIZE).enumerate() {
+        let aad = chunk_aad(&header, index, count);
+        chunks.push(aead.encrypt(&key, piece, &aad)?);
+    }
+
+    let body = bincode::serialize(&chunks).map_err(|e| ProtocolError::Backup(e.to_string()))?;
+    header.extend_from_slice(&body);
+    Ok(header)
+}
+
+/// Decrypt a backup produced by [`seal`]
+///
+/// Decompresses with the algorithm the backup records, whatever this
+/// client's own setting.
+pub fn open(bytes: &[u8], passphrase: &str) -> Result<StorageSnapshot> {
+    if bytes.len() <= BACKUP_MAGIC.len() || &bytes[..BACKUP_MAGIC.len()] != BACKUP_MAGIC {
+        return Err(ProtocolError::Backup("not a QiyasHash backup".to_string()));
+    }
+    let version = bytes[BACKUP_MAGIC.len()];
+    let header_size = match version {
+        1 => V1_HEADER_SIZE,
+        2 | BACKUP_VERSION => HEADER_SIZE,
+        _ => {
+            return Err(ProtocolError::Backup(format!(
+                "unsupported backup version {}",
+                version
+            )))
+        }
+    };
+    if bytes.len() < header_size {
+        return Err(ProtocolError::Backup("backup header truncated".to_string()));
+    }
+
+    let (header, body) = bytes.split_at(header_size);
+    let salt = &header[header_size - SALT_SIZE..];
+    let chunks: Vec<EncryptedPayload> = bincode_limited(body.len() as u64)
+        .deserialize(body)
+        .map_err(|e| ProtocolError::Backup(e.to_string()))?;
+    if chunks.is_empty() {
+        return Err(ProtocolError::Backup("backup has no content".to_string()));
+    }
+
+    let key = derive_key(passphrase, salt)?;
+    let aead = Aead::new();
+    let mut compressed = Zeroizing::new(Vec::new());
+    for (index, chunk) in chunks.iter().enumerate() {
+        let aad = chunk_aad(header, index, chunks.len());
+        let plaintext = Zeroizing::new(aead.decrypt(&key, chunk, &aad).map_err(|_| {
+            ProtocolError::DecryptionFailed("wrong passphrase or corrupted backup".to_string())
+        })?);
+        compressed.extend_from_slice(&plaintext);
+    }
+
+    let serialized = Zeroizing::new(if version == 1 {
+        decompress_v1(&compressed)?
+    } else {
+        let offset = BACKUP_MAGIC.len() + 1;
+        let compression = Compression::from_byte(header[offset])?;
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&header[offset + 1..offset + 9]);
+        let size = u64::from_be_bytes(size);
+        if size > MAX_SNAPSHOT_SIZE {
+            return Err(ProtocolError::Decompression(format!(
+                "backup of {} bytes exceeds the {} byte limit",
+                size, MAX_SNAPSHOT_SIZE
+            )));
+        }
+        compression.decompress(&compressed, size)?
+    });
+
+    let snapshot = if version < BACKUP_VERSION {
+        bincode_limited(MAX_SNAPSHOT_SIZE)
+            .deserialize::<SnapshotV2>(&serialized)
+            .map(StorageSnapshot::from)
+    } else {
+        bincode_limited(MAX_SNAPSHOT_SIZE).deserialize(&serialized)
+    };
+    snapshot.map_err(|e| ProtocolError::Backup(e.to_string()))
+}
+
+/// Snapshot layout of version 1 and 2 backups
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct SnapshotV2 {
+    users: Vec<User>,
+    contacts: Vec<Contact>,
+    sessions: Vec<SessionRecord>,
+    messages: Vec<Message>,
+    identity_key: Option<Vec<u8>>,
+    remote_identities: Vec<(UserId, [u8; 32])>,
+    signed_prekeys: Vec<(u32, Vec<u8>)>,
+    one_time_prekeys: Vec<(u32, Vec<u8>)>,
+}
+
+impl From<SnapshotV2> for StorageSnapshot {
+    fn from(old: SnapshotV2) -> Self {
+        Self {
+            users: old.users,
+            contacts: old.contacts,
+            sessions: old.sessions,
+            messages: old.messages,
+            identity_key: old.identity_key,
+            remote_identities: old.remote_identities,
+            signed_prekeys: old.signed_prekeys,
+            one_time_prekeys: old.one_time_prekeys,
+            ..Default::default()
+        }
+    }
+}
+
+/// Version 1 backups are DEFLATE with no recorded size
+fn decompress_v1(compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut serialized = Vec::new();
+    DeflateDecoder::new(compressed)
+        .take(MAX_SNAPSHOT_SIZE + 1)
+        .read_to_end(&mut serialized)
+        .map_err(|e| ProtocolError::Decompression(e.to_string()))?;
+    if serialized.len() as u64 > MAX_SNAPSHOT_SIZE {
+        serialized.zeroize();
+        return Err(ProtocolError::Decompression(format!(
+            "backup exceeds the {} byte limit",
+            MAX_SNAPSHOT_SIZE
+        )));
+    }
+    Ok(serialized)
+}
+
+/// Stretch `passphrase` into an AEAD key with Argon2id
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<AeadKey> {
+    let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| ProtocolError::Backup(e.to_string()))?;
+    Ok(AeadKey::from_bytes(*key))
+}
+
+/// Associated data binding a chunk to its backup and position
+fn chunk_aad(header: &[u8], index: usize, count: usize) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + 8);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&(index as u32).to_be_bytes());
+    aad.extend_from_slice(&(count as u32).to_be_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_large_snapshot() {
+        // Large enough to need several chunks even after compression
+        let mut noise = vec![0u8; 3 * MAX_MESSAGE_SIZE];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let snapshot = StorageSnapshot {
+            identity_key: Some(noise.clone()),
+            ..Default::default()
+        };
+
+        let sealed = seal(&snapshot, "correct horse", Compression::default()).unwrap();
+        let opened = open(&sealed, "correct horse").unwrap();
+        assert_eq!(opened.identity_key, Some(noise));
+
+        assert!(matches!(
+            open(&sealed, "battery staple"),
+            Err(ProtocolError::DecryptionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_seal_open_each_compression() {
+        let snapshot = StorageSnapshot {
+            identity_key: Some(b"identity ".repeat(1000)),
+            ..Default::default()
+        };
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Deflate] {
+            let sealed = seal(&snapshot, "pass", compression).unwrap();
+            assert_eq!(sealed[BACKUP_MAGIC.len() + 1], compression.to_byte());
+            let opened = open(&sealed, "pass").unwrap();
+            assert_eq!(
+                opened.identity_key, snapshot.identity_key,
+                "{:?}",
+                compression
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_rejects_implausible_size() {
+        let serialized = bincode::serialize(&StorageSnapshot::default()).unwrap();
+        let compressed = Compression::Zstd.compress(&serialized).unwrap();
+        let sealed = seal_compressed(
+            BACKUP_VERSION,
+            &compressed,
+            Compression::Zstd,
+            MAX_SNAPSHOT_SIZE + 1,
+            "pass",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            open(&sealed, "pass"),
+            Err(ProtocolError::Decompression(_))
+        ));
+    }
+
+    #[test]
+    fn test_open_version_2_snapshot() {
+        let old = SnapshotV2 {
+            users: Vec::new(),
+            contacts: Vec::new(),
+            sessions: Vec::new(),
+            messages: Vec::new(),
+            identity_key: Some(vec![0x01; 32]),
+            remote_identities: vec![(UserId::from_string("bob"), [0x02; 32])],
+            signed_prekeys: vec![(1, vec![0x03])],
+            one_time_prekeys: vec![(2, vec![0x04])],
+        };
+        let serialized = bincode::serialize(&old).unwrap();
+        let compressed = Compression::Zstd.compress(&serialized).unwrap();
+        let sealed = seal_compressed(
+            2,
+            &compressed,
+            Compression::Zstd,
+            serialized.len() as u64,
+            "pass",
+        )
+        .unwrap();
+
+        let opened = open(&sealed, "pass").unwrap();
+        assert_eq!(opened.identity_key, Some(vec![0x01; 32]));
+        assert_eq!(opened.remote_identities, old.remote_identities);
+        assert_eq!(opened.signed_prekeys, old.signed_prekeys);
+        assert_eq!(opened.one_time_prekeys, old.one_time_prekeys);
+        assert!(opened.conversation_settings.is_empty());
+        assert!(opened.quarantine.is_empty());
+        assert!(opened.deliveries.is_empty());
+        assert!(opened.session_nonces.is_empty());
+    }
+
+    #[test]
+    fn test_open_rejects_other_versions() {
+        let mut sealed = seal(&StorageSnapshot::default(), "pass", Compression::default()).unwrap();
+        sealed[BACKUP_MAGIC.len()] = BACKUP_VERSION + 1;
+        assert!(matches!(
+            open(&sealed, "pass"),
+            Err(ProtocolError::Backup(_))
+        ));
+        assert!(matches!(
+            open(b"nope", "pass"),
+            Err(ProtocolError::Backup(_))
+        ));
+    }
+}
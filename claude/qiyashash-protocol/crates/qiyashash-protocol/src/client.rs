@@ -3,26 +3,39 @@
 //! The ProtocolClient provides a high-level interface for sending and
 //! receiving encrypted messages.
 
+use std::collections::HashMap;
 use std::sync::Arc;
 use parking_lot::RwLock;
+use rand::RngCore;
+use tokio::sync::broadcast;
 use tracing::{debug, info, warn, error, instrument};
 
-use qiyashash_core::message::{Message, MessageEnvelope, MessageId, RatchetHeaderWire};
-use qiyashash_core::session::SessionId;
-use qiyashash_core::storage::{Storage, MessageStore, SessionStore, IdentityStore, PreKeyStore};
+use qiyashash_core::message::{
+    DeliveryFailure, Message, MessageEnvelope, MessageId, MessageReceipt, MessageStatus,
+    QuarantinedPayload, RatchetHeaderWire, Reaction, ReceiptType, SEALED_SENDER_IDENTITY,
+};
+use qiyashash_core::session::{SessionId, SessionState};
+use qiyashash_core::storage::{Storage, MessageStore, SessionStore, IdentityStore, PreKeyStore, UserStore};
 use qiyashash_core::types::{DeviceId, Fingerprint, Timestamp, UserId};
-use qiyashash_core::user::User;
-use qiyashash_crypto::identity::Identity;
-use qiyashash_crypto::chain::compute_message_hash;
+use qiyashash_core::user::{TrustLevel, User, UserProfile};
+use qiyashash_crypto::identity::{Identity, IdentityPublicKey};
 use qiyashash_crypto::kdf::derive_chain_proof;
+use qiyashash_crypto::rng::secure_rng;
+use qiyashash_crypto::x3dh::X3DHHeader;
+use qiyashash_crypto::MAX_MESSAGE_SIZE;
 
 use crate::config::ClientConfig;
 use crate::error::{ProtocolError, Result};
+use crate::identity_service::IdentityServiceClient;
+use crate::metrics::{Counter, Histogram, NoopMetrics, ProtocolMetrics};
 use crate::protocol::{
-    DevicePreKeyBundle, PreKeyBundleRequest, PreKeyBundleResponse,
-    ProtocolMessage, ProtocolMessageType,
+    DevicePreKeyBundle, IdentityKeyUpdate, IdentityUpdateReason, KeyConfirmation, OneTimePreKeyInfo,
+    PreKeyBundleRequest, PreKeyBundleResponse, PrekeyReplenish, ProtocolMessage,
+    ProtocolMessageType, SealedSenderContent, VersionRange,
 };
-use crate::session_manager::SessionManager;
+use crate::rotation::{Clock, KeyRotationPolicy, RotationReport, StoredIdentityKey, StoredSignedPreKey};
+use crate::session_manager::{SessionInfo, SessionManager};
+use crate::transport::MessageTransport;
 
 /// Protocol client state
 enum ClientState {
@@ -36,6 +49,63 @@ enum ClientState {
     ShuttingDown,
 }
 
+/// Capacity of the notification channel
+const NOTIFICATION_CHANNEL_CAPACITY: usize = 64;
+
+/// Capacity of the event channel
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+
+/// Attempts at evicting sessions that keep being used meanwhile
+const MAX_EVICTION_ROUNDS: usize = 3;
+
+/// Per-message results of a batch call such as
+/// [`ProtocolClient::decrypt_messages`]
+#[derive(Debug)]
+pub struct BatchOutcome<T> {
+    /// One result per input, in order
+    pub results: Vec<Result<T>>,
+    /// Why the decrypted messages could not be stored, if they weren't
+    ///
+    /// They are then only in `results`; their ratchet keys are gone.
+    pub save_error: Option<ProtocolError>,
+}
+
+/// Signal that a received message should be announced to the user
+#[derive(Clone, Debug)]
+pub struct Notification {
+    /// Message ID
+    pub message_id: MessageId,
+    /// Sender
+    pub sender_id: UserId,
+    /// Custom sound for the conversation, if any
+    pub sound: Option<String>,
+    /// Message text, unless previews are disabled for the conversation
+    pub preview: Option<String>,
+}
+
+/// Outcome of [`ProtocolClient::flush_pending`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlushReport {
+    /// Messages handed to the transport, now stored as sent
+    pub sent: Vec<MessageId>,
+    /// Messages that failed again; their status records why
+    pub failed: Vec<MessageId>,
+}
+
+/// Security-relevant change observed by the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// A sender's identity key differs from the one stored for them
+    SafetyNumberChanged {
+        /// Sender
+        user_id: UserId,
+        /// Previously stored Ed25519 identity key
+        old_key: [u8; 32],
+        /// Identity key the sender now uses
+        new_key: [u8; 32],
+    },
+}
+
 /// Protocol client for encrypted messaging
 pub struct ProtocolClient<S: Storage> {
     /// Configuration
@@ -44,12 +114,28 @@ pub struct ProtocolClient<S: Storage> {
     user_id: UserId,
     /// Our device ID
     device_id: DeviceId,
+    /// Identity to adopt when storage holds none
+    initial_identity: Option<Identity>,
     /// Session manager
     session_manager: RwLock<Option<SessionManager>>,
     /// Storage backend
     storage: Arc<S>,
+    /// Held while a session changes and its new state is saved, so an older
+    /// snapshot never overwrites a newer one
+    session_writes: tokio::sync::Mutex<()>,
+    /// Held by [`Self::flush_pending`], so two flushes never send the same
+    /// message
+    flushing: tokio::sync::Mutex<()>,
     /// Client state
     state: RwLock<ClientState>,
+    /// Current time, for mute expiry, bundle age and session activity
+    clock: Clock,
+    /// Notifications for received messages
+    notifications: broadcast::Sender<Notification>,
+    /// Security events
+    events: broadcast::Sender<ClientEvent>,
+    /// Counters for the embedding app
+    metrics: Arc<dyn ProtocolMetrics>,
 }
 
 impl<S: Storage + 'static> ProtocolClient<S> {
@@ -59,12 +145,73 @@ impl<S: Storage + 'static> ProtocolClient<S> {
             config,
             user_id: UserId::new(),
             device_id: DeviceId::new(),
+            initial_identity: None,
             session_manager: RwLock::new(None),
             storage,
+            session_writes: tokio::sync::Mutex::new(()),
+            flushing: tokio::sync::Mutex::new(()),
             state: RwLock::new(ClientState::Uninitialized),
+            clock: Arc::new(Timestamp::now),
+            notifications: broadcast::channel(NOTIFICATION_CHANNEL_CAPACITY).0,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            metrics: Arc::new(NoopMetrics),
         }
     }
 
+    /// Use `clock` instead of the system time when checking mutes, prekey
+    /// bundle ages and session activity, and when stamping reactions
+    pub fn with_clock(mut self, clock: impl Fn() -> Timestamp + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Report counters and sizes to `metrics` instead of discarding them
+    pub fn with_metrics(mut self, metrics: Arc<dyn ProtocolMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Act as `user_id` on device `device_id` instead of freshly generated IDs
+    ///
+    /// For apps that persist their IDs across restarts; the device ID ends
+    /// up in our prekey bundles and sessions.
+    pub fn with_ids(mut self, user_id: UserId, device_id: DeviceId) -> Self {
+        self.user_id = user_id;
+        self.device_id = device_id;
+        self
+    }
+
+    /// Adopt `identity` instead of generating one if storage holds none
+    ///
+    /// For apps that keep their identity key outside the protocol storage.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.initial_identity = Some(identity);
+        self
+    }
+
+    /// Receive a [`Notification`] for every message from an unmuted conversation
+    pub fn subscribe_notifications(&self) -> broadcast::Receiver<Notification> {
+        self.notifications.subscribe()
+    }
+
+    /// Receive [`ClientEvent`]s, such as changed safety numbers
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    /// Mark the identity key currently stored for `user_id` as verified
+    ///
+    /// Called once the user has compared safety numbers; messages from the
+    /// sender are no longer flagged until their key changes again.
+    pub async fn verify_identity(&self, user_id: &UserId) -> Result<()> {
+        let key = self.storage.get_remote_identity(user_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?
+            .ok_or_else(|| ProtocolError::UntrustedIdentity(user_id.to_string()))?;
+
+        let identity = IdentityPublicKey::from_bytes(&key)?;
+        self.save_trust_level(user_id, &identity, TrustLevel::Verified).await
+    }
+
     /// Initialize the client with a new or existing identity
     #[instrument(skip(self))]
     pub async fn initialize(&self) -> Result<()> {
@@ -80,16 +227,32 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         info!("Initializing protocol client");
 
         // Try to load existing identity
-        let identity = match self.load_identity().await? {
-            Some(identity) => {
+        let (identity, account_secret) = match self.load_identity().await? {
+            Some(stored) => {
                 info!("Loaded existing identity");
-                identity
+                // Ages are counted from the first load when the creation
+                // time was never recorded
+                if stored.created_at.is_none() {
+                    self.save_identity(stored.secret, stored.account_secret, Timestamp::now()).await?;
+                }
+                let key_pair = qiyashash_crypto::identity::IdentityKeyPair::from_secret_bytes(&stored.secret);
+                (Identity::from_key_pair(key_pair), stored.account_secret)
             }
             None => {
-                info!("Creating new identity");
-                let identity = Identity::new();
-                self.save_identity(&identity).await?;
-                identity
+                let identity = match &self.initial_identity {
+                    Some(initial) => {
+                        info!("Adopting provided identity");
+                        Identity::from_key_pair(initial.key_pair.clone())
+                    }
+                    None => {
+                        info!("Creating new identity");
+                        Identity::new()
+                    }
+                };
+                let mut account_secret = [0u8; 32];
+                secure_rng().fill_bytes(&mut account_secret);
+                self.save_identity(identity.key_pair.secret_bytes(), account_secret, Timestamp::now()).await?;
+                (identity, account_secret)
             }
         };
 
@@ -97,13 +260,17 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         let session_manager = SessionManager::new(
             self.config.clone(),
             identity,
+            account_secret,
             self.device_id.clone(),
             self.storage.clone(),
             self.storage.clone(),
             self.storage.clone(),
         ).await?;
+        let clock = self.clock.clone();
+        let session_manager = session_manager.with_clock(move || clock());
 
         *self.session_manager.write() = Some(session_manager);
+        self.save_signed_prekey(Timestamp::now()).await?;
         *self.state.write() = ClientState::Ready;
 
         info!("Protocol client initialized");
@@ -115,6 +282,11 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         matches!(*self.state.read(), ClientState::Ready)
     }
 
+    /// Configuration this client runs with
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
     /// Get our user ID
     pub fn user_id(&self) -> &UserId {
         &self.user_id
@@ -130,12 +302,22 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         self.with_session_manager(|sm| Ok(sm.fingerprint()))
     }
 
+    /// Summaries of our active sessions, oldest first
+    pub fn sessions(&self) -> Result<Vec<SessionInfo>> {
+        self.with_session_manager(|sm| Ok(sm.session_infos()))
+    }
+
     /// Get our prekey bundle for publishing
     pub fn get_prekey_bundle(&self) -> Result<qiyashash_crypto::keys::PreKeyBundle> {
         self.with_session_manager(|sm| Ok(sm.get_prekey_bundle()))
     }
 
     /// Send a text message to a user
+    ///
+    /// The message is stored as pending; if it cannot be encrypted it is
+    /// stored as failed instead, with the reason. Once the envelope has been
+    /// handed to the network, record that with [`Self::record_sent`], or
+    /// [`Self::flush_pending`] will send it again.
     #[instrument(skip(self, content))]
     pub async fn send_message(
         &self,
@@ -144,19 +326,307 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         content: &str,
     ) -> Result<MessageEnvelope> {
         self.ensure_ready()?;
+        self.check_outgoing(recipient_id, content)?;
 
         // Create message
-        let message = Message::text(
+        let mut message = Message::text(
             self.user_id.clone(),
             self.device_id.clone(),
             recipient_id.clone(),
             content,
-        );
+        )
+        .with_recipient_device(recipient_device_id.clone());
 
         // Encrypt and send
+        let result = self.encrypt_message(recipient_id, recipient_device_id, &message).await;
+        if let Err(e) = &result {
+            message.mark_failed(e.delivery_failure());
+            warn!("Message {} to {} failed: {}", message.id, recipient_id, e);
+        }
+
+        self.storage.save_message(&message).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        result
+    }
+
+    /// React to a message, or withdraw a reaction with `remove`
+    ///
+    /// The reaction is attached to our own copy of `target` instead of
+    /// being stored as a message.
+    #[instrument(skip(self))]
+    pub async fn send_reaction(
+        &self,
+        recipient_id: &UserId,
+        recipient_device_id: &DeviceId,
+        target: &MessageId,
+        emoji: &str,
+        remove: bool,
+    ) -> Result<MessageEnvelope> {
+        self.ensure_ready()?;
+
+        let reaction = Reaction {
+            target: target.clone(),
+            emoji: emoji.to_string(),
+            remove,
+        };
+        let message = Message::reaction(
+            self.user_id.clone(),
+            self.device_id.clone(),
+            recipient_id.clone(),
+            &reaction,
+        )?;
+
+        let envelope = self.encrypt_message(recipient_id, recipient_device_id, &message).await?;
+        self.apply_reaction(&self.user_id, &reaction).await?;
+        Ok(envelope)
+    }
+
+    /// Attach `sender_id`'s reaction to the stored target message
+    ///
+    /// Reactions to messages we don't have, or that aren't part of the
+    /// conversation with `sender_id`, are dropped.
+    async fn apply_reaction(&self, sender_id: &UserId, reaction: &Reaction) -> Result<()> {
+        let target = self.storage.get_message(&reaction.target).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        let mut target = match target {
+            Some(target) if &target.sender_id == sender_id || &target.recipient_id == sender_id => {
+                target
+            }
+            _ => {
+                debug!("Dropping reaction from {} to unknown message {}", sender_id, reaction.target);
+                return Ok(());
+            }
+        };
+
+        target.apply_reaction(sender_id, reaction, (self.clock)());
+        self.storage.save_message(&target).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Acknowledge a message from `recipient_id` as delivered or read
+    ///
+    /// The receipt goes through the session like any message, so only the
+    /// peer can read it, and is not stored.
+    #[instrument(skip(self))]
+    pub async fn send_receipt(
+        &self,
+        recipient_id: &UserId,
+        recipient_device_id: &DeviceId,
+        message_id: &MessageId,
+        receipt_type: ReceiptType,
+    ) -> Result<MessageEnvelope> {
+        self.ensure_ready()?;
+
+        let receipt = MessageReceipt {
+            message_id: message_id.clone(),
+            receipt_type,
+            timestamp: (self.clock)(),
+        };
+        let message = Message::receipt(
+            self.user_id.clone(),
+            self.device_id.clone(),
+            recipient_id.clone(),
+            &receipt,
+        )?;
+
         self.encrypt_message(recipient_id, recipient_device_id, &message).await
     }
 
+    /// Record `sender_id`'s receipt on the message of ours it refers to
+    ///
+    /// Receipts for messages we did not send to `sender_id` are dropped, and
+    /// a late delivery receipt never takes back a read.
+    async fn apply_receipt(&self, sender_id: &UserId, receipt: &MessageReceipt) -> Result<()> {
+        let message = self.storage.get_message(&receipt.message_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        let mut message = match message {
+            Some(message) if message.sender_id == self.user_id && &message.recipient_id == sender_id => {
+                message
+            }
+            _ => {
+                debug!("Dropping receipt from {} for unknown message {}", sender_id, receipt.message_id);
+                return Ok(());
+            }
+        };
+
+        message.status = match (receipt.receipt_type, &message.status) {
+            (ReceiptType::Delivered, MessageStatus::Read) => return Ok(()),
+            (ReceiptType::Delivered, _) => MessageStatus::Delivered,
+            (ReceiptType::Read, _) => MessageStatus::Read,
+        };
+        self.storage.save_message(&message).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Apply a reaction or receipt from `sender_id` to the message it refers
+    /// to; other messages are left alone
+    ///
+    /// [`Self::process_message`] does this itself; callers of
+    /// [`Self::decrypt_message`] pass on what it returns.
+    pub async fn apply_control(&self, sender_id: &UserId, message: &Message) -> Result<()> {
+        if let Some(reaction) = message.as_reaction() {
+            self.apply_reaction(sender_id, &reaction?).await?;
+        } else if let Some(receipt) = message.as_receipt() {
+            self.apply_receipt(sender_id, &receipt?).await?;
+        }
+        Ok(())
+    }
+
+    /// Record that delivering a stored message failed, e.g. at the relay
+    ///
+    /// Returns the message's new status.
+    pub async fn record_delivery_failure(
+        &self,
+        message_id: &MessageId,
+        reason: DeliveryFailure,
+    ) -> Result<MessageStatus> {
+        let mut message = self.storage.get_message(message_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?
+            .ok_or_else(|| ProtocolError::InvalidMessage(format!("unknown message {}", message_id)))?;
+
+        message.mark_failed(reason);
+        self.storage.save_message(&message).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        Ok(message.status)
+    }
+
+    /// Record that a stored message was handed to the network
+    pub async fn record_sent(&self, message_id: &MessageId) -> Result<()> {
+        let mut message = self.storage.get_message(message_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?
+            .ok_or_else(|| ProtocolError::InvalidMessage(format!("unknown message {}", message_id)))?;
+
+        message.status = MessageStatus::Sent;
+        self.storage.save_message(&message).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Send our pending messages through `transport`, e.g. on reconnect
+    ///
+    /// Messages composed while offline, and earlier attempts that failed in a
+    /// way worth retrying, are encrypted again and sent oldest first. If the
+    /// session with a recipient was lost meanwhile, a new one is established
+    /// from the bundle `identity_client` serves. A message stored without a
+    /// recipient device goes to the device we last talked to. Each message
+    /// is then stored as sent, or as failed with the reason; permanent
+    /// failures leave the queue. A flush started while another runs waits
+    /// for it, then sends whatever is still pending.
+    #[instrument(skip(self, transport, identity_client))]
+    pub async fn flush_pending(
+        &self,
+        transport: &dyn MessageTransport,
+        identity_client: &dyn IdentityServiceClient,
+    ) -> Result<FlushReport> {
+        self.ensure_ready()?;
+        let _flushing = self.flushing.lock().await;
+
+        let mut pending = self.storage.get_pending_messages().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        pending.retain(|message| message.sender_id == self.user_id);
+        pending.sort_by_key(|message| message.created_at);
+
+        let mut report = FlushReport::default();
+        for mut message in pending {
+            match self.resend(transport, identity_client, &mut message).await {
+                Ok(()) => {
+                    message.status = MessageStatus::Sent;
+                    report.sent.push(message.id.clone());
+                }
+                Err(e @ (ProtocolError::Storage(_) | ProtocolError::NotInitialized)) => return Err(e),
+                Err(e) => {
+                    message.mark_failed(e.delivery_failure());
+                    warn!("Resending message {} to {} failed: {}", message.id, message.recipient_id, e);
+                    report.failed.push(message.id.clone());
+                }
+            }
+
+            self.storage.save_message(&message).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        }
+
+        if !report.sent.is_empty() || !report.failed.is_empty() {
+            info!("Flushed pending messages: {} sent, {} failed", report.sent.len(), report.failed.len());
+        }
+        Ok(report)
+    }
+
+    /// Encrypt a stored message again and send it, re-establishing the
+    /// session first if it was lost
+    ///
+    /// A message without a recipient device is assigned one.
+    async fn resend(
+        &self,
+        transport: &dyn MessageTransport,
+        identity_client: &dyn IdentityServiceClient,
+        message: &mut Message,
+    ) -> Result<()> {
+        let recipient_id = message.recipient_id.clone();
+        let recipient_id = &recipient_id;
+        let device_id = match message.recipient_device_id.clone() {
+            Some(device_id) => device_id,
+            None => {
+                let device_id = self.last_device_of(recipient_id).await?;
+                debug!("Sending message {} to {} device {}", message.id, recipient_id, device_id);
+                message.recipient_device_id = Some(device_id.clone());
+                device_id
+            }
+        };
+
+        if self.active_session(recipient_id, &device_id).await?.is_none() {
+            debug!("Re-establishing session with {} device {}", recipient_id, device_id);
+            let established = self
+                .establish_session_from_server(identity_client, recipient_id, &device_id)
+                .await;
+            if let Err(e) = established {
+                return Err(match e {
+                    ProtocolError::Storage(_)
+                    | ProtocolError::NotInitialized
+                    | ProtocolError::Delivery(_)
+                    | ProtocolError::InvalidPreKeyBundle(_)
+                    | ProtocolError::UntrustedIdentity(_)
+                    | ProtocolError::IncompatibleVersions { .. }
+                    | ProtocolError::Crypto(_) => e,
+                    // Anything else, e.g. the identity service having a bad
+                    // moment, is worth another try later
+                    e => {
+                        warn!("Could not re-establish session with {}: {}", recipient_id, e);
+                        ProtocolError::SessionNotEstablished(recipient_id.to_string())
+                    }
+                });
+            }
+        }
+
+        // Sent as it was composed, not with the local delivery state
+        let mut outgoing = message.clone();
+        outgoing.status = MessageStatus::Pending;
+        let envelope = self.encrypt_message(recipient_id, &device_id, &outgoing).await?;
+
+        let protocol_message = ProtocolMessage::new(
+            ProtocolMessageType::EncryptedMessage(envelope),
+            self.user_id.clone(),
+            self.device_id.clone(),
+        );
+        transport.send(recipient_id, &device_id, protocol_message).await
+    }
+
+    /// Device of `user_id` with the most recently used open session
+    ///
+    /// Fails as [`ProtocolError::SessionNotEstablished`], which is retried,
+    /// while we have never talked to any of their devices.
+    async fn last_device_of(&self, user_id: &UserId) -> Result<DeviceId> {
+        let sessions = self.storage.get_sessions_for_user(user_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        sessions
+            .into_iter()
+            .filter(|record| record.session.state != SessionState::Closed)
+            .max_by_key(|record| record.session.last_activity_at)
+            .map(|record| record.session.their_device_id)
+            .ok_or_else(|| ProtocolError::SessionNotEstablished(user_id.to_string()))
+    }
+
     /// Encrypt a message for a recipient
     #[instrument(skip(self, message))]
     pub async fn encrypt_message(
@@ -168,9 +638,7 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         self.ensure_ready()?;
 
         // Check for existing session
-        let session_id = self.with_session_manager(|sm| {
-            Ok(sm.get_session(recipient_id, recipient_device_id))
-        })?;
+        let session_id = self.active_session(recipient_id, recipient_device_id).await?;
 
         let session_id = match session_id {
             Some(id) => id,
@@ -180,14 +648,36 @@ impl<S: Storage + 'static> ProtocolClient<S> {
             }
         };
 
+        // Held as pending until the peer has confirmed the keys
+        if !self.with_session_manager(|sm| Ok(sm.is_confirmed(&session_id)))? {
+            debug!("Holding message to {} until keys are confirmed", recipient_id);
+            return Err(ProtocolError::SessionNotEstablished(recipient_id.to_string()));
+        }
+
         // Serialize message
         let plaintext = message.to_bytes()
             .map_err(|e| ProtocolError::Internal(e.to_string()))?;
 
+        // Get our identity key
+        let identity = self.with_session_manager(|sm| Ok(sm.identity_public_key()))?;
+        let initiation = self.with_session_manager(|sm| Ok(sm.pending_initiation(&session_id)))?;
+
+        // In sealed-sender mode the identity only exists inside the
+        // ciphertext, except while the peer still needs it for X3DH
+        let (plaintext, sender_identity_key) = if self.config.sealed_sender && initiation.is_none() {
+            let sealed = SealedSenderContent::new(&identity, plaintext).encode()?;
+            (sealed, SEALED_SENDER_IDENTITY)
+        } else {
+            (plaintext, identity.signing_key_bytes())
+        };
+
         // Encrypt
-        let (ciphertext, chain_state, msg_hash) = self.with_session_manager(|sm| {
+        let (ciphertext, chain_state, msg_hash) = self.with_saved_session(&session_id, |sm| {
             sm.encrypt(&session_id, &plaintext)
-        })?;
+        }).await?;
+        self.metrics.increment(Counter::MessagesEncrypted);
+        self.metrics.observe(Histogram::PlaintextBytes, plaintext.len() as f64);
+        self.metrics.observe(Histogram::CiphertextBytes, ciphertext.len() as f64);
 
         // Create timestamp hash
         let timestamp = Timestamp::now();
@@ -196,37 +686,36 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         // Create chain proof
         let chain_proof = derive_chain_proof(&chain_state, &msg_hash, timestamp.as_millis() as u64);
 
-        // Get our identity key
-        let identity_key = self.with_session_manager(|sm| {
-            Ok(sm.identity_public_key().signing_key_bytes())
-        })?;
-
-        // Get ratchet public key
-        let ratchet_public = self.with_session_manager(|sm| {
-            sm.encrypt(&session_id, &[]) // Dummy call to get current key
-                .map(|(_, _, _)| [0u8; 32]) // Placeholder
-                .or_else(|_| Ok([0u8; 32]))
+        let version = self.with_session_manager(|sm| {
+            sm.session_version(&session_id)
+                .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
         })?;
 
         // Create envelope
         let envelope = MessageEnvelope {
-            version: crate::PROTOCOL_VERSION,
-            sender_identity_key: identity_key,
-            ephemeral_key: None, // Only for initial message
-            one_time_prekey_id: None,
+            version,
+            sender_identity_key,
+            ephemeral_key: initiation.as_ref().map(|header| header.ephemeral_key.0),
+            one_time_prekey_id: initiation.as_ref().and_then(|header| header.one_time_prekey_id),
+            // The ratchet header travels inside the ciphertext
             ratchet_header: RatchetHeaderWire {
-                dh_public: ratchet_public,
+                dh_public: [0; 32],
                 message_number: 0, // Would come from ratchet
                 previous_chain_length: 0,
             },
             ciphertext,
             chain_proof,
             timestamp_hash,
+            signed_prekey_id: initiation.as_ref().map(|header| header.signed_prekey_id),
+            session_nonce: initiation.map(|header| header.session_nonce),
         };
 
-        // Save message to storage
-        self.storage.save_message(message).await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        // Save message to storage; reactions and receipts only update
+        // other messages
+        if !message.is_control() {
+            self.storage.save_message(message).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        }
 
         debug!("Encrypted message {} for {}", message.id, recipient_id);
         Ok(envelope)
@@ -240,141 +729,885 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         sender_device_id: &DeviceId,
         envelope: &MessageEnvelope,
     ) -> Result<Message> {
-        self.ensure_ready()?;
-
-        // Verify protocol version
-        if envelope.version != crate::PROTOCOL_VERSION {
-            return Err(ProtocolError::VersionMismatch {
-                expected: crate::PROTOCOL_VERSION,
-                actual: envelope.version,
-            });
+        let delivery_key = self.delivery_key(sender_id, envelope);
+        if let Some(stored) = self.delivered_copy(delivery_key.as_ref()).await? {
+            debug!("Dropping duplicate of message {} from {}", stored.id, sender_id);
+            return Ok(stored);
         }
 
-        // Check for session
-        let session_id = self.with_session_manager(|sm| {
-            Ok(sm.get_session(sender_id, sender_device_id))
-        })?;
-
-        let session_id = match session_id {
-            Some(id) => id,
-            None => {
-                // Check if this is an initial message (has ephemeral key)
-                if let Some(ephemeral_key) = envelope.ephemeral_key {
-                    // Accept new session
-                    self.with_session_manager_mut(|sm| {
-                        // Need async here - this is a simplification
-                        Err(ProtocolError::SessionNotEstablished(sender_id.to_string()))
-                    })?
-                } else {
-                    return Err(ProtocolError::SessionNotFound(sender_id.to_string()));
-                }
-            }
-        };
-
-        // Decrypt
-        let plaintext = self.with_session_manager(|sm| {
-            sm.decrypt(&session_id, &envelope.ciphertext)
-        })?;
+        let message = self.open_envelope(sender_id, sender_device_id, envelope).await?;
 
-        // Deserialize message
-        let message = Message::from_bytes(&plaintext)
-            .map_err(|e| ProtocolError::InvalidMessage(e.to_string()))?;
+        // Reactions and receipts are applied to their message by
+        // process_message rather than stored or announced on their own
+        if message.is_control() {
+            debug!("Decrypted reaction or receipt {} from {}", message.id, sender_id);
+            return Ok(message);
+        }
 
         // Save to storage
         self.storage.save_message(&message).await
             .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        if let Some(key) = delivery_key {
+            self.storage.record_delivery(&key, &message.id).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        }
+
+        self.notify(sender_id, &message).await?;
 
         debug!("Decrypted message {} from {}", message.id, sender_id);
         Ok(message)
     }
 
-    /// Establish a session with a user using their prekey bundle
-    #[instrument(skip(self, bundle))]
-    pub async fn establish_session(
+    /// Decrypt a batch of received messages, e.g. a backfill after
+    /// reconnecting
+    ///
+    /// Each envelope is decrypted in order and gets its own result. The
+    /// messages that decrypt are stored with a single
+    /// [`MessageStore::save_messages`] before any notification goes out. If
+    /// that save fails, the ratchets have still moved past these messages:
+    /// the decrypted messages stay in the results and the failure is
+    /// reported as [`BatchOutcome::save_error`], for the caller to store
+    /// them again or show them. With [`ClientConfig::deduplicate_deliveries`],
+    /// a duplicate gets the copy already received and is neither stored nor
+    /// announced again.
+    #[instrument(skip(self, envelopes), fields(count = envelopes.len()))]
+    pub async fn decrypt_messages(
         &self,
-        user_id: &UserId,
-        device_id: &DeviceId,
-        bundle: &DevicePreKeyBundle,
-    ) -> Result<SessionId> {
-        self.ensure_ready()?;
-
-        self.with_session_manager_mut(|sm| {
-            // This is async but we're in sync context - simplified
-            Err(ProtocolError::Internal("Need async context".to_string()))
-        })
-    }
-
-    /// Process an incoming protocol message
-    #[instrument(skip(self, message))]
-    pub async fn process_message(&self, message: ProtocolMessage) -> Result<Option<ProtocolMessage>> {
-        self.ensure_ready()?;
+        envelopes: &[(UserId, DeviceId, MessageEnvelope)],
+    ) -> BatchOutcome<Message> {
+        let mut results: Vec<Result<Message>> = Vec::with_capacity(envelopes.len());
+        let mut fresh = Vec::with_capacity(envelopes.len());
+        let mut first_copies: HashMap<[u8; 32], usize> = HashMap::new();
+        for (sender_id, sender_device_id, envelope) in envelopes {
+            let delivery_key = self.delivery_key(sender_id, envelope);
 
-        match message.message_type {
-            ProtocolMessageType::EncryptedMessage(envelope) => {
-                let decrypted = self.decrypt_message(
-                    &message.sender_id,
-                    &message.sender_device_id,
-                    &envelope,
-                ).await?;
-                
-                // Return delivery receipt
-                // ...
-                Ok(None)
-            }
-            ProtocolMessageType::PreKeyBundleRequest(request) => {
-                // Handle prekey request
-                let bundle = self.get_prekey_bundle()?;
-                // Convert and return response
-                Ok(None)
-            }
-            ProtocolMessageType::DeliveryReceipt(receipt) => {
-                // Update message status
-                Ok(None)
-            }
-            ProtocolMessageType::ReadReceipt(receipt) => {
-                // Update message status
-                Ok(None)
+            let earlier = delivery_key
+                .as_ref()
+                .and_then(|key| first_copies.get(key))
+                .and_then(|&i| results[i].as_ref().ok().cloned());
+            let duplicate = match earlier {
+                Some(message) => Ok(Some(message)),
+                None => self.delivered_copy(delivery_key.as_ref()).await,
+            };
+            match duplicate {
+                Ok(None) => {}
+                Ok(Some(message)) => {
+                    results.push(Ok(message));
+                    fresh.push(false);
+                    continue;
+                }
+                // Nothing was decrypted for this one, so nothing is lost
+                Err(e) => {
+                    results.push(Err(e));
+                    fresh.push(false);
+                    continue;
+                }
             }
-            ProtocolMessageType::SessionReset(reset) => {
-                // Handle session reset
-                Ok(None)
+
+            let result = self.open_envelope(sender_id, sender_device_id, envelope).await;
+            if let (Ok(_), Some(key)) = (&result, delivery_key) {
+                first_copies.insert(key, results.len());
             }
-            _ => {
-                debug!("Unhandled message type");
-                Ok(None)
+            results.push(result);
+            fresh.push(true);
+        }
+
+        let received: Vec<(&UserId, &Message)> = envelopes
+            .iter()
+            .zip(&results)
+            .zip(&fresh)
+            .filter(|(_, fresh)| **fresh)
+            .filter_map(|(((sender_id, _, _), result), _)| Some((sender_id, result.as_ref().ok()?)))
+            .filter(|(_, message)| !message.is_control())
+            .collect();
+
+        let messages: Vec<Message> = received.iter().map(|(_, message)| (*message).clone()).collect();
+        if let Err(e) = self.store_received(&messages, &first_copies, &results).await {
+            error!("Storing {} decrypted messages failed: {}", messages.len(), e);
+            return BatchOutcome { results, save_error: Some(e) };
+        }
+
+        for (sender_id, message) in received {
+            if let Err(e) = self.notify(sender_id, message).await {
+                warn!("Could not announce message {}: {}", message.id, e);
             }
         }
+
+        debug!("Decrypted {} of {} messages", messages.len(), envelopes.len());
+        BatchOutcome { results, save_error: None }
     }
 
-    /// Shutdown the client
-    pub async fn shutdown(&self) -> Result<()> {
-        *self.state.write() = ClientState::ShuttingDown;
-        
-        // Flush storage
-        self.storage.flush().await
+    /// Save a decrypted batch and remember the deliveries it came from
+    async fn store_received(
+        &self,
+        messages: &[Message],
+        first_copies: &HashMap<[u8; 32], usize>,
+        results: &[Result<Message>],
+    ) -> Result<()> {
+        self.storage.save_messages(messages).await
             .map_err(|e| ProtocolError::Storage(e.to_string()))?;
-
-        info!("Protocol client shutdown");
+        for (key, &i) in first_copies {
+            if let Ok(message) = &results[i] {
+                if !message.is_control() {
+                    self.storage.record_delivery(key, &message.id).await
+                        .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+                }
+            }
+        }
         Ok(())
     }
 
-    // Helper methods
+    /// Delivery key of a received envelope, when duplicates are recognised
+    fn delivery_key(&self, sender_id: &UserId, envelope: &MessageEnvelope) -> Option<[u8; 32]> {
+        self.config
+            .deduplicate_deliveries
+            .then(|| envelope.delivery_key(sender_id))
+    }
 
-    fn ensure_ready(&self) -> Result<()> {
-        if !self.is_ready() {
-            return Err(ProtocolError::NotInitialized);
+    /// The stored message first received with `delivery_key`, if any
+    async fn delivered_copy(&self, delivery_key: Option<&[u8; 32]>) -> Result<Option<Message>> {
+        let Some(key) = delivery_key else {
+            return Ok(None);
+        };
+        let id = self.storage.get_delivery(key).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        match id {
+            Some(id) => self.storage.get_message(&id).await
+                .map_err(|e| ProtocolError::Storage(e.to_string())),
+            None => Ok(None),
         }
-        Ok(())
     }
 
-    fn with_session_manager<F, T>(&self, f: F) -> Result<T>
-    where
-        F: FnOnce(&SessionManager) -> Result<T>,
-    {
-        let guard = self.session_manager.read();
-        let sm = guard.as_ref().ok_or(ProtocolError::NotInitialized)?;
-        f(sm)
-    }
+    /// Decrypt and parse an envelope without storing the message
+    async fn open_envelope(
+        &self,
+        sender_id: &UserId,
+        sender_device_id: &DeviceId,
+        envelope: &MessageEnvelope,
+    ) -> Result<Message> {
+        self.ensure_ready()?;
+
+        // Check for session
+        let session_id = self.active_session(sender_id, sender_device_id).await?;
+
+        // An existing session wins: the initiator repeats its X3DH header
+        // until we answer
+        let (session_id, accepted) = match session_id {
+            Some(id) => (id, false),
+            None => match envelope.x3dh_header() {
+                Some(header) => {
+                    let id = self.accept_initiation(sender_id, sender_device_id, envelope.version, &header).await?;
+                    (id, true)
+                }
+                None => return Err(ProtocolError::SessionNotFound(sender_id.to_string())),
+            },
+        };
+
+        // Messages use the version negotiated for the session; anything
+        // else is either a different session or a downgrade attempt
+        let expected = self.with_session_manager(|sm| {
+            sm.session_version(&session_id)
+                .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+        })?;
+        if envelope.version != expected {
+            return Err(ProtocolError::VersionMismatch {
+                expected,
+                actual: envelope.version,
+            });
+        }
+
+        // An identity sent in the clear is unauthenticated; only the one the
+        // session was established with is accepted, before any key is spent
+        let clear_identity = if envelope.is_sealed_sender() {
+            None
+        } else {
+            let identity = IdentityPublicKey::from_bytes(&envelope.sender_identity_key)?;
+            self.with_session_manager(|sm| sm.verify_sender_identity(&session_id, &identity))?;
+            Some(identity)
+        };
+
+        // Decrypt
+        let decrypted = self.with_saved_session(&session_id, |sm| {
+            sm.decrypt(&session_id, &envelope.ciphertext)
+        }).await;
+        let plaintext = match decrypted {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                if matches!(e, ProtocolError::DecryptionFailed(_)) {
+                    self.metrics.increment(Counter::DecryptionFailures);
+                }
+                // A forged initial message leaves no session behind
+                if accepted {
+                    self.discard_accepted(&session_id).await?;
+                }
+                return Err(e);
+            }
+        };
+        self.metrics.increment(Counter::MessagesDecrypted);
+        self.metrics.observe(Histogram::PlaintextBytes, plaintext.len() as f64);
+        self.metrics.observe(Histogram::CiphertextBytes, envelope.ciphertext.len() as f64);
+        // A new ratchet key from the peer restarts the count at this message
+        let health = self.with_session_manager(|sm| sm.session_health(&session_id))?;
+        if health.messages_since_dh_ratchet == 1 {
+            self.metrics.increment(Counter::RatchetSteps);
+        }
+
+        // The sender is whoever the session is with; a sealed identity must
+        // agree too. An undecodable wrapper is quarantined below like any
+        // other malformed plaintext.
+        let (plaintext, identity) = if envelope.is_sealed_sender() {
+            match SealedSenderContent::decode(&plaintext) {
+                Ok(content) => {
+                    let identity = content.identity()?;
+                    self.with_session_manager(|sm| {
+                        sm.verify_sender_identity(&session_id, &identity)
+                    })?;
+                    (content.message, Some(identity))
+                }
+                Err(_) => (plaintext, None),
+            }
+        } else {
+            (plaintext, clear_identity)
+        };
+
+        let unverified_sender = match identity {
+            Some(identity) => self.check_sender_identity(sender_id, &identity).await?,
+            None => false,
+        };
+
+        // The ratchet has already advanced past this key, so a payload we
+        // cannot parse is kept rather than lost
+        let mut message = match Message::from_bytes(&plaintext) {
+            Ok(message) => message,
+            Err(e) => {
+                let payload = QuarantinedPayload {
+                    id: MessageId::new(),
+                    sender_id: sender_id.clone(),
+                    sender_device_id: sender_device_id.clone(),
+                    envelope_version: envelope.version,
+                    plaintext,
+                    error: e.to_string(),
+                    quarantined_at: Timestamp::now(),
+                };
+                self.storage.quarantine_payload(&payload).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+                warn!("Quarantined malformed plaintext {} from {}: {}", payload.id, sender_id, payload.error);
+                return Err(ProtocolError::MalformedPlaintext(payload.id.to_string()));
+            }
+        };
+        message.unverified_sender = unverified_sender;
+        Ok(message)
+    }
+
+    /// Accept the session an X3DH initial message starts
+    ///
+    /// Each session nonce is accepted once; a replayed initial message is
+    /// rejected before any key material is used.
+    async fn accept_initiation(
+        &self,
+        sender_id: &UserId,
+        sender_device_id: &DeviceId,
+        version: u32,
+        header: &X3DHHeader,
+    ) -> Result<SessionId> {
+        let fresh = self.storage.record_session_nonce(&header.session_nonce).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        if !fresh {
+            warn!("Rejected replayed session initiation from {} device {}", sender_id, sender_device_id);
+            return Err(ProtocolError::ReplayedInitiation(sender_id.to_string()));
+        }
+
+        let record = self.with_session_manager_mut(|sm| {
+            sm.respond_session(sender_id, sender_device_id, header, version)
+        })?;
+        if let Some(opk_id) = header.one_time_prekey_id {
+            self.storage.delete_one_time_prekey(opk_id).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        }
+        self.storage.save_session(&record).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        self.metrics.increment(Counter::SessionsEstablished);
+        self.enforce_session_limit().await?;
+        Ok(record.session.id)
+    }
+
+    /// Forget a session accepted from a message that turned out not to
+    /// come from the initiator
+    async fn discard_accepted(&self, session_id: &SessionId) -> Result<()> {
+        self.with_session_manager(|sm| {
+            sm.discard_session(session_id);
+            Ok(())
+        })?;
+        self.storage.delete_session(session_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Start key confirmation on a session we initiated
+    ///
+    /// Send the returned message to the peer. With
+    /// [`ClientConfig::require_key_confirmation`], messages to the peer wait
+    /// as pending until its reply arrives through
+    /// [`Self::process_message`]; flush them with [`Self::flush_pending`].
+    pub async fn confirm_keys(&self, user_id: &UserId, device_id: &DeviceId) -> Result<ProtocolMessage> {
+        self.ensure_ready()?;
+
+        let session_id = self.active_session(user_id, device_id).await?
+            .ok_or_else(|| ProtocolError::SessionNotEstablished(user_id.to_string()))?;
+        let confirmation = self.with_session_manager(|sm| sm.confirm_keys(&session_id))?;
+
+        Ok(ProtocolMessage::new(
+            ProtocolMessageType::KeyConfirmation(confirmation),
+            self.user_id.clone(),
+            self.device_id.clone(),
+        ))
+    }
+
+    /// Answer the initiator's key confirmation, or check the responder's
+    /// answer to ours
+    ///
+    /// A responder without a session derives it from the header the
+    /// confirmation carries. Returns the reply to send, if any.
+    async fn handle_key_confirmation(
+        &self,
+        sender_id: &UserId,
+        sender_device_id: &DeviceId,
+        confirmation: &KeyConfirmation,
+    ) -> Result<Option<ProtocolMessage>> {
+        let existing = self.active_session(sender_id, sender_device_id).await?;
+        let (session_id, accepted) = match (existing, &confirmation.initiation) {
+            (Some(id), _) => (id, false),
+            (None, Some(header)) => {
+                let id = self.accept_initiation(
+                    sender_id,
+                    sender_device_id,
+                    confirmation.session_version,
+                    header,
+                ).await?;
+                (id, true)
+            }
+            (None, None) => return Err(ProtocolError::SessionNotFound(sender_id.to_string())),
+        };
+
+        let initiator = self.with_session_manager(|sm| {
+            sm.is_initiator(&session_id)
+                .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+        })?;
+        let reply = if initiator {
+            self.with_saved_session(&session_id, |sm| {
+                sm.finish_key_confirmation(&session_id, confirmation).map(|()| None)
+            }).await
+        } else {
+            self.with_saved_session(&session_id, |sm| {
+                sm.answer_key_confirmation(&session_id, confirmation).map(Some)
+            }).await
+        };
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                if accepted {
+                    self.discard_accepted(&session_id).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        // The tag proves the initiator holds the identity in its header
+        if let Some(header) = confirmation.initiation.as_ref().filter(|_| accepted) {
+            let identity = IdentityPublicKey::from_bytes(&header.identity_key.0)?;
+            self.check_sender_identity(sender_id, &identity).await?;
+        }
+
+        debug!("Key confirmation with {} device {} succeeded", sender_id, sender_device_id);
+        Ok(reply.map(|reply| {
+            ProtocolMessage::new(
+                ProtocolMessageType::KeyConfirmation(reply),
+                self.user_id.clone(),
+                self.device_id.clone(),
+            )
+        }))
+    }
+
+    /// Establish a session with a user using their prekey bundle
+    #[instrument(skip(self, bundle))]
+    pub async fn establish_session(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        bundle: &DevicePreKeyBundle,
+    ) -> Result<SessionId> {
+        self.ensure_ready()?;
+
+        let record = self.with_session_manager_mut(|sm| sm.start_session(user_id, device_id, bundle))?;
+        self.storage.save_session(&record).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        self.storage.save_remote_identity(user_id, bundle.identity_key).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        self.metrics.increment(Counter::SessionsEstablished);
+        info!("Established session {} with {} device {}", record.session.id, user_id, device_id);
+        self.enforce_session_limit().await?;
+        Ok(record.session.id)
+    }
+
+    /// Fetch the prekey bundle for a device from the identity service and
+    /// establish a session with it
+    ///
+    /// The bundle's signature and age are checked before any key material is
+    /// used.
+    #[instrument(skip(self, identity_client))]
+    pub async fn establish_session_from_server(
+        &self,
+        identity_client: &dyn IdentityServiceClient,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<SessionId> {
+        self.ensure_ready()?;
+
+        let bundle = identity_client.fetch_bundle(user_id, device_id).await?;
+        if &bundle.device_id != device_id {
+            return Err(ProtocolError::InvalidPreKeyBundle(format!(
+                "requested device {} but got {}",
+                device_id, bundle.device_id
+            )));
+        }
+        bundle.verify((self.clock)(), self.config.max_prekey_bundle_age_secs)?;
+
+        self.establish_session(user_id, device_id, &bundle).await
+    }
+
+    /// Process an incoming protocol message
+    #[instrument(skip(self, message))]
+    pub async fn process_message(&self, message: ProtocolMessage) -> Result<Option<ProtocolMessage>> {
+        self.ensure_ready()?;
+
+        match message.message_type {
+            ProtocolMessageType::EncryptedMessage(envelope) => {
+                let decrypted = self.decrypt_message(
+                    &message.sender_id,
+                    &message.sender_device_id,
+                    &envelope,
+                ).await?;
+
+                if decrypted.is_control() {
+                    self.apply_control(&message.sender_id, &decrypted).await?;
+                    return Ok(None);
+                }
+
+                // Return delivery receipt
+                // ...
+                Ok(None)
+            }
+            ProtocolMessageType::PreKeyBundleRequest(request) => {
+                // Handle prekey request
+                let bundle = self.get_prekey_bundle()?;
+                // Convert and return response
+                Ok(None)
+            }
+            ProtocolMessageType::DeliveryReceipt(receipt) => {
+                // Update message status
+                Ok(None)
+            }
+            ProtocolMessageType::ReadReceipt(receipt) => {
+                // Update message status
+                Ok(None)
+            }
+            ProtocolMessageType::KeyConfirmation(confirmation) => {
+                self.handle_key_confirmation(
+                    &message.sender_id,
+                    &message.sender_device_id,
+                    &confirmation,
+                ).await
+            }
+            ProtocolMessageType::SessionReset(reset) => {
+                // Only the peer holding the session secret may reset it
+                let session_id = self.with_session_manager(|sm| {
+                    let session_id = sm
+                        .get_session(&message.sender_id, &message.sender_device_id)
+                        .ok_or_else(|| {
+                            ProtocolError::Unauthorized(format!(
+                                "session reset from {} without a session",
+                                message.sender_id
+                            ))
+                        })?;
+                    sm.apply_session_reset(&session_id, &reset)?;
+                    Ok(session_id)
+                })?;
+
+                self.storage.delete_session(&session_id).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+                Ok(None)
+            }
+            _ => {
+                debug!("Unhandled message type");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Process a batch of incoming protocol messages, e.g. a backfill
+    ///
+    /// Messages are handled in order. Each run of encrypted messages goes
+    /// through [`Self::decrypt_messages`], so it is stored in one write;
+    /// anything else through [`Self::process_message`]. The first storage
+    /// failure of a run is reported as [`BatchOutcome::save_error`].
+    #[instrument(skip(self, messages), fields(count = messages.len()))]
+    pub async fn process_messages(
+        &self,
+        messages: Vec<ProtocolMessage>,
+    ) -> BatchOutcome<Option<ProtocolMessage>> {
+        let mut outcome = BatchOutcome { results: Vec::with_capacity(messages.len()), save_error: None };
+        let mut messages = messages.into_iter().peekable();
+        while let Some(message) = messages.next() {
+            let ProtocolMessageType::EncryptedMessage(envelope) = message.message_type else {
+                outcome.results.push(self.process_message(message).await);
+                continue;
+            };
+
+            let mut run = vec![(message.sender_id, message.sender_device_id, envelope)];
+            while let Some(next) = messages.next_if(|m| {
+                matches!(m.message_type, ProtocolMessageType::EncryptedMessage(_))
+            }) {
+                if let ProtocolMessageType::EncryptedMessage(envelope) = next.message_type {
+                    run.push((next.sender_id, next.sender_device_id, envelope));
+                }
+            }
+
+            let decrypted = self.decrypt_messages(&run).await;
+            if outcome.save_error.is_none() {
+                outcome.save_error = decrypted.save_error;
+            }
+            for ((sender_id, _, _), result) in run.iter().zip(decrypted.results) {
+                let result = match result {
+                    Ok(message) => self.apply_control(sender_id, &message).await,
+                    Err(e) => Err(e),
+                };
+                outcome.results.push(result.map(|()| None));
+            }
+        }
+        outcome
+    }
+
+    /// Shutdown the client
+    pub async fn shutdown(&self) -> Result<()> {
+        *self.state.write() = ClientState::ShuttingDown;
+        
+        // Flush storage
+        self.storage.flush().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        info!("Protocol client shutdown");
+        Ok(())
+    }
+
+    /// Payloads that decrypted but could not be parsed, oldest first
+    pub async fn quarantined_payloads(&self) -> Result<Vec<QuarantinedPayload>> {
+        self.storage.get_quarantined_payloads().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Export the whole account as a backup encrypted under `passphrase`
+    ///
+    /// Compressed with [`ClientConfig::compression`].
+    pub async fn export_backup(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let snapshot = self.storage.export_snapshot().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        crate::backup::seal(&snapshot, passphrase, self.config.compression)
+    }
+
+    /// Restore a backup produced by [`export_backup`](Self::export_backup)
+    ///
+    /// Must be called before [`initialize`](Self::initialize) so the restored
+    /// identity is picked up. Refuses to overwrite a non-empty store unless
+    /// `force` is set, in which case the store is replaced rather than
+    /// merged. The import is one transaction: if it fails, the store is left
+    /// as it was.
+    pub async fn import_backup(&self, passphrase: &str, bytes: &[u8], force: bool) -> Result<()> {
+        if !matches!(*self.state.read(), ClientState::Uninitialized) {
+            return Err(ProtocolError::AlreadyInitialized);
+        }
+
+        if !force {
+            let existing = self.storage.export_snapshot().await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            if !existing.is_empty() {
+                return Err(ProtocolError::Backup("target store is not empty".to_string()));
+            }
+        }
+
+        let snapshot = crate::backup::open(bytes, passphrase)?;
+        self.storage.begin_transaction().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        let imported = async {
+            if force {
+                self.storage.clear().await?;
+            }
+            self.storage.import_snapshot(snapshot).await
+        }
+        .await;
+        match imported {
+            Ok(()) => self.storage.commit().await,
+            Err(e) => {
+                if let Err(rollback) = self.storage.rollback().await {
+                    error!("Rolling back the backup import failed: {}", rollback);
+                }
+                Err(e)
+            }
+        }
+        .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        info!("Imported account backup");
+        Ok(())
+    }
+
+    /// Rotate keys that `policy` considers too old and top up one-time prekeys
+    ///
+    /// The returned report carries the messages announcing the new keys; they
+    /// are not sent from here.
+    #[instrument(skip(self, policy))]
+    pub async fn enforce_rotation_policy(&self, policy: &KeyRotationPolicy) -> Result<RotationReport> {
+        self.ensure_ready()?;
+
+        let now = policy.now();
+        let mut report = RotationReport::default();
+        let spk_id = self.with_session_manager(|sm| Ok(sm.get_prekey_bundle().signed_prekey.id))?;
+        let superseded = self.with_session_manager(|sm| Ok(sm.previous_signed_prekey_id()))?;
+
+        if policy.needs_identity_rotation(self.storage.as_ref()).await? {
+            let proof = self.with_session_manager_mut(|sm| Ok(sm.rotate_identity()))?;
+            let (secret, account_secret) = self.with_session_manager(|sm| {
+                Ok((sm.identity_secret(), sm.account_secret()))
+            })?;
+            self.save_identity(secret, account_secret, now).await?;
+
+            // Prekeys signed by the old identity are gone
+            let stale = self.storage.get_one_time_prekey_ids().await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            for id in stale {
+                self.storage.delete_one_time_prekey(id).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            }
+
+            report.announcements.push(self.announcement(ProtocolMessageType::IdentityKeyUpdate(
+                IdentityKeyUpdate {
+                    new_identity_key: proof.new_public_key.signing_key,
+                    old_key_signature: proof.old_signature,
+                    new_key_signature: proof.new_signature,
+                    reason: IdentityUpdateReason::Rotation,
+                },
+            )));
+            report.identity_rotation = Some(proof);
+        }
+
+        // The new identity came with a new signed prekey
+        if report.identity_rotation.is_some()
+            || policy.needs_spk_rotation(self.storage.as_ref(), spk_id).await?
+        {
+            if report.identity_rotation.is_none() {
+                self.with_session_manager_mut(|sm| {
+                    sm.rotate_signed_prekey();
+                    Ok(())
+                })?;
+            }
+            // The replaced key stays stored through its grace period; one
+            // replaced before it is gone
+            if let Some(id) = superseded {
+                self.storage.delete_signed_prekey(id).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            }
+            self.save_signed_prekey(now).await?;
+            report.signed_prekey_rotated = true;
+        }
+
+        let current_spk_id = self.with_session_manager(|sm| Ok(sm.get_prekey_bundle().signed_prekey.id))?;
+        let previous = self.with_session_manager(|sm| Ok(sm.previous_signed_prekey_id()))?;
+        if previous.is_some() && policy.previous_spk_expired(self.storage.as_ref(), current_spk_id).await? {
+            let retired = self.with_session_manager_mut(|sm| Ok(sm.retire_previous_signed_prekey()))?;
+            if let Some(id) = retired {
+                self.storage.delete_signed_prekey(id).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            }
+            report.signed_prekey_retired = retired;
+        }
+
+        if policy.needs_opk_replenishment(self.storage.as_ref()).await? {
+            let available = self.storage.get_one_time_prekey_count().await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            let new_prekeys = self.generate_prekeys(policy.replenishment_count(available)).await?;
+
+            report.one_time_prekeys_added = new_prekeys.len();
+            report.announcements.push(self.announcement(ProtocolMessageType::PrekeyReplenish(
+                PrekeyReplenish { new_prekeys },
+            )));
+        }
+
+        if report.signed_prekey_rotated {
+            let bundle = self.device_prekey_bundle()?;
+            report.announcements.push(self.announcement(ProtocolMessageType::PreKeyBundleResponse(
+                PreKeyBundleResponse {
+                    user_id: self.user_id.clone(),
+                    bundles: vec![bundle],
+                },
+            )));
+        }
+
+        if !report.is_empty() {
+            info!(
+                identity = report.identity_rotation.is_some(),
+                signed_prekey = report.signed_prekey_rotated,
+                retired_signed_prekey = ?report.signed_prekey_retired,
+                one_time_prekeys = report.one_time_prekeys_added,
+                "Enforced key rotation policy"
+            );
+        }
+        Ok(report)
+    }
+
+    /// Generate `count` one-time prekeys, returning the public halves to
+    /// publish
+    ///
+    /// The secrets stay with the session layer, which answers initial
+    /// messages made with them; each is recorded in storage until used.
+    pub async fn generate_prekeys(&self, count: usize) -> Result<Vec<OneTimePreKeyInfo>> {
+        self.ensure_ready()?;
+
+        let generated = self.with_session_manager_mut(|sm| Ok(sm.generate_prekeys(count)))?;
+        let mut prekeys = Vec::with_capacity(generated.len());
+        for prekey in generated {
+            self.storage.save_one_time_prekey(prekey.id, prekey.public_key.0.to_vec()).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+            prekeys.push(OneTimePreKeyInfo {
+                id: prekey.id,
+                public_key: prekey.public_key.0,
+            });
+        }
+        Ok(prekeys)
+    }
+
+    /// Drop one-time prekeys that were never published
+    ///
+    /// E.g. after their upload failed, so they are neither counted nor
+    /// offered in a bundle.
+    pub async fn discard_prekeys(&self, ids: &[u32]) -> Result<()> {
+        self.ensure_ready()?;
+
+        for &id in ids {
+            self.with_session_manager_mut(|sm| {
+                sm.discard_prekey(id);
+                Ok(())
+            })?;
+            self.storage.delete_one_time_prekey(id).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Install a session with `peer`, bypassing X3DH
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn pair_for_tests(&self, peer: &ProtocolClient<S>) -> Result<(SessionId, SessionId)> {
+        let ours = self.session_manager.read();
+        let theirs = peer.session_manager.read();
+        let ours = ours.as_ref().ok_or(ProtocolError::NotInitialized)?;
+        let theirs = theirs.as_ref().ok_or(ProtocolError::NotInitialized)?;
+
+        Ok(ours.pair_for_tests(&self.user_id, theirs, &peer.user_id))
+    }
+
+    // Helper methods
+
+    /// Session with a device, reloading it if it was evicted from memory
+    async fn active_session(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<SessionId>> {
+        let in_memory = self.with_session_manager(|sm| {
+            let session_id = sm.get_session(user_id, device_id);
+            if let Some(ref id) = session_id {
+                sm.touch(id);
+            }
+            Ok(session_id)
+        })?;
+
+        let session_id = match in_memory {
+            Some(id) => id,
+            None => {
+                let stored = self.storage.get_session_by_user_device(user_id, device_id).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+                let record = match stored {
+                    Some(record) if !record.ratchet_state.is_empty() => record,
+                    _ => return Ok(None),
+                };
+
+                self.with_session_manager(|sm| sm.reload_session(record))?
+            }
+        };
+
+        self.enforce_session_limit().await?;
+        Ok(Some(session_id))
+    }
+
+    /// Save and drop least recently used sessions beyond the configured limit
+    ///
+    /// A session used while it was being saved stays in memory, and a fresh
+    /// snapshot is taken in the next round.
+    async fn enforce_session_limit(&self) -> Result<()> {
+        let _writes = self.session_writes.lock().await;
+
+        for _ in 0..MAX_EVICTION_ROUNDS {
+            let candidates = self.with_session_manager(|sm| sm.eviction_candidates())?;
+            if candidates.is_empty() {
+                return Ok(());
+            }
+
+            for (record, last_used) in candidates {
+                // Persisted before removal, so no state is lost if saving fails
+                self.storage.save_session(&record).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+                if !self.with_session_manager(|sm| Ok(sm.evict(&record.session.id, last_used)))? {
+                    debug!("Session {} was used while being saved, keeping it", record.session.id);
+                }
+            }
+        }
+
+        warn!("Sessions still over the limit after {} eviction rounds", MAX_EVICTION_ROUNDS);
+        Ok(())
+    }
+
+    /// Run `f` on a session and save the session's new state before
+    /// returning
+    ///
+    /// Nothing produced under a ratchet state leaves the client before that
+    /// state is stored, so a restart never reuses message keys or nonces.
+    async fn with_saved_session<F, T>(&self, session_id: &SessionId, f: F) -> Result<T>
+    where
+        F: FnOnce(&SessionManager) -> Result<T>,
+    {
+        let _writes = self.session_writes.lock().await;
+        let output = self.with_session_manager(f)?;
+        let record = self.with_session_manager(|sm| sm.session_snapshot(session_id))?;
+        self.storage.save_session(&record).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        Ok(output)
+    }
+
+    fn ensure_ready(&self) -> Result<()> {
+        if !self.is_ready() {
+            return Err(ProtocolError::NotInitialized);
+        }
+        Ok(())
+    }
+
+    fn with_session_manager<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&SessionManager) -> Result<T>,
+    {
+        let guard = self.session_manager.read();
+        let sm = guard.as_ref().ok_or(ProtocolError::NotInitialized)?;
+        f(sm)
+    }
 
     fn with_session_manager_mut<F, T>(&self, f: F) -> Result<T>
     where
@@ -385,77 +1618,1432 @@ impl<S: Storage + 'static> ProtocolClient<S> {
         f(sm)
     }
 
-    async fn load_identity(&self) -> Result<Option<Identity>> {
-        let encrypted = self.storage.get_identity_key().await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+    async fn load_identity(&self) -> Result<Option<StoredIdentityKey>> {
+        let encrypted = self.storage.get_identity_key().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        // In production, decrypt with user password
+        // For now, just deserialize
+        encrypted.map(|data| StoredIdentityKey::decode(&data)).transpose()
+    }
+
+    async fn save_identity(
+        &self,
+        secret: [u8; 32],
+        account_secret: [u8; 32],
+        created_at: Timestamp,
+    ) -> Result<()> {
+        let encrypted = StoredIdentityKey {
+            secret,
+            created_at: Some(created_at),
+            account_secret,
+        }
+        .encode()?;
+
+        self.storage.save_identity_key(encrypted).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Reject content that could never be sent, before anything is stored
+    fn check_outgoing(&self, recipient_id: &UserId, content: &str) -> Result<()> {
+        if recipient_id.as_str().trim().is_empty() {
+            return Err(ProtocolError::InvalidMessage("empty recipient".to_string()));
+        }
+        if content.is_empty() {
+            return Err(ProtocolError::InvalidMessage("empty message".to_string()));
+        }
+
+        let max = self.config.max_message_size.min(MAX_MESSAGE_SIZE);
+        if content.len() > max {
+            return Err(ProtocolError::MessageTooLarge { size: content.len(), max });
+        }
+        Ok(())
+    }
+
+    /// Compare `identity` with the key stored for `sender_id`
+    ///
+    /// The first key seen is trusted. A different key replaces it as
+    /// unverified and emits [`ClientEvent::SafetyNumberChanged`] once.
+    /// Returns whether the sender is unverified; in strict mode that is an
+    /// error instead.
+    async fn check_sender_identity(&self, sender_id: &UserId, identity: &IdentityPublicKey) -> Result<bool> {
+        let key = identity.signing_key_bytes();
+        let stored = self.storage.get_remote_identity(sender_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        let unverified = match stored {
+            None => {
+                self.storage.save_remote_identity(sender_id, key).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+                false
+            }
+            Some(old_key) if old_key != key => {
+                self.storage.save_remote_identity(sender_id, key).await
+                    .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+                self.save_trust_level(sender_id, identity, TrustLevel::Unknown).await?;
+
+                warn!("Identity key of {} changed", sender_id);
+                // Nobody listening is not an error
+                let _ = self.events.send(ClientEvent::SafetyNumberChanged {
+                    user_id: sender_id.clone(),
+                    old_key,
+                    new_key: key,
+                });
+                true
+            }
+            Some(_) => self.storage.get_user(sender_id).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?
+                .is_some_and(|user| user.trust_level == TrustLevel::Unknown),
+        };
+
+        if unverified && self.config.strict_identity_keys {
+            return Err(ProtocolError::UntrustedIdentity(sender_id.to_string()));
+        }
+        Ok(unverified)
+    }
+
+    /// Record `trust_level` for `user_id` and the fingerprint of `identity`
+    async fn save_trust_level(
+        &self,
+        user_id: &UserId,
+        identity: &IdentityPublicKey,
+        trust_level: TrustLevel,
+    ) -> Result<()> {
+        let fingerprint = Fingerprint::from_bytes(identity.fingerprint());
+        let mut user = match self.storage.get_user(user_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?
+        {
+            Some(user) => user,
+            None => User {
+                id: user_id.clone(),
+                ..User::new(fingerprint.clone(), UserProfile::default())
+            },
+        };
+        user.fingerprint = fingerprint;
+        user.trust_level = trust_level;
+        self.storage.save_user(&user).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Signal a notification for `message` unless its conversation is muted
+    async fn notify(&self, sender_id: &UserId, message: &Message) -> Result<()> {
+        let settings = self.storage.get_conversation_settings(sender_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?
+            .unwrap_or_default();
+
+        if settings.is_muted_at((self.clock)()) {
+            debug!("Conversation with {} is muted, not notifying", sender_id);
+            return Ok(());
+        }
+
+        let notification = Notification {
+            message_id: message.id.clone(),
+            sender_id: sender_id.clone(),
+            sound: settings.notification_sound,
+            preview: if settings.show_previews { message.content_as_string() } else { None },
+        };
+        // Nobody listening is not an error
+        let _ = self.notifications.send(notification);
+        Ok(())
+    }
+
+    /// Record the current signed prekey and when it was created
+    async fn save_signed_prekey(&self, created_at: Timestamp) -> Result<()> {
+        let prekey = self.with_session_manager(|sm| Ok(sm.get_prekey_bundle().signed_prekey))?;
+        let record = StoredSignedPreKey {
+            public_key: prekey.public_key.0,
+            created_at,
+        };
+
+        self.storage.save_signed_prekey(prekey.id, record.encode()?).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))
+    }
+
+    /// Our current prekey bundle in wire form, naming this device
+    pub fn device_prekey_bundle(&self) -> Result<DevicePreKeyBundle> {
+        let bundle = self.get_prekey_bundle()?;
+
+        Ok(DevicePreKeyBundle {
+            device_id: self.device_id.clone(),
+            registration_id: 0,
+            identity_key: bundle.identity_key,
+            signed_prekey_id: bundle.signed_prekey.id,
+            signed_prekey: bundle.signed_prekey.public_key.0,
+            signed_prekey_signature: bundle.signed_prekey.signature,
+            one_time_prekey_id: bundle.one_time_prekey.as_ref().map(|opk| opk.id),
+            one_time_prekey: bundle.one_time_prekey.map(|opk| opk.public_key.0),
+            versions: VersionRange::supported(),
+            signed_prekey_timestamp: Some(Timestamp::from_secs(bundle.signed_prekey.timestamp)),
+        })
+    }
+
+    fn announcement(&self, message_type: ProtocolMessageType) -> ProtocolMessage {
+        ProtocolMessage::new(message_type, self.user_id.clone(), self.device_id.clone())
+    }
+
+    fn compute_timestamp_hash(&self, timestamp: Timestamp) -> [u8; 32] {
+        use sha2::{Sha256, Digest};
+        let mut hasher = Sha256::new();
+        hasher.update(b"QiyasHash_Timestamp_v1");
+        hasher.update(&timestamp.as_millis().to_be_bytes());
+        // Add random noise for metadata protection
+        let mut noise = [0u8; 16];
+        secure_rng().fill_bytes(&mut noise);
+        hasher.update(&noise);
+        let result = hasher.finalize();
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&result);
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qiyashash_core::storage::memory::MemoryStorage;
+    use qiyashash_crypto::kdf::compute_auth_tag;
+
+    use crate::handlers::SessionResetHandler;
+    use crate::protocol::{SessionResetReason, SessionResetRequest};
+    use crate::test_support::TestClient;
+
+    #[tokio::test]
+    async fn test_client_initialization() {
+        let storage = MemoryStorage::new();
+        let config = ClientConfig::default();
+        let client = ProtocolClient::new(config, storage);
+
+        assert!(!client.is_ready());
+        
+        client.initialize().await.unwrap();
+        
+        assert!(client.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_double_initialization() {
+        let storage = MemoryStorage::new();
+        let config = ClientConfig::default();
+        let client = ProtocolClient::new(config, storage);
+
+        client.initialize().await.unwrap();
+        
+        let result = client.initialize().await;
+        assert!(matches!(result, Err(ProtocolError::AlreadyInitialized)));
+    }
+
+    #[tokio::test]
+    async fn test_establish_session_from_server() {
+        use crate::test_support::MockIdentityService;
+
+        let alice = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+
+        let server = MockIdentityService::new();
+        server.publish_client(&bob);
+
+        let session_id = alice
+            .establish_session_from_server(&server, bob.user_id(), bob.device_id())
+            .await
+            .unwrap();
+        let found = alice.with_session_manager(|sm| Ok(sm.get_session(bob.user_id(), bob.device_id()))).unwrap();
+        assert_eq!(found, Some(session_id));
+
+        // A bundle whose signed prekey signature doesn't verify is refused
+        // before any session exists
+        let carol = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        carol.initialize().await.unwrap();
+        let mut bundle = carol.device_prekey_bundle().unwrap();
+        bundle.signed_prekey_signature[0] ^= 0xFF;
+        server.publish(carol.user_id(), bundle);
+
+        let result = alice
+            .establish_session_from_server(&server, carol.user_id(), carol.device_id())
+            .await;
+        assert!(matches!(result, Err(ProtocolError::InvalidPreKeyBundle(_))));
+        let found = alice.with_session_manager(|sm| Ok(sm.get_session(carol.user_id(), carol.device_id()))).unwrap();
+        assert!(found.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_initial_messages_establish_session_over_the_wire() {
+        let alice = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+
+        let bundle = bob.device_prekey_bundle().unwrap();
+        alice.establish_session(bob.user_id(), bob.device_id(), &bundle).await.unwrap();
+
+        // Until bob answers, every envelope carries the X3DH header
+        let mut initial = Vec::new();
+        for text in ["hello", "again"] {
+            let envelope = alice.send_message(bob.user_id(), bob.device_id(), text).await.unwrap();
+            let wire = MessageEnvelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+            assert!(wire.x3dh_header().is_some());
+
+            let received = bob.decrypt_message(alice.user_id(), alice.device_id(), &wire).await.unwrap();
+            assert_eq!(received.content_as_string(), Some(text.to_string()));
+            initial.push(wire);
+        }
+
+        let reply = bob.send_message(alice.user_id(), alice.device_id(), "hi").await.unwrap();
+        assert!(reply.x3dh_header().is_none());
+        alice.decrypt_message(bob.user_id(), bob.device_id(), &reply).await.unwrap();
+
+        let envelope = alice.send_message(bob.user_id(), bob.device_id(), "answered").await.unwrap();
+        assert!(envelope.x3dh_header().is_none());
+        assert!(envelope.session_nonce.is_none());
+        bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await.unwrap();
+
+        // Replaying the first message to a bob that lost the session must not
+        // start a second one
+        let session_id = bob
+            .with_session_manager(|sm| Ok(sm.get_session(alice.user_id(), alice.device_id())))
+            .unwrap()
+            .unwrap();
+        bob.storage.delete_session(&session_id).await.unwrap();
+        let restarted = ProtocolClient::new(ClientConfig::default(), bob.storage.clone());
+        restarted.initialize().await.unwrap();
+
+        let result = restarted.decrypt_message(alice.user_id(), alice.device_id(), &initial[0]).await;
+        assert!(matches!(result, Err(ProtocolError::ReplayedInitiation(_))));
+        assert_eq!(restarted.sessions().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sends_wait_for_key_confirmation() {
+        use crate::test_support::{LoopbackTransport, MockIdentityService};
+
+        let config = ClientConfig::builder().require_key_confirmation(true).build().unwrap();
+        let alice = ProtocolClient::new(config.clone(), MemoryStorage::new());
+        let bob = ProtocolClient::new(config, MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+
+        let bundle = bob.device_prekey_bundle().unwrap();
+        alice.establish_session(bob.user_id(), bob.device_id(), &bundle).await.unwrap();
+        let held = alice.send_message(bob.user_id(), bob.device_id(), "held");
+        assert!(matches!(held.await, Err(ProtocolError::SessionNotEstablished(_))));
+
+        // Bob derives the session from the confirmation and answers
+        let confirmation = alice.confirm_keys(bob.user_id(), bob.device_id()).await.unwrap();
+        let reply = bob.process_message(confirmation.clone()).await.unwrap().unwrap();
+        assert!(matches!(reply.message_type, ProtocolMessageType::KeyConfirmation(_)));
+
+        // Alice's own confirmation reflected back does not confirm her side
+        let mut reflected = confirmation;
+        reflected.sender_id = bob.user_id().clone();
+        reflected.sender_device_id = bob.device_id().clone();
+        assert!(matches!(
+            alice.process_message(reflected).await,
+            Err(ProtocolError::KeyConfirmationFailed(_))
+        ));
+
+        assert!(alice.process_message(reply).await.unwrap().is_none());
+        let transport = LoopbackTransport::new();
+        let report = alice.flush_pending(transport.as_ref(), &MockIdentityService::new()).await.unwrap();
+        assert_eq!(report.sent.len(), 1);
+        let sent = transport.receive(bob.user_id()).unwrap();
+        let ProtocolMessageType::EncryptedMessage(envelope) = sent.message_type else {
+            panic!("expected an encrypted message");
+        };
+        let received = bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await.unwrap();
+        assert_eq!(received.content_as_string().as_deref(), Some("held"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_send_and_receive() {
+        use crate::metrics::MemoryMetrics;
+        use crate::test_support::MockIdentityService;
+
+        let alice_metrics = Arc::new(MemoryMetrics::new());
+        let bob_metrics = Arc::new(MemoryMetrics::new());
+        let alice = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new())
+            .with_metrics(alice_metrics.clone());
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new())
+            .with_metrics(bob_metrics.clone());
+        let carol = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+        carol.initialize().await.unwrap();
+
+        let server = MockIdentityService::new();
+        server.publish_client(&carol);
+        alice
+            .establish_session_from_server(&server, carol.user_id(), carol.device_id())
+            .await
+            .unwrap();
+
+        alice.pair_for_tests(&bob).unwrap();
+        let message = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            bob.user_id().clone(),
+            "hello",
+        );
+        let envelope = alice
+            .encrypt_message(bob.user_id(), bob.device_id(), &message)
+            .await
+            .unwrap();
+        bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope)
+            .await
+            .unwrap();
+
+        let counts = |metrics: &MemoryMetrics| {
+            [
+                Counter::MessagesEncrypted,
+                Counter::MessagesDecrypted,
+                Counter::DecryptionFailures,
+                Counter::SessionsEstablished,
+                Counter::RatchetSteps,
+            ]
+            .map(|counter| metrics.count(counter))
+        };
+        assert_eq!(counts(&alice_metrics), [1, 0, 0, 1, 0]);
+        assert_eq!(counts(&bob_metrics), [0, 1, 0, 0, 1]);
+        let ciphertext_len = vec![envelope.ciphertext.len() as f64];
+        assert_eq!(alice_metrics.observations(Histogram::CiphertextBytes), ciphertext_len);
+        assert_eq!(bob_metrics.observations(Histogram::CiphertextBytes), ciphertext_len);
+
+        // A replay no longer decrypts
+        let result = bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await;
+        assert!(matches!(result, Err(ProtocolError::DecryptionFailed(_))));
+        assert_eq!(counts(&bob_metrics), [0, 1, 1, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_backup_restores_identity() {
+        let client = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        client.initialize().await.unwrap();
+        let backup = client.export_backup("hunter2").await.unwrap();
 
-        match encrypted {
-            Some(data) => {
-                // In production, decrypt with user password
-                // For now, just deserialize
-                let key_bytes: [u8; 32] = bincode::deserialize(&data)
-                    .map_err(|e| ProtocolError::Internal(e.to_string()))?;
-                
-                let key_pair = qiyashash_crypto::identity::IdentityKeyPair::from_secret_bytes(&key_bytes);
-                Ok(Some(Identity::from_key_pair(key_pair)))
-            }
-            None => Ok(None),
-        }
+        let restored = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        assert!(matches!(
+            restored.import_backup("wrong", &backup, false).await,
+            Err(ProtocolError::DecryptionFailed(_))
+        ));
+        restored.import_backup("hunter2", &backup, false).await.unwrap();
+        restored.initialize().await.unwrap();
+
+        assert_eq!(restored.fingerprint().unwrap(), client.fingerprint().unwrap());
+        assert!(matches!(
+            restored.import_backup("hunter2", &backup, true).await,
+            Err(ProtocolError::AlreadyInitialized)
+        ));
     }
 
-    async fn save_identity(&self, identity: &Identity) -> Result<()> {
-        let key_bytes = identity.key_pair.secret_bytes();
-        let encrypted = bincode::serialize(&key_bytes)
-            .map_err(|e| ProtocolError::Internal(e.to_string()))?;
+    #[tokio::test]
+    async fn test_backup_refuses_non_empty_store() {
+        let client = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        client.initialize().await.unwrap();
+        let backup = client.export_backup("hunter2").await.unwrap();
 
-        self.storage.save_identity_key(encrypted).await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        let storage = MemoryStorage::new();
+        ProtocolClient::new(ClientConfig::default(), storage.clone())
+            .initialize()
+            .await
+            .unwrap();
 
-        Ok(())
+        let target = ProtocolClient::new(ClientConfig::default(), storage);
+        assert!(matches!(
+            target.import_backup("hunter2", &backup, false).await,
+            Err(ProtocolError::Backup(_))
+        ));
+        target.import_backup("hunter2", &backup, true).await.unwrap();
+        target.initialize().await.unwrap();
+        assert_eq!(target.fingerprint().unwrap(), client.fingerprint().unwrap());
     }
 
-    fn compute_timestamp_hash(&self, timestamp: Timestamp) -> [u8; 32] {
-        use sha2::{Sha256, Digest};
-        let mut hasher = Sha256::new();
-        hasher.update(b"QiyasHash_Timestamp_v1");
-        hasher.update(&timestamp.as_millis().to_be_bytes());
-        // Add random noise for metadata protection
-        let noise: [u8; 16] = rand::random();
-        hasher.update(&noise);
-        let result = hasher.finalize();
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&result);
-        hash
+    #[tokio::test]
+    async fn test_forced_backup_import_replaces_store_atomically() {
+        let client = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        client.initialize().await.unwrap();
+        let backed_up = Message::text(UserId::new(), DeviceId::new(), client.user_id().clone(), "kept");
+        client.storage.save_message(&backed_up).await.unwrap();
+        let backup = client.export_backup("hunter2").await.unwrap();
+
+        let storage = MemoryStorage::new();
+        let previous = ProtocolClient::new(ClientConfig::default(), storage.clone());
+        previous.initialize().await.unwrap();
+        let local = Message::text(UserId::new(), DeviceId::new(), previous.user_id().clone(), "local");
+        storage.save_message(&local).await.unwrap();
+
+        // A failure part-way leaves the store untouched
+        let target = ProtocolClient::new(ClientConfig::default(), storage.clone());
+        storage.fail_message_writes_after(0);
+        assert!(matches!(
+            target.import_backup("hunter2", &backup, true).await,
+            Err(ProtocolError::Storage(_))
+        ));
+        assert!(storage.get_message(&local.id).await.unwrap().is_some());
+        assert!(storage.get_message(&backed_up.id).await.unwrap().is_none());
+
+        // Records only the old store had are gone after a forced import
+        storage.fail_message_writes_after(usize::MAX);
+        target.import_backup("hunter2", &backup, true).await.unwrap();
+        assert!(storage.get_message(&local.id).await.unwrap().is_none());
+        assert!(storage.get_message(&backed_up.id).await.unwrap().is_some());
+        target.initialize().await.unwrap();
+        assert_eq!(target.fingerprint().unwrap(), client.fingerprint().unwrap());
+        let prekeys = client.storage.get_one_time_prekey_ids().await.unwrap();
+        let mut restored = storage.get_one_time_prekey_ids().await.unwrap();
+        restored.sort_unstable();
+        let mut expected = prekeys;
+        expected.sort_unstable();
+        assert_eq!(restored, expected);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use qiyashash_core::storage::memory::MemoryStorage;
+    #[tokio::test]
+    async fn test_malformed_plaintext_is_quarantined() {
+        let alice = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+
+        let (alice_session, _) = {
+            let alice_sm = alice.session_manager.read();
+            let bob_sm = bob.session_manager.read();
+            alice_sm.as_ref().unwrap().pair_for_tests(
+                alice.user_id(),
+                bob_sm.as_ref().unwrap(),
+                bob.user_id(),
+            )
+        };
+
+        // Authentic ciphertext whose plaintext is not a Message
+        let garbage = b"\xff\xff not a message".to_vec();
+        let (ciphertext, chain_state, _) = alice
+            .with_session_manager(|sm| sm.encrypt(&alice_session, &garbage))
+            .unwrap();
+        let mut envelope = alice
+            .encrypt_message(
+                bob.user_id(),
+                bob.device_id(),
+                &Message::text(
+                    alice.user_id().clone(),
+                    alice.device_id().clone(),
+                    bob.user_id().clone(),
+                    "x",
+                ),
+            )
+            .await
+            .unwrap();
+        let valid_ciphertext = std::mem::replace(&mut envelope.ciphertext, ciphertext);
+        envelope.chain_proof = chain_state;
+
+        let result = bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await;
+        assert!(matches!(result, Err(ProtocolError::MalformedPlaintext(_))));
+
+        let quarantined = bob.quarantined_payloads().await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].plaintext, garbage);
+        assert_eq!(&quarantined[0].sender_id, alice.user_id());
+
+        // The session keeps working for the next message
+        envelope.ciphertext = valid_ciphertext;
+        let message = bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await.unwrap();
+        assert_eq!(message.content_as_string().as_deref(), Some("x"));
+    }
 
     #[tokio::test]
-    async fn test_client_initialization() {
+    async fn test_discarded_prekeys_are_not_offered() {
         let storage = MemoryStorage::new();
-        let config = ClientConfig::default();
-        let client = ProtocolClient::new(config, storage);
+        let client = ProtocolClient::new(ClientConfig::default(), storage.clone());
+        client.initialize().await.unwrap();
 
-        assert!(!client.is_ready());
-        
+        let prekeys = client.generate_prekeys(2).await.unwrap();
+        assert_eq!(storage.get_one_time_prekey_count().await.unwrap(), 2);
+        let bundle = client.device_prekey_bundle().unwrap();
+        assert_eq!(bundle.one_time_prekey_id, Some(prekeys[0].id));
+        assert_eq!(bundle.one_time_prekey, Some(prekeys[0].public_key));
+
+        client.discard_prekeys(&[prekeys[0].id]).await.unwrap();
+        assert_eq!(storage.get_one_time_prekey_ids().await.unwrap(), vec![prekeys[1].id]);
+        assert_eq!(client.device_prekey_bundle().unwrap().one_time_prekey_id, Some(prekeys[1].id));
+    }
+
+    #[tokio::test]
+    async fn test_rotation_policy_rotates_aged_keys() {
+        use std::sync::atomic::{AtomicI64, Ordering};
+
+        let storage = MemoryStorage::new();
+        let client = ProtocolClient::new(ClientConfig::default(), storage.clone());
         client.initialize().await.unwrap();
-        
-        assert!(client.is_ready());
+        let old_fingerprint = client.fingerprint().unwrap();
+        let account_secret = StoredIdentityKey::decode(&storage.get_identity_key().await.unwrap().unwrap())
+            .unwrap()
+            .account_secret;
+
+        let now = Arc::new(AtomicI64::new(Timestamp::now().as_millis()));
+        let clock = now.clone();
+        let policy = KeyRotationPolicy::default()
+            .with_clock(move || Timestamp::from_millis(clock.load(Ordering::SeqCst)));
+
+        // Fresh keys only need one-time prekeys
+        let report = client.enforce_rotation_policy(&policy).await.unwrap();
+        assert!(report.identity_rotation.is_none());
+        assert!(!report.signed_prekey_rotated);
+        assert_eq!(report.one_time_prekeys_added, policy.one_time_prekey_target);
+        assert_eq!(
+            storage.get_one_time_prekey_count().await.unwrap(),
+            policy.one_time_prekey_target
+        );
+        assert!(client.enforce_rotation_policy(&policy).await.unwrap().is_empty());
+
+        // Past the signed prekey limit only
+        let later = Timestamp::from_millis(now.load(Ordering::SeqCst)).saturating_add_secs(31 * 24 * 3600);
+        now.store(later.as_millis(), Ordering::SeqCst);
+        let spk_id = client.get_prekey_bundle().unwrap().signed_prekey.id;
+        let report = client.enforce_rotation_policy(&policy).await.unwrap();
+        assert!(report.identity_rotation.is_none());
+        assert!(report.signed_prekey_rotated);
+        let new_spk_id = client.get_prekey_bundle().unwrap().signed_prekey.id;
+        assert_ne!(new_spk_id, spk_id);
+        // The replaced key is kept for messages already in flight
+        assert!(storage.get_signed_prekey(spk_id).await.unwrap().is_some());
+        let record = StoredSignedPreKey::decode(
+            &storage.get_signed_prekey(new_spk_id).await.unwrap().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(record.created_at, later);
+
+        // Past the identity limit
+        let much_later = later.saturating_add_secs(366 * 24 * 3600);
+        now.store(much_later.as_millis(), Ordering::SeqCst);
+        let report = client.enforce_rotation_policy(&policy).await.unwrap();
+        let proof = report.identity_rotation.as_ref().unwrap();
+        proof.verify().unwrap();
+        assert!(report.signed_prekey_rotated);
+        assert_eq!(report.one_time_prekeys_added, policy.one_time_prekey_target);
+        assert_ne!(client.fingerprint().unwrap(), old_fingerprint);
+        assert!(report.announcements.iter().any(|m| matches!(
+            m.message_type,
+            ProtocolMessageType::IdentityKeyUpdate(_)
+        )));
+
+        let stored = StoredIdentityKey::decode(&storage.get_identity_key().await.unwrap().unwrap())
+            .unwrap();
+        assert_eq!(stored.created_at, Some(much_later));
+        // Sessions sealed before the rotation still open
+        assert_eq!(stored.account_secret, account_secret);
+        // Only the signed prekey just replaced is still kept
+        assert!(storage.get_signed_prekey(spk_id).await.unwrap().is_none());
+        assert!(storage.get_signed_prekey(new_spk_id).await.unwrap().is_some());
+        assert!(client.enforce_rotation_policy(&policy).await.unwrap().is_empty());
+
+        // Until its grace period is over
+        let past_grace = much_later.saturating_add_secs(policy.signed_prekey_grace_secs + 1);
+        now.store(past_grace.as_millis(), Ordering::SeqCst);
+        let report = client.enforce_rotation_policy(&policy).await.unwrap();
+        assert_eq!(report.signed_prekey_retired, Some(new_spk_id));
+        assert!(storage.get_signed_prekey(new_spk_id).await.unwrap().is_none());
+        assert!(client.enforce_rotation_policy(&policy).await.unwrap().is_empty());
     }
 
     #[tokio::test]
-    async fn test_double_initialization() {
+    async fn test_untimed_identity_is_stamped_on_load() {
         let storage = MemoryStorage::new();
-        let config = ClientConfig::default();
-        let client = ProtocolClient::new(config, storage);
+        let legacy = StoredIdentityKey {
+            secret: [0x01; 32],
+            created_at: None,
+            account_secret: [0x02; 32],
+        };
+        storage.save_identity_key(legacy.encode().unwrap()).await.unwrap();
 
+        let client = ProtocolClient::new(ClientConfig::default(), storage.clone());
         client.initialize().await.unwrap();
-        
-        let result = client.initialize().await;
-        assert!(matches!(result, Err(ProtocolError::AlreadyInitialized)));
+        let stored = StoredIdentityKey::decode(&storage.get_identity_key().await.unwrap().unwrap())
+            .unwrap();
+        assert!(stored.created_at.is_some());
+        assert_eq!(stored.account_secret, [0x02; 32]);
+
+        let report = client.enforce_rotation_policy(&KeyRotationPolicy::default()).await.unwrap();
+        assert!(report.identity_rotation.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_muted_conversation_suppresses_notifications() {
+        use qiyashash_core::user::ConversationSettings;
+        use std::sync::atomic::{AtomicI64, Ordering};
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        let start = Timestamp::from_secs(1_700_000_000);
+        let now = Arc::new(AtomicI64::new(start.as_millis()));
+        let clock = now.clone();
+
+        let alice = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        let bob_storage = MemoryStorage::new();
+        let bob = ProtocolClient::new(ClientConfig::default(), bob_storage.clone())
+            .with_clock(move || Timestamp::from_millis(clock.load(Ordering::SeqCst)));
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+        {
+            let alice_sm = alice.session_manager.read();
+            let bob_sm = bob.session_manager.read();
+            alice_sm.as_ref().unwrap().pair_for_tests(
+                alice.user_id(),
+                bob_sm.as_ref().unwrap(),
+                bob.user_id(),
+            );
+        }
+
+        let mut notifications = bob.subscribe_notifications();
+        async fn send(
+            from: &ProtocolClient<MemoryStorage>,
+            to: &ProtocolClient<MemoryStorage>,
+            text: &str,
+        ) {
+            let message = Message::text(
+                from.user_id().clone(),
+                from.device_id().clone(),
+                to.user_id().clone(),
+                text,
+            );
+            let envelope = from
+                .encrypt_message(to.user_id(), to.device_id(), &message)
+                .await
+                .unwrap();
+            to.decrypt_message(from.user_id(), from.device_id(), &envelope)
+                .await
+                .unwrap();
+        }
+
+        send(&alice, &bob, "first").await;
+        let notification = notifications.try_recv().unwrap();
+        assert_eq!(&notification.sender_id, alice.user_id());
+        assert_eq!(notification.preview.as_deref(), Some("first"));
+
+        let mut settings = ConversationSettings::default();
+        settings.mute_until(start.saturating_add_secs(3600));
+        settings.notification_sound = Some("chime".to_string());
+        bob_storage.set_conversation_settings(alice.user_id(), &settings).await.unwrap();
+
+        send(&alice, &bob, "muted").await;
+        assert!(matches!(notifications.try_recv(), Err(TryRecvError::Empty)));
+
+        // The mute lapses once its end has passed
+        now.store(start.saturating_add_secs(3600).as_millis(), Ordering::SeqCst);
+        send(&alice, &bob, "unmuted").await;
+        let notification = notifications.try_recv().unwrap();
+        assert_eq!(notification.preview.as_deref(), Some("unmuted"));
+        assert_eq!(notification.sound.as_deref(), Some("chime"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_is_recorded() {
+        let storage = MemoryStorage::new();
+        let client = ProtocolClient::new(ClientConfig::default(), storage.clone());
+        client.initialize().await.unwrap();
+
+        // No session yet: worth retrying once one is established
+        let result = client.send_message(&UserId::new(), &DeviceId::new(), "hi").await;
+        assert!(matches!(result, Err(ProtocolError::SessionNotEstablished(_))));
+
+        let pending = storage.get_pending_messages().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            pending[0].status,
+            MessageStatus::Failed {
+                reason: DeliveryFailure::NoSession,
+                retryable: true,
+                attempts: 1,
+            }
+        );
+
+        // A permanent failure reported later takes it out of the queue
+        let status = client
+            .record_delivery_failure(&pending[0].id, DeliveryFailure::RecipientBlocked)
+            .await
+            .unwrap();
+        assert_eq!(
+            status,
+            MessageStatus::Failed {
+                reason: DeliveryFailure::RecipientBlocked,
+                retryable: false,
+                attempts: 2,
+            }
+        );
+        assert!(storage.get_pending_messages().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flush_pending_sends_queued_messages() {
+        use crate::test_support::{LoopbackTransport, MockIdentityService};
+
+        let storage = MemoryStorage::new();
+        let alice = ProtocolClient::new(ClientConfig::default(), storage.clone());
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+
+        // Composed while offline, before any session with bob existed
+        assert!(alice.send_message(bob.user_id(), bob.device_id(), "first").await.is_err());
+        let queued = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            bob.user_id().clone(),
+            "second",
+        )
+        .with_recipient_device(bob.device_id().clone());
+        storage.save_message(&queued).await.unwrap();
+        // Nobody publishes a bundle for this device
+        let unreachable = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            UserId::new(),
+            "lost",
+        )
+        .with_recipient_device(DeviceId::new());
+        storage.save_message(&unreachable).await.unwrap();
+        assert_eq!(storage.get_pending_messages().await.unwrap().len(), 3);
+
+        // Reconnected
+        let server = MockIdentityService::new();
+        server.publish_client(&bob);
+        let transport = LoopbackTransport::new();
+        let report = alice.flush_pending(transport.as_ref(), &server).await.unwrap();
+
+        assert_eq!(report.sent.len(), 2);
+        assert!(report.sent.contains(&queued.id));
+        assert_eq!(report.failed, vec![unreachable.id.clone()]);
+        assert_eq!(transport.pending(bob.user_id()), 2);
+        for id in &report.sent {
+            let stored = storage.get_message(id).await.unwrap().unwrap();
+            assert_eq!(stored.status, MessageStatus::Sent);
+        }
+        let stored = storage.get_message(&unreachable.id).await.unwrap().unwrap();
+        assert!(matches!(stored.status, MessageStatus::Failed { retryable: false, .. }));
+
+        // Nothing is sent twice
+        assert!(storage.get_pending_messages().await.unwrap().is_empty());
+        let report = alice.flush_pending(transport.as_ref(), &server).await.unwrap();
+        assert_eq!(report, FlushReport::default());
+        assert_eq!(transport.pending(bob.user_id()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_flush_pending_falls_back_and_retries() {
+        use crate::test_support::{LoopbackTransport, MockIdentityService};
+
+        let storage = MemoryStorage::new();
+        let alice = ProtocolClient::new(ClientConfig::default(), storage.clone());
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        let carol = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new());
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+        carol.initialize().await.unwrap();
+        let server = MockIdentityService::new();
+        server.publish_client(&bob);
+        server.publish_client(&carol);
+        let transport = LoopbackTransport::new();
+
+        let queue = |recipient: &UserId, device: Option<&DeviceId>, text: &str| {
+            let mut message = Message::text(
+                alice.user_id().clone(),
+                alice.device_id().clone(),
+                recipient.clone(),
+                text,
+            );
+            message.recipient_device_id = device.cloned();
+            message
+        };
+
+        // A session with bob exists after the first flush
+        let first = queue(bob.user_id(), Some(bob.device_id()), "first");
+        storage.save_message(&first).await.unwrap();
+        alice.flush_pending(transport.as_ref(), &server).await.unwrap();
+
+        // A message that never recorded a device goes to the one we talk to;
+        // with the identity service down, carol's waits for the next flush
+        let deviceless = queue(bob.user_id(), None, "no device");
+        storage.save_message(&deviceless).await.unwrap();
+        let to_carol = queue(carol.user_id(), Some(carol.device_id()), "later");
+        storage.save_message(&to_carol).await.unwrap();
+        server.set_unavailable(true);
+
+        // Two flushes at once send everything once
+        let (a, b) = tokio::join!(
+            alice.flush_pending(transport.as_ref(), &server),
+            alice.flush_pending(transport.as_ref(), &server),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!([a.sent.clone(), b.sent.clone()].concat(), vec![deviceless.id.clone()]);
+        assert_eq!(transport.pending(bob.user_id()), 2);
+        let stored = storage.get_message(&deviceless.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, MessageStatus::Sent);
+        assert_eq!(stored.recipient_device_id.as_ref(), Some(bob.device_id()));
+        let stored = storage.get_message(&to_carol.id).await.unwrap().unwrap();
+        assert!(matches!(
+            stored.status,
+            MessageStatus::Failed { reason: DeliveryFailure::NoSession, retryable: true, .. }
+        ));
+
+        // Back online
+        server.set_unavailable(false);
+        let report = alice.flush_pending(transport.as_ref(), &server).await.unwrap();
+        assert_eq!(report.sent, vec![to_carol.id.clone()]);
+        assert_eq!(transport.pending(carol.user_id()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_deliveries_collapse() {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        let config = ClientConfig::builder()
+            .deduplicate_deliveries(true)
+            .build()
+            .unwrap();
+        let pair = crate::test_support::establish_paired_clients_with(config).await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let mut notifications = bob.subscribe_notifications();
+
+        let encrypt = |text: &str| {
+            let message = Message::text(
+                alice.user_id().clone(),
+                alice.device_id().clone(),
+                bob.user_id().clone(),
+                text,
+            );
+            async move {
+                alice.encrypt_message(bob.user_id(), bob.device_id(), &message).await.unwrap()
+            }
+        };
+
+        // The same envelope through two relays
+        let sent = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            bob.user_id().clone(),
+            "twice",
+        );
+        let envelope = alice.encrypt_message(bob.user_id(), bob.device_id(), &sent).await.unwrap();
+        let first = bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await.unwrap();
+        let second = bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await.unwrap();
+        // The sender's ID survives, so reactions and receipts still find it
+        assert_eq!(first.id, sent.id);
+        assert_eq!(second.id, sent.id);
+        assert_eq!(second.content_as_string().as_deref(), Some("twice"));
+        assert_eq!(bob.storage.get_stats().await.unwrap().message_count, 1);
+
+        // And twice within one backfill
+        let envelope = encrypt("batched").await;
+        let copy = (alice.user_id().clone(), alice.device_id().clone(), envelope);
+        let results = bob.decrypt_messages(&[copy.clone(), copy]).await.results;
+        let ids: Vec<_> = results.into_iter().map(|r| r.unwrap().id).collect();
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[0], first.id);
+        assert_eq!(bob.storage.get_stats().await.unwrap().message_count, 2);
+
+        // Each message is announced once
+        assert!(notifications.try_recv().is_ok());
+        assert!(notifications.try_recv().is_ok());
+        assert!(matches!(notifications.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn test_send_preflight_checks() {
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+
+        let oversized = "a".repeat(MAX_MESSAGE_SIZE + 1);
+        let result = alice.send_message(bob.user_id(), bob.device_id(), &oversized).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::MessageTooLarge { size, max })
+                if size == MAX_MESSAGE_SIZE + 1 && max == MAX_MESSAGE_SIZE
+        ));
+
+        // Nothing to send
+        let result = alice.send_message(bob.user_id(), bob.device_id(), "").await;
+        assert!(matches!(result, Err(ProtocolError::InvalidMessage(_))));
+
+        let result = alice.send_message(&UserId::from_string(""), bob.device_id(), "hi").await;
+        assert!(matches!(result, Err(ProtocolError::InvalidMessage(_))));
+
+        // Rejected up front, so none of them were stored
+        assert!(alice.storage.get_pending_messages().await.unwrap().is_empty());
+
+        alice.send_message(bob.user_id(), bob.device_id(), "hi").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_reset_requires_peer_mac() {
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let session_of = |client: &TestClient, peer: &TestClient| {
+            client
+                .with_session_manager(|sm| Ok(sm.get_session(peer.user_id(), peer.device_id())))
+                .unwrap()
+        };
+        let alice_session = session_of(alice, bob).unwrap();
+        let reset_from_alice = |request: SessionResetRequest| {
+            ProtocolMessage::new(
+                ProtocolMessageType::SessionReset(request),
+                alice.user_id().clone(),
+                alice.device_id().clone(),
+            )
+        };
+
+        let valid = alice
+            .with_session_manager(|sm| {
+                sm.session_reset(&alice_session, SessionResetReason::DecryptionFailure)
+            })
+            .unwrap();
+
+        // Unauthenticated, tampered and foreign-key resets are all refused
+        let unauthenticated = SessionResetHandler::create_reset(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            SessionResetReason::DecryptionFailure,
+        );
+        let mut tampered = valid.clone();
+        tampered.reason = SessionResetReason::UserRequested;
+        let mut forged = valid.clone();
+        forged.mac = compute_auth_tag(&[0x42; 32], &forged.authenticated_data());
+        // The app-level deniable MAC key can't authorize a reset
+        let mut deniable = valid.clone();
+        deniable.mac = alice
+            .with_session_manager(|sm| sm.deniable_mac(&alice_session, &valid.authenticated_data()))
+            .unwrap();
+
+        for request in [unauthenticated, tampered, forged, deniable] {
+            assert!(matches!(
+                bob.process_message(reset_from_alice(request)).await,
+                Err(ProtocolError::Unauthorized(_))
+            ));
+            assert!(session_of(bob, alice).is_some());
+        }
+
+        bob.process_message(reset_from_alice(valid.clone())).await.unwrap();
+        assert!(session_of(bob, alice).is_none());
+
+        // Replaying it with no session left is refused too
+        assert!(matches!(
+            bob.process_message(reset_from_alice(valid)).await,
+            Err(ProtocolError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reaction_updates_target() {
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let target = pair.assert_delivers(alice, bob, "lunch?").await.id;
+
+        let react = |emoji: &'static str, remove: bool| {
+            let target = target.clone();
+            async move {
+                let envelope = bob
+                    .send_reaction(alice.user_id(), alice.device_id(), &target, emoji, remove)
+                    .await?;
+                alice
+                    .process_message(ProtocolMessage::new(
+                        ProtocolMessageType::EncryptedMessage(envelope),
+                        bob.user_id().clone(),
+                        bob.device_id().clone(),
+                    ))
+                    .await
+            }
+        };
+
+        react("👍", false).await.unwrap();
+        let stored = alice.storage.get_message(&target).await.unwrap().unwrap();
+        assert_eq!(stored.reactions.len(), 1);
+        assert_eq!(stored.reactions[0].emoji, "👍");
+        assert_eq!(&stored.reactions[0].sender_id, bob.user_id());
+
+        // Bob's copy carries it too, and neither side stored the reaction itself
+        let bobs_copy = bob.storage.get_message(&target).await.unwrap().unwrap();
+        assert_eq!(bobs_copy.reactions, stored.reactions);
+        for (client, peer) in [(alice, bob), (bob, alice)] {
+            let history = client
+                .storage
+                .get_messages_for_conversation(peer.user_id(), 10, None)
+                .await
+                .unwrap();
+            assert_eq!(history.len(), 1);
+        }
+
+        // Only single graphemes are accepted
+        assert!(matches!(react("👍👍", false).await, Err(ProtocolError::Core(_))));
+
+        react("👍", true).await.unwrap();
+        let stored = alice.storage.get_message(&target).await.unwrap().unwrap();
+        assert!(stored.reactions.is_empty());
+        assert!(bob.storage.get_message(&target).await.unwrap().unwrap().reactions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reaction_uses_client_clock() {
+        let fixed = Timestamp::from_millis(Timestamp::now().as_millis() - 60_000);
+        let alice = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new())
+            .with_clock(move || fixed);
+        let bob = ProtocolClient::new(ClientConfig::default(), MemoryStorage::new())
+            .with_clock(move || fixed);
+        alice.initialize().await.unwrap();
+        bob.initialize().await.unwrap();
+        alice.pair_for_tests(&bob).unwrap();
+        let pair = crate::test_support::PairedClients {
+            alice,
+            bob,
+            transport: crate::test_support::LoopbackTransport::new(),
+        };
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let target = pair.assert_delivers(alice, bob, "lunch?").await.id;
+
+        let envelope = bob
+            .send_reaction(alice.user_id(), alice.device_id(), &target, "👍", false)
+            .await
+            .unwrap();
+        alice
+            .process_message(ProtocolMessage::new(
+                ProtocolMessageType::EncryptedMessage(envelope),
+                bob.user_id().clone(),
+                bob.device_id().clone(),
+            ))
+            .await
+            .unwrap();
+
+        for client in [alice, bob] {
+            let stored = client.storage.get_message(&target).await.unwrap().unwrap();
+            assert_eq!(stored.reactions[0].reacted_at, fixed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receipts_update_sent_status() {
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let sent = pair.assert_delivers(alice, bob, "lunch?").await.id;
+
+        let acknowledge = |receipt_type| {
+            let sent = sent.clone();
+            async move {
+                let envelope = bob
+                    .send_receipt(alice.user_id(), alice.device_id(), &sent, receipt_type)
+                    .await?;
+                alice
+                    .process_message(ProtocolMessage::new(
+                        ProtocolMessageType::EncryptedMessage(envelope),
+                        bob.user_id().clone(),
+                        bob.device_id().clone(),
+                    ))
+                    .await
+            }
+        };
+        let status = || async { alice.storage.get_message(&sent).await.unwrap().unwrap().status };
+
+        acknowledge(ReceiptType::Delivered).await.unwrap();
+        assert_eq!(status().await, MessageStatus::Delivered);
+        acknowledge(ReceiptType::Read).await.unwrap();
+        assert_eq!(status().await, MessageStatus::Read);
+        // A delivery receipt arriving late leaves the message read
+        acknowledge(ReceiptType::Delivered).await.unwrap();
+        assert_eq!(status().await, MessageStatus::Read);
+
+        // Receipts are not stored as messages on either side
+        for (client, peer) in [(alice, bob), (bob, alice)] {
+            let history = client
+                .storage
+                .get_messages_for_conversation(peer.user_id(), 10, None)
+                .await
+                .unwrap();
+            assert_eq!(history.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_messages_stores_batch_in_one_commit() {
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        // Bob records Alice's identity key on the first message
+        pair.assert_delivers(alice, bob, "first").await;
+
+        for i in 0..20 {
+            pair.send(alice, bob, &format!("backfill {}", i)).await;
+        }
+        let mut envelopes = Vec::new();
+        while let Some(message) = pair.transport.receive(bob.user_id()) {
+            match message.message_type {
+                ProtocolMessageType::EncryptedMessage(envelope) => {
+                    envelopes.push((message.sender_id, message.sender_device_id, envelope))
+                }
+                _ => unreachable!("only messages were sent"),
+            }
+        }
+
+        let commits = bob.storage.commit_count();
+        let outcome = bob.decrypt_messages(&envelopes).await;
+        assert!(outcome.save_error.is_none());
+        let results = outcome.results;
+        assert_eq!(bob.storage.commit_count(), commits + 1);
+        assert_eq!(results.len(), 20);
+        assert!(results.iter().all(Result::is_ok));
+
+        let history = bob
+            .storage
+            .get_messages_for_conversation(alice.user_id(), 100, None)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 21);
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_save_returns_decrypted_messages() {
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        pair.assert_delivers(alice, bob, "first").await;
+
+        let one = pair.send(alice, bob, "one").await;
+        pair.send(alice, bob, "two").await;
+        pair.send(alice, bob, "three").await;
+        let mut backfill = Vec::new();
+        while let Some(message) = pair.transport.receive(bob.user_id()) {
+            backfill.push(message);
+        }
+
+        // The disk fills up part-way through the batch
+        bob.storage.fail_message_writes_after(2);
+        let outcome = bob.process_messages(backfill).await;
+        assert!(matches!(outcome.save_error, Some(ProtocolError::Storage(_))));
+        assert_eq!(outcome.results.len(), 3);
+        assert!(outcome.results.iter().all(Result::is_ok));
+        assert!(bob.storage.get_message(&one).await.unwrap().is_none());
+
+        // Each ratchet has moved on, so the copies in the results are the
+        // only ones; a later batch still decrypts
+        pair.send(alice, bob, "after").await;
+        let after = pair.transport.receive(bob.user_id()).unwrap();
+        bob.storage.fail_message_writes_after(usize::MAX);
+        let outcome = bob.process_messages(vec![after]).await;
+        assert!(outcome.save_error.is_none());
+        assert!(outcome.results[0].is_ok());
+    }
+
+    /// Make `client` remember `peer` by a key other than the one it uses
+    async fn store_stale_identity(client: &TestClient, peer: &TestClient) -> [u8; 32] {
+        let stale = [0x42; 32];
+        client.storage.save_remote_identity(peer.user_id(), stale).await.unwrap();
+        stale
+    }
+
+    #[tokio::test]
+    async fn test_changed_identity_key_emits_one_event() {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let mut events = bob.subscribe_events();
+
+        // The first key seen is trusted without an event
+        let first = pair.assert_delivers(alice, bob, "hi").await;
+        assert!(!first.unverified_sender);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        let stale = store_stale_identity(bob, alice).await;
+        let changed = pair.assert_delivers(alice, bob, "new phone").await;
+        assert!(changed.unverified_sender);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ClientEvent::SafetyNumberChanged {
+                user_id: alice.user_id().clone(),
+                old_key: stale,
+                new_key: alice
+                    .with_session_manager(|sm| Ok(sm.identity_public_key().signing_key_bytes()))
+                    .unwrap(),
+            }
+        );
+
+        // Still unverified, but the change was already reported
+        assert!(pair.assert_delivers(alice, bob, "it's me").await.unverified_sender);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        // A key swapped into the envelope is not the session's and changes nothing
+        let message = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            bob.user_id().clone(),
+            "trust me",
+        );
+        let mut envelope = alice.encrypt_message(bob.user_id(), bob.device_id(), &message).await.unwrap();
+        envelope.sender_identity_key = Identity::new().key_pair.public_key().signing_key_bytes();
+        assert!(matches!(
+            bob.decrypt_message(alice.user_id(), alice.device_id(), &envelope).await,
+            Err(ProtocolError::IdentityMismatch { .. })
+        ));
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn test_reverified_identity_is_not_flagged() {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        let config = ClientConfig { strict_identity_keys: true, ..Default::default() };
+        let pair = crate::test_support::establish_paired_clients_with(config).await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        pair.assert_delivers(alice, bob, "hi").await;
+        let mut events = bob.subscribe_events();
+
+        // Strict mode refuses the message instead of flagging it
+        store_stale_identity(bob, alice).await;
+        pair.send(alice, bob, "new phone").await;
+        let refused = pair.receive(bob).await.unwrap();
+        assert!(matches!(refused, Err(ProtocolError::UntrustedIdentity(_))));
+        assert!(events.try_recv().is_ok());
+
+        bob.verify_identity(alice.user_id()).await.unwrap();
+        for text in ["verified", "again"] {
+            assert!(!pair.assert_delivers(alice, bob, text).await.unverified_sender);
+        }
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+        let user = bob.storage.get_user(alice.user_id()).await.unwrap().unwrap();
+        assert_eq!(user.trust_level, TrustLevel::Verified);
+    }
+
+    #[tokio::test]
+    async fn test_lru_session_eviction_and_reload() {
+        let config = ClientConfig {
+            max_active_sessions: 1,
+            ..Default::default()
+        };
+        let pair = crate::test_support::establish_paired_clients_with(config.clone()).await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let carol = ProtocolClient::new(config, MemoryStorage::new());
+        carol.initialize().await.unwrap();
+        alice.pair_for_tests(&carol).unwrap();
+
+        let in_memory = |peer: &TestClient| {
+            alice
+                .with_session_manager(|sm| Ok(sm.get_session(peer.user_id(), peer.device_id())))
+                .unwrap()
+                .is_some()
+        };
+
+        // Using bob's session saves and drops carol's, the least recently used
+        pair.assert_delivers(alice, bob, "hi bob").await;
+        assert!(in_memory(bob));
+        assert!(!in_memory(&carol));
+        let stored = alice.storage
+            .get_session_by_user_device(carol.user_id(), carol.device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!stored.ratchet_state.is_empty());
+
+        // Carol's session comes back with its ratchet intact, evicting bob's
+        let message = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            carol.user_id().clone(),
+            "hi carol",
+        );
+        let envelope = alice
+            .encrypt_message(carol.user_id(), carol.device_id(), &message)
+            .await
+            .unwrap();
+        let received = carol
+            .decrypt_message(alice.user_id(), alice.device_id(), &envelope)
+            .await
+            .unwrap();
+        assert_eq!(received.content_as_string().as_deref(), Some("hi carol"));
+        assert!(in_memory(&carol));
+        assert!(!in_memory(bob));
+
+        // Incoming messages reload sessions too
+        pair.assert_delivers(bob, alice, "hi alice").await;
+        pair.assert_delivers(alice, bob, "and back").await;
+        assert!(in_memory(bob));
+        assert!(!in_memory(&carol));
+    }
+
+    #[tokio::test]
+    async fn test_restarts_never_reuse_message_keys() {
+        use qiyashash_crypto::ratchet::RatchetMessage;
+        use std::collections::HashSet;
+
+        let pair = crate::test_support::establish_paired_clients().await;
+        let (alice_id, alice_device) = (pair.alice.user_id().clone(), pair.alice.device_id().clone());
+        let mut numbers = HashSet::new();
+        let mut nonces = HashSet::new();
+        let mut restarted: Option<(TestClient, TestClient)> = None;
+
+        for round in 0..3 {
+            if round > 0 {
+                // A fresh process over the same stores, dropping the old one
+                let restart = |client: &TestClient| {
+                    ProtocolClient::new(ClientConfig::default(), client.storage.clone())
+                };
+                let (alice, bob) = match &restarted {
+                    Some((alice, bob)) => (restart(alice), restart(bob)),
+                    None => (restart(&pair.alice), restart(&pair.bob)),
+                };
+                alice.initialize().await.unwrap();
+                bob.initialize().await.unwrap();
+                restarted = Some((alice, bob));
+            }
+            let (alice, bob) = match &restarted {
+                Some((alice, bob)) => (alice, bob),
+                None => (&pair.alice, &pair.bob),
+            };
+
+            for i in 0..2 {
+                let text = format!("round {} message {}", round, i);
+                let envelope = alice
+                    .send_message(pair.bob.user_id(), pair.bob.device_id(), &text)
+                    .await
+                    .unwrap();
+                let sent = RatchetMessage::from_bytes(&envelope.ciphertext).unwrap();
+                assert!(numbers.insert(sent.header.message_number), "message number reused");
+                assert!(nonces.insert(sent.payload.nonce.as_bytes().to_vec()), "nonce reused");
+
+                let received = bob.decrypt_message(&alice_id, &alice_device, &envelope).await.unwrap();
+                assert_eq!(received.content_as_string(), Some(text));
+            }
+        }
+        assert_eq!(numbers.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_sealed_sender() {
+        let config = ClientConfig {
+            sealed_sender: true,
+            ..Default::default()
+        };
+        let pair = crate::test_support::establish_paired_clients_with(config).await;
+        let (alice, bob) = (&pair.alice, &pair.bob);
+        let alice_key = alice
+            .with_session_manager(|sm| Ok(sm.identity_public_key().signing_key_bytes()))
+            .unwrap();
+
+        let message = Message::text(
+            alice.user_id().clone(),
+            alice.device_id().clone(),
+            bob.user_id().clone(),
+            "who sent this?",
+        );
+        let envelope = alice
+            .encrypt_message(bob.user_id(), bob.device_id(), &message)
+            .await
+            .unwrap();
+
+        // Nothing on the wire names the sender
+        assert!(envelope.is_sealed_sender());
+        let wire = envelope.to_bytes().unwrap();
+        assert!(!wire.windows(32).any(|w| w == alice_key));
+        let json = envelope.to_json().unwrap();
+        assert!(!json.contains(&hex::encode(alice_key)));
+
+        let received = bob
+            .decrypt_message(alice.user_id(), alice.device_id(), &envelope)
+            .await
+            .unwrap();
+        assert_eq!(received.id, message.id);
+        assert_eq!(&received.sender_id, alice.user_id());
+        assert_eq!(received.content_as_string().as_deref(), Some("who sent this?"));
     }
 }
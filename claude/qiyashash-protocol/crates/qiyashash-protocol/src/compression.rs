@@ -0,0 +1,258 @@
+//! Compression for backups and attachments
+//!
+//! The sender picks a [`Compression`] (see [`ClientConfig::compression`])
+//! and records it, with the decompressed size, next to the data. Readers use
+//! the recorded algorithm rather than their own default, and refuse to
+//! produce more than the recorded size or [`MAX_DECOMPRESSED_SIZE`],
+//! whichever is smaller, so a small input cannot expand without bound.
+//!
+//! [`ClientConfig::compression`]: crate::config::ClientConfig::compression
+
+use std::io::{self, Write};
+
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ProtocolError, Result};
+
+/// Largest decompressed size a reader accepts (1 GiB)
+pub const MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+/// Compression algorithm
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    /// Stored as is
+    None,
+    /// Zstandard: fast with a good ratio
+    #[default]
+    Zstd,
+    /// DEFLATE: slower, readable by older clients
+    Deflate,
+}
+
+impl Compression {
+    /// Tag recorded in headers
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Deflate => 2,
+        }
+    }
+
+    /// Parse a header tag
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Deflate),
+            other => Err(ProtocolError::Decompression(format!(
+                "unknown compression {}",
+                other
+            ))),
+        }
+    }
+
+    /// Compress `data` in one go
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::stream::encode_all(data, 0),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+        };
+        compressed.map_err(|e| ProtocolError::Internal(format!("compression failed: {}", e)))
+    }
+
+    /// Decompress `data` that should expand to exactly `decompressed_size` bytes
+    pub fn decompress(self, data: &[u8], decompressed_size: u64) -> Result<Vec<u8>> {
+        let mut decompressor = Decompressor::new(self, decompressed_size)?;
+        let mut output = decompressor.update(data)?;
+        output.extend(decompressor.finish()?);
+        Ok(output)
+    }
+}
+
+/// Incremental decompression with a size cap
+///
+/// Feed input in order with [`update`](Self::update), then call
+/// [`finish`](Self::finish). Both return whatever output became available.
+pub struct Decompressor {
+    inner: Inner,
+}
+
+enum Inner {
+    None(CappedOutput),
+    Zstd(zstd::stream::write::Decoder<'static, CappedOutput>),
+    Deflate(DeflateDecoder<CappedOutput>),
+}
+
+impl Decompressor {
+    /// Start decompressing data declared to expand to `decompressed_size` bytes
+    ///
+    /// Fails up front if the declared size is over [`MAX_DECOMPRESSED_SIZE`].
+    pub fn new(compression: Compression, decompressed_size: u64) -> Result<Self> {
+        if decompressed_size > MAX_DECOMPRESSED_SIZE {
+            return Err(ProtocolError::Decompression(format!(
+                "declared size of {} bytes exceeds the {} byte limit",
+                decompressed_size, MAX_DECOMPRESSED_SIZE
+            )));
+        }
+
+        let output = CappedOutput {
+            buffer: Vec::new(),
+            written: 0,
+            limit: decompressed_size,
+        };
+        let inner = match compression {
+            Compression::None => Inner::None(output),
+            Compression::Zstd => {
+                Inner::Zstd(zstd::stream::write::Decoder::new(output).map_err(decompression_error)?)
+            }
+            Compression::Deflate => Inner::Deflate(DeflateDecoder::new(output)),
+        };
+        Ok(Self { inner })
+    }
+
+    /// Feed the next piece of input
+    pub fn update(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        let output = match &mut self.inner {
+            Inner::None(output) => {
+                output.write_all(input).map_err(decompression_error)?;
+                output
+            }
+            Inner::Zstd(decoder) => {
+                decoder
+                    .write_all(input)
+                    .and_then(|_| decoder.flush())
+                    .map_err(decompression_error)?;
+                decoder.get_mut()
+            }
+            Inner::Deflate(decoder) => {
+                decoder
+                    .write_all(input)
+                    .and_then(|_| decoder.flush())
+                    .map_err(decompression_error)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(&mut output.buffer))
+    }
+
+    /// End of input; fails unless exactly the declared size was produced
+    pub fn finish(self) -> Result<Vec<u8>> {
+        let output = match self.inner {
+            Inner::None(output) => output,
+            Inner::Zstd(mut decoder) => {
+                decoder.flush().map_err(decompression_error)?;
+                decoder.into_inner()
+            }
+            Inner::Deflate(decoder) => decoder.finish().map_err(decompression_error)?,
+        };
+
+        if output.written != output.limit {
+            return Err(ProtocolError::Decompression(format!(
+                "expected {} bytes, got {}",
+                output.limit, output.written
+            )));
+        }
+        Ok(output.buffer)
+    }
+}
+
+/// Collects decompressed output, failing once it passes `limit` bytes
+struct CappedOutput {
+    buffer: Vec<u8>,
+    written: u64,
+    limit: u64,
+}
+
+impl Write for CappedOutput {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.written + data.len() as u64 > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("output exceeds the declared {} bytes", self.limit),
+            ));
+        }
+        self.written += data.len() as u64;
+        self.buffer.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn decompression_error(e: io::Error) -> ProtocolError {
+    ProtocolError::Decompression(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Compression; 3] = [Compression::None, Compression::Zstd, Compression::Deflate];
+
+    fn payload() -> Vec<u8> {
+        b"qiyashash attachment ".repeat(4096)
+    }
+
+    #[test]
+    fn test_round_trip_each_algorithm() {
+        let data = payload();
+        for compression in ALL {
+            assert_eq!(
+                Compression::from_byte(compression.to_byte()).unwrap(),
+                compression
+            );
+
+            let compressed = compression.compress(&data).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 10, "{:?}", compression);
+            }
+            let size = data.len() as u64;
+            assert_eq!(compression.decompress(&compressed, size).unwrap(), data);
+
+            // Fed in small pieces, as attachment chunks are
+            let mut decompressor = Decompressor::new(compression, size).unwrap();
+            let mut streamed = Vec::new();
+            for piece in compressed.chunks(7) {
+                streamed.extend(decompressor.update(piece).unwrap());
+            }
+            streamed.extend(decompressor.finish().unwrap());
+            assert_eq!(streamed, data, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn test_rejects_implausible_declared_size() {
+        let compressed = Compression::Zstd.compress(b"tiny").unwrap();
+        assert!(matches!(
+            Compression::Zstd.decompress(&compressed, MAX_DECOMPRESSED_SIZE + 1),
+            Err(ProtocolError::Decompression(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_output_beyond_declared_size() {
+        let data = payload();
+        for compression in ALL {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(matches!(
+                compression.decompress(&compressed, 1024),
+                Err(ProtocolError::Decompression(_))
+            ));
+            // Truncated input falls short of the declared size
+            let truncated = &compressed[..compressed.len() / 2];
+            assert!(matches!(
+                compression.decompress(truncated, data.len() as u64),
+                Err(ProtocolError::Decompression(_))
+            ));
+        }
+        assert!(Compression::from_byte(9).is_err());
+    }
+}
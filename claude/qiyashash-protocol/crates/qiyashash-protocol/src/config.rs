@@ -1,8 +1,13 @@
 //! Protocol configuration
 
+use qiyashash_crypto::aead::AeadAlgorithm;
+use qiyashash_crypto::MAX_CHAIN_LENGTH;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+use crate::compression::Compression;
+use crate::error::{ProtocolError, Result as ProtocolResult};
+
 /// Client configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientConfig {
@@ -16,8 +21,40 @@ pub struct ClientConfig {
     pub session_stale_timeout_secs: u64,
     /// Session rekey interval (seconds)
     pub session_rekey_interval_secs: u64,
+    /// Fraction of the maximum chain length after which a sending chain is re-keyed
+    #[serde(default = "default_rekey_threshold_ratio")]
+    pub rekey_threshold_ratio: f64,
     /// Maximum message size
     pub max_message_size: usize,
+    /// Oldest signed pre-key accepted in a fetched bundle (seconds)
+    #[serde(default = "default_max_prekey_bundle_age_secs")]
+    pub max_prekey_bundle_age_secs: u64,
+    /// AEAD for outgoing messages in new sessions
+    #[serde(default)]
+    pub preferred_aead: AeadPreference,
+    /// Carry our identity key inside the ciphertext instead of the envelope
+    #[serde(default)]
+    pub sealed_sender: bool,
+    /// Reject messages from senders whose identity key changed until it is
+    /// verified again, instead of delivering them flagged as unverified
+    #[serde(default)]
+    pub strict_identity_keys: bool,
+    /// Sessions kept in memory; the least recently used are saved and
+    /// dropped beyond this, and reloaded on their next use
+    #[serde(default = "default_max_active_sessions")]
+    pub max_active_sessions: usize,
+    /// Compression for backups and attachments this client writes; readers
+    /// use whatever the data records
+    #[serde(default)]
+    pub compression: Compression,
+    /// Recognise an envelope delivered again, e.g. by a second relay, and
+    /// return the message already received instead of decrypting it twice
+    #[serde(default)]
+    pub deduplicate_deliveries: bool,
+    /// Hold messages on a new session until both sides have confirmed the
+    /// shared secret with a [`KeyConfirmation`](crate::KeyConfirmation) round
+    #[serde(default)]
+    pub require_key_confirmation: bool,
     /// Enable disappearing messages by default
     pub default_disappearing_messages: bool,
     /// Default disappearing message duration (seconds)
@@ -38,7 +75,16 @@ impl Default for ClientConfig {
             prekey_refresh_threshold: 20,
             session_stale_timeout_secs: 30 * 24 * 3600, // 30 days
             session_rekey_interval_secs: 7 * 24 * 3600, // 7 days
+            rekey_threshold_ratio: default_rekey_threshold_ratio(),
             max_message_size: 65536,
+            max_prekey_bundle_age_secs: default_max_prekey_bundle_age_secs(),
+            preferred_aead: AeadPreference::default(),
+            sealed_sender: false,
+            strict_identity_keys: false,
+            max_active_sessions: default_max_active_sessions(),
+            compression: Compression::default(),
+            deduplicate_deliveries: false,
+            require_key_confirmation: false,
             default_disappearing_messages: false,
             default_disappearing_duration_secs: 24 * 3600, // 24 hours
             retry: RetryConfig::default(),
@@ -49,6 +95,11 @@ impl Default for ClientConfig {
 }
 
 impl ClientConfig {
+    /// Start from the defaults and change only what is needed
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::new()
+    }
+
     /// Create with device name
     pub fn with_device_name(name: impl Into<String>) -> Self {
         Self {
@@ -68,8 +119,204 @@ impl ClientConfig {
         if self.max_message_size == 0 {
             return Err("max_message_size must be greater than 0".to_string());
         }
+        if !(self.rekey_threshold_ratio > 0.0 && self.rekey_threshold_ratio <= 1.0) {
+            return Err("rekey_threshold_ratio must be in (0, 1]".to_string());
+        }
+        if self.max_active_sessions == 0 {
+            return Err("max_active_sessions must be greater than 0".to_string());
+        }
         Ok(())
     }
+
+    /// Sending chain length at which a session re-keys
+    pub fn rekey_threshold(&self) -> u32 {
+        let threshold = (MAX_CHAIN_LENGTH as f64 * self.rekey_threshold_ratio) as u32;
+        threshold.clamp(1, MAX_CHAIN_LENGTH)
+    }
+}
+
+/// Builder for [`ClientConfig`]
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+    /// Whether the refresh threshold was set, rather than following
+    /// the prekey count
+    refresh_threshold_set: bool,
+}
+
+impl ClientConfigBuilder {
+    /// Create builder with the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set device name
+    pub fn device_name(mut self, name: impl Into<String>) -> Self {
+        self.config.device_name = name.into();
+        self
+    }
+
+    /// Set number of one-time prekeys to maintain
+    ///
+    /// Unless set explicitly, the refresh threshold keeps its default share
+    /// of the count.
+    pub fn prekey_count(mut self, count: usize) -> Self {
+        self.config.prekey_count = count;
+        if !self.refresh_threshold_set {
+            let defaults = ClientConfig::default();
+            self.config.prekey_refresh_threshold =
+                count * defaults.prekey_refresh_threshold / defaults.prekey_count;
+        }
+        self
+    }
+
+    /// Set one-time prekey count below which more are generated
+    pub fn prekey_refresh_threshold(mut self, threshold: usize) -> Self {
+        self.config.prekey_refresh_threshold = threshold;
+        self.refresh_threshold_set = true;
+        self
+    }
+
+    /// Set inactivity after which a session is stale (seconds)
+    pub fn session_stale_timeout_secs(mut self, secs: u64) -> Self {
+        self.config.session_stale_timeout_secs = secs;
+        self
+    }
+
+    /// Set interval after which a session re-keys (seconds)
+    pub fn session_rekey_interval_secs(mut self, secs: u64) -> Self {
+        self.config.session_rekey_interval_secs = secs;
+        self
+    }
+
+    /// Set oldest signed prekey accepted in a fetched bundle (seconds)
+    pub fn max_prekey_bundle_age_secs(mut self, secs: u64) -> Self {
+        self.config.max_prekey_bundle_age_secs = secs;
+        self
+    }
+
+    /// Set whether new conversations default to disappearing messages, and
+    /// after how long (seconds)
+    pub fn disappearing_messages(mut self, enabled: bool, duration_secs: u64) -> Self {
+        self.config.default_disappearing_messages = enabled;
+        self.config.default_disappearing_duration_secs = duration_secs;
+        self
+    }
+
+    /// Set retry configuration
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    /// Set maximum message size
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Set sessions kept in memory
+    pub fn max_active_sessions(mut self, count: usize) -> Self {
+        self.config.max_active_sessions = count;
+        self
+    }
+
+    /// Set AEAD for new sessions
+    pub fn preferred_aead(mut self, aead: AeadPreference) -> Self {
+        self.config.preferred_aead = aead;
+        self
+    }
+
+    /// Set fraction of the maximum chain length at which chains re-key
+    pub fn rekey_threshold_ratio(mut self, ratio: f64) -> Self {
+        self.config.rekey_threshold_ratio = ratio;
+        self
+    }
+
+    /// Set compression for backups and attachments
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    /// Set whether repeated deliveries of an envelope are recognised
+    pub fn deduplicate_deliveries(mut self, enabled: bool) -> Self {
+        self.config.deduplicate_deliveries = enabled;
+        self
+    }
+
+    /// Set whether new sessions wait for key confirmation
+    pub fn require_key_confirmation(mut self, required: bool) -> Self {
+        self.config.require_key_confirmation = required;
+        self
+    }
+
+    /// Set sealed-sender mode
+    pub fn sealed_sender(mut self, enabled: bool) -> Self {
+        self.config.sealed_sender = enabled;
+        self
+    }
+
+    /// Set whether changed identity keys block messages until re-verified
+    pub fn strict_identity_keys(mut self, enabled: bool) -> Self {
+        self.config.strict_identity_keys = enabled;
+        self
+    }
+
+    /// Set privacy configuration
+    pub fn privacy(mut self, privacy: PrivacyConfig) -> Self {
+        self.config.privacy = privacy;
+        self
+    }
+
+    /// Set network configuration
+    pub fn network(mut self, network: NetworkConfig) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// Validate and build the configuration
+    pub fn build(self) -> ProtocolResult<ClientConfig> {
+        self.config.validate().map_err(ProtocolError::Configuration)?;
+        Ok(self.config)
+    }
+}
+
+fn default_rekey_threshold_ratio() -> f64 {
+    0.9
+}
+
+fn default_max_active_sessions() -> usize {
+    1024
+}
+
+fn default_max_prekey_bundle_age_secs() -> u64 {
+    // Twice the signed pre-key rotation interval, so a peer that is late
+    // rotating stays reachable
+    60 * 24 * 3600
+}
+
+/// Which AEAD new sessions encrypt with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AeadPreference {
+    /// Benchmark once and use whichever is faster here
+    #[default]
+    Auto,
+    /// Always XChaCha20-Poly1305
+    XChaCha20Poly1305,
+    /// Always AES-256-GCM
+    Aes256Gcm,
+}
+
+impl AeadPreference {
+    /// Concrete algorithm to use
+    pub fn resolve(self) -> AeadAlgorithm {
+        match self {
+            AeadPreference::Auto => AeadAlgorithm::detect_fastest(),
+            AeadPreference::XChaCha20Poly1305 => AeadAlgorithm::XChaCha20Poly1305,
+            AeadPreference::Aes256Gcm => AeadAlgorithm::Aes256Gcm,
+        }
+    }
 }
 
 /// Retry configuration
@@ -190,6 +437,77 @@ mod tests {
         assert!(config.validate().is_ok());
     }
 
+    #[test]
+    fn test_builder() {
+        let config = ClientConfig::builder()
+            .device_name("Laptop")
+            .max_active_sessions(16)
+            .preferred_aead(AeadPreference::Aes256Gcm)
+            .rekey_threshold_ratio(0.5)
+            .compression(Compression::Deflate)
+            .sealed_sender(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.device_name, "Laptop");
+        assert_eq!(config.max_active_sessions, 16);
+        assert_eq!(config.preferred_aead, AeadPreference::Aes256Gcm);
+        assert_eq!(config.rekey_threshold(), MAX_CHAIN_LENGTH / 2);
+        assert_eq!(config.compression, Compression::Deflate);
+        assert!(config.sealed_sender);
+        assert_eq!(config.prekey_count, ClientConfig::default().prekey_count);
+    }
+
+    #[test]
+    fn test_builder_sets_every_field() {
+        let retry = RetryConfig { max_retries: 2, ..RetryConfig::default() };
+        let config = ClientConfig::builder()
+            .prekey_count(15)
+            .session_stale_timeout_secs(60)
+            .session_rekey_interval_secs(30)
+            .max_prekey_bundle_age_secs(3600)
+            .disappearing_messages(true, 300)
+            .retry(retry)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.prekey_count, 15);
+        assert_eq!(config.prekey_refresh_threshold, 3);
+        assert_eq!(config.session_stale_timeout_secs, 60);
+        assert_eq!(config.session_rekey_interval_secs, 30);
+        assert_eq!(config.max_prekey_bundle_age_secs, 3600);
+        assert!(config.default_disappearing_messages);
+        assert_eq!(config.default_disappearing_duration_secs, 300);
+        assert_eq!(config.retry.max_retries, 2);
+
+        // An explicit threshold is kept, in either order
+        let config = ClientConfig::builder()
+            .prekey_refresh_threshold(10)
+            .prekey_count(15)
+            .build()
+            .unwrap();
+        assert_eq!(config.prekey_refresh_threshold, 10);
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_values() {
+        for builder in [
+            ClientConfig::builder().rekey_threshold_ratio(1.5),
+            ClientConfig::builder().rekey_threshold_ratio(0.0),
+            ClientConfig::builder().max_active_sessions(0),
+            ClientConfig::builder().max_message_size(0),
+            ClientConfig::builder().prekey_count(50).prekey_refresh_threshold(50),
+        ] {
+            assert!(matches!(builder.build(), Err(ProtocolError::Configuration(_))));
+        }
+    }
+
+    #[test]
+    fn test_aead_preference_resolves() {
+        assert_eq!(AeadPreference::Aes256Gcm.resolve(), AeadAlgorithm::Aes256Gcm);
+        assert_eq!(AeadPreference::Auto.resolve(), AeadAlgorithm::detect_fastest());
+    }
+
     #[test]
     fn test_retry_delay() {
         let config = RetryConfig::default();
@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+use qiyashash_core::message::DeliveryFailure;
+
 /// Result type alias
 pub type Result<T> = std::result::Result<T, ProtocolError>;
 
@@ -36,6 +38,14 @@ pub enum ProtocolError {
     #[error("Invalid message format: {0}")]
     InvalidMessage(String),
 
+    /// Authentic plaintext that does not parse; it has been quarantined
+    #[error("Malformed plaintext (quarantined as {0})")]
+    MalformedPlaintext(String),
+
+    /// Message content over the size limit
+    #[error("Message of {size} bytes exceeds the {max} byte limit; send it as an attachment")]
+    MessageTooLarge { size: usize, max: usize },
+
     /// Decryption failed
     #[error("Decryption failed: {0}")]
     DecryptionFailed(String),
@@ -44,6 +54,18 @@ pub enum ProtocolError {
     #[error("Identity mismatch: expected {expected}, got {actual}")]
     IdentityMismatch { expected: String, actual: String },
 
+    /// The peer did not derive the same shared secret
+    #[error("Key confirmation failed for session {0}")]
+    KeyConfirmationFailed(String),
+
+    /// Control message that could not be authenticated
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// An X3DH initial message whose session nonce was already used
+    #[error("Replayed session initiation from {0}")]
+    ReplayedInitiation(String),
+
     /// Untrusted identity
     #[error("Untrusted identity for user {0}")]
     UntrustedIdentity(String),
@@ -52,14 +74,35 @@ pub enum ProtocolError {
     #[error("Protocol version mismatch: expected {expected}, got {actual}")]
     VersionMismatch { expected: u32, actual: u32 },
 
+    /// No protocol version both sides support
+    #[error("No common protocol version: ours {ours}, theirs {theirs}")]
+    IncompatibleVersions {
+        /// Versions this build supports
+        ours: crate::protocol::VersionRange,
+        /// Versions the peer advertised, or used
+        theirs: crate::protocol::VersionRange,
+    },
+
     /// Chain verification failed
     #[error("Chain verification failed: {0}")]
     ChainVerificationFailed(String),
 
+    /// The transport could not hand a message to the network
+    #[error("Delivery failed: {0}")]
+    Delivery(DeliveryFailure),
+
     /// Storage error
     #[error("Storage error: {0}")]
     Storage(String),
 
+    /// Backup error
+    #[error("Backup error: {0}")]
+    Backup(String),
+
+    /// Compressed data that is corrupt or decompresses past its limit
+    #[error("Decompression failed: {0}")]
+    Decompression(String),
+
     /// Configuration error
     #[error("Configuration error: {0}")]
     Configuration(String),
@@ -76,3 +119,18 @@ pub enum ProtocolError {
     #[error("Internal error: {0}")]
     Internal(String),
 }
+
+impl ProtocolError {
+    /// How this error should be reported on the message that failed
+    pub fn delivery_failure(&self) -> DeliveryFailure {
+        match self {
+            Self::SessionNotFound(_) | Self::SessionNotEstablished(_) => DeliveryFailure::NoSession,
+            Self::Delivery(reason) => *reason,
+            Self::MessageTooLarge { .. }
+            | Self::Crypto(qiyashash_crypto::CryptoError::MessageTooLarge { .. }) => {
+                DeliveryFailure::MessageTooLarge
+            }
+            _ => DeliveryFailure::Rejected,
+        }
+    }
+}
@@ -168,7 +168,12 @@ impl SessionResetHandler {
         on_reset(&request.target_user_id, &request.target_device_id, request.reason);
     }
 
-    /// Create session reset request
+    /// Create an unauthenticated session reset request
+    ///
+    /// Peers reject it until it carries a MAC; use
+    /// [`SessionManager::session_reset`] to build one they accept.
+    ///
+    /// [`SessionManager::session_reset`]: crate::SessionManager::session_reset
     pub fn create_reset(
         target_user_id: UserId,
         target_device_id: DeviceId,
@@ -178,6 +183,7 @@ impl SessionResetHandler {
             target_user_id,
             target_device_id,
             reason,
+            mac: [0; 32],
         }
     }
 }
@@ -0,0 +1,27 @@
+//! Identity service integration
+//!
+//! The identity service publishes prekey bundles. [`IdentityServiceClient`]
+//! is the client side of that lookup, used by
+//! [`ProtocolClient::establish_session_from_server`].
+//!
+//! [`ProtocolClient::establish_session_from_server`]: crate::ProtocolClient::establish_session_from_server
+
+use async_trait::async_trait;
+
+use qiyashash_core::types::{DeviceId, UserId};
+
+use crate::error::Result;
+use crate::protocol::DevicePreKeyBundle;
+
+/// Fetches prekey bundles from the identity service
+#[async_trait]
+pub trait IdentityServiceClient: Send + Sync {
+    /// Fetch the current prekey bundle of `device_id` belonging to `user_id`
+    ///
+    /// The bundle is returned as published; callers verify it. Report a
+    /// device without a bundle as [`ProtocolError::InvalidPreKeyBundle`];
+    /// other errors are treated as the service being briefly unavailable.
+    ///
+    /// [`ProtocolError::InvalidPreKeyBundle`]: crate::ProtocolError::InvalidPreKeyBundle
+    async fn fetch_bundle(&self, user_id: &UserId, device_id: &DeviceId) -> Result<DevicePreKeyBundle>;
+}
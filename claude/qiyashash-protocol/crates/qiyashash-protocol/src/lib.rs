@@ -31,22 +31,40 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs, rust_2018_idioms)]
 
+pub mod backup;
 pub mod client;
+pub mod compression;
 pub mod config;
 pub mod error;
 pub mod handlers;
+pub mod identity_service;
+pub mod metrics;
+pub mod parse;
 pub mod protocol;
+pub mod rotation;
 pub mod session_manager;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_support;
+pub mod transport;
 
-pub use client::ProtocolClient;
-pub use config::ClientConfig;
+pub use client::{BatchOutcome, ClientEvent, FlushReport, Notification, ProtocolClient};
+pub use compression::Compression;
+pub use config::{AeadPreference, ClientConfig, ClientConfigBuilder};
 pub use error::{ProtocolError, Result};
-pub use protocol::{ProtocolMessage, ProtocolMessageType};
-pub use session_manager::SessionManager;
+pub use identity_service::IdentityServiceClient;
+pub use metrics::{Counter, Histogram, ProtocolMetrics};
+pub use parse::{parse_envelope, ParseLimits};
+pub use protocol::{KeyConfirmation, ProtocolMessage, ProtocolMessageType, VersionRange};
+pub use rotation::{KeyRotationPolicy, RotationReport};
+pub use session_manager::{SessionInfo, SessionManager};
+pub use transport::MessageTransport;
 
 /// Protocol version
 pub const PROTOCOL_VERSION: u32 = 1;
 
+/// Oldest protocol version this client still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
 /// Prelude for convenient imports
 pub mod prelude {
     pub use crate::client::ProtocolClient;
@@ -0,0 +1,98 @@
+//! Protocol metrics
+//!
+//! [`ProtocolClient`](crate::ProtocolClient) reports what it does through a
+//! [`ProtocolMetrics`] so an embedding app can forward counts to whatever
+//! telemetry it uses. Nothing identifying is reported: no user IDs, keys or
+//! message content, only counts and sizes.
+
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+
+/// Events counted by the client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Counter {
+    /// Messages encrypted for sending
+    MessagesEncrypted,
+    /// Messages decrypted
+    MessagesDecrypted,
+    /// Received messages the ratchet could not decrypt
+    DecryptionFailures,
+    /// Sessions established from a prekey bundle
+    SessionsEstablished,
+    /// DH ratchet steps taken on receiving a new ratchet key
+    RatchetSteps,
+}
+
+/// Measurements recorded by the client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Histogram {
+    /// Plaintext size of each encrypted or decrypted message, in bytes
+    PlaintextBytes,
+    /// Ciphertext size of each encrypted or decrypted message, in bytes
+    CiphertextBytes,
+}
+
+/// Receives the client's metrics
+///
+/// Called on the messaging path, so implementations should only record and
+/// return.
+pub trait ProtocolMetrics: Send + Sync {
+    /// Add one to `counter`
+    fn increment(&self, counter: Counter);
+
+    /// Record one measurement
+    fn observe(&self, histogram: Histogram, value: f64);
+}
+
+/// Discards everything; the client's default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl ProtocolMetrics for NoopMetrics {
+    fn increment(&self, _counter: Counter) {}
+
+    fn observe(&self, _histogram: Histogram, _value: f64) {}
+}
+
+/// Keeps metrics in memory, for tests and diagnostics
+#[derive(Debug, Default)]
+pub struct MemoryMetrics {
+    counters: Mutex<HashMap<Counter, u64>>,
+    observations: Mutex<HashMap<Histogram, Vec<f64>>>,
+}
+
+impl MemoryMetrics {
+    /// Create an empty recorder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current value of `counter`
+    pub fn count(&self, counter: Counter) -> u64 {
+        self.counters.lock().get(&counter).copied().unwrap_or(0)
+    }
+
+    /// Measurements recorded for `histogram`, oldest first
+    pub fn observations(&self, histogram: Histogram) -> Vec<f64> {
+        self.observations
+            .lock()
+            .get(&histogram)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl ProtocolMetrics for MemoryMetrics {
+    fn increment(&self, counter: Counter) {
+        *self.counters.lock().entry(counter).or_insert(0) += 1;
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        self.observations
+            .lock()
+            .entry(histogram)
+            .or_default()
+            .push(value);
+    }
+}
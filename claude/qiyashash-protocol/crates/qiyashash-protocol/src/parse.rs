@@ -0,0 +1,206 @@
+//! Hardened parsing of untrusted envelopes
+//!
+//! [`parse_envelope`] is the one entry point for envelope bytes received from
+//! the network. The input length is bounded before anything is decoded, so
+//! no length prefix inside the frame can make the decoder allocate more than
+//! the caller allowed, and the decoded header fields are range checked before
+//! they reach the ratchet.
+
+use qiyashash_core::message::MessageEnvelope;
+use qiyashash_crypto::wire::WireFrame;
+use qiyashash_crypto::{MAX_CHAIN_LENGTH, MAX_MESSAGE_SIZE};
+
+use crate::error::{ProtocolError, Result};
+
+/// Room for the ratchet header, nonce and tag around the encrypted payload
+const RATCHET_OVERHEAD: usize = 1024;
+
+/// Fixed-size envelope fields, hex encoded, plus the frame header
+const ENVELOPE_OVERHEAD: usize = 1024;
+
+/// Bounds applied by [`parse_envelope`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Largest accepted ciphertext
+    pub max_ciphertext_len: usize,
+    /// Largest accepted `message_number`
+    pub max_message_number: u32,
+    /// Largest accepted `previous_chain_length`
+    pub max_previous_chain_length: u32,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_ciphertext_len: MAX_MESSAGE_SIZE + RATCHET_OVERHEAD,
+            // A sending chain never gets past MAX_CHAIN_LENGTH messages
+            max_message_number: MAX_CHAIN_LENGTH - 1,
+            max_previous_chain_length: MAX_CHAIN_LENGTH,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// Largest frame that can carry an envelope within these limits
+    ///
+    /// The ciphertext travels base64 encoded.
+    pub fn max_frame_len(&self) -> usize {
+        self.max_ciphertext_len
+            .div_ceil(3)
+            .saturating_mul(4)
+            .saturating_add(ENVELOPE_OVERHEAD)
+    }
+}
+
+/// Parse an envelope received from the network
+///
+/// Every failure is reported as [`ProtocolError::InvalidMessage`].
+pub fn parse_envelope(bytes: &[u8], limits: ParseLimits) -> Result<MessageEnvelope> {
+    if bytes.len() > limits.max_frame_len() {
+        return Err(ProtocolError::InvalidMessage(format!(
+            "envelope of {} bytes exceeds {}",
+            bytes.len(),
+            limits.max_frame_len()
+        )));
+    }
+
+    let envelope: MessageEnvelope =
+        WireFrame::decode(bytes).map_err(|e| ProtocolError::InvalidMessage(e.to_string()))?;
+
+    if envelope.ciphertext.is_empty() {
+        return Err(ProtocolError::InvalidMessage("empty ciphertext".to_string()));
+    }
+    if envelope.ciphertext.len() > limits.max_ciphertext_len {
+        return Err(ProtocolError::InvalidMessage(format!(
+            "ciphertext of {} bytes exceeds {}",
+            envelope.ciphertext.len(),
+            limits.max_ciphertext_len
+        )));
+    }
+
+    let header = &envelope.ratchet_header;
+    if header.message_number > limits.max_message_number {
+        return Err(ProtocolError::InvalidMessage(format!(
+            "message number {} exceeds {}",
+            header.message_number, limits.max_message_number
+        )));
+    }
+    if header.previous_chain_length > limits.max_previous_chain_length {
+        return Err(ProtocolError::InvalidMessage(format!(
+            "previous chain length {} exceeds {}",
+            header.previous_chain_length, limits.max_previous_chain_length
+        )));
+    }
+
+    Ok(envelope)
+}
+
+/// Fuzz target: arbitrary input must never panic, and anything accepted
+/// must satisfy the limits and survive a round trip
+#[cfg(fuzz)]
+pub fn fuzz_parse_envelope(data: &[u8]) {
+    let limits = ParseLimits::default();
+    if let Ok(envelope) = parse_envelope(data, limits) {
+        assert!(envelope.ciphertext.len() <= limits.max_ciphertext_len);
+        assert!(envelope.ratchet_header.message_number <= limits.max_message_number);
+
+        let bytes = envelope.to_bytes().expect("accepted envelope re-encodes");
+        assert!(parse_envelope(&bytes, limits).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qiyashash_core::message::RatchetHeaderWire;
+    use qiyashash_crypto::wire::WIRE_FORMAT_VERSION;
+
+    fn envelope(ciphertext_len: usize, message_number: u32) -> MessageEnvelope {
+        MessageEnvelope {
+            version: crate::PROTOCOL_VERSION,
+            sender_identity_key: [0x11; 32],
+            ephemeral_key: None,
+            one_time_prekey_id: None,
+            ratchet_header: RatchetHeaderWire {
+                dh_public: [0x22; 32],
+                message_number,
+                previous_chain_length: 0,
+            },
+            ciphertext: vec![0x33; ciphertext_len],
+            chain_proof: [0x44; 32],
+            timestamp_hash: [0x55; 32],
+            session_nonce: None,
+            signed_prekey_id: None,
+        }
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![WIRE_FORMAT_VERSION];
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn assert_invalid(result: Result<MessageEnvelope>) {
+        assert!(matches!(result, Err(ProtocolError::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn test_valid_envelope_parses() {
+        let limits = ParseLimits::default();
+        let bytes = envelope(limits.max_ciphertext_len, 7).to_bytes().unwrap();
+
+        let parsed = parse_envelope(&bytes, limits).unwrap();
+        assert_eq!(parsed.ciphertext.len(), limits.max_ciphertext_len);
+        assert_eq!(parsed.ratchet_header.message_number, 7);
+    }
+
+    #[test]
+    fn test_limits_enforced() {
+        let limits = ParseLimits {
+            max_ciphertext_len: 64,
+            max_message_number: 10,
+            max_previous_chain_length: 10,
+        };
+
+        let bytes = envelope(65, 0).to_bytes().unwrap();
+        assert_invalid(parse_envelope(&bytes, limits));
+
+        let bytes = envelope(64, 11).to_bytes().unwrap();
+        assert_invalid(parse_envelope(&bytes, limits));
+
+        let mut long_chain = envelope(64, 0);
+        long_chain.ratchet_header.previous_chain_length = u32::MAX;
+        assert_invalid(parse_envelope(&long_chain.to_bytes().unwrap(), limits));
+
+        let bytes = envelope(0, 0).to_bytes().unwrap();
+        assert_invalid(parse_envelope(&bytes, limits));
+
+        // Oversized input is rejected before decoding
+        let huge = vec![0u8; limits.max_frame_len() + 1];
+        assert_invalid(parse_envelope(&huge, limits));
+    }
+
+    #[test]
+    fn test_oversized_length_prefixes_rejected() {
+        // Every length prefix in a valid envelope, overwritten with a huge
+        // value, must be refused without trying to allocate it
+        let valid = envelope(32, 0).to_bytes().unwrap();
+        let payload = &valid[5..];
+        for offset in 0..payload.len().saturating_sub(8) {
+            let mut tampered = payload.to_vec();
+            tampered[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+            assert_invalid(parse_envelope(&frame(&tampered), ParseLimits::default()));
+        }
+
+        // A frame header claiming more than it carries
+        let mut lying = valid.clone();
+        lying[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_invalid(parse_envelope(&lying, ParseLimits::default()));
+
+        // Truncation anywhere
+        for len in 0..valid.len() {
+            assert_invalid(parse_envelope(&valid[..len], ParseLimits::default()));
+        }
+    }
+}
@@ -3,9 +3,15 @@
 //! Defines all message types used in the QiyasHash protocol.
 
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 use qiyashash_core::message::{MessageEnvelope, MessageReceipt, TypingIndicator, MessageDeletion};
 use qiyashash_core::types::{DeviceId, Timestamp, UserId};
+use qiyashash_crypto::identity::IdentityPublicKey;
+use qiyashash_crypto::keys::{PublicKeyBytes, SignedPreKey};
+use qiyashash_crypto::x3dh::X3DHHeader;
+
+use crate::error::{ProtocolError, Result};
 
 /// Protocol message wrapper
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -73,6 +79,8 @@ pub enum ProtocolMessageType {
     GroupMessage(GroupMessage),
     /// Presence update
     Presence(PresenceUpdate),
+    /// Proof of the X3DH shared secret
+    KeyConfirmation(KeyConfirmation),
     /// Error response
     Error(ProtocolErrorMessage),
 }
@@ -118,6 +126,125 @@ pub struct DevicePreKeyBundle {
     /// One-time pre-key public (optional)
     #[serde(with = "option_hex")]
     pub one_time_prekey: Option<[u8; 32]>,
+    /// Protocol versions the device supports
+    #[serde(default = "VersionRange::legacy")]
+    pub versions: VersionRange,
+    /// When the signed pre-key was created; absent from older publishers
+    #[serde(default)]
+    pub signed_prekey_timestamp: Option<Timestamp>,
+}
+
+/// Allowed difference between our clock and a bundle's creation time
+const MAX_BUNDLE_CLOCK_SKEW_SECS: i64 = 300;
+
+impl DevicePreKeyBundle {
+    /// Check the signed pre-key signature and that the bundle is not stale
+    ///
+    /// The timestamp is covered by the signature, so bundles without one
+    /// are rejected.
+    pub fn verify(&self, now: Timestamp, max_age_secs: u64) -> Result<()> {
+        let identity = IdentityPublicKey::from_bytes(&self.identity_key)
+            .map_err(|e| ProtocolError::InvalidPreKeyBundle(e.to_string()))?;
+        let signed_prekey = self.signed_prekey()?;
+        signed_prekey.verify(&identity.signing_key).map_err(|_| {
+            ProtocolError::InvalidPreKeyBundle("bad signed pre-key signature".to_string())
+        })?;
+
+        let created_at = Timestamp::from_secs(signed_prekey.timestamp);
+        let age_ms = now.millis_since(created_at);
+        if age_ms > (max_age_secs as i64).saturating_mul(1000) {
+            return Err(ProtocolError::InvalidPreKeyBundle(format!(
+                "signed pre-key is {}s old",
+                age_ms / 1000
+            )));
+        }
+        if created_at.millis_since(now) > MAX_BUNDLE_CLOCK_SKEW_SECS * 1000 {
+            return Err(ProtocolError::InvalidPreKeyBundle(
+                "signed pre-key created in the future".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The signed pre-key as it was signed
+    pub fn signed_prekey(&self) -> Result<SignedPreKey> {
+        let created_at = self.signed_prekey_timestamp.ok_or_else(|| {
+            ProtocolError::InvalidPreKeyBundle("signed pre-key has no timestamp".to_string())
+        })?;
+        Ok(SignedPreKey {
+            id: self.signed_prekey_id,
+            public_key: PublicKeyBytes::from(self.signed_prekey),
+            signature: self.signed_prekey_signature,
+            timestamp: created_at.as_secs(),
+        })
+    }
+}
+
+/// First protocol version whose sessions bind it into the associated data
+pub const VERSION_BINDING_SINCE: u32 = 2;
+
+/// Inclusive range of protocol versions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionRange {
+    /// Oldest supported version
+    pub min: u32,
+    /// Newest supported version
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// Versions this build supports
+    pub fn supported() -> Self {
+        Self {
+            min: crate::MIN_PROTOCOL_VERSION,
+            max: crate::PROTOCOL_VERSION,
+        }
+    }
+
+    /// Range assumed for peers that do not advertise one
+    pub fn legacy() -> Self {
+        Self { min: 1, max: 1 }
+    }
+
+    /// Whether `version` is in the range
+    pub fn contains(&self, version: u32) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// Highest version in both ranges
+    pub fn negotiate(&self, theirs: &VersionRange) -> Result<u32> {
+        let version = self.max.min(theirs.max);
+        if version < self.min.max(theirs.min) {
+            return Err(ProtocolError::IncompatibleVersions {
+                ours: *self,
+                theirs: *theirs,
+            });
+        }
+        Ok(version)
+    }
+
+    /// Session associated data binding the negotiated version
+    ///
+    /// Includes the responder's advertised range, so stripping newer versions
+    /// from its bundle to force a downgrade makes every message fail to
+    /// decrypt instead of silently succeeding. Empty for v1 sessions, whose
+    /// peers may predate the binding.
+    pub fn binding(version: u32, responder: &VersionRange) -> Vec<u8> {
+        if version < VERSION_BINDING_SINCE {
+            return Vec::new();
+        }
+        let mut data = b"QiyasHash_Version_v1".to_vec();
+        data.extend_from_slice(&version.to_be_bytes());
+        data.extend_from_slice(&responder.min.to_be_bytes());
+        data.extend_from_slice(&responder.max.to_be_bytes());
+        data
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..={}", self.min, self.max)
+    }
 }
 
 /// Session reset request
@@ -129,6 +256,27 @@ pub struct SessionResetRequest {
     pub target_device_id: DeviceId,
     /// Reason for reset
     pub reason: SessionResetReason,
+    /// MAC over [`SessionResetRequest::authenticated_data`] under the
+    /// session's reset key; requests without one are rejected
+    #[serde(default, with = "hex::serde")]
+    pub mac: [u8; 32],
+}
+
+impl SessionResetRequest {
+    /// Bytes covered by the request MAC
+    pub fn authenticated_data(&self) -> Vec<u8> {
+        let user = self.target_user_id.as_str().as_bytes();
+        let device = self.target_device_id.as_str().as_bytes();
+
+        let mut data = Vec::with_capacity(32 + user.len() + device.len());
+        data.extend_from_slice(b"QiyasHash_SessionReset_v1");
+        data.extend_from_slice(&(user.len() as u32).to_be_bytes());
+        data.extend_from_slice(user);
+        data.extend_from_slice(&(device.len() as u32).to_be_bytes());
+        data.extend_from_slice(device);
+        data.push(self.reason as u8);
+        data
+    }
 }
 
 /// Reason for session reset
@@ -237,6 +385,49 @@ pub enum SyncType {
     Blocked,
 }
 
+/// Plaintext of a sealed-sender message
+///
+/// The sender's identity key is encrypted together with the message, so
+/// only the recipient learns it and checks it against the session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedSenderContent {
+    /// Sender's Ed25519 identity key
+    pub signing_key: [u8; 32],
+    /// Sender's X25519 identity key
+    pub dh_key: [u8; 32],
+    /// Serialized message
+    pub message: Vec<u8>,
+}
+
+impl SealedSenderContent {
+    /// Wrap `message` with the sender's identity
+    pub fn new(identity: &IdentityPublicKey, message: Vec<u8>) -> Self {
+        Self {
+            signing_key: identity.signing_key_bytes(),
+            dh_key: identity.dh_key_bytes(),
+            message,
+        }
+    }
+
+    /// Sender's identity key
+    pub fn identity(&self) -> Result<IdentityPublicKey> {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.signing_key);
+        bytes[32..].copy_from_slice(&self.dh_key);
+        Ok(IdentityPublicKey::from_full_bytes(&bytes)?)
+    }
+
+    /// Serialize for encryption
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| ProtocolError::Internal(e.to_string()))
+    }
+
+    /// Deserialize a decrypted payload
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data).map_err(|e| ProtocolError::InvalidMessage(e.to_string()))
+    }
+}
+
 /// Group message (placeholder for future)
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GroupMessage {
@@ -258,6 +449,26 @@ pub struct PresenceUpdate {
     pub status_message: Option<String>,
 }
 
+/// Key confirmation after X3DH
+///
+/// A MAC of a fixed, role-specific label under a key derived from the
+/// shared secret; see [`SessionManager::confirm_keys`].
+///
+/// [`SessionManager::confirm_keys`]: crate::SessionManager::confirm_keys
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyConfirmation {
+    /// MAC of the sender's label
+    #[serde(with = "hex::serde")]
+    pub tag: [u8; 32],
+    /// The initiator's X3DH header, so a responder that has not seen an
+    /// initial message yet can derive the session
+    #[serde(default)]
+    pub initiation: Option<X3DHHeader>,
+    /// Version negotiated for the session, with `initiation`
+    #[serde(default)]
+    pub session_version: u32,
+}
+
 /// Protocol error message
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProtocolErrorMessage {
@@ -394,6 +605,8 @@ mod tests {
             signed_prekey_signature: [0x03; 64],
             one_time_prekey_id: Some(1),
             one_time_prekey: Some([0x04; 32]),
+            versions: VersionRange::supported(),
+            signed_prekey_timestamp: Some(Timestamp::from_secs(1_700_000_000)),
         };
 
         let json = serde_json::to_string(&bundle).unwrap();
@@ -401,5 +614,65 @@ mod tests {
 
         assert_eq!(bundle.registration_id, restored.registration_id);
         assert_eq!(bundle.identity_key, restored.identity_key);
+        assert_eq!(bundle.versions, restored.versions);
+        assert_eq!(bundle.signed_prekey_timestamp, restored.signed_prekey_timestamp);
+    }
+
+    #[test]
+    fn test_bundle_freshness() {
+        let identity = qiyashash_crypto::identity::IdentityKeyPair::generate();
+        let created_at = Timestamp::from_secs(1_700_000_000);
+        let bundle = DevicePreKeyBundle {
+            device_id: DeviceId::new(),
+            registration_id: 1,
+            identity_key: identity.public_key().signing_key_bytes(),
+            signed_prekey_id: 1,
+            signed_prekey: [0x02; 32],
+            signed_prekey_signature: identity
+                .sign(&SignedPreKey::signed_data(&[0x02; 32], created_at.as_secs())),
+            one_time_prekey_id: None,
+            one_time_prekey: None,
+            versions: VersionRange::supported(),
+            signed_prekey_timestamp: Some(created_at),
+        };
+        let day = 24 * 3600;
+
+        assert!(bundle.verify(created_at.saturating_add_secs(day), 2 * day as u64).is_ok());
+        assert!(bundle.verify(created_at.saturating_add_secs(3 * day), 2 * day as u64).is_err());
+        assert!(bundle.verify(created_at.saturating_add_secs(-day), 2 * day as u64).is_err());
+
+        // The timestamp is signed, so it can be neither changed nor dropped
+        let redated = DevicePreKeyBundle {
+            signed_prekey_timestamp: Some(created_at.saturating_add_secs(day)),
+            ..bundle.clone()
+        };
+        assert!(redated.verify(created_at.saturating_add_secs(day), 2 * day as u64).is_err());
+        let undated = DevicePreKeyBundle { signed_prekey_timestamp: None, ..bundle };
+        assert!(undated.verify(created_at.saturating_add_secs(day), 2 * day as u64).is_err());
+    }
+
+    #[test]
+    fn test_version_negotiation_picks_highest_common() {
+        let ours = VersionRange { min: 1, max: 3 };
+        let theirs = VersionRange { min: 2, max: 5 };
+        assert_eq!(ours.negotiate(&theirs).unwrap(), 3);
+        assert_eq!(theirs.negotiate(&ours).unwrap(), 3);
+        assert_eq!(VersionRange::supported().negotiate(&VersionRange::legacy()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_incompatible_versions_rejected() {
+        let ours = VersionRange { min: 1, max: 1 };
+        let theirs = VersionRange { min: 2, max: 3 };
+        assert!(matches!(
+            ours.negotiate(&theirs),
+            Err(ProtocolError::IncompatibleVersions { .. })
+        ));
+        assert_ne!(
+            VersionRange::binding(2, &VersionRange { min: 1, max: 2 }),
+            VersionRange::binding(2, &VersionRange { min: 2, max: 3 })
+        );
+        // v1 peers never bound the version
+        assert!(VersionRange::binding(1, &VersionRange { min: 1, max: 2 }).is_empty());
     }
 }
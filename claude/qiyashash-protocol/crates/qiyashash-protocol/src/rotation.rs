@@ -0,0 +1,338 @@
+//! Key rotation policy
+//!
+//! Identity keys and signed prekeys are persisted together with the time
+//! they were created. A [`KeyRotationPolicy`] compares those times against
+//! configured maximum ages and checks the one-time prekey supply, so
+//! [`ProtocolClient::enforce_rotation_policy`] knows what to replace.
+//!
+//! [`ProtocolClient::enforce_rotation_policy`]: crate::ProtocolClient::enforce_rotation_policy
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use qiyashash_core::storage::{IdentityStore, PreKeyStore};
+use qiyashash_core::types::Timestamp;
+use qiyashash_crypto::identity::IdentityRotationProof;
+use qiyashash_crypto::kdf::{domain, DerivedKey, KeyDerivationContext};
+
+use crate::config::ClientConfig;
+use crate::error::{ProtocolError, Result};
+use crate::protocol::ProtocolMessage;
+
+/// Source of the current time for policy decisions
+pub type Clock = Arc<dyn Fn() -> Timestamp + Send + Sync>;
+
+/// Maximum key ages and one-time prekey supply
+#[derive(Clone)]
+pub struct KeyRotationPolicy {
+    /// Maximum identity key age (seconds)
+    pub max_identity_age_secs: i64,
+    /// Maximum signed prekey age (seconds)
+    pub max_signed_prekey_age_secs: i64,
+    /// How long a replaced signed prekey still answers initial messages
+    /// (seconds)
+    pub signed_prekey_grace_secs: i64,
+    /// Replenish when fewer one-time prekeys than this are stored
+    pub min_one_time_prekeys: usize,
+    /// Number of one-time prekeys to replenish up to
+    pub one_time_prekey_target: usize,
+    /// Current time
+    clock: Clock,
+}
+
+impl Default for KeyRotationPolicy {
+    fn default() -> Self {
+        Self::from_config(&ClientConfig::default())
+    }
+}
+
+impl KeyRotationPolicy {
+    /// Policy using the prekey counts from `config`
+    pub fn from_config(config: &ClientConfig) -> Self {
+        Self {
+            max_identity_age_secs: 365 * 24 * 3600, // 1 year
+            max_signed_prekey_age_secs: 30 * 24 * 3600, // 30 days
+            signed_prekey_grace_secs: 7 * 24 * 3600, // 7 days
+            min_one_time_prekeys: config.prekey_refresh_threshold,
+            one_time_prekey_target: config.prekey_count,
+            clock: Arc::new(Timestamp::now),
+        }
+    }
+
+    /// Use `clock` instead of the system time
+    pub fn with_clock(mut self, clock: impl Fn() -> Timestamp + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Current time according to the policy clock
+    pub fn now(&self) -> Timestamp {
+        (self.clock)()
+    }
+
+    /// Whether the stored identity key is older than allowed
+    ///
+    /// Keys saved before creation times were recorded are never due; the
+    /// client stamps them with the time it first loads them.
+    pub async fn needs_identity_rotation(&self, store: &dyn IdentityStore) -> Result<bool> {
+        let stored = store.get_identity_key().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        match stored {
+            Some(data) => {
+                let created_at = StoredIdentityKey::decode(&data)?.created_at;
+                Ok(self.is_older_than(created_at, self.max_identity_age_secs))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether signed prekey `id` is older than allowed or was never stored
+    pub async fn needs_spk_rotation(&self, store: &dyn PreKeyStore, id: u32) -> Result<bool> {
+        let stored = store.get_signed_prekey(id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        match stored {
+            Some(data) => {
+                let created_at = StoredSignedPreKey::decode(&data)?.created_at;
+                Ok(self.is_older_than(Some(created_at), self.max_signed_prekey_age_secs))
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Whether the grace period of the signed prekey that `current_id`
+    /// replaced is over
+    ///
+    /// The period starts when `current_id` was created; if it was never
+    /// stored, the period is over.
+    pub async fn previous_spk_expired(&self, store: &dyn PreKeyStore, current_id: u32) -> Result<bool> {
+        let stored = store.get_signed_prekey(current_id).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        match stored {
+            Some(data) => {
+                let created_at = StoredSignedPreKey::decode(&data)?.created_at;
+                Ok(self.is_older_than(Some(created_at), self.signed_prekey_grace_secs))
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Whether the one-time prekey supply has dropped below the minimum
+    pub async fn needs_opk_replenishment(&self, store: &dyn PreKeyStore) -> Result<bool> {
+        let count = store.get_one_time_prekey_count().await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        Ok(count < self.min_one_time_prekeys)
+    }
+
+    /// Number of one-time prekeys to generate when `available` are stored
+    pub fn replenishment_count(&self, available: usize) -> usize {
+        self.one_time_prekey_target.saturating_sub(available)
+    }
+
+    fn is_older_than(&self, created_at: Option<Timestamp>, max_age_secs: i64) -> bool {
+        match created_at {
+            Some(created_at) => {
+                self.now().millis_since(created_at) > max_age_secs.saturating_mul(1000)
+            }
+            None => false,
+        }
+    }
+}
+
+/// What [`ProtocolClient::enforce_rotation_policy`] changed
+///
+/// [`ProtocolClient::enforce_rotation_policy`]: crate::ProtocolClient::enforce_rotation_policy
+#[derive(Debug, Default)]
+pub struct RotationReport {
+    /// Proof linking the new identity to the old one, if it was rotated
+    pub identity_rotation: Option<IdentityRotationProof>,
+    /// Whether the signed prekey was replaced
+    pub signed_prekey_rotated: bool,
+    /// Replaced signed prekey whose grace period ended, now deleted
+    pub signed_prekey_retired: Option<u32>,
+    /// Number of one-time prekeys generated
+    pub one_time_prekeys_added: usize,
+    /// Messages announcing the new keys, to be published by the caller
+    pub announcements: Vec<ProtocolMessage>,
+}
+
+impl RotationReport {
+    /// Whether anything was rotated or replenished
+    pub fn is_empty(&self) -> bool {
+        self.identity_rotation.is_none()
+            && !self.signed_prekey_rotated
+            && self.signed_prekey_retired.is_none()
+            && self.one_time_prekeys_added == 0
+    }
+}
+
+/// Identity key as persisted in the [`IdentityStore`]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredIdentityKey {
+    /// Ed25519 secret key bytes
+    pub secret: [u8; 32],
+    /// When the key was created; unknown for keys saved by older versions
+    pub created_at: Option<Timestamp>,
+    /// Secret that local storage keys derive from; unlike the identity key
+    /// it survives rotation, so data sealed under it stays readable
+    pub account_secret: [u8; 32],
+}
+
+/// Record layout from before the account secret was stored
+#[derive(Deserialize)]
+struct StoredIdentityKeyV1 {
+    secret: [u8; 32],
+    created_at: Option<Timestamp>,
+}
+
+impl StoredIdentityKey {
+    /// Legacy records hold only the bincode-encoded secret
+    const LEGACY_LEN: usize = 32;
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| ProtocolError::Internal(e.to_string()))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() == Self::LEGACY_LEN {
+            let secret: [u8; 32] = bincode::deserialize(data)
+                .map_err(|e| ProtocolError::Internal(e.to_string()))?;
+            return Self::upgrade(secret, None);
+        }
+
+        if let Ok(stored) = bincode::deserialize::<Self>(data) {
+            return Ok(stored);
+        }
+        let stored: StoredIdentityKeyV1 = bincode::deserialize(data)
+            .map_err(|e| ProtocolError::Internal(e.to_string()))?;
+        Self::upgrade(stored.secret, stored.created_at)
+    }
+
+    /// Fill in the account secret of a record saved by an older version
+    ///
+    /// It is derived from the identity key, so every load of the same old
+    /// record agrees on it until the record is saved again with it.
+    fn upgrade(secret: [u8; 32], created_at: Option<Timestamp>) -> Result<Self> {
+        let account_secret: DerivedKey<32> =
+            KeyDerivationContext::new(None, &secret).derive(domain::ACCOUNT_SECRET)?;
+        Ok(Self {
+            secret,
+            created_at,
+            account_secret: account_secret.into_bytes(),
+        })
+    }
+}
+
+/// Public half of a signed prekey as persisted in the [`PreKeyStore`]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredSignedPreKey {
+    /// X25519 public key
+    pub public_key: [u8; 32],
+    /// When the key was created
+    pub created_at: Timestamp,
+}
+
+impl StoredSignedPreKey {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| ProtocolError::Internal(e.to_string()))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data).map_err(|e| ProtocolError::Internal(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qiyashash_core::storage::memory::MemoryStorage;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    const DAY: i64 = 24 * 3600;
+
+    #[tokio::test]
+    async fn test_aged_keys_flagged() {
+        let storage = MemoryStorage::new();
+        let start = Timestamp::from_secs(1_700_000_000);
+        let now = Arc::new(AtomicI64::new(start.as_millis()));
+        let clock = now.clone();
+        let policy = KeyRotationPolicy::default()
+            .with_clock(move || Timestamp::from_millis(clock.load(Ordering::SeqCst)));
+
+        // Nothing stored yet: no identity to rotate, but no prekeys either
+        assert!(!policy.needs_identity_rotation(storage.as_ref()).await.unwrap());
+        assert!(policy.needs_spk_rotation(storage.as_ref(), 1).await.unwrap());
+        assert!(policy.needs_opk_replenishment(storage.as_ref()).await.unwrap());
+
+        let identity = StoredIdentityKey {
+            secret: [0x01; 32],
+            created_at: Some(start),
+            account_secret: [0x04; 32],
+        };
+        storage.save_identity_key(identity.encode().unwrap()).await.unwrap();
+        let spk = StoredSignedPreKey { public_key: [0x02; 32], created_at: start };
+        storage.save_signed_prekey(1, spk.encode().unwrap()).await.unwrap();
+        for id in 0..policy.one_time_prekey_target as u32 {
+            storage.save_one_time_prekey(id, vec![0x03; 32]).await.unwrap();
+        }
+
+        assert!(!policy.needs_identity_rotation(storage.as_ref()).await.unwrap());
+        assert!(!policy.needs_spk_rotation(storage.as_ref(), 1).await.unwrap());
+        assert!(!policy.needs_opk_replenishment(storage.as_ref()).await.unwrap());
+
+        now.store(start.saturating_add_secs(31 * DAY).as_millis(), Ordering::SeqCst);
+        assert!(!policy.needs_identity_rotation(storage.as_ref()).await.unwrap());
+        assert!(policy.needs_spk_rotation(storage.as_ref(), 1).await.unwrap());
+
+        now.store(start.saturating_add_secs(366 * DAY).as_millis(), Ordering::SeqCst);
+        assert!(policy.needs_identity_rotation(storage.as_ref()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_identity_is_not_due() {
+        let storage = MemoryStorage::new();
+        storage
+            .save_identity_key(bincode::serialize(&[0x01u8; 32]).unwrap())
+            .await
+            .unwrap();
+
+        let policy = KeyRotationPolicy::default();
+        assert!(!policy.needs_identity_rotation(storage.as_ref()).await.unwrap());
+        assert_eq!(
+            StoredIdentityKey::decode(&storage.get_identity_key().await.unwrap().unwrap())
+                .unwrap()
+                .secret,
+            [0x01; 32]
+        );
+    }
+
+    #[test]
+    fn test_older_records_get_a_stable_account_secret() {
+        #[derive(Serialize)]
+        struct V1 {
+            secret: [u8; 32],
+            created_at: Option<Timestamp>,
+        }
+        let v1 = bincode::serialize(&V1 {
+            secret: [0x01; 32],
+            created_at: Some(Timestamp::from_secs(1_700_000_000)),
+        })
+        .unwrap();
+        let legacy = bincode::serialize(&[0x01u8; 32]).unwrap();
+
+        let upgraded = StoredIdentityKey::decode(&v1).unwrap();
+        assert_eq!(upgraded.created_at, Some(Timestamp::from_secs(1_700_000_000)));
+        assert_ne!(upgraded.account_secret, upgraded.secret);
+        assert_eq!(StoredIdentityKey::decode(&legacy).unwrap().account_secret, upgraded.account_secret);
+
+        // Once saved, the secret no longer depends on the identity key
+        let rotated = StoredIdentityKey { secret: [0x02; 32], ..upgraded };
+        let decoded = StoredIdentityKey::decode(&rotated.encode().unwrap()).unwrap();
+        assert_eq!(decoded.secret, [0x02; 32]);
+        assert_eq!(decoded.account_secret, rotated.account_secret);
+    }
+}
@@ -4,22 +4,33 @@
 //! key ratcheting, and cleanup.
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use zeroize::Zeroize;
 use tracing::{debug, info, warn, error};
 
 use qiyashash_core::session::{Session, SessionId, SessionRecord, SessionState};
 use qiyashash_core::storage::{SessionStore, IdentityStore, PreKeyStore};
-use qiyashash_core::types::{DeviceId, Fingerprint, UserId};
-use qiyashash_crypto::identity::{Identity, IdentityKeyPair, IdentityPublicKey};
-use qiyashash_crypto::ratchet::DoubleRatchet;
-use qiyashash_crypto::x3dh::{PreKeyManager, X3DHKeyAgreement};
-use qiyashash_crypto::keys::PreKeyBundle;
+use qiyashash_core::types::{DeviceId, Fingerprint, Timestamp, UserId};
+use qiyashash_crypto::aead::{Aead, AeadKey, EncryptedPayload};
+use qiyashash_crypto::identity::{Identity, IdentityKeyPair, IdentityPublicKey, IdentityRotationProof};
+use qiyashash_crypto::ratchet::{DoubleRatchet, RatchetHealth};
+use qiyashash_crypto::x3dh::{PreKeyManager, X3DHHeader, X3DHKeyAgreement};
+use qiyashash_crypto::keys::{OneTimePreKey, PreKeyBundle, PublicKeyBytes};
 use qiyashash_crypto::chain::ChainState;
+use qiyashash_crypto::kdf::{
+    compute_auth_tag, domain, verify_auth_tag, DerivedKey, KdfDomain, KeyDerivationContext,
+};
 
 use crate::config::ClientConfig;
 use crate::error::{ProtocolError, Result};
-use crate::protocol::DevicePreKeyBundle;
+use crate::protocol::{
+    DevicePreKeyBundle, KeyConfirmation, SessionResetReason, SessionResetRequest, VersionRange,
+};
+use crate::rotation::Clock;
 
 /// Active session with ratchet state
 struct ActiveSession {
@@ -29,20 +40,96 @@ struct ActiveSession {
     ratchet: DoubleRatchet,
     /// Chain state for ordering
     chain: ChainState,
+    /// Key for deniable MACs, shared by both ends
+    mac_key: DerivedKey<32>,
+    /// Key authenticating session resets
+    reset_key: DerivedKey<32>,
+    /// Key for confirming the shared secret after X3DH
+    confirmation_key: DerivedKey<32>,
+    /// Our X3DH header, sent with every message until the peer answers
+    initiation: Option<X3DHHeader>,
+    /// Whether we ran X3DH as the initiator
+    initiator: bool,
+    /// Whether the peer has proven it derived the same keys, by a key
+    /// confirmation or a message that decrypted
+    confirmed: bool,
+    /// Logical time of the last use, for LRU eviction
+    last_used: u64,
 }
 
+/// Secrets of a session as stored in [`SessionRecord::ratchet_state`],
+/// sealed under the storage key
+#[derive(Serialize, Deserialize)]
+struct StoredSessionSecrets {
+    /// [`DoubleRatchet::export_state`] output
+    ratchet: Vec<u8>,
+    mac_key: [u8; 32],
+    reset_key: [u8; 32],
+    confirmation_key: [u8; 32],
+    initiation: Option<X3DHHeader>,
+    initiator: bool,
+    confirmed: bool,
+}
+
+impl Drop for StoredSessionSecrets {
+    fn drop(&mut self) {
+        self.ratchet.zeroize();
+        self.mac_key.zeroize();
+        self.reset_key.zeroize();
+        self.confirmation_key.zeroize();
+    }
+}
+
+/// Summary of an active session for display
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// Session ID
+    pub session_id: SessionId,
+    /// Peer user
+    pub their_user_id: UserId,
+    /// Peer device
+    pub their_device_id: DeviceId,
+    /// When the session was established
+    pub created_at: Timestamp,
+    /// Messages sent and received
+    pub message_count: u64,
+    /// Ratchet diagnostics, including the skipped-key count
+    pub health: RatchetHealth,
+    /// Whether the session is due for re-keying
+    pub needs_rekey: bool,
+    /// SHA-256 of our current ratchet public key
+    pub ratchet_key_fingerprint: Option<Fingerprint>,
+    /// Sequence number of the session's message chain
+    pub chain_sequence: u64,
+    /// Whether the message chain's history verifies
+    pub chain_intact: bool,
+}
+
+/// Label the session initiator MACs to confirm its keys
+const INITIATOR_CONFIRMATION: &[u8] = b"QiyasHash_KeyConfirm_Initiator";
+/// Label the responder MACs in reply
+const RESPONDER_CONFIRMATION: &[u8] = b"QiyasHash_KeyConfirm_Responder";
+
 /// Session manager
 pub struct SessionManager {
     /// Configuration
     config: ClientConfig,
     /// Our identity
     identity: Identity,
+    /// Account secret, kept across identity rotation
+    account_secret: DerivedKey<32>,
+    /// Key sealing session secrets in storage
+    storage_key: AeadKey,
     /// Our device ID
     device_id: DeviceId,
     /// Pre-key manager
     prekey_manager: PreKeyManager,
     /// Active sessions (in memory)
     active_sessions: RwLock<HashMap<SessionId, ActiveSession>>,
+    /// Logical clock stamping [`ActiveSession::last_used`]
+    use_clock: AtomicU64,
+    /// Current time, for session activity
+    clock: Clock,
     /// Storage backend
     storage: Arc<dyn SessionStore + Send + Sync>,
     /// Identity storage
@@ -53,22 +140,33 @@ pub struct SessionManager {
 
 impl SessionManager {
     /// Create a new session manager
+    ///
+    /// Session secrets are stored under a key derived from `account_secret`,
+    /// so it must stay the same across restarts and identity rotation.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         config: ClientConfig,
         identity: Identity,
+        account_secret: [u8; 32],
         device_id: DeviceId,
         storage: Arc<dyn SessionStore + Send + Sync>,
         identity_storage: Arc<dyn IdentityStore + Send + Sync>,
         prekey_storage: Arc<dyn PreKeyStore + Send + Sync>,
     ) -> Result<Self> {
         let prekey_manager = PreKeyManager::new(identity.key_pair.clone());
+        let storage_key: DerivedKey<32> = KeyDerivationContext::new(None, &account_secret)
+            .derive(domain::SESSION_STORAGE)?;
 
         let manager = Self {
             config,
             identity,
+            account_secret: DerivedKey::from_bytes(account_secret),
+            storage_key: AeadKey::from_bytes(storage_key.into_bytes()),
             device_id,
             prekey_manager,
             active_sessions: RwLock::new(HashMap::new()),
+            use_clock: AtomicU64::new(0),
+            clock: Arc::new(Timestamp::now),
             storage,
             identity_storage,
             prekey_storage,
@@ -80,24 +178,26 @@ impl SessionManager {
         Ok(manager)
     }
 
-    /// Load active sessions from storage
+    /// Load the most recently active open sessions from storage
+    ///
+    /// At most [`ClientConfig::max_active_sessions`] are loaded; the rest
+    /// stay on disk until first used.
     async fn load_active_sessions(&self) -> Result<()> {
-        let records = self.storage.get_active_sessions().await
+        let mut records = self.storage.get_open_sessions().await
             .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        records.sort_by_key(|record| std::cmp::Reverse(record.session.last_activity_at));
+        records.truncate(self.config.max_active_sessions);
 
         let mut sessions = self.active_sessions.write();
 
         for record in records {
-            match self.restore_session(&record) {
-                Ok((ratchet, chain)) => {
-                    sessions.insert(record.session.id.clone(), ActiveSession {
-                        session: record.session,
-                        ratchet,
-                        chain,
-                    });
+            let session_id = record.session.id.clone();
+            match self.restore_session(record) {
+                Ok(active) => {
+                    sessions.insert(session_id, active);
                 }
                 Err(e) => {
-                    warn!("Failed to restore session {}: {}", record.session.id, e);
+                    warn!("Failed to restore session {}: {}", session_id, e);
                 }
             }
         }
@@ -106,11 +206,166 @@ impl SessionManager {
         Ok(())
     }
 
-    /// Restore ratchet and chain state from serialized data
-    fn restore_session(&self, record: &SessionRecord) -> Result<(DoubleRatchet, ChainState)> {
-        // In production, deserialize the actual ratchet state
-        // For now, this is a placeholder
-        Err(ProtocolError::Internal("Session restoration not implemented".to_string()))
+    /// Rebuild an in-memory session from a stored record
+    fn restore_session(&self, record: SessionRecord) -> Result<ActiveSession> {
+        if record.ratchet_state.is_empty() {
+            return Err(ProtocolError::Storage(format!(
+                "session {} has no stored ratchet state",
+                record.session.id
+            )));
+        }
+
+        let secrets = self.open_secrets(&record.session.id, &record.ratchet_state)?;
+        let chain: ChainState = bincode::deserialize(&record.chain_state)
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        Ok(ActiveSession {
+            session: record.session,
+            ratchet: DoubleRatchet::import_state(&secrets.ratchet)?,
+            chain,
+            mac_key: DerivedKey::from_bytes(secrets.mac_key),
+            reset_key: DerivedKey::from_bytes(secrets.reset_key),
+            confirmation_key: DerivedKey::from_bytes(secrets.confirmation_key),
+            initiation: secrets.initiation.clone(),
+            initiator: secrets.initiator,
+            confirmed: secrets.confirmed,
+            last_used: self.tick(),
+        })
+    }
+
+    /// Snapshot a session, secrets sealed, for storage
+    fn session_record(&self, active: &ActiveSession) -> Result<SessionRecord> {
+        let secrets = StoredSessionSecrets {
+            ratchet: active.ratchet.export_state()?,
+            mac_key: *active.mac_key.as_bytes(),
+            reset_key: *active.reset_key.as_bytes(),
+            confirmation_key: *active.confirmation_key.as_bytes(),
+            initiation: active.initiation.clone(),
+            initiator: active.initiator,
+            confirmed: active.confirmed,
+        };
+
+        Ok(SessionRecord {
+            session: active.session.clone(),
+            ratchet_state: self.seal_secrets(&active.session.id, &secrets)?,
+            chain_state: bincode::serialize(&active.chain)
+                .map_err(|e| ProtocolError::Internal(e.to_string()))?,
+        })
+    }
+
+    /// Current state of a session, to be saved after every encrypt or decrypt
+    ///
+    /// A restart resumes from the last saved state, so anything produced
+    /// under a newer state must not leave the device before it is saved.
+    pub fn session_snapshot(&self, session_id: &SessionId) -> Result<SessionRecord> {
+        let sessions = self.active_sessions.read();
+        let active = sessions.get(session_id)
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?;
+        self.session_record(active)
+    }
+
+    /// Encrypt session secrets under the storage key, bound to the session
+    fn seal_secrets(&self, session_id: &SessionId, secrets: &StoredSessionSecrets) -> Result<Vec<u8>> {
+        let mut plaintext = bincode::serialize(secrets)
+            .map_err(|e| ProtocolError::Internal(e.to_string()))?;
+        let sealed = Aead::new().encrypt(&self.storage_key, &plaintext, session_id.as_str().as_bytes());
+        plaintext.zeroize();
+
+        bincode::serialize(&sealed?).map_err(|e| ProtocolError::Internal(e.to_string()))
+    }
+
+    /// Decrypt session secrets sealed by [`SessionManager::seal_secrets`]
+    fn open_secrets(&self, session_id: &SessionId, data: &[u8]) -> Result<StoredSessionSecrets> {
+        let sealed: EncryptedPayload = bincode::deserialize(data)
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        let mut plaintext = Aead::new()
+            .decrypt(&self.storage_key, &sealed, session_id.as_str().as_bytes())
+            .map_err(|_| ProtocolError::Storage(format!(
+                "secrets of session {} do not decrypt under the storage key",
+                session_id
+            )))?;
+        let secrets = bincode::deserialize(&plaintext)
+            .map_err(|e| ProtocolError::Storage(e.to_string()));
+        plaintext.zeroize();
+        secrets
+    }
+
+    /// Use `clock` instead of the system time for session activity
+    pub fn with_clock(mut self, clock: impl Fn() -> Timestamp + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn now(&self) -> Timestamp {
+        (self.clock)()
+    }
+
+    /// Next reading of the logical use clock
+    fn tick(&self) -> u64 {
+        self.use_clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Mark a session as just used, so it is the last to be evicted
+    pub fn touch(&self, session_id: &SessionId) -> bool {
+        match self.active_sessions.write().get_mut(session_id) {
+            Some(session) => {
+                session.last_used = self.tick();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Snapshots of the least recently used sessions over the limit
+    ///
+    /// Nothing is removed: save each record, then hand it back to
+    /// [`SessionManager::evict`] with the stamp returned alongside it.
+    pub fn eviction_candidates(&self) -> Result<Vec<(SessionRecord, u64)>> {
+        let sessions = self.active_sessions.read();
+        let excess = sessions.len().saturating_sub(self.config.max_active_sessions);
+        if excess == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut by_age: Vec<&ActiveSession> = sessions.values().collect();
+        by_age.sort_by_key(|s| s.last_used);
+        by_age
+            .into_iter()
+            .take(excess)
+            .map(|s| Ok((self.session_record(s)?, s.last_used)))
+            .collect()
+    }
+
+    /// Drop a saved session from memory
+    ///
+    /// Only evicts when the session is unchanged since the snapshot stamped
+    /// `last_used`; a session used in between keeps its newer state in
+    /// memory and `false` is returned.
+    pub fn evict(&self, session_id: &SessionId, last_used: u64) -> bool {
+        let mut sessions = self.active_sessions.write();
+        if sessions.get(session_id).map(|s| s.last_used) != Some(last_used) {
+            return false;
+        }
+
+        sessions.remove(session_id);
+        debug!("Evicted session {} from memory", session_id);
+        true
+    }
+
+    /// Bring an evicted session back into memory
+    ///
+    /// The stored copy stays valid only while every later state is saved as
+    /// well; see [`SessionManager::session_snapshot`].
+    pub fn reload_session(&self, record: SessionRecord) -> Result<SessionId> {
+        let session_id = record.session.id.clone();
+        if self.touch(&session_id) {
+            return Ok(session_id);
+        }
+
+        let active = self.restore_session(record)?;
+        self.active_sessions.write().insert(session_id.clone(), active);
+        debug!("Reloaded session {} from storage", session_id);
+        Ok(session_id)
     }
 
     /// Get our identity public key
@@ -128,10 +383,61 @@ impl SessionManager {
         self.prekey_manager.get_bundle()
     }
 
-    /// Generate more one-time prekeys
-    pub fn generate_prekeys(&mut self, count: usize) {
-        self.prekey_manager.generate_one_time_prekeys(count);
+    /// Generate more one-time prekeys, returning the new public keys
+    pub fn generate_prekeys(&mut self, count: usize) -> Vec<OneTimePreKey> {
+        let generated = self.prekey_manager.generate_one_time_prekeys(count);
         info!("Generated {} new one-time prekeys", count);
+        generated
+    }
+
+    /// Forget one-time prekey `id`, returning whether it was held
+    pub fn discard_prekey(&mut self, id: u32) -> bool {
+        self.prekey_manager.consume_one_time_prekey(id).is_some()
+    }
+
+    /// Replace our signed prekey
+    pub fn rotate_signed_prekey(&mut self) {
+        self.prekey_manager.rotate_signed_prekey();
+        info!("Rotated signed prekey");
+    }
+
+    /// ID of the signed prekey replaced by the last rotation, while it
+    /// still answers initial messages
+    pub fn previous_signed_prekey_id(&self) -> Option<u32> {
+        self.prekey_manager.previous_signed_prekey_id()
+    }
+
+    /// Stop answering initial messages made with the previous signed
+    /// prekey, returning its ID
+    pub fn retire_previous_signed_prekey(&mut self) -> Option<u32> {
+        let retired = self.prekey_manager.retire_previous_signed_prekey();
+        if let Some(id) = retired {
+            info!("Retired signed prekey {}", id);
+        }
+        retired
+    }
+
+    /// Replace our identity, returning the proof that links it to the old one
+    ///
+    /// Prekeys are signed by the identity, so a new signed prekey is made
+    /// and every previously published one-time prekey becomes unusable. The
+    /// old signed prekey answers initial messages until retired.
+    pub fn rotate_identity(&mut self) -> IdentityRotationProof {
+        let (identity, proof) = self.identity.rotate();
+        self.prekey_manager.rotate_identity(identity.key_pair.clone());
+        self.identity = identity;
+        info!("Rotated identity");
+        proof
+    }
+
+    /// Secret bytes of our identity key, for persisting it
+    pub(crate) fn identity_secret(&self) -> [u8; 32] {
+        self.identity.key_pair.secret_bytes()
+    }
+
+    /// Account secret, for persisting it with the identity key
+    pub(crate) fn account_secret(&self) -> [u8; 32] {
+        *self.account_secret.as_bytes()
     }
 
     /// Check if we need more prekeys
@@ -148,8 +454,37 @@ impl SessionManager {
         their_device_id: &DeviceId,
         their_bundle: &DevicePreKeyBundle,
     ) -> Result<SessionId> {
+        let record = self.start_session(their_user_id, their_device_id, their_bundle)?;
+        let session_id = record.session.id.clone();
+
+        // Persist to storage
+        self.storage.save_session(&record).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        // Save their identity key
+        self.identity_storage.save_remote_identity(their_user_id, their_bundle.identity_key).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        info!("Established session {} with {} device {}", 
+            session_id, their_user_id, their_device_id);
+
+        Ok(session_id)
+    }
+
+    /// Run X3DH against `their_bundle` and hold the new session in memory
+    ///
+    /// The returned record and their identity key still have to be persisted.
+    pub(crate) fn start_session(
+        &mut self,
+        their_user_id: &UserId,
+        their_device_id: &DeviceId,
+        their_bundle: &DevicePreKeyBundle,
+    ) -> Result<SessionRecord> {
         debug!("Establishing session with {} device {}", their_user_id, their_device_id);
 
+        // Settle on a version before any key material is spent
+        let version = VersionRange::supported().negotiate(&their_bundle.versions)?;
+
         // Convert to crypto bundle format
         let bundle = self.convert_bundle(their_bundle)?;
 
@@ -166,65 +501,83 @@ impl SessionManager {
             shared_secret.secret(),
             &their_spk,
             session_id_bytes,
-        ).map_err(|e| ProtocolError::KeyExchangeFailed(e.to_string()))?;
+        )
+        .map_err(|e| ProtocolError::KeyExchangeFailed(e.to_string()))?
+        .with_algorithm(self.config.preferred_aead.resolve())
+        .with_associated_data(VersionRange::binding(version, &their_bundle.versions));
 
         // Create chain state
         let chain = ChainState::from_shared_secret(shared_secret.secret());
+        let mac_key = Self::derive_mac_key(shared_secret.secret());
+        let reset_key = Self::derive_reset_key(shared_secret.secret());
+        let confirmation_key = Self::derive_confirmation_key(shared_secret.secret());
+        let their_identity = IdentityPublicKey::from_bytes(&their_bundle.identity_key)
+            .map_err(|e| ProtocolError::InvalidPreKeyBundle(e.to_string()))?;
 
         // Create session metadata
-        let session = Session::new(
+        let mut session = Session::new(
             UserId::from_fingerprint(&self.identity.fingerprint),
             self.device_id.clone(),
             their_user_id.clone(),
             their_device_id.clone(),
             self.fingerprint(),
-            Fingerprint::from_bytes(their_bundle.identity_key),
+            Fingerprint::from_bytes(their_identity.fingerprint()),
             Fingerprint::from_bytes(session_id_bytes),
         );
+        session.protocol_version = version;
+        session.last_activity_at = self.now();
+
+        let initiation = X3DHHeader {
+            identity_key: PublicKeyBytes::from(self.identity_public_key().signing_key_bytes()),
+            ephemeral_key: ephemeral_public,
+            one_time_prekey_id: opk_id,
+            signed_prekey_id: bundle.signed_prekey.id,
+            session_nonce: *shared_secret.session_nonce(),
+        };
 
-        let session_id = session.id.clone();
-
-        // Store in memory
-        {
-            let mut sessions = self.active_sessions.write();
-            sessions.insert(session_id.clone(), ActiveSession {
-                session: session.clone(),
-                ratchet,
-                chain,
-            });
-        }
-
-        // Persist to storage
-        let record = SessionRecord {
+        let active = ActiveSession {
             session,
-            ratchet_state: Vec::new(), // Would serialize ratchet
-            chain_state: Vec::new(),   // Would serialize chain
+            ratchet,
+            chain,
+            mac_key,
+            reset_key,
+            confirmation_key,
+            initiation: Some(initiation),
+            initiator: true,
+            confirmed: !self.config.require_key_confirmation,
+            last_used: self.tick(),
         };
-        self.storage.save_session(&record).await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        let record = self.session_record(&active)?;
 
-        // Save their identity key
-        self.identity_storage.save_remote_identity(their_user_id, their_bundle.identity_key).await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        // Store in memory
+        self.active_sessions.write().insert(active.session.id.clone(), active);
 
-        info!("Established session {} with {} device {}", 
-            session_id, their_user_id, their_device_id);
+        Ok(record)
+    }
 
-        Ok(session_id)
+    /// X3DH header still to be sent on a session we initiated
+    ///
+    /// Cleared by the first message decrypted from the peer.
+    pub fn pending_initiation(&self, session_id: &SessionId) -> Option<X3DHHeader> {
+        self.active_sessions.read()
+            .get(session_id)
+            .and_then(|s| s.initiation.clone())
     }
 
     /// Accept an incoming session
+    ///
+    /// `protocol_version` is the version of the initiator's first envelope,
+    /// which it negotiated against the range in our bundle. An initiation
+    /// whose session nonce was seen before is rejected as a replay.
     pub async fn accept_session(
         &mut self,
         their_user_id: &UserId,
         their_device_id: &DeviceId,
-        their_identity_key: [u8; 32],
-        their_ephemeral_key: [u8; 32],
-        used_opk_id: Option<u32>,
+        header: &X3DHHeader,
+        protocol_version: u32,
     ) -> Result<SessionId> {
-        debug!("Accepting session from {} device {}", their_user_id, their_device_id);
-
         // Verify their identity
+        let their_identity_key = header.identity_key.0;
         let is_trusted = self.identity_storage.is_trusted_identity(their_user_id, &their_identity_key).await
             .map_err(|e| ProtocolError::Storage(e.to_string()))?;
 
@@ -238,21 +591,65 @@ impl SessionManager {
             }
         }
 
+        let fresh = self.storage.record_session_nonce(&header.session_nonce).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        if !fresh {
+            return Err(ProtocolError::ReplayedInitiation(their_user_id.to_string()));
+        }
+
+        let record = self.respond_session(their_user_id, their_device_id, header, protocol_version)?;
+        let session_id = record.session.id.clone();
+
+        if let Some(opk_id) = header.one_time_prekey_id {
+            self.prekey_storage.delete_one_time_prekey(opk_id).await
+                .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        }
+
+        // Persist before the session can be used
+        self.storage.save_session(&record).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        // Save their identity key
+        self.identity_storage.save_remote_identity(their_user_id, their_identity_key).await
+            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+
+        Ok(session_id)
+    }
+
+    /// Run X3DH as responder to `header` and hold the new session in memory
+    ///
+    /// The caller rejects replayed session nonces first; the returned record
+    /// and the used one-time prekey's deletion still have to be persisted.
+    pub(crate) fn respond_session(
+        &mut self,
+        their_user_id: &UserId,
+        their_device_id: &DeviceId,
+        header: &X3DHHeader,
+        protocol_version: u32,
+    ) -> Result<SessionRecord> {
+        debug!("Accepting session from {} device {}", their_user_id, their_device_id);
+
+        let ours = VersionRange::supported();
+        if !ours.contains(protocol_version) {
+            return Err(ProtocolError::IncompatibleVersions {
+                ours,
+                theirs: VersionRange { min: protocol_version, max: protocol_version },
+            });
+        }
+
         // Perform X3DH as responder
-        let their_identity = IdentityPublicKey::from_bytes(&their_identity_key)
+        let their_identity = IdentityPublicKey::from_bytes(&header.identity_key.0)
             .map_err(|e| ProtocolError::KeyExchangeFailed(e.to_string()))?;
-        
-        let ephemeral_key = qiyashash_crypto::keys::PublicKeyBytes::from(their_ephemeral_key);
 
-        let shared_secret = X3DHKeyAgreement::respond(
-            &mut self.prekey_manager,
-            &their_identity,
-            &ephemeral_key,
-            used_opk_id,
-        ).map_err(|e| ProtocolError::KeyExchangeFailed(e.to_string()))?;
+        let shared_secret = X3DHKeyAgreement::respond_to(&mut self.prekey_manager, header)
+            .map_err(|e| ProtocolError::KeyExchangeFailed(e.to_string()))?;
 
-        // Get our signed prekey for the ratchet
-        let our_spk_secret = self.prekey_manager.signed_prekey_secret().clone();
+        // The signed prekey the initiator used seeds the ratchet
+        let our_spk_secret = self.prekey_manager.signed_prekey_secret_for(header.signed_prekey_id)
+            .cloned()
+            .ok_or_else(|| ProtocolError::KeyExchangeFailed(format!(
+                "signed prekey {} is gone", header.signed_prekey_id
+            )))?;
         let session_id_bytes = self.compute_session_id(shared_secret.secret());
 
         // Create Double Ratchet session as responder
@@ -260,10 +657,16 @@ impl SessionManager {
             shared_secret.secret(),
             our_spk_secret,
             session_id_bytes,
-        );
+        )
+        .map_err(|e| ProtocolError::KeyExchangeFailed(e.to_string()))?
+        .with_algorithm(self.config.preferred_aead.resolve())
+        .with_associated_data(VersionRange::binding(protocol_version, &ours));
 
         // Create chain state
         let chain = ChainState::from_shared_secret(shared_secret.secret());
+        let mac_key = Self::derive_mac_key(shared_secret.secret());
+        let reset_key = Self::derive_reset_key(shared_secret.secret());
+        let confirmation_key = Self::derive_confirmation_key(shared_secret.secret());
 
         // Create session metadata
         let mut session = Session::new(
@@ -272,40 +675,38 @@ impl SessionManager {
             their_user_id.clone(),
             their_device_id.clone(),
             self.fingerprint(),
-            Fingerprint::from_bytes(their_identity_key),
+            Fingerprint::from_bytes(their_identity.fingerprint()),
             Fingerprint::from_bytes(session_id_bytes),
         );
+        session.protocol_version = protocol_version;
         session.activate();
+        session.last_activity_at = self.now();
 
-        let session_id = session.id.clone();
-
-        // Store in memory
-        {
-            let mut sessions = self.active_sessions.write();
-            sessions.insert(session_id.clone(), ActiveSession {
-                session: session.clone(),
-                ratchet,
-                chain,
-            });
-        }
-
-        // Persist to storage
-        let record = SessionRecord {
+        let active = ActiveSession {
             session,
-            ratchet_state: Vec::new(),
-            chain_state: Vec::new(),
+            ratchet,
+            chain,
+            mac_key,
+            reset_key,
+            confirmation_key,
+            initiation: None,
+            initiator: false,
+            confirmed: !self.config.require_key_confirmation,
+            last_used: self.tick(),
         };
-        self.storage.save_session(&record).await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        let record = self.session_record(&active)?;
+        self.active_sessions.write().insert(active.session.id.clone(), active);
 
-        // Save their identity key
-        self.identity_storage.save_remote_identity(their_user_id, their_identity_key).await
-            .map_err(|e| ProtocolError::Storage(e.to_string()))?;
+        info!("Accepted session {} from {} device {}",
+            record.session.id, their_user_id, their_device_id);
 
-        info!("Accepted session {} from {} device {}", 
-            session_id, their_user_id, their_device_id);
+        Ok(record)
+    }
 
-        Ok(session_id)
+    /// Drop a session from memory without saving it, e.g. one accepted from
+    /// an initial message that then failed to decrypt
+    pub(crate) fn discard_session(&self, session_id: &SessionId) {
+        self.active_sessions.write().remove(session_id);
     }
 
     /// Get session by user and device
@@ -323,6 +724,230 @@ impl SessionManager {
             .map(|s| s.session.id.clone())
     }
 
+    /// Key for encrypting the search index of a session's conversation
+    ///
+    /// Derived from our account secret and the peer's user ID rather than
+    /// anything the session shares with the peer: every device holding the
+    /// account secret derives the same key for the conversation, and the
+    /// peer derives none of it.
+    pub fn conversation_search_key(&self, session_id: &SessionId) -> Result<DerivedKey<32>> {
+        self.active_sessions.read()
+            .get(session_id)
+            .map(|s| self.derive_search_key(&s.session.their_user_id))
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+    }
+
+    /// MAC over `data` that either end of the session could have produced
+    ///
+    /// The key is derived from the session's shared secret, so the peer can
+    /// verify the tag while it proves nothing to a third party: the peer
+    /// could have computed the same tag itself.
+    pub fn deniable_mac(&self, session_id: &SessionId, data: &[u8]) -> Result<[u8; 32]> {
+        self.active_sessions.read()
+            .get(session_id)
+            .map(|s| compute_auth_tag(s.mac_key.as_bytes(), data))
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+    }
+
+    /// Check a tag from [`SessionManager::deniable_mac`]
+    pub fn verify_deniable_mac(
+        &self,
+        session_id: &SessionId,
+        data: &[u8],
+        tag: &[u8; 32],
+    ) -> Result<bool> {
+        self.active_sessions.read()
+            .get(session_id)
+            .map(|s| verify_auth_tag(s.mac_key.as_bytes(), data, tag))
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+    }
+
+    /// Build a reset request for a session that only the peer can verify
+    ///
+    /// The request names us as the party whose session is reset and is
+    /// MACed under a reset key derived from the session's shared secret, so
+    /// a replayed request is useless once the session is gone.
+    pub fn session_reset(
+        &self,
+        session_id: &SessionId,
+        reason: SessionResetReason,
+    ) -> Result<SessionResetRequest> {
+        let sessions = self.active_sessions.read();
+        let session = sessions.get(session_id)
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?;
+
+        let mut request = SessionResetRequest {
+            target_user_id: session.session.our_user_id.clone(),
+            target_device_id: session.session.our_device_id.clone(),
+            reason,
+            mac: [0; 32],
+        };
+        request.mac = compute_auth_tag(session.reset_key.as_bytes(), &request.authenticated_data());
+        Ok(request)
+    }
+
+    /// Authenticate a reset request from the peer and drop the session
+    ///
+    /// Fails with [`ProtocolError::Unauthorized`], leaving the session in
+    /// place, unless the request names the peer and carries its MAC.
+    pub fn apply_session_reset(
+        &self,
+        session_id: &SessionId,
+        request: &SessionResetRequest,
+    ) -> Result<()> {
+        let mut sessions = self.active_sessions.write();
+        let session = sessions.get(session_id)
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?;
+
+        let names_peer = request.target_user_id == session.session.their_user_id
+            && request.target_device_id == session.session.their_device_id;
+        if !names_peer
+            || !verify_auth_tag(
+                session.reset_key.as_bytes(),
+                &request.authenticated_data(),
+                &request.mac,
+            )
+        {
+            warn!("Rejected unauthenticated reset of session {}", session_id);
+            return Err(ProtocolError::Unauthorized(format!(
+                "session reset for {} is not authenticated",
+                session_id
+            )));
+        }
+
+        if let Some(mut session) = sessions.remove(session_id) {
+            session.session.close();
+        }
+        info!("Session {} reset by peer ({:?})", session_id, request.reason);
+        Ok(())
+    }
+
+    /// Start key confirmation as the session initiator
+    ///
+    /// Send the result to the responder before any real data; it carries
+    /// our X3DH header while the responder may not have seen one. Each side
+    /// MACs its own label, so a confirmation can't be reflected back.
+    pub fn confirm_keys(&self, session_id: &SessionId) -> Result<KeyConfirmation> {
+        let mut confirmation = self.confirmation_tag(session_id, INITIATOR_CONFIRMATION)?;
+        let sessions = self.active_sessions.read();
+        if let Some(session) = sessions.get(session_id) {
+            confirmation.initiation = session.initiation.clone();
+            confirmation.session_version = session.session.protocol_version;
+        }
+        Ok(confirmation)
+    }
+
+    /// Check the initiator's confirmation and produce our reply
+    ///
+    /// Fails with [`ProtocolError::KeyConfirmationFailed`] when the
+    /// initiator derived a different shared secret, or when we are the
+    /// initiator ourselves. Marks the session confirmed.
+    pub fn answer_key_confirmation(
+        &self,
+        session_id: &SessionId,
+        confirmation: &KeyConfirmation,
+    ) -> Result<KeyConfirmation> {
+        self.check_confirmation(session_id, false, INITIATOR_CONFIRMATION, confirmation)?;
+        self.confirmation_tag(session_id, RESPONDER_CONFIRMATION)
+    }
+
+    /// Check the responder's reply to [`SessionManager::confirm_keys`]
+    ///
+    /// Marks the session confirmed.
+    pub fn finish_key_confirmation(
+        &self,
+        session_id: &SessionId,
+        confirmation: &KeyConfirmation,
+    ) -> Result<()> {
+        self.check_confirmation(session_id, true, RESPONDER_CONFIRMATION, confirmation)
+    }
+
+    /// Whether the session initiated X3DH on our side
+    pub fn is_initiator(&self, session_id: &SessionId) -> Option<bool> {
+        self.active_sessions.read().get(session_id).map(|s| s.initiator)
+    }
+
+    /// Whether the peer has proven it holds the session's keys
+    ///
+    /// Always true unless [`ClientConfig::require_key_confirmation`] is set.
+    pub fn is_confirmed(&self, session_id: &SessionId) -> bool {
+        self.active_sessions.read()
+            .get(session_id)
+            .is_some_and(|s| s.confirmed)
+    }
+
+    fn confirmation_tag(&self, session_id: &SessionId, label: &[u8]) -> Result<KeyConfirmation> {
+        self.active_sessions.read()
+            .get(session_id)
+            .map(|s| KeyConfirmation {
+                tag: compute_auth_tag(s.confirmation_key.as_bytes(), label),
+                initiation: None,
+                session_version: s.session.protocol_version,
+            })
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+    }
+
+    fn check_confirmation(
+        &self,
+        session_id: &SessionId,
+        initiator: bool,
+        label: &[u8],
+        confirmation: &KeyConfirmation,
+    ) -> Result<()> {
+        let mut sessions = self.active_sessions.write();
+        let session = sessions.get_mut(session_id)
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?;
+
+        if session.initiator != initiator
+            || !verify_auth_tag(session.confirmation_key.as_bytes(), label, &confirmation.tag)
+        {
+            warn!("Key confirmation failed for session {}", session_id);
+            return Err(ProtocolError::KeyConfirmationFailed(session_id.to_string()));
+        }
+        session.confirmed = true;
+        Ok(())
+    }
+
+    /// Diagnostic snapshot of a session's ratchet
+    ///
+    /// A growing skipped-key count means the peer's messages are being lost
+    /// or badly reordered.
+    pub fn session_health(&self, session_id: &SessionId) -> Result<RatchetHealth> {
+        self.active_sessions.read()
+            .get(session_id)
+            .map(|s| s.ratchet.health())
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))
+    }
+
+    /// Check that `identity` is the peer's identity in a session
+    pub fn verify_sender_identity(
+        &self,
+        session_id: &SessionId,
+        identity: &IdentityPublicKey,
+    ) -> Result<()> {
+        let sessions = self.active_sessions.read();
+        let session = &sessions
+            .get(session_id)
+            .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?
+            .session;
+
+        let actual = Fingerprint::from_bytes(identity.fingerprint());
+        if actual != session.their_fingerprint {
+            return Err(ProtocolError::IdentityMismatch {
+                expected: session.their_fingerprint.to_hex(),
+                actual: actual.to_hex(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Protocol version negotiated for a session
+    pub fn session_version(&self, session_id: &SessionId) -> Option<u32> {
+        self.active_sessions.read()
+            .get(session_id)
+            .map(|s| s.session.protocol_version)
+    }
+
     /// Check if session exists
     pub fn has_session(
         &self,
@@ -341,24 +966,36 @@ impl SessionManager {
         let mut sessions = self.active_sessions.write();
         let session = sessions.get_mut(session_id)
             .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?;
+        session.last_used = self.tick();
+
+        // Re-key before the ratchet refuses to extend the chain
+        if session.ratchet.sending_chain_length() >= self.config.rekey_threshold() {
+            let epoch = session.ratchet.rekey_sending_chain()
+                .map_err(ProtocolError::Crypto)?;
+            let proof = Self::compute_rekey_proof(session.ratchet.session_id(), epoch);
+            session.chain.add_rekey(&proof);
+            debug!("Re-keyed sending chain of session {} (epoch {})", session_id, epoch);
+        }
 
         // Encrypt with ratchet
         let ratchet_msg = session.ratchet.encrypt(plaintext)
             .map_err(|e| ProtocolError::Crypto(e))?;
 
         // Update chain state
+        let header_bytes = ratchet_msg.header.to_bytes()
+            .map_err(ProtocolError::Crypto)?;
         let msg_hash = qiyashash_crypto::chain::compute_message_hash(
             &ratchet_msg.payload.ciphertext,
-            &ratchet_msg.header.to_bytes(),
+            &header_bytes,
         );
         let chain_link = session.chain.add_message(&msg_hash);
 
         // Serialize ratchet message
-        let ciphertext = bincode::serialize(&ratchet_msg)
+        let ciphertext = ratchet_msg.to_bytes()
             .map_err(|e| ProtocolError::Internal(e.to_string()))?;
 
         // Update session
-        session.session.increment_message_count();
+        session.session.record_message(self.now());
         session.session.update_ratchet_hash(session.ratchet.current_ratchet_public()
             .map(|p| *p.as_bytes())
             .unwrap_or([0; 32]));
@@ -375,17 +1012,24 @@ impl SessionManager {
         let mut sessions = self.active_sessions.write();
         let session = sessions.get_mut(session_id)
             .ok_or_else(|| ProtocolError::SessionNotFound(session_id.to_string()))?;
+        session.last_used = self.tick();
 
         // Deserialize ratchet message
-        let ratchet_msg: qiyashash_crypto::ratchet::RatchetMessage = bincode::deserialize(ciphertext)
+        let ratchet_msg = qiyashash_crypto::ratchet::RatchetMessage::from_bytes(ciphertext)
             .map_err(|e| ProtocolError::InvalidMessage(e.to_string()))?;
 
         // Decrypt with ratchet
         let plaintext = session.ratchet.decrypt(&ratchet_msg)
             .map_err(|e| ProtocolError::DecryptionFailed(e.to_string()))?;
 
+        // The peer answered, so it holds the session and needs no header
+        if session.initiation.take().is_some() {
+            session.session.activate();
+        }
+        session.confirmed = true;
+
         // Update session
-        session.session.increment_message_count();
+        session.session.record_message(self.now());
         session.session.update_ratchet_hash(session.ratchet.current_ratchet_public()
             .map(|p| *p.as_bytes())
             .unwrap_or([0; 32]));
@@ -415,6 +1059,46 @@ impl SessionManager {
         self.active_sessions.read().len()
     }
 
+    /// Summaries of all active sessions, oldest first
+    pub fn session_infos(&self) -> Vec<SessionInfo> {
+        use sha2::{Digest, Sha256};
+
+        let mut infos: Vec<_> = self.active_sessions.read()
+            .values()
+            .map(|s| {
+                let health = s.ratchet.health();
+                SessionInfo {
+                    session_id: s.session.id.clone(),
+                    their_user_id: s.session.their_user_id.clone(),
+                    their_device_id: s.session.their_device_id.clone(),
+                    created_at: s.session.created_at,
+                    message_count: s.session.message_count,
+                    health,
+                    needs_rekey: s.session.needs_rekey() || health.near_limit,
+                    ratchet_key_fingerprint: s.ratchet.current_ratchet_public().map(|key| {
+                        Fingerprint::from_bytes(Sha256::digest(key.as_bytes()).into())
+                    }),
+                    chain_sequence: s.chain.sequence(),
+                    chain_intact: s.chain.verify_integrity().is_ok(),
+                }
+            })
+            .collect();
+        infos.sort_by_key(|info| info.created_at);
+        infos
+    }
+
+    /// Sessions with no message for longer than `max_idle`
+    ///
+    /// Candidates for closing or saving and evicting; nothing is changed here.
+    pub fn idle_sessions(&self, max_idle: Duration) -> Vec<SessionId> {
+        let now = self.now();
+        self.active_sessions.read()
+            .values()
+            .filter(|s| s.session.idle_for(now) > max_idle)
+            .map(|s| s.session.id.clone())
+            .collect()
+    }
+
     /// Get sessions needing rekey
     pub fn sessions_needing_rekey(&self) -> Vec<SessionId> {
         self.active_sessions.read()
@@ -424,17 +1108,83 @@ impl SessionManager {
             .collect()
     }
 
+    /// Install a matching session with `peer`, bypassing X3DH
+    ///
+    /// Returns our session ID and the peer's.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn pair_for_tests(
+        &self,
+        our_user_id: &UserId,
+        peer: &SessionManager,
+        peer_user_id: &UserId,
+    ) -> (SessionId, SessionId) {
+        let shared_secret = [0x42u8; 32];
+        let peer_secret = x25519_dalek::StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let peer_public = x25519_dalek::PublicKey::from(&peer_secret);
+        let session_id_bytes = self.compute_session_id(&shared_secret);
+        let versions = VersionRange::supported();
+        let binding = VersionRange::binding(crate::PROTOCOL_VERSION, &versions);
+
+        let mut ours = Session::new(
+            our_user_id.clone(),
+            self.device_id.clone(),
+            peer_user_id.clone(),
+            peer.device_id.clone(),
+            self.fingerprint(),
+            peer.fingerprint(),
+            Fingerprint::from_bytes(session_id_bytes),
+        );
+        let mut theirs = Session::new(
+            peer_user_id.clone(),
+            peer.device_id.clone(),
+            our_user_id.clone(),
+            self.device_id.clone(),
+            peer.fingerprint(),
+            self.fingerprint(),
+            Fingerprint::from_bytes(session_id_bytes),
+        );
+        ours.last_activity_at = self.now();
+        theirs.last_activity_at = peer.now();
+        let ids = (ours.id.clone(), theirs.id.clone());
+
+        self.active_sessions.write().insert(ours.id.clone(), ActiveSession {
+            session: ours,
+            ratchet: DoubleRatchet::new_initiator(&shared_secret, &peer_public, session_id_bytes)
+                .expect("initiator ratchet")
+                .with_associated_data(binding.clone()),
+            chain: ChainState::from_shared_secret(&shared_secret),
+            mac_key: Self::derive_mac_key(&shared_secret),
+            reset_key: Self::derive_reset_key(&shared_secret),
+            confirmation_key: Self::derive_confirmation_key(&shared_secret),
+            initiation: None,
+            initiator: true,
+            confirmed: true,
+            last_used: self.tick(),
+        });
+        peer.active_sessions.write().insert(theirs.id.clone(), ActiveSession {
+            session: theirs,
+            ratchet: DoubleRatchet::new_responder(&shared_secret, peer_secret, session_id_bytes)
+                .expect("responder ratchet")
+                .with_associated_data(binding),
+            chain: ChainState::from_shared_secret(&shared_secret),
+            mac_key: Self::derive_mac_key(&shared_secret),
+            reset_key: Self::derive_reset_key(&shared_secret),
+            confirmation_key: Self::derive_confirmation_key(&shared_secret),
+            initiation: None,
+            initiator: false,
+            confirmed: true,
+            last_used: peer.tick(),
+        });
+
+        ids
+    }
+
     // Helper functions
 
     fn convert_bundle(&self, bundle: &DevicePreKeyBundle) -> Result<PreKeyBundle> {
         Ok(PreKeyBundle {
             identity_key: bundle.identity_key,
-            signed_prekey: qiyashash_crypto::keys::SignedPreKey {
-                id: bundle.signed_prekey_id,
-                public_key: qiyashash_crypto::keys::PublicKeyBytes::from(bundle.signed_prekey),
-                signature: bundle.signed_prekey_signature,
-                timestamp: chrono::Utc::now().timestamp(),
-            },
+            signed_prekey: bundle.signed_prekey()?,
             one_time_prekey: bundle.one_time_prekey_id.map(|id| {
                 qiyashash_crypto::keys::OneTimePreKey {
                     id,
@@ -446,6 +1196,43 @@ impl SessionManager {
         })
     }
 
+    fn compute_rekey_proof(session_id: &[u8; 32], epoch: u32) -> [u8; 32] {
+        use sha2::{Sha256, Digest};
+        let mut hasher = Sha256::new();
+        hasher.update(b"QiyasHash_ReKey_v1");
+        hasher.update(session_id);
+        hasher.update(&epoch.to_be_bytes());
+        let result = hasher.finalize();
+        let mut proof = [0u8; 32];
+        proof.copy_from_slice(&result);
+        proof
+    }
+
+    fn derive_search_key(&self, their_user_id: &UserId) -> DerivedKey<32> {
+        let kdf = KeyDerivationContext::new(
+            Some(their_user_id.as_str().as_bytes()),
+            self.account_secret.as_bytes(),
+        );
+        DerivedKey::from_bytes(kdf.derive_labeled(KdfDomain::SearchKey, 0))
+    }
+
+    fn derive_mac_key(shared_secret: &[u8; 32]) -> DerivedKey<32> {
+        Self::derive_session_key(shared_secret, KdfDomain::DeniableMac)
+    }
+
+    fn derive_confirmation_key(shared_secret: &[u8; 32]) -> DerivedKey<32> {
+        Self::derive_session_key(shared_secret, KdfDomain::KeyConfirmation)
+    }
+
+    fn derive_reset_key(shared_secret: &[u8; 32]) -> DerivedKey<32> {
+        Self::derive_session_key(shared_secret, KdfDomain::SessionReset)
+    }
+
+    fn derive_session_key(shared_secret: &[u8; 32], label: KdfDomain) -> DerivedKey<32> {
+        let kdf = KeyDerivationContext::new(None, shared_secret);
+        DerivedKey::from_bytes(kdf.derive_labeled(label, 0))
+    }
+
     fn compute_session_id(&self, shared_secret: &[u8; 32]) -> [u8; 32] {
         use sha2::{Sha256, Digest};
         let mut hasher = Sha256::new();
@@ -461,5 +1248,302 @@ impl SessionManager {
 
 #[cfg(test)]
 mod tests {
-    // Tests would go here
+    use super::*;
+    use qiyashash_core::storage::memory::MemoryStorage;
+    use qiyashash_crypto::chain::ChainLinkType;
+    use qiyashash_crypto::kdf::domain;
+    use qiyashash_crypto::MAX_CHAIN_LENGTH;
+    use qiyashash_crypto::x3dh::SESSION_NONCE_SIZE;
+
+    async fn manager(config: ClientConfig) -> SessionManager {
+        manager_with(config, MemoryStorage::new(), [0x07; 32]).await
+    }
+
+    async fn manager_with(
+        config: ClientConfig,
+        storage: Arc<MemoryStorage>,
+        account_secret: [u8; 32],
+    ) -> SessionManager {
+        SessionManager::new(
+            config,
+            Identity::new(),
+            account_secret,
+            DeviceId::new(),
+            storage.clone(),
+            storage.clone(),
+            storage,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_startup_loads_most_recent_open_sessions() {
+        let storage = MemoryStorage::new();
+        let alice = manager_with(ClientConfig::default(), storage.clone(), [0x07; 32]).await;
+        let bob = manager(ClientConfig::default()).await;
+        let carol = manager(ClientConfig::default()).await;
+        let alice_id = UserId::new();
+        let (older, _) = alice.pair_for_tests(&alice_id, &bob, &UserId::new());
+        let (recent, _) = alice.pair_for_tests(&alice_id, &carol, &UserId::new());
+        alice.active_sessions.write().get_mut(&older).unwrap().session.last_activity_at =
+            Timestamp::from_secs(1_700_000_000);
+        for id in [&older, &recent] {
+            storage.save_session(&alice.session_snapshot(id).unwrap()).await.unwrap();
+        }
+
+        // Secrets are only stored sealed
+        let record = storage.get_session(&recent).await.unwrap().unwrap();
+        let mac_key = alice.active_sessions.read()[&recent].mac_key.clone();
+        assert!(!record.ratchet_state.windows(32).any(|w| w == mac_key.as_bytes()));
+
+        // Sessions that never became active still load, up to the limit
+        let config = ClientConfig {
+            max_active_sessions: 1,
+            ..Default::default()
+        };
+        let restarted = manager_with(config, storage.clone(), [0x07; 32]).await;
+        assert_eq!(restarted.session_count(), 1);
+        assert!(restarted.active_sessions.read().contains_key(&recent));
+        assert_eq!(restarted.session_health(&recent).unwrap(), alice.session_health(&recent).unwrap());
+
+        // Another account's key opens nothing
+        let other = manager_with(ClientConfig::default(), storage, [0x08; 32]).await;
+        assert_eq!(other.session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_long_one_way_chain_rekeys() {
+        let alice = manager(ClientConfig::default()).await;
+        let bob = manager(ClientConfig::default()).await;
+        let (session_id, bob_session_id) =
+            alice.pair_for_tests(&UserId::new(), &bob, &UserId::new());
+
+        let total = MAX_CHAIN_LENGTH * 2 + 10;
+        for i in 0..total {
+            let plaintext = format!("message {}", i);
+            let (ciphertext, _, _) = alice.encrypt(&session_id, plaintext.as_bytes()).unwrap();
+            assert_eq!(bob.decrypt(&bob_session_id, &ciphertext).unwrap(), plaintext.as_bytes());
+        }
+
+        let sessions = alice.active_sessions.read();
+        let active = sessions.get(&session_id).unwrap();
+        assert!(active.ratchet.sending_chain_length() < ClientConfig::default().rekey_threshold());
+        assert!(active.chain.history().iter().any(|l| l.link_type == ChainLinkType::ReKey));
+    }
+
+    #[tokio::test]
+    async fn test_conversation_search_key() {
+        let storage = MemoryStorage::new();
+        let alice = manager_with(ClientConfig::default(), storage, [0x07; 32]).await;
+        let bob = manager(ClientConfig::default()).await;
+        let (alice_id, bob_id) = (UserId::new(), UserId::new());
+        let (session_id, bob_session_id) = alice.pair_for_tests(&alice_id, &bob, &bob_id);
+
+        // Messages don't change the key, and the peer can't derive it
+        let key = alice.conversation_search_key(&session_id).unwrap();
+        let (ciphertext, _, _) = alice.encrypt(&session_id, b"hello").unwrap();
+        bob.decrypt(&bob_session_id, &ciphertext).unwrap();
+        assert_eq!(key.as_bytes(), alice.conversation_search_key(&session_id).unwrap().as_bytes());
+        assert_ne!(key.as_bytes(), bob.conversation_search_key(&bob_session_id).unwrap().as_bytes());
+
+        // Another of our devices derives the same key for the conversation
+        let laptop = manager_with(ClientConfig::default(), MemoryStorage::new(), [0x07; 32]).await;
+        let peer = manager(ClientConfig::default()).await;
+        let (laptop_session, _) = laptop.pair_for_tests(&alice_id, &peer, &bob_id);
+        assert_eq!(key.as_bytes(), laptop.conversation_search_key(&laptop_session).unwrap().as_bytes());
+
+        // Other conversations and other accounts get other keys
+        let (carol_session, _) = alice.pair_for_tests(&alice_id, &peer, &UserId::new());
+        let carol_key = alice.conversation_search_key(&carol_session).unwrap();
+        assert_ne!(carol_key.as_bytes(), key.as_bytes());
+        let mallory = manager_with(ClientConfig::default(), MemoryStorage::new(), [0x08; 32]).await;
+        let (mallory_session, _) = mallory.pair_for_tests(&alice_id, &peer, &bob_id);
+        assert_ne!(mallory.conversation_search_key(&mallory_session).unwrap().as_bytes(), key.as_bytes());
+
+        assert!(matches!(
+            alice.conversation_search_key(&SessionId::new()),
+            Err(ProtocolError::SessionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deniable_mac() {
+        let alice = manager(ClientConfig::default()).await;
+        let bob = manager(ClientConfig::default()).await;
+        let carol = manager(ClientConfig::default()).await;
+        let (alice_session, bob_session) =
+            alice.pair_for_tests(&UserId::new(), &bob, &UserId::new());
+
+        // Either end computes the same tag, so neither can prove authorship
+        let tag = alice.deniable_mac(&alice_session, b"meet at noon").unwrap();
+        assert_eq!(tag, bob.deniable_mac(&bob_session, b"meet at noon").unwrap());
+        assert!(bob.verify_deniable_mac(&bob_session, b"meet at noon", &tag).unwrap());
+        assert!(!bob.verify_deniable_mac(&bob_session, b"meet at one", &tag).unwrap());
+
+        // A session with another key rejects it
+        let (carol_session, _) = carol.pair_for_tests(&UserId::new(), &bob, &UserId::new());
+        carol.active_sessions.write().get_mut(&carol_session).unwrap().mac_key =
+            DerivedKey::from_bytes([0x01; 32]);
+        assert!(!carol.verify_deniable_mac(&carol_session, b"meet at noon", &tag).unwrap());
+
+        // The MAC key is separated from every other key off the same secret
+        let shared_secret = [0x42u8; 32];
+        let mac_key = alice.active_sessions.read()[&alice_session].mac_key.clone();
+        let (root_key, chain_key) = qiyashash_crypto::kdf::derive_root_and_chain_keys(
+            &[0u8; 32],
+            &shared_secret,
+        )
+        .unwrap();
+        let (_, message_key, _) = qiyashash_crypto::kdf::derive_message_keys(&chain_key);
+        let kdf = KeyDerivationContext::new(None, &shared_secret);
+        for domain in [domain::ROOT_KEY, domain::CHAIN_KEY, domain::MESSAGE_KEY, domain::SEARCH_KEY] {
+            let other: DerivedKey<32> = kdf.derive(domain).unwrap();
+            assert_ne!(mac_key.as_bytes(), other.as_bytes());
+        }
+        for other in [root_key, chain_key, message_key, shared_secret] {
+            assert_ne!(mac_key.as_bytes(), &other);
+        }
+
+        assert!(matches!(
+            alice.deniable_mac(&SessionId::new(), b"data"),
+            Err(ProtocolError::SessionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_key_confirmation() {
+        let alice = manager(ClientConfig::default()).await;
+        let bob = manager(ClientConfig::default()).await;
+        let (alice_session, bob_session) =
+            alice.pair_for_tests(&UserId::new(), &bob, &UserId::new());
+
+        let confirmation = alice.confirm_keys(&alice_session).unwrap();
+        let reply = bob.answer_key_confirmation(&bob_session, &confirmation).unwrap();
+        alice.finish_key_confirmation(&alice_session, &reply).unwrap();
+
+        // A confirmation reflected back is not a valid reply
+        assert!(matches!(
+            alice.finish_key_confirmation(&alice_session, &confirmation),
+            Err(ProtocolError::KeyConfirmationFailed(_))
+        ));
+        // Nor is it ours to answer
+        assert!(matches!(
+            alice.answer_key_confirmation(&alice_session, &confirmation),
+            Err(ProtocolError::KeyConfirmationFailed(_))
+        ));
+
+        // Bob ends up with a different shared secret
+        bob.active_sessions.write().get_mut(&bob_session).unwrap().confirmation_key =
+            SessionManager::derive_confirmation_key(&[0x43; 32]);
+        assert!(matches!(
+            bob.answer_key_confirmation(&bob_session, &confirmation),
+            Err(ProtocolError::KeyConfirmationFailed(_))
+        ));
+        let forged = bob.confirmation_tag(&bob_session, RESPONDER_CONFIRMATION).unwrap();
+        assert!(matches!(
+            alice.finish_key_confirmation(&alice_session, &forged),
+            Err(ProtocolError::KeyConfirmationFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_health() {
+        let alice = manager(ClientConfig::default()).await;
+        let bob = manager(ClientConfig::default()).await;
+        let (alice_session, bob_session) =
+            alice.pair_for_tests(&UserId::new(), &bob, &UserId::new());
+
+        let ciphertexts: Vec<_> = (0..5)
+            .map(|i| alice.encrypt(&alice_session, &[i]).unwrap().0)
+            .collect();
+        bob.decrypt(&bob_session, &ciphertexts[4]).unwrap();
+        bob.decrypt(&bob_session, &ciphertexts[1]).unwrap();
+
+        let health = bob.session_health(&bob_session).unwrap();
+        assert_eq!(health.skipped_key_count, 3);
+        assert_eq!(health.nr, 5);
+        assert_eq!(health.messages_since_dh_ratchet, 2);
+        assert!(!health.near_limit);
+        assert_eq!(alice.session_health(&alice_session).unwrap().ns, 5);
+
+        assert!(matches!(
+            bob.session_health(&SessionId::new()),
+            Err(ProtocolError::SessionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions() {
+        use std::sync::atomic::AtomicI64;
+
+        let now = Arc::new(AtomicI64::new(1_700_000_000_000));
+        let clock = {
+            let now = now.clone();
+            move || {
+                let now = now.clone();
+                move || Timestamp::from_millis(now.load(Ordering::SeqCst))
+            }
+        };
+        let advance = move |secs: i64| now.fetch_add(secs * 1000, Ordering::SeqCst);
+        let alice = manager(ClientConfig::default()).await.with_clock(clock());
+        let bob = manager(ClientConfig::default()).await.with_clock(clock());
+        let carol = manager(ClientConfig::default()).await.with_clock(clock());
+        let alice_id = UserId::new();
+        let (active, bob_session) = alice.pair_for_tests(&alice_id, &bob, &UserId::new());
+        let (idle, _) = alice.pair_for_tests(&alice_id, &carol, &UserId::new());
+        let last_activity = |manager: &SessionManager, id: &SessionId| {
+            manager.active_sessions.read()[id].session.last_activity_at
+        };
+
+        advance(600);
+        let (ciphertext, _, _) = alice.encrypt(&active, b"still here").unwrap();
+        assert_eq!(last_activity(&alice, &active), alice.now());
+        bob.decrypt(&bob_session, &ciphertext).unwrap();
+        assert_eq!(last_activity(&bob, &bob_session), bob.now());
+
+        let max_idle = Duration::from_secs(300);
+        assert_eq!(alice.idle_sessions(max_idle), vec![idle.clone()]);
+        assert!(bob.idle_sessions(max_idle).is_empty());
+
+        advance(301);
+        let mut idle_now = alice.idle_sessions(max_idle);
+        idle_now.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        let mut expected = vec![active, idle];
+        expected.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(idle_now, expected);
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_versions_rejected_cleanly() {
+        let mut alice = manager(ClientConfig::default()).await;
+        let bundle = DevicePreKeyBundle {
+            device_id: DeviceId::new(),
+            registration_id: 1,
+            identity_key: [0x01; 32],
+            signed_prekey_id: 1,
+            signed_prekey: [0x02; 32],
+            signed_prekey_signature: [0x03; 64],
+            one_time_prekey_id: None,
+            one_time_prekey: None,
+            versions: VersionRange { min: 99, max: 100 },
+            signed_prekey_timestamp: None,
+        };
+
+        let result = alice.establish_session(&UserId::new(), &bundle.device_id, &bundle).await;
+        assert!(matches!(result, Err(ProtocolError::IncompatibleVersions { .. })));
+        assert_eq!(alice.session_count(), 0);
+
+        let header = X3DHHeader {
+            identity_key: PublicKeyBytes::from([0x01; 32]),
+            ephemeral_key: PublicKeyBytes::from([0x02; 32]),
+            one_time_prekey_id: None,
+            session_nonce: [0x03; SESSION_NONCE_SIZE],
+        };
+        let result = alice
+            .accept_session(&UserId::new(), &DeviceId::new(), &header, 99)
+            .await;
+        assert!(matches!(result, Err(ProtocolError::IncompatibleVersions { .. })));
+        assert_eq!(alice.session_count(), 0);
+    }
 }
@@ -0,0 +1,241 @@
+//! Helpers for tests that need working sessions
+//!
+//! Enabled with the `testing` feature. [`establish_paired_clients`] returns
+//! two initialized clients that already share a session, connected through a
+//! [`LoopbackTransport`], so a test can go straight to exchanging messages:
+//!
+//! ```ignore
+//! use qiyashash_protocol::test_support::establish_paired_clients;
+//!
+//! let pair = establish_paired_clients().await;
+//! pair.send(&pair.alice, &pair.bob, "hi").await;
+//! let message = pair.receive(&pair.bob).await.unwrap();
+//! ```
+//!
+//! The session is installed directly rather than negotiated with X3DH, and
+//! uses a fixed root secret. Never use this outside tests.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use qiyashash_core::message::{Message, MessageId};
+use qiyashash_core::storage::memory::MemoryStorage;
+use qiyashash_core::types::{DeviceId, UserId};
+
+use crate::client::ProtocolClient;
+use crate::config::ClientConfig;
+use crate::error::{ProtocolError, Result};
+use crate::identity_service::IdentityServiceClient;
+use crate::protocol::{DevicePreKeyBundle, ProtocolMessage, ProtocolMessageType};
+use crate::transport::MessageTransport;
+
+/// Client type produced by the helpers
+pub type TestClient = ProtocolClient<MemoryStorage>;
+
+/// In-memory message queues, one per recipient
+#[derive(Default)]
+pub struct LoopbackTransport {
+    queues: Mutex<HashMap<UserId, VecDeque<ProtocolMessage>>>,
+}
+
+impl LoopbackTransport {
+    /// Create an empty transport
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Queue `message` for `recipient`
+    pub fn send(&self, recipient: &UserId, message: ProtocolMessage) {
+        self.queues
+            .lock()
+            .entry(recipient.clone())
+            .or_default()
+            .push_back(message);
+    }
+
+    /// Take the oldest message queued for `recipient`
+    pub fn receive(&self, recipient: &UserId) -> Option<ProtocolMessage> {
+        self.queues.lock().get_mut(recipient)?.pop_front()
+    }
+
+    /// Number of messages queued for `recipient`
+    pub fn pending(&self, recipient: &UserId) -> usize {
+        self.queues.lock().get(recipient).map_or(0, VecDeque::len)
+    }
+}
+
+#[async_trait]
+impl MessageTransport for LoopbackTransport {
+    async fn send(
+        &self,
+        recipient_id: &UserId,
+        _recipient_device_id: &DeviceId,
+        message: ProtocolMessage,
+    ) -> Result<()> {
+        LoopbackTransport::send(self, recipient_id, message);
+        Ok(())
+    }
+}
+
+/// Identity service serving bundles from memory
+#[derive(Default)]
+pub struct MockIdentityService {
+    bundles: Mutex<HashMap<(UserId, DeviceId), DevicePreKeyBundle>>,
+    unavailable: Mutex<bool>,
+}
+
+impl MockIdentityService {
+    /// Create a service with no bundles
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish `bundle` for `user_id`, replacing any bundle for the same device
+    pub fn publish(&self, user_id: &UserId, bundle: DevicePreKeyBundle) {
+        self.bundles
+            .lock()
+            .insert((user_id.clone(), bundle.device_id.clone()), bundle);
+    }
+
+    /// Make every lookup fail as if the service were down, or bring it back
+    pub fn set_unavailable(&self, unavailable: bool) {
+        *self.unavailable.lock() = unavailable;
+    }
+
+    /// Publish the current bundle of `client`
+    pub fn publish_client(&self, client: &TestClient) {
+        let bundle = client.device_prekey_bundle().expect("client is initialized");
+        self.publish(client.user_id(), bundle);
+    }
+}
+
+#[async_trait]
+impl IdentityServiceClient for MockIdentityService {
+    async fn fetch_bundle(&self, user_id: &UserId, device_id: &DeviceId) -> Result<DevicePreKeyBundle> {
+        if *self.unavailable.lock() {
+            return Err(ProtocolError::Internal("identity service unavailable".to_string()));
+        }
+        self.bundles
+            .lock()
+            .get(&(user_id.clone(), device_id.clone()))
+            .cloned()
+            .ok_or_else(|| {
+                ProtocolError::InvalidPreKeyBundle(format!(
+                    "no bundle for {} device {}",
+                    user_id, device_id
+                ))
+            })
+    }
+}
+
+/// Two clients sharing a session
+pub struct PairedClients {
+    /// Initiating side
+    pub alice: TestClient,
+    /// Responding side
+    pub bob: TestClient,
+    /// Transport connecting them
+    pub transport: Arc<LoopbackTransport>,
+}
+
+/// Create two initialized clients with a session between them
+pub async fn establish_paired_clients() -> PairedClients {
+    establish_paired_clients_with(ClientConfig::default()).await
+}
+
+/// Like [`establish_paired_clients`], with `config` for both clients
+pub async fn establish_paired_clients_with(config: ClientConfig) -> PairedClients {
+    let alice = ProtocolClient::new(config.clone(), MemoryStorage::new());
+    let bob = ProtocolClient::new(config, MemoryStorage::new());
+    alice.initialize().await.expect("initialize alice");
+    bob.initialize().await.expect("initialize bob");
+
+    alice.pair_for_tests(&bob).expect("pair clients");
+
+    PairedClients {
+        alice,
+        bob,
+        transport: LoopbackTransport::new(),
+    }
+}
+
+impl PairedClients {
+    /// Encrypt `text` from `from` to `to` and queue it on the transport
+    pub async fn send(&self, from: &TestClient, to: &TestClient, text: &str) -> MessageId {
+        let message = Message::text(
+            from.user_id().clone(),
+            from.device_id().clone(),
+            to.user_id().clone(),
+            text,
+        );
+        let envelope = from
+            .encrypt_message(to.user_id(), to.device_id(), &message)
+            .await
+            .expect("encrypt message");
+
+        self.transport.send(
+            to.user_id(),
+            ProtocolMessage::new(
+                ProtocolMessageType::EncryptedMessage(envelope),
+                from.user_id().clone(),
+                from.device_id().clone(),
+            ),
+        );
+        message.id
+    }
+
+    /// Take the next message queued for `client` and decrypt it
+    ///
+    /// Returns `None` when nothing is queued.
+    pub async fn receive(&self, client: &TestClient) -> Option<Result<Message>> {
+        let message = self.transport.receive(client.user_id())?;
+        let result = match message.message_type {
+            ProtocolMessageType::EncryptedMessage(envelope) => {
+                client
+                    .decrypt_message(&message.sender_id, &message.sender_device_id, &envelope)
+                    .await
+            }
+            _ => Err(ProtocolError::InvalidMessage(
+                "expected an encrypted message".to_string(),
+            )),
+        };
+        Some(result)
+    }
+
+    /// Send `text` from `from` to `to` and assert it arrives intact
+    pub async fn assert_delivers(&self, from: &TestClient, to: &TestClient, text: &str) -> Message {
+        let id = self.send(from, to, text).await;
+        let message = self
+            .receive(to)
+            .await
+            .expect("message was queued")
+            .expect("message decrypts");
+
+        assert_eq!(message.id, id);
+        assert_eq!(&message.sender_id, from.user_id());
+        assert_eq!(message.content_as_string().as_deref(), Some(text));
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_paired_clients_exchange_both_ways() {
+        let pair = establish_paired_clients().await;
+        assert!(pair.alice.is_ready() && pair.bob.is_ready());
+
+        pair.assert_delivers(&pair.alice, &pair.bob, "hello bob").await;
+        pair.assert_delivers(&pair.bob, &pair.alice, "hello alice").await;
+        pair.assert_delivers(&pair.alice, &pair.bob, "and again").await;
+
+        assert_eq!(pair.transport.pending(pair.alice.user_id()), 0);
+        assert_eq!(pair.transport.pending(pair.bob.user_id()), 0);
+        assert!(pair.receive(&pair.bob).await.is_none());
+    }
+}
@@ -0,0 +1,32 @@
+//! Message transport
+//!
+//! [`MessageTransport`] hands encrypted protocol messages to the network,
+//! whether a relay, the DHT or a direct connection. The client only needs it
+//! to re-send queued messages; see [`ProtocolClient::flush_pending`].
+//!
+//! [`ProtocolClient::flush_pending`]: crate::ProtocolClient::flush_pending
+
+use async_trait::async_trait;
+
+use qiyashash_core::types::{DeviceId, UserId};
+
+use crate::error::Result;
+use crate::protocol::ProtocolMessage;
+
+/// Sends protocol messages to other devices
+#[async_trait]
+pub trait MessageTransport: Send + Sync {
+    /// Send `message` to `recipient_device_id` of `recipient_id`
+    ///
+    /// Returns once the network has accepted the message. Failures should be
+    /// reported as [`ProtocolError::Delivery`] so the client can tell
+    /// whether a retry could succeed.
+    ///
+    /// [`ProtocolError::Delivery`]: crate::ProtocolError::Delivery
+    async fn send(
+        &self,
+        recipient_id: &UserId,
+        recipient_device_id: &DeviceId,
+        message: ProtocolMessage,
+    ) -> Result<()>;
+}
@@ -1,14 +1,19 @@
 //! Relay client for distributing and retrieving message blobs
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::Arc;
-use parking_lot::RwLock;
-use rand::seq::SliceRandom;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 use tracing::{debug, info, warn, error};
 
+use qiyashash_core::message::DeliveryFailure;
+
 use crate::config::{RelayConfig, RelayNodeInfo};
 use crate::error::{RelayError, Result};
+use crate::selection::RelaySelectionStrategy;
 use crate::storage::BlobMetadata;
+use crate::transport::{RelayTransport, SimulatedTransport};
 
 /// Blob distribution result
 #[derive(Clone, Debug)]
@@ -21,10 +26,131 @@ pub struct DistributionResult {
     pub retrieval_tokens: HashMap<String, String>,
 }
 
+/// Where a delivery would go, computed without sending anything
+#[derive(Clone, Debug)]
+pub struct DeliveryPlan {
+    /// Recipient the plan is for
+    pub recipient: String,
+    /// Strategy used to choose relays
+    pub strategy: RelaySelectionStrategy,
+    /// Number of shards the message would be split into
+    pub shard_count: usize,
+    /// Chosen relays, in shard order
+    pub relays: Vec<String>,
+    /// Relays per region (`None` for relays without a region)
+    pub regions: BTreeMap<Option<String>, usize>,
+    /// Shard index to relay ID; shards without a relay are missing
+    pub shard_to_relay: BTreeMap<usize, String>,
+    /// Problems a real delivery would run into
+    pub warnings: Vec<String>,
+}
+
+impl DeliveryPlan {
+    /// Whether every shard has a relay
+    pub fn is_complete(&self) -> bool {
+        self.shard_to_relay.len() == self.shard_count
+    }
+}
+
+/// How soon a queued delivery should go out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Priority {
+    /// Interactive messages, sent first
+    #[default]
+    Urgent,
+    /// Backfill and sync payloads, sent when nothing urgent is waiting
+    Bulk,
+}
+
+/// Outcome of one dispatch from the pending queue
+#[derive(Debug)]
+pub struct DeliveryReport {
+    /// Blob that was dispatched
+    pub blob_id: String,
+    /// Distribution result, or why this attempt failed
+    pub result: Result<DistributionResult>,
+    /// How the failure should be recorded on the message, if it failed
+    pub failure: Option<DeliveryFailure>,
+    /// Attempts made so far, including this one
+    pub attempts: u32,
+    /// When a failed delivery will be tried again; `None` once it
+    /// succeeded or was given up on
+    pub retry_at: Option<Instant>,
+}
+
+/// A blob waiting to be distributed
+struct PendingDelivery {
+    blob_id: String,
+    data: Vec<u8>,
+    priority: Priority,
+    /// Failed attempts so far
+    attempts: u32,
+    /// Backoff after a failure; not sent before this
+    not_before: Option<Instant>,
+}
+
+impl PendingDelivery {
+    fn is_due(&self, now: Instant) -> bool {
+        self.not_before.map_or(true, |at| at <= now)
+    }
+}
+
+/// Pending deliveries, urgent first
+///
+/// After `max_urgent_streak` urgent deliveries in a row, a waiting bulk
+/// delivery goes next so a steady urgent stream can't starve it. Deliveries
+/// backing off after a failure are skipped until they are due.
+struct DeliveryQueue {
+    urgent: VecDeque<PendingDelivery>,
+    bulk: VecDeque<PendingDelivery>,
+    urgent_streak: usize,
+    max_urgent_streak: usize,
+}
+
+impl DeliveryQueue {
+    fn new(max_urgent_streak: usize) -> Self {
+        Self {
+            urgent: VecDeque::new(),
+            bulk: VecDeque::new(),
+            urgent_streak: 0,
+            max_urgent_streak,
+        }
+    }
+
+    fn push(&mut self, delivery: PendingDelivery) {
+        match delivery.priority {
+            Priority::Urgent => self.urgent.push_back(delivery),
+            Priority::Bulk => self.bulk.push_back(delivery),
+        }
+    }
+
+    fn pop(&mut self, now: Instant) -> Option<PendingDelivery> {
+        let urgent = self.urgent.iter().position(|d| d.is_due(now));
+        let bulk = self.bulk.iter().position(|d| d.is_due(now));
+
+        let bulk_due = self.urgent_streak >= self.max_urgent_streak && bulk.is_some();
+        if !bulk_due {
+            if let Some(index) = urgent {
+                self.urgent_streak += 1;
+                return self.urgent.remove(index);
+            }
+        }
+
+        self.urgent_streak = 0;
+        self.bulk.remove(bulk?)
+    }
+
+    fn len(&self) -> usize {
+        self.urgent.len() + self.bulk.len()
+    }
+}
+
 /// Relay client for blob distribution
 pub struct RelayClient {
     config: RelayConfig,
     connections: RwLock<HashMap<String, RelayConnection>>,
+    transport: Arc<dyn RelayTransport>,
+    pending: Mutex<DeliveryQueue>,
 }
 
 /// Connection to a relay node
@@ -37,9 +163,17 @@ struct RelayConnection {
 impl RelayClient {
     /// Create a new relay client
     pub fn new(config: RelayConfig) -> Self {
+        Self::with_transport(config, Arc::new(SimulatedTransport))
+    }
+
+    /// Create a relay client that sends through `transport`
+    pub fn with_transport(config: RelayConfig, transport: Arc<dyn RelayTransport>) -> Self {
+        let pending = Mutex::new(DeliveryQueue::new(config.max_urgent_streak.max(1)));
         Self {
             config,
             connections: RwLock::new(HashMap::new()),
+            transport,
+            pending,
         }
     }
 
@@ -94,6 +228,13 @@ impl RelayClient {
     pub async fn distribute(&self, blob_id: &str, data: Vec<u8>) -> Result<DistributionResult> {
         debug!("Distributing blob {} ({} bytes)", blob_id, data.len());
 
+        if data.len() > self.config.max_blob_size {
+            return Err(RelayError::BlobTooLarge {
+                size: data.len(),
+                max: self.config.max_blob_size,
+            });
+        }
+
         // Select relays
         let selected_relays = self.select_relays(self.config.relay_count)?;
 
@@ -139,6 +280,79 @@ impl RelayClient {
         })
     }
 
+    /// Queue a blob for [`process_pending`](Self::process_pending)
+    pub fn enqueue(&self, blob_id: impl Into<String>, data: Vec<u8>, priority: Priority) {
+        let blob_id = blob_id.into();
+        debug!("Queued blob {} ({:?})", blob_id, priority);
+        self.pending.lock().push(PendingDelivery {
+            blob_id,
+            data,
+            priority,
+            attempts: 0,
+            not_before: None,
+        });
+    }
+
+    /// Number of queued deliveries
+    pub fn pending_count(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Distribute up to `budget` due blobs, urgent ones first
+    ///
+    /// Returns a report for each dispatch, in order. A delivery that failed
+    /// for a [retryable](DeliveryFailure::is_retryable) reason is requeued
+    /// with the configured backoff until it has been tried
+    /// `retry.max_retries + 1` times; its last report has no `retry_at`.
+    pub async fn process_pending(&self, budget: usize) -> Vec<DeliveryReport> {
+        let mut reports = Vec::new();
+
+        while reports.len() < budget {
+            let Some(mut delivery) = self.pending.lock().pop(Instant::now()) else {
+                break;
+            };
+
+            let result = self.distribute(&delivery.blob_id, delivery.data.clone()).await;
+            delivery.attempts += 1;
+            let failure = result.as_ref().err().map(RelayError::delivery_failure);
+            let mut retry_at = None;
+
+            if let (Err(e), Some(failure)) = (&result, failure) {
+                if !failure.is_retryable() {
+                    error!("Delivery of blob {} failed permanently: {}", delivery.blob_id, e);
+                } else if delivery.attempts <= self.config.retry.max_retries {
+                    let at = Instant::now()
+                        + self.config.retry.delay_for_attempt(delivery.attempts - 1);
+                    warn!(
+                        "Delivery of blob {} failed (attempt {}), retrying: {}",
+                        delivery.blob_id, delivery.attempts, e
+                    );
+                    delivery.not_before = Some(at);
+                    retry_at = Some(at);
+                } else {
+                    error!(
+                        "Giving up on blob {} after {} attempts: {}",
+                        delivery.blob_id, delivery.attempts, e
+                    );
+                }
+            }
+
+            let report = DeliveryReport {
+                blob_id: delivery.blob_id.clone(),
+                result,
+                failure,
+                attempts: delivery.attempts,
+                retry_at,
+            };
+            if retry_at.is_some() {
+                self.pending.lock().push(delivery);
+            }
+            reports.push(report);
+        }
+
+        reports
+    }
+
     /// Retrieve a blob from relays
     pub async fn retrieve(&self, distribution: &DistributionResult) -> Result<Vec<u8>> {
         debug!("Retrieving blob {}", distribution.blob_id);
@@ -200,16 +414,59 @@ impl RelayClient {
         self.connections.read().values().filter(|c| c.connected).count()
     }
 
+    /// Preview which relays a delivery to `recipient` would use
+    ///
+    /// Runs the same selection as [`distribute`](Self::distribute) over the
+    /// currently connected relays, but uploads nothing.
+    pub fn plan_delivery(&self, recipient: &str, strategy: RelaySelectionStrategy) -> DeliveryPlan {
+        let shard_count = self.config.relay_count;
+        let available = self.available_relays();
+        let selected = strategy.select(&available, shard_count);
+
+        let mut warnings = Vec::new();
+        if available.len() < shard_count {
+            warnings.push(format!(
+                "only {} relays available for {} shards; delivery would fail",
+                available.len(),
+                shard_count
+            ));
+        }
+
+        let mut regions = BTreeMap::new();
+        for node in &selected {
+            *regions.entry(node.region.clone()).or_insert(0) += 1;
+        }
+        if selected.len() > 1 && regions.len() == 1 {
+            warnings.push("all shards would land in a single region".to_string());
+        }
+
+        let relays: Vec<String> = selected.into_iter().map(|n| n.id).collect();
+        let shard_to_relay = relays.iter().cloned().enumerate().collect();
+
+        DeliveryPlan {
+            recipient: recipient.to_string(),
+            strategy,
+            shard_count,
+            relays,
+            regions,
+            shard_to_relay,
+            warnings,
+        }
+    }
+
     // Helper methods
 
-    fn select_relays(&self, count: usize) -> Result<Vec<RelayNodeInfo>> {
-        let connections = self.connections.read();
-        
-        let mut available: Vec<_> = connections
+    fn available_relays(&self) -> Vec<RelayNodeInfo> {
+        self.connections
+            .read()
             .values()
             .filter(|c| c.connected)
             .map(|c| c.node.clone())
-            .collect();
+            .collect()
+    }
+
+    fn select_relays(&self, count: usize) -> Result<Vec<RelayNodeInfo>> {
+        let available = self.available_relays();
 
         if available.len() < count {
             return Err(RelayError::NotEnoughRelays {
@@ -218,17 +475,7 @@ impl RelayClient {
             });
         }
 
-        // Sort by priority and shuffle within same priority
-        available.sort_by_key(|n| n.priority);
-        
-        // Take required count
-        available.truncate(count);
-        
-        // Shuffle for randomness
-        let mut rng = rand::thread_rng();
-        available.shuffle(&mut rng);
-
-        Ok(available)
+        Ok(self.config.selection_strategy.select(&available, count))
     }
 
     fn split_data(&self, data: &[u8], parts: usize) -> Vec<Vec<u8>> {
@@ -246,26 +493,22 @@ impl RelayClient {
     }
 
     async fn store_on_relay(&self, relay_id: &str, part_id: &str, data: Vec<u8>) -> Result<String> {
-        // In production, send via QUIC
-        // Return retrieval token
-        let token = format!("token-{}-{}", relay_id, part_id);
-        Ok(token)
+        self.transport.store(relay_id, part_id, data).await
     }
 
     async fn retrieve_from_relay(&self, relay_id: &str, part_id: &str, token: &str) -> Result<Vec<u8>> {
-        // In production, request via QUIC
-        Err(RelayError::NotConnected)
+        self.transport.retrieve(relay_id, part_id, token).await
     }
 
     async fn delete_from_relay(&self, relay_id: &str, part_id: &str) -> Result<()> {
-        // In production, send delete request via QUIC
-        Ok(())
+        self.transport.delete(relay_id, part_id).await
     }
 }
 
 /// Builder for RelayClient
 pub struct RelayClientBuilder {
     config: RelayConfig,
+    transport: Option<Arc<dyn RelayTransport>>,
 }
 
 impl RelayClientBuilder {
@@ -273,6 +516,7 @@ impl RelayClientBuilder {
     pub fn new() -> Self {
         Self {
             config: RelayConfig::default(),
+            transport: None,
         }
     }
 
@@ -288,15 +532,36 @@ impl RelayClientBuilder {
         self
     }
 
+    /// Set relay selection strategy
+    pub fn selection_strategy(mut self, strategy: RelaySelectionStrategy) -> Self {
+        self.config.selection_strategy = strategy;
+        self
+    }
+
     /// Set message expiry
     pub fn message_expiry_secs(mut self, secs: u64) -> Self {
         self.config.message_expiry_secs = secs;
         self
     }
 
+    /// Set how many urgent deliveries may go before a waiting bulk one
+    pub fn max_urgent_streak(mut self, streak: usize) -> Self {
+        self.config.max_urgent_streak = streak;
+        self
+    }
+
+    /// Send through `transport` instead of the default
+    pub fn transport(mut self, transport: Arc<dyn RelayTransport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
     /// Build the client
     pub fn build(self) -> RelayClient {
-        RelayClient::new(self.config)
+        match self.transport {
+            Some(transport) => RelayClient::with_transport(self.config, transport),
+            None => RelayClient::new(self.config),
+        }
     }
 }
 
@@ -309,6 +574,49 @@ impl Default for RelayClientBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts every call that would hit the network
+    #[derive(Default)]
+    struct CountingTransport {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl RelayTransport for CountingTransport {
+        async fn store(&self, relay_id: &str, part_id: &str, _data: Vec<u8>) -> Result<String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(format!("{}:{}", relay_id, part_id))
+        }
+
+        async fn retrieve(&self, _relay_id: &str, _part_id: &str, _token: &str) -> Result<Vec<u8>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(RelayError::NotConnected)
+        }
+
+        async fn delete(&self, _relay_id: &str, _part_id: &str) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    async fn client_with_regions(
+        regions: &[&str],
+        per_region: usize,
+        transport: Arc<CountingTransport>,
+    ) -> RelayClient {
+        let mut builder = RelayClientBuilder::new().transport(transport);
+        for region in regions {
+            for i in 0..per_region {
+                let mut node = RelayNodeInfo::new(format!("{}-{}", region, i), "addr", [0u8; 32]);
+                node.region = Some(region.to_string());
+                builder = builder.add_relay(node);
+            }
+        }
+        let client = builder.build();
+        client.connect().await.unwrap();
+        client
+    }
 
     #[test]
     fn test_builder() {
@@ -318,6 +626,29 @@ mod tests {
             .build();
 
         assert_eq!(client.config.relay_count, 3);
+        assert_eq!(
+            client.config.selection_strategy,
+            RelaySelectionStrategy::SpreadRegions
+        );
+    }
+
+    #[tokio::test]
+    async fn test_distribute_spreads_regions() {
+        let mut builder = RelayClientBuilder::new().relay_count(3);
+        for region in ["eu", "us", "asia"] {
+            for i in 0..2 {
+                let mut node = RelayNodeInfo::new(format!("{}-{}", region, i), "addr", [0u8; 32]);
+                node.region = Some(region.to_string());
+                builder = builder.add_relay(node);
+            }
+        }
+        let client = builder.build();
+        client.connect().await.unwrap();
+
+        let selected = client.select_relays(3).unwrap();
+        let regions: std::collections::HashSet<_> =
+            selected.iter().map(|n| n.region.clone()).collect();
+        assert_eq!(regions.len(), 3);
     }
 
     #[test]
@@ -341,4 +672,149 @@ mod tests {
         
         assert_eq!(original, reconstructed);
     }
+
+    #[tokio::test]
+    async fn test_plan_delivery_uses_default_relay_count() {
+        let transport = Arc::new(CountingTransport::default());
+        let regions = ["eu", "us", "asia", "sa", "af", "oc"];
+        let client = client_with_regions(&regions, 2, transport.clone()).await;
+
+        let plan = client.plan_delivery("bob", RelaySelectionStrategy::SpreadRegions);
+        assert_eq!(plan.recipient, "bob");
+        assert_eq!(plan.shard_count, crate::DEFAULT_RELAY_COUNT);
+        assert_eq!(plan.relays.len(), crate::DEFAULT_RELAY_COUNT);
+        assert_eq!(plan.regions.len(), crate::DEFAULT_RELAY_COUNT);
+        assert!(plan.is_complete());
+        assert!(plan.warnings.is_empty());
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 0);
+
+        // Planning is dry; distributing is not
+        client.distribute("blob", vec![1u8; 64]).await.unwrap();
+        assert_eq!(transport.calls.load(Ordering::SeqCst), crate::DEFAULT_RELAY_COUNT);
+    }
+
+    fn dispatched(reports: &[DeliveryReport]) -> Vec<&str> {
+        reports
+            .iter()
+            .map(|report| {
+                assert!(report.result.is_ok());
+                report.blob_id.as_str()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_urgent_deliveries_go_first() {
+        let transport = Arc::new(CountingTransport::default());
+        let client = client_with_regions(&["eu", "us", "asia", "sa", "af"], 1, transport).await;
+
+        client.enqueue("backfill-1", vec![1u8; 64], Priority::Bulk);
+        client.enqueue("backfill-2", vec![1u8; 64], Priority::Bulk);
+        client.enqueue("hello", vec![2u8; 64], Priority::Urgent);
+        client.enqueue("are you there", vec![2u8; 64], Priority::Urgent);
+
+        // Only room for two: both urgent ones win
+        let results = client.process_pending(2).await;
+        assert_eq!(dispatched(&results), vec!["hello", "are you there"]);
+        assert_eq!(client.pending_count(), 2);
+
+        client.enqueue("reply", vec![2u8; 64], Priority::Urgent);
+        let results = client.process_pending(10).await;
+        assert_eq!(dispatched(&results), vec!["reply", "backfill-1", "backfill-2"]);
+        assert_eq!(client.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_deliveries_are_not_starved() {
+        let transport = Arc::new(CountingTransport::default());
+        let mut builder = RelayClientBuilder::new().transport(transport).max_urgent_streak(2);
+        for region in ["eu", "us", "asia", "sa", "af"] {
+            builder = builder.add_relay(RelayNodeInfo::new(region, "addr", [0u8; 32]));
+        }
+        let client = builder.build();
+        client.connect().await.unwrap();
+
+        client.enqueue("sync", vec![1u8; 64], Priority::Bulk);
+        for i in 0..5 {
+            client.enqueue(format!("urgent-{}", i), vec![2u8; 64], Priority::Urgent);
+        }
+
+        let results = client.process_pending(4).await;
+        assert_eq!(dispatched(&results), vec!["urgent-0", "urgent-1", "sync", "urgent-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_plan_delivery_warns_when_short_of_relays() {
+        let transport = Arc::new(CountingTransport::default());
+        let client = client_with_regions(&["eu", "us"], 1, transport.clone()).await;
+
+        let plan = client.plan_delivery("bob", RelaySelectionStrategy::Random);
+        assert_eq!(plan.relays.len(), 2);
+        assert!(!plan.is_complete());
+        assert!(plan.warnings.iter().any(|w| w.contains("only 2 relays")));
+        assert_eq!(transport.calls.load(Ordering::SeqCst), 0);
+    }
+
+    /// Fails every store
+    struct FailingTransport;
+
+    #[async_trait::async_trait]
+    impl RelayTransport for FailingTransport {
+        async fn store(&self, _relay_id: &str, _part_id: &str, _data: Vec<u8>) -> Result<String> {
+            Err(RelayError::Network("unreachable".to_string()))
+        }
+
+        async fn retrieve(&self, _relay_id: &str, _part_id: &str, _token: &str) -> Result<Vec<u8>> {
+            Err(RelayError::Network("unreachable".to_string()))
+        }
+
+        async fn delete(&self, _relay_id: &str, _part_id: &str) -> Result<()> {
+            Err(RelayError::Network("unreachable".to_string()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_deliveries_are_retried_with_backoff() {
+        let mut builder = RelayClientBuilder::new().transport(Arc::new(FailingTransport));
+        for region in ["eu", "us", "asia", "sa", "af"] {
+            builder = builder.add_relay(RelayNodeInfo::new(region, "addr", [0u8; 32]));
+        }
+        let client = builder.build();
+        client.connect().await.unwrap();
+        let max_retries = client.config.retry.max_retries;
+
+        client.enqueue("flaky", vec![1u8; 64], Priority::Urgent);
+        for attempt in 1..=max_retries {
+            let reports = client.process_pending(10).await;
+            assert_eq!(reports.len(), 1);
+            assert!(reports[0].result.is_err());
+            assert_eq!(reports[0].failure, Some(DeliveryFailure::RelayUnavailable));
+            assert_eq!(reports[0].attempts, attempt);
+            let retry_at = reports[0].retry_at.expect("requeued");
+            assert_eq!(client.pending_count(), 1);
+
+            // Not due yet
+            assert!(client.process_pending(10).await.is_empty());
+            tokio::time::advance(retry_at - Instant::now()).await;
+        }
+
+        let reports = client.process_pending(10).await;
+        assert_eq!(reports[0].attempts, max_retries + 1);
+        assert!(reports[0].retry_at.is_none());
+        assert_eq!(client.pending_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failures_are_not_retried() {
+        let transport = Arc::new(CountingTransport::default());
+        let client = client_with_regions(&["eu", "us", "asia", "sa", "af"], 1, transport).await;
+
+        client.enqueue("huge", vec![0u8; crate::MAX_BLOB_SIZE + 1], Priority::Urgent);
+        let reports = client.process_pending(10).await;
+        assert_eq!(reports.len(), 1);
+        assert!(matches!(reports[0].result, Err(RelayError::BlobTooLarge { .. })));
+        assert_eq!(reports[0].failure, Some(DeliveryFailure::MessageTooLarge));
+        assert!(reports[0].retry_at.is_none());
+        assert_eq!(client.pending_count(), 0);
+    }
 }
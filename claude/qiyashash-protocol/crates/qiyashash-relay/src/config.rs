@@ -3,6 +3,8 @@
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+use crate::selection::RelaySelectionStrategy;
+
 /// Relay node configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RelayConfig {
@@ -22,6 +24,16 @@ pub struct RelayConfig {
     pub retry: RetryConfig,
     /// TLS configuration
     pub tls: TlsConfig,
+    /// How relays are chosen for each message
+    #[serde(default)]
+    pub selection_strategy: RelaySelectionStrategy,
+    /// Urgent deliveries sent in a row before a waiting bulk one goes
+    #[serde(default = "default_max_urgent_streak")]
+    pub max_urgent_streak: usize,
+}
+
+fn default_max_urgent_streak() -> usize {
+    8
 }
 
 impl Default for RelayConfig {
@@ -35,6 +47,8 @@ impl Default for RelayConfig {
             max_blob_size: crate::MAX_BLOB_SIZE,
             retry: RetryConfig::default(),
             tls: TlsConfig::default(),
+            selection_strategy: RelaySelectionStrategy::default(),
+            max_urgent_streak: default_max_urgent_streak(),
         }
     }
 }
@@ -66,6 +80,9 @@ impl RelayConfig {
         if self.max_blob_size == 0 {
             return Err("max_blob_size must be > 0".to_string());
         }
+        if self.max_urgent_streak == 0 {
+            return Err("max_urgent_streak must be > 0".to_string());
+        }
         Ok(())
     }
 }
@@ -84,6 +101,12 @@ pub struct RelayNodeInfo {
     pub region: Option<String>,
     /// Priority (lower = preferred)
     pub priority: u32,
+    /// Load reported by the coordination service
+    #[serde(default)]
+    pub current_load: u32,
+    /// Capacity reported by the coordination service (0 = unknown)
+    #[serde(default)]
+    pub capacity: u32,
 }
 
 impl RelayNodeInfo {
@@ -95,6 +118,17 @@ impl RelayNodeInfo {
             public_key,
             region: None,
             priority: 100,
+            current_load: 0,
+            capacity: 0,
+        }
+    }
+
+    /// Fraction of capacity in use; unknown capacity counts as idle
+    pub fn load_ratio(&self) -> f64 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            self.current_load as f64 / self.capacity as f64
         }
     }
 }
@@ -169,6 +203,9 @@ pub struct RelayServerConfig {
     pub max_storage_size: u64,
     /// Cleanup interval in seconds
     pub cleanup_interval_secs: u64,
+    /// Re-hash blobs on every read and refuse to serve corrupted data
+    #[serde(default)]
+    pub verify_on_read: bool,
     /// TLS certificate path
     pub tls_cert_path: String,
     /// TLS key path
@@ -185,6 +222,7 @@ impl Default for RelayServerConfig {
             storage_path: "./relay-data".to_string(),
             max_storage_size: 10 * 1024 * 1024 * 1024, // 10 GB
             cleanup_interval_secs: 3600,
+            verify_on_read: false,
             tls_cert_path: "./certs/relay.crt".to_string(),
             tls_key_path: "./certs/relay.key".to_string(),
             rate_limit: RateLimitConfig::default(),
@@ -201,6 +239,13 @@ pub struct RateLimitConfig {
     pub burst_size: u32,
     /// Storage quota per IP in bytes
     pub storage_quota_per_ip: u64,
+    /// Bytes that may be held for a single recipient
+    #[serde(default = "default_storage_quota_per_recipient")]
+    pub storage_quota_per_recipient: u64,
+}
+
+fn default_storage_quota_per_recipient() -> u64 {
+    50 * 1024 * 1024 // 50 MB
 }
 
 impl Default for RateLimitConfig {
@@ -209,6 +254,7 @@ impl Default for RateLimitConfig {
             requests_per_second: 10,
             burst_size: 50,
             storage_quota_per_ip: 100 * 1024 * 1024, // 100 MB
+            storage_quota_per_recipient: default_storage_quota_per_recipient(),
         }
     }
 }
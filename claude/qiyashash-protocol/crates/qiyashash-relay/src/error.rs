@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+use qiyashash_core::message::DeliveryFailure;
+
 /// Result type alias
 pub type Result<T> = std::result::Result<T, RelayError>;
 
@@ -36,6 +38,18 @@ pub enum RelayError {
     #[error("Invalid blob: {0}")]
     InvalidBlob(String),
 
+    /// Stored data no longer matches its hash
+    #[error("Integrity check failed for blob {0}")]
+    IntegrityFailure(String),
+
+    /// Missing or invalid upload capability or retrieval token
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// Recipient has no storage quota left
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
     /// Timeout
     #[error("Operation timed out")]
     Timeout,
@@ -61,6 +75,30 @@ pub enum RelayError {
     Internal(String),
 }
 
+impl RelayError {
+    /// How this error should be reported on the message that failed
+    pub fn delivery_failure(&self) -> DeliveryFailure {
+        match self {
+            Self::NotConnected | Self::ConnectionFailed(_) | Self::Network(_) => {
+                DeliveryFailure::NoNetwork
+            }
+            Self::NotEnoughRelays { .. } => DeliveryFailure::RelayUnavailable,
+            Self::Timeout => DeliveryFailure::Timeout,
+            Self::RateLimited { .. } => DeliveryFailure::RateLimited,
+            Self::BlobTooLarge { .. } => DeliveryFailure::MessageTooLarge,
+            Self::BlobNotFound(_)
+            | Self::BlobExpired
+            | Self::Storage(_)
+            | Self::InvalidBlob(_)
+            | Self::IntegrityFailure(_)
+            | Self::Unauthorized(_)
+            | Self::QuotaExceeded(_)
+            | Self::Tls(_)
+            | Self::Internal(_) => DeliveryFailure::Rejected,
+        }
+    }
+}
+
 impl From<std::io::Error> for RelayError {
     fn from(err: std::io::Error) -> Self {
         RelayError::Network(err.to_string())
@@ -17,11 +17,15 @@
 pub mod client;
 pub mod config;
 pub mod error;
+pub mod selection;
 pub mod server;
 pub mod storage;
+pub mod transport;
 
+pub use client::Priority;
 pub use config::RelayConfig;
 pub use error::{RelayError, Result};
+pub use selection::RelaySelectionStrategy;
 
 /// Default number of relay nodes for message distribution
 pub const DEFAULT_RELAY_COUNT: usize = 5;
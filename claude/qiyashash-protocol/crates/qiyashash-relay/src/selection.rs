@@ -0,0 +1,194 @@
+//! Relay selection strategies
+//!
+//! Decides which relays receive a message's shards. Spreading shards across
+//! regions means no single jurisdiction can seize enough of them to rebuild
+//! a message.
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::config::RelayNodeInfo;
+
+/// How relays are chosen for a message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelaySelectionStrategy {
+    /// Maximize the number of distinct regions, reusing regions only when
+    /// there are fewer regions than shards
+    #[default]
+    SpreadRegions,
+    /// Prefer the least loaded relays
+    LowestLoad,
+    /// Uniformly random relays
+    Random,
+}
+
+impl RelaySelectionStrategy {
+    /// Pick up to `count` relays from `nodes`
+    pub fn select(&self, nodes: &[RelayNodeInfo], count: usize) -> Vec<RelayNodeInfo> {
+        self.select_with_rng(nodes, count, &mut rand::thread_rng())
+    }
+
+    /// Pick up to `count` relays from `nodes` using the given RNG
+    pub fn select_with_rng<R: Rng + ?Sized>(
+        &self,
+        nodes: &[RelayNodeInfo],
+        count: usize,
+        rng: &mut R,
+    ) -> Vec<RelayNodeInfo> {
+        let mut candidates = nodes.to_vec();
+        // Shuffle first so the stable sorts below break ties randomly
+        candidates.shuffle(rng);
+
+        let mut selected = match self {
+            Self::SpreadRegions => spread_regions(candidates, count),
+            Self::LowestLoad => {
+                candidates.sort_by(|a, b| {
+                    a.load_ratio()
+                        .total_cmp(&b.load_ratio())
+                        .then(a.priority.cmp(&b.priority))
+                });
+                candidates.truncate(count);
+                candidates
+            }
+            Self::Random => {
+                candidates.truncate(count);
+                candidates
+            }
+        };
+
+        // Shard order shouldn't reveal which relay was preferred
+        selected.shuffle(rng);
+        selected
+    }
+}
+
+/// Round-robin across regions, best node of each region first
+///
+/// Nodes without a region share one bucket, since they may well be
+/// co-located.
+fn spread_regions(candidates: Vec<RelayNodeInfo>, count: usize) -> Vec<RelayNodeInfo> {
+    let mut by_region: BTreeMap<Option<String>, Vec<RelayNodeInfo>> = BTreeMap::new();
+    for node in candidates {
+        by_region.entry(node.region.clone()).or_default().push(node);
+    }
+
+    let mut regions: Vec<Vec<RelayNodeInfo>> = by_region
+        .into_values()
+        .map(|mut nodes| {
+            nodes.sort_by_key(|n| n.priority);
+            nodes.reverse(); // pop() yields the preferred node
+            nodes
+        })
+        .collect();
+    // Regions with the best available node go first
+    regions.sort_by_key(|nodes| nodes.last().map(|n| n.priority));
+
+    let mut selected = Vec::with_capacity(count);
+    while selected.len() < count {
+        let before = selected.len();
+        for nodes in regions.iter_mut() {
+            if selected.len() == count {
+                break;
+            }
+            if let Some(node) = nodes.pop() {
+                selected.push(node);
+            }
+        }
+        if selected.len() == before {
+            break; // every region exhausted
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn node(id: &str, region: Option<&str>) -> RelayNodeInfo {
+        let mut node = RelayNodeInfo::new(id, format!("{}:4433", id), [0u8; 32]);
+        node.region = region.map(str::to_string);
+        node
+    }
+
+    fn regions(nodes: &[RelayNodeInfo]) -> Vec<Option<String>> {
+        nodes.iter().map(|n| n.region.clone()).collect()
+    }
+
+    #[test]
+    fn test_spread_regions_never_repeats_when_enough_regions() {
+        let mut nodes = Vec::new();
+        for region in ["eu", "us", "asia", "sa", "af", "oc"] {
+            for i in 0..4 {
+                nodes.push(node(&format!("{}-{}", region, i), Some(region)));
+            }
+        }
+
+        for count in 1..=6 {
+            for _ in 0..50 {
+                let selected = RelaySelectionStrategy::SpreadRegions.select(&nodes, count);
+                assert_eq!(selected.len(), count);
+                let distinct: HashSet<_> = regions(&selected).into_iter().collect();
+                assert_eq!(distinct.len(), count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_spread_regions_falls_back_evenly() {
+        let nodes = vec![
+            node("eu-1", Some("eu")),
+            node("eu-2", Some("eu")),
+            node("eu-3", Some("eu")),
+            node("us-1", Some("us")),
+            node("us-2", Some("us")),
+        ];
+
+        let selected = RelaySelectionStrategy::SpreadRegions.select(&nodes, 4);
+        assert_eq!(selected.len(), 4);
+        let eu = selected.iter().filter(|n| n.region.as_deref() == Some("eu")).count();
+        assert_eq!(eu, 2);
+
+        // Asking for more than exist returns everything once
+        let selected = RelaySelectionStrategy::SpreadRegions.select(&nodes, 10);
+        let ids: HashSet<_> = selected.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn test_spread_regions_prefers_priority_within_region() {
+        let mut preferred = node("eu-preferred", Some("eu"));
+        preferred.priority = 1;
+        let nodes = vec![node("eu-other", Some("eu")), preferred, node("us-1", Some("us"))];
+
+        for _ in 0..20 {
+            let selected = RelaySelectionStrategy::SpreadRegions.select(&nodes, 2);
+            assert!(selected.iter().any(|n| n.id == "eu-preferred"));
+        }
+    }
+
+    #[test]
+    fn test_lowest_load() {
+        let mut nodes: Vec<_> = (0..5).map(|i| node(&format!("n{}", i), None)).collect();
+        for (i, node) in nodes.iter_mut().enumerate() {
+            node.capacity = 100;
+            node.current_load = (i as u32) * 20;
+        }
+
+        let selected = RelaySelectionStrategy::LowestLoad.select(&nodes, 2);
+        let ids: HashSet<_> = selected.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, HashSet::from(["n0", "n1"]));
+    }
+
+    #[test]
+    fn test_random_selects_distinct_nodes() {
+        let nodes: Vec<_> = (0..5).map(|i| node(&format!("n{}", i), None)).collect();
+        let selected = RelaySelectionStrategy::Random.select(&nodes, 3);
+        let ids: HashSet<_> = selected.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(ids.len(), 3);
+    }
+}
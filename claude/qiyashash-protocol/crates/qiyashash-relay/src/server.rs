@@ -1,14 +1,64 @@
 //! Relay server for storing and serving message blobs
-
+//!
+//! [`configure_http`] exposes the server over HTTP:
+//!
+//! - `POST /blobs` stores the request body for the recipient named in
+//!   [`RECIPIENT_HEADER`], authorized by the [`CAPABILITY_HEADER`] the
+//!   recipient handed out, and returns the blob ID and retrieval token
+//! - `GET /blobs/{id}` returns the blob, given its token in [`TOKEN_HEADER`]
+//! - `DELETE /blobs/{id}` removes it, given the same token
+//! - `POST /capabilities` returns the upload capability for the recipient
+//!   whose secret is in [`RECIPIENT_SECRET_HEADER`]
+//! - `DELETE /capabilities` revokes every capability issued for that
+//!   recipient and returns a new one
+//!
+//! A recipient ID is the [hash of the recipient's secret](recipient_id), so
+//! only the holder of the secret can obtain or revoke its capabilities.
+//! Blobs are capped at [`MAX_BLOB_SIZE`] and each recipient at
+//! `storage_quota_per_recipient` bytes. The signing secret, capability
+//! revocations and per-recipient usage are kept in the [`RelayStorage`], so
+//! they survive a restart when the storage does.
+
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::net::SocketAddr;
-use parking_lot::RwLock;
+use actix_web::http::StatusCode;
+use sha2::{Digest, Sha256};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use parking_lot::{Mutex, RwLock};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn, error, instrument};
 
+use qiyashash_crypto::kdf::{compute_auth_tag, verify_auth_tag};
+
 use crate::config::RelayServerConfig;
 use crate::error::{RelayError, Result};
 use crate::storage::{RelayStorage, MemoryRelayStorage, StorageStats};
+use crate::{DEFAULT_MESSAGE_EXPIRY_SECS, MAX_BLOB_SIZE};
+
+/// Header naming the recipient an upload is for
+pub const RECIPIENT_HEADER: &str = "x-relay-recipient";
+
+/// Header carrying the upload capability for the recipient
+pub const CAPABILITY_HEADER: &str = "x-relay-capability";
+
+/// Header carrying a blob's retrieval token
+pub const TOKEN_HEADER: &str = "x-relay-token";
+
+/// Header carrying a recipient's secret (32 hex-encoded bytes)
+pub const RECIPIENT_SECRET_HEADER: &str = "x-relay-recipient-secret";
+
+const RETRIEVAL_TOKEN_LABEL: &[u8] = b"relay-token-v1";
+const UPLOAD_CAPABILITY_LABEL: &[u8] = b"relay-upload-v2";
+
+const SECRET_STATE_KEY: &str = "secret";
+
+/// Recipient ID owned by the holder of `secret`
+pub fn recipient_id(secret: &[u8; 32]) -> String {
+    hex::encode(Sha256::digest(secret))
+}
 
 /// Relay server events
 #[derive(Clone, Debug)]
@@ -49,29 +99,53 @@ pub struct RelayServer {
     storage: Arc<dyn RelayStorage>,
     state: RwLock<ServerState>,
     event_tx: Option<mpsc::Sender<ServerEvent>>,
+    /// Key for retrieval tokens and upload capabilities
+    secret: [u8; 32],
+    /// Blob IDs and sizes stored for each recipient, loaded from storage
+    /// on first use
+    ///
+    /// Held across the quota check and the store so concurrent uploads
+    /// cannot overrun a quota together.
+    recipient_blobs: Mutex<HashMap<String, HashMap<String, usize>>>,
+    /// Serializes capability revocations
+    capability_lock: Mutex<()>,
 }
 
 impl RelayServer {
     /// Create a new relay server
-    pub fn new(config: RelayServerConfig) -> Self {
-        let storage = Arc::new(MemoryRelayStorage::new(config.max_storage_size));
-        
-        Self {
-            config,
-            storage,
-            state: RwLock::new(ServerState::Stopped),
-            event_tx: None,
-        }
+    pub fn new(config: RelayServerConfig) -> Result<Self> {
+        let storage = Arc::new(
+            MemoryRelayStorage::new(config.max_storage_size)
+                .with_verify_on_read(config.verify_on_read),
+        );
+        Self::with_storage(config, storage)
     }
 
     /// Create with custom storage
-    pub fn with_storage(config: RelayServerConfig, storage: Arc<dyn RelayStorage>) -> Self {
-        Self {
+    ///
+    /// The signing secret is loaded from `storage`, or generated and saved
+    /// there on first start.
+    pub fn with_storage(config: RelayServerConfig, storage: Arc<dyn RelayStorage>) -> Result<Self> {
+        let secret = match storage.get_state(SECRET_STATE_KEY)? {
+            Some(bytes) => bytes.as_slice().try_into()
+                .map_err(|_| RelayError::Storage("Corrupt relay secret".to_string()))?,
+            None => {
+                let mut secret = [0u8; 32];
+                rand::rngs::OsRng.fill_bytes(&mut secret);
+                storage.put_state(SECRET_STATE_KEY, &secret)?;
+                secret
+            }
+        };
+
+        Ok(Self {
             config,
             storage,
             state: RwLock::new(ServerState::Stopped),
             event_tx: None,
-        }
+            secret,
+            recipient_blobs: Mutex::new(HashMap::new()),
+            capability_lock: Mutex::new(()),
+        })
     }
 
     /// Set event channel
@@ -130,32 +204,97 @@ impl RelayServer {
         matches!(*self.state.read(), ServerState::Running)
     }
 
-    /// Store a blob
-    #[instrument(skip(self, data))]
-    pub async fn store(&self, id: &str, data: Vec<u8>, expiry_secs: u64) -> Result<String> {
+    /// Store a blob for `recipient`
+    ///
+    /// `capability` must come from [`issue_upload_capability`] for the same
+    /// recipient and not have been revoked since, and the blob must fit in
+    /// the recipient's quota.
+    ///
+    /// [`issue_upload_capability`]: Self::issue_upload_capability
+    #[instrument(skip(self, capability, data))]
+    pub async fn store_for_recipient(
+        &self,
+        recipient: &str,
+        capability: &str,
+        id: &str,
+        data: Vec<u8>,
+        expiry_secs: u64,
+    ) -> Result<String> {
         if !self.is_running() {
             return Err(RelayError::Internal("Server not running".to_string()));
         }
+        if !self.verify_upload_capability(recipient, capability)? {
+            return Err(RelayError::Unauthorized("Invalid upload capability".to_string()));
+        }
 
         let size = data.len();
-        
-        if size > self.config.max_storage_size as usize {
+        if size > MAX_BLOB_SIZE {
             return Err(RelayError::BlobTooLarge {
                 size,
-                max: self.config.max_storage_size as usize,
+                max: MAX_BLOB_SIZE,
             });
         }
 
-        let metadata = self.storage.store(id, data, expiry_secs)?;
-        
+        {
+            let mut recipients = self.recipient_blobs.lock();
+            let blobs = self.recipient_entry(&mut recipients, recipient)?;
+            let quota = self.config.rate_limit.storage_quota_per_recipient;
+            let used = self.prune_usage(recipient, blobs)?;
+            if used + size as u64 > quota {
+                return Err(RelayError::QuotaExceeded(format!(
+                    "{} bytes stored, {} more exceeds the {} byte quota",
+                    used, size, quota
+                )));
+            }
+
+            self.storage.store(id, data, expiry_secs)?;
+            blobs.insert(id.to_string(), size);
+            if let Err(e) = self.save_recipient(recipient, blobs) {
+                blobs.remove(id);
+                let _ = self.storage.delete(id);
+                return Err(e);
+            }
+        }
+
         self.emit_event(ServerEvent::BlobStored {
             id: id.to_string(),
             size,
         }).await;
 
-        // Return retrieval token
-        let token = self.generate_retrieval_token(id);
-        Ok(token)
+        Ok(self.generate_retrieval_token(id))
+    }
+
+    /// Bytes currently held for `recipient`
+    ///
+    /// Blobs that expired or were deleted since they were stored no longer
+    /// count.
+    pub fn recipient_usage(&self, recipient: &str) -> Result<u64> {
+        let mut recipients = self.recipient_blobs.lock();
+        let blobs = self.recipient_entry(&mut recipients, recipient)?;
+        self.prune_usage(recipient, blobs)
+    }
+
+    /// Capability a recipient hands to senders so they can upload for it
+    pub fn issue_upload_capability(&self, recipient: &str) -> Result<String> {
+        let generation = self.capability_generation(recipient)?;
+        Ok(hex::encode(compute_auth_tag(
+            &self.secret,
+            &Self::capability_data(recipient, generation),
+        )))
+    }
+
+    /// Invalidate every capability issued for `recipient`, returning a new one
+    pub fn revoke_upload_capabilities(&self, recipient: &str) -> Result<String> {
+        {
+            let _revoking = self.capability_lock.lock();
+            let generation = self.capability_generation(recipient)? + 1;
+            self.storage.put_state(
+                &Self::capability_state_key(recipient),
+                &generation.to_be_bytes(),
+            )?;
+        }
+        info!("Revoked upload capabilities for a recipient");
+        self.issue_upload_capability(recipient)
     }
 
     /// Retrieve a blob
@@ -167,7 +306,7 @@ impl RelayServer {
 
         // Verify token
         if !self.verify_retrieval_token(id, token) {
-            return Err(RelayError::InvalidBlob("Invalid retrieval token".to_string()));
+            return Err(RelayError::Unauthorized("Invalid retrieval token".to_string()));
         }
 
         match self.storage.retrieve(id)? {
@@ -202,14 +341,77 @@ impl RelayServer {
 
     // Helper methods
 
+    /// Blobs held for `recipient`, loading them from storage if needed
+    fn recipient_entry<'a>(
+        &self,
+        recipients: &'a mut HashMap<String, HashMap<String, usize>>,
+        recipient: &str,
+    ) -> Result<&'a mut HashMap<String, usize>> {
+        if !recipients.contains_key(recipient) {
+            let blobs = match self.storage.get_state(&Self::recipient_state_key(recipient))? {
+                Some(bytes) => bincode::deserialize(&bytes)
+                    .map_err(|e| RelayError::Storage(e.to_string()))?,
+                None => HashMap::new(),
+            };
+            recipients.insert(recipient.to_string(), blobs);
+        }
+        Ok(recipients.get_mut(recipient).expect("just inserted"))
+    }
+
+    /// Drop blobs that no longer exist and return the bytes still held
+    fn prune_usage(&self, recipient: &str, blobs: &mut HashMap<String, usize>) -> Result<u64> {
+        let before = blobs.len();
+        let mut result = Ok(());
+        blobs.retain(|id, _| match self.storage.exists(id) {
+            Ok(exists) => exists,
+            Err(e) => {
+                result = Err(e);
+                true
+            }
+        });
+        result?;
+
+        if blobs.len() != before {
+            self.save_recipient(recipient, blobs)?;
+        }
+        Ok(blobs.values().map(|&size| size as u64).sum())
+    }
+
+    fn save_recipient(&self, recipient: &str, blobs: &HashMap<String, usize>) -> Result<()> {
+        let bytes = bincode::serialize(blobs)
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        self.storage.put_state(&Self::recipient_state_key(recipient), &bytes)
+    }
+
+    fn capability_generation(&self, recipient: &str) -> Result<u64> {
+        match self.storage.get_state(&Self::capability_state_key(recipient))? {
+            Some(bytes) => {
+                let bytes: [u8; 8] = bytes.as_slice().try_into()
+                    .map_err(|_| RelayError::Storage("Corrupt capability generation".to_string()))?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn recipient_state_key(recipient: &str) -> String {
+        format!("recipient:{}", recipient)
+    }
+
+    fn capability_state_key(recipient: &str) -> String {
+        format!("capability:{}", recipient)
+    }
+
     async fn emit_event(&self, event: ServerEvent) {
         if let Some(ref tx) = self.event_tx {
             let _ = tx.send(event).await;
         }
     }
 
+    /// Periodically remove expired blobs until the server is dropped
     fn spawn_cleanup_task(&self) {
-        let storage = Arc::clone(&self.storage);
+        // Weak so dropping the server closes its storage
+        let storage = Arc::downgrade(&self.storage);
         let interval = self.config.cleanup_interval_secs;
         
         tokio::spawn(async move {
@@ -219,7 +421,10 @@ impl RelayServer {
             
             loop {
                 interval.tick().await;
-                
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+
                 match storage.cleanup_expired() {
                     Ok(count) if count > 0 => {
                         info!("Cleaned up {} expired blobs", count);
@@ -234,25 +439,206 @@ impl RelayServer {
     }
 
     fn generate_retrieval_token(&self, id: &str) -> String {
-        use sha2::{Sha256, Digest};
-        
-        // In production, use HMAC with server secret
-        let mut hasher = Sha256::new();
-        hasher.update(b"relay-token-v1");
-        hasher.update(id.as_bytes());
-        hasher.update(&chrono::Utc::now().timestamp().to_be_bytes());
-        
-        let result = hasher.finalize();
-        hex::encode(&result[..16])
+        hex::encode(compute_auth_tag(&self.secret, &Self::token_data(id)))
     }
 
-    fn verify_retrieval_token(&self, _id: &str, _token: &str) -> bool {
-        // In production, verify HMAC
-        // For now, always accept
-        true
+    fn verify_retrieval_token(&self, id: &str, token: &str) -> bool {
+        Self::verify_tag(&self.secret, &Self::token_data(id), token)
+    }
+
+    fn verify_upload_capability(&self, recipient: &str, capability: &str) -> Result<bool> {
+        let generation = self.capability_generation(recipient)?;
+        Ok(Self::verify_tag(
+            &self.secret,
+            &Self::capability_data(recipient, generation),
+            capability,
+        ))
+    }
+
+    fn verify_tag(secret: &[u8; 32], data: &[u8], tag: &str) -> bool {
+        let mut decoded = [0u8; 32];
+        hex::decode_to_slice(tag, &mut decoded).is_ok() && verify_auth_tag(secret, data, &decoded)
+    }
+
+    fn token_data(id: &str) -> Vec<u8> {
+        [RETRIEVAL_TOKEN_LABEL, id.as_bytes()].concat()
+    }
+
+    fn capability_data(recipient: &str, generation: u64) -> Vec<u8> {
+        [UPLOAD_CAPABILITY_LABEL, &generation.to_be_bytes(), recipient.as_bytes()].concat()
+    }
+}
+
+/// Body of a successful `POST /blobs`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoreBlobResponse {
+    /// ID to fetch the blob by
+    pub id: String,
+    /// Token required to fetch or delete it
+    pub token: String,
+}
+
+/// Body of a successful `POST` or `DELETE /capabilities`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapabilityResponse {
+    /// Recipient ID to name in [`RECIPIENT_HEADER`]
+    pub recipient: String,
+    /// Capability to hand to senders
+    pub capability: String,
+}
+
+#[derive(Deserialize)]
+struct StoreBlobQuery {
+    /// Seconds until the blob expires, capped at the default expiry
+    expiry_secs: Option<u64>,
+}
+
+/// Mount the blob API for `server` on an actix app
+pub fn configure_http(cfg: &mut web::ServiceConfig, server: Arc<RelayServer>) {
+    cfg.app_data(web::Data::from(server))
+        .app_data(web::PayloadConfig::new(MAX_BLOB_SIZE))
+        .route("/blobs", web::post().to(store_blob))
+        .route("/blobs/{id}", web::get().to(fetch_blob))
+        .route("/blobs/{id}", web::delete().to(delete_blob))
+        .route("/capabilities", web::post().to(obtain_capability))
+        .route("/capabilities", web::delete().to(revoke_capabilities));
+}
+
+/// Serve the blob API of `server` on its configured listen address
+///
+/// The server must already be [started](RelayServer::start).
+pub fn serve_http(server: Arc<RelayServer>) -> Result<actix_web::dev::Server> {
+    let address = server.config.listen_address.clone();
+    let http = HttpServer::new(move || {
+        let server = Arc::clone(&server);
+        App::new().configure(move |cfg| configure_http(cfg, server))
+    })
+    .bind(&address)?
+    .run();
+
+    info!("Relay HTTP API listening on {}", address);
+    Ok(http)
+}
+
+async fn store_blob(
+    server: web::Data<RelayServer>,
+    req: HttpRequest,
+    query: web::Query<StoreBlobQuery>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let (recipient, capability) = match (
+        header_value(&req, RECIPIENT_HEADER),
+        header_value(&req, CAPABILITY_HEADER),
+    ) {
+        (Some(recipient), Some(capability)) => (recipient, capability),
+        _ => {
+            return error_response(&RelayError::Unauthorized(
+                "Missing recipient or upload capability".to_string(),
+            ))
+        }
+    };
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let expiry_secs = query
+        .expiry_secs
+        .unwrap_or(DEFAULT_MESSAGE_EXPIRY_SECS)
+        .min(DEFAULT_MESSAGE_EXPIRY_SECS);
+
+    match server
+        .store_for_recipient(recipient, capability, &id, body.to_vec(), expiry_secs)
+        .await
+    {
+        Ok(token) => HttpResponse::Created().json(StoreBlobResponse { id, token }),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn fetch_blob(
+    server: web::Data<RelayServer>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let token = header_value(&req, TOKEN_HEADER).unwrap_or_default();
+
+    match server.retrieve(&path, token).await {
+        Ok(data) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(data),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn delete_blob(
+    server: web::Data<RelayServer>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let token = header_value(&req, TOKEN_HEADER).unwrap_or_default();
+    if !server.verify_retrieval_token(&path, token) {
+        return error_response(&RelayError::Unauthorized("Invalid retrieval token".to_string()));
+    }
+
+    match server.delete(&path).await {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({ "deleted": true })),
+        Ok(false) => error_response(&RelayError::BlobNotFound(path.into_inner())),
+        Err(e) => error_response(&e),
     }
 }
 
+async fn obtain_capability(server: web::Data<RelayServer>, req: HttpRequest) -> HttpResponse {
+    let recipient = match recipient_from_secret(&req) {
+        Ok(recipient) => recipient,
+        Err(e) => return error_response(&e),
+    };
+
+    match server.issue_upload_capability(&recipient) {
+        Ok(capability) => HttpResponse::Ok().json(CapabilityResponse { recipient, capability }),
+        Err(e) => error_response(&e),
+    }
+}
+
+async fn revoke_capabilities(server: web::Data<RelayServer>, req: HttpRequest) -> HttpResponse {
+    let recipient = match recipient_from_secret(&req) {
+        Ok(recipient) => recipient,
+        Err(e) => return error_response(&e),
+    };
+
+    match server.revoke_upload_capabilities(&recipient) {
+        Ok(capability) => HttpResponse::Ok().json(CapabilityResponse { recipient, capability }),
+        Err(e) => error_response(&e),
+    }
+}
+
+/// Recipient ID proven by the secret in [`RECIPIENT_SECRET_HEADER`]
+fn recipient_from_secret(req: &HttpRequest) -> Result<String> {
+    let mut secret = [0u8; 32];
+    header_value(req, RECIPIENT_SECRET_HEADER)
+        .and_then(|value| hex::decode_to_slice(value, &mut secret).ok())
+        .ok_or_else(|| RelayError::Unauthorized("Missing or invalid recipient secret".to_string()))?;
+    Ok(recipient_id(&secret))
+}
+
+fn header_value<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name)?.to_str().ok()
+}
+
+fn error_response(error: &RelayError) -> HttpResponse {
+    let status = match error {
+        RelayError::BlobNotFound(_) | RelayError::BlobExpired => StatusCode::NOT_FOUND,
+        RelayError::BlobTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        RelayError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        RelayError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+        RelayError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        RelayError::InvalidBlob(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    if status.is_server_error() {
+        warn!("Relay request failed: {}", error);
+    }
+    HttpResponse::build(status).json(serde_json::json!({ "error": error.to_string() }))
+}
+
 /// Handle incoming request
 #[derive(Clone, Debug)]
 pub enum Request {
@@ -307,7 +693,7 @@ mod tests {
     #[tokio::test]
     async fn test_server_lifecycle() {
         let config = RelayServerConfig::default();
-        let server = RelayServer::new(config);
+        let server = RelayServer::new(config).unwrap();
 
         assert!(!server.is_running());
         
@@ -321,11 +707,15 @@ mod tests {
     #[tokio::test]
     async fn test_store_retrieve() {
         let config = RelayServerConfig::default();
-        let server = RelayServer::new(config);
+        let server = RelayServer::new(config).unwrap();
         server.start().await.unwrap();
 
         // Store
-        let token = server.store("test-1", vec![0x42; 100], 3600).await.unwrap();
+        let capability = server.issue_upload_capability("bob").unwrap();
+        let token = server
+            .store_for_recipient("bob", &capability, "test-1", vec![0x42; 100], 3600)
+            .await
+            .unwrap();
         assert!(!token.is_empty());
 
         // Retrieve
@@ -339,14 +729,198 @@ mod tests {
     #[tokio::test]
     async fn test_stats() {
         let config = RelayServerConfig::default();
-        let server = RelayServer::new(config);
+        let server = RelayServer::new(config).unwrap();
         server.start().await.unwrap();
 
-        server.store("blob-1", vec![0x42; 100], 3600).await.unwrap();
-        server.store("blob-2", vec![0x42; 200], 3600).await.unwrap();
+        let capability = server.issue_upload_capability("bob").unwrap();
+        for (id, size) in [("blob-1", 100), ("blob-2", 200)] {
+            server
+                .store_for_recipient("bob", &capability, id, vec![0x42; size], 3600)
+                .await
+                .unwrap();
+        }
 
         let stats = server.stats().unwrap();
         assert_eq!(stats.blob_count, 2);
         assert_eq!(stats.total_size, 300);
     }
+
+    async fn http_server(config: RelayServerConfig) -> Arc<RelayServer> {
+        let server = Arc::new(RelayServer::new(config).unwrap());
+        server.start().await.unwrap();
+        server
+    }
+
+    #[actix_web::test]
+    async fn test_http_store_fetch_delete() {
+        use actix_web::test;
+
+        let server = http_server(RelayServerConfig::default()).await;
+        let app = test::init_service(
+            App::new().configure(|cfg| configure_http(cfg, Arc::clone(&server))),
+        )
+        .await;
+        let capability = server.issue_upload_capability("bob").unwrap();
+
+        // Uploads need the recipient's capability
+        let req = test::TestRequest::post()
+            .uri("/blobs")
+            .insert_header((RECIPIENT_HEADER, "bob"))
+            .insert_header((CAPABILITY_HEADER, server.issue_upload_capability("carol").unwrap()))
+            .set_payload(vec![0x42; 100])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/blobs?expiry_secs=3600")
+            .insert_header((RECIPIENT_HEADER, "bob"))
+            .insert_header((CAPABILITY_HEADER, capability.as_str()))
+            .set_payload(vec![0x42; 100])
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let stored: StoreBlobResponse = test::read_body_json(response).await;
+        assert_eq!(server.recipient_usage("bob").unwrap(), 100);
+
+        let uri = format!("/blobs/{}", stored.id);
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((TOKEN_HEADER, "00".repeat(32)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((TOKEN_HEADER, stored.token.as_str()))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body.as_ref(), &[0x42; 100][..]);
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header((TOKEN_HEADER, stored.token.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(server.recipient_usage("bob").unwrap(), 0);
+
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((TOKEN_HEADER, stored.token.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_http_rejects_oversized_and_over_quota() {
+        use actix_web::test;
+
+        let mut config = RelayServerConfig::default();
+        config.rate_limit.storage_quota_per_recipient = 150;
+        let server = http_server(config).await;
+        let app = test::init_service(
+            App::new().configure(|cfg| configure_http(cfg, Arc::clone(&server))),
+        )
+        .await;
+        let capability = server.issue_upload_capability("bob").unwrap();
+        let upload = |size: usize| {
+            test::TestRequest::post()
+                .uri("/blobs")
+                .insert_header((RECIPIENT_HEADER, "bob"))
+                .insert_header((CAPABILITY_HEADER, capability.as_str()))
+                .set_payload(vec![0x42; size])
+                .to_request()
+        };
+
+        let response = test::call_service(&app, upload(MAX_BLOB_SIZE + 1)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(matches!(
+            server
+                .store_for_recipient("bob", &capability, "direct", vec![0; MAX_BLOB_SIZE + 1], 60)
+                .await,
+            Err(RelayError::BlobTooLarge { .. })
+        ));
+
+        assert_eq!(test::call_service(&app, upload(100)).await.status(), StatusCode::CREATED);
+        let response = test::call_service(&app, upload(100)).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(test::call_service(&app, upload(50)).await.status(), StatusCode::CREATED);
+        assert_eq!(server.stats().unwrap().blob_count, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_http_capabilities_obtain_and_revoke() {
+        use actix_web::test;
+
+        let server = http_server(RelayServerConfig::default()).await;
+        let app = test::init_service(
+            App::new().configure(|cfg| configure_http(cfg, Arc::clone(&server))),
+        )
+        .await;
+        let secret = [7u8; 32];
+        let capability_request = |req: test::TestRequest| {
+            req.uri("/capabilities")
+                .insert_header((RECIPIENT_SECRET_HEADER, hex::encode(secret)))
+                .to_request()
+        };
+
+        let req = test::TestRequest::post().uri("/capabilities").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let issued: CapabilityResponse =
+            test::call_and_read_body_json(&app, capability_request(test::TestRequest::post())).await;
+        assert_eq!(issued.recipient, recipient_id(&secret));
+        server
+            .store_for_recipient(&issued.recipient, &issued.capability, "before", vec![1; 10], 60)
+            .await
+            .unwrap();
+
+        let rotated: CapabilityResponse =
+            test::call_and_read_body_json(&app, capability_request(test::TestRequest::delete())).await;
+        assert_ne!(rotated.capability, issued.capability);
+        assert!(matches!(
+            server
+                .store_for_recipient(&issued.recipient, &issued.capability, "after", vec![2; 10], 60)
+                .await,
+            Err(RelayError::Unauthorized(_))
+        ));
+        server
+            .store_for_recipient(&rotated.recipient, &rotated.capability, "after", vec![2; 10], 60)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        use crate::storage::SledRelayStorage;
+
+        let dir = std::env::temp_dir().join(format!("relay-server-{}", uuid::Uuid::new_v4()));
+        let open = || -> Arc<dyn RelayStorage> {
+            Arc::new(SledRelayStorage::open(dir.to_str().unwrap(), 1024 * 1024).unwrap())
+        };
+
+        let (capability, revoked, token) = {
+            let server = RelayServer::with_storage(RelayServerConfig::default(), open()).unwrap();
+            server.start().await.unwrap();
+            let revoked = server.issue_upload_capability("bob").unwrap();
+            let capability = server.revoke_upload_capabilities("bob").unwrap();
+            let token = server
+                .store_for_recipient("bob", &capability, "kept", vec![0x42; 100], 3600)
+                .await
+                .unwrap();
+            (capability, revoked, token)
+        };
+
+        let server = RelayServer::with_storage(RelayServerConfig::default(), open()).unwrap();
+        server.start().await.unwrap();
+        assert_eq!(server.issue_upload_capability("bob").unwrap(), capability);
+        assert_eq!(server.retrieve("kept", &token).await.unwrap(), vec![0x42; 100]);
+        assert_eq!(server.recipient_usage("bob").unwrap(), 100);
+        assert!(matches!(
+            server.store_for_recipient("bob", &revoked, "late", vec![1], 60).await,
+            Err(RelayError::Unauthorized(_))
+        ));
+
+        drop(server);
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
@@ -1,8 +1,12 @@
 //! Relay storage for blob management
+//!
+//! Blob data is content-addressed: blobs with identical data share one
+//! stored copy, reference counted by the IDs pointing at it. Each ID keeps
+//! its own metadata and expiry, so per-recipient accounting is unaffected.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
@@ -44,6 +48,19 @@ pub struct StoredBlob {
     pub data: Vec<u8>,
 }
 
+impl StoredBlob {
+    /// Check that the data still hashes to `metadata.hash`
+    pub fn verify(&self) -> Result<()> {
+        use sha2::{Sha256, Digest};
+        let hash = Sha256::digest(&self.data);
+        if hash.as_slice() != self.metadata.hash {
+            warn!("Blob {} does not match its hash", self.metadata.id);
+            return Err(RelayError::IntegrityFailure(self.metadata.id.clone()));
+        }
+        Ok(())
+    }
+}
+
 /// Relay storage trait
 pub trait RelayStorage: Send + Sync {
     /// Store a blob
@@ -69,6 +86,15 @@ pub trait RelayStorage: Send + Sync {
 
     /// Get storage stats
     fn stats(&self) -> Result<StorageStats>;
+
+    /// Number of blob IDs sharing the data with `hash`
+    fn ref_count(&self, hash: &[u8; 32]) -> Result<usize>;
+
+    /// Read a record of relay server state
+    fn get_state(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Durably write a record of relay server state
+    fn put_state(&self, key: &str, value: &[u8]) -> Result<()>;
 }
 
 /// Storage statistics
@@ -76,33 +102,66 @@ pub trait RelayStorage: Send + Sync {
 pub struct StorageStats {
     /// Total blobs stored
     pub blob_count: usize,
-    /// Total size in bytes
+    /// Bytes of blob data, counting shared data once
     pub total_size: u64,
+    /// Bytes occupied on disk, including metadata and backend overhead
+    pub disk_size: u64,
     /// Expired blobs
     pub expired_count: usize,
 }
 
 /// In-memory relay storage (for testing)
 pub struct MemoryRelayStorage {
-    blobs: RwLock<HashMap<String, StoredBlob>>,
+    inner: RwLock<MemoryBlobs>,
     max_size: u64,
+    verify_on_read: bool,
+}
+
+#[derive(Default)]
+struct MemoryBlobs {
+    /// Metadata by blob ID
+    blobs: HashMap<String, BlobMetadata>,
+    /// Data and reference count by content hash
+    contents: HashMap<[u8; 32], (Vec<u8>, usize)>,
+    /// Relay server state records
+    state: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryBlobs {
+    fn current_size(&self) -> u64 {
+        self.contents.values().map(|(data, _)| data.len() as u64).sum()
+    }
+
+    /// Drop `id`, freeing its data once nothing else references it
+    fn remove(&mut self, id: &str) -> bool {
+        let Some(metadata) = self.blobs.remove(id) else {
+            return false;
+        };
+
+        if let Some((_, refs)) = self.contents.get_mut(&metadata.hash) {
+            *refs -= 1;
+            if *refs == 0 {
+                self.contents.remove(&metadata.hash);
+            }
+        }
+        true
+    }
 }
 
 impl MemoryRelayStorage {
     /// Create new in-memory storage
     pub fn new(max_size: u64) -> Self {
         Self {
-            blobs: RwLock::new(HashMap::new()),
+            inner: RwLock::new(MemoryBlobs::default()),
             max_size,
+            verify_on_read: false,
         }
     }
 
-    fn current_size(&self) -> u64 {
-        self.blobs
-            .read()
-            .values()
-            .map(|b| b.data.len() as u64)
-            .sum()
+    /// Re-hash blobs on retrieval, failing with [`RelayError::IntegrityFailure`]
+    pub fn with_verify_on_read(mut self, verify: bool) -> Self {
+        self.verify_on_read = verify;
+        self
     }
 
     fn compute_hash(data: &[u8]) -> [u8; 32] {
@@ -126,9 +185,11 @@ impl MemoryRelayStorage {
 impl RelayStorage for MemoryRelayStorage {
     fn store(&self, id: &str, data: Vec<u8>, expiry_secs: u64) -> Result<BlobMetadata> {
         let size = data.len();
-        
-        // Check size limit
-        if self.current_size() + size as u64 > self.max_size {
+        let hash = Self::compute_hash(&data);
+        let mut inner = self.inner.write();
+
+        // Shared data costs nothing more
+        if !inner.contents.contains_key(&hash) && inner.current_size() + size as u64 > self.max_size {
             return Err(RelayError::Storage("Storage full".to_string()));
         }
 
@@ -138,38 +199,42 @@ impl RelayStorage for MemoryRelayStorage {
             size,
             created_at: now,
             expires_at: now + expiry_secs,
-            hash: Self::compute_hash(&data),
+            hash,
         };
 
-        let blob = StoredBlob {
-            metadata: metadata.clone(),
-            data,
-        };
-
-        self.blobs.write().insert(id.to_string(), blob);
+        inner.remove(id);
+        inner.contents.entry(hash).or_insert((data, 0)).1 += 1;
+        inner.blobs.insert(id.to_string(), metadata.clone());
         debug!("Stored blob {}: {} bytes", id, size);
 
         Ok(metadata)
     }
 
     fn retrieve(&self, id: &str) -> Result<Option<StoredBlob>> {
-        let blobs = self.blobs.read();
+        let inner = self.inner.read();
         
-        match blobs.get(id) {
-            Some(blob) => {
-                if blob.metadata.is_expired() {
+        match inner.blobs.get(id) {
+            Some(metadata) => {
+                if metadata.is_expired() {
                     debug!("Blob {} is expired", id);
-                    Ok(None)
-                } else {
-                    Ok(Some(blob.clone()))
+                    return Ok(None);
+                }
+
+                let blob = inner.contents.get(&metadata.hash).map(|(data, _)| StoredBlob {
+                    metadata: metadata.clone(),
+                    data: data.clone(),
+                });
+                if let (Some(blob), true) = (&blob, self.verify_on_read) {
+                    blob.verify()?;
                 }
+                Ok(blob)
             }
             None => Ok(None),
         }
     }
 
     fn delete(&self, id: &str) -> Result<bool> {
-        let removed = self.blobs.write().remove(id).is_some();
+        let removed = self.inner.write().remove(id);
         if removed {
             debug!("Deleted blob {}", id);
         }
@@ -177,26 +242,33 @@ impl RelayStorage for MemoryRelayStorage {
     }
 
     fn exists(&self, id: &str) -> Result<bool> {
-        let blobs = self.blobs.read();
-        match blobs.get(id) {
-            Some(blob) => Ok(!blob.metadata.is_expired()),
+        let inner = self.inner.read();
+        match inner.blobs.get(id) {
+            Some(metadata) => Ok(!metadata.is_expired()),
             None => Ok(false),
         }
     }
 
     fn get_metadata(&self, id: &str) -> Result<Option<BlobMetadata>> {
-        Ok(self.blobs.read().get(id).map(|b| b.metadata.clone()))
+        Ok(self.inner.read().blobs.get(id).cloned())
     }
 
     fn list_ids(&self) -> Result<Vec<String>> {
-        Ok(self.blobs.read().keys().cloned().collect())
+        Ok(self.inner.read().blobs.keys().cloned().collect())
     }
 
     fn cleanup_expired(&self) -> Result<usize> {
-        let mut blobs = self.blobs.write();
-        let before = blobs.len();
-        blobs.retain(|_, blob| !blob.metadata.is_expired());
-        let removed = before - blobs.len();
+        let mut inner = self.inner.write();
+        let expired: Vec<String> = inner
+            .blobs
+            .values()
+            .filter(|metadata| metadata.is_expired())
+            .map(|metadata| metadata.id.clone())
+            .collect();
+        for id in &expired {
+            inner.remove(id);
+        }
+        let removed = expired.len();
         
         if removed > 0 {
             info!("Cleaned up {} expired blobs", removed);
@@ -206,21 +278,43 @@ impl RelayStorage for MemoryRelayStorage {
     }
 
     fn stats(&self) -> Result<StorageStats> {
-        let blobs = self.blobs.read();
+        let inner = self.inner.read();
         let now = Self::current_timestamp();
+        let total_size = inner.current_size();
         
         Ok(StorageStats {
-            blob_count: blobs.len(),
-            total_size: blobs.values().map(|b| b.data.len() as u64).sum(),
-            expired_count: blobs.values().filter(|b| b.metadata.expires_at <= now).count(),
+            blob_count: inner.blobs.len(),
+            total_size,
+            disk_size: total_size,
+            expired_count: inner.blobs.values().filter(|m| m.expires_at <= now).count(),
         })
     }
+
+    fn ref_count(&self, hash: &[u8; 32]) -> Result<usize> {
+        Ok(self.inner.read().contents.get(hash).map_or(0, |(_, refs)| *refs))
+    }
+
+    fn get_state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.read().state.get(key).cloned())
+    }
+
+    fn put_state(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.inner.write().state.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
 }
 
 /// Sled-based persistent storage
+///
+/// Keys are `meta:{id}` for metadata, `content:{hash}` for data,
+/// `refs:{hash}` for its reference count and `state:{key}` for server state.
+/// Blobs stored before data was shared keep their data under `blob:{id}`.
 pub struct SledRelayStorage {
     db: sled::Db,
     max_size: u64,
+    verify_on_read: bool,
+    /// Serializes reference count updates
+    write_lock: Mutex<()>,
 }
 
 impl SledRelayStorage {
@@ -229,7 +323,18 @@ impl SledRelayStorage {
         let db = sled::open(path)
             .map_err(|e| RelayError::Storage(e.to_string()))?;
         
-        Ok(Self { db, max_size })
+        Ok(Self {
+            db,
+            max_size,
+            verify_on_read: false,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Re-hash blobs on retrieval, failing with [`RelayError::IntegrityFailure`]
+    pub fn with_verify_on_read(mut self, verify: bool) -> Self {
+        self.verify_on_read = verify;
+        self
     }
 
     fn blob_key(id: &str) -> Vec<u8> {
@@ -240,6 +345,18 @@ impl SledRelayStorage {
         format!("meta:{}", id).into_bytes()
     }
 
+    fn content_key(hash: &[u8; 32]) -> Vec<u8> {
+        format!("content:{}", hex::encode(hash)).into_bytes()
+    }
+
+    fn refs_key(hash: &[u8; 32]) -> Vec<u8> {
+        format!("refs:{}", hex::encode(hash)).into_bytes()
+    }
+
+    fn state_key(key: &str) -> Vec<u8> {
+        format!("state:{}", key).into_bytes()
+    }
+
     fn current_timestamp() -> u64 {
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -256,66 +373,134 @@ impl SledRelayStorage {
         hash.copy_from_slice(&result);
         hash
     }
+
+    fn refs(&self, hash: &[u8; 32]) -> Result<u64> {
+        match self.db.get(Self::refs_key(hash))
+            .map_err(|e| RelayError::Storage(e.to_string()))? {
+            Some(bytes) => {
+                let bytes: [u8; 8] = bytes.as_ref().try_into()
+                    .map_err(|_| RelayError::Storage("Corrupt reference count".to_string()))?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Queue setting the reference count for `hash`, dropping its data at zero
+    fn set_refs(batch: &mut sled::Batch, hash: &[u8; 32], refs: u64) {
+        if refs == 0 {
+            batch.remove(Self::refs_key(hash));
+            batch.remove(Self::content_key(hash));
+        } else {
+            batch.insert(Self::refs_key(hash), &refs.to_be_bytes()[..]);
+        }
+    }
+
+    /// Queue dropping `id`, freeing its data once nothing else references it
+    ///
+    /// Returns the hash of the removed blob and the references left to it
+    /// once `batch` is applied, or `None` if `id` was not stored. Callers
+    /// hold the write lock and apply the batch in one step.
+    fn remove_locked(&self, id: &str, batch: &mut sled::Batch) -> Result<Option<([u8; 32], u64)>> {
+        let Some(metadata) = self.get_metadata(id)? else {
+            return Ok(None);
+        };
+
+        batch.remove(Self::meta_key(id));
+
+        let legacy = self.db.contains_key(Self::blob_key(id))
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        let refs = self.refs(&metadata.hash)?;
+        if legacy {
+            batch.remove(Self::blob_key(id));
+            return Ok(Some((metadata.hash, refs)));
+        }
+
+        let refs = refs.saturating_sub(1);
+        Self::set_refs(batch, &metadata.hash, refs);
+        Ok(Some((metadata.hash, refs)))
+    }
+
+    /// Atomically apply `batch` and flush it to disk
+    fn commit(&self, batch: sled::Batch) -> Result<()> {
+        self.db.apply_batch(batch)
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        self.db.flush()
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        Ok(())
+    }
 }
 
 impl RelayStorage for SledRelayStorage {
     fn store(&self, id: &str, data: Vec<u8>, expiry_secs: u64) -> Result<BlobMetadata> {
         let size = data.len();
         let now = Self::current_timestamp();
+        let hash = Self::compute_hash(&data);
         
         let metadata = BlobMetadata {
             id: id.to_string(),
             size,
             created_at: now,
             expires_at: now + expiry_secs,
-            hash: Self::compute_hash(&data),
+            hash,
         };
 
         let meta_bytes = bincode::serialize(&metadata)
             .map_err(|e| RelayError::Storage(e.to_string()))?;
 
-        self.db.insert(Self::blob_key(id), data)
-            .map_err(|e| RelayError::Storage(e.to_string()))?;
-        self.db.insert(Self::meta_key(id), meta_bytes)
-            .map_err(|e| RelayError::Storage(e.to_string()))?;
-        self.db.flush()
-            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        // Metadata, data and reference count change together or not at all
+        let _write = self.write_lock.lock();
+        let mut batch = sled::Batch::default();
+        let refs = match self.remove_locked(id, &mut batch)? {
+            Some((replaced, refs)) if replaced == hash => refs,
+            _ => self.refs(&hash)?,
+        };
 
-        debug!("Stored blob {}: {} bytes", id, size);
+        if refs == 0 {
+            batch.insert(Self::content_key(&hash), data);
+        }
+        Self::set_refs(&mut batch, &hash, refs + 1);
+        batch.insert(Self::meta_key(id), meta_bytes);
+        self.commit(batch)?;
+
+        debug!("Stored blob {}: {} bytes ({} references)", id, size, refs + 1);
         Ok(metadata)
     }
 
     fn retrieve(&self, id: &str) -> Result<Option<StoredBlob>> {
-        let meta_bytes = match self.db.get(Self::meta_key(id))
-            .map_err(|e| RelayError::Storage(e.to_string()))? {
-            Some(bytes) => bytes,
+        let metadata = match self.get_metadata(id)? {
+            Some(metadata) => metadata,
             None => return Ok(None),
         };
 
-        let metadata: BlobMetadata = bincode::deserialize(&meta_bytes)
-            .map_err(|e| RelayError::Storage(e.to_string()))?;
-
         if metadata.is_expired() {
             return Ok(None);
         }
 
-        let data = match self.db.get(Self::blob_key(id))
+        let data = match self.db.get(Self::content_key(&metadata.hash))
             .map_err(|e| RelayError::Storage(e.to_string()))? {
             Some(bytes) => bytes.to_vec(),
-            None => return Ok(None),
+            None => match self.db.get(Self::blob_key(id))
+                .map_err(|e| RelayError::Storage(e.to_string()))? {
+                Some(bytes) => bytes.to_vec(),
+                None => return Ok(None),
+            },
         };
 
-        Ok(Some(StoredBlob { metadata, data }))
+        let blob = StoredBlob { metadata, data };
+        if self.verify_on_read {
+            blob.verify()?;
+        }
+        Ok(Some(blob))
     }
 
     fn delete(&self, id: &str) -> Result<bool> {
-        let removed = self.db.remove(Self::blob_key(id))
-            .map_err(|e| RelayError::Storage(e.to_string()))?
-            .is_some();
-        self.db.remove(Self::meta_key(id))
-            .map_err(|e| RelayError::Storage(e.to_string()))?;
-        self.db.flush()
-            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        let _write = self.write_lock.lock();
+        let mut batch = sled::Batch::default();
+        let removed = self.remove_locked(id, &mut batch)?.is_some();
+        if removed {
+            self.commit(batch)?;
+        }
         Ok(removed)
     }
 
@@ -380,22 +565,47 @@ impl RelayStorage for SledRelayStorage {
         
         let mut total_size = 0u64;
         let mut expired_count = 0;
+        let mut counted = HashSet::new();
         
         for id in &ids {
             if let Some(meta) = self.get_metadata(id)? {
-                total_size += meta.size as u64;
+                if counted.insert(meta.hash) {
+                    total_size += meta.size as u64;
+                }
                 if meta.expires_at <= now {
                     expired_count += 1;
                 }
             }
         }
         
+        let disk_size = self.db.size_on_disk()
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        
         Ok(StorageStats {
             blob_count: ids.len(),
             total_size,
+            disk_size,
             expired_count,
         })
     }
+
+    fn ref_count(&self, hash: &[u8; 32]) -> Result<usize> {
+        Ok(self.refs(hash)? as usize)
+    }
+
+    fn get_state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(Self::state_key(key))
+            .map_err(|e| RelayError::Storage(e.to_string()))?
+            .map(|bytes| bytes.to_vec()))
+    }
+
+    fn put_state(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.db.insert(Self::state_key(key), value)
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        self.db.flush()
+            .map_err(|e| RelayError::Storage(e.to_string()))?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -434,4 +644,81 @@ mod tests {
         assert_eq!(stats.blob_count, 2);
         assert_eq!(stats.total_size, 300);
     }
+
+    fn assert_deduplicates(storage: &dyn RelayStorage) {
+        let first = storage.store("fan-out-1", vec![0x42; 100], 3600).unwrap();
+        let second = storage.store("fan-out-2", vec![0x42; 100], 7200).unwrap();
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(storage.ref_count(&first.hash).unwrap(), 2);
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.blob_count, 2);
+        assert_eq!(stats.total_size, 100);
+
+        // One delete keeps the data for the other reference
+        assert!(storage.delete("fan-out-1").unwrap());
+        assert_eq!(storage.ref_count(&first.hash).unwrap(), 1);
+        assert!(storage.retrieve("fan-out-1").unwrap().is_none());
+        let blob = storage.retrieve("fan-out-2").unwrap().unwrap();
+        assert_eq!(blob.data, vec![0x42; 100]);
+        assert_eq!(blob.metadata.expires_at, second.expires_at);
+
+        assert!(storage.delete("fan-out-2").unwrap());
+        assert_eq!(storage.ref_count(&first.hash).unwrap(), 0);
+        assert_eq!(storage.stats().unwrap().total_size, 0);
+    }
+
+    #[test]
+    fn test_memory_storage_deduplicates() {
+        assert_deduplicates(&MemoryRelayStorage::new(1024 * 1024));
+    }
+
+    #[test]
+    fn test_verify_on_read_detects_corruption() {
+        let storage = MemoryRelayStorage::new(1024 * 1024).with_verify_on_read(true);
+        let metadata = storage.store("rotting", vec![0x42; 100], 3600).unwrap();
+        assert!(storage.retrieve("rotting").unwrap().is_some());
+
+        storage.inner.write().contents.get_mut(&metadata.hash).unwrap().0[7] ^= 0x01;
+        assert!(matches!(
+            storage.retrieve("rotting"),
+            Err(RelayError::IntegrityFailure(id)) if id == "rotting"
+        ));
+
+        // Unverified reads serve whatever is stored
+        let storage = MemoryRelayStorage::new(1024 * 1024);
+        let metadata = storage.store("rotting", vec![0x42; 100], 3600).unwrap();
+        storage.inner.write().contents.get_mut(&metadata.hash).unwrap().0[7] ^= 0x01;
+        assert_eq!(storage.retrieve("rotting").unwrap().unwrap().data[7], 0x43);
+    }
+
+    #[test]
+    fn test_sled_storage_restore_keeps_one_reference() {
+        let dir = std::env::temp_dir().join(format!("relay-restore-{}", uuid::Uuid::new_v4()));
+        let storage = SledRelayStorage::open(dir.to_str().unwrap(), 1024 * 1024).unwrap();
+
+        let first = storage.store("same-id", vec![0x42; 100], 3600).unwrap();
+        storage.store("same-id", vec![0x42; 100], 7200).unwrap();
+        assert_eq!(storage.ref_count(&first.hash).unwrap(), 1);
+        assert_eq!(storage.retrieve("same-id").unwrap().unwrap().data, vec![0x42; 100]);
+
+        // Replacing the data releases the old copy
+        let second = storage.store("same-id", vec![0x17; 50], 3600).unwrap();
+        assert_eq!(storage.ref_count(&first.hash).unwrap(), 0);
+        assert_eq!(storage.ref_count(&second.hash).unwrap(), 1);
+        assert!(!storage.db.contains_key(SledRelayStorage::content_key(&first.hash)).unwrap());
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_sled_storage_deduplicates() {
+        let dir = std::env::temp_dir().join(format!("relay-dedup-{}", uuid::Uuid::new_v4()));
+        let storage = SledRelayStorage::open(dir.to_str().unwrap(), 1024 * 1024).unwrap();
+        assert_deduplicates(&storage);
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
@@ -1,9 +1,12 @@
 //! REST API handlers for Chain State Service
 
+use actix_web::middleware::from_fn;
 use actix_web::{web, HttpResponse, Result};
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
+use qiyashash_service_common::auth::require_token;
+
 use crate::error::ChainStateError;
 use crate::service::{AppendRequest, ChainEntry, ChainState};
 use crate::AppState;
@@ -13,13 +16,24 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/v1")
             .route("/health", web::get().to(health_check))
-            .route("/chains", web::post().to(create_chain))
+            .route("/chains", web::post().to(create_chain).wrap(from_fn(require_token)))
             .route("/chains", web::get().to(list_chains))
             .route("/chains/{chain_id}", web::get().to(get_chain))
-            .route("/chains/{chain_id}/entries", web::post().to(append_entry))
+            .route(
+                "/chains/{chain_id}/entries",
+                web::post().to(append_entry).wrap(from_fn(require_token)),
+            )
             .route("/chains/{chain_id}/entries", web::get().to(get_entries))
+            .route(
+                "/chains/{chain_id}/entries/batch",
+                web::post().to(append_batch).wrap(from_fn(require_token)),
+            )
             .route("/chains/{chain_id}/entries/{sequence}", web::get().to(get_entry))
-            .route("/chains/{chain_id}/verify", web::post().to(verify_chain)),
+            .route("/chains/{chain_id}/verify", web::post().to(verify_chain))
+            .route(
+                "/chains/{chain_id}/compact",
+                web::post().to(compact_chain).wrap(from_fn(require_token)),
+            ),
     );
 }
 
@@ -125,6 +139,29 @@ async fn append_entry(
     Ok(HttpResponse::Created().json(entry))
 }
 
+/// Append several entries to a chain in order
+async fn append_batch(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<Vec<AppendEntryRequest>>,
+) -> Result<HttpResponse, ChainStateError> {
+    let chain_id = path.into_inner();
+
+    let requests = body
+        .into_inner()
+        .into_iter()
+        .map(|entry| AppendRequest {
+            chain_id: chain_id.clone(),
+            content_hash: entry.content_hash,
+            expected_previous_hash: entry.expected_previous_hash,
+            metadata: entry.metadata,
+        })
+        .collect();
+
+    let entries = state.chain_manager.append_batch(&chain_id, requests)?;
+    Ok(HttpResponse::Created().json(entries))
+}
+
 /// Get entries query parameters
 #[derive(Deserialize)]
 struct GetEntriesQuery {
@@ -206,3 +243,70 @@ async fn verify_chain(
         },
     }))
 }
+
+/// Compact chain query parameters
+#[derive(Deserialize)]
+struct CompactQuery {
+    before: u64,
+}
+
+/// Remove entries older than a sequence number, keeping a checkpoint
+async fn compact_chain(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<CompactQuery>,
+) -> Result<HttpResponse, ChainStateError> {
+    let chain_id = path.into_inner();
+    info!("Compacting chain {} before {}", chain_id, query.before);
+    let result = state.chain_manager.compact_chain(&chain_id, query.before)?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qiyashash_service_common::auth::ApiTokens;
+    use crate::service::ChainStateManager;
+    use actix_web::{http::StatusCode, test, App};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[actix_web::test]
+    async fn test_append_entry_requires_token() {
+        let temp = TempDir::new().unwrap();
+        let chain_manager = Arc::new(ChainStateManager::new(temp.path()).unwrap());
+        chain_manager.create_chain("chain").unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    chain_manager: chain_manager.clone(),
+                }))
+                .app_data(web::Data::new(ApiTokens::new(["secret".to_string()])))
+                .configure(configure_routes),
+        )
+        .await;
+        let body = serde_json::json!({ "content_hash": "abc" });
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/chains/chain/entries")
+            .set_json(&body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(chain_manager.get_chain("chain").unwrap().head_sequence, 0);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/chains/chain/entries")
+            .insert_header(("Authorization", "Bearer secret"))
+            .set_json(&body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        assert_eq!(chain_manager.get_chain("chain").unwrap().head_sequence, 1);
+
+        // Reads and health stay open
+        let req = test::TestRequest::get().uri("/api/v1/chains/chain").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get().uri("/api/v1/health").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+}
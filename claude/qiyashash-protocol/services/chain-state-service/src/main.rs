@@ -7,9 +7,12 @@ use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
 use clap::Parser;
 use std::sync::Arc;
-use tracing::{info, Level};
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use qiyashash_service_common::args::{ApiTokenArgs, ServiceArgs};
+use qiyashash_service_common::auth;
+
 mod api;
 mod error;
 mod service;
@@ -36,6 +39,12 @@ struct Args {
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    #[command(flatten)]
+    service: ServiceArgs,
+
+    #[command(flatten)]
+    auth: ApiTokenArgs,
 }
 
 /// Application state shared across handlers
@@ -49,15 +58,19 @@ async fn main() -> std::io::Result<()> {
 
     // Initialize logging
     let log_level = if args.verbose { Level::DEBUG } else { Level::INFO };
-    let subscriber = FmtSubscriber::builder()
+    let builder = FmtSubscriber::builder()
         .with_max_level(log_level)
         .with_target(true)
         .with_thread_ids(true)
         .json()
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
+        .with_filter_reloading();
+    let filter = builder.reload_handle();
+    tracing::subscriber::set_global_default(builder.finish())
         .expect("Failed to set tracing subscriber");
 
+    // Apply log level edits to --config while running; --verbose wins
+    let _config_watcher = args.service.watch_log_level(filter, args.verbose);
+
     info!("Starting Chain State Service");
     info!("Storage path: {}", args.storage_path);
 
@@ -71,7 +84,14 @@ async fn main() -> std::io::Result<()> {
 
     info!("Binding to {}:{}", args.host, args.port);
 
-    HttpServer::new(move || {
+    let api_tokens = web::Data::new(auth::ApiTokens::new(args.auth.api_tokens.clone()));
+    if !api_tokens.is_enabled() {
+        warn!("No API tokens configured; mutating routes will reject every request");
+    }
+
+    let tls_config = args.service.tls_config()?;
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -80,12 +100,16 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .app_data(app_state.clone())
+            .app_data(api_tokens.clone())
             .wrap(cors)
             .wrap(middleware::Logger::default())
             .wrap(middleware::Compress::default())
             .configure(api::configure_routes)
-    })
-    .bind((args.host.as_str(), args.port))?
-    .run()
-    .await
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_22((args.host.as_str(), args.port), config)?,
+        None => server.bind((args.host.as_str(), args.port))?,
+    };
+
+    server.run().await
 }
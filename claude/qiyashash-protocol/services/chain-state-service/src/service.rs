@@ -4,8 +4,11 @@ use crate::error::ChainStateError;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use sled::Db;
+use sled::transaction::ConflictableTransactionError;
+use sled::{Db, Tree};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use tracing::{debug, info, warn};
 
 /// A single entry in the chain
@@ -42,6 +45,43 @@ pub struct ChainState {
     pub updated_at: DateTime<Utc>,
     /// Total number of entries
     pub entry_count: u64,
+    /// Compaction checkpoint; verification starts here instead of genesis
+    #[serde(default)]
+    pub checkpoint: Option<ChainCheckpoint>,
+    /// Highest sequence whose entry compaction has deleted
+    ///
+    /// Trails `checkpoint` only while a compaction is being applied.
+    #[serde(default)]
+    pub compacted_through: u64,
+}
+
+/// Stand-in for entries removed by compaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainCheckpoint {
+    /// Sequence of the last removed entry
+    pub sequence: u64,
+    /// Hash of the last removed entry (the next entry's `previous_hash`)
+    pub entry_hash: String,
+    /// When the compaction happened
+    pub compacted_at: DateTime<Utc>,
+}
+
+/// Result of compacting a chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionResult {
+    /// Chain that was compacted
+    pub chain_id: String,
+    /// Number of entries removed
+    pub removed_entries: u64,
+    /// Checkpoint now anchoring the chain
+    pub checkpoint: ChainCheckpoint,
+}
+
+impl ChainState {
+    /// First sequence number still stored (after any compaction)
+    pub fn first_retained_sequence(&self) -> u64 {
+        self.checkpoint.as_ref().map_or(1, |c| c.sequence + 1)
+    }
 }
 
 /// Request to append a new entry
@@ -63,6 +103,21 @@ pub struct ChainStateManager {
     chains_db: Db,
     /// Database for chain entries
     entries_db: Db,
+    /// Write-ahead log of in-flight appends (chain_id:head -> pending chain state)
+    wal: Tree,
+    /// Per-chain locks serializing appends and compaction
+    chain_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+/// Outcome of replaying one write-ahead log record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalRecovery {
+    /// Entry was written but the head was not; head advanced to the entry
+    RolledForward,
+    /// Entry was never written; the append is discarded
+    RolledBack,
+    /// Head already reflected the append; only the log record was stale
+    AlreadyApplied,
 }
 
 impl ChainStateManager {
@@ -81,8 +136,170 @@ impl ChainStateManager {
             ChainStateError::StorageError(format!("Failed to open entries database: {}", e))
         })?;
 
+        let wal = chains_db.open_tree("wal").map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to open write-ahead log: {}", e))
+        })?;
+
+        let manager = Self {
+            chains_db,
+            entries_db,
+            wal,
+            chain_locks: Mutex::new(HashMap::new()),
+        };
+
+        let recovered = manager.recover()?;
+        if !recovered.is_empty() {
+            info!("Recovered {} interrupted appends", recovered.len());
+        }
+
         info!("Chain state manager initialized at {:?}", path);
-        Ok(Self { chains_db, entries_db })
+        Ok(manager)
+    }
+
+    /// Replay the write-ahead log, reconciling dangling entries with chain heads
+    ///
+    /// An append writes, in order: the log record, the entry (or a batch of
+    /// entries), the new head, then removes the log record, flushing each step
+    /// before the next. A crash at any point leaves a state that this
+    /// function resolves to either the old or the new head. Records are keyed
+    /// per append and replayed in head order.
+    pub fn recover(&self) -> Result<Vec<(String, WalRecovery)>, ChainStateError> {
+        let mut outcomes = Vec::new();
+
+        for record in self.wal.iter() {
+            let (key, value) = record.map_err(|e| {
+                ChainStateError::StorageError(format!("Failed to read write-ahead log: {}", e))
+            })?;
+            let pending: ChainState = serde_json::from_slice(&value).map_err(|e| {
+                ChainStateError::SerializationError(format!("Corrupt write-ahead log record: {}", e))
+            })?;
+            let chain_id = pending.chain_id.clone();
+
+            let current = self.get_chain(&chain_id)?;
+            let outcome = if current.head_sequence >= pending.head_sequence {
+                WalRecovery::AlreadyApplied
+            } else if self.links_to_pending(&chain_id, &current, &pending)? {
+                self.put_chain_state(&pending)?;
+                self.flush_chains()?;
+                WalRecovery::RolledForward
+            } else {
+                // Entries are missing or don't link to the head they were written for
+                for seq in current.head_sequence + 1..=pending.head_sequence {
+                    let entry_key = format!("{}:{}", chain_id, seq);
+                    self.entries_db.remove(&entry_key).map_err(|e| {
+                        ChainStateError::StorageError(format!("Failed to remove entry: {}", e))
+                    })?;
+                }
+                self.flush_entries()?;
+                WalRecovery::RolledBack
+            };
+
+            warn!(
+                "Write-ahead log for chain {} (sequence {}): {:?}",
+                chain_id, pending.head_sequence, outcome
+            );
+            self.clear_wal(&key)?;
+            outcomes.push((chain_id, outcome));
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Check that stored entries chain from `current` head up to `pending` head
+    fn links_to_pending(
+        &self,
+        chain_id: &str,
+        current: &ChainState,
+        pending: &ChainState,
+    ) -> Result<bool, ChainStateError> {
+        let mut previous_hash = current.head_hash.clone();
+        for seq in current.head_sequence + 1..=pending.head_sequence {
+            match self.get_entry(chain_id, seq) {
+                Ok(entry) if entry.previous_hash == previous_hash => {
+                    previous_hash = entry.entry_hash;
+                }
+                Ok(_) | Err(ChainStateError::ChainNotFound(_)) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(previous_hash == pending.head_hash)
+    }
+
+    /// Write lock for one chain, held while its head or entries change
+    fn chain_lock(&self, chain_id: &str) -> Arc<Mutex<()>> {
+        self.chain_locks
+            .lock()
+            .unwrap()
+            .entry(chain_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Persist a chain state record
+    fn put_chain_state(&self, state: &ChainState) -> Result<(), ChainStateError> {
+        let state_data = serde_json::to_vec(state).map_err(|e| {
+            ChainStateError::SerializationError(format!("Failed to serialize state: {}", e))
+        })?;
+
+        self.chains_db.insert(&state.chain_id, state_data).map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to update chain state: {}", e))
+        })?;
+        Ok(())
+    }
+
+    /// Record the head an append is about to produce, returning the record key
+    ///
+    /// The key includes the pending head sequence, zero-padded so records for
+    /// one chain iterate in append order.
+    fn write_wal(&self, pending: &ChainState) -> Result<String, ChainStateError> {
+        let key = format!("{}:{:020}", pending.chain_id, pending.head_sequence);
+        let data = serde_json::to_vec(pending).map_err(|e| {
+            ChainStateError::SerializationError(format!("Failed to serialize WAL record: {}", e))
+        })?;
+        self.wal.insert(key.as_bytes(), data).map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to write write-ahead log: {}", e))
+        })?;
+        self.wal.flush().map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to flush write-ahead log: {}", e))
+        })?;
+        Ok(key)
+    }
+
+    /// Remove a write-ahead log record once its append is durable
+    fn clear_wal(&self, key: impl AsRef<[u8]>) -> Result<(), ChainStateError> {
+        self.wal.remove(key).map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to clear write-ahead log: {}", e))
+        })?;
+        Ok(())
+    }
+
+    /// Make entry writes durable
+    fn flush_entries(&self) -> Result<(), ChainStateError> {
+        self.entries_db.flush().map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to flush entries: {}", e))
+        })?;
+        Ok(())
+    }
+
+    /// Make chain state writes durable
+    fn flush_chains(&self) -> Result<(), ChainStateError> {
+        self.chains_db.flush().map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to flush chain state: {}", e))
+        })?;
+        Ok(())
+    }
+
+    /// Store an entry under its chain and sequence key
+    fn put_entry(&self, chain_id: &str, entry: &ChainEntry) -> Result<(), ChainStateError> {
+        let entry_key = format!("{}:{}", chain_id, entry.sequence);
+        let entry_data = serde_json::to_vec(entry).map_err(|e| {
+            ChainStateError::SerializationError(format!("Failed to serialize entry: {}", e))
+        })?;
+
+        self.entries_db.insert(&entry_key, entry_data).map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to store entry: {}", e))
+        })?;
+        Ok(())
     }
 
     /// Create a new chain
@@ -107,6 +324,8 @@ impl ChainStateManager {
             created_at: now,
             updated_at: now,
             entry_count: 0,
+            checkpoint: None,
+            compacted_through: 0,
         };
 
         let serialized = serde_json::to_vec(&state).map_err(|e| {
@@ -136,6 +355,8 @@ impl ChainStateManager {
 
     /// Append an entry to a chain
     pub fn append_entry(&self, request: AppendRequest) -> Result<ChainEntry, ChainStateError> {
+        let lock = self.chain_lock(&request.chain_id);
+        let _guard = lock.lock().unwrap();
         let mut state = self.get_chain(&request.chain_id)?;
 
         // Verify expected previous hash if provided
@@ -170,29 +391,19 @@ impl ChainStateManager {
             metadata: request.metadata,
         };
 
-        // Store entry
-        let entry_key = format!("{}:{}", request.chain_id, new_sequence);
-        let entry_data = serde_json::to_vec(&entry).map_err(|e| {
-            ChainStateError::SerializationError(format!("Failed to serialize entry: {}", e))
-        })?;
-
-        self.entries_db.insert(&entry_key, entry_data).map_err(|e| {
-            ChainStateError::StorageError(format!("Failed to store entry: {}", e))
-        })?;
-
         // Update chain state
         state.head_sequence = new_sequence;
         state.head_hash = entry_hash;
         state.updated_at = now;
         state.entry_count += 1;
 
-        let state_data = serde_json::to_vec(&state).map_err(|e| {
-            ChainStateError::SerializationError(format!("Failed to serialize state: {}", e))
-        })?;
-
-        self.chains_db.insert(&request.chain_id, state_data).map_err(|e| {
-            ChainStateError::StorageError(format!("Failed to update chain state: {}", e))
-        })?;
+        // Log intent, store entry, advance head, then clear the log
+        let wal_key = self.write_wal(&state)?;
+        self.put_entry(&request.chain_id, &entry)?;
+        self.flush_entries()?;
+        self.put_chain_state(&state)?;
+        self.flush_chains()?;
+        self.clear_wal(&wal_key)?;
 
         debug!(
             "Appended entry {} to chain {}",
@@ -201,6 +412,102 @@ impl ChainStateManager {
         Ok(entry)
     }
 
+    /// Append several entries to a chain, in order, all-or-nothing
+    ///
+    /// Each entry links to the one before it in the batch. Only the first
+    /// request's `expected_previous_hash` is checked. Entries are written in a
+    /// single sled transaction; the head moves afterwards under the
+    /// write-ahead log, so a crash in between is rolled forward on recovery.
+    pub fn append_batch(
+        &self,
+        chain_id: &str,
+        requests: Vec<AppendRequest>,
+    ) -> Result<Vec<ChainEntry>, ChainStateError> {
+        if requests.is_empty() {
+            return Err(ChainStateError::ValidationError(
+                "Batch must contain at least one entry".to_string(),
+            ));
+        }
+        if let Some(other) = requests.iter().find(|r| r.chain_id != chain_id) {
+            return Err(ChainStateError::ValidationError(format!(
+                "Batch for chain {} contains entry for chain {}",
+                chain_id, other.chain_id
+            )));
+        }
+
+        let lock = self.chain_lock(chain_id);
+        let _guard = lock.lock().unwrap();
+        let mut state = self.get_chain(chain_id)?;
+
+        if let Some(expected) = &requests[0].expected_previous_hash {
+            if expected != &state.head_hash {
+                return Err(ChainStateError::HashMismatch {
+                    expected: expected.clone(),
+                    actual: state.head_hash.clone(),
+                });
+            }
+        }
+
+        let now = Utc::now();
+        let mut entries = Vec::with_capacity(requests.len());
+        let mut records = Vec::with_capacity(requests.len());
+        let mut previous_hash = state.head_hash.clone();
+
+        for (offset, request) in requests.into_iter().enumerate() {
+            let sequence = state.head_sequence + 1 + offset as u64;
+            let entry_hash = self.compute_entry_hash(
+                chain_id,
+                sequence,
+                &previous_hash,
+                &request.content_hash,
+                &now,
+            );
+            let entry = ChainEntry {
+                entry_id: format!("{}:{}", chain_id, sequence),
+                sequence,
+                previous_hash: std::mem::replace(&mut previous_hash, entry_hash.clone()),
+                content_hash: request.content_hash,
+                entry_hash,
+                timestamp: now,
+                metadata: request.metadata,
+            };
+            let data = serde_json::to_vec(&entry).map_err(|e| {
+                ChainStateError::SerializationError(format!("Failed to serialize entry: {}", e))
+            })?;
+            records.push((entry.entry_id.clone(), data));
+            entries.push(entry);
+        }
+
+        state.head_sequence += entries.len() as u64;
+        state.head_hash = previous_hash;
+        state.updated_at = now;
+        state.entry_count += entries.len() as u64;
+
+        let wal_key = self.write_wal(&state)?;
+        self.entries_db
+            .transaction(|tx| {
+                for (key, data) in &records {
+                    tx.insert(key.as_bytes(), data.as_slice())?;
+                }
+                Ok::<_, ConflictableTransactionError>(())
+            })
+            .map_err(|e| {
+                ChainStateError::StorageError(format!("Failed to store batch: {}", e))
+            })?;
+        self.flush_entries()?;
+        self.put_chain_state(&state)?;
+        self.flush_chains()?;
+        self.clear_wal(&wal_key)?;
+
+        debug!(
+            "Appended {} entries to chain {} (head {})",
+            entries.len(),
+            chain_id,
+            state.head_sequence
+        );
+        Ok(entries)
+    }
+
     /// Get an entry by chain ID and sequence number
     pub fn get_entry(&self, chain_id: &str, sequence: u64) -> Result<ChainEntry, ChainStateError> {
         let key = format!("{}:{}", chain_id, sequence);
@@ -225,8 +532,9 @@ impl ChainStateManager {
         to_sequence: u64,
     ) -> Result<Vec<ChainEntry>, ChainStateError> {
         let mut entries = Vec::new();
+        let first = self.get_chain(chain_id)?.first_retained_sequence();
 
-        for seq in from_sequence..=to_sequence {
+        for seq in from_sequence.max(first)..=to_sequence {
             match self.get_entry(chain_id, seq) {
                 Ok(entry) => entries.push(entry),
                 Err(ChainStateError::ChainNotFound(_)) => break,
@@ -245,9 +553,12 @@ impl ChainStateManager {
             return Ok(true);
         }
 
-        let mut previous_hash = self.compute_genesis_hash(chain_id);
+        let mut previous_hash = match &state.checkpoint {
+            Some(checkpoint) => checkpoint.entry_hash.clone(),
+            None => self.compute_genesis_hash(chain_id),
+        };
 
-        for seq in 1..=state.head_sequence {
+        for seq in state.first_retained_sequence()..=state.head_sequence {
             let entry = self.get_entry(chain_id, seq)?;
 
             // Verify previous hash linkage
@@ -283,6 +594,85 @@ impl ChainStateManager {
         Ok(true)
     }
 
+    /// Remove entries with sequence < `before`, anchoring the chain at a checkpoint
+    ///
+    /// The checkpoint keeps the hash of the last removed entry so the
+    /// remaining entries still verify. The head can never be compacted away.
+    /// Runs under the chain's write lock, and deletes only entries after
+    /// the last compacted one, finishing any compaction a crash interrupted.
+    pub fn compact_chain(
+        &self,
+        chain_id: &str,
+        before: u64,
+    ) -> Result<CompactionResult, ChainStateError> {
+        let lock = self.chain_lock(chain_id);
+        let _guard = lock.lock().unwrap();
+        let mut state = self.get_chain(chain_id)?;
+        self.remove_compacted(&mut state)?;
+        let first = state.first_retained_sequence();
+
+        if before > state.head_sequence {
+            return Err(ChainStateError::ValidationError(format!(
+                "Cannot compact before {}: head is at sequence {}",
+                before, state.head_sequence
+            )));
+        }
+        if before <= first {
+            return Err(ChainStateError::ValidationError(format!(
+                "Nothing to compact before {}: chain already starts at {}",
+                before, first
+            )));
+        }
+
+        let last_removed = self.get_entry(chain_id, before - 1)?;
+        let checkpoint = ChainCheckpoint {
+            sequence: last_removed.sequence,
+            entry_hash: last_removed.entry_hash,
+            compacted_at: Utc::now(),
+        };
+        let removed_entries = before - first;
+
+        // Anchor first so a crash mid-delete leaves only unreachable entries
+        state.checkpoint = Some(checkpoint.clone());
+        state.entry_count = state.entry_count.saturating_sub(removed_entries);
+        state.updated_at = checkpoint.compacted_at;
+        self.put_chain_state(&state)?;
+        self.flush_chains()?;
+        self.remove_compacted(&mut state)?;
+
+        info!(
+            "Compacted chain {}: removed {} entries before {}",
+            chain_id, removed_entries, before
+        );
+        Ok(CompactionResult {
+            chain_id: chain_id.to_string(),
+            removed_entries,
+            checkpoint,
+        })
+    }
+
+    /// Delete entries the checkpoint has replaced but that are still stored
+    fn remove_compacted(&self, state: &mut ChainState) -> Result<(), ChainStateError> {
+        let through = match &state.checkpoint {
+            Some(checkpoint) if checkpoint.sequence > state.compacted_through => {
+                checkpoint.sequence
+            }
+            _ => return Ok(()),
+        };
+
+        let mut batch = sled::Batch::default();
+        for seq in state.compacted_through + 1..=through {
+            batch.remove(format!("{}:{}", state.chain_id, seq).as_bytes());
+        }
+        self.entries_db.apply_batch(batch).map_err(|e| {
+            ChainStateError::StorageError(format!("Failed to remove entries: {}", e))
+        })?;
+        self.flush_entries()?;
+
+        state.compacted_through = through;
+        self.put_chain_state(state)
+    }
+
     /// List all chains
     pub fn list_chains(&self, limit: usize, offset: usize) -> Result<Vec<ChainState>, ChainStateError> {
         let mut chains = Vec::new();
@@ -389,4 +779,314 @@ mod tests {
 
         assert!(manager.verify_chain("test-chain").unwrap());
     }
+
+    #[test]
+    fn test_recover_entry_written_before_head_update() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pending, entry) = {
+            let manager = ChainStateManager::new(temp_dir.path()).unwrap();
+            manager.create_chain("test-chain").unwrap();
+            for i in 0..3 {
+                manager
+                    .append_entry(AppendRequest {
+                        chain_id: "test-chain".to_string(),
+                        content_hash: format!("content_{}", i),
+                        expected_previous_hash: None,
+                        metadata: None,
+                    })
+                    .unwrap();
+            }
+
+            // Build the 4th append by hand and stop after the entry write
+            let head = manager.get_chain("test-chain").unwrap();
+            let now = Utc::now();
+            let entry_hash = manager.compute_entry_hash(
+                "test-chain",
+                head.head_sequence + 1,
+                &head.head_hash,
+                "content_3",
+                &now,
+            );
+            let entry = ChainEntry {
+                entry_id: format!("test-chain:{}", head.head_sequence + 1),
+                sequence: head.head_sequence + 1,
+                previous_hash: head.head_hash.clone(),
+                content_hash: "content_3".to_string(),
+                entry_hash: entry_hash.clone(),
+                timestamp: now,
+                metadata: None,
+            };
+            let mut pending = head.clone();
+            pending.head_sequence += 1;
+            pending.head_hash = entry_hash;
+            pending.entry_count += 1;
+            pending.updated_at = now;
+
+            manager.write_wal(&pending).unwrap();
+            manager.put_entry("test-chain", &entry).unwrap();
+            // "Crash": head never updated
+            assert_eq!(manager.get_chain("test-chain").unwrap().head_sequence, 3);
+            (pending, entry)
+        };
+
+        let manager = ChainStateManager::new(temp_dir.path()).unwrap();
+        let state = manager.get_chain("test-chain").unwrap();
+        assert_eq!(state.head_sequence, pending.head_sequence);
+        assert_eq!(state.head_hash, entry.entry_hash);
+        assert_eq!(state.entry_count, 4);
+        assert!(manager.verify_chain("test-chain").unwrap());
+        assert!(manager.recover().unwrap().is_empty());
+
+        // Appending continues from the recovered head
+        let next = manager
+            .append_entry(AppendRequest {
+                chain_id: "test-chain".to_string(),
+                content_hash: "content_4".to_string(),
+                expected_previous_hash: Some(entry.entry_hash.clone()),
+                metadata: None,
+            })
+            .unwrap();
+        assert_eq!(next.sequence, 5);
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    #[test]
+    fn test_recover_discards_append_without_entry() {
+        let (manager, _temp) = create_test_manager();
+        manager.create_chain("test-chain").unwrap();
+        manager
+            .append_entry(AppendRequest {
+                chain_id: "test-chain".to_string(),
+                content_hash: "content_0".to_string(),
+                expected_previous_hash: None,
+                metadata: None,
+            })
+            .unwrap();
+
+        let head = manager.get_chain("test-chain").unwrap();
+        let mut pending = head.clone();
+        pending.head_sequence += 1;
+        pending.head_hash = "never-written".to_string();
+        manager.write_wal(&pending).unwrap();
+
+        let outcomes = manager.recover().unwrap();
+        assert_eq!(
+            outcomes,
+            vec![("test-chain".to_string(), WalRecovery::RolledBack)]
+        );
+        let state = manager.get_chain("test-chain").unwrap();
+        assert_eq!(state.head_sequence, head.head_sequence);
+        assert_eq!(state.head_hash, head.head_hash);
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    #[test]
+    fn test_wal_keeps_one_record_per_append() {
+        let (manager, _temp) = create_test_manager();
+        let head = manager.create_chain("test-chain").unwrap();
+
+        let mut first = head.clone();
+        first.head_sequence = 1;
+        let mut second = head.clone();
+        second.head_sequence = 2;
+        let first_key = manager.write_wal(&first).unwrap();
+        let second_key = manager.write_wal(&second).unwrap();
+        assert_ne!(first_key, second_key);
+        assert_eq!(manager.wal.len(), 2);
+
+        // Neither append wrote its entry, so both are rolled back
+        let outcomes = manager.recover().unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("test-chain".to_string(), WalRecovery::RolledBack),
+                ("test-chain".to_string(), WalRecovery::RolledBack),
+            ]
+        );
+        assert!(manager.wal.is_empty());
+        assert_eq!(manager.get_chain("test-chain").unwrap().head_sequence, 0);
+    }
+
+    fn append_n(manager: &ChainStateManager, chain_id: &str, n: usize) {
+        for i in 0..n {
+            manager
+                .append_entry(AppendRequest {
+                    chain_id: chain_id.to_string(),
+                    content_hash: format!("content_{}", i),
+                    expected_previous_hash: None,
+                    metadata: None,
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_compaction_preserves_verifiability() {
+        let (manager, _temp) = create_test_manager();
+        manager.create_chain("test-chain").unwrap();
+        append_n(&manager, "test-chain", 10);
+        let before = manager.get_chain("test-chain").unwrap();
+
+        let result = manager.compact_chain("test-chain", 6).unwrap();
+        assert_eq!(result.removed_entries, 5);
+        assert_eq!(result.checkpoint.sequence, 5);
+
+        let state = manager.get_chain("test-chain").unwrap();
+        assert_eq!(state.head_hash, before.head_hash);
+        assert_eq!(state.head_sequence, 10);
+        assert_eq!(state.entry_count, 5);
+        assert_eq!(state.first_retained_sequence(), 6);
+        assert!(manager.get_entry("test-chain", 5).is_err());
+        assert!(manager.verify_chain("test-chain").unwrap());
+
+        let entries = manager.get_entries("test-chain", 1, 100).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0].sequence, 6);
+        assert_eq!(entries[0].previous_hash, result.checkpoint.entry_hash);
+
+        // Compacting again builds on the checkpoint
+        let result = manager.compact_chain("test-chain", 10).unwrap();
+        assert_eq!(result.removed_entries, 4);
+        append_n(&manager, "test-chain", 2);
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    #[test]
+    fn test_compaction_resumes_after_interrupted_delete() {
+        let (manager, _temp) = create_test_manager();
+        manager.create_chain("test-chain").unwrap();
+        append_n(&manager, "test-chain", 10);
+        manager.compact_chain("test-chain", 4).unwrap();
+        assert_eq!(manager.get_chain("test-chain").unwrap().compacted_through, 3);
+
+        // Simulate a crash after the checkpoint moved to 7 but before deleting
+        let mut state = manager.get_chain("test-chain").unwrap();
+        let anchor = manager.get_entry("test-chain", 7).unwrap();
+        state.checkpoint = Some(ChainCheckpoint {
+            sequence: 7,
+            entry_hash: anchor.entry_hash,
+            compacted_at: Utc::now(),
+        });
+        manager.put_chain_state(&state).unwrap();
+        assert!(manager.entries_db.contains_key("test-chain:5").unwrap());
+
+        let result = manager.compact_chain("test-chain", 9).unwrap();
+        assert_eq!(result.checkpoint.sequence, 8);
+        let state = manager.get_chain("test-chain").unwrap();
+        assert_eq!(state.compacted_through, 8);
+        for seq in 1..=8 {
+            let key = format!("test-chain:{}", seq);
+            assert!(!manager.entries_db.contains_key(key).unwrap());
+        }
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    #[test]
+    fn test_compaction_rejects_over_aggressive_requests() {
+        let (manager, _temp) = create_test_manager();
+        manager.create_chain("test-chain").unwrap();
+        append_n(&manager, "test-chain", 3);
+
+        // Would remove the head
+        assert!(matches!(
+            manager.compact_chain("test-chain", 4),
+            Err(ChainStateError::ValidationError(_))
+        ));
+        // Nothing to remove
+        assert!(matches!(
+            manager.compact_chain("test-chain", 1),
+            Err(ChainStateError::ValidationError(_))
+        ));
+        manager.compact_chain("test-chain", 3).unwrap();
+        assert!(matches!(
+            manager.compact_chain("test-chain", 3),
+            Err(ChainStateError::ValidationError(_))
+        ));
+
+        assert_eq!(manager.get_chain("test-chain").unwrap().entry_count, 1);
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    fn batch(chain_id: &str, n: usize) -> Vec<AppendRequest> {
+        (0..n)
+            .map(|i| AppendRequest {
+                chain_id: chain_id.to_string(),
+                content_hash: format!("batch_{}", i),
+                expected_previous_hash: None,
+                metadata: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_append_batch_produces_verifiable_chain() {
+        let (manager, _temp) = create_test_manager();
+        manager.create_chain("test-chain").unwrap();
+        append_n(&manager, "test-chain", 2);
+
+        let entries = manager.append_batch("test-chain", batch("test-chain", 100)).unwrap();
+        assert_eq!(entries.len(), 100);
+        assert_eq!(entries[0].sequence, 3);
+        for pair in entries.windows(2) {
+            assert_eq!(pair[1].previous_hash, pair[0].entry_hash);
+            assert_eq!(pair[1].sequence, pair[0].sequence + 1);
+        }
+
+        let state = manager.get_chain("test-chain").unwrap();
+        assert_eq!(state.head_sequence, 102);
+        assert_eq!(state.entry_count, 102);
+        assert_eq!(state.head_hash, entries[99].entry_hash);
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    #[test]
+    fn test_append_batch_aborts_on_first_hash_mismatch() {
+        let (manager, _temp) = create_test_manager();
+        manager.create_chain("test-chain").unwrap();
+        append_n(&manager, "test-chain", 1);
+        let before = manager.get_chain("test-chain").unwrap();
+
+        let mut requests = batch("test-chain", 10);
+        requests[0].expected_previous_hash = Some("stale".to_string());
+        assert!(matches!(
+            manager.append_batch("test-chain", requests),
+            Err(ChainStateError::HashMismatch { .. })
+        ));
+
+        let after = manager.get_chain("test-chain").unwrap();
+        assert_eq!(after.head_sequence, before.head_sequence);
+        assert_eq!(after.head_hash, before.head_hash);
+        assert!(manager.get_entry("test-chain", 2).is_err());
+
+        // Later entries' expectations are ignored
+        let mut requests = batch("test-chain", 3);
+        requests[0].expected_previous_hash = Some(before.head_hash.clone());
+        requests[2].expected_previous_hash = Some("ignored".to_string());
+        manager.append_batch("test-chain", requests).unwrap();
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
+
+    #[test]
+    fn test_recover_rolls_forward_interrupted_batch() {
+        let temp = TempDir::new().unwrap();
+        {
+            let manager = ChainStateManager::new(temp.path()).unwrap();
+            manager.create_chain("test-chain").unwrap();
+            manager.append_batch("test-chain", batch("test-chain", 5)).unwrap();
+
+            // Simulate a crash after the entries landed but before the head moved
+            let applied = manager.get_chain("test-chain").unwrap();
+            let mut rewound = applied.clone();
+            rewound.head_sequence = 0;
+            rewound.head_hash = manager.compute_genesis_hash("test-chain");
+            rewound.entry_count = 0;
+            manager.put_chain_state(&rewound).unwrap();
+            manager.write_wal(&applied).unwrap();
+        }
+
+        let manager = ChainStateManager::new(temp.path()).unwrap();
+        let state = manager.get_chain("test-chain").unwrap();
+        assert_eq!(state.head_sequence, 5);
+        assert!(manager.verify_chain("test-chain").unwrap());
+    }
 }
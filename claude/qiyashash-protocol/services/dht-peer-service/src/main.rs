@@ -11,10 +11,12 @@ use libp2p::{
 use serde::Serialize;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use qiyashash_service_common::args::ServiceArgs;
+
 mod error;
 mod peer;
 mod storage;
@@ -50,6 +52,9 @@ struct Args {
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    #[command(flatten)]
+    service: ServiceArgs,
 }
 
 /// Shared application state
@@ -102,13 +107,17 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize logging
     let log_level = if args.verbose { Level::DEBUG } else { Level::INFO };
-    let subscriber = FmtSubscriber::builder()
+    let builder = FmtSubscriber::builder()
         .with_max_level(log_level)
         .with_target(true)
         .with_thread_ids(true)
         .json()
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+        .with_filter_reloading();
+    let filter = builder.reload_handle();
+    tracing::subscriber::set_global_default(builder.finish())?;
+
+    // Apply log level edits to --config while running; --verbose wins
+    let _config_watcher = args.service.watch_log_level(filter, args.verbose);
 
     info!("Starting DHT Peer Service");
     info!("Storage path: {}", args.storage_path);
@@ -141,26 +150,38 @@ async fn main() -> anyhow::Result<()> {
 
     // Start HTTP API server
     let api_port = args.api_port;
-    let http_server = HttpServer::new(move || {
+    let tls_config = args.service.tls_config()?;
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
             .wrap(middleware::Logger::default())
             .route("/api/v1/health", web::get().to(health_check))
             .route("/api/v1/peer", web::get().to(peer_info))
-    })
-    .bind(("0.0.0.0", api_port))?
-    .run();
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_22(("0.0.0.0", api_port), config)?,
+        None => server.bind(("0.0.0.0", api_port))?,
+    };
+
+    let http_server = server.run();
 
     info!("HTTP API listening on port {}", api_port);
 
-    // Run DHT peer event loop
-    let peer_handle = {
+    // Run DHT peer event loop until shutdown is signalled
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+    let mut peer_handle = {
         let peer = peer.clone();
         tokio::spawn(async move {
             loop {
                 let mut peer_guard = peer.write().await;
-                if let Err(e) = peer_guard.poll_once().await {
-                    error!("DHT peer error: {}", e);
+                tokio::select! {
+                    result = peer_guard.poll_once() => {
+                        if let Err(e) = result {
+                            error!("DHT peer error: {}", e);
+                        }
+                    }
+                    _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
                 }
                 drop(peer_guard);
                 tokio::time::sleep(Duration::from_millis(10)).await;
@@ -175,7 +196,7 @@ async fn main() -> anyhow::Result<()> {
                 error!("HTTP server error: {}", e);
             }
         }
-        _ = peer_handle => {
+        _ = &mut peer_handle => {
             warn!("DHT peer loop ended unexpectedly");
         }
         _ = tokio::signal::ctrl_c() => {
@@ -184,5 +205,14 @@ async fn main() -> anyhow::Result<()> {
     }
 
     info!("DHT Peer Service shutting down");
+    shutdown_tx.send_replace(true);
+    if !peer_handle.is_finished() {
+        if let Err(e) = peer_handle.await {
+            error!("DHT peer loop failed: {}", e);
+        }
+    }
+    if let Err(e) = peer.write().await.shutdown() {
+        error!("DHT peer shutdown failed: {}", e);
+    }
     Ok(())
 }
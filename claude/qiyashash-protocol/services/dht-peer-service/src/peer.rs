@@ -7,7 +7,8 @@ use libp2p::{
     kad::{self, store::MemoryStore, Behaviour as KademliaBehaviour, Config as KadConfig},
     mdns,
     noise,
-    swarm::{NetworkBehaviour, Swarm, SwarmEvent},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, Swarm, SwarmEvent},
+    core::transport::ListenerId,
     tcp, yamux, Multiaddr, PeerId, StreamProtocol,
 };
 use std::collections::HashSet;
@@ -20,7 +21,8 @@ use tracing::{debug, error, info, warn};
 #[behaviour(out_event = "DhtBehaviourEvent")]
 pub struct DhtBehaviour {
     pub kademlia: KademliaBehaviour<MemoryStore>,
-    pub mdns: mdns::tokio::Behaviour,
+    /// Local discovery; disabled unless the peer was created with mDNS
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
 }
 
 /// Events from the network behaviour
@@ -47,6 +49,7 @@ pub struct DhtPeer {
     swarm: Swarm<DhtBehaviour>,
     local_peer_id: PeerId,
     listen_addresses: Vec<Multiaddr>,
+    listeners: Vec<ListenerId>,
     connected_peers: HashSet<PeerId>,
     message_store: Arc<MessageStore>,
 }
@@ -83,12 +86,17 @@ impl DhtPeer {
                 let kademlia = KademliaBehaviour::with_config(local_peer_id, store, kad_config);
 
                 // mDNS for local discovery
-                let mdns = mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    local_peer_id,
-                ).expect("Failed to create mDNS behaviour");
+                let mdns = if enable_mdns {
+                    Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?)
+                } else {
+                    info!("mDNS discovery disabled");
+                    None
+                };
 
-                DhtBehaviour { kademlia, mdns }
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(DhtBehaviour {
+                    kademlia,
+                    mdns: Toggle::from(mdns),
+                })
             })
             .map_err(|e| DhtError::NetworkError(format!("Failed to create behaviour: {}", e)))?
             .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
@@ -98,13 +106,15 @@ impl DhtPeer {
             swarm,
             local_peer_id,
             listen_addresses: Vec::new(),
+            listeners: Vec::new(),
             connected_peers: HashSet::new(),
             message_store,
         };
 
         // Start listening
-        peer.swarm.listen_on(listen_addr.clone())
+        let listener = peer.swarm.listen_on(listen_addr.clone())
             .map_err(|e| DhtError::NetworkError(format!("Failed to listen: {}", e)))?;
+        peer.listeners.push(listener);
         
         info!("Listening on {}", listen_addr);
 
@@ -185,6 +195,17 @@ impl DhtPeer {
         Ok(())
     }
 
+    /// Close listeners and flush the message store
+    ///
+    /// Call after the polling loop has stopped.
+    pub fn shutdown(&mut self) -> Result<(), DhtError> {
+        for listener in self.listeners.drain(..) {
+            self.swarm.remove_listener(listener);
+        }
+        self.listen_addresses.clear();
+        self.message_store.flush()
+    }
+
     /// Handle swarm events
     fn handle_event(&mut self, event: SwarmEvent<DhtBehaviourEvent>) {
         match event {
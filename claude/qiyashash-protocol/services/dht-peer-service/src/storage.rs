@@ -113,6 +113,14 @@ impl MessageStore {
         Ok(existed)
     }
 
+    /// Flush pending writes to disk
+    pub fn flush(&self) -> Result<(), DhtError> {
+        self.db
+            .flush()
+            .map_err(|e| DhtError::StorageError(format!("Failed to flush: {}", e)))?;
+        Ok(())
+    }
+
     /// Get record count
     pub fn record_count(&self) -> usize {
         self.record_count.load(Ordering::Relaxed)
@@ -8,6 +8,8 @@ use clap::Parser;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use qiyashash_service_common::args::ServiceArgs;
+
 mod api;
 mod error;
 mod service;
@@ -34,6 +36,9 @@ struct Args {
     /// Storage path
     #[arg(short, long, default_value = "./data/encryption")]
     storage_path: String,
+
+    #[command(flatten)]
+    service: ServiceArgs,
 }
 
 #[actix_web::main]
@@ -50,15 +55,18 @@ async fn main() -> std::io::Result<()> {
         _ => Level::INFO,
     };
 
-    let subscriber = FmtSubscriber::builder()
+    let builder = FmtSubscriber::builder()
         .with_max_level(level)
         .with_target(true)
         .with_thread_ids(true)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)
+        .with_filter_reloading();
+    let filter = builder.reload_handle();
+    tracing::subscriber::set_global_default(builder.finish())
         .expect("Failed to set tracing subscriber");
 
+    // Apply log level edits to --config while running
+    let _config_watcher = args.service.watch_log_level(filter, false);
+
     info!("Starting QiyasHash Encryption Service");
     info!("Binding to {}:{}", args.host, args.port);
 
@@ -68,14 +76,20 @@ async fn main() -> std::io::Result<()> {
     let service = web::Data::new(service);
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let addr = format!("{}:{}", args.host, args.port);
+    let tls_config = args.service.tls_config()?;
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(service.clone())
             .wrap(middleware::Logger::default())
             .wrap(actix_cors::Cors::permissive())
             .configure(api::configure_routes)
-    })
-    .bind(format!("{}:{}", args.host, args.port))?
-    .run()
-    .await
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_22(addr.as_str(), config)?,
+        None => server.bind(addr.as_str())?,
+    };
+
+    server.run().await
 }
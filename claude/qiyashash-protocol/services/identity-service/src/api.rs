@@ -1,9 +1,12 @@
 //! API handlers for Identity Service
 
+use actix_web::middleware::from_fn;
 use actix_web::{web, HttpResponse, Result as ActixResult};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info};
 
+use qiyashash_service_common::auth::require_token;
+
 use crate::error::ServiceError;
 use crate::AppState;
 
@@ -11,12 +14,22 @@ use crate::AppState;
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api/v1/identity")
-            .route("/generate", web::post().to(generate_identity))
-            .route("/rotate", web::post().to(rotate_identity))
+            .route("/generate", web::post().to(generate_identity).wrap(from_fn(require_token)))
+            .route("/rotate", web::post().to(rotate_identity).wrap(from_fn(require_token)))
             .route("/verify", web::post().to(verify_identity))
             .route("/prekeys", web::get().to(get_prekeys))
-            .route("/prekeys", web::post().to(register_prekeys))
-            .route("/bundle/{user_id}", web::get().to(get_bundle))
+            .route("/prekeys", web::post().to(register_prekeys).wrap(from_fn(require_token)))
+            .route("/bundle/{user_id}", web::get().to(get_bundle).wrap(from_fn(require_token)))
+            .route("/{user_id}/rotations", web::get().to(get_rotations))
+            .route(
+                "/{user_id}/devices/{device_id}/rotate-spk",
+                web::post().to(rotate_signed_prekey).wrap(from_fn(require_token)),
+            )
+            .route(
+                "/{user_id}/devices/{device_id}/signed-prekeys/{prekey_id}",
+                web::get().to(get_signed_prekey),
+            )
+            .route("/stats", web::get().to(storage_stats).wrap(from_fn(require_token)))
             .route("/health", web::get().to(health_check)),
     );
 }
@@ -45,6 +58,7 @@ pub struct SignedPreKeyResponse {
     pub id: u32,
     pub public_key: String,
     pub signature: String,
+    pub timestamp: i64,
 }
 
 /// One-time prekey response
@@ -108,6 +122,27 @@ async fn rotate_identity(
     Ok(HttpResponse::Ok().json(result))
 }
 
+/// Rotation history response
+#[derive(Debug, Serialize)]
+pub struct RotationHistoryResponse {
+    pub user_id: String,
+    pub rotations: Vec<RotationProofResponse>,
+    pub chain_valid: bool,
+}
+
+/// Get rotation history, oldest first
+async fn get_rotations(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> ActixResult<HttpResponse, ServiceError> {
+    let user_id = path.into_inner();
+    debug!("Getting rotation history for user: {}", user_id);
+
+    let result = state.service.get_rotation_history(&user_id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
 /// Verify identity request
 #[derive(Debug, Deserialize)]
 pub struct VerifyIdentityRequest {
@@ -146,6 +181,7 @@ pub struct GetPreKeysResponse {
     pub count: usize,
     pub needs_replenishment: bool,
     pub signed_prekey_id: u32,
+    pub needs_signed_prekey_rotation: bool,
 }
 
 /// Get prekey status
@@ -181,6 +217,7 @@ pub struct SignedPreKeyInput {
     pub id: u32,
     pub public_key: String,
     pub signature: String,
+    pub timestamp: i64,
 }
 
 /// One-time prekey input
@@ -216,6 +253,39 @@ async fn register_prekeys(
     Ok(HttpResponse::Ok().json(result))
 }
 
+/// Rotate a device's signed prekey
+async fn rotate_signed_prekey(
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    req: web::Json<SignedPreKeyInput>,
+) -> ActixResult<HttpResponse, ServiceError> {
+    let (user_id, device_id) = path.into_inner();
+    info!("Rotating signed prekey for user {} device {}", user_id, device_id);
+
+    let result = state
+        .service
+        .rotate_signed_prekey(&user_id, &device_id, &req)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Get a signed prekey by ID, including a recently retired one
+async fn get_signed_prekey(
+    state: web::Data<AppState>,
+    path: web::Path<(String, String, u32)>,
+) -> ActixResult<HttpResponse, ServiceError> {
+    let (user_id, device_id, prekey_id) = path.into_inner();
+
+    let result = state
+        .service
+        .get_signed_prekey(&user_id, &device_id, prekey_id)
+        .await?
+        .ok_or_else(|| ServiceError::NotFound(format!("Signed prekey {} not found", prekey_id)))?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
 /// Get prekey bundle response
 #[derive(Debug, Serialize)]
 pub struct PreKeyBundleResponse {
@@ -249,6 +319,23 @@ pub struct DeviceIdQuery {
     pub device_id: Option<String>,
 }
 
+/// Storage statistics response
+#[derive(Debug, Serialize)]
+pub struct StorageStatsResponse {
+    pub user_count: usize,
+    pub prekey_count: usize,
+    pub storage_size_bytes: u64,
+    pub prekey_bytes: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// Get storage statistics
+async fn storage_stats(state: web::Data<AppState>) -> ActixResult<HttpResponse, ServiceError> {
+    let result = state.service.storage_stats().await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
 /// Health check response
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
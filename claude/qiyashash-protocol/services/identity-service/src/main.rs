@@ -6,9 +6,13 @@ use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
 use clap::Parser;
 use std::sync::Arc;
-use tracing::{info, Level};
+use std::time::Duration;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use qiyashash_service_common::args::{ApiTokenArgs, ServiceArgs};
+use qiyashash_service_common::auth;
+
 mod api;
 mod error;
 mod service;
@@ -37,6 +41,20 @@ struct Args {
     /// Log level
     #[arg(short, long, default_value = "info")]
     log_level: String,
+
+    #[command(flatten)]
+    service: ServiceArgs,
+
+    #[command(flatten)]
+    auth: ApiTokenArgs,
+
+    /// Seconds a replaced signed prekey stays valid
+    #[arg(long, default_value_t = service::DEFAULT_SPK_GRACE_PERIOD_SECS)]
+    spk_grace_period: i64,
+
+    /// Storage vacuum interval in seconds (0 disables)
+    #[arg(long, default_value = "3600")]
+    vacuum_interval: u64,
 }
 
 /// Application state
@@ -58,16 +76,19 @@ async fn main() -> std::io::Result<()> {
         _ => Level::INFO,
     };
 
-    let subscriber = FmtSubscriber::builder()
+    let builder = FmtSubscriber::builder()
         .with_max_level(log_level)
         .with_target(true)
         .with_thread_ids(true)
         .json()
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)
+        .with_filter_reloading();
+    let filter = builder.reload_handle();
+    tracing::subscriber::set_global_default(builder.finish())
         .expect("Failed to set tracing subscriber");
 
+    // Apply log level edits to --config while running
+    let _config_watcher = args.service.watch_log_level(filter, false);
+
     info!("Starting Identity Service on {}:{}", args.host, args.port);
 
     // Initialize storage
@@ -75,12 +96,30 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to open storage");
 
     // Initialize service
-    let service = Arc::new(IdentityServiceImpl::new(storage));
+    let service = Arc::new(
+        IdentityServiceImpl::new(storage).with_spk_grace_period(args.spk_grace_period),
+    );
+
+    // Spawn storage vacuum task
+    if args.vacuum_interval > 0 {
+        let service = service.clone();
+        let interval = Duration::from_secs(args.vacuum_interval);
+        tokio::spawn(async move {
+            vacuum_task(service, interval).await;
+        });
+    }
 
     let app_state = web::Data::new(AppState { service });
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let api_tokens = web::Data::new(auth::ApiTokens::new(args.auth.api_tokens.clone()));
+    if !api_tokens.is_enabled() {
+        warn!("No API tokens configured; mutating routes will reject every request");
+    }
+
+    let tls_config = args.service.tls_config()?;
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -89,11 +128,85 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .app_data(app_state.clone())
+            .app_data(api_tokens.clone())
             .wrap(middleware::Logger::default())
             .wrap(cors)
             .configure(api::configure)
-    })
-    .bind((args.host.as_str(), args.port))?
-    .run()
-    .await
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_22((args.host.as_str(), args.port), config)?,
+        None => server.bind((args.host.as_str(), args.port))?,
+    };
+
+    server.run().await
+}
+
+/// Background task to compact storage and drop tombstones
+async fn vacuum_task(service: Arc<IdentityServiceImpl>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let service = service.clone();
+        match tokio::task::spawn_blocking(move || service.vacuum()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Storage vacuum failed: {}", e),
+            Err(e) => error!("Storage vacuum task panicked: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qiyashash_service_common::tls;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Write a self-signed localhost certificate, returning (cert, key, cert PEM)
+    fn write_self_signed(dir: &TempDir) -> (PathBuf, PathBuf, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, &cert_pem).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path, cert_pem)
+    }
+
+    #[actix_web::test]
+    async fn test_health_served_over_tls() {
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path, cert_pem) = write_self_signed(&dir);
+        let config = tls::server_config(Some(&cert_path), Some(&key_path))
+            .unwrap()
+            .expect("TLS should be enabled");
+
+        let server = HttpServer::new(|| App::new().configure(api::configure))
+            .workers(1)
+            .bind_rustls_0_22(("127.0.0.1", 0), config)
+            .unwrap();
+        let addr: SocketAddr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(cert_pem.as_bytes()).unwrap())
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://localhost:{}/api/v1/identity/health", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        // Plaintext HTTP against the TLS port must not be served
+        let plaintext = reqwest::get(format!("http://{}/api/v1/identity/health", addr)).await;
+        assert!(plaintext.map_or(true, |r| !r.status().is_success()));
+
+        handle.stop(false).await;
+    }
 }
@@ -3,13 +3,17 @@
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info};
 
-use qiyashash_crypto::identity::{Identity, IdentityKeyPair, IdentityPublicKey};
+use qiyashash_crypto::identity::{
+    verify_rotation_chain, Identity, IdentityKeyPair, IdentityPublicKey, IdentityRotationProof,
+};
+use qiyashash_crypto::keys::SignedPreKey;
 use qiyashash_crypto::x3dh::PreKeyManager;
 
 use crate::api::{
     GenerateIdentityResponse, GetPreKeysResponse, OneTimePreKeyInput, OneTimePreKeyResponse,
-    PreKeyBundleResponse, RegisterPreKeysResponse, RotateIdentityResponse, RotationProofResponse,
-    SignedPreKeyResponse, VerifyIdentityResponse,
+    PreKeyBundleResponse, RegisterPreKeysResponse, RotateIdentityResponse, RotationHistoryResponse,
+    RotationProofResponse, SignedPreKeyInput, SignedPreKeyResponse, StorageStatsResponse,
+    VerifyIdentityResponse,
 };
 use crate::error::ServiceError;
 use crate::storage::RocksDbStorage;
@@ -32,23 +36,49 @@ struct StoredDevice {
     created_at: i64,
 }
 
+/// Age after which a device should upload a new signed prekey
+const SPK_ROTATION_INTERVAL_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// How long a replaced signed prekey stays valid by default
+pub const DEFAULT_SPK_GRACE_PERIOD_SECS: i64 = 7 * 24 * 60 * 60;
+
 /// Stored prekey
 #[derive(Serialize, Deserialize)]
 struct StoredPreKey {
     id: u32,
     public_key: String,
     signature: Option<String>,
+    #[serde(default)]
+    created_at: i64,
+}
+
+/// Signed prekey kept around after rotation for in-flight X3DH
+#[derive(Serialize, Deserialize)]
+struct RetiredPreKey {
+    prekey: StoredPreKey,
+    retired_at: i64,
 }
 
 /// Identity service implementation
 pub struct IdentityServiceImpl {
     storage: RocksDbStorage,
+    /// Seconds a replaced signed prekey remains usable
+    spk_grace_period_secs: i64,
 }
 
 impl IdentityServiceImpl {
     /// Create new service
     pub fn new(storage: RocksDbStorage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            spk_grace_period_secs: DEFAULT_SPK_GRACE_PERIOD_SECS,
+        }
+    }
+
+    /// Set how long a replaced signed prekey remains usable
+    pub fn with_spk_grace_period(mut self, secs: i64) -> Self {
+        self.spk_grace_period_secs = secs;
+        self
     }
 
     /// Generate a new identity
@@ -102,6 +132,7 @@ impl IdentityServiceImpl {
             id: bundle.signed_prekey.id,
             public_key: hex::encode(bundle.signed_prekey.public_key.as_bytes()),
             signature: Some(hex::encode(bundle.signed_prekey.signature)),
+            created_at: bundle.signed_prekey.timestamp,
         };
 
         self.storage.store_signed_prekey(
@@ -116,6 +147,7 @@ impl IdentityServiceImpl {
                 id: otpk.id,
                 public_key: hex::encode(otpk.public_key.as_bytes()),
                 signature: None,
+                created_at: chrono::Utc::now().timestamp(),
             };
 
             self.storage.store_one_time_prekey(
@@ -137,6 +169,7 @@ impl IdentityServiceImpl {
                 id: bundle.signed_prekey.id,
                 public_key: hex::encode(bundle.signed_prekey.public_key.as_bytes()),
                 signature: hex::encode(bundle.signed_prekey.signature),
+                timestamp: bundle.signed_prekey.timestamp,
             },
             one_time_prekeys: bundle
                 .one_time_prekey
@@ -199,14 +232,47 @@ impl IdentityServiceImpl {
         Ok(RotateIdentityResponse {
             new_identity_key: hex::encode(new_identity.key_pair.public_key().signing_key_bytes()),
             new_fingerprint: hex::encode(new_identity.fingerprint),
-            rotation_proof: RotationProofResponse {
-                old_public_key: hex::encode(proof.old_public_key.signing_key),
-                new_public_key: hex::encode(proof.new_public_key.signing_key),
-                old_signature: hex::encode(proof.old_signature),
-                new_signature: hex::encode(proof.new_signature),
-                timestamp: proof.timestamp,
-                commitment: hex::encode(proof.commitment),
-            },
+            rotation_proof: rotation_proof_response(&proof),
+        })
+    }
+
+    /// Get rotation history, oldest first
+    ///
+    /// `chain_valid` is set when every proof verifies, consecutive proofs
+    /// link up, and the last proof ends at the currently stored identity.
+    pub async fn get_rotation_history(
+        &self,
+        user_id: &str,
+    ) -> Result<RotationHistoryResponse, ServiceError> {
+        let identity_data = self
+            .storage
+            .get_identity(user_id)?
+            .ok_or_else(|| ServiceError::NotFound(format!("User {} not found", user_id)))?;
+        let stored: StoredIdentity = serde_json::from_slice(&identity_data)?;
+
+        let proofs = self
+            .storage
+            .get_rotation_history(user_id)?
+            .iter()
+            .map(|data| serde_json::from_slice::<IdentityRotationProof>(data))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let current_secret: [u8; 32] = hex::decode(&stored.identity_key_secret)?
+            .try_into()
+            .map_err(|_| ServiceError::Crypto("Invalid key length".to_string()))?;
+        let current_key = IdentityKeyPair::from_secret_bytes(&current_secret)
+            .public_key()
+            .signing_key_bytes();
+        let ends_at_current = proofs
+            .last()
+            .map_or(true, |last| last.new_public_key.signing_key == current_key);
+
+        let chain_valid = ends_at_current && verify_rotation_chain(&proofs).is_ok();
+
+        Ok(RotationHistoryResponse {
+            user_id: user_id.to_string(),
+            rotations: proofs.iter().map(rotation_proof_response).collect(),
+            chain_valid,
         })
     }
 
@@ -293,10 +359,16 @@ impl IdentityServiceImpl {
             .map(|data| serde_json::from_slice::<StoredPreKey>(&data).ok())
             .flatten();
 
+        let signed_prekey_age = signed_prekey
+            .as_ref()
+            .map(|p| chrono::Utc::now().timestamp() - p.created_at);
+
         Ok(GetPreKeysResponse {
             count,
             needs_replenishment: count < 20,
             signed_prekey_id: signed_prekey.map(|p| p.id).unwrap_or(0),
+            needs_signed_prekey_rotation: signed_prekey_age
+                .map_or(true, |age| age >= SPK_ROTATION_INTERVAL_SECS),
         })
     }
 
@@ -314,6 +386,7 @@ impl IdentityServiceImpl {
                 id: prekey.id,
                 public_key: prekey.public_key.clone(),
                 signature: None,
+                created_at: chrono::Utc::now().timestamp(),
             };
 
             self.storage.store_one_time_prekey(
@@ -341,6 +414,138 @@ impl IdentityServiceImpl {
         })
     }
 
+    /// Replace a device's signed prekey
+    ///
+    /// The new key must be signed by the user's identity key. The replaced
+    /// key is retired rather than deleted so sessions started against it
+    /// during the grace period can still be completed.
+    pub async fn rotate_signed_prekey(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        prekey: &SignedPreKeyInput,
+    ) -> Result<SignedPreKeyResponse, ServiceError> {
+        let identity_data = self
+            .storage
+            .get_identity(user_id)?
+            .ok_or_else(|| ServiceError::NotFound(format!("User {} not found", user_id)))?;
+        let identity: StoredIdentity = serde_json::from_slice(&identity_data)?;
+
+        let current_data = self
+            .storage
+            .get_signed_prekey(user_id, device_id)?
+            .ok_or_else(|| ServiceError::NotFound(format!("Device {} not found", device_id)))?;
+        let current: StoredPreKey = serde_json::from_slice(&current_data)?;
+
+        if prekey.id == current.id {
+            return Err(ServiceError::BadRequest(format!(
+                "Signed prekey {} is already active",
+                prekey.id
+            )));
+        }
+
+        let public_key: [u8; 32] = hex::decode(&prekey.public_key)?
+            .try_into()
+            .map_err(|_| ServiceError::BadRequest("Invalid prekey length".to_string()))?;
+        let signature: [u8; 64] = hex::decode(&prekey.signature)?
+            .try_into()
+            .map_err(|_| ServiceError::BadRequest("Invalid signature length".to_string()))?;
+
+        let secret: [u8; 32] = hex::decode(&identity.identity_key_secret)?
+            .try_into()
+            .map_err(|_| ServiceError::Crypto("Invalid key length".to_string()))?;
+        IdentityKeyPair::from_secret_bytes(&secret)
+            .public_key()
+            .verify(&SignedPreKey::signed_data(&public_key, prekey.timestamp), &signature)
+            .map_err(|_| {
+                ServiceError::VerificationFailed(
+                    "Signed prekey not signed by identity key".to_string(),
+                )
+            })?;
+
+        let now = chrono::Utc::now().timestamp();
+        let retired = RetiredPreKey {
+            prekey: current,
+            retired_at: now,
+        };
+        self.storage.store_retired_signed_prekey(
+            user_id,
+            device_id,
+            &serde_json::to_vec(&retired)?,
+        )?;
+
+        let stored = StoredPreKey {
+            id: prekey.id,
+            public_key: prekey.public_key.clone(),
+            signature: Some(prekey.signature.clone()),
+            created_at: prekey.timestamp,
+        };
+        self.storage
+            .store_signed_prekey(user_id, device_id, &serde_json::to_vec(&stored)?)?;
+
+        info!(
+            "Rotated signed prekey for user {} device {}: {} -> {}",
+            user_id, device_id, retired.prekey.id, prekey.id
+        );
+
+        Ok(SignedPreKeyResponse {
+            id: stored.id,
+            public_key: stored.public_key,
+            signature: prekey.signature.clone(),
+            timestamp: stored.created_at,
+        })
+    }
+
+    /// Look up a signed prekey by ID
+    ///
+    /// Finds the active key, or the retired one while its grace period lasts.
+    pub async fn get_signed_prekey(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        prekey_id: u32,
+    ) -> Result<Option<SignedPreKeyResponse>, ServiceError> {
+        let now = chrono::Utc::now().timestamp();
+
+        if let Some(data) = self.storage.get_signed_prekey(user_id, device_id)? {
+            let active: StoredPreKey = serde_json::from_slice(&data)?;
+            if active.id == prekey_id {
+                return Ok(Some(signed_prekey_response(active)));
+            }
+        }
+
+        let Some(data) = self.storage.get_retired_signed_prekey(user_id, device_id)? else {
+            return Ok(None);
+        };
+        let retired: RetiredPreKey = serde_json::from_slice(&data)?;
+
+        if now - retired.retired_at >= self.spk_grace_period_secs {
+            self.storage.delete_retired_signed_prekey(user_id, device_id)?;
+            return Ok(None);
+        }
+
+        Ok((retired.prekey.id == prekey_id).then(|| signed_prekey_response(retired.prekey)))
+    }
+
+    /// Drop retired signed prekeys whose grace period ended before `now`
+    pub fn purge_retired_signed_prekeys(&self, now: i64) -> Result<usize, ServiceError> {
+        let mut purged = 0;
+
+        for (user_id, device_id, data) in self.storage.get_all_retired_signed_prekeys()? {
+            let retired: RetiredPreKey = serde_json::from_slice(&data)?;
+            if now - retired.retired_at >= self.spk_grace_period_secs {
+                self.storage.delete_retired_signed_prekey(&user_id, &device_id)?;
+                purged += 1;
+            }
+        }
+
+        if purged > 0 {
+            info!("Purged {} retired signed prekeys", purged);
+        }
+
+        Ok(purged)
+    }
+
     /// Get prekey bundle
     pub async fn get_prekey_bundle(
         &self,
@@ -412,8 +617,220 @@ impl IdentityServiceImpl {
                 id: signed_prekey.id,
                 public_key: signed_prekey.public_key,
                 signature: signed_prekey.signature.unwrap_or_default(),
+                timestamp: signed_prekey.created_at,
             },
             one_time_prekey,
         })
     }
+
+    /// Get storage statistics
+    pub async fn storage_stats(&self) -> Result<StorageStatsResponse, ServiceError> {
+        let stats = self.storage.stats()?;
+
+        Ok(StorageStatsResponse {
+            user_count: stats.user_count,
+            prekey_count: stats.prekey_count,
+            storage_size_bytes: stats.storage_size_bytes,
+            prekey_bytes: stats.prekey_bytes,
+            reclaimed_bytes: stats.reclaimed_bytes,
+        })
+    }
+
+    /// Purge expired retired prekeys and compact storage, returning the
+    /// bytes reclaimed
+    ///
+    /// Blocks while RocksDB compacts; run it off the async executor.
+    pub fn vacuum(&self) -> Result<u64, ServiceError> {
+        self.purge_retired_signed_prekeys(chrono::Utc::now().timestamp())?;
+
+        let reclaimed = self.storage.vacuum()?;
+        info!("Vacuumed identity storage, reclaimed {} bytes", reclaimed);
+        Ok(reclaimed)
+    }
+}
+
+/// Convert a stored signed prekey for the API
+fn signed_prekey_response(prekey: StoredPreKey) -> SignedPreKeyResponse {
+    SignedPreKeyResponse {
+        id: prekey.id,
+        public_key: prekey.public_key,
+        signature: prekey.signature.unwrap_or_default(),
+        timestamp: prekey.created_at,
+    }
+}
+
+/// Hex-encode a rotation proof for the API
+fn rotation_proof_response(proof: &IdentityRotationProof) -> RotationProofResponse {
+    RotationProofResponse {
+        old_public_key: hex::encode(proof.old_public_key.signing_key),
+        new_public_key: hex::encode(proof.new_public_key.signing_key),
+        old_signature: hex::encode(proof.old_signature),
+        new_signature: hex::encode(proof.new_signature),
+        timestamp: proof.timestamp,
+        commitment: hex::encode(proof.commitment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_bundles_hand_out_unique_prekeys() {
+        let dir = tempdir().unwrap();
+        let service = Arc::new(IdentityServiceImpl::new(
+            RocksDbStorage::open(dir.path()).unwrap(),
+        ));
+
+        let identity = service.generate_identity("phone").await.unwrap();
+        let prekeys: Vec<_> = (1000..1050)
+            .map(|id| OneTimePreKeyInput {
+                id,
+                public_key: hex::encode([id as u8; 32]),
+            })
+            .collect();
+        let total = service
+            .register_prekeys(&identity.user_id, &identity.device_id, &prekeys)
+            .await
+            .unwrap()
+            .total_count;
+        assert_eq!(total, 51);
+
+        // More requests than keys, so some bundles must come back without one
+        let handles: Vec<_> = (0..64)
+            .map(|_| {
+                let service = service.clone();
+                let user_id = identity.user_id.clone();
+                tokio::spawn(async move { service.get_prekey_bundle(&user_id, None).await })
+            })
+            .collect();
+
+        let mut handed_out = HashSet::new();
+        for handle in handles {
+            let bundle = handle.await.unwrap().unwrap();
+            if let Some(otpk) = bundle.one_time_prekey {
+                assert!(handed_out.insert(otpk.id), "prekey {} handed out twice", otpk.id);
+            }
+        }
+
+        assert_eq!(handed_out.len(), total);
+        let status = service.get_prekey_status(&identity.user_id).await.unwrap();
+        assert_eq!(status.count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_history_forms_chain() {
+        let dir = tempdir().unwrap();
+        let service = IdentityServiceImpl::new(RocksDbStorage::open(dir.path()).unwrap());
+
+        let identity = service.generate_identity("phone").await.unwrap();
+        let empty = service.get_rotation_history(&identity.user_id).await.unwrap();
+        assert!(empty.rotations.is_empty());
+        assert!(empty.chain_valid);
+
+        let first = service
+            .rotate_identity(&identity.user_id, &identity.device_id)
+            .await
+            .unwrap();
+        let second = service
+            .rotate_identity(&identity.user_id, &identity.device_id)
+            .await
+            .unwrap();
+
+        let history = service.get_rotation_history(&identity.user_id).await.unwrap();
+        assert_eq!(history.rotations.len(), 2);
+        assert!(history.chain_valid);
+        assert_eq!(history.rotations[0].old_public_key, identity.identity_key);
+        assert_eq!(history.rotations[0].new_public_key, first.new_identity_key);
+        assert_eq!(history.rotations[1].old_public_key, first.new_identity_key);
+        assert_eq!(history.rotations[1].new_public_key, second.new_identity_key);
+
+        assert!(service.get_rotation_history("unknown").await.is_err());
+    }
+
+    /// Build a signed prekey input signed by the user's stored identity key
+    fn sign_prekey(service: &IdentityServiceImpl, user_id: &str, id: u32) -> SignedPreKeyInput {
+        let data = service.storage.get_identity(user_id).unwrap().unwrap();
+        let stored: StoredIdentity = serde_json::from_slice(&data).unwrap();
+        let secret: [u8; 32] = hex::decode(stored.identity_key_secret)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let public_key = [id as u8; 32];
+        let timestamp = chrono::Utc::now().timestamp();
+        let signed = SignedPreKey::signed_data(&public_key, timestamp);
+
+        SignedPreKeyInput {
+            id,
+            public_key: hex::encode(public_key),
+            signature: hex::encode(IdentityKeyPair::from_secret_bytes(&secret).sign(&signed)),
+            timestamp,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_prekey_rotation() {
+        let dir = tempdir().unwrap();
+        let service = IdentityServiceImpl::new(RocksDbStorage::open(dir.path()).unwrap());
+        let identity = service.generate_identity("phone").await.unwrap();
+        let (user, device) = (&identity.user_id, &identity.device_id);
+        let old_id = identity.signed_prekey.id;
+        let new_id = old_id.wrapping_add(1);
+
+        let status = service.get_prekey_status(user).await.unwrap();
+        assert!(!status.needs_signed_prekey_rotation);
+
+        // Must be signed by the identity key
+        let mut forged = sign_prekey(&service, user, new_id);
+        forged.public_key = hex::encode([0xFF; 32]);
+        assert!(matches!(
+            service.rotate_signed_prekey(user, device, &forged).await,
+            Err(ServiceError::VerificationFailed(_))
+        ));
+        let mut backdated = sign_prekey(&service, user, new_id);
+        backdated.timestamp -= 3600;
+        assert!(matches!(
+            service.rotate_signed_prekey(user, device, &backdated).await,
+            Err(ServiceError::VerificationFailed(_))
+        ));
+
+        let rotated = service
+            .rotate_signed_prekey(user, device, &sign_prekey(&service, user, new_id))
+            .await
+            .unwrap();
+        assert_eq!(rotated.id, new_id);
+
+        let bundle = service.get_prekey_bundle(user, Some(device.as_str())).await.unwrap();
+        assert_eq!(bundle.signed_prekey.id, new_id);
+
+        // The replaced key stays usable during the grace period
+        let old = service.get_signed_prekey(user, device, old_id).await.unwrap();
+        assert_eq!(old.map(|p| p.id), Some(old_id));
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(service.purge_retired_signed_prekeys(now).unwrap(), 0);
+
+        let after_grace = now + DEFAULT_SPK_GRACE_PERIOD_SECS;
+        assert_eq!(service.purge_retired_signed_prekeys(after_grace).unwrap(), 1);
+        assert!(service.get_signed_prekey(user, device, old_id).await.unwrap().is_none());
+        assert!(service.get_signed_prekey(user, device, new_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_retired_signed_prekey_expires_on_lookup() {
+        let dir = tempdir().unwrap();
+        let service = IdentityServiceImpl::new(RocksDbStorage::open(dir.path()).unwrap())
+            .with_spk_grace_period(0);
+        let identity = service.generate_identity("phone").await.unwrap();
+        let (user, device) = (&identity.user_id, &identity.device_id);
+        let old_id = identity.signed_prekey.id;
+
+        let prekey = sign_prekey(&service, user, old_id.wrapping_add(1));
+        service.rotate_signed_prekey(user, device, &prekey).await.unwrap();
+
+        assert!(service.get_signed_prekey(user, device, old_id).await.unwrap().is_none());
+        assert!(service.storage.get_retired_signed_prekey(user, device).unwrap().is_none());
+    }
 }
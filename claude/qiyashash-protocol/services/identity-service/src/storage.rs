@@ -1,8 +1,13 @@
 //! RocksDB storage for Identity Service
 
-use rocksdb::{ColumnFamilyDescriptor, Options, DB};
-use std::path::Path;
-use std::sync::Arc;
+use qiyashash_core::storage::{check_migration, StorageStats, SCHEMA_VERSION};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    BottommostLevelCompaction, ColumnFamilyDescriptor, CompactOptions, IteratorMode, Options, DB,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crate::error::ServiceError;
 
@@ -12,33 +17,133 @@ const CF_PREKEYS: &str = "prekeys";
 const CF_ONE_TIME_PREKEYS: &str = "one_time_prekeys";
 const CF_DEVICES: &str = "devices";
 const CF_ROTATION_HISTORY: &str = "rotation_history";
+const CF_RETIRED_PREKEYS: &str = "retired_prekeys";
+
+/// Every column family, in the order they are opened
+const ALL_CFS: [&str; 6] = [
+    CF_IDENTITIES,
+    CF_PREKEYS,
+    CF_ONE_TIME_PREKEYS,
+    CF_DEVICES,
+    CF_ROTATION_HISTORY,
+    CF_RETIRED_PREKEYS,
+];
+
+/// Key of the layout version, in the default column family
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
 
 /// RocksDB-based storage
 pub struct RocksDbStorage {
     db: Arc<DB>,
+    /// Bytes freed by the most recent vacuum
+    reclaimed_bytes: AtomicU64,
+    /// Serializes one-time prekey consumption so each key is handed out once
+    consume_lock: Mutex<()>,
 }
 
 impl RocksDbStorage {
     /// Open storage at the given path
+    ///
+    /// Older layouts are migrated to [`SCHEMA_VERSION`], with a checkpoint
+    /// of the old data taken first. Stores written by a newer release are
+    /// refused.
     pub fn open(path: impl AsRef<Path>) -> Result<Self, ServiceError> {
+        let storage = Self::open_unmigrated(path.as_ref())?;
+        storage.migrate_to(path.as_ref(), SCHEMA_VERSION)?;
+        Ok(storage)
+    }
+
+    fn open_unmigrated(path: &Path) -> Result<Self, ServiceError> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
         opts.set_max_open_files(256);
         opts.set_keep_log_file_num(5);
 
-        let cf_opts = Options::default();
-        let cfs = vec![
-            ColumnFamilyDescriptor::new(CF_IDENTITIES, cf_opts.clone()),
-            ColumnFamilyDescriptor::new(CF_PREKEYS, cf_opts.clone()),
-            ColumnFamilyDescriptor::new(CF_ONE_TIME_PREKEYS, cf_opts.clone()),
-            ColumnFamilyDescriptor::new(CF_DEVICES, cf_opts.clone()),
-            ColumnFamilyDescriptor::new(CF_ROTATION_HISTORY, cf_opts),
-        ];
+        let cfs = ALL_CFS
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, Options::default()));
 
         let db = DB::open_cf_descriptors(&opts, path, cfs)?;
 
-        Ok(Self { db: Arc::new(db) })
+        Ok(Self {
+            db: Arc::new(db),
+            reclaimed_bytes: AtomicU64::new(0),
+            consume_lock: Mutex::new(()),
+        })
+    }
+
+    /// Layout version of the stored data
+    ///
+    /// Stores from before versioning have no marker and count as version 0;
+    /// an empty store counts as current.
+    pub fn schema_version(&self) -> Result<u32, ServiceError> {
+        match self.db.get(SCHEMA_VERSION_KEY)? {
+            Some(bytes) => {
+                let bytes: [u8; 4] = bytes.as_slice().try_into().map_err(|_| {
+                    ServiceError::Storage("Corrupt schema version".to_string())
+                })?;
+                Ok(u32::from_be_bytes(bytes))
+            }
+            None if self.is_empty()? => Ok(SCHEMA_VERSION),
+            None => Ok(0),
+        }
+    }
+
+    /// Upgrade the store at `path` to layout `version`
+    ///
+    /// Returns the checkpoint taken before changing anything, if a migration
+    /// was needed. Version 1 only adds the version marker.
+    pub fn migrate_to(&self, path: &Path, version: u32) -> Result<Option<PathBuf>, ServiceError> {
+        let current = self.schema_version()?;
+        if current > SCHEMA_VERSION {
+            return Err(ServiceError::Storage(format!(
+                "Store at {} has schema version {}, newer than the supported version {}; upgrade the identity service",
+                path.display(),
+                current,
+                SCHEMA_VERSION
+            )));
+        }
+        check_migration(current, version).map_err(|e| ServiceError::Storage(e.to_string()))?;
+
+        let backup = if current < version {
+            let backup = path.with_extension(format!(
+                "backup-v{}-{}",
+                current,
+                chrono::Utc::now().timestamp()
+            ));
+            Checkpoint::new(&self.db)?.create_checkpoint(&backup)?;
+            tracing::info!(
+                "Migrating {} from schema {} to {}; backup at {}",
+                path.display(),
+                current,
+                version,
+                backup.display()
+            );
+            Some(backup)
+        } else {
+            None
+        };
+
+        if self.db.get(SCHEMA_VERSION_KEY)?.as_deref() != Some(&version.to_be_bytes()[..]) {
+            self.db.put(SCHEMA_VERSION_KEY, version.to_be_bytes())?;
+            self.db.flush()?;
+        }
+        Ok(backup)
+    }
+
+    /// Whether no column family holds any data
+    fn is_empty(&self) -> Result<bool, ServiceError> {
+        for name in ALL_CFS {
+            let cf = self
+                .db
+                .cf_handle(name)
+                .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+            if self.db.iterator_cf(cf, IteratorMode::Start).next().is_some() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     /// Store identity
@@ -100,6 +205,72 @@ impl RocksDbStorage {
         Ok(self.db.get_cf(cf, key.as_bytes())?)
     }
 
+    /// Store the signed prekey a device just rotated away from
+    pub fn store_retired_signed_prekey(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        data: &[u8],
+    ) -> Result<(), ServiceError> {
+        let cf = self
+            .db
+            .cf_handle(CF_RETIRED_PREKEYS)
+            .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+        let key = format!("{}:{}", user_id, device_id);
+        self.db.put_cf(cf, key.as_bytes(), data)?;
+        Ok(())
+    }
+
+    /// Get retired signed prekey
+    pub fn get_retired_signed_prekey(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<Option<Vec<u8>>, ServiceError> {
+        let cf = self
+            .db
+            .cf_handle(CF_RETIRED_PREKEYS)
+            .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+        let key = format!("{}:{}", user_id, device_id);
+        Ok(self.db.get_cf(cf, key.as_bytes())?)
+    }
+
+    /// Delete retired signed prekey
+    pub fn delete_retired_signed_prekey(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<(), ServiceError> {
+        let cf = self
+            .db
+            .cf_handle(CF_RETIRED_PREKEYS)
+            .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+        let key = format!("{}:{}", user_id, device_id);
+        self.db.delete_cf(cf, key.as_bytes())?;
+        Ok(())
+    }
+
+    /// Get all retired signed prekeys as (user ID, device ID, data)
+    pub fn get_all_retired_signed_prekeys(
+        &self,
+    ) -> Result<Vec<(String, String, Vec<u8>)>, ServiceError> {
+        let cf = self
+            .db
+            .cf_handle(CF_RETIRED_PREKEYS)
+            .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+
+        let mut retired = Vec::new();
+        for item in self.db.iterator_cf(cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            let key_str = String::from_utf8_lossy(&key);
+            if let Some((user_id, device_id)) = key_str.split_once(':') {
+                retired.push((user_id.to_string(), device_id.to_string(), value.to_vec()));
+            }
+        }
+
+        Ok(retired)
+    }
+
     /// Store one-time prekey
     pub fn store_one_time_prekey(
         &self,
@@ -118,6 +289,9 @@ impl RocksDbStorage {
     }
 
     /// Get and consume one-time prekey
+    ///
+    /// The lookup and delete happen under a lock, so concurrent callers
+    /// never receive the same key.
     pub fn consume_one_time_prekey(
         &self,
         user_id: &str,
@@ -130,6 +304,12 @@ impl RocksDbStorage {
 
         let prefix = format!("{}:{}:", user_id, device_id);
 
+        // A poisoned lock only means another consumer panicked; the DB is intact
+        let _guard = self
+            .consume_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
         // Find first available prekey
         let iter = self.db.prefix_iterator_cf(cf, prefix.as_bytes());
         for item in iter {
@@ -221,6 +401,9 @@ impl RocksDbStorage {
     }
 
     /// Store rotation history
+    ///
+    /// Entries are keyed by timestamp plus sequence number, so rotations
+    /// within the same second keep their order instead of overwriting.
     pub fn store_rotation(
         &self,
         user_id: &str,
@@ -231,7 +414,8 @@ impl RocksDbStorage {
             .db
             .cf_handle(CF_ROTATION_HISTORY)
             .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
-        let key = format!("{}:{:016x}", user_id, timestamp);
+        let seq = self.get_rotation_history(user_id)?.len();
+        let key = format!("{}:{:016x}:{:08x}", user_id, timestamp, seq);
         self.db.put_cf(cf, key.as_bytes(), data)?;
         Ok(())
     }
@@ -260,11 +444,107 @@ impl RocksDbStorage {
         Ok(history)
     }
 
-    /// Flush to disk
+    /// Flush all column families to disk
     pub fn flush(&self) -> Result<(), ServiceError> {
-        self.db.flush()?;
+        for name in ALL_CFS {
+            let cf = self
+                .db
+                .cf_handle(name)
+                .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+            self.db.flush_cf(cf)?;
+        }
         Ok(())
     }
+
+    /// Bytes held by one column family: live SST files plus memtables
+    fn cf_size_bytes(&self, name: &str) -> Result<u64, ServiceError> {
+        let cf = self
+            .db
+            .cf_handle(name)
+            .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+        let sst = self
+            .db
+            .property_int_value_cf(cf, "rocksdb.live-sst-files-size")?
+            .unwrap_or(0);
+        let memtables = self
+            .db
+            .property_int_value_cf(cf, "rocksdb.cur-size-all-mem-tables")?
+            .unwrap_or(0);
+        Ok(sst + memtables)
+    }
+
+    /// Number of live keys in one column family
+    fn cf_key_count(&self, name: &str) -> Result<usize, ServiceError> {
+        let cf = self
+            .db
+            .cf_handle(name)
+            .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+        let mut count = 0;
+        for item in self.db.iterator_cf(cf, IteratorMode::Start) {
+            item?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Total size across all column families
+    pub fn storage_size_bytes(&self) -> Result<u64, ServiceError> {
+        let mut total = 0;
+        for name in ALL_CFS {
+            total += self.cf_size_bytes(name)?;
+        }
+        Ok(total)
+    }
+
+    /// Storage statistics with a per-category size breakdown
+    ///
+    /// This service keeps no sessions or messages, so those stay zero.
+    pub fn stats(&self) -> Result<StorageStats, ServiceError> {
+        let prekey_bytes = self.cf_size_bytes(CF_PREKEYS)?
+            + self.cf_size_bytes(CF_ONE_TIME_PREKEYS)?
+            + self.cf_size_bytes(CF_RETIRED_PREKEYS)?;
+
+        Ok(StorageStats {
+            user_count: self.cf_key_count(CF_IDENTITIES)?,
+            storage_size_bytes: self.storage_size_bytes()?,
+            prekey_bytes,
+            prekey_count: self.cf_key_count(CF_ONE_TIME_PREKEYS)?,
+            reclaimed_bytes: self.reclaimed_bytes(),
+            ..StorageStats::default()
+        })
+    }
+
+    /// Flush and fully compact every column family
+    ///
+    /// Deleted identities and consumed one-time prekeys only leave
+    /// tombstones behind; forcing a bottommost compaction drops them together
+    /// with the values they shadow. Returns the number of bytes reclaimed.
+    pub fn vacuum(&self) -> Result<u64, ServiceError> {
+        let mut compact_opts = CompactOptions::default();
+        compact_opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+
+        self.flush()?;
+        let before = self.storage_size_bytes()?;
+
+        for name in ALL_CFS {
+            let cf = self
+                .db
+                .cf_handle(name)
+                .ok_or_else(|| ServiceError::Storage("CF not found".to_string()))?;
+            self.db
+                .compact_range_cf_opt(cf, None::<&[u8]>, None::<&[u8]>, &compact_opts);
+        }
+        let after = self.storage_size_bytes()?;
+
+        let reclaimed = before.saturating_sub(after);
+        self.reclaimed_bytes.store(reclaimed, Ordering::Relaxed);
+        Ok(reclaimed)
+    }
+
+    /// Bytes freed by the most recent [`vacuum`](Self::vacuum)
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.reclaimed_bytes.load(Ordering::Relaxed)
+    }
 }
 
 #[cfg(test)]
@@ -286,6 +566,41 @@ mod tests {
         assert!(storage.get_identity("user1").unwrap().is_none());
     }
 
+    #[test]
+    fn test_unversioned_store_is_migrated() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+        {
+            let storage = RocksDbStorage::open(&path).unwrap();
+            assert_eq!(storage.schema_version().unwrap(), SCHEMA_VERSION);
+            storage.store_identity("user1", b"identity_data").unwrap();
+            // As written before versioning
+            storage.db.delete(SCHEMA_VERSION_KEY).unwrap();
+            assert_eq!(storage.schema_version().unwrap(), 0);
+        }
+
+        let storage = RocksDbStorage::open(&path).unwrap();
+        assert_eq!(storage.schema_version().unwrap(), 1);
+        assert_eq!(storage.get_identity("user1").unwrap(), Some(b"identity_data".to_vec()));
+        let backups = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("db.backup-v0-"))
+            .count();
+        assert_eq!(backups, 1);
+    }
+
+    #[test]
+    fn test_future_schema_is_rejected() {
+        let dir = tempdir().unwrap();
+        {
+            let storage = RocksDbStorage::open(dir.path()).unwrap();
+            storage.db.put(SCHEMA_VERSION_KEY, (SCHEMA_VERSION + 1).to_be_bytes()).unwrap();
+        }
+
+        let err = RocksDbStorage::open(dir.path()).err().expect("newer schema is refused");
+        assert!(err.to_string().contains("newer than the supported version"), "{}", err);
+    }
+
     #[test]
     fn test_prekey_storage() {
         let dir = tempdir().unwrap();
@@ -321,4 +636,80 @@ mod tests {
 
         assert_eq!(storage.get_one_time_prekey_count("user1", "device1").unwrap(), 1);
     }
+
+    #[test]
+    fn test_rotation_history_keeps_order() {
+        let dir = tempdir().unwrap();
+        let storage = RocksDbStorage::open(dir.path()).unwrap();
+
+        storage.store_rotation("user1", 100, b"first").unwrap();
+        storage.store_rotation("user1", 100, b"second").unwrap();
+        storage.store_rotation("user10", 50, b"other").unwrap();
+
+        let history = storage.get_rotation_history("user1").unwrap();
+        assert_eq!(history, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn test_vacuum_reclaims_deleted_prekeys() {
+        let dir = tempdir().unwrap();
+        let storage = RocksDbStorage::open(dir.path()).unwrap();
+
+        for key_id in 0..1000 {
+            storage
+                .store_one_time_prekey("user1", "device1", key_id, &[0xAB; 256])
+                .unwrap();
+        }
+        storage.vacuum().unwrap();
+        let full = storage.storage_size_bytes().unwrap();
+        assert!(full > 0);
+
+        while storage
+            .consume_one_time_prekey("user1", "device1")
+            .unwrap()
+            .is_some()
+        {}
+
+        let reclaimed = storage.vacuum().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(storage.reclaimed_bytes(), reclaimed);
+        assert!(storage.storage_size_bytes().unwrap() < full);
+        assert_eq!(storage.get_one_time_prekey_count("user1", "device1").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stats_report_prekey_size() {
+        let dir = tempdir().unwrap();
+        let storage = RocksDbStorage::open(dir.path()).unwrap();
+
+        // Incompressible payloads so the on-disk size tracks what was written
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut payload = || -> Vec<u8> {
+            (0..256)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+
+        storage.store_identity("user1", b"identity_data").unwrap();
+        for key_id in 0..500 {
+            storage
+                .store_one_time_prekey("user1", "device1", key_id, &payload())
+                .unwrap();
+        }
+        storage.flush().unwrap();
+
+        let stats = storage.stats().unwrap();
+        let written = 500 * 256;
+        assert_eq!(stats.user_count, 1);
+        assert_eq!(stats.prekey_count, 500);
+        assert!(stats.prekey_bytes >= written);
+        assert!(stats.prekey_bytes < 2 * written);
+        assert!(stats.storage_size_bytes >= stats.prekey_bytes);
+        assert_eq!(stats.message_bytes, 0);
+    }
 }
@@ -11,10 +11,12 @@ use std::sync::Arc;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use qiyashash_service_common::args::ServiceArgs;
+
 mod nullifier;
 mod error;
 
-use nullifier::MetadataNullifier;
+use nullifier::{DelayDistribution, MetadataNullifier};
 
 /// CLI arguments
 #[derive(Parser, Debug)]
@@ -33,9 +35,17 @@ struct Args {
     #[arg(long)]
     aggressive: bool,
 
+    /// Random delay distribution in milliseconds: uniform:MIN-MAX,
+    /// exponential:MEAN or constant:D
+    #[arg(long, default_value = "uniform:0-500")]
+    delay_distribution: DelayDistribution,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    #[command(flatten)]
+    service: ServiceArgs,
 }
 
 /// Application state
@@ -200,22 +210,31 @@ async fn main() -> std::io::Result<()> {
 
     // Initialize logging
     let log_level = if args.verbose { Level::DEBUG } else { Level::INFO };
-    let subscriber = FmtSubscriber::builder()
+    let builder = FmtSubscriber::builder()
         .with_max_level(log_level)
         .with_target(true)
         .json()
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
+        .with_filter_reloading();
+    let filter = builder.reload_handle();
+    tracing::subscriber::set_global_default(builder.finish())
         .expect("Failed to set tracing subscriber");
 
+    // Apply log level edits to --config while running; --verbose wins
+    let _config_watcher = args.service.watch_log_level(filter, args.verbose);
+
     info!("Starting Metadata Nullification Service");
 
-    let nullifier = Arc::new(MetadataNullifier::new(args.aggressive));
+    info!("Random delays drawn from {}", args.delay_distribution);
+    let nullifier = Arc::new(
+        MetadataNullifier::new(args.aggressive).with_delay_distribution(args.delay_distribution),
+    );
     let app_state = web::Data::new(AppState { nullifier });
 
     info!("Binding to {}:{}", args.host, args.port);
 
-    HttpServer::new(move || {
+    let tls_config = args.service.tls_config()?;
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -229,8 +248,11 @@ async fn main() -> std::io::Result<()> {
             .route("/api/v1/nullify", web::post().to(nullify_message))
             .route("/api/v1/nullify/batch", web::post().to(nullify_batch))
             .route("/api/v1/stats", web::get().to(get_stats))
-    })
-    .bind((args.host.as_str(), args.port))?
-    .run()
-    .await
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_22((args.host.as_str(), args.port), config)?,
+        None => server.bind((args.host.as_str(), args.port))?,
+    };
+
+    server.run().await
 }
@@ -1,7 +1,11 @@
 //! Metadata Nullifier implementation
 
-use rand::{seq::SliceRandom, Rng};
+use rand::rngs::StdRng;
+use rand::{seq::SliceRandom, Rng, RngCore, SeedableRng};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 use tracing::debug;
 
@@ -11,6 +15,104 @@ const BLOCK_SIZES: [usize; 6] = [256, 512, 1024, 2048, 4096, 8192];
 /// Maximum random delay in milliseconds
 const MAX_DELAY_MS: u64 = 500;
 
+/// Exponential delays are cut off at this many means
+const EXPONENTIAL_CUTOFF: f64 = 20.0;
+
+/// Distribution `random_delay` draws from
+///
+/// Parsed from `uniform:MIN-MAX`, `exponential:MEAN` or `constant:D`, all in
+/// milliseconds. Exponential delays make forwarding a Poisson process, which
+/// leaks the least about when a message actually arrived.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DelayDistribution {
+    /// Uniform between `min` (inclusive) and `max` (exclusive)
+    Uniform { min: Duration, max: Duration },
+    /// Exponential with the given mean
+    Exponential { mean: Duration },
+    /// Always the same delay
+    Constant { d: Duration },
+}
+
+impl Default for DelayDistribution {
+    fn default() -> Self {
+        Self::Uniform {
+            min: Duration::ZERO,
+            max: Duration::from_millis(MAX_DELAY_MS),
+        }
+    }
+}
+
+impl DelayDistribution {
+    /// Draw one delay
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Self::Uniform { min, max } if min < max => rng.gen_range(min..max),
+            Self::Uniform { min, .. } => min,
+            Self::Exponential { mean } => {
+                // Inverse transform sampling; 1 - u is in (0, 1]
+                let u: f64 = rng.gen();
+                let factor = (-(1.0 - u).ln()).min(EXPONENTIAL_CUTOFF);
+                mean.mul_f64(factor)
+            }
+            Self::Constant { d } => d,
+        }
+    }
+
+    /// Expected value of a sample
+    pub fn mean(&self) -> Duration {
+        match *self {
+            Self::Uniform { min, max } if min < max => (min + max) / 2,
+            Self::Uniform { min, .. } => min,
+            Self::Exponential { mean } => mean,
+            Self::Constant { d } => d,
+        }
+    }
+}
+
+impl FromStr for DelayDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ms = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|e| format!("invalid delay '{}': {}", v, e))
+        };
+
+        let (kind, params) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected KIND:PARAMS, got '{}'", s))?;
+        match kind.trim() {
+            "uniform" => {
+                let (min, max) = params
+                    .split_once('-')
+                    .ok_or_else(|| format!("expected uniform:MIN-MAX, got '{}'", s))?;
+                let (min, max) = (ms(min)?, ms(max)?);
+                if min > max {
+                    return Err(format!("uniform minimum exceeds maximum in '{}'", s));
+                }
+                Ok(Self::Uniform { min, max })
+            }
+            "exponential" => Ok(Self::Exponential { mean: ms(params)? }),
+            "constant" => Ok(Self::Constant { d: ms(params)? }),
+            other => Err(format!("unknown delay distribution '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for DelayDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uniform { min, max } => {
+                write!(f, "uniform:{}-{}", min.as_millis(), max.as_millis())
+            }
+            Self::Exponential { mean } => write!(f, "exponential:{}", mean.as_millis()),
+            Self::Constant { d } => write!(f, "constant:{}", d.as_millis()),
+        }
+    }
+}
+
 /// Nullification statistics
 pub struct NullificationStats {
     pub messages_processed: u64,
@@ -30,6 +132,10 @@ pub struct MetadataNullifier {
     total_padding: AtomicU64,
     /// Total original size
     total_original: AtomicU64,
+    /// Distribution for `random_delay`
+    delay: DelayDistribution,
+    /// Randomness for delays
+    delay_rng: Mutex<Box<dyn RngCore + Send>>,
 }
 
 impl MetadataNullifier {
@@ -41,9 +147,34 @@ impl MetadataNullifier {
             bytes_nullified: AtomicU64::new(0),
             total_padding: AtomicU64::new(0),
             total_original: AtomicU64::new(0),
+            delay: DelayDistribution::default(),
+            delay_rng: Mutex::new(Box::new(StdRng::from_entropy())),
         }
     }
 
+    /// Draw `random_delay` durations from `delay`
+    pub fn with_delay_distribution(mut self, delay: DelayDistribution) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Use `rng` for delays, e.g. a seeded one in tests
+    pub fn with_delay_rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.delay_rng = Mutex::new(Box::new(rng));
+        self
+    }
+
+    /// Configured delay distribution
+    pub fn delay_distribution(&self) -> DelayDistribution {
+        self.delay
+    }
+
+    /// Draw the next delay without waiting for it
+    pub fn sample_delay(&self) -> Duration {
+        let mut rng = self.delay_rng.lock().unwrap_or_else(|e| e.into_inner());
+        self.delay.sample(&mut **rng)
+    }
+
     /// Strip timing metadata from message
     /// In practice, this removes any embedded timestamps or sequence info
     pub fn strip_timing_metadata(&self, data: &[u8]) -> Vec<u8> {
@@ -128,9 +259,8 @@ impl MetadataNullifier {
 
     /// Add random delay to prevent timing analysis
     pub async fn random_delay(&self) {
-        let mut rng = rand::thread_rng();
-        let delay_ms = rng.gen_range(0..MAX_DELAY_MS);
-        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        let delay = self.sample_delay();
+        tokio::time::sleep(delay).await;
     }
 
     /// Add Poisson-distributed delay
@@ -227,4 +357,48 @@ mod tests {
         // Should be same elements, possibly different order
         assert_eq!(messages.len(), original.len());
     }
+
+    fn sample_mean(distribution: DelayDistribution, samples: u32) -> f64 {
+        let nullifier = MetadataNullifier::new(false)
+            .with_delay_distribution(distribution)
+            .with_delay_rng(StdRng::seed_from_u64(7));
+        let total: f64 = (0..samples)
+            .map(|_| nullifier.sample_delay().as_secs_f64())
+            .sum();
+        total / f64::from(samples)
+    }
+
+    #[test]
+    fn test_delay_distributions_match_mean() {
+        let distributions = [
+            DelayDistribution::Uniform {
+                min: Duration::from_millis(100),
+                max: Duration::from_millis(300),
+            },
+            DelayDistribution::Exponential { mean: Duration::from_millis(150) },
+            DelayDistribution::Constant { d: Duration::from_millis(42) },
+        ];
+
+        for distribution in distributions {
+            let expected = distribution.mean().as_secs_f64();
+            let observed = sample_mean(distribution, 20_000);
+            assert!(
+                (observed - expected).abs() <= expected * 0.05,
+                "{}: mean {} vs expected {}",
+                distribution,
+                observed,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_delay_distribution_parsing() {
+        for spec in ["uniform:0-500", "exponential:200", "constant:50"] {
+            let parsed: DelayDistribution = spec.parse().unwrap();
+            assert_eq!(parsed.to_string(), spec);
+        }
+        assert!("uniform:500-0".parse::<DelayDistribution>().is_err());
+        assert!("gaussian:10".parse::<DelayDistribution>().is_err());
+    }
 }
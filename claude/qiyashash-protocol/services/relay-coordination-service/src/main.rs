@@ -4,15 +4,20 @@
 //! Manages relay node registration, health monitoring, and load balancing.
 
 use actix_cors::Cors;
+use actix_web::middleware::from_fn;
 use actix_web::{middleware, web, App, HttpServer, HttpResponse};
 use chrono::{DateTime, Utc};
 use clap::Parser;
 use dashmap::DashMap;
+use qiyashash_crypto::identity::IdentityPublicKey;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
+
+use qiyashash_service_common::args::{ApiTokenArgs, ServiceArgs};
+use qiyashash_service_common::auth::{self, require_token};
 use uuid::Uuid;
 
 mod error;
@@ -41,6 +46,12 @@ struct Args {
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    #[command(flatten)]
+    service: ServiceArgs,
+
+    #[command(flatten)]
+    auth: ApiTokenArgs,
 }
 
 /// Relay node information
@@ -56,6 +67,42 @@ pub struct RelayNode {
     pub registered_at: DateTime<Utc>,
     pub last_heartbeat: DateTime<Utc>,
     pub status: NodeStatus,
+    /// Timestamp of the last accepted signed heartbeat (unix seconds)
+    #[serde(default)]
+    pub last_heartbeat_timestamp: i64,
+    /// Features the node supports, e.g. `"onion"` or `"quota"`
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Relay protocol version the node speaks
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u32,
+}
+
+impl RelayNode {
+    /// Whether the node advertises every capability in `required`
+    pub fn supports_all(&self, required: &[&str]) -> bool {
+        required
+            .iter()
+            .all(|capability| self.capabilities.iter().any(|c| c == capability))
+    }
+
+    /// Fraction of capacity in use; nodes without capacity count as full
+    pub fn load_ratio(&self) -> f64 {
+        if self.capacity == 0 {
+            return 1.0;
+        }
+        self.current_load as f64 / self.capacity as f64
+    }
+
+    /// Capacity left before the node is full
+    pub fn available_capacity(&self) -> u32 {
+        self.capacity.saturating_sub(self.current_load)
+    }
+}
+
+/// Version assumed for nodes that do not report one
+fn default_protocol_version() -> u32 {
+    1
 }
 
 /// Node status
@@ -104,6 +151,10 @@ struct RegisterNodeRequest {
     public_key: String,
     region: Option<String>,
     capacity: u32,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    #[serde(default = "default_protocol_version")]
+    protocol_version: u32,
 }
 
 /// Register response
@@ -117,6 +168,12 @@ async fn register_node(
     state: web::Data<AppState>,
     body: web::Json<RegisterNodeRequest>,
 ) -> HttpResponse {
+    if parse_public_key(&body.public_key).is_none() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "public_key must be a hex-encoded Ed25519 key"
+        }));
+    }
+
     let node_id = Uuid::new_v4().to_string();
     let now = Utc::now();
 
@@ -131,6 +188,9 @@ async fn register_node(
         registered_at: now,
         last_heartbeat: now,
         status: NodeStatus::Active,
+        last_heartbeat_timestamp: 0,
+        capabilities: body.capabilities.clone(),
+        protocol_version: body.protocol_version,
     };
 
     state.nodes.insert(node_id.clone(), node);
@@ -142,11 +202,83 @@ async fn register_node(
     })
 }
 
+/// Maximum distance between a heartbeat's timestamp and our clock
+const HEARTBEAT_MAX_SKEW_SECS: i64 = 60;
+
 /// Heartbeat request
 #[derive(Deserialize)]
 struct HeartbeatRequest {
     current_load: u32,
     status: Option<NodeStatus>,
+    /// Unix seconds; must be recent and newer than the last accepted heartbeat
+    timestamp: i64,
+    /// Hex Ed25519 signature over
+    /// `heartbeat_payload(node_id, timestamp, current_load, status)`
+    signature: String,
+}
+
+/// Bytes a node signs for a heartbeat:
+/// `node_id || timestamp || current_load || status`
+///
+/// The status is one byte, 0 when the heartbeat leaves it unchanged.
+pub fn heartbeat_payload(
+    node_id: &str,
+    timestamp: i64,
+    current_load: u32,
+    status: Option<NodeStatus>,
+) -> Vec<u8> {
+    let status_byte = match status {
+        None => 0u8,
+        Some(NodeStatus::Active) => 1,
+        Some(NodeStatus::Degraded) => 2,
+        Some(NodeStatus::Offline) => 3,
+        Some(NodeStatus::Maintenance) => 4,
+    };
+
+    let mut payload = Vec::with_capacity(node_id.len() + 13);
+    payload.extend_from_slice(node_id.as_bytes());
+    payload.extend_from_slice(&timestamp.to_be_bytes());
+    payload.extend_from_slice(&current_load.to_be_bytes());
+    payload.push(status_byte);
+    payload
+}
+
+/// Parse a hex-encoded Ed25519 public key
+fn parse_public_key(hex_key: &str) -> Option<IdentityPublicKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key).ok()?.try_into().ok()?;
+    IdentityPublicKey::from_bytes(&bytes).ok()
+}
+
+/// Check a heartbeat's freshness and signature against the registered key
+fn verify_heartbeat(
+    node: &RelayNode,
+    request: &HeartbeatRequest,
+    now: i64,
+) -> Result<(), &'static str> {
+    if (now - request.timestamp).abs() > HEARTBEAT_MAX_SKEW_SECS {
+        return Err("Heartbeat timestamp outside allowed window");
+    }
+    if request.timestamp <= node.last_heartbeat_timestamp {
+        return Err("Heartbeat timestamp already used");
+    }
+
+    let signature: [u8; 64] = hex::decode(&request.signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("Malformed heartbeat signature")?;
+    let public_key = parse_public_key(&node.public_key).ok_or("Node has no valid public key")?;
+
+    public_key
+        .verify(
+            &heartbeat_payload(
+                &node.id,
+                request.timestamp,
+                request.current_load,
+                request.status,
+            ),
+            &signature,
+        )
+        .map_err(|_| "Invalid heartbeat signature")
 }
 
 async fn heartbeat(
@@ -157,7 +289,15 @@ async fn heartbeat(
     let node_id = path.into_inner();
 
     if let Some(mut node) = state.nodes.get_mut(&node_id) {
+        if let Err(reason) = verify_heartbeat(&node, &body, Utc::now().timestamp()) {
+            warn!("Rejected heartbeat for node {}: {}", node_id, reason);
+            return HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": reason
+            }));
+        }
+
         node.last_heartbeat = Utc::now();
+        node.last_heartbeat_timestamp = body.timestamp;
         node.current_load = body.current_load;
         if let Some(status) = body.status {
             node.status = status;
@@ -196,6 +336,15 @@ async fn unregister_node(
 struct ListNodesQuery {
     region: Option<String>,
     status: Option<String>,
+    /// Leave out nodes whose load ratio is above this
+    max_load_ratio: Option<f64>,
+    /// Leave out nodes with less spare capacity than this
+    min_available_capacity: Option<u32>,
+    /// Order of the results; by node id when unset
+    sort_by: Option<NodeSort>,
+    /// Number of matching nodes to skip
+    #[serde(default)]
+    offset: usize,
     #[serde(default = "default_limit")]
     limit: usize,
 }
@@ -204,45 +353,101 @@ fn default_limit() -> usize {
     50
 }
 
+/// Sort orders for `list_nodes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NodeSort {
+    /// Lowest load ratio first
+    Load,
+    /// Alphabetically by region, nodes without one last
+    Region,
+    /// Oldest registration first
+    RegisteredAt,
+}
+
+impl ListNodesQuery {
+    fn matches(&self, node: &RelayNode) -> bool {
+        if let Some(ref region) = self.region {
+            if node.region.as_ref() != Some(region) {
+                return false;
+            }
+        }
+        if let Some(ref status) = self.status {
+            let target_status = match status.as_str() {
+                "active" => NodeStatus::Active,
+                "degraded" => NodeStatus::Degraded,
+                "offline" => NodeStatus::Offline,
+                "maintenance" => NodeStatus::Maintenance,
+                _ => return true,
+            };
+            if node.status != target_status {
+                return false;
+            }
+        }
+        if let Some(max_ratio) = self.max_load_ratio {
+            if node.load_ratio() > max_ratio {
+                return false;
+            }
+        }
+        if let Some(min_capacity) = self.min_available_capacity {
+            if node.available_capacity() < min_capacity {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Sort `nodes`, breaking ties by id so pages stay stable
+    fn sort(&self, nodes: &mut [RelayNode]) {
+        nodes.sort_by(|a, b| {
+            let order = match self.sort_by {
+                Some(NodeSort::Load) => a.load_ratio().total_cmp(&b.load_ratio()),
+                Some(NodeSort::Region) => match (&a.region, &b.region) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (a, b) => b.is_some().cmp(&a.is_some()),
+                },
+                Some(NodeSort::RegisteredAt) => a.registered_at.cmp(&b.registered_at),
+                None => std::cmp::Ordering::Equal,
+            };
+            order.then_with(|| a.id.cmp(&b.id))
+        });
+    }
+}
+
 /// List nodes response
 #[derive(Serialize)]
 struct ListNodesResponse {
     nodes: Vec<RelayNode>,
     count: usize,
+    /// Number of nodes matching the filters
+    total: usize,
+    /// Offset of the next page, absent on the last page
+    next_offset: Option<usize>,
 }
 
 async fn list_nodes(
     state: web::Data<AppState>,
     query: web::Query<ListNodesQuery>,
 ) -> HttpResponse {
-    let nodes: Vec<RelayNode> = state.nodes.iter()
+    let mut matching: Vec<RelayNode> = state.nodes.iter()
+        .filter(|entry| query.matches(entry.value()))
         .map(|entry| entry.value().clone())
-        .filter(|node| {
-            if let Some(ref region) = query.region {
-                if node.region.as_ref() != Some(region) {
-                    return false;
-                }
-            }
-            if let Some(ref status) = query.status {
-                let target_status = match status.as_str() {
-                    "active" => NodeStatus::Active,
-                    "degraded" => NodeStatus::Degraded,
-                    "offline" => NodeStatus::Offline,
-                    "maintenance" => NodeStatus::Maintenance,
-                    _ => return true,
-                };
-                if node.status != target_status {
-                    return false;
-                }
-            }
-            true
-        })
+        .collect();
+    query.sort(&mut matching);
+
+    let total = matching.len();
+    let nodes: Vec<RelayNode> = matching.into_iter()
+        .skip(query.offset)
         .take(query.limit)
         .collect();
+    let end = query.offset.saturating_add(nodes.len());
+    let next_offset = (end < total).then_some(end);
 
     HttpResponse::Ok().json(ListNodesResponse {
         count: nodes.len(),
         nodes,
+        total,
+        next_offset,
     })
 }
 
@@ -252,6 +457,24 @@ struct GetRelaysQuery {
     recipient_id: String,
     count: Option<usize>,
     region: Option<String>,
+    /// Comma-separated capabilities every returned relay must support
+    require_capability: Option<String>,
+    /// Oldest acceptable relay protocol version
+    min_protocol_version: Option<u32>,
+}
+
+impl GetRelaysQuery {
+    fn required_capabilities(&self) -> Vec<&str> {
+        self.require_capability
+            .as_deref()
+            .map(|list| {
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|c| !c.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Serialize)]
@@ -264,6 +487,8 @@ async fn get_relays(
     query: web::Query<GetRelaysQuery>,
 ) -> HttpResponse {
     let count = query.count.unwrap_or(3);
+    let required = query.required_capabilities();
+    let min_version = query.min_protocol_version.unwrap_or(0);
 
     // Get active nodes sorted by load
     let mut candidates: Vec<RelayNode> = state.nodes.iter()
@@ -271,23 +496,40 @@ async fn get_relays(
             let node = entry.value();
             node.status == NodeStatus::Active &&
             node.current_load < node.capacity &&
-            query.region.as_ref().map_or(true, |r| node.region.as_ref() == Some(r))
+            query.region.as_ref().map_or(true, |r| node.region.as_ref() == Some(r)) &&
+            node.protocol_version >= min_version &&
+            node.supports_all(&required)
         })
         .map(|entry| entry.value().clone())
         .collect();
 
     // Sort by load ratio (lowest first)
-    candidates.sort_by(|a, b| {
-        let ratio_a = a.current_load as f64 / a.capacity as f64;
-        let ratio_b = b.current_load as f64 / b.capacity as f64;
-        ratio_a.partial_cmp(&ratio_b).unwrap()
-    });
+    candidates.sort_by(|a, b| a.load_ratio().total_cmp(&b.load_ratio()));
 
     let relays: Vec<RelayNode> = candidates.into_iter().take(count).collect();
 
     HttpResponse::Ok().json(GetRelaysResponse { relays })
 }
 
+/// Configure API routes
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/api/v1/health", web::get().to(health_check))
+        .route(
+            "/api/v1/nodes",
+            web::post().to(register_node).wrap(from_fn(require_token)),
+        )
+        .route("/api/v1/nodes", web::get().to(list_nodes))
+        .route(
+            "/api/v1/nodes/{node_id}/heartbeat",
+            web::post().to(heartbeat).wrap(from_fn(require_token)),
+        )
+        .route(
+            "/api/v1/nodes/{node_id}",
+            web::delete().to(unregister_node).wrap(from_fn(require_token)),
+        )
+        .route("/api/v1/relays", web::get().to(get_relays));
+}
+
 /// Background task to check node health
 async fn health_check_task(state: web::Data<AppState>, timeout: Duration) {
     loop {
@@ -322,14 +564,18 @@ async fn main() -> std::io::Result<()> {
 
     // Initialize logging
     let log_level = if args.verbose { Level::DEBUG } else { Level::INFO };
-    let subscriber = FmtSubscriber::builder()
+    let builder = FmtSubscriber::builder()
         .with_max_level(log_level)
         .with_target(true)
         .json()
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
+        .with_filter_reloading();
+    let filter = builder.reload_handle();
+    tracing::subscriber::set_global_default(builder.finish())
         .expect("Failed to set tracing subscriber");
 
+    // Apply log level edits to --config while running; --verbose wins
+    let _config_watcher = args.service.watch_log_level(filter, args.verbose);
+
     info!("Starting Relay Coordination Service");
 
     let node_timeout = Duration::from_secs(args.node_timeout);
@@ -346,7 +592,14 @@ async fn main() -> std::io::Result<()> {
 
     info!("Binding to {}:{}", args.host, args.port);
 
-    HttpServer::new(move || {
+    let api_tokens = web::Data::new(auth::ApiTokens::new(args.auth.api_tokens.clone()));
+    if !api_tokens.is_enabled() {
+        warn!("No API tokens configured; mutating routes will reject every request");
+    }
+
+    let tls_config = args.service.tls_config()?;
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -354,16 +607,352 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .app_data(app_state.clone())
+            .app_data(api_tokens.clone())
             .wrap(cors)
             .wrap(middleware::Logger::default())
-            .route("/api/v1/health", web::get().to(health_check))
-            .route("/api/v1/nodes", web::post().to(register_node))
-            .route("/api/v1/nodes", web::get().to(list_nodes))
-            .route("/api/v1/nodes/{node_id}/heartbeat", web::post().to(heartbeat))
-            .route("/api/v1/nodes/{node_id}", web::delete().to(unregister_node))
-            .route("/api/v1/relays", web::get().to(get_relays))
-    })
-    .bind((args.host.as_str(), args.port))?
-    .run()
-    .await
+            .configure(configure_routes)
+    });
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_22((args.host.as_str(), args.port), config)?,
+        None => server.bind((args.host.as_str(), args.port))?,
+    };
+
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+    use qiyashash_crypto::identity::IdentityKeyPair;
+
+    /// Token for the test apps' protected routes
+    fn test_tokens() -> web::Data<auth::ApiTokens> {
+        web::Data::new(auth::ApiTokens::new(["secret".to_string()]))
+    }
+
+    /// POST carrying the test token
+    fn authorized_post(uri: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", "Bearer secret"))
+    }
+
+    fn test_app_state() -> web::Data<AppState> {
+        web::Data::new(AppState {
+            nodes: Arc::new(DashMap::new()),
+            node_timeout: Duration::from_secs(120),
+        })
+    }
+
+    fn register_body(keypair: &IdentityKeyPair) -> serde_json::Value {
+        serde_json::json!({
+            "address": "10.0.0.1",
+            "port": 9000,
+            "public_key": hex::encode(keypair.public_key().signing_key_bytes()),
+            "capacity": 10
+        })
+    }
+
+    fn signed_heartbeat(
+        keypair: &IdentityKeyPair,
+        node_id: &str,
+        timestamp: i64,
+        current_load: u32,
+        status: Option<NodeStatus>,
+    ) -> serde_json::Value {
+        let signature =
+            keypair.sign(&heartbeat_payload(node_id, timestamp, current_load, status));
+        serde_json::json!({
+            "current_load": current_load,
+            "status": status,
+            "timestamp": timestamp,
+            "signature": hex::encode(signature)
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_register_node_requires_token() {
+        let keypair = IdentityKeyPair::generate();
+        let state = test_app_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(test_tokens())
+                .configure(configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/nodes")
+            .set_json(register_body(&keypair))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/nodes")
+            .insert_header(("Authorization", "Bearer wrong"))
+            .set_json(register_body(&keypair))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.nodes.is_empty());
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/nodes")
+            .insert_header(("Authorization", "Bearer secret"))
+            .set_json(register_body(&keypair))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        assert_eq!(state.nodes.len(), 1);
+
+        // Health stays open
+        let req = test::TestRequest::get().uri("/api/v1/health").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_heartbeat_signatures() {
+        let keypair = IdentityKeyPair::generate();
+        let state = test_app_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(test_tokens())
+                .configure(configure_routes),
+        )
+        .await;
+
+        let req = authorized_post("/api/v1/nodes")
+            .set_json(register_body(&keypair))
+            .to_request();
+        let registered: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let node_id = registered["node_id"].as_str().unwrap().to_string();
+        let uri = format!("/api/v1/nodes/{}/heartbeat", node_id);
+        let now = Utc::now().timestamp();
+
+        // Valid signed heartbeat
+        let valid = signed_heartbeat(&keypair, &node_id, now, 4, None);
+        let req = authorized_post(&uri).set_json(&valid).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(state.nodes.get(&node_id).unwrap().current_load, 4);
+
+        // Replaying the same heartbeat
+        let req = authorized_post(&uri).set_json(&valid).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Stale heartbeat outside the window
+        let stale = signed_heartbeat(&keypair, &node_id, now - 3600, 0, None);
+        let req = authorized_post(&uri).set_json(&stale).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Signed by a different key
+        let forged = signed_heartbeat(&IdentityKeyPair::generate(), &node_id, now + 1, 0, None);
+        let req = authorized_post(&uri).set_json(&forged).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Status changed after signing
+        let mut tampered =
+            signed_heartbeat(&keypair, &node_id, now + 2, 4, Some(NodeStatus::Active));
+        tampered["status"] = serde_json::json!("offline");
+        let req = authorized_post(&uri).set_json(&tampered).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Status added to a heartbeat signed without one
+        let mut tampered = signed_heartbeat(&keypair, &node_id, now + 3, 4, None);
+        tampered["status"] = serde_json::json!("maintenance");
+        let req = authorized_post(&uri).set_json(&tampered).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(state.nodes.get(&node_id).unwrap().status, NodeStatus::Active);
+
+        // Signed status change
+        let degraded =
+            signed_heartbeat(&keypair, &node_id, now + 4, 4, Some(NodeStatus::Degraded));
+        let req = authorized_post(&uri).set_json(&degraded).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(state.nodes.get(&node_id).unwrap().status, NodeStatus::Degraded);
+
+        assert_eq!(state.nodes.get(&node_id).unwrap().current_load, 4);
+    }
+
+    fn relay_ids(response: &serde_json::Value) -> Vec<String> {
+        let mut ids: Vec<String> = response["relays"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|relay| relay["id"].as_str().unwrap().to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[actix_web::test]
+    async fn test_get_relays_filters_capabilities_and_version() {
+        let app = test::init_service(
+            App::new()
+                .app_data(test_app_state())
+                .app_data(test_tokens())
+                .configure(configure_routes),
+        )
+        .await;
+
+        let mut ids = Vec::new();
+        for (capabilities, version) in [
+            (vec!["onion"], 2),
+            (vec!["onion", "quota"], 2),
+            (vec!["quota"], 2),
+            (vec!["onion"], 1),
+        ] {
+            let mut body = register_body(&IdentityKeyPair::generate());
+            body["capabilities"] = serde_json::json!(capabilities);
+            body["protocol_version"] = serde_json::json!(version);
+            let req = authorized_post("/api/v1/nodes").set_json(&body).to_request();
+            let registered: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            ids.push(registered["node_id"].as_str().unwrap().to_string());
+        }
+        let (onion, onion_quota, quota, old_onion) = (&ids[0], &ids[1], &ids[2], &ids[3]);
+        let sorted = |mut ids: Vec<&String>| {
+            ids.sort();
+            ids.into_iter().cloned().collect::<Vec<_>>()
+        };
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/relays?recipient_id=bob&count=10&require_capability=onion")
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(relay_ids(&response), sorted(vec![onion, onion_quota, old_onion]));
+
+        // Every listed capability is required
+        let req = test::TestRequest::get()
+            .uri("/api/v1/relays?recipient_id=bob&count=10&require_capability=onion,quota")
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(relay_ids(&response), vec![onion_quota.clone()]);
+
+        // Too-old nodes are left out
+        let req = test::TestRequest::get()
+            .uri("/api/v1/relays?recipient_id=bob&count=10&min_protocol_version=2")
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(relay_ids(&response), sorted(vec![onion, onion_quota, quota]));
+    }
+
+    fn insert_node(
+        state: &web::Data<AppState>,
+        id: &str,
+        region: Option<&str>,
+        current_load: u32,
+        registered_secs_ago: i64,
+    ) {
+        let registered_at = Utc::now() - chrono::Duration::seconds(registered_secs_ago);
+        state.nodes.insert(id.to_string(), RelayNode {
+            id: id.to_string(),
+            address: "10.0.0.1".to_string(),
+            port: 9000,
+            public_key: String::new(),
+            region: region.map(str::to_string),
+            capacity: 10,
+            current_load,
+            registered_at,
+            last_heartbeat: registered_at,
+            status: NodeStatus::Active,
+            last_heartbeat_timestamp: 0,
+            capabilities: Vec::new(),
+            protocol_version: 1,
+        });
+    }
+
+    fn node_ids(response: &serde_json::Value) -> Vec<&str> {
+        response["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["id"].as_str().unwrap())
+            .collect()
+    }
+
+    async fn list(state: &web::Data<AppState>, query: &str) -> serde_json::Value {
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(test_tokens())
+                .configure(configure_routes),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/v1/nodes?{}", query))
+            .to_request();
+        test::call_and_read_body_json(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn test_list_nodes_filters_by_load() {
+        let state = test_app_state();
+        insert_node(&state, "idle", Some("eu"), 0, 0);
+        insert_node(&state, "half", Some("eu"), 5, 0);
+        insert_node(&state, "busy", Some("us"), 8, 0);
+        insert_node(&state, "full", Some("us"), 10, 0);
+
+        let response = list(&state, "max_load_ratio=0.5").await;
+        assert_eq!(node_ids(&response), vec!["half", "idle"]);
+        assert_eq!(response["total"], 2);
+
+        let response = list(&state, "min_available_capacity=2").await;
+        assert_eq!(node_ids(&response), vec!["busy", "half", "idle"]);
+
+        let response = list(&state, "max_load_ratio=0.9&region=us").await;
+        assert_eq!(node_ids(&response), vec!["busy"]);
+    }
+
+    #[actix_web::test]
+    async fn test_list_nodes_sorts_and_pages() {
+        let state = test_app_state();
+        insert_node(&state, "a", Some("us"), 7, 10);
+        insert_node(&state, "b", None, 2, 30);
+        insert_node(&state, "c", Some("ap"), 9, 20);
+        insert_node(&state, "d", Some("eu"), 0, 40);
+
+        let response = list(&state, "sort_by=load").await;
+        assert_eq!(node_ids(&response), vec!["d", "b", "a", "c"]);
+
+        let response = list(&state, "sort_by=region").await;
+        assert_eq!(node_ids(&response), vec!["c", "d", "a", "b"]);
+
+        let response = list(&state, "sort_by=registered_at").await;
+        assert_eq!(node_ids(&response), vec!["d", "b", "c", "a"]);
+
+        // Pages follow the sort order and end without a next offset
+        let first = list(&state, "sort_by=load&limit=3").await;
+        assert_eq!(node_ids(&first), vec!["d", "b", "a"]);
+        assert_eq!(first["next_offset"], 3);
+        assert_eq!(first["total"], 4);
+
+        let second = list(&state, "sort_by=load&limit=3&offset=3").await;
+        assert_eq!(node_ids(&second), vec!["c"]);
+        assert!(second["next_offset"].is_null());
+
+        let past_end = list(&state, "offset=10").await;
+        assert_eq!(past_end["count"], 0);
+        assert!(past_end["next_offset"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn test_register_rejects_invalid_public_key() {
+        let app = test::init_service(
+            App::new()
+                .app_data(test_app_state())
+                .app_data(test_tokens())
+                .configure(configure_routes),
+        )
+        .await;
+
+        let req = authorized_post("/api/v1/nodes")
+            .set_json(serde_json::json!({
+                "address": "10.0.0.1",
+                "port": 9000,
+                "public_key": "aa",
+                "capacity": 10
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
 }
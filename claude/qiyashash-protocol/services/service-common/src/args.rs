@@ -0,0 +1,104 @@
+//! Command-line arguments every service takes
+
+use clap::Args;
+use rustls::ServerConfig;
+use std::io;
+use std::path::PathBuf;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::reload;
+
+use crate::config::{self, ConfigWatcher};
+use crate::tls;
+
+/// TLS and runtime config arguments, flattened into each service's `Args`
+#[derive(Args, Debug, Clone)]
+pub struct ServiceArgs {
+    /// PEM certificate chain for TLS (plaintext HTTP when unset)
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for TLS
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// TOML file with settings applied while running, such as log_level
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+impl ServiceArgs {
+    /// TLS config from `--tls-cert` and `--tls-key`, `None` for plaintext
+    pub fn tls_config(&self) -> io::Result<Option<ServerConfig>> {
+        tls::server_config(self.tls_cert.as_deref(), self.tls_key.as_deref())
+    }
+
+    /// Apply log level edits to `--config` through `filter` while running
+    ///
+    /// With `pinned` set (e.g. by `--verbose`) the level given on the
+    /// command line wins and edits are ignored. Keep the returned watcher
+    /// alive for as long as edits should apply.
+    pub fn watch_log_level<S: 'static>(
+        &self,
+        filter: reload::Handle<LevelFilter, S>,
+        pinned: bool,
+    ) -> Option<ConfigWatcher> {
+        let path = self.config.as_deref()?;
+        config::watch_log_level(path, move |level| {
+            if !pinned {
+                let _ = filter.modify(|filter| *filter = LevelFilter::from_level(level));
+            }
+        })
+    }
+}
+
+/// Bearer token arguments for services with mutating routes
+#[derive(Args, Debug, Clone)]
+pub struct ApiTokenArgs {
+    /// Bearer token accepted on mutating routes (repeatable; without one
+    /// they reject every request)
+    #[arg(long = "api-token")]
+    pub api_tokens: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser, Debug)]
+    struct TestArgs {
+        #[command(flatten)]
+        service: ServiceArgs,
+
+        #[command(flatten)]
+        auth: ApiTokenArgs,
+    }
+
+    #[test]
+    fn test_flattened_args() {
+        let args = TestArgs::try_parse_from([
+            "svc",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--api-token",
+            "one",
+            "--api-token",
+            "two",
+        ])
+        .unwrap();
+        assert_eq!(args.service.tls_cert, Some(PathBuf::from("cert.pem")));
+        assert_eq!(args.service.tls_key, Some(PathBuf::from("key.pem")));
+        assert_eq!(args.auth.api_tokens, ["one", "two"]);
+
+        // The cert and key only come together
+        assert!(TestArgs::try_parse_from(["svc", "--tls-cert", "cert.pem"]).is_err());
+
+        // Without --config there is nothing to watch
+        let args = TestArgs::try_parse_from(["svc"]).unwrap();
+        let (_, filter) = reload::Layer::<_, tracing_subscriber::Registry>::new(LevelFilter::INFO);
+        assert!(args.service.watch_log_level(filter, false).is_none());
+        assert!(args.service.tls_config().unwrap().is_none());
+    }
+}
@@ -0,0 +1,101 @@
+//! Bearer-token authentication for mutating routes
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use std::collections::HashSet;
+
+/// Bearer tokens accepted on protected routes
+///
+/// An empty set accepts no request at all: protected routes fail closed
+/// rather than open up when no token is configured.
+#[derive(Debug, Clone, Default)]
+pub struct ApiTokens {
+    tokens: HashSet<String>,
+}
+
+impl ApiTokens {
+    /// Create from the accepted tokens (empty strings are ignored)
+    pub fn new(tokens: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            tokens: tokens.into_iter().filter(|t| !t.is_empty()).collect(),
+        }
+    }
+
+    /// Whether any token is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Check an `Authorization` header value
+    pub fn authorizes(&self, header: Option<&str>) -> bool {
+        let Some(presented) = header.and_then(|h| h.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        // Compare against every token so timing doesn't reveal which one matched
+        self.tokens.iter().fold(false, |found, token| {
+            constant_time_eq(token.as_bytes(), presented.as_bytes()) | found
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reject requests without a valid bearer token with 401
+///
+/// Attach per route with `.wrap(middleware::from_fn(auth::require_token))`.
+/// Tokens are read from `web::Data<ApiTokens>`; if none is registered the
+/// route fails closed.
+pub async fn require_token<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let authorized = req
+        .app_data::<web::Data<ApiTokens>>()
+        .is_some_and(|tokens| tokens.authorizes(header));
+
+    if !authorized {
+        let response = HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .json(serde_json::json!({
+                "error": "unauthorized",
+                "message": "Missing or invalid bearer token"
+            }));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_any_configured_token() {
+        let tokens = ApiTokens::new(["one".to_string(), "two".to_string()]);
+        assert!(tokens.authorizes(Some("Bearer one")));
+        assert!(tokens.authorizes(Some("Bearer two")));
+        assert!(!tokens.authorizes(Some("Bearer three")));
+        assert!(!tokens.authorizes(Some("one")));
+        assert!(!tokens.authorizes(None));
+    }
+
+    #[test]
+    fn test_no_tokens_fails_closed() {
+        let tokens = ApiTokens::new([String::new()]);
+        assert!(!tokens.is_enabled());
+        assert!(!tokens.authorizes(None));
+        assert!(!tokens.authorizes(Some("Bearer ")));
+        assert!(!tokens.authorizes(Some("Bearer anything")));
+        assert!(!ApiTokens::default().authorizes(Some("Bearer ")));
+    }
+}
@@ -0,0 +1,204 @@
+//! Service settings that apply while running
+//!
+//! Listen address, storage and TLS come from the command line and are fixed
+//! for the life of the process. The file passed with `--config` holds the
+//! settings that may change, and is reloaded whenever it is edited.
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn, Level};
+
+/// Hot-reloadable service settings
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    /// Log level (trace, debug, info, warn, error)
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            log_level: default_log_level(),
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Read and validate the config at `path`
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let config: ServiceConfig = toml::from_str(&content)?;
+        config.level()?;
+        Ok(config)
+    }
+
+    /// Save config to file
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Configured log level
+    pub fn level(&self) -> anyhow::Result<Level> {
+        Level::from_str(&self.log_level)
+            .map_err(|_| anyhow::anyhow!("Invalid log level: {:?}", self.log_level))
+    }
+}
+
+/// Called with the config after a reload changed it
+pub type ChangeHandler = Box<dyn Fn(&ServiceConfig) + Send + Sync>;
+
+/// Reloads a service config file when it changes
+///
+/// Edits that fail to parse or validate are ignored and the previous
+/// config is kept.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    config: Arc<RwLock<ServiceConfig>>,
+    on_change: Arc<ChangeHandler>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    /// Load `path` and watch it, calling `on_change` after each change
+    ///
+    /// `on_change` is also called once with the config as loaded now.
+    pub fn watch(
+        path: &Path,
+        on_change: impl Fn(&ServiceConfig) + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        let path = path.to_path_buf();
+        let initial = ServiceConfig::load(&path)?;
+        on_change(&initial);
+        let config = Arc::new(RwLock::new(initial));
+        let on_change: Arc<ChangeHandler> = Arc::new(Box::new(on_change));
+
+        let (watched_path, watched_config, watched_handler) =
+            (path.clone(), config.clone(), on_change.clone());
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    if event.paths.iter().any(|p| p.file_name() == watched_path.file_name()) {
+                        reload(&watched_path, &watched_config, &watched_handler);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Config watch error: {}", e),
+            }
+        })?;
+
+        // Editors often replace the file rather than write to it, so watch
+        // the directory
+        let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            path,
+            config,
+            on_change,
+            _watcher: watcher,
+        })
+    }
+
+    /// Current config
+    pub fn current(&self) -> ServiceConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Reload now instead of waiting for a change event
+    pub fn reload(&self) -> bool {
+        reload(&self.path, &self.config, &self.on_change)
+    }
+}
+
+/// Watch `path` and pass its log level to `set_level` now and on every change
+///
+/// A service that cannot watch its config logs why and keeps running with
+/// the level it started with.
+pub fn watch_log_level(
+    path: &Path,
+    set_level: impl Fn(Level) + Send + Sync + 'static,
+) -> Option<ConfigWatcher> {
+    let watched = ConfigWatcher::watch(path, move |config| {
+        if let Ok(level) = config.level() {
+            set_level(level);
+        }
+    });
+    match watched {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!("Not watching {} for changes: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Read `path` and apply it to `config`; false if the file was rejected
+fn reload(path: &Path, config: &RwLock<ServiceConfig>, on_change: &ChangeHandler) -> bool {
+    let new = match ServiceConfig::load(path) {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("Ignoring config change in {}: {}", path.display(), e);
+            return false;
+        }
+    };
+
+    let mut config = config.write().unwrap();
+    if *config != new {
+        *config = new;
+        on_change(&config);
+        info!("Reloaded config from {}", path.display());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_log_level_hot_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("service.toml");
+        ServiceConfig::default().save(&path).unwrap();
+
+        let levels = Arc::new(Mutex::new(Vec::new()));
+        let seen = levels.clone();
+        let watcher = watch_log_level(&path, move |level| seen.lock().unwrap().push(level)).unwrap();
+        assert_eq!(*levels.lock().unwrap(), vec![Level::INFO]);
+
+        let debug = ServiceConfig {
+            log_level: "debug".to_string(),
+        };
+        debug.save(&path).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while watcher.current() != debug && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(watcher.current(), debug);
+        assert_eq!(levels.lock().unwrap().last(), Some(&Level::DEBUG));
+
+        // Invalid edits leave the previous config in place
+        std::fs::write(&path, "log_level = \"loud\"").unwrap();
+        assert!(!watcher.reload());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(watcher.current(), debug);
+        assert_eq!(levels.lock().unwrap().last(), Some(&Level::DEBUG));
+    }
+
+    #[test]
+    fn test_missing_config_is_not_watched() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(watch_log_level(&dir.path().join("missing.toml"), |_| {}).is_none());
+    }
+}
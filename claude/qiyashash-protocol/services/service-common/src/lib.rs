@@ -0,0 +1,9 @@
+//! Code shared by the QiyasHash services
+//!
+//! Each service is its own binary; what they all need in the same form
+//! lives here.
+
+pub mod args;
+pub mod auth;
+pub mod config;
+pub mod tls;
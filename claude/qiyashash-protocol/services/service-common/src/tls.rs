@@ -0,0 +1,74 @@
+//! TLS configuration for the services' HTTP APIs
+
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Build the server TLS config from `--tls-cert`/`--tls-key`
+///
+/// Returns `None` (plaintext HTTP) only when both are unset.
+pub fn server_config(cert: Option<&Path>, key: Option<&Path>) -> io::Result<Option<ServerConfig>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let config = load_server_config(cert, key)?;
+            info!("TLS enabled with certificate {}", cert.display());
+            Ok(Some(config))
+        }
+        (None, None) => {
+            warn!("TLS not configured; serving plaintext HTTP");
+            Ok(None)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--tls-cert and --tls-key must be given together",
+        )),
+    }
+}
+
+/// Load a PEM certificate chain and private key into a rustls server config
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", cert_path.display()),
+        ));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No private key found in {}", key_path.display()),
+            )
+        })?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_requires_cert_and_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        assert!(server_config(None, None).unwrap().is_none());
+        assert!(server_config(Some(&cert_path), None).is_err());
+        assert!(server_config(Some(&cert_path), Some(&key_path)).unwrap().is_some());
+        // A certificate file is not a private key
+        assert!(server_config(Some(&cert_path), Some(&cert_path)).is_err());
+    }
+}